@@ -0,0 +1,20 @@
+CREATE EXTENSION IF NOT EXISTS vector;
+
+CREATE TABLE messages (
+    id BIGSERIAL PRIMARY KEY,
+    username TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    role TEXT NOT NULL,
+    content TEXT NOT NULL,
+    -- vectors of every model share the column, so it has no fixed size
+    embedding VECTOR,
+    embedding_model TEXT,
+    timestamp BIGINT NOT NULL,
+    day DATE NOT NULL,
+    conversation_id TEXT,
+    forgotten BOOLEAN NOT NULL DEFAULT FALSE,
+    low_value BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX messages_username_day ON messages (username, day);
+CREATE INDEX messages_username_hash ON messages (username, hash);
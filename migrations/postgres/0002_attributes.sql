@@ -0,0 +1,28 @@
+CREATE TABLE attributes (
+    username TEXT NOT NULL,
+    attribute TEXT NOT NULL,
+    value TEXT NOT NULL,
+    expires_at BIGINT,
+    PRIMARY KEY (username, attribute)
+);
+
+CREATE TABLE attribute_events (
+    id BIGSERIAL PRIMARY KEY,
+    username TEXT NOT NULL,
+    attribute TEXT NOT NULL,
+    -- 'set' or 'delete'
+    kind TEXT NOT NULL,
+    value TEXT,
+    expires_at BIGINT,
+    at BIGINT NOT NULL
+);
+
+CREATE INDEX attribute_events_username_attribute ON attribute_events (username, attribute);
+
+CREATE TABLE expired_attributes (
+    username TEXT NOT NULL,
+    attribute TEXT NOT NULL,
+    value TEXT NOT NULL,
+    expires_at BIGINT,
+    archived_at BIGINT NOT NULL
+);
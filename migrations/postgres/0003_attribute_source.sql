@@ -0,0 +1,4 @@
+-- 'stated' or 'inferred'
+ALTER TABLE attributes ADD COLUMN source TEXT NOT NULL DEFAULT 'stated';
+ALTER TABLE attribute_events ADD COLUMN source TEXT NOT NULL DEFAULT 'stated';
+ALTER TABLE expired_attributes ADD COLUMN source TEXT NOT NULL DEFAULT 'stated';
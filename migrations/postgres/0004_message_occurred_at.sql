@@ -0,0 +1,2 @@
+-- When the client says the message was said, NULL when it did not say
+ALTER TABLE messages ADD COLUMN occurred_at BIGINT;
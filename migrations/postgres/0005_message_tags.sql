@@ -0,0 +1,4 @@
+-- Client supplied key/value pairs and tags, filtered on by history
+ALTER TABLE messages ADD COLUMN metadata JSONB NOT NULL DEFAULT '{}';
+ALTER TABLE messages ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}';
+CREATE INDEX messages_tags ON messages USING GIN (tags);
@@ -0,0 +1,437 @@
+//! Saves messages, searches, dumps a day of history and asks for summaries
+//! from the terminal, by talking to a running Muninn server or, offline, to
+//! the storage directly.
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::NaiveDate;
+use muninn::{
+    services::chat::{ChatRequest, SearchRequest},
+    Muninn,
+};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+const USAGE: &str =
+    "Usage: muninn-cli [--server URL] [--token TOKEN] [--offline] [--json] <command>
+
+Commands:
+  save <username> <role> <content>      Save a message
+  search <username> <query> [--limit N] [--mode semantic|keyword|hybrid]
+  history <username> [YYYY-MM-DD]       Messages of a day, today by default
+  summary <username> [YYYY-MM-DD]       Summary of a day, today by default
+
+The server defaults to MUNINN_URL or http://localhost:8080, the token to
+MUNINN_TOKEN. --offline uses the storage and models of muninn.toml instead of a
+server.";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Save {
+        username: String,
+        role: String,
+        content: String,
+    },
+    Search {
+        username: String,
+        query: String,
+        limit: Option<usize>,
+        mode: Option<String>,
+    },
+    History {
+        username: String,
+        date: Option<String>,
+    },
+    Summary {
+        username: String,
+        date: Option<String>,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    server: String,
+    token: Option<String>,
+    offline: bool,
+    json: bool,
+    command: Command,
+}
+
+fn parse(args: Vec<String>, env: impl Fn(&str) -> Option<String>) -> Result<Options> {
+    let mut server = env("MUNINN_URL").unwrap_or_else(|| "http://localhost:8080".to_string());
+    let mut token = env("MUNINN_TOKEN");
+    let mut offline = false;
+    let mut json = false;
+    let mut limit = None;
+    let mut mode = None;
+    let mut positional = vec![];
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| anyhow!("{} needs a value", name));
+        match arg.as_str() {
+            "--server" => server = value("--server")?,
+            "--token" => token = Some(value("--token")?),
+            "--offline" => offline = true,
+            "--json" => json = true,
+            "--limit" => {
+                limit = Some(
+                    value("--limit")?
+                        .parse::<usize>()
+                        .context("--limit must be a number")?,
+                )
+            }
+            "--mode" => mode = Some(value("--mode")?),
+            "-h" | "--help" => bail!(USAGE),
+            flag if flag.starts_with("--") => bail!("Unknown option {}\n\n{}", flag, USAGE),
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let name = positional.next().ok_or_else(|| anyhow!(USAGE))?;
+    let username = positional
+        .next()
+        .ok_or_else(|| anyhow!("{} needs a username", name))?;
+    let rest = positional.collect::<Vec<String>>();
+    let command = match name.as_str() {
+        "save" => match rest.as_slice() {
+            [role, content @ ..] if !content.is_empty() => Command::Save {
+                username,
+                role: role.clone(),
+                content: content.join(" "),
+            },
+            _ => bail!("save needs a role and the content"),
+        },
+        "search" if !rest.is_empty() => Command::Search {
+            username,
+            query: rest.join(" "),
+            limit,
+            mode,
+        },
+        "search" => bail!("search needs a query"),
+        "history" => Command::History {
+            username,
+            date: rest.into_iter().next(),
+        },
+        "summary" => Command::Summary {
+            username,
+            date: rest.into_iter().next(),
+        },
+        _ => bail!("Unknown command {}\n\n{}", name, USAGE),
+    };
+
+    Ok(Options {
+        server: server.trim_end_matches('/').to_string(),
+        token,
+        offline,
+        json,
+        command,
+    })
+}
+
+struct Server {
+    client: reqwest::Client,
+    url: String,
+    token: Option<String>,
+}
+
+impl Server {
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<Value> {
+        let request = match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("Could not reach {}", self.url))?;
+        let status = response.status();
+        let body = response.text().await?;
+        let value = serde_json::from_str::<Value>(&body).unwrap_or(Value::String(body));
+        if !status.is_success() {
+            let message = value["error"].as_str().map(str::to_string);
+            bail!("{}: {}", status, message.unwrap_or(value.to_string()));
+        }
+        Ok(value)
+    }
+
+    async fn get(&self, path: &str, query: &[(&str, String)]) -> Result<Value> {
+        let url = format!("{}{}", self.url, path);
+        self.send(self.client.get(url).query(query)).await
+    }
+
+    async fn post(&self, path: &str, body: Value) -> Result<Value> {
+        let url = format!("{}{}", self.url, path);
+        let request = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+        self.send(request).await
+    }
+}
+
+fn today() -> String {
+    chrono::Local::now()
+        .date_naive()
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+fn parse_date(date: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").context("Dates must be YYYY-MM-DD")
+}
+
+fn time_of(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format("%H:%M")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+// When a message was said, or else saved
+fn said_at(message: &Value) -> Option<i64> {
+    message["occurred_at"]
+        .as_i64()
+        .or(message["created_at"].as_i64())
+}
+
+// A new message, hashed so that saving the same words twice keeps both
+fn new_message(role: &str, content: &str) -> Value {
+    let stamp = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    let hash = Sha256::digest(format!("{}{}{}", role, content, stamp).as_bytes());
+    json!({ "role": role, "content": content, "hash": format!("{:x}", hash) })
+}
+
+fn search_body(query: &str, limit: Option<usize>, mode: &Option<String>) -> Value {
+    let mut body = json!({ "content": query, "limit": limit.unwrap_or(10) });
+    if let Some(mode) = mode {
+        body["mode"] = json!(mode);
+    }
+    body
+}
+
+// Asks the server, answering with what it sent
+async fn fetch(server: &Server, command: &Command) -> Result<Value> {
+    match command {
+        Command::Save {
+            username,
+            role,
+            content,
+        } => {
+            server
+                .post(
+                    &format!("/api/v1/chat/{}", username),
+                    new_message(role, content),
+                )
+                .await
+        }
+        Command::Search {
+            username,
+            query,
+            limit,
+            mode,
+        } => {
+            server
+                .post(
+                    &format!("/api/v1/chat/{}/search", username),
+                    search_body(query, *limit, mode),
+                )
+                .await
+        }
+        Command::History { username, date } => {
+            let date = date.clone().unwrap_or_else(today);
+            let query = [
+                ("from_date", date.clone()),
+                ("to_date", date),
+                ("limit", "1000".to_string()),
+            ];
+            server
+                .get(&format!("/api/v1/chat/{}/history", username), &query)
+                .await
+        }
+        Command::Summary { username, date } => {
+            let date = date.clone().unwrap_or_else(today);
+            server
+                .get(&format!("/api/v1/summary/{}/{}", username, date), &[])
+                .await
+        }
+    }
+}
+
+// Runs the command against the storage, answering with what the server
+// would have sent
+async fn fetch_offline(muninn: &Muninn, command: &Command) -> Result<Value> {
+    let value = match command {
+        Command::Save {
+            username,
+            role,
+            content,
+        } => {
+            let chat: ChatRequest = serde_json::from_value(new_message(role, content))?;
+            serde_json::to_value(muninn.save(username, chat).await?)?
+        }
+        Command::Search {
+            username,
+            query,
+            limit,
+            mode,
+        } => {
+            let request: SearchRequest =
+                serde_json::from_value(search_body(query, *limit, mode))
+                    .map_err(|_| anyhow!("--mode must be semantic, keyword or hybrid"))?;
+            serde_json::to_value(muninn.search(username, &request).await?)?
+        }
+        Command::History { username, date } => {
+            let date = parse_date(&date.clone().unwrap_or_else(today))?;
+            json!({ "messages": muninn.history(username, date).await? })
+        }
+        Command::Summary { username, date } => {
+            let date = parse_date(&date.clone().unwrap_or_else(today))?;
+            serde_json::to_value(muninn.summarize(username, date).await?)?
+        }
+    };
+    Ok(value)
+}
+
+fn render(command: &Command, value: &Value) -> String {
+    match command {
+        Command::Save { .. } => format!("Saved {}", value["hash"].as_str().unwrap_or_default()),
+        Command::Search { .. } => value
+            .as_array()
+            .map(|results| {
+                results
+                    .iter()
+                    .map(|result| {
+                        format!(
+                            "{:.3}  {:<9}  {}",
+                            result["ranking"].as_f64().unwrap_or_default(),
+                            result["role"].as_str().unwrap_or_default(),
+                            result["content"].as_str().unwrap_or_default()
+                        )
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            })
+            .unwrap_or_default(),
+        Command::History { .. } => {
+            let mut messages = value["messages"].as_array().cloned().unwrap_or_default();
+            messages.sort_by_key(said_at);
+            messages
+                .iter()
+                .map(|message| {
+                    format!(
+                        "[{}] {}: {}",
+                        time_of(said_at(message).unwrap_or_default()),
+                        message["role"].as_str().unwrap_or_default(),
+                        message["content"].as_str().unwrap_or_default()
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join("\n")
+        }
+        Command::Summary { .. } => value["summary"].as_str().unwrap_or_default().to_string(),
+    }
+}
+
+// Returns what to print
+async fn run(options: Options) -> Result<String> {
+    let value = match options.offline {
+        true => {
+            // the storage logs why something failed
+            tracing_subscriber::fmt()
+                .with_writer(std::io::stderr)
+                .with_max_level(tracing::Level::WARN)
+                .init();
+            fetch_offline(&Muninn::open().await?, &options.command).await?
+        }
+        false => {
+            let server = Server {
+                client: reqwest::Client::new(),
+                url: options.server,
+                token: options.token,
+            };
+            fetch(&server, &options.command).await?
+        }
+    };
+    match options.json {
+        true => Ok(serde_json::to_string_pretty(&value)?),
+        false => Ok(render(&options.command, &value)),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match parse(std::env::args().skip(1).collect(), |name| {
+        std::env::var(name).ok()
+    }) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    match run(options).await {
+        Ok(output) if output.is_empty() => {}
+        Ok(output) => println!("{}", output),
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse_commands_and_options() {
+        let options = parse(args("search alice where did I park --limit 3"), |name| {
+            (name == "MUNINN_URL").then(|| "http://muninn.local/".to_string())
+        })
+        .unwrap();
+        assert_eq!(options.server, "http://muninn.local");
+        assert_eq!(
+            options.command,
+            Command::Search {
+                username: "alice".to_string(),
+                query: "where did I park".to_string(),
+                limit: Some(3),
+                mode: None,
+            }
+        );
+
+        let options = parse(args("--json --token s3cret summary bob 2024-03-01"), |_| {
+            None
+        })
+        .unwrap();
+        assert!(options.json);
+        assert!(!options.offline);
+        assert_eq!(options.token.as_deref(), Some("s3cret"));
+        assert_eq!(options.server, "http://localhost:8080");
+        assert_eq!(
+            options.command,
+            Command::Summary {
+                username: "bob".to_string(),
+                date: Some("2024-03-01".to_string()),
+            }
+        );
+
+        assert!(
+            parse(args("--offline history alice"), |_| None)
+                .unwrap()
+                .offline
+        );
+        assert!(parse(args("save alice user"), |_| None).is_err());
+        assert!(parse(args("search alice --limit many"), |_| None).is_err());
+        assert!(parse(args("forget alice"), |_| None).is_err());
+    }
+}
@@ -1,9 +1,13 @@
-use std::{env, fmt};
+use std::{fmt, sync::Arc};
 
 use reqwest::header;
 use serde::{Deserialize, Serialize};
 use serde_json::Result;
+use tokio::sync::Mutex;
 use tracing::error;
+
+use super::retry::{self, RetryPolicy};
+use crate::config::Config;
 #[derive(Debug, Serialize, Deserialize)]
 struct ChatRequest {
     pub model: String,
@@ -32,11 +36,106 @@ pub struct ChatResponse {
     choices: Vec<Choice>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Usage {
-    prompt_tokens: u64,
-    completion_tokens: u64,
-    total_tokens: u64,
+/// Tokens spent on one completion
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+/// A reply from the chat model
+#[derive(Clone, Debug)]
+pub struct ChatCompletion {
+    pub content: String,
+    pub model: String,
+    pub usage: Usage,
+}
+
+/// Why a completion failed
+#[derive(Debug)]
+pub enum ChatError {
+    /// The provider could not be reached
+    Transport(String),
+    /// The provider answered with an error status
+    Provider {
+        status: u16,
+        /// Error code given by the provider, such as `rate_limit_exceeded`
+        code: Option<String>,
+        message: String,
+    },
+    /// The provider answered with something that is not a completion
+    InvalidResponse(String),
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChatError::Transport(message) => write!(f, "chat provider unreachable: {}", message),
+            ChatError::Provider {
+                status,
+                code,
+                message,
+            } => match code {
+                Some(code) => write!(f, "chat provider error {} ({}): {}", status, code, message),
+                None => write!(f, "chat provider error {}: {}", status, message),
+            },
+            ChatError::InvalidResponse(message) => {
+                write!(f, "invalid chat provider response: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChatError {}
+
+#[derive(Deserialize)]
+struct ProviderErrorBody {
+    error: ProviderError,
+}
+
+// OpenAI nests its errors in an object, Ollama sends a plain string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProviderError {
+    Detailed {
+        message: String,
+        code: Option<String>,
+    },
+    Message(String),
+}
+
+// Reads the body of `response` or the provider's reason for failing
+async fn read_body(
+    response: std::result::Result<reqwest::Response, reqwest::Error>,
+) -> std::result::Result<String, ChatError> {
+    let response = response.map_err(|e| ChatError::Transport(e.to_string()))?;
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .map_err(|e| ChatError::Transport(e.to_string()))?;
+    match status.is_success() {
+        true => Ok(body),
+        false => Err(provider_error(status.as_u16(), body)),
+    }
+}
+
+fn provider_error(status: u16, body: String) -> ChatError {
+    let (code, message) = match serde_json::from_str::<ProviderErrorBody>(&body) {
+        Ok(ProviderErrorBody {
+            error: ProviderError::Detailed { message, code },
+        }) => (code, message),
+        Ok(ProviderErrorBody {
+            error: ProviderError::Message(message),
+        }) => (None, message),
+        Err(_) => (None, body),
+    };
+    ChatError::Provider {
+        status,
+        code,
+        message,
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -69,15 +168,38 @@ impl fmt::Display for Role {
 
 #[async_trait::async_trait]
 pub trait ChatClient: Send + Sync {
-    async fn complete(&mut self, context: Vec<Message>) -> String;
+    async fn complete(
+        &mut self,
+        context: Vec<Message>,
+    ) -> std::result::Result<ChatCompletion, ChatError> {
+        self.complete_with(context, None).await
+    }
+    /// Answers with `model` instead of the configured one when given
+    async fn complete_with(
+        &mut self,
+        context: Vec<Message>,
+        model: Option<&str>,
+    ) -> std::result::Result<ChatCompletion, ChatError>;
+    /// Name of the model answering, used to size contexts
+    fn model(&self) -> String;
+}
+
+/// Chat client picked by `models.chat_backend`, `openai` unless set to `ollama`
+pub fn client_from_config(config: &Config) -> Arc<Mutex<dyn ChatClient>> {
+    match config.models.chat_backend.as_str() {
+        "ollama" => Arc::new(Mutex::new(OllamaClient::new(config))),
+        "openai" => Arc::new(Mutex::new(GptClient::new(config))),
+        backend => {
+            error!("Unknown chat backend {}, using openai", backend);
+            Arc::new(Mutex::new(GptClient::new(config)))
+        }
+    }
 }
 
-#[allow(dead_code)]
 pub struct ContextBuilder {
     messages: Vec<Message>,
 }
 
-#[allow(dead_code)]
 impl ContextBuilder {
     pub fn new() -> Self {
         ContextBuilder {
@@ -97,112 +219,274 @@ impl ContextBuilder {
     }
 }
 /// Ollama client implementation
-pub struct OllamaClient;
-#[allow(dead_code)]
+pub struct OllamaClient {
+    url: String,
+    model: String,
+}
 impl OllamaClient {
-    pub fn new() -> Self {
-        OllamaClient {}
+    pub fn new(config: &Config) -> Self {
+        OllamaClient {
+            url: format!("{}/api/chat", config.ollama.url.trim_end_matches('/')),
+            model: config.models.ollama_chat_model.clone(),
+        }
     }
 }
 
 #[derive(Deserialize)]
 struct OllamaResponse {
     pub message: Message,
+    #[serde(default)]
+    prompt_eval_count: u64,
+    #[serde(default)]
+    eval_count: u64,
 }
-#[allow(dead_code)]
 #[async_trait::async_trait]
 impl ChatClient for OllamaClient {
-    async fn complete(&mut self, context: Vec<Message>) -> String {
+    fn model(&self) -> String {
+        self.model.clone()
+    }
+
+    async fn complete_with(
+        &mut self,
+        context: Vec<Message>,
+        model: Option<&str>,
+    ) -> std::result::Result<ChatCompletion, ChatError> {
         let client = reqwest::Client::new();
-        let url = "http://localhost:11434/api/chat";
+        let url = &self.url;
+        let model = model.map(str::to_string).unwrap_or_else(|| self.model());
 
         let chat_request = ChatRequest {
-            model: "gemma:2b".to_string(),
+            model: model.clone(),
             messages: context.clone(),
         };
 
         let request_body = serde_json::to_string(&chat_request).unwrap();
 
-        let response = client
-            .post(url)
-            .body(request_body)
-            .send()
-            .await;
-
-        let response = match response {
-            Ok(response) => response.text().await,
-            Err(e) => {
-                error!("Error: {}", e);
-                return "Error".to_string();
-            }
-        };
-
-        let response_text = response.unwrap();
-
-        let response_object: OllamaResponse = serde_json::from_str(&response_text).unwrap();
+        let response = retry::send(&RetryPolicy::for_backend("ollama"), "ollama", || {
+            client.post(url).body(request_body.clone())
+        })
+        .await;
+        let response_text = read_body(response).await?;
 
-        response_object.message.content
+        let response_object =
+            serde_json::from_str::<OllamaResponse>(&response_text).map_err(|e| {
+                error!("Error: {}, {}", e, response_text);
+                ChatError::InvalidResponse(e.to_string())
+            })?;
+        Ok(ChatCompletion {
+            content: response_object.message.content,
+            model,
+            usage: Usage {
+                prompt_tokens: response_object.prompt_eval_count,
+                completion_tokens: response_object.eval_count,
+                total_tokens: response_object.prompt_eval_count + response_object.eval_count,
+            },
+        })
     }
 }
-/// OpenAI client implementation
-pub struct GptClient;
+/// OpenAI client implementation, also talking to Azure OpenAI
+pub struct GptClient {
+    base_url: String,
+    api_key: Option<String>,
+    // Azure takes the key in `api-key` rather than as a bearer token
+    azure: bool,
+    deployment: Option<String>,
+    api_version: String,
+    model: String,
+}
 impl GptClient {
-    pub fn new() -> Self {
-        GptClient {}
+    pub fn new(config: &Config) -> Self {
+        let base = config.openai.url.trim_end_matches('/');
+        let azure = match config.openai.api.as_str() {
+            "azure" => true,
+            "openai" => false,
+            api => {
+                error!("Unknown OpenAI api {}, using openai", api);
+                false
+            }
+        };
+        GptClient {
+            base_url: base.to_string(),
+            api_key: config.openai.api_key.clone(),
+            azure,
+            deployment: config.openai.deployment.clone(),
+            api_version: config.openai.api_version.clone(),
+            model: config.models.chat_model.clone(),
+        }
+    }
+
+    // Azure picks the model by the deployment in the path, named after the
+    // model asked for in a request
+    fn url(&self, model: Option<&str>) -> String {
+        match self.azure {
+            true => format!(
+                "{}/openai/deployments/{}/chat/completions?api-version={}",
+                self.base_url,
+                model.or(self.deployment.as_deref()).unwrap_or(&self.model),
+                self.api_version
+            ),
+            false => format!("{}/chat/completions", self.base_url),
+        }
     }
 }
-impl GptClient {
-    //complete method
-    pub async fn complete(&mut self, context: Vec<Message>) -> String {
-        // Retrieve the API key from the environment variable
-        let api_key =
-            env::var("OPENAI_API_KEY").expect("Missing OPENAI_API_KEY environment variable");
+#[async_trait::async_trait]
+impl ChatClient for GptClient {
+    fn model(&self) -> String {
+        self.model.clone()
+    }
+
+    async fn complete_with(
+        &mut self,
+        context: Vec<Message>,
+        model: Option<&str>,
+    ) -> std::result::Result<ChatCompletion, ChatError> {
+        let api_key = self
+            .api_key
+            .as_ref()
+            .ok_or_else(|| ChatError::Transport("missing OPENAI_API_KEY".to_string()))?;
 
         let client = reqwest::Client::new();
-        let url = "https://api.openai.com/v1/chat/completions";
+        let url = &self.url(model);
 
         let mut headers = header::HeaderMap::new();
         headers.insert(
             header::CONTENT_TYPE,
             header::HeaderValue::from_static("application/json"),
         );
+        let (name, value) = match self.azure {
+            true => (header::HeaderName::from_static("api-key"), api_key.clone()),
+            false => (header::AUTHORIZATION, format!("Bearer {}", api_key)),
+        };
         headers.insert(
-            header::AUTHORIZATION,
-            header::HeaderValue::from_str(&format!("Bearer {}", api_key)).unwrap(),
+            name,
+            header::HeaderValue::from_str(&value)
+                .map_err(|_| ChatError::Transport("invalid OPENAI_API_KEY".to_string()))?,
         );
 
         let chat_request = ChatRequest {
-            model: "gpt-4-turbo-preview".to_string(),
+            model: model.map(str::to_string).unwrap_or_else(|| self.model()),
             messages: context.clone(),
         };
 
         let request_body = serde_json::to_string(&chat_request).unwrap();
 
-        let response = client
-            .post(url)
-            .headers(headers)
-            .body(request_body)
-            .send()
-            .await;
-
-        let response = match response {
-            Ok(response) => response.text().await,
-            Err(e) => {
-                error!("Error: {}", e);
-                return "Error".to_string();
-            }
-        };
+        let response = retry::send(&RetryPolicy::for_backend("openai"), "openai", || {
+            client
+                .post(url)
+                .headers(headers.clone())
+                .body(request_body.clone())
+        })
+        .await;
+        let response_text = read_body(response).await?;
+
+        let response_object = parse_response(&response_text).map_err(|e| {
+            error!("Error: {}, {}", e, response_text);
+            ChatError::InvalidResponse(e.to_string())
+        })?;
+        let choice = response_object
+            .choices
+            .into_iter()
+            .next()
+            .ok_or_else(|| ChatError::InvalidResponse("no choices".to_string()))?;
+        Ok(ChatCompletion {
+            content: choice.message.content,
+            model: response_object.model,
+            usage: response_object.usage,
+        })
+    }
+}
 
-        let response_text = response.unwrap();
+/**
+ * Mocking the chat client
+ */
+pub struct MockChatClient {}
+impl MockChatClient {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        MockChatClient {}
+    }
+}
 
-        let response_object = match parse_response(&response_text) {
-            Ok(response_object) => response_object,
-            Err(e) => {
-                error!("Error: {}, {}", e, response_text);
-                return "Error".to_string();
+#[async_trait::async_trait]
+impl ChatClient for MockChatClient {
+    fn model(&self) -> String {
+        "mock".to_string()
+    }
+
+    async fn complete_with(
+        &mut self,
+        context: Vec<Message>,
+        model: Option<&str>,
+    ) -> std::result::Result<ChatCompletion, ChatError> {
+        Ok(ChatCompletion {
+            content: format!("Reply to {} messages", context.len()),
+            model: model.map(str::to_string).unwrap_or_else(|| self.model()),
+            usage: Usage::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_provider_error_reads_code_and_message() {
+        let openai = r#"{"error":{"message":"Rate limit reached","type":"requests","code":"rate_limit_exceeded"}}"#;
+        match provider_error(429, openai.to_string()) {
+            ChatError::Provider {
+                status,
+                code,
+                message,
+            } => {
+                assert_eq!(status, 429);
+                assert_eq!(code.as_deref(), Some("rate_limit_exceeded"));
+                assert_eq!(message, "Rate limit reached");
             }
-        };
+            e => panic!("unexpected error {:?}", e),
+        }
 
-        response_object.choices[0].message.content.clone()
+        let ollama = r#"{"error":"model 'gemma:2b' not found"}"#;
+        match provider_error(404, ollama.to_string()) {
+            ChatError::Provider { code, message, .. } => {
+                assert_eq!(code, None);
+                assert_eq!(message, "model 'gemma:2b' not found");
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        match provider_error(502, "Bad Gateway".to_string()) {
+            ChatError::Provider { message, .. } => assert_eq!(message, "Bad Gateway"),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_azure_requests_go_to_the_deployment() {
+        let mut config = Config::default();
+        assert_eq!(
+            GptClient::new(&config).url(None),
+            "https://api.openai.com/v1/chat/completions"
+        );
+
+        config.openai.api = "azure".to_string();
+        config.openai.url = "https://muninn.openai.azure.com/".to_string();
+        let client = GptClient::new(&config);
+        assert!(client.azure);
+        assert_eq!(
+            client.url(None),
+            "https://muninn.openai.azure.com/openai/deployments/gpt-4-turbo-preview/chat/completions?api-version=2024-02-01"
+        );
+
+        config.openai.deployment = Some("muninn-gpt4".to_string());
+        let client = GptClient::new(&config);
+        assert_eq!(
+            client.url(None),
+            "https://muninn.openai.azure.com/openai/deployments/muninn-gpt4/chat/completions?api-version=2024-02-01"
+        );
+        assert_eq!(
+            client.url(Some("gpt-35-turbo")),
+            "https://muninn.openai.azure.com/openai/deployments/gpt-35-turbo/chat/completions?api-version=2024-02-01"
+        );
     }
 }
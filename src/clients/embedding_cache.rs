@@ -0,0 +1,260 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use serde::Serialize;
+
+use super::embeddings::{EmbeddingsClient, Similarity};
+
+/// Hits and misses of an embeddings cache since startup
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct EmbeddingCacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl EmbeddingCacheStats {
+    /// The counters as OpenMetrics samples
+    pub fn render(&self, out: &mut String, labels: &str) {
+        let samples = [
+            (
+                "muninn_embedding_cache_entries",
+                "gauge",
+                "Embeddings held in the cache",
+                self.entries as u64,
+            ),
+            (
+                "muninn_embedding_cache_hits",
+                "counter",
+                "Embeddings served from the cache",
+                self.hits,
+            ),
+            (
+                "muninn_embedding_cache_misses",
+                "counter",
+                "Embeddings fetched from the backend",
+                self.misses,
+            ),
+        ];
+        for (name, kind, help, value) in samples {
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let name = match kind {
+                "counter" => format!("{}_total", name),
+                _ => name.to_string(),
+            };
+            let _ = writeln!(out, "{}{} {}", name, labels, value);
+        }
+    }
+}
+
+struct Entry {
+    embedding: Vec<f32>,
+    stored_at: Instant,
+    // value of `State::clock` when the entry was last read or written
+    used_at: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<(String, String), Entry>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+/// Text as it is cached, so queries differing only in spacing share an
+/// entry. Case is kept since embedding models tell it apart.
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// Remembers the embeddings of the most recently used texts in front of
+/// another client, so a repeated search does not call the backend again.
+/// Entries older than the time to live are fetched again.
+pub struct CachedEmbeddingsClient {
+    inner: Box<dyn EmbeddingsClient>,
+    capacity: usize,
+    ttl: Duration,
+    state: Mutex<State>,
+}
+
+impl CachedEmbeddingsClient {
+    pub fn new(inner: Box<dyn EmbeddingsClient>, capacity: usize, ttl: Duration) -> Self {
+        CachedEmbeddingsClient {
+            inner,
+            capacity,
+            ttl,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    fn lookup(&self, key: &(String, String)) -> Option<Vec<f32>> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        let fresh = match state.entries.get_mut(key) {
+            Some(entry) if entry.stored_at.elapsed() < self.ttl => {
+                entry.used_at = clock;
+                Some(entry.embedding.clone())
+            }
+            Some(_) => {
+                state.entries.remove(key);
+                None
+            }
+            None => None,
+        };
+        match fresh.is_some() {
+            true => state.hits += 1,
+            false => state.misses += 1,
+        }
+        fresh
+    }
+
+    fn store(&self, key: (String, String), embedding: Vec<f32>) {
+        let mut state = self.state.lock().unwrap();
+        if state.entries.len() >= self.capacity && !state.entries.contains_key(&key) {
+            let oldest = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                state.entries.remove(&oldest);
+            }
+        }
+        state.clock += 1;
+        let used_at = state.clock;
+        state.entries.insert(
+            key,
+            Entry {
+                embedding,
+                stored_at: Instant::now(),
+                used_at,
+            },
+        );
+    }
+}
+
+#[async_trait]
+impl EmbeddingsClient for CachedEmbeddingsClient {
+    async fn get_embeddings(&self, text: String) -> Result<Vec<f32>, ()> {
+        let key = (self.inner.model(), normalize(&text));
+        if let Some(embedding) = self.lookup(&key) {
+            return Ok(embedding);
+        }
+        let embedding = self.inner.get_embeddings(text).await?;
+        self.store(key, embedding.clone());
+        Ok(embedding)
+    }
+
+    fn model(&self) -> String {
+        self.inner.model()
+    }
+
+    fn similarity(&self) -> Similarity {
+        self.inner.similarity()
+    }
+
+    fn cache_stats(&self) -> Option<EmbeddingCacheStats> {
+        let state = self.state.lock().unwrap();
+        Some(EmbeddingCacheStats {
+            entries: state.entries.len(),
+            capacity: self.capacity,
+            hits: state.hits,
+            misses: state.misses,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    struct CountingClient {
+        calls: std::sync::Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl EmbeddingsClient for CountingClient {
+        async fn get_embeddings(&self, text: String) -> Result<Vec<f32>, ()> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Ok(vec![text.len() as f32])
+        }
+
+        fn model(&self) -> String {
+            "counting".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_repeated_queries_are_served_from_cache() {
+        let calls = std::sync::Arc::new(AtomicUsize::new(0));
+        let client = CachedEmbeddingsClient::new(
+            Box::new(CountingClient {
+                calls: calls.clone(),
+            }),
+            2,
+            Duration::from_secs(60),
+        );
+
+        client
+            .get_embeddings("where did I park".to_string())
+            .await
+            .unwrap();
+        client
+            .get_embeddings("  where did  I park ".to_string())
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // the least recently used entry makes room for a new one
+        client
+            .get_embeddings("anna's birthday".to_string())
+            .await
+            .unwrap();
+        client
+            .get_embeddings("where did I park".to_string())
+            .await
+            .unwrap();
+        client
+            .get_embeddings("locker code".to_string())
+            .await
+            .unwrap();
+        client
+            .get_embeddings("anna's birthday".to_string())
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+
+        let stats = client.cache_stats().unwrap();
+        assert_eq!(stats.entries, 2);
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 4);
+
+        let expiring = CachedEmbeddingsClient::new(
+            Box::new(CountingClient {
+                calls: calls.clone(),
+            }),
+            2,
+            Duration::ZERO,
+        );
+        expiring
+            .get_embeddings("locker code".to_string())
+            .await
+            .unwrap();
+        expiring
+            .get_embeddings("locker code".to_string())
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 6);
+    }
+}
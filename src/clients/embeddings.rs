@@ -1,9 +1,15 @@
-use std::env;
+use std::{env, sync::Arc};
 
 use async_trait::async_trait;
 use reqwest::header;
 use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
 use tracing::{error, info};
+
+use super::{
+    embedding_cache::EmbeddingCacheStats,
+    retry::{self, RetryPolicy},
+};
 pub struct OpenAiEmbeddingsClient {}
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -31,6 +37,84 @@ pub trait EmbeddingsClient: Send + Sync {
         &self,
         text: String,
     ) -> Result<Vec<f32>,()>;
+    /// Name of the model the vectors come from, stored with each message
+    fn model(&self) -> String;
+    /// How vectors from this client's model are compared
+    fn similarity(&self) -> Similarity {
+        Similarity::for_model(&self.model())
+    }
+    /// Hits and misses when the client caches its embeddings
+    fn cache_stats(&self) -> Option<EmbeddingCacheStats> {
+        None
+    }
+}
+
+/// Metric used to compare two embeddings, higher scores are always closer
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Similarity {
+    Cosine,
+    DotProduct,
+    /// Scored as `1 / (1 + distance)` so it stays in the same range as cosine
+    Euclidean,
+}
+
+impl Similarity {
+    pub fn parse(value: &str) -> Option<Similarity> {
+        match value.trim().to_lowercase().as_str() {
+            "cosine" => Some(Similarity::Cosine),
+            "dot" | "dot_product" => Some(Similarity::DotProduct),
+            "euclidean" => Some(Similarity::Euclidean),
+            _ => None,
+        }
+    }
+
+    /// The metric configured for `model` in `EMBEDDING_SIMILARITY`, given as
+    /// `model=metric` pairs separated by commas, falling back to cosine
+    pub fn for_model(model: &str) -> Similarity {
+        env::var("EMBEDDING_SIMILARITY")
+            .ok()
+            .and_then(|config| {
+                config.split(',').find_map(|pair| match pair.split_once('=') {
+                    Some((name, metric)) if name.trim() == model => Similarity::parse(metric),
+                    _ => None,
+                })
+            })
+            .unwrap_or(Similarity::Cosine)
+    }
+
+    pub fn score(&self, v1: &[f32], v2: &[f32]) -> f32 {
+        match self {
+            Similarity::Cosine => crate::repos::messages::cosine_similarity(v1, v2),
+            Similarity::DotProduct => v1.iter().zip(v2).map(|(a, b)| a * b).sum::<f32>(),
+            Similarity::Euclidean => {
+                let distance = v1
+                    .iter()
+                    .zip(v2)
+                    .map(|(a, b)| (a - b).powi(2))
+                    .sum::<f32>()
+                    .sqrt();
+                1.0 / (1.0 + distance)
+            }
+        }
+    }
+
+    /// Scales cosine vectors to unit length before they are stored when
+    /// `NORMALIZE_EMBEDDINGS` is set, other metrics depend on the magnitude
+    pub fn prepare(&self, embedding: Vec<f32>) -> Vec<f32> {
+        let normalize = env::var("NORMALIZE_EMBEDDINGS")
+            .ok()
+            .and_then(|value| value.parse::<bool>().ok())
+            .unwrap_or(false);
+        if *self != Similarity::Cosine || !normalize {
+            return embedding;
+        }
+        let magnitude = embedding.iter().map(|a| a.powi(2)).sum::<f32>().sqrt();
+        match magnitude > 0.0 {
+            true => embedding.into_iter().map(|a| a / magnitude).collect(),
+            false => embedding,
+        }
+    }
 }
 
 impl OpenAiEmbeddingsClient {
@@ -46,12 +130,14 @@ impl EmbeddingsClient for OpenAiEmbeddingsClient {
         &self,
         text: String,
     ) -> Result<Vec<f32>,()> {
-        let api_key =
-            env::var("OPENAI_API_KEY").expect("Missing OPENAI_API_KEY environment variable");
+        let openai = &crate::config::get().openai;
+        let api_key = openai.api_key.as_ref().ok_or_else(|| {
+            error!("Missing OPENAI_API_KEY");
+        })?;
 
         let client = reqwest::Client::new();
 
-        let url = "https://api.openai.com/v1/embeddings";
+        let url = format!("{}/embeddings", openai.url.trim_end_matches('/'));
 
         let mut headers = header::HeaderMap::new();
         headers.insert(
@@ -68,15 +154,20 @@ impl EmbeddingsClient for OpenAiEmbeddingsClient {
             model: "text-embedding-ada-002".to_string(),
         })
         .unwrap();
-        let response = client
-            .post(url)
-            .headers(headers)
-            .body(request_body)
-            .send()
-            .await;
+        let response = retry::send(&RetryPolicy::for_backend("openai"), "openai", || {
+            client
+                .post(&url)
+                .headers(headers.clone())
+                .body(request_body.clone())
+        })
+        .await;
 
         let response = match response {
-            Ok(response) => response.text().await.unwrap(),
+            Ok(response) => response.text().await,
+            Err(e) => Err(e),
+        };
+        let response = match response {
+            Ok(response) => response,
             Err(e) => {
                 error!("Error in response: {}", e);
                 return Err(())
@@ -94,12 +185,17 @@ impl EmbeddingsClient for OpenAiEmbeddingsClient {
         let embeddings = response_object.data[0].embedding.clone();
         Ok(embeddings)
     }
+
+    fn model(&self) -> String {
+        "text-embedding-ada-002".to_string()
+    }
 }
 
 /// Ollama Client
 /// Implementation of the EmbeddingsClient trait which uses the Ollama service
-pub struct OllamaEmbeddingsClient<'a> {
-    base_url: &'a str,
+pub struct OllamaEmbeddingsClient {
+    base_url: String,
+    model: String,
 }
 
 /*
@@ -121,18 +217,26 @@ struct OllamaResponse {
     embedding: Vec<f32>,
 }
 
-impl OllamaEmbeddingsClient<'_> {
-    //ignore unused
-    #[allow(dead_code)]
+impl OllamaEmbeddingsClient {
+    /// Client for the configured embedding model
     pub fn new() -> Self {
+        OllamaEmbeddingsClient::with_model(&crate::config::get().models.embedding_model)
+    }
+
+    pub fn with_model(model: &str) -> Self {
         OllamaEmbeddingsClient {
-            base_url: "http://localhost:11434",
+            base_url: crate::config::get()
+                .ollama
+                .url
+                .trim_end_matches('/')
+                .to_string(),
+            model: model.to_string(),
         }
     }
 }
 
 #[async_trait]
-impl EmbeddingsClient for OllamaEmbeddingsClient<'_> {
+impl EmbeddingsClient for OllamaEmbeddingsClient {
     async fn get_embeddings(
         &self,
         text: String,
@@ -142,14 +246,22 @@ impl EmbeddingsClient for OllamaEmbeddingsClient<'_> {
         let client = reqwest::Client::new();
 
         let request_body = serde_json::to_string(&OllamaRequest {
-            model: "all-minilm".to_string(),
+            model: self.model.clone(),
             prompt: text.to_string(),
         });
 
-        let response = client.post(&url).body(request_body.unwrap()).send().await;
+        let request_body = request_body.unwrap();
+        let response = retry::send(&RetryPolicy::for_backend("ollama"), "ollama", || {
+            client.post(&url).body(request_body.clone())
+        })
+        .await;
 
+        let response = match response {
+            Ok(response) => response.text().await,
+            Err(e) => Err(e),
+        };
         let ollama_response = match response {
-            Ok(response) => response.text().await.unwrap(),
+            Ok(response) => response,
             Err(e) => {
                 error!("Error in response: {}", e);
                 return Err(());
@@ -165,6 +277,10 @@ impl EmbeddingsClient for OllamaEmbeddingsClient<'_> {
 
         Ok(response_object.embedding)
     }
+
+    fn model(&self) -> String {
+        self.model.clone()
+    }
 }
 /// Barnstokker Client
 /// Implementation of the EmbeddingsClient trait which uses the Barnstokkr service
@@ -206,10 +322,18 @@ impl<'a> EmbeddingsClient for BarnstokkrClient<'a> {
             text: text.to_string(),
         });
 
-        let response = client.post(&url).body(request_body.unwrap()).send().await;
+        let request_body = request_body.unwrap();
+        let response = retry::send(&RetryPolicy::for_backend("barnstokkr"), "barnstokkr", || {
+            client.post(&url).body(request_body.clone())
+        })
+        .await;
 
+        let response = match response {
+            Ok(response) => response.text().await,
+            Err(e) => Err(e),
+        };
         let barnstokkr_response = match response {
-            Ok(response) => response.text().await.unwrap(),
+            Ok(response) => response,
             Err(e) => {
                 error!("Error in response: {}", e);
                 return Err(());
@@ -225,6 +349,27 @@ impl<'a> EmbeddingsClient for BarnstokkrClient<'a> {
 
         Ok(response_object.embeddings)
     }
+
+    fn model(&self) -> String {
+        "barnstokkr".to_string()
+    }
+}
+
+/// Looks up an embeddings provider by name, `ollama:<model>` selects a
+/// specific Ollama model
+pub fn client_for(name: &str) -> Option<Arc<Mutex<dyn EmbeddingsClient>>> {
+    match name.split_once(':') {
+        Some(("ollama", model)) => Some(Arc::new(Mutex::new(
+            OllamaEmbeddingsClient::with_model(model),
+        ))),
+        Some(_) => None,
+        None => match name {
+            "openai" => Some(Arc::new(Mutex::new(OpenAiEmbeddingsClient::new()))),
+            "ollama" => Some(Arc::new(Mutex::new(OllamaEmbeddingsClient::new()))),
+            "barnstokkr" => Some(Arc::new(Mutex::new(BarnstokkrClient::new()))),
+            _ => None,
+        },
+    }
 }
 
 /**
@@ -247,4 +392,27 @@ impl EmbeddingsClient for MockEmbeddingsClient {
         info!("Mocking embeddings for: {}", text);
         Ok(vec![0.0, 0.0, 0.0])
     }
+
+    fn model(&self) -> String {
+        "mock".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_similarity_scores() {
+        let a = [3.0, 4.0];
+        let b = [3.0, 4.0];
+        let c = [0.0, 0.0];
+
+        assert!((Similarity::Cosine.score(&a, &b) - 1.0).abs() < 1e-6);
+        assert_eq!(Similarity::DotProduct.score(&a, &b), 25.0);
+        assert_eq!(Similarity::Euclidean.score(&a, &b), 1.0);
+        assert!((Similarity::Euclidean.score(&a, &c) - 1.0 / 6.0).abs() < 1e-6);
+        assert_eq!(Similarity::parse("dot"), Some(Similarity::DotProduct));
+        assert_eq!(Similarity::parse("manhattan"), None);
+    }
 }
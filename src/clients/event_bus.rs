@@ -0,0 +1,227 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info};
+
+use crate::{
+    config::MqttConfig,
+    hub::{EventHub, HubEvent},
+    namespace,
+    services::sensors::SensorService,
+};
+
+/// Somewhere outside the process that events are announced on
+#[async_trait]
+pub trait EventBus: Send + Sync {
+    async fn publish(&self, topic: &str, payload: Vec<u8>) -> Result<(), ()>;
+}
+
+/// Topic an event is published on, for the events other systems follow
+pub fn topic_for(event: &HubEvent) -> Option<String> {
+    let kind = match event {
+        HubEvent::MessageSaved { .. } => "chat/created",
+        HubEvent::AttributeChanged { .. } => "attribute/updated",
+        HubEvent::SummaryReady { .. } => "summary/ready",
+        HubEvent::FeedItem { .. } => "feed/item",
+        HubEvent::ReminderDue { .. } => "reminder/due",
+        _ => return None,
+    };
+    let topic = format!("muninn/{}/{}", event.username(), kind);
+    let namespace = namespace::namespace();
+    Some(namespace::prefixed(namespace.as_deref(), &topic, "/"))
+}
+
+pub struct MqttEventBus {
+    client: AsyncClient,
+}
+
+// Host and port of a broker URL such as `mqtt://broker:1883`
+fn parse_broker_url(url: &str) -> Option<(String, u16)> {
+    let address = url
+        .strip_prefix("mqtt://")
+        .or_else(|| url.strip_prefix("tcp://"))
+        .unwrap_or(url)
+        .trim_end_matches('/');
+    match address.rsplit_once(':') {
+        Some((host, port)) => Some((host.to_string(), port.parse().ok()?)),
+        None if !address.is_empty() => Some((address.to_string(), 1883)),
+        None => None,
+    }
+}
+
+// Options for connecting to the broker at `mqtt.broker_url` as `name`,
+// authenticating with the username and password when both are set
+fn broker_options(mqtt: &MqttConfig, name: &str) -> Option<MqttOptions> {
+    let url = mqtt.broker_url.as_ref()?;
+    let (host, port) = match parse_broker_url(url) {
+        Some(address) => address,
+        None => {
+            error!("Invalid MQTT_BROKER_URL {}", url);
+            return None;
+        }
+    };
+    let namespace = namespace::namespace();
+    let client_id = namespace::prefixed(namespace.as_deref(), name, "-");
+    let mut mqttoptions = MqttOptions::new(client_id, host, port);
+    mqttoptions.set_keep_alive(Duration::from_secs(5));
+    if let (Some(username), Some(password)) = (&mqtt.username, &mqtt.password) {
+        mqttoptions.set_credentials(username, password);
+    }
+    Some(mqttoptions)
+}
+
+impl MqttEventBus {
+    /// Connects to the broker at `mqtt.broker_url`, authenticating with the
+    /// username and password when both are set
+    pub fn from_config(mqtt: &MqttConfig) -> Option<MqttEventBus> {
+        // a separate client id so the connection does not replace the bridge's
+        let mqttoptions = broker_options(mqtt, "muninn-events")?;
+        let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = eventloop.poll().await {
+                    error!("MQTT connection error {}", e);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        });
+        info!(
+            "Publishing events to {}",
+            mqtt.broker_url.as_deref().unwrap_or_default()
+        );
+        Some(MqttEventBus { client })
+    }
+}
+
+/// Subscribes to the topics of `sensors` on the broker at `mqtt.broker_url`
+/// and hands it every message that arrives, until the process exits
+pub fn start_sensor_subscriber(mqtt: &MqttConfig, sensors: Arc<SensorService>) {
+    if sensors.subscriptions.is_empty() {
+        return;
+    }
+    let mqttoptions = match broker_options(mqtt, "muninn-sensors") {
+        Some(mqttoptions) => mqttoptions,
+        None => {
+            error!("MQTT subscriptions need MQTT_BROKER_URL");
+            return;
+        }
+    };
+    let topics = sensors
+        .subscriptions
+        .iter()
+        .map(|subscription| subscription.topic.clone())
+        .collect::<Vec<String>>();
+    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10 + topics.len());
+
+    tokio::spawn(async move {
+        loop {
+            match eventloop.poll().await {
+                // the broker forgets subscriptions of clean sessions, so they
+                // are made again on every connection
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    for topic in &topics {
+                        if let Err(e) = client.try_subscribe(topic, QoS::AtLeastOnce) {
+                            error!("Error subscribing to {}: {}", topic, e);
+                        }
+                    }
+                    info!("Subscribed to {} MQTT topics", topics.len());
+                }
+                Ok(Event::Incoming(Packet::Publish(message))) => {
+                    if sensors
+                        .receive(&message.topic, &message.payload)
+                        .await
+                        .is_err()
+                    {
+                        error!("Error saving the reading on {}", message.topic);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("MQTT subscriber connection error {}", e);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        }
+    });
+}
+
+#[async_trait]
+impl EventBus for MqttEventBus {
+    async fn publish(&self, topic: &str, payload: Vec<u8>) -> Result<(), ()> {
+        self.client
+            .publish(topic, QoS::AtLeastOnce, false, payload)
+            .await
+            .map_err(|e| {
+                error!("Error publishing to {}: {}", topic, e);
+            })
+    }
+}
+
+/// Follows the event hub and publishes every event that has a topic on
+/// `bus`, as JSON
+pub fn start_event_publisher(hub: Arc<EventHub>, bus: Arc<dyn EventBus>) {
+    let mut events = hub.subscribe_all();
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    error!("Event publisher skipped {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let topic = match topic_for(&event) {
+                Some(topic) => topic,
+                None => continue,
+            };
+            match serde_json::to_vec(&event) {
+                Ok(payload) => {
+                    let _ = bus.publish(&topic, payload).await;
+                }
+                Err(e) => error!("Error serializing event: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topics_and_broker_urls() {
+        let saved = HubEvent::MessageSaved {
+            username: "alice".to_string(),
+            hash: "123".to_string(),
+            role: "user".to_string(),
+        };
+        assert_eq!(
+            topic_for(&saved),
+            Some(namespace::prefixed(
+                namespace::namespace().as_deref(),
+                "muninn/alice/chat/created",
+                "/"
+            ))
+        );
+        let progress = HubEvent::ReembedProgress {
+            username: "alice".to_string(),
+            done: 1,
+            total: 2,
+        };
+        assert_eq!(topic_for(&progress), None);
+
+        assert_eq!(
+            parse_broker_url("mqtt://broker:1884"),
+            Some(("broker".to_string(), 1884))
+        );
+        assert_eq!(
+            parse_broker_url("broker"),
+            Some(("broker".to_string(), 1883))
+        );
+        assert_eq!(parse_broker_url("mqtt://broker:port"), None);
+    }
+}
@@ -1,2 +1,12 @@
 pub mod embeddings;
+pub mod embedding_cache;
 pub mod chat;
+pub mod mqtt;
+pub mod event_bus;
+pub mod retry;
+pub mod models;
+pub mod vectordb;
+pub mod telegram;
+pub mod transcription;
+pub mod web;
+pub mod objectstore;
@@ -0,0 +1,83 @@
+use std::env;
+
+// Context windows, in tokens, of the models we know about
+const KNOWN_MODELS: [(&str, usize); 10] = [
+    ("gpt-4-turbo-preview", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4o", 128_000),
+    ("gpt-4", 8_192),
+    ("gpt-3.5-turbo", 16_385),
+    ("gemma:2b", 8_192),
+    ("llama3", 8_192),
+    ("mistral", 32_768),
+    ("phi", 2_048),
+    ("tinyllama", 2_048),
+];
+
+// Used for models missing from the registry, small enough for local models
+const DEFAULT_CONTEXT_WINDOW: usize = 4_096;
+
+// Share of the window given to the assembled context, the rest is left for
+// the prompt wrapped around it and the model's reply
+const CONTEXT_SHARE: f32 = 0.5;
+
+/// Context window of `model`. Entries in MODEL_CONTEXT_WINDOWS, formatted as
+/// `name=tokens,name=tokens`, take precedence over the built in registry.
+pub fn context_window(model: &str) -> usize {
+    let overrides = env::var("MODEL_CONTEXT_WINDOWS").unwrap_or_default();
+    let overridden = overrides.split(',').find_map(|entry| {
+        let (name, tokens) = entry.split_once('=')?;
+        match name.trim() == model {
+            true => tokens.trim().parse::<usize>().ok(),
+            false => None,
+        }
+    });
+    if let Some(tokens) = overridden {
+        return tokens;
+    }
+
+    KNOWN_MODELS
+        .iter()
+        .find(|(name, _)| *name == model)
+        // tagged local models such as `llama3:8b` share their family's window
+        .or_else(|| {
+            KNOWN_MODELS
+                .iter()
+                .find(|(name, _)| model.split(':').next() == Some(name))
+        })
+        .map(|(_, tokens)| *tokens)
+        .unwrap_or(DEFAULT_CONTEXT_WINDOW)
+}
+
+/// Token budget for assembled context sent to `model`
+pub fn context_budget(model: &str) -> usize {
+    (context_window(model) as f32 * CONTEXT_SHARE) as usize
+}
+
+/// Tokens `text` takes up, counted with the cl100k tokenizer of the OpenAI
+/// models. Local models tokenize differently but land close enough to
+/// budget with.
+pub fn count_tokens(text: &str) -> usize {
+    tiktoken_rs::cl100k_base_singleton()
+        .encode_with_special_tokens(text)
+        .len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_budget_for_known_and_unknown_models() {
+        assert_eq!(context_window("gpt-4"), 8_192);
+        assert_eq!(context_window("llama3:8b"), 8_192);
+        assert_eq!(context_window("some-new-model"), DEFAULT_CONTEXT_WINDOW);
+        assert_eq!(context_budget("phi"), 1_024);
+    }
+
+    #[test]
+    fn test_count_tokens() {
+        assert_eq!(count_tokens(""), 0);
+        assert_eq!(count_tokens("Hello world"), 2);
+    }
+}
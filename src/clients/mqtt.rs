@@ -0,0 +1,83 @@
+use std::{sync::Arc, time::Duration};
+
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use tokio::sync::{broadcast::error::RecvError, Mutex};
+use tracing::{error, info};
+
+use crate::{
+    handlers::events::MessageEvent,
+    hub::{EventHub, HubEvent},
+    namespace,
+    repos::attributes::AttributeRepo,
+};
+
+/// Follows the event hub and forwards assistant messages to the MQTT broker
+/// for users that have a `telegram_chat_id` attribute
+pub fn start_mqtt_bridge(
+    hub: Arc<EventHub>,
+    attribute_repo: Arc<Mutex<dyn AttributeRepo>>,
+    host: String,
+    port: u16,
+) {
+    let namespace = namespace::namespace();
+    let client_id = namespace::prefixed(namespace.as_deref(), "muninn", "-");
+    let topic = namespace::prefixed(namespace.as_deref(), "messages/assistant", "/");
+    let mut mqttoptions = MqttOptions::new(client_id, host, port);
+    mqttoptions.set_keep_alive(Duration::from_secs(5));
+    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = eventloop.poll().await {
+                error!("MQTT connection error {}", e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    });
+
+    let mut events = hub.subscribe_all();
+    tokio::spawn(async move {
+        loop {
+            let (username, hash) = match events.recv().await {
+                Ok(HubEvent::MessageSaved {
+                    username,
+                    hash,
+                    role,
+                }) if role == "assistant" => (username, hash),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    error!("MQTT bridge skipped {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            let attr = "telegram_chat_id".to_string();
+            let chat_id = attribute_repo
+                .lock()
+                .await
+                .get_attribute(&username, &attr)
+                .await;
+            let chat_id = match chat_id.map(|chat_id| chat_id.value.parse::<i64>()) {
+                Ok(Ok(chat_id)) => chat_id,
+                _ => continue,
+            };
+
+            let payload = rmp_serde::to_vec(&MessageEvent {
+                username,
+                hash,
+                chat_id,
+            })
+            .unwrap();
+
+            info!("Sending message to mqtt");
+            match client
+                .publish(&topic, QoS::AtLeastOnce, false, payload)
+                .await
+            {
+                Ok(_) => info!("Message sent"),
+                Err(e) => error!("Error sending message {}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,163 @@
+use std::time::Duration;
+
+use rand::Rng;
+use reqwest::{header, RequestBuilder, Response, StatusCode};
+use tracing::warn;
+
+/// How often and how patiently a backend is retried. Each backend reads its
+/// own settings, e.g. `OPENAI_RETRY_ATTEMPTS`, `OPENAI_RETRY_BASE_MS` and
+/// `OPENAI_RETRY_MAX_MS` for `openai`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every retry after it
+    pub base_delay: Duration,
+    /// Longest delay between two attempts
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn for_backend(backend: &str) -> RetryPolicy {
+        let setting = |name: &str, default: u64| {
+            std::env::var(format!("{}_RETRY_{}", backend.to_uppercase(), name))
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+                .unwrap_or(default)
+        };
+        RetryPolicy {
+            max_attempts: setting("ATTEMPTS", 3).max(1) as u32,
+            base_delay: Duration::from_millis(setting("BASE_MS", 250)),
+            max_delay: Duration::from_millis(setting("MAX_MS", 10_000)),
+        }
+    }
+
+    // Full jitter: anywhere between nothing and the exponential backoff, so
+    // clients that failed together do not retry together
+    fn delay(&self, retry: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay);
+        backoff.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+/// Rate limits and server errors usually pass, anything else will not
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+// Delay the server asked for with a `Retry-After` of seconds
+fn retry_after(response: &Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Sends the request built by `request` until it gets a response that is not
+/// worth retrying or the policy runs out of attempts. The last response is
+/// returned even when it is an error, so callers can still read it.
+pub async fn send(
+    policy: &RetryPolicy,
+    backend: &str,
+    request: impl Fn() -> RequestBuilder,
+) -> Result<Response, reqwest::Error> {
+    let mut retry = 0;
+    loop {
+        let attempt = retry + 1;
+        let delay = match request().send().await {
+            Ok(response) if !is_retryable(response.status()) => return Ok(response),
+            Ok(response) if attempt >= policy.max_attempts => return Ok(response),
+            Err(e) if attempt >= policy.max_attempts => return Err(e),
+            Ok(response) => {
+                warn!(
+                    "{} answered {} on attempt {} of {}",
+                    backend,
+                    response.status(),
+                    attempt,
+                    policy.max_attempts
+                );
+                retry_after(&response)
+                    .map(|delay| delay.min(policy.max_delay))
+                    .unwrap_or_else(|| policy.delay(retry))
+            }
+            Err(e) => {
+                warn!(
+                    "{} request failed on attempt {} of {}: {}",
+                    backend, attempt, policy.max_attempts, e
+                );
+                policy.delay(retry)
+            }
+        };
+        tokio::time::sleep(delay).await;
+        retry += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Answers one connection after another with the given statuses
+    async fn serve(statuses: Vec<u16>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buffer = [0; 1024];
+                let _ = socket.read(&mut buffer).await;
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_rate_limits_and_server_errors() {
+        let url = serve(vec![429, 503, 200]).await;
+        let client = reqwest::Client::new();
+        let response = send(&policy(3), "test", || client.get(&url)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let url = serve(vec![500, 500]).await;
+        let response = send(&policy(2), "test", || client.get(&url)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let url = serve(vec![400, 200]).await;
+        let response = send(&policy(3), "test", || client.get(&url)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_delay_stays_within_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+        };
+        assert!(policy.delay(0) <= Duration::from_millis(100));
+        assert!(policy.delay(1) <= Duration::from_millis(200));
+        assert!(policy.delay(8) <= Duration::from_millis(300));
+    }
+}
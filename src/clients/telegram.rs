@@ -0,0 +1,159 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::error;
+
+use crate::config::TelegramConfig;
+
+// Longest text Telegram takes in one message
+const MAX_MESSAGE_LENGTH: usize = 4096;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Update {
+    pub update_id: i64,
+    pub message: Option<TelegramMessage>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TelegramMessage {
+    pub message_id: i64,
+    pub chat: Chat,
+    /// When it was sent, in seconds since the epoch
+    pub date: i64,
+    pub text: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Chat {
+    pub id: i64,
+}
+
+/// The parts of the Telegram Bot API the bot uses
+#[async_trait]
+pub trait TelegramApi: Send + Sync {
+    /// Updates after `offset`, waiting up to `timeout` for one to arrive
+    async fn get_updates(&self, offset: i64, timeout: Duration) -> Result<Vec<Update>, ()>;
+    async fn send_message(&self, chat_id: i64, text: &str) -> Result<(), ()>;
+}
+
+pub struct HttpTelegramApi {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpTelegramApi {
+    pub fn from_config(telegram: &TelegramConfig) -> Option<HttpTelegramApi> {
+        let token = telegram.bot_token.as_ref()?;
+        Some(HttpTelegramApi {
+            client: reqwest::Client::new(),
+            url: format!("{}/bot{}", telegram.api_url.trim_end_matches('/'), token),
+        })
+    }
+
+    // The `result` of a call, Telegram answers `ok: false` with a description
+    async fn call(&self, request: reqwest::RequestBuilder, method: &str) -> Result<Value, ()> {
+        let response = request.send().await.map_err(|e| {
+            // the URL holds the token, so it is left out
+            error!("Error calling Telegram {}: {}", method, e.without_url());
+        })?;
+        let body = response.text().await.map_err(|e| {
+            error!("Error reading Telegram {}: {}", method, e.without_url());
+        })?;
+        let mut value = serde_json::from_str::<Value>(&body).map_err(|e| {
+            error!("Error deserializing Telegram {}: {}", method, e);
+        })?;
+        if value["ok"] != json!(true) {
+            error!(
+                "Telegram refused {}: {}",
+                method,
+                value["description"].as_str().unwrap_or_default()
+            );
+            return Err(());
+        }
+        Ok(value["result"].take())
+    }
+}
+
+#[async_trait]
+impl TelegramApi for HttpTelegramApi {
+    async fn get_updates(&self, offset: i64, timeout: Duration) -> Result<Vec<Update>, ()> {
+        let request = self
+            .client
+            .get(format!("{}/getUpdates", self.url))
+            .query(&[
+                ("offset", offset.to_string()),
+                ("timeout", timeout.as_secs().to_string()),
+            ])
+            // long polling holds the request open for the whole timeout
+            .timeout(timeout + Duration::from_secs(10));
+        let result = self.call(request, "getUpdates").await?;
+        serde_json::from_value(result).map_err(|e| {
+            error!("Error deserializing Telegram updates: {}", e);
+        })
+    }
+
+    async fn send_message(&self, chat_id: i64, text: &str) -> Result<(), ()> {
+        for part in split_message(text) {
+            let request = self
+                .client
+                .post(format!("{}/sendMessage", self.url))
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(json!({ "chat_id": chat_id, "text": part }).to_string());
+            self.call(request, "sendMessage").await?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits `text` into messages Telegram takes, at line breaks where it can
+pub fn split_message(text: &str) -> Vec<String> {
+    let mut parts = vec![];
+    let mut part = String::new();
+    for line in text.split_inclusive('\n') {
+        let mut line = line;
+        while !line.is_empty() {
+            let room = MAX_MESSAGE_LENGTH - part.chars().count();
+            if line.chars().count() <= room {
+                part.push_str(line);
+                break;
+            }
+            if !part.is_empty() {
+                parts.push(std::mem::take(&mut part));
+                continue;
+            }
+            // a single line longer than a message
+            let cut = line
+                .char_indices()
+                .nth(MAX_MESSAGE_LENGTH)
+                .map(|(index, _)| index)
+                .unwrap_or(line.len());
+            parts.push(line[..cut].to_string());
+            line = &line[cut..];
+        }
+    }
+    if !part.is_empty() {
+        parts.push(part);
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_texts_are_split_at_line_breaks() {
+        assert_eq!(split_message("Hello"), vec!["Hello".to_string()]);
+        assert!(split_message("").is_empty());
+
+        let line = format!("{}\n", "a".repeat(3000));
+        let parts = split_message(&line.repeat(3));
+        assert_eq!(parts, vec![line.clone(), line.clone(), line]);
+
+        let parts = split_message(&"é".repeat(MAX_MESSAGE_LENGTH + 10));
+        assert_eq!(parts[0].chars().count(), MAX_MESSAGE_LENGTH);
+        assert_eq!(parts[1].chars().count(), 10);
+    }
+}
@@ -0,0 +1,239 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use reqwest::header;
+use serde::Deserialize;
+use tracing::error;
+
+use crate::{
+    clients::retry::{self, RetryPolicy},
+    config::Config,
+};
+
+/// What was said in an audio file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transcription {
+    pub text: String,
+    /// Length of the audio in seconds, when the backend tells
+    pub duration: Option<f64>,
+    pub language: Option<String>,
+}
+
+/// An audio file to transcribe, backends tell its format by the extension
+/// of `filename`
+pub struct Audio<'a> {
+    pub data: &'a [u8],
+    pub filename: &'a str,
+    pub content_type: &'a str,
+}
+
+/// Why a transcription failed
+#[derive(Debug, PartialEq)]
+pub enum TranscriptionError {
+    /// The backend is missing its URL or key
+    NotConfigured,
+    /// The backend could not be reached or did not transcribe, logged
+    Failed,
+}
+
+#[async_trait]
+pub trait TranscriptionClient: Send + Sync {
+    async fn transcribe(&self, audio: Audio<'_>) -> Result<Transcription, TranscriptionError>;
+}
+
+pub fn client_from_config(config: &Config) -> Arc<dyn TranscriptionClient> {
+    Arc::new(WhisperClient::new(config))
+}
+
+/// Whisper through the OpenAI API, Azure OpenAI or a whisper.cpp server,
+/// which all take the same form
+pub struct WhisperClient {
+    client: reqwest::Client,
+    /// Where audio is posted, `None` when the backend is not configured
+    url: Option<String>,
+    // Azure takes the key in `api-key` rather than as a bearer token
+    auth: Option<(header::HeaderName, String)>,
+    model: String,
+    language: Option<String>,
+    retry: RetryPolicy,
+}
+
+impl WhisperClient {
+    pub fn new(config: &Config) -> Self {
+        let whisper = &config.whisper;
+        let openai = &config.openai;
+        let base = openai.url.trim_end_matches('/');
+        let (url, auth) = match whisper.backend.as_str() {
+            "whispercpp" => (
+                whisper
+                    .url
+                    .as_ref()
+                    .map(|url| format!("{}/inference", url.trim_end_matches('/'))),
+                None,
+            ),
+            backend => {
+                if backend != "openai" {
+                    error!("Unknown Whisper backend {}, using openai", backend);
+                }
+                match (openai.api.as_str(), &openai.api_key) {
+                    (_, None) => (None, None),
+                    ("azure", Some(key)) => (
+                        Some(format!(
+                            "{}/openai/deployments/{}/audio/transcriptions?api-version={}",
+                            base, whisper.model, openai.api_version
+                        )),
+                        Some((header::HeaderName::from_static("api-key"), key.clone())),
+                    ),
+                    (_, Some(key)) => (
+                        Some(format!("{}/audio/transcriptions", base)),
+                        Some((header::AUTHORIZATION, format!("Bearer {}", key))),
+                    ),
+                }
+            }
+        };
+        WhisperClient {
+            client: reqwest::Client::new(),
+            url,
+            auth,
+            model: whisper.model.clone(),
+            language: whisper.language.clone(),
+            retry: RetryPolicy::for_backend("whisper"),
+        }
+    }
+}
+
+#[async_trait]
+impl TranscriptionClient for WhisperClient {
+    async fn transcribe(&self, audio: Audio<'_>) -> Result<Transcription, TranscriptionError> {
+        let url = self.url.as_ref().ok_or_else(|| {
+            error!("Transcribing needs OPENAI_API_KEY, or WHISPER_URL with the whispercpp backend");
+            TranscriptionError::NotConfigured
+        })?;
+        let boundary = format!("muninn-{}", uuid::Uuid::new_v4().simple());
+        let mut fields = vec![
+            ("model", self.model.as_str()),
+            ("response_format", "verbose_json"),
+        ];
+        if let Some(language) = &self.language {
+            fields.push(("language", language));
+        }
+        let body = multipart(&boundary, &fields, &audio);
+        let content_type = format!("multipart/form-data; boundary={}", boundary);
+
+        let response = retry::send(&self.retry, "whisper", || {
+            let request = self
+                .client
+                .post(url)
+                .header(header::CONTENT_TYPE, &content_type)
+                .body(body.clone());
+            match &self.auth {
+                Some((name, value)) => request.header(name, value),
+                None => request,
+            }
+        })
+        .await
+        .map_err(|e| {
+            error!("Error calling Whisper: {}", e.without_url());
+            TranscriptionError::Failed
+        })?;
+        let status = response.status();
+        let text = response.text().await.map_err(|e| {
+            error!("Error reading Whisper response: {}", e);
+            TranscriptionError::Failed
+        })?;
+        if !status.is_success() {
+            error!("Whisper answered {}: {}", status, text);
+            return Err(TranscriptionError::Failed);
+        }
+        parse_transcription(&text).ok_or_else(|| {
+            error!("Error deserializing Whisper response: {}", text);
+            TranscriptionError::Failed
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct VerboseTranscription {
+    text: String,
+    duration: Option<f64>,
+    language: Option<String>,
+    #[serde(default)]
+    segments: Vec<Segment>,
+}
+
+#[derive(Deserialize)]
+struct Segment {
+    end: f64,
+}
+
+// The `verbose_json` answer of OpenAI and whisper.cpp
+fn parse_transcription(body: &str) -> Option<Transcription> {
+    let transcription = serde_json::from_str::<VerboseTranscription>(body).ok()?;
+    let duration = transcription
+        .duration
+        .or(transcription.segments.last().map(|segment| segment.end));
+    Some(Transcription {
+        text: transcription.text.trim().to_string(),
+        duration,
+        language: transcription.language,
+    })
+}
+
+// A multipart/form-data body of `fields` and the audio as `file`
+fn multipart(boundary: &str, fields: &[(&str, &str)], audio: &Audio) -> Vec<u8> {
+    let mut body = vec![];
+    for (name, value) in fields {
+        let field = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+            boundary, name, value
+        );
+        body.extend_from_slice(field.as_bytes());
+    }
+    let file = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+        boundary,
+        audio.filename.replace(['"', '\r', '\n'], ""),
+        audio.content_type
+    );
+    body.extend_from_slice(file.as_bytes());
+    body.extend_from_slice(audio.data);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audio_is_sent_as_a_form() {
+        let audio = Audio {
+            data: b"RIFF",
+            filename: "note\".wav",
+            content_type: "audio/wav",
+        };
+        let body = multipart("b", &[("model", "whisper-1")], &audio);
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "--b\r\nContent-Disposition: form-data; name=\"model\"\r\n\r\nwhisper-1\r\n\
+            --b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"note.wav\"\r\n\
+            Content-Type: audio/wav\r\n\r\nRIFF\r\n--b--\r\n"
+        );
+    }
+
+    #[test]
+    fn test_durations_fall_back_to_the_last_segment() {
+        let openai = r#"{"text": " Buy milk. ", "duration": 2.5, "language": "english"}"#;
+        assert_eq!(
+            parse_transcription(openai),
+            Some(Transcription {
+                text: "Buy milk.".to_string(),
+                duration: Some(2.5),
+                language: Some("english".to_string()),
+            })
+        );
+        let segments = r#"{"text": "Hi", "segments": [{"end": 0.8}, {"end": 1.6}]}"#;
+        assert_eq!(parse_transcription(segments).unwrap().duration, Some(1.6));
+        assert_eq!(parse_transcription("Hi"), None);
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+use reqwest::header;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use super::{
+    embeddings::Similarity,
+    retry::{self, RetryPolicy},
+};
+use crate::config::StorageConfig;
+
+/// One message's vector, keyed by its hash
+#[derive(Clone, Debug)]
+pub struct VectorPoint {
+    pub hash: String,
+    pub embedding: Vec<f32>,
+    pub model: String,
+}
+
+/// A message found by a vector search, best first
+#[derive(Clone, Debug, PartialEq)]
+pub struct VectorHit {
+    pub hash: String,
+    pub score: f32,
+}
+
+/// External store for message embeddings. Only vectors and hashes are kept
+/// there, the messages themselves stay in the message repo.
+#[async_trait]
+pub trait VectorDb: Send + Sync {
+    async fn upsert(&self, user: &str, points: Vec<VectorPoint>) -> Result<(), ()>;
+    /// The user's closest messages to `vector` among those embedded with `model`
+    async fn search(
+        &self,
+        user: &str,
+        vector: Vec<f32>,
+        model: &str,
+        limit: usize,
+    ) -> Result<Vec<VectorHit>, ()>;
+    async fn delete(&self, user: &str, hashes: Vec<String>) -> Result<(), ()>;
+}
+
+// Qdrant only accepts integers and UUIDs as point ids, so one is derived
+// from the user and message hash
+fn point_id(user: &str, hash: &str) -> String {
+    let digest = Sha256::digest(format!("{}:{}", user, hash).as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    uuid::Uuid::from_bytes(bytes).to_string()
+}
+
+// Vectors of different models have different sizes, so every model gets a
+// collection of its own
+fn collection_name(base: &str, model: &str) -> String {
+    let model = model
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+            true => c,
+            false => '_',
+        })
+        .collect::<String>();
+    format!("{}_{}", base, model)
+}
+
+fn distance(similarity: Similarity) -> &'static str {
+    match similarity {
+        Similarity::Cosine => "Cosine",
+        Similarity::DotProduct => "Dot",
+        Similarity::Euclidean => "Euclid",
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchResponse {
+    result: Vec<ScoredPoint>,
+}
+
+#[derive(Deserialize)]
+struct ScoredPoint {
+    score: f32,
+    payload: Value,
+}
+
+#[derive(Deserialize)]
+struct CollectionsResponse {
+    result: CollectionList,
+}
+
+#[derive(Deserialize)]
+struct CollectionList {
+    collections: Vec<CollectionDescription>,
+}
+
+#[derive(Deserialize)]
+struct CollectionDescription {
+    name: String,
+}
+
+/// Qdrant over its REST API
+pub struct QdrantClient {
+    url: String,
+    api_key: Option<String>,
+    collection: String,
+    client: reqwest::Client,
+    // Collections known to exist, created on their first upsert
+    collections: Mutex<HashSet<String>>,
+}
+
+impl QdrantClient {
+    /// A client for the server at `storage.qdrant_url`, `None` when it is
+    /// not set. Collections are named after `storage.qdrant_collection`,
+    /// prefixed with the namespace.
+    pub fn from_config(storage: &StorageConfig) -> Option<QdrantClient> {
+        let url = storage.qdrant_url.as_ref()?;
+        Some(QdrantClient {
+            url: url.trim_end_matches('/').to_string(),
+            api_key: storage.qdrant_api_key.clone(),
+            collection: crate::namespace::prefixed(
+                crate::namespace::namespace().as_deref(),
+                &storage.qdrant_collection,
+                "_",
+            ),
+            client: reqwest::Client::new(),
+            collections: Mutex::new(HashSet::new()),
+        })
+    }
+
+    async fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<String, ()> {
+        let url = format!("{}{}", self.url, path);
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("application/json"),
+        );
+        if let Some(api_key) = &self.api_key {
+            let api_key = header::HeaderValue::from_str(api_key).map_err(|_| {
+                error!("QDRANT_API_KEY is not a valid header value");
+            })?;
+            headers.insert("api-key", api_key);
+        }
+        let response = retry::send(&RetryPolicy::for_backend("qdrant"), "qdrant", || {
+            let request = self
+                .client
+                .request(method.clone(), &url)
+                .headers(headers.clone());
+            match &body {
+                Some(body) => request.body(body.to_string()),
+                None => request,
+            }
+        })
+        .await
+        .map_err(|e| {
+            error!("Error reaching Qdrant: {}", e);
+        })?;
+        let status = response.status();
+        let text = response.text().await.map_err(|e| {
+            error!("Error reading Qdrant response: {}", e);
+        })?;
+        if !status.is_success() {
+            error!("Qdrant answered {} to {}: {}", status, path, text);
+            return Err(());
+        }
+        Ok(text)
+    }
+
+    async fn ensure_collection(&self, model: &str, size: usize) -> Result<String, ()> {
+        let name = collection_name(&self.collection, model);
+        let mut collections = self.collections.lock().await;
+        if collections.contains(&name) {
+            return Ok(name);
+        }
+        if !self.list_collections().await?.contains(&name) {
+            self.request(
+                reqwest::Method::PUT,
+                &format!("/collections/{}", name),
+                Some(json!({
+                    "vectors": {
+                        "size": size,
+                        "distance": distance(Similarity::for_model(model)),
+                    }
+                })),
+            )
+            .await?;
+            info!("Created Qdrant collection {}", name);
+        }
+        collections.insert(name.clone());
+        Ok(name)
+    }
+
+    // Every collection of this instance, one per model
+    async fn list_collections(&self) -> Result<Vec<String>, ()> {
+        let response = self
+            .request(reqwest::Method::GET, "/collections", None)
+            .await?;
+        let response: CollectionsResponse = serde_json::from_str(&response).map_err(|e| {
+            error!("Error parsing Qdrant collections: {}", e);
+        })?;
+        let prefix = format!("{}_", self.collection);
+        Ok(response
+            .result
+            .collections
+            .into_iter()
+            .map(|collection| collection.name)
+            .filter(|name| name.starts_with(&prefix))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl VectorDb for QdrantClient {
+    async fn upsert(&self, user: &str, points: Vec<VectorPoint>) -> Result<(), ()> {
+        let mut by_model: Vec<(String, Vec<VectorPoint>)> = vec![];
+        for point in points {
+            match by_model.iter_mut().find(|(model, _)| *model == point.model) {
+                Some((_, points)) => points.push(point),
+                None => by_model.push((point.model.clone(), vec![point])),
+            }
+        }
+
+        for (model, points) in by_model {
+            let collection = self
+                .ensure_collection(&model, points[0].embedding.len())
+                .await?;
+            let points = points
+                .into_iter()
+                .map(|point| {
+                    json!({
+                        "id": point_id(user, &point.hash),
+                        "vector": point.embedding,
+                        "payload": { "username": user, "hash": point.hash },
+                    })
+                })
+                .collect::<Vec<Value>>();
+            self.request(
+                reqwest::Method::PUT,
+                &format!("/collections/{}/points?wait=true", collection),
+                Some(json!({ "points": points })),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        user: &str,
+        vector: Vec<f32>,
+        model: &str,
+        limit: usize,
+    ) -> Result<Vec<VectorHit>, ()> {
+        let name = collection_name(&self.collection, model);
+        if !self.list_collections().await?.contains(&name) {
+            return Ok(vec![]);
+        }
+        let response = self
+            .request(
+                reqwest::Method::POST,
+                &format!("/collections/{}/points/search", name),
+                Some(json!({
+                    "vector": vector,
+                    "limit": limit,
+                    "with_payload": true,
+                    "filter": { "must": [{ "key": "username", "match": { "value": user } }] },
+                })),
+            )
+            .await?;
+        let response: SearchResponse = serde_json::from_str(&response).map_err(|e| {
+            error!("Error parsing Qdrant search: {}", e);
+        })?;
+        let euclidean = Similarity::for_model(model) == Similarity::Euclidean;
+        Ok(response
+            .result
+            .into_iter()
+            .filter_map(|point| {
+                let hash = point.payload.get("hash")?.as_str()?.to_string();
+                // Qdrant gives the distance itself, scored like the file backend does
+                let score = match euclidean {
+                    true => 1.0 / (1.0 + point.score),
+                    false => point.score,
+                };
+                Some(VectorHit { hash, score })
+            })
+            .collect())
+    }
+
+    async fn delete(&self, user: &str, hashes: Vec<String>) -> Result<(), ()> {
+        let ids = hashes
+            .iter()
+            .map(|hash| point_id(user, hash))
+            .collect::<Vec<String>>();
+        for collection in self.list_collections().await? {
+            self.request(
+                reqwest::Method::POST,
+                &format!("/collections/{}/points/delete?wait=true", collection),
+                Some(json!({ "points": ids })),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_points_and_collections_are_named_stably() {
+        assert_eq!(point_id("mark", "abc"), point_id("mark", "abc"));
+        assert_ne!(point_id("mark", "abc"), point_id("anna", "abc"));
+        assert!(uuid::Uuid::parse_str(&point_id("mark", "abc")).is_ok());
+
+        assert_eq!(
+            collection_name("muninn", "nomic-embed-text:latest"),
+            "muninn_nomic-embed-text_latest"
+        );
+    }
+}
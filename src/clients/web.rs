@@ -0,0 +1,151 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::header;
+use tracing::error;
+
+// Longest a page may take to load
+const FETCH_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Largest page that is read, in bytes
+pub const MAX_PAGE_BYTES: usize = 10 * 1024 * 1024;
+
+/// A page fetched from the web
+pub struct Page {
+    /// Where the page was found, after any redirects
+    pub url: String,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+/// Why a page could not be fetched
+#[derive(Debug, PartialEq)]
+pub enum FetchError {
+    /// The site could not be reached or stopped answering, logged
+    Failed,
+    /// The site answered with this status rather than the page
+    Status(u16),
+    /// The page is larger than `MAX_PAGE_BYTES`
+    TooLarge,
+}
+
+#[async_trait]
+pub trait PageClient: Send + Sync {
+    async fn fetch(&self, url: &str) -> Result<Page, FetchError>;
+}
+
+pub struct HttpPageClient {
+    client: reqwest::Client,
+}
+
+impl HttpPageClient {
+    pub fn new() -> Self {
+        let client = reqwest::Client::builder()
+            .user_agent(concat!("Muninn/", env!("CARGO_PKG_VERSION")))
+            .timeout(FETCH_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        HttpPageClient { client }
+    }
+}
+
+#[async_trait]
+impl PageClient for HttpPageClient {
+    async fn fetch(&self, url: &str) -> Result<Page, FetchError> {
+        let failed = |e: reqwest::Error| {
+            error!("Error fetching page: {}", e);
+            FetchError::Failed
+        };
+        let mut response = self
+            .client
+            .get(url)
+            .header(
+                header::ACCEPT,
+                "text/html,application/xhtml+xml,application/pdf;q=0.9,text/plain;q=0.8,*/*;q=0.5",
+            )
+            .send()
+            .await
+            .map_err(failed)?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(FetchError::Status(status.as_u16()));
+        }
+        if response
+            .content_length()
+            .is_some_and(|length| length > MAX_PAGE_BYTES as u64)
+        {
+            return Err(FetchError::TooLarge);
+        }
+        let url = response.url().to_string();
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        // the length is not always sent, so the body is read up to the limit
+        let mut body = vec![];
+        while let Some(chunk) = response.chunk().await.map_err(failed)? {
+            if body.len() + chunk.len() > MAX_PAGE_BYTES {
+                return Err(FetchError::TooLarge);
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(Page {
+            url,
+            content_type,
+            body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    async fn serve(responses: Vec<String>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buffer = [0; 1024];
+                let _ = socket.read(&mut buffer).await;
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    #[tokio::test]
+    async fn test_pages_are_fetched_after_redirects() {
+        let page = "<p>Hello</p>";
+        let url = serve(vec![
+            "HTTP/1.1 301 Moved\r\nlocation: /post\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                .to_string(),
+            format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: text/html; charset=utf-8\r\n\
+                content-length: {}\r\nconnection: close\r\n\r\n{}",
+                page.len(),
+                page
+            ),
+        ])
+        .await;
+        let client = HttpPageClient::new();
+        let fetched = client.fetch(&url).await.unwrap();
+        assert_eq!(fetched.url, format!("{}/post", url));
+        assert_eq!(fetched.content_type, "text/html; charset=utf-8");
+        assert_eq!(fetched.body, page.as_bytes());
+
+        let url = serve(vec![
+            "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n".to_string(),
+        ])
+        .await;
+        assert_eq!(
+            client.fetch(&url).await.err(),
+            Some(FetchError::Status(404))
+        );
+    }
+}
@@ -0,0 +1,896 @@
+use std::{collections::HashMap, path::PathBuf, sync::OnceLock};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+pub use crate::scheduler::IndexLoadPolicy;
+
+/// Settings read from `muninn.toml`, or the file at MUNINN_CONFIG. Every
+/// setting can be overridden by its environment variable, so deployments
+/// configured through the environment keep working without a file.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub models: ModelConfig,
+    pub ollama: OllamaConfig,
+    pub openai: OpenAiConfig,
+    pub mqtt: MqttConfig,
+    pub rate_limit: RateLimitConfig,
+    pub telegram: TelegramConfig,
+    pub ingest: IngestConfig,
+    pub whisper: WhisperConfig,
+    pub context: ContextConfig,
+    pub jobs: JobsConfig,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// Listens on this unix socket instead of `host` and `port` when set
+    pub socket: Option<PathBuf>,
+    /// PEM certificate chain, serves HTTPS together with `tls_key`
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Largest JSON body accepted, in bytes
+    pub max_body_bytes: usize,
+    /// Longest message content accepted, in characters
+    pub max_content_length: usize,
+    /// Token always accepted with admin scope. Tokens are only checked when
+    /// it is set, which is also how the first user tokens get issued.
+    pub admin_token: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: "0.0.0.0".to_string(),
+            port: 8080,
+            socket: None,
+            tls_cert: None,
+            tls_key: None,
+            max_body_bytes: 1024 * 1024,
+            max_content_length: 32_000,
+            admin_token: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// Folder holding the `muninn` data folder, the local data folder by default
+    pub path: Option<PathBuf>,
+    /// Keeps messages and attributes in Postgres when set
+    pub database_url: Option<String>,
+    /// Searches embeddings in Qdrant when set
+    pub qdrant_url: Option<String>,
+    pub qdrant_collection: String,
+    pub qdrant_api_key: Option<String>,
+    /// What saving a message the user already has does
+    pub dedup: DedupMode,
+    /// What happens to the messages of users without a policy of their own
+    pub retention: RetentionPolicy,
+    /// Days after which day folders are compressed into cold storage, never
+    /// when 0
+    pub compress_after_days: u32,
+    /// Parsed day files kept in memory for repeated reads, none when 0
+    pub day_cache_size: usize,
+    /// Base64 AES-256 key message and attribute files are encrypted with
+    pub encryption_key: Option<String>,
+    /// File holding the encryption key, read when `encryption_key` is unset
+    pub encryption_key_file: Option<PathBuf>,
+    /// Masks emails, phone and card numbers before messages are stored, for
+    /// users without the `scrub_pii` attribute
+    pub scrub_pii: bool,
+    /// Also asks the chat model for personal details the patterns miss
+    pub scrub_pii_with_model: bool,
+    /// Mirrors message and attribute files to this S3 bucket when set, the
+    /// local files are then a cache of it
+    pub s3_bucket: Option<String>,
+    /// S3 or S3-compatible server, e.g. `http://localhost:9000` for MinIO
+    pub s3_endpoint: String,
+    pub s3_region: String,
+    /// Key prefix of the objects, prefixed with the namespace
+    pub s3_prefix: String,
+    pub s3_access_key: Option<String>,
+    pub s3_secret_key: Option<String>,
+    /// Where messages and attributes are replicated to in the background:
+    /// `files` for the filesystem when they are kept in the database, or a
+    /// Postgres URL when they are kept in files
+    pub replica: Option<String>,
+}
+
+/// How a saved message with the same role and content as a stored one is
+/// handled
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupMode {
+    /// Stores it again
+    #[default]
+    Allow,
+    /// Refuses it and answers with the stored message
+    Reject,
+    /// Replaces the stored message with it
+    Overwrite,
+}
+
+impl DedupMode {
+    fn parse(mode: &str) -> Option<DedupMode> {
+        match mode {
+            "allow" => Some(DedupMode::Allow),
+            "reject" => Some(DedupMode::Reject),
+            "overwrite" => Some(DedupMode::Overwrite),
+            _ => None,
+        }
+    }
+}
+
+/// What happens to a user's messages as they age. Days are the days the
+/// messages are stored under, today is never touched.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum RetentionPolicy {
+    /// Keeps every message
+    #[default]
+    Forever,
+    /// Removes the messages of days more than `days` ago
+    Delete { days: u32 },
+    /// Stores the summary of each day more than `days` ago, then removes its
+    /// messages
+    Summarize { days: u32 },
+}
+
+impl RetentionPolicy {
+    /// Reads `forever`, `delete:<days>` and `summarize:<days>`
+    pub fn parse(policy: &str) -> Option<RetentionPolicy> {
+        let policy = policy.trim();
+        if policy == "forever" {
+            return Some(RetentionPolicy::Forever);
+        }
+        let (mode, days) = policy.split_once(':')?;
+        let days = days.trim().parse::<u32>().ok()?;
+        match mode.trim() {
+            "delete" => Some(RetentionPolicy::Delete { days }),
+            "summarize" => Some(RetentionPolicy::Summarize { days }),
+            _ => None,
+        }
+    }
+
+    /// How many days of messages are kept, `None` when all of them are
+    pub fn days(&self) -> Option<u32> {
+        match self {
+            RetentionPolicy::Forever => None,
+            RetentionPolicy::Delete { days } | RetentionPolicy::Summarize { days } => Some(*days),
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            path: None,
+            database_url: None,
+            qdrant_url: None,
+            qdrant_collection: "muninn".to_string(),
+            qdrant_api_key: None,
+            dedup: DedupMode::Allow,
+            retention: RetentionPolicy::Forever,
+            compress_after_days: 90,
+            day_cache_size: 365,
+            encryption_key: None,
+            encryption_key_file: None,
+            scrub_pii: false,
+            scrub_pii_with_model: false,
+            s3_bucket: None,
+            s3_endpoint: "https://s3.amazonaws.com".to_string(),
+            s3_region: "us-east-1".to_string(),
+            s3_prefix: "muninn".to_string(),
+            s3_access_key: None,
+            s3_secret_key: None,
+            replica: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ModelConfig {
+    /// `openai` or `ollama`
+    pub chat_backend: String,
+    /// Model answering through OpenAI
+    pub chat_model: String,
+    /// Model answering through Ollama
+    pub ollama_chat_model: String,
+    /// Other models a request may ask to answer with
+    pub allowed_chat_models: Vec<String>,
+    /// Ollama model computing embeddings
+    pub embedding_model: String,
+    /// Saves messages right away and embeds them in the background
+    pub background_embeddings: bool,
+    /// Times a background embedding is tried before it is given up on
+    pub embedding_attempts: u32,
+    /// Embeddings of recent texts kept in memory, none when 0
+    pub embedding_cache_size: usize,
+    /// Seconds a cached embedding is used before it is fetched again
+    pub embedding_cache_ttl_secs: u64,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        ModelConfig {
+            chat_backend: "openai".to_string(),
+            chat_model: "gpt-4-turbo-preview".to_string(),
+            ollama_chat_model: "gemma:2b".to_string(),
+            allowed_chat_models: vec![],
+            embedding_model: "all-minilm".to_string(),
+            background_embeddings: false,
+            embedding_attempts: 5,
+            embedding_cache_size: 1000,
+            embedding_cache_ttl_secs: 3600,
+        }
+    }
+}
+
+impl ModelConfig {
+    /// Whether a request may ask for `model`, the backend's own model always
+    /// may
+    pub fn allows_chat_model(&self, model: &str) -> bool {
+        let configured = match self.chat_backend.as_str() {
+            "ollama" => &self.ollama_chat_model,
+            _ => &self.chat_model,
+        };
+        model == configured
+            || self
+                .allowed_chat_models
+                .iter()
+                .any(|allowed| allowed == model)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct OllamaConfig {
+    pub url: String,
+}
+
+impl Default for OllamaConfig {
+    fn default() -> Self {
+        OllamaConfig {
+            url: "http://localhost:11434".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct OpenAiConfig {
+    /// `openai`, or `azure` for an Azure OpenAI resource at `url`
+    pub api: String,
+    pub url: String,
+    pub api_key: Option<String>,
+    /// Azure deployment answering, named after `models.chat_model` when unset
+    pub deployment: Option<String>,
+    /// Azure `api-version` of the requests
+    pub api_version: String,
+}
+
+impl Default for OpenAiConfig {
+    fn default() -> Self {
+        OpenAiConfig {
+            api: "openai".to_string(),
+            url: "https://api.openai.com/v1".to_string(),
+            api_key: None,
+            deployment: None,
+            api_version: "2024-02-01".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct MqttConfig {
+    /// Broker the attribute bridge connects to, the bridge is off without it
+    pub host: Option<String>,
+    pub port: u16,
+    /// Broker events are published to, as `mqtt://host:port`
+    pub broker_url: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Topics of the broker at `broker_url` whose values are kept as
+    /// attributes
+    pub subscriptions: Vec<MqttSubscription>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            host: None,
+            port: 1883,
+            broker_url: None,
+            username: None,
+            password: None,
+            subscriptions: vec![],
+        }
+    }
+}
+
+/// A topic whose messages set an attribute. `{1}`, `{2}` and so on in the
+/// username and attribute stand for the topic levels matched by the
+/// wildcards of the topic, in order.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct MqttSubscription {
+    /// Topic filter, may hold `+` and `#` wildcards
+    pub topic: String,
+    pub username: String,
+    pub attribute: String,
+    /// `value` for payloads that are the value, such as Home Assistant
+    /// states, or `owntracks` for OwnTracks locations
+    #[serde(default = "default_payload_format")]
+    pub format: String,
+}
+
+fn default_payload_format() -> String {
+    "value".to_string()
+}
+
+impl MqttSubscription {
+    // `topic=username:attribute`, optionally followed by `:format`
+    fn parse(entry: &str) -> Option<MqttSubscription> {
+        let (topic, target) = entry.trim().split_once('=')?;
+        let mut parts = target.split(':').map(str::trim);
+        let username = parts.next().filter(|part| !part.is_empty())?;
+        let attribute = parts.next().filter(|part| !part.is_empty())?;
+        Some(MqttSubscription {
+            topic: topic.trim().to_string(),
+            username: username.to_string(),
+            attribute: attribute.to_string(),
+            format: parts
+                .next()
+                .map_or_else(default_payload_format, str::to_string),
+        })
+    }
+}
+
+/// Requests a client may make, by its API token or else its address. Reads
+/// and the endpoints that call the chat model have buckets of their own.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Requests refilled per minute, no limit when 0
+    pub requests_per_minute: u32,
+    /// Requests that may be made at once before the limit applies
+    pub burst: u32,
+    /// Requests per minute to endpoints calling the chat model, no limit when 0
+    pub llm_requests_per_minute: u32,
+    pub llm_burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            requests_per_minute: 0,
+            burst: 60,
+            llm_requests_per_minute: 0,
+            llm_burst: 5,
+        }
+    }
+}
+
+/// The Telegram bot, which runs when it has a token
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TelegramConfig {
+    pub bot_token: Option<String>,
+    pub api_url: String,
+    /// Muninn user of each chat id, messages from other chats are ignored
+    pub chats: HashMap<String, String>,
+}
+
+impl Default for TelegramConfig {
+    fn default() -> Self {
+        TelegramConfig {
+            bot_token: None,
+            api_url: "https://api.telegram.org".to_string(),
+            chats: HashMap::new(),
+        }
+    }
+}
+
+/// How emails and documents are split before they are embedded
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct IngestConfig {
+    /// Most characters of one chunk
+    pub chunk_chars: usize,
+    /// Characters a chunk repeats from the end of the one before it, so text
+    /// cut at a boundary is still found
+    pub chunk_overlap: usize,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        IngestConfig {
+            chunk_chars: 1500,
+            chunk_overlap: 200,
+        }
+    }
+}
+
+/// The Whisper backend transcribing audio notes
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct WhisperConfig {
+    /// `openai`, through the `[openai]` settings, or `whispercpp` for a
+    /// whisper.cpp server at `url`
+    pub backend: String,
+    pub url: Option<String>,
+    /// Model transcribing through OpenAI, the deployment on Azure
+    pub model: String,
+    /// Language spoken in the audio as ISO 639-1, detected when unset
+    pub language: Option<String>,
+}
+
+impl Default for WhisperConfig {
+    fn default() -> Self {
+        WhisperConfig {
+            backend: "openai".to_string(),
+            url: None,
+            model: "whisper-1".to_string(),
+            language: None,
+        }
+    }
+}
+
+/// Time a context request may take, overall and for each stage
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ContextConfig {
+    pub deadline_ms: u64,
+    /// Loading the history and searching it
+    pub retrieval_timeout_ms: u64,
+    /// Asking the chat model for a summary of what was found
+    pub completion_timeout_ms: u64,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        ContextConfig {
+            deadline_ms: 8000,
+            retrieval_timeout_ms: 2000,
+            completion_timeout_ms: 5000,
+        }
+    }
+}
+
+/// How often the background jobs of the server run
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct JobsConfig {
+    /// When the message search index is loaded into memory
+    pub index_load_policy: IndexLoadPolicy,
+    /// Seconds between reloads of the index when it is loaded on a schedule
+    pub index_warmup_interval_secs: u64,
+    pub attribute_expiry_interval_secs: u64,
+    /// Seconds without a message after which a conversation is summarized
+    pub thread_idle_secs: i64,
+    pub saved_search_interval_secs: u64,
+    pub metrics_interval_secs: u64,
+    /// Hour of the day, in UTC, at which the day before is summarized
+    pub daily_summary_hour: u32,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            index_load_policy: IndexLoadPolicy::Lazy,
+            index_warmup_interval_secs: 3600,
+            attribute_expiry_interval_secs: 60,
+            thread_idle_secs: 30 * 60,
+            saved_search_interval_secs: 300,
+            metrics_interval_secs: 300,
+            daily_summary_hour: 1,
+        }
+    }
+}
+
+impl Config {
+    pub fn parse(content: &str) -> Result<Config> {
+        Ok(toml::from_str(content)?)
+    }
+
+    /// Replaces settings with the variables `var` finds
+    fn override_with(&mut self, var: impl Fn(&str) -> Option<String>) {
+        if let Some(host) = var("MUNINN_HOST") {
+            self.server.host = host;
+        }
+        if let Some(port) = var("MUNINN_PORT").and_then(|val| val.parse::<u16>().ok()) {
+            self.server.port = port;
+        }
+        if let Some(socket) = var("MUNINN_SOCKET") {
+            self.server.socket = Some(PathBuf::from(socket));
+        }
+        if let Some(cert) = var("MUNINN_TLS_CERT") {
+            self.server.tls_cert = Some(PathBuf::from(cert));
+        }
+        if let Some(key) = var("MUNINN_TLS_KEY") {
+            self.server.tls_key = Some(PathBuf::from(key));
+        }
+        if let Some(bytes) = var("MUNINN_MAX_BODY_BYTES").and_then(|val| val.parse::<usize>().ok())
+        {
+            self.server.max_body_bytes = bytes;
+        }
+        if let Some(length) =
+            var("MUNINN_MAX_CONTENT_LENGTH").and_then(|val| val.parse::<usize>().ok())
+        {
+            self.server.max_content_length = length;
+        }
+        if let Some(token) = var("MUNINN_ADMIN_TOKEN").filter(|token| !token.is_empty()) {
+            self.server.admin_token = Some(token);
+        }
+        if let Some(path) = var("MESSAGE_STORAGE_PATH") {
+            self.storage.path = Some(PathBuf::from(path));
+        }
+        if let Some(url) = var("DATABASE_URL") {
+            self.storage.database_url = Some(url);
+        }
+        if let Some(url) = var("QDRANT_URL") {
+            self.storage.qdrant_url = Some(url);
+        }
+        if let Some(collection) = var("QDRANT_COLLECTION") {
+            self.storage.qdrant_collection = collection;
+        }
+        if let Some(api_key) = var("QDRANT_API_KEY") {
+            self.storage.qdrant_api_key = Some(api_key);
+        }
+        if let Some(mode) = var("MESSAGE_DEDUP").and_then(|val| DedupMode::parse(&val)) {
+            self.storage.dedup = mode;
+        }
+        if let Some(days) = var("COMPRESS_AFTER_DAYS").and_then(|val| val.parse::<u32>().ok()) {
+            self.storage.compress_after_days = days;
+        }
+        if let Some(size) = var("DAY_CACHE_SIZE").and_then(|val| val.parse::<usize>().ok()) {
+            self.storage.day_cache_size = size;
+        }
+        if let Some(key) = var("ENCRYPTION_KEY") {
+            self.storage.encryption_key = Some(key);
+        }
+        if let Some(path) = var("ENCRYPTION_KEY_FILE") {
+            self.storage.encryption_key_file = Some(PathBuf::from(path));
+        }
+        if let Some(scrub) = var("SCRUB_PII").and_then(|val| val.parse::<bool>().ok()) {
+            self.storage.scrub_pii = scrub;
+        }
+        if let Some(with_model) =
+            var("SCRUB_PII_WITH_MODEL").and_then(|val| val.parse::<bool>().ok())
+        {
+            self.storage.scrub_pii_with_model = with_model;
+        }
+        if let Some(bucket) = var("S3_BUCKET") {
+            self.storage.s3_bucket = Some(bucket);
+        }
+        if let Some(endpoint) = var("S3_ENDPOINT") {
+            self.storage.s3_endpoint = endpoint;
+        }
+        if let Some(region) = var("S3_REGION") {
+            self.storage.s3_region = region;
+        }
+        if let Some(prefix) = var("S3_PREFIX") {
+            self.storage.s3_prefix = prefix;
+        }
+        if let Some(access_key) = var("S3_ACCESS_KEY") {
+            self.storage.s3_access_key = Some(access_key);
+        }
+        if let Some(secret_key) = var("S3_SECRET_KEY") {
+            self.storage.s3_secret_key = Some(secret_key);
+        }
+        if let Some(replica) = var("STORAGE_REPLICA") {
+            self.storage.replica = Some(replica);
+        }
+        if let Some(policy) = var("RETENTION_POLICY") {
+            match RetentionPolicy::parse(&policy) {
+                Some(policy) => self.storage.retention = policy,
+                None => error!("Invalid RETENTION_POLICY \"{}\"", policy),
+            }
+        }
+        if let Some(backend) = var("CHAT_BACKEND") {
+            self.models.chat_backend = backend;
+        }
+        if let Some(model) = var("CHAT_MODEL") {
+            self.models.chat_model = model;
+        }
+        if let Some(model) = var("OLLAMA_CHAT_MODEL") {
+            self.models.ollama_chat_model = model;
+        }
+        if let Some(models) = var("ALLOWED_CHAT_MODELS") {
+            self.models.allowed_chat_models = models
+                .split(',')
+                .map(|model| model.trim().to_string())
+                .filter(|model| !model.is_empty())
+                .collect();
+        }
+        if let Some(model) = var("EMBEDDING_MODEL") {
+            self.models.embedding_model = model;
+        }
+        if let Some(background) =
+            var("BACKGROUND_EMBEDDINGS").and_then(|val| val.parse::<bool>().ok())
+        {
+            self.models.background_embeddings = background;
+        }
+        if let Some(attempts) = var("EMBEDDING_ATTEMPTS").and_then(|val| val.parse::<u32>().ok()) {
+            self.models.embedding_attempts = attempts;
+        }
+        if let Some(size) = var("EMBEDDING_CACHE_SIZE").and_then(|val| val.parse::<usize>().ok()) {
+            self.models.embedding_cache_size = size;
+        }
+        if let Some(ttl) = var("EMBEDDING_CACHE_TTL_SECS").and_then(|val| val.parse::<u64>().ok()) {
+            self.models.embedding_cache_ttl_secs = ttl;
+        }
+        if let Some(url) = var("OLLAMA_URL") {
+            self.ollama.url = url;
+        }
+        if let Some(url) = var("OPENAI_URL") {
+            self.openai.url = url;
+        }
+        if let Some(api_key) = var("OPENAI_API_KEY") {
+            self.openai.api_key = Some(api_key);
+        }
+        if let Some(api) = var("OPENAI_API_TYPE") {
+            self.openai.api = api;
+        }
+        if let Some(deployment) = var("OPENAI_DEPLOYMENT") {
+            self.openai.deployment = Some(deployment);
+        }
+        if let Some(api_version) = var("OPENAI_API_VERSION") {
+            self.openai.api_version = api_version;
+        }
+        if let Some(host) = var("MQTT_HOST") {
+            self.mqtt.host = Some(host);
+        }
+        if let Some(port) = var("MQTT_PORT").and_then(|val| val.parse::<u16>().ok()) {
+            self.mqtt.port = port;
+        }
+        if let Some(url) = var("MQTT_BROKER_URL") {
+            self.mqtt.broker_url = Some(url);
+        }
+        if let Some(username) = var("MQTT_USERNAME") {
+            self.mqtt.username = Some(username);
+        }
+        if let Some(password) = var("MQTT_PASSWORD") {
+            self.mqtt.password = Some(password);
+        }
+        if let Some(subscriptions) = var("MQTT_SUBSCRIPTIONS") {
+            self.mqtt.subscriptions = subscriptions
+                .split(',')
+                .filter_map(MqttSubscription::parse)
+                .collect();
+        }
+        if let Some(rpm) = var("RATE_LIMIT_RPM").and_then(|val| val.parse::<u32>().ok()) {
+            self.rate_limit.requests_per_minute = rpm;
+        }
+        if let Some(burst) = var("RATE_LIMIT_BURST").and_then(|val| val.parse::<u32>().ok()) {
+            self.rate_limit.burst = burst;
+        }
+        if let Some(rpm) = var("RATE_LIMIT_LLM_RPM").and_then(|val| val.parse::<u32>().ok()) {
+            self.rate_limit.llm_requests_per_minute = rpm;
+        }
+        if let Some(burst) = var("RATE_LIMIT_LLM_BURST").and_then(|val| val.parse::<u32>().ok()) {
+            self.rate_limit.llm_burst = burst;
+        }
+        if let Some(token) = var("TELEGRAM_BOT_TOKEN") {
+            self.telegram.bot_token = Some(token);
+        }
+        if let Some(url) = var("TELEGRAM_API_URL") {
+            self.telegram.api_url = url;
+        }
+        if let Some(chats) = var("TELEGRAM_CHATS") {
+            self.telegram.chats = chats
+                .split(',')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(chat, username)| (chat.trim().to_string(), username.trim().to_string()))
+                .collect();
+        }
+        if let Some(chars) = var("INGEST_CHUNK_CHARS").and_then(|val| val.parse::<usize>().ok()) {
+            self.ingest.chunk_chars = chars;
+        }
+        if let Some(overlap) = var("INGEST_CHUNK_OVERLAP").and_then(|val| val.parse::<usize>().ok())
+        {
+            self.ingest.chunk_overlap = overlap;
+        }
+        if let Some(backend) = var("WHISPER_BACKEND") {
+            self.whisper.backend = backend;
+        }
+        if let Some(url) = var("WHISPER_URL") {
+            self.whisper.url = Some(url);
+        }
+        if let Some(model) = var("WHISPER_MODEL") {
+            self.whisper.model = model;
+        }
+        if let Some(language) = var("WHISPER_LANGUAGE") {
+            self.whisper.language = Some(language);
+        }
+        if let Some(ms) = var("CONTEXT_DEADLINE_MS").and_then(|val| val.parse::<u64>().ok()) {
+            self.context.deadline_ms = ms;
+        }
+        if let Some(ms) =
+            var("CONTEXT_RETRIEVAL_TIMEOUT_MS").and_then(|val| val.parse::<u64>().ok())
+        {
+            self.context.retrieval_timeout_ms = ms;
+        }
+        if let Some(ms) =
+            var("CONTEXT_COMPLETION_TIMEOUT_MS").and_then(|val| val.parse::<u64>().ok())
+        {
+            self.context.completion_timeout_ms = ms;
+        }
+        if let Some(policy) = var("INDEX_LOAD_POLICY").and_then(|val| IndexLoadPolicy::parse(&val))
+        {
+            self.jobs.index_load_policy = policy;
+        }
+        if let Some(secs) =
+            var("INDEX_WARMUP_INTERVAL_SECS").and_then(|val| val.parse::<u64>().ok())
+        {
+            self.jobs.index_warmup_interval_secs = secs;
+        }
+        if let Some(secs) =
+            var("ATTRIBUTE_EXPIRY_INTERVAL_SECS").and_then(|val| val.parse::<u64>().ok())
+        {
+            self.jobs.attribute_expiry_interval_secs = secs;
+        }
+        if let Some(secs) = var("THREAD_IDLE_SECS").and_then(|val| val.parse::<i64>().ok()) {
+            self.jobs.thread_idle_secs = secs;
+        }
+        if let Some(secs) =
+            var("SAVED_SEARCH_INTERVAL_SECS").and_then(|val| val.parse::<u64>().ok())
+        {
+            self.jobs.saved_search_interval_secs = secs;
+        }
+        if let Some(secs) = var("METRICS_INTERVAL_SECS").and_then(|val| val.parse::<u64>().ok()) {
+            self.jobs.metrics_interval_secs = secs;
+        }
+        if let Some(hour) = var("DAILY_SUMMARY_HOUR").and_then(|val| val.parse::<u32>().ok()) {
+            self.jobs.daily_summary_hour = hour;
+        }
+    }
+}
+
+/// Reads the config file and applies the environment on top. A missing
+/// `muninn.toml` means defaults, a missing MUNINN_CONFIG file is an error.
+pub fn load() -> Result<Config> {
+    let (path, required) = match std::env::var("MUNINN_CONFIG") {
+        Ok(path) => (PathBuf::from(path), true),
+        Err(_) => (PathBuf::from("muninn.toml"), false),
+    };
+    let mut config = match std::fs::read_to_string(&path) {
+        Ok(content) => {
+            info!("Loading configuration from {}", path.display());
+            Config::parse(&content)
+                .with_context(|| format!("Invalid configuration in {}", path.display()))?
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => Config::default(),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Error reading {}", path.display()));
+        }
+    };
+    config.override_with(|name| std::env::var(name).ok());
+    Ok(config)
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Loads the configuration once at startup, failing on an invalid file
+pub fn init() -> Result<&'static Config> {
+    let config = load()?;
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+/// The configuration loaded by `init`, loaded on first use elsewhere (tests)
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(|| {
+        load().unwrap_or_else(|e| {
+            error!("{:#}, using defaults", e);
+            let mut config = Config::default();
+            config.override_with(|name| std::env::var(name).ok());
+            config
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_settings_are_overridden_by_env() {
+        let mut config = Config::parse(
+            r#"
+            [server]
+            port = 9090
+
+            [models]
+            chat_backend = "ollama"
+
+            [mqtt]
+            host = "broker.local"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.server.port, 9090);
+        assert_eq!(config.server.host, "0.0.0.0");
+        assert_eq!(config.models.chat_model, "gpt-4-turbo-preview");
+        assert_eq!(config.mqtt.port, 1883);
+
+        config.override_with(|name| match name {
+            "MUNINN_HOST" => Some("127.0.0.1".to_string()),
+            "CHAT_BACKEND" => Some("openai".to_string()),
+            "MQTT_PORT" => Some("8883".to_string()),
+            "MQTT_USERNAME" => Some("muninn".to_string()),
+            "MESSAGE_DEDUP" => Some("reject".to_string()),
+            "RETENTION_POLICY" => Some("summarize:30".to_string()),
+            "ALLOWED_CHAT_MODELS" => Some("gpt-4o, gpt-4o-mini".to_string()),
+            "TELEGRAM_CHATS" => Some("12345=alice, -100987=family".to_string()),
+            "INGEST_CHUNK_OVERLAP" => Some("0".to_string()),
+            "WHISPER_BACKEND" => Some("whispercpp".to_string()),
+            "MUNINN_ADMIN_TOKEN" => Some("".to_string()),
+            "CONTEXT_DEADLINE_MS" => Some("3000".to_string()),
+            "INDEX_LOAD_POLICY" => Some("scheduled".to_string()),
+            "MQTT_SUBSCRIPTIONS" => Some(
+                "owntracks/+/phone=alice:location:owntracks, homeassistant/sensor/+/state={1}:x"
+                    .to_string(),
+            ),
+            _ => None,
+        });
+        assert_eq!(config.server.host, "127.0.0.1");
+        assert_eq!(config.server.port, 9090);
+        assert_eq!(config.models.chat_backend, "openai");
+        assert_eq!(config.mqtt.host.as_deref(), Some("broker.local"));
+        assert_eq!(config.mqtt.port, 8883);
+        assert_eq!(config.mqtt.username.as_deref(), Some("muninn"));
+        assert_eq!(
+            config.mqtt.subscriptions[0],
+            MqttSubscription {
+                topic: "owntracks/+/phone".to_string(),
+                username: "alice".to_string(),
+                attribute: "location".to_string(),
+                format: "owntracks".to_string(),
+            }
+        );
+        assert_eq!(config.mqtt.subscriptions[1].format, "value");
+        assert_eq!(config.storage.dedup, DedupMode::Reject);
+        assert_eq!(
+            config.storage.retention,
+            RetentionPolicy::Summarize { days: 30 }
+        );
+        assert_eq!(RetentionPolicy::parse("keep:30"), None);
+        assert!(config.models.allows_chat_model("gpt-4o-mini"));
+        assert!(config.models.allows_chat_model("gpt-4-turbo-preview"));
+        assert!(!config.models.allows_chat_model("gemma:2b"));
+        assert_eq!(config.telegram.chats["-100987"], "family");
+        assert_eq!(config.telegram.chats.len(), 2);
+        assert_eq!(config.ingest.chunk_overlap, 0);
+        assert_eq!(config.ingest.chunk_chars, 1500);
+        assert_eq!(config.whisper.backend, "whispercpp");
+        assert_eq!(config.whisper.model, "whisper-1");
+        assert_eq!(config.server.admin_token, None);
+        assert_eq!(config.context.deadline_ms, 3000);
+        assert_eq!(config.context.retrieval_timeout_ms, 2000);
+        assert_eq!(config.jobs.index_load_policy, IndexLoadPolicy::Scheduled);
+        assert_eq!(config.jobs.daily_summary_hour, 1);
+
+        assert!(Config::parse("[server]\nport = \"eighty\"").is_err());
+        let delete = Config::parse("[storage]\nretention = { mode = \"delete\", days = 90 }");
+        assert_eq!(
+            delete.unwrap().storage.retention,
+            RetentionPolicy::Delete { days: 90 }
+        );
+    }
+
+    #[test]
+    fn test_example_matches_defaults() {
+        let example = Config::parse(include_str!("../muninn.toml.example")).unwrap();
+        assert_eq!(example, Config::default());
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::{anyhow, bail, Result};
+use chrono::NaiveDate;
+
+use crate::{
+    config,
+    hub::HubEvent,
+    repos::{self, messages::HistoryQuery, summaries::SummaryModel},
+    services::{
+        chat::{ChatRequest, ChatResponse, ChatService, SaveError, SearchRequest, SearchResponse},
+        summary::SummaryService,
+    },
+    Resources,
+};
+
+// Messages read from the history at once
+const HISTORY_PAGE: usize = 1000;
+
+/// Muninn without the HTTP server, for bots that keep their memory in the
+/// same process. It uses the storage and models of the configuration, so a
+/// server and an embedding program can share one storage root.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use muninn::{services::chat::SearchRequest, Muninn};
+///
+/// let muninn = Muninn::open().await?;
+/// let results = muninn
+///     .search("alice", &SearchRequest::new("where did I park"))
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Muninn {
+    resources: Resources,
+}
+
+impl Muninn {
+    /// Reads the configuration like the server does, from `muninn.toml` or
+    /// MUNINN_CONFIG and the environment, and brings the storage up to date
+    pub async fn open() -> Result<Self> {
+        config::init()?;
+        repos::migrations::migrate(
+            &repos::get_storage_root(),
+            &repos::migrations::migrations(),
+            &repos::migrations::MigrationOptions::from_env(),
+        )
+        .map_err(|_| anyhow!("Storage migration failed"))?;
+        Ok(Muninn {
+            resources: Resources::open().await?,
+        })
+    }
+
+    fn chat_service(&self) -> ChatService {
+        let resources = &self.resources;
+        ChatService::from_resources(resources)
+    }
+
+    /// Embeds and stores a message, checked like one sent to the server
+    pub async fn save(&self, username: &str, chat: ChatRequest) -> Result<ChatResponse> {
+        chat.validate(self.resources.config.server.max_content_length)?;
+        let saved = self
+            .chat_service()
+            .save_chat(username, chat, self.resources.config.storage.dedup)
+            .await;
+        match saved {
+            Ok(chat) => {
+                self.resources.event_hub.publish(HubEvent::MessageSaved {
+                    username: username.to_string(),
+                    hash: chat.hash.clone(),
+                    role: chat.role.clone(),
+                });
+                Ok(chat)
+            }
+            Err(SaveError::Duplicate(existing)) => {
+                bail!("{} already has this message as {}", username, existing.hash)
+            }
+            Err(SaveError::Failed) => bail!("Error saving chat"),
+        }
+    }
+
+    /// The user's messages that match the request, best first
+    pub async fn search(
+        &self,
+        username: &str,
+        request: &SearchRequest,
+    ) -> Result<Vec<SearchResponse>> {
+        crate::services::validation::content(
+            "content",
+            &request.content,
+            self.resources.config.server.max_content_length,
+        )?;
+        self.chat_service()
+            .search_chat(username, request)
+            .await
+            .map_err(|_| anyhow!("Error searching chat"))
+    }
+
+    /// The messages the user saved on `date`, oldest first
+    pub async fn history(&self, username: &str, date: NaiveDate) -> Result<Vec<ChatResponse>> {
+        let chat_service = self.chat_service();
+        let mut query = HistoryQuery {
+            limit: HISTORY_PAGE,
+            from_date: Some(date),
+            to_date: Some(date),
+            ..HistoryQuery::default()
+        };
+        let mut messages = vec![];
+        loop {
+            let page = chat_service
+                .get_history(username, &query)
+                .await
+                .map_err(|_| anyhow!("Error getting history"))?;
+            messages.extend(page.messages);
+            match page.next_offset {
+                Some(offset) => query.offset = offset,
+                None => return Ok(messages),
+            }
+        }
+    }
+
+    /// The summary of the user's `date`, written by the chat model when
+    /// there is none yet
+    pub async fn summarize(&self, username: &str, date: NaiveDate) -> Result<SummaryModel> {
+        let resources = &self.resources;
+        let summary_service = SummaryService {
+            message_repo: resources.message_repo.clone(),
+            chat_client: resources.chat_client.clone(),
+            summary_repo: resources.summary_repo.clone(),
+            embedding_client: resources.embeddings_client.clone(),
+        };
+        let today = chrono::Utc::now().date_naive();
+        let summary = summary_service
+            .get_summary(username, date, today, None)
+            .await
+            .map_err(|_| anyhow!("Error summarizing {}", date))?;
+        Ok(summary.without_embedding())
+    }
+}
@@ -0,0 +1,164 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::error::ApiError;
+use crate::{
+    clients::embeddings,
+    services::{admin::AdminService, dedup::DedupService, reembed::ReembedService},
+    Resources,
+};
+
+fn admin_service(resources: &Resources) -> AdminService {
+    AdminService {
+        message_repo: resources.message_repo.clone(),
+        embedding_client: resources.embeddings_client.clone(),
+        event_hub: resources.event_hub.clone(),
+        job_statuses: resources.job_statuses.clone(),
+    }
+}
+
+pub async fn get_overview(resources: web::Data<Resources>) -> Result<HttpResponse, ApiError> {
+    let overview = admin_service(&resources)
+        .overview()
+        .await
+        .map_err(|_| ApiError::internal("Error building admin overview"))?;
+    Ok(HttpResponse::Ok().json(overview))
+}
+
+/// Every user the instance holds messages for
+pub async fn get_users(resources: web::Data<Resources>) -> Result<HttpResponse, ApiError> {
+    let users = admin_service(&resources)
+        .users()
+        .await
+        .map_err(|_| ApiError::internal("Error listing users"))?;
+    Ok(HttpResponse::Ok().json(users))
+}
+
+pub async fn get_stats(resources: web::Data<Resources>) -> Result<HttpResponse, ApiError> {
+    let stats = admin_service(&resources)
+        .stats()
+        .await
+        .map_err(|_| ApiError::internal("Error collecting instance stats"))?;
+    Ok(HttpResponse::Ok().json(stats))
+}
+
+/// Bytes the user's folder takes, by what the files hold
+pub async fn get_storage(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+) -> Result<HttpResponse, ApiError> {
+    let username = &params.0.clone();
+    let usage = resources
+        .storage_usage_repo
+        .lock()
+        .await
+        .get_usage(username)
+        .map_err(|_| ApiError::internal("Error measuring storage"))?
+        .ok_or_else(|| ApiError::NotFound(format!("no data for {}", username)))?;
+    Ok(HttpResponse::Ok().json(usage))
+}
+
+#[derive(Serialize)]
+pub struct WarmupResponse {
+    pub indexed: usize,
+    pub elapsed_ms: u128,
+}
+
+pub async fn warm_up(resources: web::Data<Resources>) -> Result<HttpResponse, ApiError> {
+    let started = std::time::Instant::now();
+    let indexed = resources.message_repo.lock().await.warm_up().await?;
+    let elapsed_ms = started.elapsed().as_millis();
+    info!(
+        "Warmed up index with {} messages in {}ms",
+        indexed, elapsed_ms
+    );
+    Ok(HttpResponse::Ok().json(WarmupResponse {
+        indexed,
+        elapsed_ms,
+    }))
+}
+
+/// Depth and counters of the background embedding queue
+pub async fn get_embedding_queue(
+    resources: web::Data<Resources>,
+) -> Result<HttpResponse, ApiError> {
+    match &resources.embedding_queue {
+        Some(queue) => Ok(HttpResponse::Ok().json(queue.stats())),
+        None => Err(ApiError::NotFound(
+            "background embeddings are off".to_string(),
+        )),
+    }
+}
+
+/// Removes the messages the user has stored more than once, keeping the
+/// oldest copy
+pub async fn dedupe(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+) -> Result<HttpResponse, ApiError> {
+    let dedup_service = DedupService {
+        message_repo: resources.message_repo.clone(),
+    };
+
+    let report = dedup_service
+        .dedupe(&params.0)
+        .await
+        .map_err(|_| ApiError::internal("Error removing duplicate messages"))?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[derive(Deserialize)]
+pub struct ReembedQuery {
+    /// Provider to embed with, the configured embeddings client when absent
+    pub model: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ReembedResponse {
+    pub model: String,
+    pub total: usize,
+}
+
+/// Starts re-embedding every message of the user in the background, the
+/// progress is published as events
+pub async fn reembed(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    query: web::Query<ReembedQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let (client, model) = match &query.model {
+        Some(model) => {
+            let client = embeddings::client_for(model).ok_or_else(|| {
+                ApiError::invalid("model", format!("unknown embeddings model {}", model))
+            })?;
+            (client, model.clone())
+        }
+        None => {
+            let client = resources.embeddings_client.clone();
+            let model = client.lock().await.model();
+            (client, model)
+        }
+    };
+    let reembed_service = ReembedService {
+        message_repo: resources.message_repo.clone(),
+        event_hub: resources.event_hub.clone(),
+    };
+
+    let username = params.0.clone();
+    let total = reembed_service
+        .get_embeddable(&username)
+        .await
+        .map_err(|_| ApiError::internal("Error reading messages to re-embed"))?
+        .len();
+
+    let response = ReembedResponse {
+        model: model.clone(),
+        total,
+    };
+    tokio::spawn(async move {
+        let _ = reembed_service.reembed(&username, &model, client).await;
+    });
+
+    Ok(HttpResponse::Accepted().json(response))
+}
@@ -0,0 +1,242 @@
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header,
+    middleware::Next,
+    web, HttpMessage, HttpResponse, ResponseError,
+};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::info;
+
+use super::error::ApiError;
+use crate::{
+    repos::tokens::{TokenModel, TokenScope},
+    Resources,
+};
+
+/// The part of the API `path` belongs to, when it needs a token
+enum Protected<'a> {
+    /// Data of one user, open to that user's tokens and to admins
+    User(&'a str),
+    /// Token management and operator routes, open to admins only
+    Admin,
+}
+
+// Routes anyone may read, everything else under the API needs a token
+const PUBLIC_PATHS: [&str; 2] = ["/api/v1/openapi.json", "/api/v1/docs"];
+
+fn protected(path: &str) -> Option<Protected<'_>> {
+    if PUBLIC_PATHS.contains(&path) {
+        return None;
+    }
+    // per-user gauges
+    if path == "/metrics" {
+        return Some(Protected::Admin);
+    }
+    let mut segments = path.strip_prefix("/api/v1/")?.split('/');
+    match (segments.next(), segments.next()) {
+        (Some("auth" | "admin"), _) => Some(Protected::Admin),
+        (_, Some(username)) if !username.is_empty() => Some(Protected::User(username)),
+        // paths without a user are for operators
+        _ => Some(Protected::Admin),
+    }
+}
+
+fn is_allowed(path: &str, token: Option<&TokenModel>) -> bool {
+    match (protected(path), token) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(_), Some(token)) if token.scope == TokenScope::Admin => true,
+        (Some(Protected::User(username)), Some(token)) => token.username == username,
+        (Some(Protected::Admin), Some(_)) => false,
+    }
+}
+
+/// Rejects requests to protected routes unless their bearer token grants
+/// access to the user in the path
+pub async fn require_token(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let admin_token = crate::config::get().server.admin_token.clone();
+    check_token(admin_token, req, next).await
+}
+
+// `require_token` with the admin token given, so tests need not set it for
+// the whole process
+async fn check_token(
+    admin_token: Option<String>,
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let admin_token = match admin_token {
+        Some(admin_token) => admin_token,
+        None => return next.call(req).await.map(|res| res.map_into_boxed_body()),
+    };
+
+    let secret = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|secret| secret.trim().to_string());
+    let token = match secret {
+        Some(secret) if secret == admin_token => Some(TokenModel {
+            id: "admin".to_string(),
+            username: "admin".to_string(),
+            name: "MUNINN_ADMIN_TOKEN".to_string(),
+            scope: TokenScope::Admin,
+            created_at: 0,
+        }),
+        Some(secret) => match req.app_data::<web::Data<Resources>>() {
+            Some(resources) => resources.token_repo.lock().await.find_token(&secret),
+            None => None,
+        },
+        None => None,
+    };
+
+    if !is_allowed(req.path(), token.as_ref()) {
+        let error = match token {
+            Some(_) => ApiError::Forbidden,
+            None => ApiError::Unauthorized,
+        };
+        return Ok(req.into_response(error.error_response()));
+    }
+    // for the rate limit
+    if let Some(token) = token {
+        req.extensions_mut().insert(token);
+    }
+    next.call(req).await.map(|res| res.map_into_boxed_body())
+}
+
+#[derive(Deserialize)]
+pub struct TokenRequest {
+    pub name: String,
+    #[serde(default = "default_scope")]
+    pub scope: TokenScope,
+}
+
+fn default_scope() -> TokenScope {
+    TokenScope::User
+}
+
+pub async fn create_token(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    payload: web::Json<TokenRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let username = &params.0.clone();
+    let (secret, token) = resources
+        .token_repo
+        .lock()
+        .await
+        .create_token(username, &payload.name, payload.scope)
+        .map_err(|_| ApiError::internal("Error creating token"))?;
+    info!("Issued token {} for {}", token.id, username);
+    Ok(HttpResponse::Ok().json(json!({ "token": secret, "details": token })))
+}
+
+pub async fn get_tokens(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+) -> Result<HttpResponse, ApiError> {
+    let username = &params.0.clone();
+    let tokens = resources
+        .token_repo
+        .lock()
+        .await
+        .get_tokens(username)
+        .map_err(|_| ApiError::internal("Error getting tokens"))?;
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+pub async fn revoke_token(
+    resources: web::Data<Resources>,
+    params: web::Path<(String, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let (username, id) = params.into_inner();
+    resources
+        .token_repo
+        .lock()
+        .await
+        .revoke_token(&username, &id)
+        .map_err(|_| ApiError::NotFound(format!("no token {}", id)))?;
+    info!("Revoked token {} of {}", id, username);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_only_open_their_own_user() {
+        let token = |username: &str, scope| TokenModel {
+            id: "id".to_string(),
+            username: username.to_string(),
+            name: "test".to_string(),
+            scope,
+            created_at: 0,
+        };
+        let alice = token("alice", TokenScope::User);
+        let admin = token("root", TokenScope::Admin);
+
+        assert!(is_allowed("/api/v1/chat/alice/search", Some(&alice)));
+        assert!(!is_allowed("/api/v1/chat/bob/search", Some(&alice)));
+        assert!(!is_allowed("/api/v1/chat/alice", None));
+        assert!(is_allowed("/api/v1/chat/bob", Some(&admin)));
+        assert!(!is_allowed("/api/v1/webhooks/bob", Some(&alice)));
+        assert!(is_allowed("/api/v1/ingest/alice/email", Some(&alice)));
+        assert!(!is_allowed("/api/v1/ingest/bob/email", None));
+        assert!(!is_allowed("/api/v1/feeds/bob", Some(&alice)));
+        assert!(is_allowed("/api/v1/reminder/alice/1", Some(&alice)));
+        assert!(!is_allowed("/api/v1/retention/bob", Some(&alice)));
+        assert!(!is_allowed("/api/v1/auth/alice/tokens", Some(&alice)));
+        assert!(is_allowed("/api/v1/auth/alice/tokens", Some(&admin)));
+        assert!(!is_allowed("/api/v1/admin/users", Some(&alice)));
+        assert!(is_allowed("/api/v1/admin/stats", Some(&admin)));
+        assert!(!is_allowed("/api/v1/summary/alice", None));
+        assert!(is_allowed("/api/v1/summary/alice", Some(&alice)));
+        assert!(!is_allowed("/api/v1/attribute/bob/import", Some(&alice)));
+        assert!(!is_allowed("/api/v1/memory/bob/forget", None));
+        assert!(!is_allowed("/api/v1/contacts/bob", Some(&alice)));
+        assert!(!is_allowed("/api/v1/usage/bob", None));
+        assert!(!is_allowed("/metrics", Some(&alice)));
+        assert!(is_allowed("/metrics", Some(&admin)));
+        assert!(is_allowed("/api/v1/openapi.json", None));
+        assert!(is_allowed("/api/v1/docs", None));
+        assert!(is_allowed("/api/v1/events/alice/ws", Some(&alice)));
+        assert!(!is_allowed("/api/v1/events/bob/stream", Some(&alice)));
+        assert!(!is_allowed("/api/v1/events/bob/ws", None));
+        assert!(!is_allowed("/api/v1/import/bob/chatgpt", None));
+        assert!(!is_allowed("/api/v1/import/bob", Some(&alice)));
+    }
+
+    #[actix::test]
+    async fn test_exports_need_a_token() {
+        use actix_web::{middleware::from_fn, test, App};
+
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(|req, next| {
+                    check_token(Some("test-admin-token".to_string()), req, next)
+                }))
+                .route("/api/v1/export/{username}", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/export/alice")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/export/alice")
+            .insert_header((header::AUTHORIZATION, "Bearer test-admin-token"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(res.status().is_success());
+    }
+}
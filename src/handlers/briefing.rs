@@ -0,0 +1,31 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use super::error::ApiError;
+use crate::{
+    handlers::caching::{cached_json, REVALIDATE},
+    services::briefing::BriefingService,
+    Resources,
+};
+
+pub async fn get_briefing(
+    req: HttpRequest,
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+) -> Result<HttpResponse, ApiError> {
+    let resources = resources.into_inner();
+    let briefing_service = BriefingService {
+        message_repo: resources.message_repo.clone(),
+        chat_client: resources.chat_client.clone(),
+        thread_repo: resources.thread_repo.clone(),
+        contact_repo: resources.contact_repo.clone(),
+        briefing_repo: resources.briefing_repo.clone(),
+    };
+
+    let username = &params.0.clone();
+    let today = chrono::Utc::now().date_naive();
+    let briefing = briefing_service
+        .get_briefing(username, today)
+        .await
+        .map_err(|_| ApiError::internal("Error composing briefing"))?;
+    Ok(cached_json(&req, &briefing, REVALIDATE))
+}
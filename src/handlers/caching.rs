@@ -0,0 +1,87 @@
+use actix_web::{
+    http::header::{self, HeaderValue},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use super::error::ApiError;
+
+// Days in the past only change when something is forgotten
+pub const PAST_DAY: &str = "private, max-age=86400";
+// Content that keeps changing, clients must revalidate every time
+pub const REVALIDATE: &str = "private, no-cache";
+
+fn matches_etag(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(',')
+                .any(|candidate| candidate.trim() == etag || candidate.trim() == "*")
+        })
+        .unwrap_or(false)
+}
+
+/// Serializes `value` with an ETag and `cache_control`, answering with
+/// `304 Not Modified` when the client already holds the same body
+pub fn cached_json<T: Serialize>(
+    req: &HttpRequest,
+    value: &T,
+    cache_control: &str,
+) -> HttpResponse {
+    let body = match serde_json::to_vec(value) {
+        Ok(body) => body,
+        Err(e) => {
+            return ApiError::internal(format!("Error serializing response: {}", e))
+                .error_response()
+        }
+    };
+    let etag = format!("\"{:x}\"", Sha256::digest(&body));
+    let cache_control = HeaderValue::from_str(cache_control).unwrap();
+
+    if matches_etag(req, &etag) {
+        return HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .insert_header((header::CACHE_CONTROL, cache_control))
+            .finish();
+    }
+    HttpResponse::Ok()
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::CACHE_CONTROL, cache_control))
+        .content_type("application/json")
+        .body(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test::TestRequest};
+
+    #[test]
+    fn test_matching_etag_is_not_modified() {
+        let value = vec!["hello".to_string()];
+        let first = cached_json(&TestRequest::default().to_http_request(), &value, PAST_DAY);
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(
+            first.headers().get(header::CACHE_CONTROL).unwrap(),
+            PAST_DAY
+        );
+
+        let etag = first.headers().get(header::ETAG).unwrap().clone();
+        let req = TestRequest::default()
+            .insert_header((header::IF_NONE_MATCH, etag))
+            .to_http_request();
+        assert_eq!(
+            cached_json(&req, &value, PAST_DAY).status(),
+            StatusCode::NOT_MODIFIED
+        );
+
+        let changed = vec!["changed".to_string()];
+        assert_eq!(
+            cached_json(&req, &changed, PAST_DAY).status(),
+            StatusCode::OK
+        );
+    }
+}
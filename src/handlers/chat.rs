@@ -1,101 +1,298 @@
 use actix_web::{web, HttpResponse};
-use tracing::error;
 
+use super::error::ApiError;
 use crate::{
-    services::chat::{ChatRequest, ChatService, SearchRequest},
+    hub::HubEvent,
+    repos::presets::ContextPreset,
+    services::{
+        chat::{
+            ChatRequest, ChatResponse, ChatService, CompleteError, CompleteRequest, ContextQuery,
+            DeleteParams, HistoryParams, SaveError, SearchRequest,
+        },
+        collections::{CollectionChatPath, CollectionPath, CollectionService},
+        context::message_tokens,
+        feedback::{FeedbackRequest, FeedbackService},
+        latency::LatencyBudget,
+        personas::PersonaService,
+        validation,
+    },
     Resources,
 };
 
 pub async fn get_chat(
     resources: web::Data<Resources>,
-    params: web::Path<(String, String)>,
-) -> HttpResponse {
+    params: web::Path<CollectionChatPath>,
+) -> Result<HttpResponse, ApiError> {
     let resources = resources.into_inner();
-    let chat_service = ChatService {
-        embedding_client: resources.embeddings_client.clone(),
-        message_repo: resources.message_repo.clone(),
-    };
-    let username = &params.0.clone();
-    let id = &params.1.clone();
-    let chat = chat_service.get_chat(username, id).await;
-    let chat = match chat {
-        Ok(chat) => chat,
-        Err(_) => {
-            error!("Error getting chat");
-            return HttpResponse::InternalServerError().finish();
-        }
-    };
-    HttpResponse::Ok().json(chat)
+    let chat_service = ChatService::from_resources(&resources);
+    let username = params.storage_key().ok_or_else(invalid_collection)?;
+    let chat = chat_service.get_chat(&username, &params.id).await?;
+    Ok(HttpResponse::Ok().json(chat))
+}
+
+pub async fn delete_chat(
+    resources: web::Data<Resources>,
+    params: web::Path<CollectionChatPath>,
+    query: web::Query<DeleteParams>,
+) -> Result<HttpResponse, ApiError> {
+    let resources = resources.into_inner();
+    let chat_service = ChatService::from_resources(&resources);
+    let username = params.storage_key().ok_or_else(invalid_collection)?;
+    chat_service
+        .delete_chat(&username, &params.id, query.mode)
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn get_history(
+    resources: web::Data<Resources>,
+    params: web::Path<CollectionPath>,
+    query: web::Query<HistoryParams>,
+) -> Result<HttpResponse, ApiError> {
+    let resources = resources.into_inner();
+    let chat_service = ChatService::from_resources(&resources);
+    let query = query
+        .to_query()
+        .ok_or_else(|| ApiError::invalid("from_date", "Dates must be YYYY-MM-DD"))?;
+
+    let username = params.storage_key().ok_or_else(invalid_collection)?;
+
+    let history = chat_service
+        .get_history(&username, &query)
+        .await
+        .map_err(|_| ApiError::internal("Error getting chat history"))?;
+    Ok(HttpResponse::Ok().json(history))
 }
 
 pub async fn search_chat(
     resources: web::Data<Resources>,
-    params: web::Path<(String,)>,
+    params: web::Path<CollectionPath>,
     payload: web::Json<SearchRequest>,
-) -> HttpResponse {
+) -> Result<HttpResponse, ApiError> {
     let resources = resources.into_inner();
-    let chat_service = ChatService {
-        embedding_client: resources.embeddings_client.clone(),
-        message_repo: resources.message_repo.clone(),
-    };
-    let username = &params.0.clone();
-    let query = &payload.content.clone();
-    let chat = chat_service.search_chat(username, query).await;
-
-    let chat = match chat {
-        Ok(chat) => chat,
-        Err(_) => {
-            error!("Error searching chat");
-            return HttpResponse::InternalServerError().finish();
-        }
-    };
-    HttpResponse::Ok().json(chat)
+    let chat_service = ChatService::from_resources(&resources);
+    let username = params.storage_key().ok_or_else(invalid_collection)?;
+    validation::content(
+        "content",
+        &payload.content,
+        resources.config.server.max_content_length,
+    )?;
+    payload
+        .dates()
+        .ok_or_else(|| ApiError::invalid("from_date", "Dates must be YYYY-MM-DD"))?;
+    let chat = chat_service
+        .search_chat(&username, &payload)
+        .await
+        .map_err(|_| ApiError::internal("Error searching chat"))?;
+    Ok(HttpResponse::Ok().json(chat))
 }
 
 
 pub async fn get_context_with(
     resources: web::Data<Resources>,
-    params: web::Path<(String,)>,
+    params: web::Path<CollectionPath>,
+    query: web::Query<ContextQuery>,
     payload: web::Json<ChatRequest>,
-) -> HttpResponse {
+) -> Result<HttpResponse, ApiError> {
+    let budget = LatencyBudget::from_config(&resources.config.context);
     let resources = resources.into_inner();
-    let chat_service = ChatService {
-        embedding_client: resources.embeddings_client.clone(),
-        message_repo: resources.message_repo.clone(),
+    let username = &params.username;
+    let key = params.storage_key().ok_or_else(invalid_collection)?;
+    validation::content(
+        "content",
+        &payload.content,
+        resources.config.server.max_content_length,
+    )?;
+    let preset = match &query.preset {
+        Some(name) => resources
+            .preset_repo
+            .lock()
+            .await
+            .get_preset(username, name)
+            .map_err(|_| ApiError::NotFound(format!("no context preset named {}", name)))?,
+        None => ContextPreset::default(),
     };
-    let username = &params.0.clone();
-    let chat_request = payload.into_inner();
-    let chat = chat_service.get_context(username, &chat_request.content).await;
-
-    let chat = match chat {
-        Ok(chat) => chat,
-        Err(_) => {
-            error!("Error getting chat context");
-            return HttpResponse::InternalServerError().finish();
+    let persona = match &query.persona {
+        Some(name) => {
+            let persona_service = PersonaService {
+                attribute_repo: resources.user_attributes_repo.clone(),
+            };
+            let persona = persona_service
+                .get_persona(username, name)
+                .await
+                .map_err(|_| ApiError::NotFound(format!("no persona named {}", name)))?;
+            Some(persona)
         }
+        None => None,
     };
-    HttpResponse::Ok().json(chat)
+    let chat_service = ChatService::from_resources(&resources);
+    let chat_request = payload.into_inner();
+    if let Some(max_tokens) = query.max_tokens {
+        // the persona's prompt is part of the context and of its budget
+        let persona = persona.map(|persona| persona.system_message());
+        let reserved = persona
+            .as_ref()
+            .map(|persona| message_tokens("system", persona))
+            .unwrap_or(0);
+        let mut packed = chat_service
+            .pack_context(
+                &key,
+                &chat_request.content,
+                &preset,
+                max_tokens.saturating_sub(reserved),
+            )
+            .await
+            .map_err(|_| ApiError::internal("Error packing chat context"))?;
+        if let Some(persona) = persona.filter(|_| reserved <= max_tokens) {
+            packed.messages.insert(
+                0,
+                ChatResponse::new("system".to_string(), persona, "".to_string()),
+            );
+            packed.used_tokens += reserved;
+        }
+        packed.max_tokens = max_tokens;
+        return Ok(HttpResponse::Ok().json(packed));
+    }
+    let mut chat = chat_service
+        .get_context(&key, &chat_request.content, &preset, &budget)
+        .await
+        .map_err(|_| ApiError::internal("Error getting chat context"))?;
+    if let Some(persona) = persona {
+        chat.insert(
+            0,
+            ChatResponse::new("system".to_string(), persona.system_message(), "".to_string()),
+        );
+    }
+    Ok(HttpResponse::Ok().json(chat))
 }
 
 pub async fn save_chat(
     resources: web::Data<Resources>,
-    params: web::Path<(String,)>,
+    params: web::Path<CollectionPath>,
     payload: web::Json<ChatRequest>,
-) -> HttpResponse {
-    let username = &params.0.clone();
+) -> Result<HttpResponse, ApiError> {
     let resources = resources.into_inner();
-    let chat_service = ChatService {
-        embedding_client: resources.embeddings_client.clone(),
-        message_repo: resources.message_repo.clone(),
-    };
+    let key = params.storage_key().ok_or_else(invalid_collection)?;
+    payload.validate(resources.config.server.max_content_length)?;
+    let chat_service = ChatService::from_resources(&resources);
     let chat = payload.into_inner();
-    let chat = chat_service.save_chat(username, chat).await;
+    let chat = chat_service
+        .save_chat(&key, chat, resources.config.storage.dedup)
+        .await;
 
     match chat {
-        Ok(chat) => HttpResponse::Ok().json(chat),
-        Err(_) => {
-            error!("Error saving chat");
-            HttpResponse::InternalServerError().finish()
+        Ok(chat) => {
+            resources.event_hub.publish(HubEvent::MessageSaved {
+                username: params.username.clone(),
+                hash: chat.hash.clone(),
+                role: chat.role.clone(),
+            });
+            Ok(HttpResponse::Ok().json(chat))
+        }
+        // the stored message is the answer, not an error
+        Err(SaveError::Duplicate(existing)) => Ok(HttpResponse::Conflict().json(existing)),
+        Err(SaveError::Failed) => Err(ApiError::internal("Error saving chat")),
+    }
+}
+
+pub async fn complete(
+    resources: web::Data<Resources>,
+    params: web::Path<CollectionPath>,
+    payload: web::Json<CompleteRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let resources = resources.into_inner();
+    let key = params.storage_key().ok_or_else(invalid_collection)?;
+    validation::content(
+        "content",
+        &payload.content,
+        resources.config.server.max_content_length,
+    )?;
+    let chat_service = ChatService::from_resources(&resources);
+
+    match chat_service.complete(&key, payload.into_inner()).await {
+        Ok((prompt, completion)) => {
+            for chat in [&prompt, &completion.reply] {
+                resources.event_hub.publish(HubEvent::MessageSaved {
+                    username: params.username.clone(),
+                    hash: chat.hash.clone(),
+                    role: chat.role.clone(),
+                });
+            }
+            Ok(HttpResponse::Ok().json(completion))
+        }
+        Err(CompleteError::Chat(e)) => Err(e.into()),
+        Err(CompleteError::QuotaExceeded) => Err(ApiError::RateLimited(
+            "daily token quota exceeded".to_string(),
+        )),
+        Err(CompleteError::ModelNotAllowed(model)) => Err(ApiError::invalid(
+            "model",
+            format!("model {} is not allowed", model),
+        )),
+        Err(CompleteError::Internal) => Err(ApiError::internal("Error completing chat")),
+    }
+}
+
+pub async fn save_chats(
+    resources: web::Data<Resources>,
+    params: web::Path<CollectionPath>,
+    payload: web::Json<Vec<ChatRequest>>,
+) -> Result<HttpResponse, ApiError> {
+    let resources = resources.into_inner();
+    let key = params.storage_key().ok_or_else(invalid_collection)?;
+    for (index, chat) in payload.iter().enumerate() {
+        chat.validate(resources.config.server.max_content_length)
+            .map_err(|e| e.at(index))?;
+    }
+    let chat_service = ChatService::from_resources(&resources);
+
+    match chat_service.save_chats(&key, payload.into_inner()).await {
+        Ok(chats) => {
+            for chat in chats.iter() {
+                resources.event_hub.publish(HubEvent::MessageSaved {
+                    username: params.username.clone(),
+                    hash: chat.hash.clone(),
+                    role: chat.role.clone(),
+                });
+            }
+            Ok(HttpResponse::Ok().json(chats))
         }
+        Err(_) => Err(ApiError::internal("Error saving chat batch")),
     }
 }
+
+pub async fn save_feedback(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    payload: web::Json<FeedbackRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let resources = resources.into_inner();
+    let feedback_service = FeedbackService {
+        embedding_client: resources.embeddings_client.clone(),
+        feedback_repo: resources.feedback_repo.clone(),
+    };
+    let username = &params.0.clone();
+
+    let feedback = feedback_service
+        .save_feedback(username, payload.into_inner())
+        .await
+        .map_err(|_| ApiError::internal("Error saving feedback"))?;
+    Ok(HttpResponse::Ok().json(feedback))
+}
+
+pub async fn get_collections(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+) -> Result<HttpResponse, ApiError> {
+    let collection_service = CollectionService {
+        message_repo: resources.message_repo.clone(),
+    };
+    let collections = collection_service
+        .get_collections(&params.0)
+        .await
+        .map_err(|_| ApiError::internal("Error listing collections"))?;
+    Ok(HttpResponse::Ok().json(collections))
+}
+
+fn invalid_collection() -> ApiError {
+    ApiError::invalid("collection", "Invalid collection name")
+}
@@ -0,0 +1,48 @@
+use actix_web::{web, HttpResponse};
+
+use super::error::ApiError;
+use crate::{repos::contacts::ContactModel, Resources};
+
+pub async fn save_contact(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    payload: web::Json<ContactModel>,
+) -> Result<HttpResponse, ApiError> {
+    let username = &params.0.clone();
+    let contact = resources
+        .contact_repo
+        .lock()
+        .await
+        .save_contact(username, payload.into_inner())
+        .map_err(|_| ApiError::internal("Error saving contact"))?;
+    Ok(HttpResponse::Ok().json(contact))
+}
+
+pub async fn get_contacts(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+) -> Result<HttpResponse, ApiError> {
+    let username = &params.0.clone();
+    let contacts = resources
+        .contact_repo
+        .lock()
+        .await
+        .get_contacts(username)
+        .map_err(|_| ApiError::internal("Error getting contacts"))?;
+    Ok(HttpResponse::Ok().json(contacts))
+}
+
+pub async fn get_contact(
+    resources: web::Data<Resources>,
+    params: web::Path<(String, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let username = &params.0.clone();
+    let name = &params.1.clone();
+    let contact = resources
+        .contact_repo
+        .lock()
+        .await
+        .get_contact(username, name)
+        .map_err(|_| ApiError::NotFound(format!("no contact named {}", name)))?;
+    Ok(HttpResponse::Ok().json(contact))
+}
@@ -0,0 +1,169 @@
+use std::fmt;
+
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde_json::json;
+use tracing::{debug, error};
+
+use crate::{clients::chat::ChatError, repos::RepoError, services::validation::ValidationError};
+
+/// Why a request failed, answered as JSON with a machine readable `code`
+/// next to the human readable `error`
+#[derive(Debug)]
+pub enum ApiError {
+    /// The request was refused, `field` names the offending part of it
+    InvalidInput {
+        message: String,
+        field: Option<String>,
+    },
+    NotFound(String),
+    /// No token, or one the server does not know
+    Unauthorized,
+    /// A token that does not grant access to what was asked for
+    Forbidden,
+    PayloadTooLarge(String),
+    /// A limit or quota of the client, or of a backend, ran out
+    RateLimited(String),
+    /// The chat model, embeddings or storage failed or could not be reached
+    BackendUnavailable(String),
+    /// Logged, the client is only told something went wrong
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn invalid(field: &str, message: impl Into<String>) -> Self {
+        ApiError::InvalidInput {
+            message: message.into(),
+            field: Some(field.to_string()),
+        }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        ApiError::Internal(message.into())
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidInput { .. } => "invalid_input",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::Forbidden => "forbidden",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::RateLimited(_) => "rate_limited",
+            ApiError::BackendUnavailable(_) => "backend_unavailable",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::InvalidInput { message, .. }
+            | ApiError::NotFound(message)
+            | ApiError::PayloadTooLarge(message)
+            | ApiError::RateLimited(message)
+            | ApiError::BackendUnavailable(message) => write!(f, "{}", message),
+            ApiError::Unauthorized => write!(f, "a valid bearer token is required"),
+            ApiError::Forbidden => write!(f, "the token does not grant access"),
+            ApiError::Internal(_) => write!(f, "internal error"),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidInput { .. } => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden => StatusCode::FORBIDDEN,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::BackendUnavailable(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            ApiError::Internal(message) => error!("{}", message),
+            ApiError::BackendUnavailable(message) => error!("Backend failed: {}", message),
+            _ => debug!("Refused request: {}", self),
+        }
+        let mut body = json!({ "error": self.to_string(), "code": self.code() });
+        if let ApiError::InvalidInput {
+            field: Some(field), ..
+        } = self
+        {
+            body["field"] = json!(field);
+        }
+        HttpResponse::build(self.status_code()).json(body)
+    }
+}
+
+impl From<ValidationError> for ApiError {
+    fn from(error: ValidationError) -> Self {
+        ApiError::InvalidInput {
+            message: error.message,
+            field: Some(error.field),
+        }
+    }
+}
+
+impl From<RepoError> for ApiError {
+    fn from(error: RepoError) -> Self {
+        match error {
+            RepoError::NotFound => ApiError::NotFound("not found".to_string()),
+            RepoError::Corrupt(e) => ApiError::Internal(format!("Corrupt storage: {}", e)),
+            // the cause stays in the log
+            e => {
+                error!("Storage error: {}", e);
+                ApiError::BackendUnavailable("storage is unavailable".to_string())
+            }
+        }
+    }
+}
+
+impl From<ChatError> for ApiError {
+    fn from(error: ChatError) -> Self {
+        match error {
+            ChatError::Provider { status: 429, .. } => ApiError::RateLimited(error.to_string()),
+            e => ApiError::BackendUnavailable(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::to_bytes;
+
+    use super::*;
+
+    #[actix::test]
+    async fn test_errors_answer_with_codes() {
+        let response = ApiError::invalid("hash", "must be 1 to 128 characters").error_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value =
+            serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "error": "must be 1 to 128 characters",
+                "code": "invalid_input",
+                "field": "hash"
+            })
+        );
+
+        let response = ApiError::from(RepoError::NotFound).error_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // what went wrong inside is logged, not sent
+        let response = ApiError::internal("Error reading /var/lib/muninn").error_response();
+        let body: serde_json::Value =
+            serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap();
+        assert_eq!(
+            body,
+            json!({ "error": "internal error", "code": "internal" })
+        );
+    }
+}
@@ -1,12 +1,12 @@
-use std::time::Duration;
-
-use actix_web::{web, HttpResponse};
-use rumqttc::MqttOptions;
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::StreamExt;
 use serde::Serialize;
-use tracing::{error, info};
-use crate::repos::attributes::AttributeRepo;
 use sha2::{Digest, Sha256};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::info;
 
+use super::error::ApiError;
+use crate::hub::HubEvent;
 use crate::repos::messages::ChatModel;
 use crate::Resources;
 
@@ -20,15 +20,8 @@ pub struct MessageEvent {
 pub async fn test_mtqq(
     resources: web::Data<Resources>,
     params: web::Path<(String,)>,
-) -> HttpResponse {
+) -> Result<HttpResponse, ApiError> {
     let username = &params.0.clone();
-    let attr = "telegram_chat_id".to_string();
-    let chat_id = resources
-        .user_attributes_repo
-        .lock()
-        .await
-        .get_attribute(&username, &attr)
-        .await;
 
     //create hash of message
     let content = "Event test message".to_string();
@@ -40,62 +33,110 @@ pub async fn test_mtqq(
         hash: format!("{:x}", hash),
         embedding: None,
         timestamp,
+        conversation_id: None,
+        forgotten: false,
+        low_value: false,
+        embedding_model: None,
+        occurred_at: None,
+        metadata: std::collections::HashMap::new(),
+        tags: vec![],
     };
     let date = chrono::Utc::now().date_naive();
-    resources.message_repo.lock().await.save_chat(date, username.clone(), chat);
+    let chat = resources
+        .message_repo
+        .lock()
+        .await
+        .save_chat(date, username.clone(), chat)
+        .await?;
 
-    let chat_id = match chat_id {
-        Ok(chat_id) => chat_id.value,
-        Err(_) => {
-            error!("Error getting chat id");
-            return HttpResponse::InternalServerError().finish();
-        }
+    let event = HubEvent::MessageSaved {
+        username: username.clone(),
+        hash: chat.hash,
+        role: chat.role,
     };
+    info!("Publishing test event");
+    resources.event_hub.publish(event.clone());
 
-    // convert chatid from string to i64
-    let chat_id = chat_id.parse::<i64>().unwrap();
+    Ok(HttpResponse::Ok().json(event))
+}
 
-    let chat = rmp_serde::to_vec(&MessageEvent {
-        username: username.clone(),
-        hash: format!("{:x}", hash),
-        chat_id,
-    })
-    .unwrap();
+/// Streams the user's events as server-sent events for as long as the
+/// client stays connected
+pub async fn stream_events(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+) -> HttpResponse {
+    let receiver = resources.event_hub.subscribe(&params.0);
 
-    info!("Sending message to mqtt");
+    let stream = futures::stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let data = serde_json::to_string(&event).unwrap();
+                    let frame = web::Bytes::from(format!("data: {}\n\n", data));
+                    return Some((Ok::<_, actix_web::Error>(frame), receiver));
+                }
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
 
-    let mut mqttoptions = MqttOptions::new("muninn", "127.0.0.1", 1883);
-    mqttoptions.set_keep_alive(Duration::from_secs(5));
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .streaming(stream)
+}
 
-    let (client, mut eventloop) = rumqttc::AsyncClient::new(mqttoptions, 10);
-    match client
-        .publish(
-            "messages/assistant",
-            rumqttc::QoS::AtLeastOnce,
-            false,
-            chat.clone(),
-        )
-        .await
-    {
-        Ok(_) => {
-            info!("Message sent");
-        }
-        Err(e) => {
-            error!("Error sending message {}", e);
-        }
-    };
+/// Whether live subscribers hear about `event`, only changes to the user's
+/// memory are streamed
+fn is_live(event: &HubEvent) -> bool {
+    matches!(
+        event,
+        HubEvent::MessageSaved { .. }
+            | HubEvent::SummaryReady { .. }
+            | HubEvent::AttributeChanged { .. }
+            | HubEvent::FeedItem { .. }
+            | HubEvent::ReminderDue { .. }
+    )
+}
 
-    while let Ok(notification) = eventloop.poll().await {
-        match notification {
-            rumqttc::Event::Incoming(incoming) => match incoming {
-                rumqttc::Packet::PubAck(_) => {
-                    info!("PubAck received");
-                    break;
-                }
-                _ => {}
-            },
-            _ => {}
+/// Upgrades to a WebSocket that sends every new event of the user as a JSON
+/// text frame until either side closes it
+pub async fn stream_events_ws(
+    req: HttpRequest,
+    body: web::Payload,
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (response, mut session, mut messages) = actix_ws::handle(&req, body)?;
+    let mut receiver = resources.event_hub.subscribe(&params.0);
+
+    actix_web::rt::spawn(async move {
+        loop {
+            tokio::select! {
+                event = receiver.recv() => match event {
+                    Ok(event) if is_live(&event) => {
+                        let data = serde_json::to_string(&event).unwrap();
+                        if session.text(data).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                message = messages.next() => match message {
+                    Some(Ok(actix_ws::Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Ok(actix_ws::Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                },
+            }
         }
-    }
-    HttpResponse::Ok().json(chat)
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
 }
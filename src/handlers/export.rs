@@ -0,0 +1,75 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::StreamExt;
+
+use super::error::ApiError;
+use crate::{
+    handlers::caching::{cached_json, REVALIDATE},
+    services::export::{ArchiveQuery, ExportService, TranscriptQuery},
+    Resources,
+};
+
+pub async fn export_transcript(
+    req: HttpRequest,
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    query: web::Query<TranscriptQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let resources = resources.into_inner();
+    let export_service = ExportService {
+        message_repo: resources.message_repo.clone(),
+        contact_repo: resources.contact_repo.clone(),
+        attribute_repo: resources.user_attributes_repo.clone(),
+        summary_repo: resources.summary_repo.clone(),
+    };
+
+    let username = &params.0.clone();
+    let transcript = export_service
+        .export_transcript(username, query.anonymize)
+        .await
+        .map_err(|_| ApiError::internal("Error exporting transcript"))?;
+    Ok(cached_json(&req, &transcript, REVALIDATE))
+}
+
+/// Streams everything kept for the user as JSON lines, one message,
+/// attribute or summary per line, for backups and moving to another host
+pub async fn export_archive(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    query: web::Query<ArchiveQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let query = query
+        .to_query()
+        .ok_or_else(|| ApiError::invalid("from_date", "Dates must be YYYY-MM-DD"))?;
+    let resources = resources.into_inner();
+    let export_service = ExportService {
+        message_repo: resources.message_repo.clone(),
+        contact_repo: resources.contact_repo.clone(),
+        attribute_repo: resources.user_attributes_repo.clone(),
+        summary_repo: resources.summary_repo.clone(),
+    };
+
+    let records = export_service
+        .export_archive(&params.0, query)
+        .await
+        .map_err(|_| ApiError::internal("Error exporting archive"))?;
+    // a failing page ends the stream early, the truncated body tells the
+    // client the archive is incomplete
+    let lines = records.map(|page| {
+        let page =
+            page.map_err(|_| actix_web::error::ErrorInternalServerError("Error reading messages"))?;
+        let mut chunk = String::new();
+        for record in page {
+            chunk.push_str(&serde_json::to_string(&record)?);
+            chunk.push('\n');
+        }
+        Ok::<_, actix_web::Error>(web::Bytes::from(chunk))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{}.jsonl\"", params.0),
+        ))
+        .streaming(lines))
+}
@@ -0,0 +1,83 @@
+use actix_web::{web, HttpResponse};
+use tracing::error;
+
+use super::{error::ApiError, ingest::ingest_service};
+use crate::{
+    clients::web::FetchError,
+    services::feeds::{FeedError, FeedRequest, FeedService},
+    Resources,
+};
+
+/// Polls the feeds of users with the configured clients
+pub fn feed_service(resources: &Resources) -> FeedService {
+    FeedService {
+        feed_repo: resources.feed_repo.clone(),
+        page_client: resources.page_client.clone(),
+        ingest_service: ingest_service(resources),
+        event_hub: resources.event_hub.clone(),
+    }
+}
+
+/// Follows a feed, its current items are remembered in the background
+pub async fn register_feed(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    payload: web::Json<FeedRequest>,
+) -> Result<HttpResponse, ApiError> {
+    payload.validate()?;
+    let feed_service = feed_service(&resources);
+
+    let username = params.0.clone();
+    let feed = feed_service
+        .register(&username, payload.into_inner())
+        .await
+        .map_err(|e| match e {
+            FeedError::Fetch(FetchError::Failed | FetchError::Status(_)) => {
+                ApiError::BackendUnavailable(e.to_string())
+            }
+            FeedError::Fetch(FetchError::TooLarge) | FeedError::NotAFeed => {
+                ApiError::invalid("url", e.to_string())
+            }
+            FeedError::Storage => ApiError::internal("Error saving feed"),
+        })?;
+
+    let polled = feed.clone();
+    tokio::spawn(async move {
+        let now = chrono::Utc::now().timestamp();
+        if feed_service.poll(&username, &polled, now).await.is_err() {
+            error!("Error polling new feed {} of {}", polled.id, username);
+        }
+    });
+    Ok(HttpResponse::Ok().json(feed))
+}
+
+/// Feeds with how their last poll went
+pub async fn get_feeds(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+) -> Result<HttpResponse, ApiError> {
+    let username = &params.0.clone();
+    let feeds = resources
+        .feed_repo
+        .lock()
+        .await
+        .get_feeds(username)
+        .map_err(|_| ApiError::internal("Error getting feeds"))?;
+    Ok(HttpResponse::Ok().json(feeds))
+}
+
+/// Stops following a feed, the items already remembered are kept
+pub async fn delete_feed(
+    resources: web::Data<Resources>,
+    params: web::Path<(String, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let username = &params.0.clone();
+    let id = &params.1.clone();
+    resources
+        .feed_repo
+        .lock()
+        .await
+        .delete_feed(username, id)
+        .map_err(|_| ApiError::NotFound(format!("no feed {}", id)))?;
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,56 @@
+use actix_web::{web, HttpResponse};
+
+use super::error::ApiError;
+use crate::{
+    services::{
+        import::{chatgpt::ChatGptImporter, find_importer, ImportQuery, ImportService, Importer},
+        pii::PiiScrubber,
+    },
+    Resources,
+};
+
+/// Imports a chat export sent as the raw request body, detecting its format
+/// unless `?format=` names one
+pub async fn import_messages(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    query: web::Query<ImportQuery>,
+    body: String,
+) -> Result<HttpResponse, ApiError> {
+    let importer = find_importer(query.format.as_deref(), &body)
+        .ok_or_else(|| ApiError::invalid("format", "unrecognised import format"))?;
+    run_import(resources, &params.0, importer.as_ref(), &body).await
+}
+
+/// Imports the conversations.json of a ChatGPT data export
+pub async fn import_chatgpt(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    body: String,
+) -> Result<HttpResponse, ApiError> {
+    run_import(resources, &params.0, &ChatGptImporter {}, &body).await
+}
+
+async fn run_import(
+    resources: web::Data<Resources>,
+    username: &str,
+    importer: &dyn Importer,
+    body: &str,
+) -> Result<HttpResponse, ApiError> {
+    let resources = resources.into_inner();
+    let import_service = ImportService {
+        embedding_client: resources.embeddings_client.clone(),
+        message_repo: resources.message_repo.clone(),
+        event_hub: resources.event_hub.clone(),
+        pii_scrubber: Some(PiiScrubber::from_resources(&resources)),
+    };
+
+    let report = import_service
+        .import(username, importer, body)
+        .await
+        .map_err(|_| ApiError::InvalidInput {
+            message: format!("not a valid {} export", importer.name()),
+            field: None,
+        })?;
+    Ok(HttpResponse::Ok().json(report))
+}
@@ -0,0 +1,183 @@
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+
+use super::error::ApiError;
+use crate::{
+    clients::{
+        transcription::{Audio, TranscriptionError},
+        web::{FetchError, MAX_PAGE_BYTES},
+    },
+    hub::HubEvent,
+    services::{
+        chat::ChatService,
+        ingest::{
+            audio::{audio_format, transcript_document, AudioQuery, AUDIO_FORMATS},
+            bookmark::{page_document, BookmarkRequest},
+            document::{read_document, DocumentFormat, DocumentQuery},
+            email::{self, EmailRequest},
+            IngestDocument, IngestService,
+        },
+    },
+    Resources,
+};
+
+/// Remembers an email sent as JSON, or as a raw RFC 822 message with any
+/// other content type
+pub async fn ingest_email(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    request: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let email = match request.content_type() {
+        "application/json" => {
+            serde_json::from_slice::<EmailRequest>(&body).map_err(|e| ApiError::InvalidInput {
+                message: e.to_string(),
+                field: None,
+            })?
+        }
+        _ => email::parse_rfc822(&String::from_utf8_lossy(&body)).ok_or_else(|| {
+            ApiError::InvalidInput {
+                message: "not an RFC 822 email".to_string(),
+                field: None,
+            }
+        })?,
+    };
+    email.validate()?;
+    ingest(resources, &params.0, email.into_document()).await
+}
+
+/// Remembers a text, markdown or PDF document sent as the raw request body
+pub async fn ingest_document(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    query: web::Query<DocumentQuery>,
+    request: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let query = query.into_inner();
+    let format = DocumentFormat::detect(&query, request.content_type(), &body)
+        .ok_or_else(|| ApiError::invalid("format", "must be text, markdown or pdf"))?;
+    let document = read_document(&body, format, query).ok_or_else(|| {
+        ApiError::invalid(
+            "body",
+            format!("no text could be read from the {} document", format.name()),
+        )
+    })?;
+    ingest(resources, &params.0, document).await
+}
+
+/// Transcribes an audio note sent as the raw request body and remembers
+/// what was said
+pub async fn ingest_audio(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    query: web::Query<AudioQuery>,
+    request: HttpRequest,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let query = query.into_inner();
+    let format = audio_format(&query, request.content_type(), &body).ok_or_else(|| {
+        ApiError::invalid(
+            "format",
+            format!("must be one of {}", AUDIO_FORMATS.join(", ")),
+        )
+    })?;
+    let recorded_at = query
+        .recorded_at()
+        .map_err(|_| {
+            ApiError::invalid(
+                "recorded_at",
+                "must be an RFC 3339 date or seconds since the epoch",
+            )
+        })?
+        .unwrap_or_else(|| chrono::Utc::now().timestamp());
+
+    // backends tell the format by the extension
+    let filename = format!("audio.{}", format);
+    let audio = Audio {
+        data: &body,
+        filename: &filename,
+        content_type: request.content_type(),
+    };
+    let transcription = resources
+        .transcription_client
+        .transcribe(audio)
+        .await
+        .map_err(|e| match e {
+            TranscriptionError::NotConfigured => {
+                ApiError::BackendUnavailable("no transcription backend is configured".to_string())
+            }
+            TranscriptionError::Failed => {
+                ApiError::BackendUnavailable("the audio could not be transcribed".to_string())
+            }
+        })?;
+    if transcription.text.is_empty() {
+        return Err(ApiError::invalid(
+            "body",
+            "no speech was heard in the audio",
+        ));
+    }
+    let document = transcript_document(&body, transcription, query, recorded_at);
+    ingest(resources, &params.0, document).await
+}
+
+/// Fetches the page a link points to and remembers the article it holds
+pub async fn ingest_url(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    payload: web::Json<BookmarkRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let bookmark = payload.into_inner();
+    bookmark.validate()?;
+    let page = resources
+        .page_client
+        .fetch(&bookmark.url)
+        .await
+        .map_err(|e| match e {
+            FetchError::Failed => {
+                ApiError::BackendUnavailable("the page could not be fetched".to_string())
+            }
+            FetchError::Status(status) => {
+                ApiError::BackendUnavailable(format!("the page answered {}", status))
+            }
+            FetchError::TooLarge => ApiError::invalid(
+                "url",
+                format!(
+                    "points to a page larger than {} MB",
+                    MAX_PAGE_BYTES / 1024 / 1024
+                ),
+            ),
+        })?;
+    let document = page_document(bookmark, page)
+        .ok_or_else(|| ApiError::invalid("url", "no text could be read from the page"))?;
+    ingest(resources, &params.0, document).await
+}
+
+/// Stores documents with the configured chunking and clients
+pub fn ingest_service(resources: &Resources) -> IngestService {
+    IngestService {
+        chat_service: ChatService::from_resources(resources),
+        max_content_length: resources.config.server.max_content_length,
+        chunk_chars: resources.config.ingest.chunk_chars,
+        chunk_overlap: resources.config.ingest.chunk_overlap,
+    }
+}
+
+async fn ingest(
+    resources: web::Data<Resources>,
+    username: &str,
+    document: IngestDocument,
+) -> Result<HttpResponse, ApiError> {
+    let report = ingest_service(&resources)
+        .ingest(username, document)
+        .await
+        .map_err(|_| ApiError::internal("Error ingesting document"))?;
+    for chat in report.messages.iter() {
+        resources.event_hub.publish(HubEvent::MessageSaved {
+            username: username.to_string(),
+            hash: chat.hash.clone(),
+            role: chat.role.clone(),
+        });
+    }
+    Ok(HttpResponse::Ok().json(report))
+}
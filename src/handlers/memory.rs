@@ -0,0 +1,27 @@
+use actix_web::{web, HttpResponse};
+
+use super::error::ApiError;
+use crate::{
+    services::memory::{ForgetRequest, MemoryService},
+    Resources,
+};
+
+pub async fn forget(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    payload: web::Json<ForgetRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let resources = resources.into_inner();
+    let memory_service = MemoryService {
+        embedding_client: resources.embeddings_client.clone(),
+        message_repo: resources.message_repo.clone(),
+        suppression_repo: resources.suppression_repo.clone(),
+    };
+    let username = &params.0.clone();
+
+    let response = memory_service
+        .forget(username, payload.into_inner())
+        .await
+        .map_err(|_| ApiError::internal("Error forgetting memories"))?;
+    Ok(HttpResponse::Ok().json(response))
+}
@@ -0,0 +1,17 @@
+use actix_web::{web, HttpResponse};
+
+use crate::{metrics::LiveMetrics, Resources};
+
+pub async fn get_metrics(resources: web::Data<Resources>) -> HttpResponse {
+    let metrics = resources.storage_metrics.lock().await;
+    let live = LiveMetrics {
+        embedding_queue: resources
+            .embedding_queue
+            .as_ref()
+            .map(|queue| queue.stats()),
+        embedding_cache: resources.embeddings_client.lock().await.cache_stats(),
+    };
+    HttpResponse::Ok()
+        .content_type("application/openmetrics-text; version=1.0.0; charset=utf-8")
+        .body(metrics.render(&live))
+}
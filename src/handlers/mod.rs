@@ -2,3 +2,27 @@ pub mod chat;
 pub mod summary;
 pub mod user_attributes;
 pub mod events;
+pub mod memory;
+pub mod presets;
+pub mod metrics;
+pub mod contacts;
+pub mod briefing;
+pub mod export;
+pub mod saved_searches;
+pub mod admin;
+pub mod personas;
+pub mod caching;
+pub mod import;
+pub mod auth;
+pub mod usage;
+pub mod request_id;
+pub mod topics;
+pub mod rate_limit;
+pub mod validation;
+pub mod error;
+pub mod openapi;
+pub mod webhooks;
+pub mod ingest;
+pub mod feeds;
+pub mod reminders;
+pub mod retention;
@@ -0,0 +1,1113 @@
+use actix_web::HttpResponse;
+use serde_json::{json, Map, Value};
+
+/// One route of the API as it is described in the OpenAPI document
+struct Operation {
+    method: &'static str,
+    path: &'static str,
+    tag: &'static str,
+    summary: &'static str,
+    /// Query parameters as name, type and description
+    query: &'static [(&'static str, &'static str, &'static str)],
+    /// Schema of the JSON body, `text` for a raw text body
+    body: Option<&'static str>,
+    /// Schema of a successful answer, a JSON object when unset
+    response: Option<&'static str>,
+    /// Also served for a named collection of the user
+    collection: bool,
+}
+
+const fn op(
+    method: &'static str,
+    path: &'static str,
+    tag: &'static str,
+    summary: &'static str,
+) -> Operation {
+    Operation {
+        method,
+        path,
+        tag,
+        summary,
+        query: &[],
+        body: None,
+        response: None,
+        collection: false,
+    }
+}
+
+impl Operation {
+    const fn query(self, query: &'static [(&'static str, &'static str, &'static str)]) -> Self {
+        Operation { query, ..self }
+    }
+
+    const fn body(self, body: &'static str) -> Self {
+        Operation {
+            body: Some(body),
+            ..self
+        }
+    }
+
+    const fn response(self, response: &'static str) -> Self {
+        Operation {
+            response: Some(response),
+            ..self
+        }
+    }
+
+    const fn in_collections(self) -> Self {
+        Operation {
+            collection: true,
+            ..self
+        }
+    }
+}
+
+const DATE_RANGE: &[(&str, &str, &str)] = &[
+    ("from_date", "string", "First day to include, as YYYY-MM-DD"),
+    ("to_date", "string", "Last day to include, as YYYY-MM-DD"),
+];
+
+const SUMMARY_MODEL: &[(&str, &str, &str)] = &[(
+    "model",
+    "string",
+    "Model writing a summary that is not stored yet, from models.allowed_chat_models",
+)];
+
+const OPERATIONS: &[Operation] = &[
+    op("post", "/api/v1/chat/{username}", "chat", "Save a message")
+        .body("ChatRequest")
+        .response("ChatResponse")
+        .in_collections(),
+    op(
+        "post",
+        "/api/v1/chat/{username}/batch",
+        "chat",
+        "Save several messages at once",
+    )
+    .body("ChatRequestList")
+    .response("ChatResponseList")
+    .in_collections(),
+    op(
+        "post",
+        "/api/v1/chat/{username}/context",
+        "chat",
+        "Recent or packed context for a new message",
+    )
+    .query(&[
+        (
+            "preset",
+            "string",
+            "Context preset to build the context with",
+        ),
+        (
+            "persona",
+            "string",
+            "Persona whose system prompt opens the context",
+        ),
+        (
+            "max_tokens",
+            "integer",
+            "Packs the most relevant and recent memories into this many tokens",
+        ),
+    ])
+    .body("ChatRequest")
+    .in_collections(),
+    op(
+        "post",
+        "/api/v1/chat/{username}/complete",
+        "chat",
+        "Answer a message with the chat model and the user's memories",
+    )
+    .body("CompleteRequest")
+    .response("CompleteResponse")
+    .in_collections(),
+    op(
+        "get",
+        "/api/v1/chat/{username}/history",
+        "chat",
+        "Page through stored messages, newest first",
+    )
+    .query(&[
+        ("limit", "integer", "Messages per page"),
+        ("offset", "integer", "Messages to skip"),
+        ("from_date", "string", "First day to include, as YYYY-MM-DD"),
+        ("to_date", "string", "Last day to include, as YYYY-MM-DD"),
+        (
+            "tags",
+            "string",
+            "Comma separated tags or key=value metadata pairs the messages must all have",
+        ),
+    ])
+    .in_collections(),
+    op(
+        "post",
+        "/api/v1/chat/{username}/search",
+        "chat",
+        "Search the user's messages",
+    )
+    .body("SearchRequest")
+    .response("SearchResponseList")
+    .in_collections(),
+    op(
+        "get",
+        "/api/v1/chat/{username}/{id}",
+        "chat",
+        "Get a message by its hash",
+    )
+    .response("ChatResponse")
+    .in_collections(),
+    op(
+        "delete",
+        "/api/v1/chat/{username}/{id}",
+        "chat",
+        "Delete or redact a message",
+    )
+    .query(&[(
+        "mode",
+        "string",
+        "delete, the default, or redact to keep the hash",
+    )])
+    .in_collections(),
+    op(
+        "get",
+        "/api/v1/chat/{username}/collections",
+        "chat",
+        "List the user's collections",
+    ),
+    op(
+        "post",
+        "/api/v1/chat/{username}/feedback",
+        "chat",
+        "Mark a search result as useful or not",
+    )
+    .body("FeedbackRequest"),
+    op(
+        "get",
+        "/api/v1/summary/{username}",
+        "summaries",
+        "List stored day summaries",
+    )
+    .query(DATE_RANGE),
+    op(
+        "get",
+        "/api/v1/summary/{username}/{date}",
+        "summaries",
+        "Summary of one day, written when missing",
+    )
+    .query(SUMMARY_MODEL),
+    op(
+        "get",
+        "/api/v1/summary/{username}/week/{iso_week}",
+        "summaries",
+        "Summary of an ISO week such as 2024-W09",
+    )
+    .query(SUMMARY_MODEL),
+    op(
+        "get",
+        "/api/v1/summary/{username}/month/{month}",
+        "summaries",
+        "Summary of a month such as 2024-03",
+    )
+    .query(SUMMARY_MODEL),
+    op(
+        "get",
+        "/api/v1/topics/{username}",
+        "summaries",
+        "Cluster the user's messages into topics",
+    )
+    .query(&[(
+        "k",
+        "integer",
+        "Number of topics, picked from the number of messages when unset",
+    )]),
+    op(
+        "get",
+        "/api/v1/briefing/{username}",
+        "summaries",
+        "Today's briefing",
+    ),
+    op(
+        "get",
+        "/api/v1/usage/{username}",
+        "summaries",
+        "Tokens the chat model spent for the user",
+    ),
+    op(
+        "post",
+        "/api/v1/attribute/{username}",
+        "attributes",
+        "Save an attribute",
+    )
+    .body("AttributeRequest"),
+    op(
+        "get",
+        "/api/v1/attribute/{username}",
+        "attributes",
+        "Export every attribute",
+    ),
+    op(
+        "post",
+        "/api/v1/attribute/{username}/import",
+        "attributes",
+        "Import exported attributes",
+    )
+    .query(&[(
+        "strategy",
+        "string",
+        "What a conflicting attribute does: overwrite, keep or fail",
+    )])
+    .body("Object"),
+    op(
+        "post",
+        "/api/v1/attribute/{username}/extract",
+        "attributes",
+        "Infer attributes from a day of messages",
+    )
+    .query(&[(
+        "date",
+        "string",
+        "Day to read, as YYYY-MM-DD, today when absent",
+    )]),
+    op(
+        "get",
+        "/api/v1/attribute/{username}/{attribute}",
+        "attributes",
+        "Get an attribute",
+    )
+    .query(&[(
+        "at",
+        "string",
+        "Point in time, as unix seconds or YYYY-MM-DD",
+    )]),
+    op(
+        "delete",
+        "/api/v1/attribute/{username}/{attribute}",
+        "attributes",
+        "Delete an attribute",
+    ),
+    op(
+        "get",
+        "/api/v1/attribute/{username}/{attribute}/history",
+        "attributes",
+        "Every value an attribute had",
+    ),
+    op(
+        "post",
+        "/api/v1/memory/{username}/forget",
+        "memory",
+        "Forget the memories matching a description",
+    )
+    .body("ForgetRequest"),
+    op(
+        "get",
+        "/api/v1/retention/{username}",
+        "memory",
+        "The retention policy the user's messages are kept by",
+    )
+    .response("RetentionPolicy"),
+    op(
+        "put",
+        "/api/v1/retention/{username}",
+        "memory",
+        "Set the user's retention policy",
+    )
+    .body("RetentionPolicy")
+    .response("RetentionPolicy"),
+    op(
+        "delete",
+        "/api/v1/retention/{username}",
+        "memory",
+        "Go back to the configured retention policy",
+    ),
+    op(
+        "post",
+        "/api/v1/preset/{username}",
+        "memory",
+        "Save a context preset",
+    )
+    .body("Object"),
+    op(
+        "get",
+        "/api/v1/preset/{username}",
+        "memory",
+        "List context presets",
+    ),
+    op(
+        "post",
+        "/api/v1/persona/{username}",
+        "memory",
+        "Save a persona",
+    )
+    .body("Object"),
+    op(
+        "get",
+        "/api/v1/persona/{username}",
+        "memory",
+        "List personas",
+    ),
+    op(
+        "get",
+        "/api/v1/persona/{username}/{name}",
+        "memory",
+        "Get a persona",
+    ),
+    op(
+        "post",
+        "/api/v1/contacts/{username}",
+        "memory",
+        "Save a contact",
+    )
+    .body("Object"),
+    op(
+        "get",
+        "/api/v1/contacts/{username}",
+        "memory",
+        "List contacts",
+    ),
+    op(
+        "get",
+        "/api/v1/contacts/{username}/{name}",
+        "memory",
+        "Get a contact",
+    ),
+    op(
+        "post",
+        "/api/v1/search/{username}/saved",
+        "memory",
+        "Save a search to be told about new matches",
+    )
+    .body("SavedSearchRequest"),
+    op(
+        "get",
+        "/api/v1/search/{username}/saved",
+        "memory",
+        "List saved searches",
+    ),
+    op(
+        "delete",
+        "/api/v1/search/{username}/saved/{name}",
+        "memory",
+        "Delete a saved search",
+    ),
+    op(
+        "post",
+        "/api/v1/webhooks/{username}",
+        "events",
+        "Register a URL that signed events are posted to",
+    )
+    .body("WebhookRequest"),
+    op(
+        "get",
+        "/api/v1/webhooks/{username}",
+        "events",
+        "List webhooks with their latest deliveries",
+    ),
+    op(
+        "delete",
+        "/api/v1/webhooks/{username}/{id}",
+        "events",
+        "Delete a webhook",
+    ),
+    op(
+        "post",
+        "/api/v1/reminder/{username}",
+        "events",
+        "Create a reminder, reading when it is due from its text when due_at is left out",
+    )
+    .body("ReminderRequest"),
+    op(
+        "get",
+        "/api/v1/reminder/{username}",
+        "events",
+        "List pending and sent reminders, the earliest due first",
+    ),
+    op(
+        "delete",
+        "/api/v1/reminder/{username}/{id}",
+        "events",
+        "Delete a reminder",
+    ),
+    op(
+        "post",
+        "/api/v1/import/{username}",
+        "import",
+        "Import a chat export, detecting its format",
+    )
+    .query(&[(
+        "format",
+        "string",
+        "Format of the export when it should not be detected",
+    )])
+    .body("text"),
+    op(
+        "post",
+        "/api/v1/import/{username}/chatgpt",
+        "import",
+        "Import the conversations.json of a ChatGPT export",
+    )
+    .body("text"),
+    op(
+        "post",
+        "/api/v1/ingest/{username}/email",
+        "import",
+        "Remember an email, sent as JSON or as raw RFC 822 with any other content type",
+    )
+    .body("EmailRequest"),
+    op(
+        "post",
+        "/api/v1/ingest/{username}/document",
+        "import",
+        "Remember a text, markdown or PDF document sent as the body",
+    )
+    .query(&[
+        (
+            "format",
+            "string",
+            "text, markdown or pdf, detected when left out",
+        ),
+        ("title", "string", "Put in front of every chunk"),
+        (
+            "filename",
+            "string",
+            "Stored with the chunks, its extension can tell the format",
+        ),
+    ])
+    .body("binary"),
+    op(
+        "post",
+        "/api/v1/ingest/{username}/audio",
+        "import",
+        "Transcribe an audio note sent as the body and remember it",
+    )
+    .query(&[
+        ("filename", "string", "Its extension can tell the format"),
+        (
+            "recorded_at",
+            "string",
+            "RFC 3339 or seconds since the epoch, now when left out",
+        ),
+        ("title", "string", "Put in front of the transcription"),
+        ("tags", "string", "Tags besides audio, separated by commas"),
+    ])
+    .body("binary"),
+    op(
+        "post",
+        "/api/v1/ingest/{username}/url",
+        "import",
+        "Fetch a page and remember its article, tagged bookmark and with its site",
+    )
+    .body("BookmarkRequest"),
+    op(
+        "post",
+        "/api/v1/feeds/{username}",
+        "import",
+        "Follow an RSS or Atom feed, remembering its new items tagged rss:<id>",
+    )
+    .body("FeedRequest"),
+    op(
+        "get",
+        "/api/v1/feeds/{username}",
+        "import",
+        "List feeds with how their last poll went",
+    ),
+    op(
+        "delete",
+        "/api/v1/feeds/{username}/{id}",
+        "import",
+        "Stop following a feed, keeping the items already remembered",
+    ),
+    op(
+        "get",
+        "/api/v1/export/{username}",
+        "import",
+        "Stream everything kept for the user as JSON lines",
+    )
+    .query(DATE_RANGE),
+    op(
+        "get",
+        "/api/v1/export/{username}/transcript",
+        "import",
+        "Transcript of every message",
+    )
+    .query(&[(
+        "anonymize",
+        "boolean",
+        "Replace contact names with placeholders",
+    )]),
+    op(
+        "get",
+        "/api/v1/events/{username}",
+        "events",
+        "Publish a test event",
+    ),
+    op(
+        "get",
+        "/api/v1/events/{username}/stream",
+        "events",
+        "Server-sent events of the user",
+    ),
+    op(
+        "get",
+        "/api/v1/events/{username}/ws",
+        "events",
+        "WebSocket of the user's events",
+    ),
+    op(
+        "post",
+        "/api/v1/auth/{username}/tokens",
+        "auth",
+        "Issue a token",
+    )
+    .body("TokenRequest"),
+    op(
+        "get",
+        "/api/v1/auth/{username}/tokens",
+        "auth",
+        "List the user's tokens",
+    ),
+    op(
+        "delete",
+        "/api/v1/auth/{username}/tokens/{id}",
+        "auth",
+        "Revoke a token",
+    ),
+    op(
+        "get",
+        "/api/v1/admin/overview",
+        "admin",
+        "Storage, embeddings and job statuses",
+    ),
+    op(
+        "get",
+        "/api/v1/admin/users",
+        "admin",
+        "Users with messages, their message counts and last activity",
+    ),
+    op(
+        "get",
+        "/api/v1/admin/stats",
+        "admin",
+        "Messages, days and disk usage of the whole instance",
+    ),
+    op(
+        "get",
+        "/api/v1/admin/storage/{username}",
+        "admin",
+        "Bytes used by the user's messages, summaries, attributes and indexes",
+    ),
+    op(
+        "post",
+        "/api/v1/admin/warmup",
+        "admin",
+        "Load the search index into memory",
+    ),
+    op(
+        "get",
+        "/api/v1/admin/embedding-queue",
+        "admin",
+        "Depth and counters of the background embedding queue",
+    ),
+    op(
+        "post",
+        "/api/v1/admin/reembed/{username}",
+        "admin",
+        "Re-embed every message of the user",
+    )
+    .query(&[(
+        "model",
+        "string",
+        "Provider to embed with, the configured one when absent",
+    )]),
+    op(
+        "post",
+        "/api/v1/admin/{username}/dedupe",
+        "admin",
+        "Remove messages stored more than once",
+    ),
+    op(
+        "get",
+        "/metrics",
+        "admin",
+        "OpenMetrics of storage and embeddings",
+    ),
+];
+
+// What `crate::username` takes
+const USERNAME_PATTERN: &str = "^[A-Za-z0-9_@+-][A-Za-z0-9._@+-]{0,63}$";
+
+fn parameters(path: &str, query: &[(&str, &str, &str)]) -> Vec<Value> {
+    let mut parameters = path
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .map(|name| {
+            let schema = match name {
+                "username" => json!({ "type": "string", "pattern": USERNAME_PATTERN }),
+                _ => json!({ "type": "string" }),
+            };
+            json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": schema,
+            })
+        })
+        .collect::<Vec<Value>>();
+    for (name, kind, description) in query {
+        parameters.push(json!({
+            "name": name,
+            "in": "query",
+            "required": false,
+            "description": description,
+            "schema": { "type": kind },
+        }));
+    }
+    parameters
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn describe(operation: &Operation, path: &str) -> Value {
+    let error = json!({ "content": { "application/json": { "schema": schema_ref("Error") } } });
+    let success = match operation.response {
+        Some(schema) => json!({ "application/json": { "schema": schema_ref(schema) } }),
+        None => json!({ "application/json": { "schema": { "type": "object" } } }),
+    };
+    let mut described = json!({
+        "tags": [operation.tag],
+        "summary": operation.summary,
+        "parameters": parameters(path, operation.query),
+        "responses": {
+            "200": { "description": "Success", "content": success },
+            "400": { "description": "Invalid input", "content": error["content"] },
+            "404": { "description": "Not found", "content": error["content"] },
+            "default": { "description": "Error", "content": error["content"] },
+        },
+    });
+    match operation.body {
+        Some("text") => {
+            described["requestBody"] = json!({
+                "required": true,
+                "content": { "text/plain": { "schema": { "type": "string" } } },
+            })
+        }
+        Some("binary") => {
+            described["requestBody"] = json!({
+                "required": true,
+                "content": {
+                    "application/octet-stream": {
+                        "schema": { "type": "string", "format": "binary" },
+                    },
+                },
+            })
+        }
+        Some(schema) => {
+            described["requestBody"] = json!({
+                "required": true,
+                "content": { "application/json": { "schema": schema_ref(schema) } },
+            })
+        }
+        None => {}
+    }
+    described
+}
+
+fn schemas() -> Value {
+    let tags = json!({
+        "type": "array",
+        "items": { "type": "string" },
+        "description": "Tags, or key=value metadata pairs",
+    });
+    let metadata = json!({ "type": "object", "additionalProperties": { "type": "string" } });
+    json!({
+        "Error": {
+            "type": "object",
+            "required": ["error", "code"],
+            "properties": {
+                "error": { "type": "string", "description": "What went wrong, for people" },
+                "code": {
+                    "type": "string",
+                    "enum": [
+                        "invalid_input", "not_found", "unauthorized", "forbidden",
+                        "payload_too_large", "rate_limited", "backend_unavailable", "internal"
+                    ],
+                },
+                "field": { "type": "string", "description": "The refused part of the request" },
+            },
+        },
+        "ChatRequest": {
+            "type": "object",
+            "required": ["role", "content", "hash"],
+            "properties": {
+                "role": { "type": "string", "enum": ["user", "assistant", "system"] },
+                "content": { "type": "string" },
+                "hash": { "type": "string", "description": "Identifier chosen by the client" },
+                "conversation_id": { "type": "string" },
+                "occurred_at": {
+                    "type": "integer",
+                    "description": "When the message was said, in seconds since the epoch",
+                },
+                "metadata": metadata,
+                "tags": tags,
+            },
+        },
+        "ChatRequestList": { "type": "array", "items": schema_ref("ChatRequest") },
+        "ChatResponse": {
+            "type": "object",
+            "properties": {
+                "role": { "type": "string" },
+                "content": { "type": "string" },
+                "hash": { "type": "string" },
+                "created_at": { "type": "integer" },
+                "occurred_at": { "type": "integer" },
+                "metadata": metadata,
+                "tags": tags,
+                "embedding_status": { "type": "string", "enum": ["ready", "pending", "skipped"] },
+            },
+        },
+        "ChatResponseList": { "type": "array", "items": schema_ref("ChatResponse") },
+        "SearchRequest": {
+            "type": "object",
+            "required": ["content"],
+            "properties": {
+                "content": { "type": "string" },
+                "mode": { "type": "string", "enum": ["semantic", "keyword", "hybrid"] },
+                "limit": { "type": "integer" },
+                "min_score": { "type": "number" },
+                "ranking": {
+                    "type": "object",
+                    "properties": {
+                        "recency_weight": { "type": "number" },
+                        "half_life_days": { "type": "number" },
+                        "role_weights": {
+                            "type": "object",
+                            "additionalProperties": { "type": "number" },
+                        },
+                    },
+                },
+                "tags": tags,
+                "diversify": { "type": "boolean" },
+                "summaries": { "type": "boolean" },
+                "document_id": {
+                    "type": "string",
+                    "description": "Only chunks of this ingested email or document",
+                },
+                "from_date": {
+                    "type": "string",
+                    "description": "First day to search, as YYYY-MM-DD",
+                },
+                "to_date": {
+                    "type": "string",
+                    "description": "Last day to search, as YYYY-MM-DD",
+                },
+            },
+        },
+        "SearchResponse": {
+            "type": "object",
+            "properties": {
+                "kind": { "type": "string", "enum": ["message", "summary"] },
+                "role": { "type": "string" },
+                "content": { "type": "string" },
+                "hash": { "type": "string" },
+                "ranking": { "type": "number" },
+                "timestamp": { "type": "integer" },
+                "occurred_at": { "type": "integer" },
+                "metadata": metadata,
+                "tags": tags,
+            },
+        },
+        "SearchResponseList": { "type": "array", "items": schema_ref("SearchResponse") },
+        "CompleteRequest": {
+            "type": "object",
+            "required": ["content"],
+            "properties": {
+                "content": { "type": "string" },
+                "conversation_id": { "type": "string" },
+                "limit": {
+                    "type": "integer",
+                    "description": "Most memories to put in front of the model",
+                },
+                "model": { "type": "string", "description": "One of models.allowed_chat_models" },
+            },
+        },
+        "CompleteResponse": {
+            "type": "object",
+            "properties": {
+                "reply": schema_ref("ChatResponse"),
+                "memories": schema_ref("SearchResponseList"),
+                "usage": {
+                    "type": "object",
+                    "properties": {
+                        "prompt_tokens": { "type": "integer" },
+                        "completion_tokens": { "type": "integer" },
+                        "total_tokens": { "type": "integer" },
+                    },
+                },
+            },
+        },
+        "FeedbackRequest": {
+            "type": "object",
+            "required": ["query", "hash", "signal"],
+            "properties": {
+                "query": { "type": "string" },
+                "hash": { "type": "string" },
+                "signal": { "type": "string", "enum": ["useful", "irrelevant"] },
+            },
+        },
+        "AttributeRequest": {
+            "type": "object",
+            "required": ["attribute", "value"],
+            "properties": {
+                "attribute": { "type": "string" },
+                "value": { "type": "string" },
+                "expires_at": {
+                    "type": "integer",
+                    "description": "Unix timestamp after which the attribute expires",
+                },
+            },
+        },
+        "ForgetRequest": {
+            "type": "object",
+            "required": ["description"],
+            "properties": {
+                "description": { "type": "string" },
+                "confirm": {
+                    "type": "boolean",
+                    "description": "Without it the matching memories are only listed",
+                },
+                "threshold": { "type": "number" },
+            },
+        },
+        "SavedSearchRequest": {
+            "type": "object",
+            "required": ["name", "query"],
+            "properties": {
+                "name": { "type": "string" },
+                "query": { "type": "string" },
+                "threshold": { "type": "number" },
+            },
+        },
+        "WebhookRequest": {
+            "type": "object",
+            "required": ["url"],
+            "properties": {
+                "url": { "type": "string" },
+                "events": {
+                    "type": "array",
+                    "items": {
+                        "type": "string",
+                        "enum": [
+                            "message_saved",
+                            "summary_ready",
+                            "attribute_changed",
+                            "feed_item",
+                            "reminder_due",
+                        ],
+                    },
+                    "description": "Every event type when left out",
+                },
+                "secret": {
+                    "type": "string",
+                    "description": "Key of the X-Muninn-Signature HMAC, generated when left out",
+                },
+            },
+        },
+        "RetentionPolicy": {
+            "type": "object",
+            "required": ["mode"],
+            "properties": {
+                "mode": {
+                    "type": "string",
+                    "enum": ["forever", "delete", "summarize"],
+                    "description": "delete removes the messages of older days, summarize stores each day's summary first",
+                },
+                "days": {
+                    "type": "integer",
+                    "description": "Days of messages kept, needed unless kept forever",
+                },
+            },
+        },
+        "ReminderRequest": {
+            "type": "object",
+            "required": ["text"],
+            "properties": {
+                "text": {
+                    "type": "string",
+                    "description": "What to be reminded of, saying when as well without due_at, such as \"remind me Friday at 5 to call Ana\"",
+                },
+                "due_at": {
+                    "type": "string",
+                    "description": "RFC 3339 or seconds since the epoch",
+                },
+            },
+        },
+        "EmailRequest": {
+            "type": "object",
+            "required": ["body"],
+            "properties": {
+                "subject": { "type": "string" },
+                "from": { "type": "string" },
+                "to": { "type": "string" },
+                "date": { "type": "string", "description": "RFC 2822 or RFC 3339" },
+                "message_id": {
+                    "type": "string",
+                    "description": "Sending the same email again gives its chunks the same hashes",
+                },
+                "body": { "type": "string" },
+            },
+        },
+        "BookmarkRequest": {
+            "type": "object",
+            "required": ["url"],
+            "properties": {
+                "url": { "type": "string", "description": "An http:// or https:// URL" },
+                "title": { "type": "string", "description": "Taken from the page when left out" },
+                "tags": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "Tags besides bookmark and the site",
+                },
+            },
+        },
+        "FeedRequest": {
+            "type": "object",
+            "required": ["url"],
+            "properties": {
+                "url": { "type": "string", "description": "An http:// or https:// URL" },
+                "id": {
+                    "type": "string",
+                    "description": "Lowercase letters, digits and - _ ., the site of the feed when left out",
+                },
+                "notify": {
+                    "type": "boolean",
+                    "description": "Publish a feed_item event for every new item",
+                },
+                "full_text": {
+                    "type": "boolean",
+                    "description": "Remember the page of every item rather than its summary",
+                },
+            },
+        },
+        "TokenRequest": {
+            "type": "object",
+            "required": ["name"],
+            "properties": {
+                "name": { "type": "string" },
+                "scope": { "type": "string", "enum": ["user", "admin"] },
+            },
+        },
+        "Object": { "type": "object" },
+    })
+}
+
+/// The OpenAPI 3 document of every route
+pub fn spec() -> Value {
+    let mut paths = Map::new();
+    for operation in OPERATIONS {
+        let mut variants = vec![operation.path.to_string()];
+        if operation.collection {
+            variants.push(operation.path.replacen(
+                "/api/v1/chat/{username}",
+                "/api/v1/chat/{username}/collections/{collection}",
+                1,
+            ));
+        }
+        for path in variants {
+            let item = paths.entry(path.clone()).or_insert_with(|| json!({}));
+            item[operation.method] = describe(operation, &path);
+        }
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Muninn",
+            "description": "Long term memory for chat bots",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+            },
+        },
+        "security": [{ "bearer": [] }],
+        "paths": paths,
+    })
+}
+
+pub async fn get_openapi() -> HttpResponse {
+    HttpResponse::Ok().json(spec())
+}
+
+// Swagger UI from its CDN, pointed at the document above
+const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8" />
+  <title>Muninn API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    window.ui = SwaggerUIBundle({ url: "/api/v1/openapi.json", dom_id: "#swagger-ui" });
+  </script>
+</body>
+</html>
+"##;
+
+pub async fn get_docs() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(SWAGGER_UI)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spec_describes_every_operation() {
+        let spec = spec();
+        let schemas = spec["components"]["schemas"].as_object().unwrap();
+        let mut operations = 0;
+        for (path, item) in spec["paths"].as_object().unwrap() {
+            for (method, operation) in item.as_object().unwrap() {
+                operations += 1;
+                let path_params = operation["parameters"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .filter(|parameter| parameter["in"] == "path")
+                    .count();
+                assert_eq!(
+                    path_params,
+                    path.matches('{').count(),
+                    "{} {}",
+                    method,
+                    path
+                );
+                // every schema that is referred to exists
+                let text = operation.to_string();
+                for reference in text.split("#/components/schemas/").skip(1) {
+                    let name = reference.split('"').next().unwrap();
+                    assert!(
+                        schemas.contains_key(name),
+                        "{} {} uses {}",
+                        method,
+                        path,
+                        name
+                    );
+                }
+            }
+        }
+        let collection_routes = OPERATIONS.iter().filter(|op| op.collection).count();
+        assert_eq!(operations, OPERATIONS.len() + collection_routes);
+        assert!(
+            spec["paths"]["/api/v1/chat/{username}/collections/{collection}/search"]["post"]
+                .is_object()
+        );
+    }
+}
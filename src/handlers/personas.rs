@@ -0,0 +1,57 @@
+use actix_web::{web, HttpResponse};
+
+use super::error::ApiError;
+use crate::{
+    services::personas::{PersonaModel, PersonaService},
+    Resources,
+};
+
+pub async fn save_persona(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    payload: web::Json<PersonaModel>,
+) -> Result<HttpResponse, ApiError> {
+    let persona_service = PersonaService {
+        attribute_repo: resources.user_attributes_repo.clone(),
+    };
+    let username = &params.0.clone();
+
+    let persona = persona_service
+        .save_persona(username, payload.into_inner())
+        .await
+        .map_err(|_| ApiError::internal("Error saving persona"))?;
+    Ok(HttpResponse::Ok().json(persona))
+}
+
+pub async fn get_personas(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+) -> Result<HttpResponse, ApiError> {
+    let persona_service = PersonaService {
+        attribute_repo: resources.user_attributes_repo.clone(),
+    };
+    let username = &params.0.clone();
+
+    let personas = persona_service
+        .get_personas(username)
+        .await
+        .map_err(|_| ApiError::internal("Error getting personas"))?;
+    Ok(HttpResponse::Ok().json(personas))
+}
+
+pub async fn get_persona(
+    resources: web::Data<Resources>,
+    params: web::Path<(String, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let persona_service = PersonaService {
+        attribute_repo: resources.user_attributes_repo.clone(),
+    };
+    let username = &params.0.clone();
+    let name = &params.1.clone();
+
+    let persona = persona_service
+        .get_persona(username, name)
+        .await
+        .map_err(|_| ApiError::NotFound(format!("no persona named {}", name)))?;
+    Ok(HttpResponse::Ok().json(persona))
+}
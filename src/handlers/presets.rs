@@ -0,0 +1,33 @@
+use actix_web::{web, HttpResponse};
+
+use super::error::ApiError;
+use crate::{repos::presets::ContextPreset, Resources};
+
+pub async fn save_preset(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    payload: web::Json<ContextPreset>,
+) -> Result<HttpResponse, ApiError> {
+    let username = &params.0.clone();
+    let preset = resources
+        .preset_repo
+        .lock()
+        .await
+        .save_preset(username, payload.into_inner())
+        .map_err(|_| ApiError::internal("Error saving preset"))?;
+    Ok(HttpResponse::Ok().json(preset))
+}
+
+pub async fn get_presets(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+) -> Result<HttpResponse, ApiError> {
+    let username = &params.0.clone();
+    let presets = resources
+        .preset_repo
+        .lock()
+        .await
+        .get_presets(username)
+        .map_err(|_| ApiError::internal("Error getting presets"))?;
+    Ok(HttpResponse::Ok().json(presets))
+}
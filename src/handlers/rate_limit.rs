@@ -0,0 +1,186 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderValue},
+    middleware::Next,
+    web, HttpMessage, ResponseError,
+};
+use tracing::warn;
+
+use super::error::ApiError;
+use crate::{config::RateLimitConfig, repos::tokens::TokenModel, Resources};
+
+// Buckets kept before the full ones are dropped, a full bucket is the same
+// as none
+const MAX_BUCKETS: usize = 10_000;
+
+/// Which limit a request counts against
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Class {
+    Read,
+    /// Endpoints that call the chat model, which cost far more to answer
+    Llm,
+}
+
+fn class(path: &str) -> Class {
+    let llm = path.ends_with("/complete")
+        || path.ends_with("/context")
+        || path.ends_with("/extract")
+        || ["/api/v1/summary/", "/api/v1/topics/", "/api/v1/briefing/"]
+            .iter()
+            .any(|prefix| path.starts_with(prefix));
+    match llm {
+        true => Class::Llm,
+        false => Class::Read,
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Token buckets per client and class, refilled continuously
+pub struct RateLimiter {
+    limits: RateLimitConfig,
+    buckets: Mutex<HashMap<(String, Class), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimitConfig) -> Self {
+        RateLimiter {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn limit(&self, class: Class) -> Option<(f64, f64)> {
+        let (per_minute, burst) = match class {
+            Class::Read => (self.limits.requests_per_minute, self.limits.burst),
+            Class::Llm => (self.limits.llm_requests_per_minute, self.limits.llm_burst),
+        };
+        match per_minute {
+            0 => None,
+            per_minute => Some((per_minute as f64 / 60.0, burst.max(1) as f64)),
+        }
+    }
+
+    /// Takes a request from the bucket of `client`, or says how long until
+    /// there is one
+    pub fn check(&self, client: &str, class: Class, now: Instant) -> Result<(), Duration> {
+        let (per_second, burst) = match self.limit(class) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|(_, class), bucket| match self.limit(*class) {
+                Some((per_second, burst)) => {
+                    let elapsed = now.saturating_duration_since(bucket.refilled_at);
+                    bucket.tokens + elapsed.as_secs_f64() * per_second < burst
+                }
+                None => false,
+            });
+        }
+        let bucket = buckets
+            .entry((client.to_string(), class))
+            .or_insert(Bucket {
+                tokens: burst,
+                refilled_at: now,
+            });
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * per_second).min(burst);
+        bucket.refilled_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+    }
+}
+
+// The token that `require_token` accepted, or else the peer address. The
+// forwarded headers are not trusted, any client can set them.
+fn client(req: &ServiceRequest) -> String {
+    if let Some(token) = req.extensions().get::<TokenModel>() {
+        return format!("token:{}", token.id);
+    }
+    match req.peer_addr() {
+        Some(addr) => format!("ip:{}", addr.ip()),
+        None => "local".to_string(),
+    }
+}
+
+/// Answers 429 with a Retry-After header once a client has spent its
+/// requests
+pub async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let limited = match req.app_data::<web::Data<Resources>>() {
+        Some(resources) => {
+            let client = client(&req);
+            resources
+                .rate_limiter
+                .check(&client, class(req.path()), Instant::now())
+                .map_err(|wait| (client, wait))
+        }
+        None => Ok(()),
+    };
+    if let Err((client, wait)) = limited {
+        warn!("Rate limited {} on {}", client, req.path());
+        let mut response =
+            ApiError::RateLimited("rate limit exceeded".to_string()).error_response();
+        response.headers_mut().insert(
+            header::RETRY_AFTER,
+            HeaderValue::from(wait.as_secs_f64().ceil().max(1.0) as u64),
+        );
+        return Ok(req.into_response(response));
+    }
+    next.call(req).await.map(|res| res.map_into_boxed_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets_refill_per_client_and_class() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            requests_per_minute: 60,
+            burst: 2,
+            llm_requests_per_minute: 6,
+            llm_burst: 1,
+        });
+        let start = Instant::now();
+
+        assert!(limiter.check("ip:10.0.0.1", Class::Read, start).is_ok());
+        assert!(limiter.check("ip:10.0.0.1", Class::Read, start).is_ok());
+        let wait = limiter
+            .check("ip:10.0.0.1", Class::Read, start)
+            .unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+        assert!(limiter.check("ip:10.0.0.2", Class::Read, start).is_ok());
+        assert!(limiter
+            .check("ip:10.0.0.1", Class::Read, start + Duration::from_secs(1))
+            .is_ok());
+
+        assert!(limiter.check("token:a", Class::Llm, start).is_ok());
+        let wait = limiter.check("token:a", Class::Llm, start).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(10));
+
+        let unlimited = RateLimiter::new(RateLimitConfig::default());
+        for _ in 0..100 {
+            assert!(unlimited.check("local", Class::Llm, start).is_ok());
+        }
+        assert_eq!(class("/api/v1/chat/alice/complete"), Class::Llm);
+        assert_eq!(class("/api/v1/summary/alice/2024-03-01"), Class::Llm);
+        assert_eq!(class("/api/v1/chat/alice/search"), Class::Read);
+    }
+}
@@ -0,0 +1,69 @@
+use actix_web::{web, HttpResponse};
+
+use super::error::ApiError;
+use crate::{
+    services::reminders::{ReminderError, ReminderRequest, ReminderService},
+    Resources,
+};
+
+/// Keeps reminders with the configured stores and chat model
+pub fn reminder_service(resources: &Resources) -> ReminderService {
+    ReminderService {
+        reminder_repo: resources.reminder_repo.clone(),
+        attribute_repo: resources.user_attributes_repo.clone(),
+        chat_client: resources.chat_client.clone(),
+        event_hub: resources.event_hub.clone(),
+    }
+}
+
+/// Stores a reminder, reading when it is due from its text when the request
+/// does not say
+pub async fn create_reminder(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    payload: web::Json<ReminderRequest>,
+) -> Result<HttpResponse, ApiError> {
+    payload.validate(resources.config.server.max_content_length)?;
+    let username = &params.0.clone();
+    let now = chrono::Utc::now().timestamp();
+    let reminder = reminder_service(&resources)
+        .create(username, payload.into_inner(), now)
+        .await
+        .map_err(|e| match e {
+            ReminderError::NoTime => ApiError::invalid("text", e.to_string()),
+            ReminderError::Past => ApiError::invalid("due_at", e.to_string()),
+            ReminderError::Chat(e) => ApiError::from(e),
+            ReminderError::Storage => ApiError::internal("Error saving reminder"),
+        })?;
+    Ok(HttpResponse::Ok().json(reminder))
+}
+
+/// Pending and sent reminders, the earliest due first
+pub async fn get_reminders(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+) -> Result<HttpResponse, ApiError> {
+    let username = &params.0.clone();
+    let reminders = resources
+        .reminder_repo
+        .lock()
+        .await
+        .get_reminders(username)
+        .map_err(|_| ApiError::internal("Error getting reminders"))?;
+    Ok(HttpResponse::Ok().json(reminders))
+}
+
+pub async fn delete_reminder(
+    resources: web::Data<Resources>,
+    params: web::Path<(String, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let username = &params.0.clone();
+    let id = &params.1.clone();
+    resources
+        .reminder_repo
+        .lock()
+        .await
+        .delete_reminder(username, id)
+        .map_err(|_| ApiError::NotFound(format!("no reminder {}", id)))?;
+    Ok(HttpResponse::NoContent().finish())
+}
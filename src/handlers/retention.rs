@@ -0,0 +1,69 @@
+use actix_web::{web, HttpResponse};
+
+use super::error::ApiError;
+use crate::{
+    config::RetentionPolicy,
+    services::{retention::RetentionService, summary::SummaryService},
+    Resources,
+};
+
+/// Enforces retention with the configured stores and default policy
+pub fn retention_service(resources: &Resources) -> RetentionService {
+    RetentionService {
+        retention_repo: resources.retention_repo.clone(),
+        message_repo: resources.message_repo.clone(),
+        summary_service: SummaryService {
+            message_repo: resources.message_repo.clone(),
+            chat_client: resources.chat_client.clone(),
+            summary_repo: resources.summary_repo.clone(),
+            embedding_client: resources.embeddings_client.clone(),
+        },
+        default_policy: resources.config.storage.retention,
+    }
+}
+
+/// The policy the user's messages are kept by, the configured one unless
+/// they set their own
+pub async fn get_retention(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+) -> Result<HttpResponse, ApiError> {
+    let username = &params.0.clone();
+    let policy = retention_service(&resources)
+        .policy(username)
+        .await
+        .map_err(|_| ApiError::internal("Error getting retention policy"))?;
+    Ok(HttpResponse::Ok().json(policy))
+}
+
+pub async fn set_retention(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    payload: web::Json<RetentionPolicy>,
+) -> Result<HttpResponse, ApiError> {
+    payload.validate()?;
+    let username = &params.0.clone();
+    let policy = payload.into_inner();
+    resources
+        .retention_repo
+        .lock()
+        .await
+        .save_policy(username, policy)
+        .map_err(|_| ApiError::internal("Error saving retention policy"))?;
+    Ok(HttpResponse::Ok().json(policy))
+}
+
+/// Goes back to the configured policy
+pub async fn delete_retention(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+) -> Result<HttpResponse, ApiError> {
+    let username = &params.0.clone();
+    resources
+        .retention_repo
+        .lock()
+        .await
+        .delete_policy(username)
+        .map_err(|_| ApiError::internal("Error removing retention policy"))?;
+    Ok(HttpResponse::NoContent().finish())
+}
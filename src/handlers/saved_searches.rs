@@ -0,0 +1,57 @@
+use actix_web::{web, HttpResponse};
+
+use super::error::ApiError;
+use crate::{
+    services::saved_searches::{SavedSearchRequest, SavedSearchService},
+    Resources,
+};
+
+pub async fn save_search(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    payload: web::Json<SavedSearchRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let resources = resources.into_inner();
+    let saved_search_service = SavedSearchService {
+        embedding_client: resources.embeddings_client.clone(),
+        message_repo: resources.message_repo.clone(),
+        saved_search_repo: resources.saved_search_repo.clone(),
+        event_hub: resources.event_hub.clone(),
+    };
+
+    let username = &params.0.clone();
+    let search = saved_search_service
+        .save_search(username, payload.into_inner())
+        .await
+        .map_err(|_| ApiError::internal("Error saving search"))?;
+    Ok(HttpResponse::Ok().json(search))
+}
+
+pub async fn get_searches(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+) -> Result<HttpResponse, ApiError> {
+    let username = &params.0.clone();
+    let searches = resources
+        .saved_search_repo
+        .lock()
+        .await
+        .get_searches(username)
+        .map_err(|_| ApiError::internal("Error getting saved searches"))?;
+    Ok(HttpResponse::Ok().json(searches))
+}
+
+pub async fn delete_search(
+    resources: web::Data<Resources>,
+    params: web::Path<(String, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let username = &params.0.clone();
+    let name = &params.1.clone();
+    resources
+        .saved_search_repo
+        .lock()
+        .await
+        .delete_search(username, name)
+        .map_err(|_| ApiError::NotFound(format!("no saved search named {}", name)))?;
+    Ok(HttpResponse::NoContent().finish())
+}
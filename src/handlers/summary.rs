@@ -1,29 +1,135 @@
-use actix_web::{web, HttpResponse};
-use tracing::error;
+use super::error::ApiError;
+use crate::{
+    handlers::caching::{cached_json, PAST_DAY, REVALIDATE},
+    repos::summaries::SummaryModel,
+    services::summary::{Period, SummaryParams, SummaryRangeQuery, SummaryService},
+    Resources,
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::NaiveDate;
 
-use crate::{services::summary::SummaryService, Resources};
+// Refuses a model `params` asks for that is not allowed
+fn check_model(params: &SummaryParams) -> Result<(), ApiError> {
+    match &params.model {
+        Some(model) if !crate::config::get().models.allows_chat_model(model) => Err(
+            ApiError::invalid("model", format!("model {} is not allowed", model)),
+        ),
+        _ => Ok(()),
+    }
+}
 
 pub async fn get_summary(
+    req: HttpRequest,
     resources: web::Data<Resources>,
     params: web::Path<(String, String)>,
-) -> HttpResponse {
+    query: web::Query<SummaryParams>,
+) -> Result<HttpResponse, ApiError> {
+    check_model(&query)?;
     let resources = resources.into_inner();
 
     let summary_service = SummaryService {
         message_repo: resources.message_repo.clone(),
+        chat_client: resources.chat_client.clone(),
+        summary_repo: resources.summary_repo.clone(),
         embedding_client: resources.embeddings_client.clone(),
     };
 
     let username = &params.0.clone();
-    let date = &params.1.clone();
-    let summary = summary_service.summarize_chats_for_user_for_date(username.clone(), date.clone()).await;
-    let summary = match summary {
-        Ok(summary) => summary,
-        Err(_) => {
-            error!("Error getting summary");
-            return HttpResponse::InternalServerError().finish();
-        }
+    let date = NaiveDate::parse_from_str(&params.1, "%Y-%m-%d")
+        .map_err(|_| ApiError::invalid("date", "Dates must be YYYY-MM-DD"))?;
+    let today = chrono::Utc::now().date_naive();
+    let summary = summary_service
+        .get_summary(username, date, today, query.model.as_deref())
+        .await
+        .map_err(|_| ApiError::internal("Error getting summary"))?;
+    // only days that are over can be cached, today may still grow
+    let cache_control = match date < today {
+        true => PAST_DAY,
+        false => REVALIDATE,
     };
-    HttpResponse::Ok().json(summary)
+    Ok(cached_json(
+        &req,
+        &summary.without_embedding(),
+        cache_control,
+    ))
+}
+
+pub async fn list_summaries(
+    resources: web::Data<Resources>,
+    params: web::Path<String>,
+    query: web::Query<SummaryRangeQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let (from, to) = query
+        .dates()
+        .ok_or_else(|| ApiError::invalid("from_date", "Dates must be YYYY-MM-DD"))?;
+    let summary_service = SummaryService {
+        message_repo: resources.message_repo.clone(),
+        chat_client: resources.chat_client.clone(),
+        summary_repo: resources.summary_repo.clone(),
+        embedding_client: resources.embeddings_client.clone(),
+    };
+
+    let summaries = summary_service
+        .list_summaries(&params, from, to)
+        .await
+        .map_err(|_| ApiError::internal("Error listing summaries"))?;
+    Ok(HttpResponse::Ok().json(
+        summaries
+            .into_iter()
+            .map(SummaryModel::without_embedding)
+            .collect::<Vec<SummaryModel>>(),
+    ))
+}
+
+pub async fn get_week_summary(
+    req: HttpRequest,
+    resources: web::Data<Resources>,
+    params: web::Path<(String, String)>,
+    query: web::Query<SummaryParams>,
+) -> Result<HttpResponse, ApiError> {
+    let period = Period::parse_week(&params.1);
+    get_rollup(req, resources, &params.0, period, query.into_inner()).await
 }
 
+pub async fn get_month_summary(
+    req: HttpRequest,
+    resources: web::Data<Resources>,
+    params: web::Path<(String, String)>,
+    query: web::Query<SummaryParams>,
+) -> Result<HttpResponse, ApiError> {
+    let period = Period::parse_month(&params.1);
+    get_rollup(req, resources, &params.0, period, query.into_inner()).await
+}
+
+async fn get_rollup(
+    req: HttpRequest,
+    resources: web::Data<Resources>,
+    username: &str,
+    period: Option<Period>,
+    query: SummaryParams,
+) -> Result<HttpResponse, ApiError> {
+    let period = period.ok_or_else(|| ApiError::invalid("period", "Not a week or month"))?;
+    check_model(&query)?;
+    let summary_service = SummaryService {
+        message_repo: resources.message_repo.clone(),
+        chat_client: resources.chat_client.clone(),
+        summary_repo: resources.summary_repo.clone(),
+        embedding_client: resources.embeddings_client.clone(),
+    };
+
+    let today = chrono::Utc::now().date_naive();
+    let rollup = summary_service
+        .get_rollup(username, period, today, query.model.as_deref())
+        .await
+        .map_err(|_| ApiError::internal("Error rolling up summaries"))?;
+    let over = period.days().last().is_some_and(|last| *last < today);
+    let cache_control = match over {
+        true => PAST_DAY,
+        false => REVALIDATE,
+    };
+    Ok(cached_json(
+        &req,
+        &rollup.without_embedding(),
+        cache_control,
+    ))
+}
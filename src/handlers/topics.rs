@@ -0,0 +1,26 @@
+use actix_web::{web, HttpResponse};
+
+use super::error::ApiError;
+use crate::{
+    services::topics::{TopicService, TopicsQuery},
+    Resources,
+};
+
+pub async fn get_topics(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    query: web::Query<TopicsQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let topic_service = TopicService {
+        message_repo: resources.message_repo.clone(),
+        embedding_client: resources.embeddings_client.clone(),
+        chat_client: resources.chat_client.clone(),
+    };
+
+    let username = &params.0.clone();
+    let topics = topic_service
+        .get_topics(username, query.k)
+        .await
+        .map_err(|_| ApiError::internal("Error clustering topics"))?;
+    Ok(HttpResponse::Ok().json(topics))
+}
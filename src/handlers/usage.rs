@@ -0,0 +1,20 @@
+use actix_web::{web, HttpResponse};
+
+use super::error::ApiError;
+use crate::{services::usage::UsageService, Resources};
+
+pub async fn get_usage(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+) -> Result<HttpResponse, ApiError> {
+    let usage_service = UsageService {
+        usage_repo: resources.usage_repo.clone(),
+    };
+
+    let username = &params.0.clone();
+    let usage = usage_service
+        .get_usage(username)
+        .await
+        .map_err(|_| ApiError::internal("Error loading token usage"))?;
+    Ok(HttpResponse::Ok().json(usage))
+}
@@ -1,60 +1,197 @@
 use actix_web::{web, HttpResponse};
 use tracing::error;
 
+use super::error::ApiError;
 use crate::{
-    services::user_attributes::{AttributeRequest, UserAttributeService},
+    hub::HubEvent,
+    services::{
+        extraction::{ExtractionQuery, ExtractionService},
+        user_attributes::{
+            parse_point_in_time, AttributeDocument, AttributeQuery, AttributeRequest, ImportQuery,
+            UserAttributeService,
+        },
+    },
     Resources,
 };
 pub async fn save_attribute(
     resources: web::Data<Resources>,
     params: web::Path<(String,)>,
     payload: web::Json<AttributeRequest>,
-) -> HttpResponse {
+) -> Result<HttpResponse, ApiError> {
     let resources = resources.into_inner();
     let username = &params.0.clone();
     let attribute = &payload.attribute.clone();
     let value = &payload.value.clone();
+    let expires_at = payload.expires_at;
 
     let mut user_attributes_service = UserAttributeService {
         attribute_repo: resources.user_attributes_repo.clone(),
     };
 
-    let attribute = user_attributes_service
-        .save_attribute(username, attribute, value)
-        .await;
-
-    match attribute {
-        Ok(attribute) => attribute,
-        Err(_) => {
-            error!("Error saving attribute");
-            return HttpResponse::InternalServerError().finish();
-        }
-    };
-    HttpResponse::Ok().json(attribute)
+    user_attributes_service
+        .save_attribute(username, attribute, value, expires_at)
+        .await
+        .map_err(|_| ApiError::internal("Error saving attribute"))?;
+    resources.event_hub.publish(HubEvent::AttributeChanged {
+        username: username.clone(),
+        attribute: payload.attribute.clone(),
+    });
+    Ok(HttpResponse::Ok().finish())
 }
 
 pub async fn get_attribute(
     resources: web::Data<Resources>,
     params: web::Path<(String, String)>,
-) -> HttpResponse {
+    query: web::Query<AttributeQuery>,
+) -> Result<HttpResponse, ApiError> {
     let resources = resources.into_inner();
     let username = &params.0.clone();
     let attribute = &params.1.clone();
 
+    let user_attributes_service = UserAttributeService {
+        attribute_repo: resources.user_attributes_repo.clone(),
+    };
+
+    let attribute = match &query.at {
+        Some(at) => match parse_point_in_time(at) {
+            Some(at) => {
+                user_attributes_service
+                    .get_attribute_at(username, attribute, at)
+                    .await
+            }
+            None => return Err(ApiError::invalid("at", "Invalid point in time")),
+        },
+        None => user_attributes_service.get_attribute(username, attribute).await,
+    };
+
+    let attribute = attribute.map_err(|_| ApiError::internal("Error getting attribute"))?;
+    Ok(HttpResponse::Ok().json(attribute))
+}
+
+pub async fn get_attribute_history(
+    resources: web::Data<Resources>,
+    params: web::Path<(String, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let resources = resources.into_inner();
+    let (username, attribute) = params.into_inner();
+
+    let user_attributes_service = UserAttributeService {
+        attribute_repo: resources.user_attributes_repo.clone(),
+    };
+
+    let history = user_attributes_service
+        .get_history(&username, &attribute)
+        .await
+        .map_err(|_| ApiError::internal("Error getting attribute history"))?;
+    Ok(HttpResponse::Ok().json(history))
+}
+
+pub async fn delete_attribute(
+    resources: web::Data<Resources>,
+    params: web::Path<(String, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let resources = resources.into_inner();
+    let (username, attribute) = params.into_inner();
+
+    let mut user_attributes_service = UserAttributeService {
+        attribute_repo: resources.user_attributes_repo.clone(),
+    };
+
+    user_attributes_service
+        .delete_attribute(&username, &attribute)
+        .await
+        .map_err(|_| ApiError::NotFound(format!("no attribute named {}", attribute)))?;
+    resources.event_hub.publish(HubEvent::AttributeChanged {
+        username,
+        attribute,
+    });
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn export_attributes(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+) -> Result<HttpResponse, ApiError> {
+    let resources = resources.into_inner();
+    let username = &params.0.clone();
+
+    let user_attributes_service = UserAttributeService {
+        attribute_repo: resources.user_attributes_repo.clone(),
+    };
+
+    let document = user_attributes_service
+        .export_attributes(username)
+        .await
+        .map_err(|_| ApiError::internal("Error exporting attributes"))?;
+    Ok(HttpResponse::Ok().json(document))
+}
+
+pub async fn import_attributes(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    query: web::Query<ImportQuery>,
+    payload: web::Json<AttributeDocument>,
+) -> Result<HttpResponse, ApiError> {
+    let resources = resources.into_inner();
+    let username = &params.0.clone();
+
     let mut user_attributes_service = UserAttributeService {
         attribute_repo: resources.user_attributes_repo.clone(),
     };
 
-    let attribute = user_attributes_service.get_attribute(username, attribute).await;
+    let document = payload.into_inner();
+    let attributes = document
+        .attributes
+        .iter()
+        .map(|attribute| attribute.attribute.clone())
+        .collect::<Vec<String>>();
 
-    let attribute = match attribute {
-        Ok(attribute) => attribute,
-        Err(_) => {
-            error!("Error getting attribute");
-            return HttpResponse::InternalServerError().finish();
+    match user_attributes_service
+        .import_attributes(username, document, query.strategy)
+        .await
+    {
+        Ok(result) => {
+            for attribute in attributes {
+                resources.event_hub.publish(HubEvent::AttributeChanged {
+                    username: username.clone(),
+                    attribute,
+                });
+            }
+            Ok(HttpResponse::Ok().json(result))
         }
+        // the report of what conflicted is the answer
+        Err(result) => {
+            error!("Attribute import rejected due to conflicts");
+            Ok(HttpResponse::Conflict().json(result))
+        }
+    }
+}
+
+/// Infers attributes from one day of the user's messages, returning the
+/// ones that were saved
+pub async fn extract_attributes(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    query: web::Query<ExtractionQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let date = match &query.date {
+        Some(date) => chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .map_err(|_| ApiError::invalid("date", "Dates must be YYYY-MM-DD"))?,
+        None => chrono::Utc::now().date_naive(),
+    };
+    let extraction_service = ExtractionService {
+        message_repo: resources.message_repo.clone(),
+        chat_client: resources.chat_client.clone(),
+        attribute_repo: resources.user_attributes_repo.clone(),
+        contact_repo: resources.contact_repo.clone(),
+        event_hub: resources.event_hub.clone(),
     };
-    HttpResponse::Ok().json(attribute)
+
+    let attributes = extraction_service
+        .extract_for_date(&params.0, date)
+        .await
+        .map_err(|_| ApiError::internal("Error extracting attributes"))?;
+    Ok(HttpResponse::Ok().json(attributes))
 }
 
 #[cfg(test)]
@@ -64,12 +201,12 @@ mod tests {
 
     use crate::{handlers::user_attributes::save_attribute, Resources};
 
-    use super::get_attribute;
+    use super::{export_attributes, get_attribute, import_attributes};
 
     #[actix::test]
     async fn test_save_attribute() {
         let resources = Resources::new();
-        let mut app = test::init_service(App::new().app_data(web::Data::new(resources)).route(
+        let app = test::init_service(App::new().app_data(web::Data::new(resources)).route(
             "/api/v1/attribute/{username}",
             web::post().to(save_attribute),
         ).route(
@@ -80,17 +217,17 @@ mod tests {
 
         let req = test::TestRequest::post()
             .uri("/api/v1/attribute/username")
-            .set_json(&json!({"attribute": "test_attr", "value": "test"}))
+            .set_json(json!({"attribute": "test_attr", "value": "test"}))
             .to_request();
 
-        let resp = test::call_service(&mut app, req).await;
+        let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::OK);
 
         // check if attribute is present in memory
         let resp = test::TestRequest::get()
             .uri("/api/v1/attribute/username/test_attr")
             .to_request();
-        let resp = test::call_service(&mut app, resp).await;
+        let resp = test::call_service(&app, resp).await;
         assert_eq!(resp.status(), StatusCode::OK);
 
     }
@@ -98,7 +235,7 @@ mod tests {
     #[actix::test]
     async fn test_get_attribute_when_absent() {
         let resources = Resources::new();
-        let mut app = test::init_service(App::new().app_data(web::Data::new(resources)).route(
+        let app = test::init_service(App::new().app_data(web::Data::new(resources)).route(
             "/api/v1/attributes/{username}/{attribute}",
             web::get().to(get_attribute),
         ))
@@ -108,7 +245,64 @@ mod tests {
             .uri("/api/v1/attribute/username/test_key")
             .to_request();
 
-        let resp = test::call_service(&mut app, req).await;
+        let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::NOT_FOUND);
     }
+
+    #[actix::test]
+    async fn test_export_then_import_attributes() {
+        let resources = Resources::new();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(resources))
+                .route(
+                    "/api/v1/attribute/{username}",
+                    web::post().to(save_attribute),
+                )
+                .route(
+                    "/api/v1/attribute/{username}",
+                    web::get().to(export_attributes),
+                )
+                .route(
+                    "/api/v1/attribute/{username}/import",
+                    web::post().to(import_attributes),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/v1/attribute/export_user")
+            .set_json(json!({"attribute": "city", "value": "Oslo"}))
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/attribute/export_user")
+            .to_request();
+        let document: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(document["attributes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|attribute| attribute["attribute"] == "city"));
+
+        let import_uri = format!(
+            "/api/v1/attribute/import_{}/import?strategy=fail",
+            uuid::Uuid::new_v4()
+        );
+        let req = test::TestRequest::post()
+            .uri(&import_uri)
+            .set_json(&document)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // importing the same document again conflicts with itself
+        let req = test::TestRequest::post()
+            .uri(&import_uri)
+            .set_json(&document)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+    }
 }
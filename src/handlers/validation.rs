@@ -0,0 +1,73 @@
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    error::JsonPayloadError,
+    middleware::Next,
+    HttpRequest, ResponseError,
+};
+
+use super::error::ApiError;
+use crate::services::validation;
+
+/// Answers JSON bodies that are too large or do not parse with an
+/// `ApiError` instead of actix's plain text one
+pub fn json_error(error: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    match &error {
+        JsonPayloadError::OverflowKnownLength { .. } | JsonPayloadError::Overflow { .. } => {
+            ApiError::PayloadTooLarge(error.to_string()).into()
+        }
+        _ => ApiError::InvalidInput {
+            message: error.to_string(),
+            field: None,
+        }
+        .into(),
+    }
+}
+
+/// The segment of an API path naming the user, as routed. Every per-user
+/// route has it after the area, apart from the admin routes whose first
+/// segment is an action, such as `/api/v1/admin/storage/{username}`.
+fn username_segment(path: &str) -> Option<&str> {
+    let mut segments = path.strip_prefix("/api/v1/")?.split('/');
+    let area = segments.next()?;
+    let first = segments.next()?;
+    let second = segments.next();
+    match (area, first, second) {
+        ("admin", "reembed" | "storage", Some(username)) => Some(username),
+        ("admin", _, None) => None,
+        (_, username, _) => Some(username),
+    }
+}
+
+/// Refuses requests for usernames that could not name a user's folder,
+/// before any handler turns one into a storage path
+pub async fn reject_invalid_usernames(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    if let Some(segment) = username_segment(req.path()) {
+        let username = percent_encoding::percent_decode_str(segment).decode_utf8_lossy();
+        if let Err(e) = validation::username(&username) {
+            return Ok(req.into_response(ApiError::from(e).error_response()));
+        }
+    }
+    next.call(req).await.map(|res| res.map_into_boxed_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usernames_are_found_in_routed_paths() {
+        assert_eq!(username_segment("/api/v1/chat/alice/search"), Some("alice"));
+        assert_eq!(username_segment("/api/v1/usage/alice"), Some("alice"));
+        assert_eq!(username_segment("/api/v1/chat/%2E%2E"), Some("%2E%2E"));
+        assert_eq!(username_segment("/api/v1/admin/bob/dedupe"), Some("bob"));
+        assert_eq!(username_segment("/api/v1/admin/reembed/bob"), Some("bob"));
+        assert_eq!(username_segment("/api/v1/admin/storage/bob"), Some("bob"));
+        assert_eq!(username_segment("/api/v1/admin/users"), None);
+        assert_eq!(username_segment("/api/v1/openapi.json"), None);
+        assert_eq!(username_segment("/metrics"), None);
+    }
+}
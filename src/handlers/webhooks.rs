@@ -0,0 +1,58 @@
+use actix_web::{web, HttpResponse};
+
+use super::error::ApiError;
+use crate::{
+    services::webhooks::{RegisterError, WebhookRequest, WebhookService},
+    Resources,
+};
+
+pub async fn register_webhook(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+    payload: web::Json<WebhookRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let webhook_service = WebhookService::new(resources.webhook_repo.clone());
+
+    let username = &params.0.clone();
+    let webhook = webhook_service
+        .register(username, payload.into_inner())
+        .await
+        .map_err(|e| match e {
+            RegisterError::Invalid(e) => ApiError::from(e),
+            RegisterError::Failed => ApiError::internal("Error saving webhook"),
+        })?;
+    Ok(HttpResponse::Ok().json(webhook))
+}
+
+/// Webhooks with their latest deliveries, without their secrets
+pub async fn get_webhooks(
+    resources: web::Data<Resources>,
+    params: web::Path<(String,)>,
+) -> Result<HttpResponse, ApiError> {
+    let username = &params.0.clone();
+    let webhooks = resources
+        .webhook_repo
+        .lock()
+        .await
+        .get_webhooks(username)
+        .map_err(|_| ApiError::internal("Error getting webhooks"))?
+        .into_iter()
+        .map(|webhook| webhook.without_secret())
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(webhooks))
+}
+
+pub async fn delete_webhook(
+    resources: web::Data<Resources>,
+    params: web::Path<(String, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let username = &params.0.clone();
+    let id = &params.1.clone();
+    resources
+        .webhook_repo
+        .lock()
+        .await
+        .delete_webhook(username, id)
+        .map_err(|_| ApiError::NotFound(format!("no webhook {}", id)))?;
+    Ok(HttpResponse::NoContent().finish())
+}
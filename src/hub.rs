@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Something that happened to a user's memory
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HubEvent {
+    MessageSaved {
+        username: String,
+        hash: String,
+        role: String,
+    },
+    AttributeChanged {
+        username: String,
+        attribute: String,
+    },
+    SummaryReady {
+        username: String,
+        conversation_id: String,
+    },
+    SearchMatched {
+        username: String,
+        search: String,
+        hashes: Vec<String>,
+    },
+    ReembedProgress {
+        username: String,
+        done: usize,
+        total: usize,
+    },
+    ReembedFinished {
+        username: String,
+        model: String,
+        success: bool,
+    },
+    ImportProgress {
+        username: String,
+        format: String,
+        done: usize,
+        total: usize,
+    },
+    /// A new item of a feed was remembered
+    FeedItem {
+        username: String,
+        feed: String,
+        title: Option<String>,
+        url: Option<String>,
+        document_id: String,
+    },
+    /// A reminder came due
+    ReminderDue {
+        username: String,
+        id: String,
+        text: String,
+        due_at: i64,
+    },
+}
+
+impl HubEvent {
+    pub fn username(&self) -> &str {
+        match self {
+            HubEvent::MessageSaved { username, .. } => username,
+            HubEvent::AttributeChanged { username, .. } => username,
+            HubEvent::SummaryReady { username, .. } => username,
+            HubEvent::SearchMatched { username, .. } => username,
+            HubEvent::ReembedProgress { username, .. } => username,
+            HubEvent::ReembedFinished { username, .. } => username,
+            HubEvent::ImportProgress { username, .. } => username,
+            HubEvent::FeedItem { username, .. } => username,
+            HubEvent::ReminderDue { username, .. } => username,
+        }
+    }
+}
+
+/// In-process fan-out of events. Every user gets their own broadcast
+/// channel so any number of listeners can follow them, and bridges such as
+/// MQTT can follow every user through `subscribe_all`.
+pub struct EventHub {
+    capacity: usize,
+    all: broadcast::Sender<HubEvent>,
+    channels: std::sync::Mutex<HashMap<String, broadcast::Sender<HubEvent>>>,
+}
+
+impl EventHub {
+    pub fn new(capacity: usize) -> Self {
+        let (all, _) = broadcast::channel(capacity);
+        EventHub {
+            capacity,
+            all,
+            channels: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn subscribe(&self, username: &str) -> broadcast::Receiver<HubEvent> {
+        let mut channels = self.channels.lock().unwrap();
+        channels
+            .entry(username.to_string())
+            .or_insert_with(|| broadcast::channel(self.capacity).0)
+            .subscribe()
+    }
+
+    pub fn subscribe_all(&self) -> broadcast::Receiver<HubEvent> {
+        self.all.subscribe()
+    }
+
+    /// Events buffered for the slowest instance-wide subscriber
+    pub fn queue_depth(&self) -> usize {
+        self.all.len()
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.all.receiver_count()
+            + self
+                .channels
+                .lock()
+                .unwrap()
+                .values()
+                .map(|sender| sender.receiver_count())
+                .sum::<usize>()
+    }
+
+    pub fn publish(&self, event: HubEvent) {
+        let mut channels = self.channels.lock().unwrap();
+        let username = event.username().to_string();
+        if let Some(sender) = channels.get(&username) {
+            // a send only fails when nobody is listening any more
+            if sender.send(event.clone()).is_err() {
+                channels.remove(&username);
+            }
+        }
+        let _ = self.all.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_events_reach_only_their_user() {
+        let hub = EventHub::new(16);
+        let mut alice = hub.subscribe("alice");
+        let mut alice_again = hub.subscribe("alice");
+        let mut bob = hub.subscribe("bob");
+        let mut all = hub.subscribe_all();
+
+        hub.publish(HubEvent::AttributeChanged {
+            username: "alice".to_string(),
+            attribute: "city".to_string(),
+        });
+
+        assert_eq!(alice.recv().await.unwrap().username(), "alice");
+        assert_eq!(alice_again.recv().await.unwrap().username(), "alice");
+        assert_eq!(all.recv().await.unwrap().username(), "alice");
+        assert!(bob.try_recv().is_err());
+    }
+}
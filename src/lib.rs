@@ -0,0 +1,247 @@
+//! Muninn remembers what was said in chats and hands the relevant parts back
+//! as context. The server in `main.rs` serves it over HTTP, `Muninn` offers
+//! the same memory to programs that embed it.
+
+// Services log why they failed and answer `Err(())`, and the stores and
+// clients are built with `new` from the configuration rather than defaulted
+#![allow(clippy::result_unit_err, clippy::new_without_default)]
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use clients::{embedding_cache::CachedEmbeddingsClient, embeddings::OllamaEmbeddingsClient};
+use repos::{
+    attributes::FsAttributeRepo, briefings::FsBriefingRepo, contacts::FsContactRepo, feedback::FsFeedbackRepo, messages::FsMessageRepo, presets::FsPresetRepo,
+    saved_searches::FsSavedSearchRepo, suppressions::FsSuppressionRepo, threads::FsThreadSummaryRepo,
+};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+pub mod clients;
+pub mod config;
+mod embedded;
+mod handlers;
+pub mod hub;
+mod metrics;
+pub mod namespace;
+pub mod repos;
+pub mod server;
+pub mod services;
+pub mod username;
+mod scheduler;
+mod tls;
+
+pub use embedded::Muninn;
+
+struct Resources {
+    config: &'static config::Config,
+    message_repo: Arc<Mutex<dyn repos::messages::MessageRepo>>,
+    embeddings_client: Arc<Mutex<dyn clients::embeddings::EmbeddingsClient>>,
+    user_attributes_repo: Arc<Mutex<dyn repos::attributes::AttributeRepo>>,
+    thread_repo: Arc<Mutex<dyn repos::threads::ThreadSummaryRepo>>,
+    suppression_repo: Arc<Mutex<dyn repos::suppressions::SuppressionRepo>>,
+    preset_repo: Arc<Mutex<dyn repos::presets::PresetRepo>>,
+    event_hub: Arc<hub::EventHub>,
+    storage_metrics: Arc<Mutex<metrics::StorageMetrics>>,
+    contact_repo: Arc<Mutex<dyn repos::contacts::ContactRepo>>,
+    briefing_repo: Arc<Mutex<dyn repos::briefings::BriefingRepo>>,
+    saved_search_repo: Arc<Mutex<dyn repos::saved_searches::SavedSearchRepo>>,
+    feedback_repo: Arc<Mutex<dyn repos::feedback::FeedbackRepo>>,
+    job_statuses: Arc<scheduler::JobStatuses>,
+    token_repo: Arc<Mutex<dyn repos::tokens::TokenRepo>>,
+    summary_repo: Arc<Mutex<dyn repos::summaries::SummaryRepo>>,
+    chat_client: Arc<Mutex<dyn clients::chat::ChatClient>>,
+    usage_repo: Arc<Mutex<dyn repos::usage::UsageRepo>>,
+    embedding_queue: Option<Arc<services::embedding_queue::EmbeddingQueue>>,
+    rate_limiter: handlers::rate_limit::RateLimiter,
+    webhook_repo: Arc<Mutex<dyn repos::webhooks::WebhookRepo>>,
+    transcription_client: Arc<dyn clients::transcription::TranscriptionClient>,
+    page_client: Arc<dyn clients::web::PageClient>,
+    feed_repo: Arc<Mutex<dyn repos::feeds::FeedRepo>>,
+    reminder_repo: Arc<Mutex<dyn repos::reminders::ReminderRepo>>,
+    retention_repo: Arc<Mutex<dyn repos::retention::RetentionRepo>>,
+    storage_usage_repo: Arc<Mutex<dyn repos::storage_usage::StorageUsageRepo>>,
+    /// Stores messages and attributes are replicated to, when configured
+    replica: Option<repos::replication::Replica>,
+}
+
+impl Resources {
+    fn new() -> Self {
+        let config = config::get();
+        Resources {
+            config,
+            message_repo: Arc::new(Mutex::new(FsMessageRepo::with_day_cache(
+                config.storage.day_cache_size,
+            ))),
+            embeddings_client: match config.models.embedding_cache_size {
+                0 => Arc::new(Mutex::new(OllamaEmbeddingsClient::new())),
+                size => Arc::new(Mutex::new(CachedEmbeddingsClient::new(
+                    Box::new(OllamaEmbeddingsClient::new()),
+                    size,
+                    std::time::Duration::from_secs(config.models.embedding_cache_ttl_secs),
+                ))),
+            },
+            user_attributes_repo: Arc::new(Mutex::new(FsAttributeRepo::new())),
+            thread_repo: Arc::new(Mutex::new(FsThreadSummaryRepo::new())),
+            suppression_repo: Arc::new(Mutex::new(FsSuppressionRepo::new())),
+            preset_repo: Arc::new(Mutex::new(FsPresetRepo::new())),
+            event_hub: Arc::new(hub::EventHub::new(64)),
+            storage_metrics: Arc::new(Mutex::new(metrics::StorageMetrics::default())),
+            contact_repo: Arc::new(Mutex::new(FsContactRepo::new())),
+            briefing_repo: Arc::new(Mutex::new(FsBriefingRepo::new())),
+            saved_search_repo: Arc::new(Mutex::new(FsSavedSearchRepo::new())),
+            feedback_repo: Arc::new(Mutex::new(FsFeedbackRepo::new())),
+            job_statuses: Arc::new(scheduler::JobStatuses::default()),
+            token_repo: Arc::new(Mutex::new(repos::tokens::FsTokenRepo::new())),
+            summary_repo: Arc::new(Mutex::new(repos::summaries::FsSummaryRepo::new())),
+            chat_client: clients::chat::client_from_config(config),
+            usage_repo: Arc::new(Mutex::new(repos::usage::FsUsageRepo::new())),
+            embedding_queue: None,
+            rate_limiter: handlers::rate_limit::RateLimiter::new(config.rate_limit.clone()),
+            webhook_repo: Arc::new(Mutex::new(repos::webhooks::FsWebhookRepo::new())),
+            transcription_client: clients::transcription::client_from_config(config),
+            page_client: Arc::new(clients::web::HttpPageClient::new()),
+            feed_repo: Arc::new(Mutex::new(repos::feeds::FsFeedRepo::new())),
+            reminder_repo: Arc::new(Mutex::new(repos::reminders::FsReminderRepo::new())),
+            retention_repo: Arc::new(Mutex::new(repos::retention::FsRetentionRepo::new())),
+            storage_usage_repo: Arc::new(Mutex::new(
+                repos::storage_usage::FsStorageUsageRepo::new(),
+            )),
+            replica: None,
+        }
+    }
+
+    /// The stores and clients of the configuration, with messages and
+    /// attributes in the database, mirrored to S3 or replicated, and
+    /// embeddings in the vector database when those are configured
+    async fn open() -> Result<Self> {
+        let mut resources = Resources::new();
+        let storage = &resources.config.storage;
+        (resources.message_repo, resources.user_attributes_repo) = match &storage.database_url {
+            Some(url) => database_stores(url).await?,
+            None => file_stores(storage).await?,
+        };
+        if storage.database_url.is_some()
+            && storage.s3_bucket.is_some()
+            && storage.replica.as_deref() != Some("files")
+        {
+            warn!("Not mirroring to S3, messages and attributes are in the database");
+        }
+        if let Some(replica) = &storage.replica {
+            use_replica(&mut resources, replica).await?;
+        }
+        if let Some(vector_db) = clients::vectordb::QdrantClient::from_config(storage) {
+            info!("Searching embeddings in Qdrant");
+            resources.message_repo = Arc::new(Mutex::new(
+                repos::external_vectors::ExternalVectorRepo::new(
+                    resources.message_repo.clone(),
+                    Arc::new(vector_db),
+                ),
+            ));
+        }
+        Ok(resources)
+    }
+}
+
+/// Message and attribute stores, which are kept and replicated together
+type Stores = (
+    Arc<Mutex<dyn repos::messages::MessageRepo>>,
+    Arc<Mutex<dyn repos::attributes::AttributeRepo>>,
+);
+
+/// Messages and attributes in files, mirrored to the S3 bucket when one is
+/// configured. What is missing locally is restored from the bucket first, a
+/// host starting empty would otherwise write day files holding only the
+/// messages saved since it started over the stored ones.
+async fn file_stores(storage: &config::StorageConfig) -> Result<Stores> {
+    let messages = Arc::new(Mutex::new(FsMessageRepo::with_day_cache(
+        storage.day_cache_size,
+    )));
+    let attributes = Arc::new(Mutex::new(FsAttributeRepo::new()));
+    let store = match clients::objectstore::S3Client::from_config(storage) {
+        Some(store) => Arc::new(store?),
+        None => return Ok((messages, attributes)),
+    };
+    let prefix = crate::namespace::prefixed(
+        crate::namespace::namespace().as_deref(),
+        &storage.s3_prefix,
+        "/",
+    );
+    let mirror = Arc::new(repos::object_storage::ObjectMirror::new(
+        store,
+        repos::get_storage_root(),
+        &prefix,
+    ));
+    let restored = mirror
+        .sync()
+        .await
+        .map_err(|_| anyhow::anyhow!("Error restoring storage from S3"))?;
+    info!("Mirroring storage to S3, restored {} files", restored);
+    Ok((
+        Arc::new(Mutex::new(
+            repos::object_storage::ObjectStorageMessageRepo::new(messages, mirror.clone()),
+        )),
+        Arc::new(Mutex::new(
+            repos::object_storage::ObjectStorageAttributeRepo::new(attributes, mirror),
+        )),
+    ))
+}
+
+/// Replicates messages and attributes to the stores `replica` names, which
+/// are the other kind than the primary ones: the files when those are in
+/// the database, or the database at the URL when they are in files
+async fn use_replica(resources: &mut Resources, replica: &str) -> Result<()> {
+    let storage = &resources.config.storage;
+    let (message_repo, attribute_repo) = match (replica, &storage.database_url) {
+        ("files", Some(_)) => file_stores(storage).await?,
+        ("files", None) => {
+            anyhow::bail!("STORAGE_REPLICA is files, which already are the primary storage")
+        }
+        (url, None) => database_stores(url).await?,
+        (_, Some(_)) => {
+            anyhow::bail!("STORAGE_REPLICA must be files when messages are kept in the database")
+        }
+    };
+    info!(
+        "Replicating messages and attributes to the {}",
+        match replica {
+            "files" => "filesystem",
+            _ => "database",
+        }
+    );
+    let replica = repos::replication::Replica {
+        message_repo,
+        attribute_repo,
+        replicator: Arc::new(repos::replication::Replicator::start()),
+    };
+    resources.message_repo = Arc::new(Mutex::new(repos::replication::ReplicatedMessageRepo::new(
+        resources.message_repo.clone(),
+        replica.clone(),
+    )));
+    resources.user_attributes_repo = Arc::new(Mutex::new(
+        repos::replication::ReplicatedAttributeRepo::new(
+            resources.user_attributes_repo.clone(),
+            replica.clone(),
+        ),
+    ));
+    resources.replica = Some(replica);
+    Ok(())
+}
+
+/// Messages and attributes in the Postgres database at `url`
+#[cfg(feature = "postgres")]
+async fn database_stores(url: &str) -> Result<Stores> {
+    let pool = repos::postgres::connect(url).await?;
+    Ok((
+        Arc::new(Mutex::new(repos::postgres::PgMessageRepo::new(
+            pool.clone(),
+        ))),
+        Arc::new(Mutex::new(repos::postgres::PgAttributeRepo::new(pool))),
+    ))
+}
+
+#[cfg(not(feature = "postgres"))]
+async fn database_stores(_url: &str) -> Result<Stores> {
+    anyhow::bail!("A database URL is configured but muninn was built without the postgres feature")
+}
+
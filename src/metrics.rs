@@ -0,0 +1,191 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::{
+    clients::embedding_cache::EmbeddingCacheStats,
+    repos::{get_directory_size, get_storage_root, get_user_root, messages::MessageRepo},
+    services::embedding_queue::QueueStats,
+};
+
+#[derive(Clone, Default)]
+pub struct UserStorage {
+    pub messages: usize,
+    pub bytes_on_disk: u64,
+    pub embeddings: usize,
+    /// Bytes taken by the stored vectors themselves
+    pub embedding_bytes: usize,
+}
+
+// Name, help text and how to read the value of a per-user gauge
+type Gauge = (&'static str, &'static str, fn(&UserStorage) -> u64);
+
+/// Counters read when the metrics are scraped rather than collected
+#[derive(Default)]
+pub struct LiveMetrics {
+    pub embedding_queue: Option<QueueStats>,
+    pub embedding_cache: Option<EmbeddingCacheStats>,
+}
+
+/// Latest snapshot of storage growth, refreshed by the metrics collector
+#[derive(Clone, Default)]
+pub struct StorageMetrics {
+    pub users: BTreeMap<String, UserStorage>,
+    pub content_store_bytes: u64,
+    pub collected_at: i64,
+    /// Added as a `namespace` label to every sample when set
+    pub namespace: Option<String>,
+}
+
+impl StorageMetrics {
+    pub async fn collect(message_repo: &dyn MessageRepo) -> Result<StorageMetrics, ()> {
+        let mut users = BTreeMap::new();
+        for user in message_repo.get_users().await? {
+            let chats = message_repo.get_all_for_user(user.clone()).await?;
+            let embeddings = chats
+                .iter()
+                .filter_map(|chat| chat.embedding.as_ref())
+                .collect::<Vec<&Vec<f32>>>();
+            users.insert(
+                user.clone(),
+                UserStorage {
+                    messages: chats.len(),
+                    bytes_on_disk: get_directory_size(&get_user_root(&user)),
+                    embeddings: embeddings.len(),
+                    embedding_bytes: embeddings
+                        .iter()
+                        .map(|embedding| embedding.len() * std::mem::size_of::<f32>())
+                        .sum(),
+                },
+            );
+        }
+
+        Ok(StorageMetrics {
+            users,
+            content_store_bytes: get_directory_size(&get_storage_root().join(".content")),
+            collected_at: chrono::Utc::now().timestamp(),
+            namespace: crate::namespace::namespace(),
+        })
+    }
+
+    /// Renders the snapshot in the OpenMetrics text format, followed by the
+    /// `live` counters
+    pub fn render(&self, live: &LiveMetrics) -> String {
+        let mut out = String::new();
+        let namespace_label = match &self.namespace {
+            Some(namespace) => format!("namespace=\"{}\"", escape_label(namespace)),
+            None => String::new(),
+        };
+        let gauges: [Gauge; 4] = [
+            ("muninn_messages", "Stored messages per user", |u| {
+                u.messages as u64
+            }),
+            (
+                "muninn_storage_bytes",
+                "Bytes on disk in the user's data directory",
+                |u| u.bytes_on_disk,
+            ),
+            ("muninn_embeddings", "Messages with an embedding", |u| {
+                u.embeddings as u64
+            }),
+            (
+                "muninn_embedding_index_bytes",
+                "Bytes taken by stored embedding vectors",
+                |u| u.embedding_bytes as u64,
+            ),
+        ];
+
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            for (user, storage) in &self.users {
+                let labels = match namespace_label.is_empty() {
+                    true => format!("user=\"{}\"", escape_label(user)),
+                    false => format!("{},user=\"{}\"", namespace_label, escape_label(user)),
+                };
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels, value(storage));
+            }
+        }
+        let _ = writeln!(out, "# TYPE muninn_content_store_bytes gauge");
+        let _ = writeln!(
+            out,
+            "# HELP muninn_content_store_bytes Bytes in the shared message content store"
+        );
+        let global_labels = match namespace_label.is_empty() {
+            true => String::new(),
+            false => format!("{{{}}}", namespace_label),
+        };
+        let _ = writeln!(
+            out,
+            "muninn_content_store_bytes{} {}",
+            global_labels, self.content_store_bytes
+        );
+        let _ = writeln!(
+            out,
+            "# TYPE muninn_metrics_collected_timestamp_seconds gauge"
+        );
+        let _ = writeln!(
+            out,
+            "muninn_metrics_collected_timestamp_seconds{} {}",
+            global_labels, self.collected_at
+        );
+        if let Some(queue) = &live.embedding_queue {
+            queue.render(&mut out, &global_labels);
+        }
+        if let Some(cache) = &live.embedding_cache {
+            cache.render(&mut out, &global_labels);
+        }
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_openmetrics() {
+        let mut metrics = StorageMetrics::default();
+        metrics.users.insert(
+            "alice".to_string(),
+            UserStorage {
+                messages: 3,
+                bytes_on_disk: 1024,
+                embeddings: 2,
+                embedding_bytes: 24,
+            },
+        );
+
+        let rendered = metrics.render(&LiveMetrics::default());
+        assert!(rendered.contains("muninn_messages{user=\"alice\"} 3"));
+        assert!(rendered.contains("muninn_storage_bytes{user=\"alice\"} 1024"));
+        assert!(rendered.contains("muninn_embedding_index_bytes{user=\"alice\"} 24"));
+        assert!(rendered.ends_with("# EOF\n"));
+
+        metrics.namespace = Some("staging".to_string());
+        let rendered = metrics.render(&LiveMetrics::default());
+        assert!(rendered.contains("muninn_messages{namespace=\"staging\",user=\"alice\"} 3"));
+        assert!(rendered.contains("muninn_content_store_bytes{namespace=\"staging\"} 0"));
+
+        let live = LiveMetrics {
+            embedding_queue: Some(QueueStats {
+                depth: 4,
+                ..QueueStats::default()
+            }),
+            embedding_cache: Some(EmbeddingCacheStats {
+                hits: 7,
+                ..EmbeddingCacheStats::default()
+            }),
+        };
+        let rendered = metrics.render(&live);
+        assert!(rendered.contains("muninn_embedding_queue_depth{namespace=\"staging\"} 4"));
+        assert!(rendered.contains("muninn_embedding_queue_failed_total{namespace=\"staging\"} 0"));
+        assert!(rendered.contains("muninn_embedding_cache_hits_total{namespace=\"staging\"} 7"));
+    }
+}
@@ -0,0 +1,31 @@
+/// Deployment namespace such as `prod` or `staging`, read from
+/// `MUNINN_NAMESPACE`. Instances in different namespaces can share storage,
+/// a broker and a metrics backend without seeing each other's data.
+pub fn namespace() -> Option<String> {
+    std::env::var("MUNINN_NAMESPACE")
+        .ok()
+        .map(|namespace| namespace.trim().to_string())
+        .filter(|namespace| !namespace.is_empty())
+}
+
+/// Prefixes `name` with the namespace, joined by `separator`
+pub fn prefixed(namespace: Option<&str>, name: &str, separator: &str) -> String {
+    match namespace {
+        Some(namespace) => format!("{}{}{}", namespace, separator, name),
+        None => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefixed() {
+        assert_eq!(
+            prefixed(Some("staging"), "messages/assistant", "/"),
+            "staging/messages/assistant"
+        );
+        assert_eq!(prefixed(None, "messages/assistant", "/"), "messages/assistant");
+    }
+}
@@ -1,27 +1,214 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, io::Write};
 
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use tracing::error;
 
+use super::{encryption, get_storage_root, get_user_root};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AttributeModel {
     pub attribute: String,
     pub value: String,
+    /// Unix timestamp after which the attribute no longer applies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "AttributeSource::is_stated")]
+    pub source: AttributeSource,
+}
+
+/// Where an attribute's value came from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AttributeSource {
+    /// Set by the user or a client acting for them
+    #[default]
+    Stated,
+    /// Read from the user's conversations by the extraction job
+    Inferred,
+    /// Reported by a device or home automation over MQTT
+    Sensor,
+}
+
+impl AttributeSource {
+    pub fn is_stated(&self) -> bool {
+        *self == AttributeSource::Stated
+    }
+
+    // the database keeps the source as text
+    #[cfg(feature = "postgres")]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AttributeSource::Stated => "stated",
+            AttributeSource::Inferred => "inferred",
+            AttributeSource::Sensor => "sensor",
+        }
+    }
+
+    #[cfg(feature = "postgres")]
+    pub fn parse(source: &str) -> AttributeSource {
+        match source {
+            "inferred" => AttributeSource::Inferred,
+            "sensor" => AttributeSource::Sensor,
+            _ => AttributeSource::Stated,
+        }
+    }
+}
+
+impl AttributeModel {
+    pub fn is_expired(&self, now: i64) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= now,
+            None => false,
+        }
+    }
+}
+
+/// One change to a user's attributes. The append-only log of these is the
+/// source of truth, `attributes.json` only holds the current state.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AttributeEvent {
+    Set {
+        attribute: String,
+        value: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<i64>,
+        #[serde(default, skip_serializing_if = "AttributeSource::is_stated")]
+        source: AttributeSource,
+        at: i64,
+    },
+    Delete {
+        attribute: String,
+        at: i64,
+    },
+}
+
+impl AttributeEvent {
+    pub fn attribute(&self) -> &str {
+        match self {
+            AttributeEvent::Set { attribute, .. } => attribute,
+            AttributeEvent::Delete { attribute, .. } => attribute,
+        }
+    }
+
+    pub fn at(&self) -> i64 {
+        match self {
+            AttributeEvent::Set { at, .. } => *at,
+            AttributeEvent::Delete { at, .. } => *at,
+        }
+    }
+}
+
+/// Replays the log up to and including `at`, leaving out attributes that
+/// had expired by then
+pub fn materialize(events: &[AttributeEvent], at: i64) -> HashMap<String, AttributeModel> {
+    let mut state = HashMap::new();
+    for event in events.iter().filter(|event| event.at() <= at) {
+        match event {
+            AttributeEvent::Set {
+                attribute,
+                value,
+                expires_at,
+                source,
+                ..
+            } => {
+                state.insert(
+                    attribute.clone(),
+                    AttributeModel {
+                        attribute: attribute.clone(),
+                        value: value.clone(),
+                        expires_at: *expires_at,
+                        source: *source,
+                    },
+                );
+            }
+            AttributeEvent::Delete { attribute, .. } => {
+                state.remove(attribute);
+            }
+        }
+    }
+    state.retain(|_, attribute| !attribute.is_expired(at));
+    state
+}
+
+/// Attributes written before expiry support were stored as plain strings,
+/// so both shapes are accepted when reading from disk.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredAttribute {
+    Plain(String),
+    Detailed {
+        value: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<i64>,
+        #[serde(default, skip_serializing_if = "AttributeSource::is_stated")]
+        source: AttributeSource,
+    },
+}
+
+impl StoredAttribute {
+    fn into_model(self, attribute: String) -> AttributeModel {
+        match self {
+            StoredAttribute::Plain(value) => AttributeModel {
+                attribute,
+                value,
+                expires_at: None,
+                source: AttributeSource::Stated,
+            },
+            StoredAttribute::Detailed {
+                value,
+                expires_at,
+                source,
+            } => AttributeModel {
+                attribute,
+                value,
+                expires_at,
+                source,
+            },
+        }
+    }
+
+    fn from_model(model: &AttributeModel) -> Self {
+        StoredAttribute::Detailed {
+            value: model.value.clone(),
+            expires_at: model.expires_at,
+            source: model.source,
+        }
+    }
 }
 
 #[async_trait]
-pub trait AttributeRepo {
+pub trait AttributeRepo: Send + Sync {
     async fn save_attribute(
         &mut self,
-        user: &String,
-        attribute: &String,
-        value: &String,
+        user: &str,
+        attribute: &str,
+        value: &str,
+        expires_at: Option<i64>,
+        source: AttributeSource,
+    ) -> Result<AttributeModel, ()>;
+    async fn get_attribute(&mut self, user: &str, id: &str) -> Result<AttributeModel, ()>;
+    async fn get_all_attributes(&mut self, user: &str) -> Result<Vec<AttributeModel>, ()>;
+    async fn delete_attribute(&mut self, user: &str, attribute: &str) -> Result<(), ()>;
+    /// The value the attribute held at the unix timestamp `at`
+    async fn get_attribute_at(
+        &mut self,
+        user: &str,
+        attribute: &str,
+        at: i64,
     ) -> Result<AttributeModel, ()>;
-    async fn get_attribute(&mut self, user: &String, id: &String) -> Result<AttributeModel, ()>;
+    /// Every recorded change to the attribute, oldest first
+    async fn get_history(&mut self, user: &str, attribute: &str)
+        -> Result<Vec<AttributeEvent>, ()>;
+    /// Moves every attribute that has expired by `now` out of the active set
+    /// and returns the archived attributes with the user they belonged to
+    async fn archive_expired(&mut self, now: i64) -> Result<Vec<(String, AttributeModel)>, ()>;
 }
 
 pub struct FsAttributeRepo {
     // Username: Attribute: Value
-    memory: HashMap<String, HashMap<String, String>>,
+    memory: HashMap<String, HashMap<String, AttributeModel>>,
 }
 
 impl FsAttributeRepo {
@@ -36,105 +223,280 @@ impl FsAttributeRepo {
 impl AttributeRepo for FsAttributeRepo {
     async fn save_attribute(
         &mut self,
-        user: &String,
-        attribute: &String,
-        value: &String,
+        user: &str,
+        attribute: &str,
+        value: &str,
+        expires_at: Option<i64>,
+        source: AttributeSource,
     ) -> Result<AttributeModel, ()> {
-        if !self.memory.contains_key(user) {
-            self.memory.insert(user.clone(), HashMap::new());
-        }
-        let user_attributes = self.memory.get_mut(user).unwrap();
-        user_attributes.insert(attribute.clone(), value.clone());
+        let model = AttributeModel {
+            attribute: attribute.to_string(),
+            value: value.to_string(),
+            expires_at,
+            source,
+        };
+        append_event(
+            user,
+            &AttributeEvent::Set {
+                attribute: attribute.to_string(),
+                value: value.to_string(),
+                expires_at,
+                source,
+                at: chrono::Utc::now().timestamp(),
+            },
+        )?;
+        self.memory
+            .entry(user.to_string())
+            .or_default()
+            .insert(attribute.to_string(), model.clone());
 
-        let user_attributes_save_file_path = get_root_path(&user).join("attributes.json");
         // Hashmap from attributes file
-        let mut hm: HashMap<String, String> =
-            match std::fs::read_to_string(&user_attributes_save_file_path) {
-                Ok(content) => match serde_json::from_str(&content) {
-                    Ok(hm) => hm,
-                    Err(e) => {
-                        error!("Error deserializing file: {}", e);
-                        HashMap::new()
-                    }
-                },
-                Err(_) => HashMap::new(),
-            };
+        let mut hm = read_attributes_file(user);
         // Insert the new attribute
-        hm.insert(attribute.clone(), value.clone());
-        // Serialize the hashmap
-        let serialized = serde_json::to_string(&hm).unwrap();
-        // Write the serialized hashmap to the file
-        match std::fs::write(&user_attributes_save_file_path, serialized) {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Error writing to file: {}", e)
-            }
+        hm.insert(attribute.to_string(), model.clone());
+        write_attributes_file(user, "attributes.json", &hm);
+
+        Ok(model)
+    }
+
+    async fn get_attribute(&mut self, user: &str, id: &str) -> Result<AttributeModel, ()> {
+        let now = chrono::Utc::now().timestamp();
+        let in_memory = self
+            .memory
+            .get(user)
+            .and_then(|user_attributes| user_attributes.get(id))
+            .cloned();
+
+        // if the value is not in memory we check the file system
+        let attribute = match in_memory {
+            Some(attribute) => attribute,
+            None => match read_attributes_file(user).remove(id) {
+                Some(attribute) => attribute,
+                // if that still fails we return an error
+                None => return Err(()),
+            },
+        };
+
+        if attribute.is_expired(now) {
+            return Err(());
         }
+        Ok(attribute)
+    }
 
-        Ok(AttributeModel {
-            attribute: attribute.clone(),
-            value: value.clone(),
-        })
+    async fn get_all_attributes(&mut self, user: &str) -> Result<Vec<AttributeModel>, ()> {
+        let now = chrono::Utc::now().timestamp();
+        let mut attributes = read_attributes_file(user);
+        // memory may hold writes that failed to reach the disk
+        if let Some(user_attributes) = self.memory.get(user) {
+            attributes.extend(user_attributes.clone());
+        }
+        let mut attributes = attributes
+            .into_values()
+            .filter(|attribute| !attribute.is_expired(now))
+            .collect::<Vec<AttributeModel>>();
+        attributes.sort_by(|a, b| a.attribute.cmp(&b.attribute));
+        Ok(attributes)
     }
 
-    async fn get_attribute(&mut self, user: &String, id: &String) -> Result<AttributeModel, ()> {
-        let user_attributes = self.memory.get(user);
-        if user_attributes.is_none() {
-            let value = get_from_file(user, id);
-            // if that still fails we return an error
-            if let None = value {
-                return Err(());
+    async fn delete_attribute(&mut self, user: &str, attribute: &str) -> Result<(), ()> {
+        let mut hm = read_attributes_file(user);
+        let in_memory = self
+            .memory
+            .get_mut(user)
+            .and_then(|user_attributes| user_attributes.remove(attribute));
+        if hm.remove(attribute).is_none() && in_memory.is_none() {
+            return Err(());
+        }
+
+        append_event(
+            user,
+            &AttributeEvent::Delete {
+                attribute: attribute.to_string(),
+                at: chrono::Utc::now().timestamp(),
+            },
+        )?;
+        write_attributes_file(user, "attributes.json", &hm);
+        Ok(())
+    }
+
+    async fn get_attribute_at(
+        &mut self,
+        user: &str,
+        attribute: &str,
+        at: i64,
+    ) -> Result<AttributeModel, ()> {
+        materialize(&read_events(user), at).remove(attribute).ok_or(())
+    }
+
+    async fn get_history(
+        &mut self,
+        user: &str,
+        attribute: &str,
+    ) -> Result<Vec<AttributeEvent>, ()> {
+        Ok(read_events(user)
+            .into_iter()
+            .filter(|event| event.attribute() == attribute)
+            .collect())
+    }
+
+    async fn archive_expired(&mut self, now: i64) -> Result<Vec<(String, AttributeModel)>, ()> {
+        let users = match std::fs::read_dir(get_storage_root()) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().join("attributes.json").exists())
+                .filter_map(|entry| entry.file_name().to_str().map(|name| name.to_string()))
+                .collect::<Vec<String>>(),
+            Err(_) => return Ok(vec![]),
+        };
+
+        let mut archived = vec![];
+        for user in users {
+            let (expired, active): (HashMap<_, _>, HashMap<_, _>) = read_attributes_file(&user)
+                .into_iter()
+                .partition(|(_, attribute)| attribute.is_expired(now));
+            if expired.is_empty() {
+                continue;
             }
 
-            return Ok(AttributeModel {
-                attribute: id.clone(),
-                value: value.unwrap().clone(),
-            });
+            let mut archive = read_file(&user, "expired_attributes.json");
+            archive.extend(expired.clone());
+            write_attributes_file(&user, "expired_attributes.json", &archive);
+            write_attributes_file(&user, "attributes.json", &active);
+
+            if let Some(user_attributes) = self.memory.get_mut(&user) {
+                user_attributes.retain(|_, attribute| !attribute.is_expired(now));
+            }
+            for (_, attribute) in expired {
+                archived.push((user.clone(), attribute));
+            }
         }
-        let user_attributes = user_attributes.unwrap();
-        let value = user_attributes.get(id);
-
-        if value.is_none() {
-            let value = get_from_file(user, id);
-            // if that still fails we return an error
-            if let None = value {
-                return Err(());
+        Ok(archived)
+    }
+}
+
+fn read_attributes_file(user: &str) -> HashMap<String, AttributeModel> {
+    read_file(user, "attributes.json")
+}
+
+fn read_file(user: &str, file_name: &str) -> HashMap<String, AttributeModel> {
+    let path = get_root_path(user).join(file_name);
+    let stored: HashMap<String, StoredAttribute> = match encryption::read_to_string(&path) {
+        Ok(content) => match serde_json::from_str(&content) {
+            Ok(hm) => hm,
+            Err(e) => {
+                error!("Error deserializing file: {}", e);
+                HashMap::new()
             }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => {
+            error!("Error reading {:?}: {}", path, e);
+            HashMap::new()
+        }
+    };
+    stored
+        .into_iter()
+        .map(|(attribute, stored)| (attribute.clone(), stored.into_model(attribute)))
+        .collect()
+}
 
-            return Ok(AttributeModel {
-                attribute: id.clone(),
-                value: value.unwrap().clone(),
-            });
+fn write_attributes_file(user: &str, file_name: &str, attributes: &HashMap<String, AttributeModel>) {
+    let path = get_root_path(user).join(file_name);
+    let stored: HashMap<&String, StoredAttribute> = attributes
+        .iter()
+        .map(|(attribute, model)| (attribute, StoredAttribute::from_model(model)))
+        .collect();
+    // Serialize the hashmap
+    let serialized = serde_json::to_string(&stored).unwrap();
+    if let Err(e) = std::fs::create_dir_all(get_root_path(user)) {
+        error!("Error creating directory: {}", e);
+        return;
+    }
+    // Write the serialized hashmap to the file
+    match std::fs::write(&path, encryption::seal(serialized.as_bytes())) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Error writing to file: {}", e)
         }
+    }
+}
+
+fn get_events_path(user: &str) -> std::path::PathBuf {
+    get_root_path(user).join("attribute_events.jsonl")
+}
 
-        Ok(AttributeModel {
-            attribute: id.clone(),
-            value: value.unwrap().clone(),
+fn read_events(user: &str) -> Vec<AttributeEvent> {
+    let content = std::fs::read_to_string(get_events_path(user)).unwrap_or_default();
+    let events = content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match encryption::open_line(line) {
+            Ok(line) => Some(line),
+            Err(e) => {
+                error!("Skipping attribute event: {}", e);
+                None
+            }
         })
+        .filter_map(|line| match serde_json::from_str(&line) {
+            Ok(event) => Some(event),
+            Err(e) => {
+                error!("Skipping unreadable attribute event: {}", e);
+                None
+            }
+        })
+        .collect::<Vec<AttributeEvent>>();
+    if !events.is_empty() {
+        return events;
     }
+    // attributes saved before the log existed are known since the epoch
+    read_attributes_file(user)
+        .into_values()
+        .map(|attribute| AttributeEvent::Set {
+            attribute: attribute.attribute,
+            value: attribute.value,
+            expires_at: attribute.expires_at,
+            source: attribute.source,
+            at: 0,
+        })
+        .collect()
 }
-fn get_from_file(user: &String, id: &String) -> Option<String> {
-    // if the value is not in memory we check the file system
-    let user_attributes_save_file_path = get_root_path(user).join("attributes.json");
-    let hm: HashMap<String, String> = match std::fs::read_to_string(&user_attributes_save_file_path)
-    {
-        Ok(content) => serde_json::from_str(&content).unwrap(),
-        Err(_) => HashMap::new(),
-    };
-    let value = hm.get(id);
-    match value {
-        Some(value) => Some(value.clone()),
-        None => None,
+
+fn append_event(user: &str, event: &AttributeEvent) -> Result<(), ()> {
+    let path = get_events_path(user);
+    std::fs::create_dir_all(get_root_path(user)).map_err(|e| {
+        error!("Error creating directory: {}", e);
+    })?;
+
+    // the first event also records the attributes that predate the log
+    let mut lines = vec![];
+    if !path.exists() {
+        let mut existing = read_events(user);
+        existing.sort_by(|a, b| a.attribute().cmp(b.attribute()));
+        lines.extend(existing);
+    }
+    lines.push(event.clone());
+
+    let mut serialized = String::new();
+    for line in lines {
+        serialized.push_str(&encryption::seal_line(
+            &serde_json::to_string(&line).map_err(|_| ())?,
+        ));
+        serialized.push('\n');
     }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| {
+            error!("Error opening attribute log: {}", e);
+        })?;
+    file.write_all(serialized.as_bytes()).map_err(|e| {
+        error!("Error writing attribute log: {}", e);
+    })
 }
-fn get_root_path(user: &String) -> std::path::PathBuf {
-    let dir = match std::env::var("MESSAGE_STORAGE_PATH") {
-        Ok(val) => std::path::PathBuf::from(val),
-        Err(_) => dirs::data_local_dir().unwrap(),
-    };
 
-    let path = dir.join("muninn").join(user.clone());
-    path
+fn get_root_path(user: &str) -> std::path::PathBuf {
+    get_user_root(user)
 }
 
 #[cfg(test)]
@@ -148,7 +510,9 @@ mod tests {
         let attribute = "test_attribute".to_string();
         let value = "test_attribute_value".to_string();
 
-        let result = repo.save_attribute(&user, &attribute, &value).await;
+        let result = repo
+            .save_attribute(&user, &attribute, &value, None, AttributeSource::Stated)
+            .await;
         assert!(result.is_ok());
         let result = repo.get_attribute(&user, &attribute).await;
         assert!(result.is_ok());
@@ -177,4 +541,71 @@ mod tests {
         assert_eq!(result.attribute, attribute);
         assert_eq!(result.value, "test_disk_value");
     }
+
+    #[tokio::test]
+    async fn test_expired_attribute_is_archived() {
+        let mut repo = FsAttributeRepo::new();
+        let user = "test_expiry_user".to_string();
+        let attribute = "visiting".to_string();
+        let now = chrono::Utc::now().timestamp();
+
+        repo.save_attribute(
+            &user,
+            &attribute,
+            "parents",
+            Some(now - 1),
+            AttributeSource::Stated,
+        )
+        .await
+        .unwrap();
+        assert!(repo.get_attribute(&user, &attribute).await.is_err());
+
+        let archived = repo.archive_expired(now).await.unwrap();
+        assert!(archived
+            .iter()
+            .any(|(u, a)| u == &user && a.attribute == attribute));
+        assert!(!read_attributes_file(&user).contains_key(&attribute));
+        assert!(read_file(&user, "expired_attributes.json").contains_key(&attribute));
+    }
+
+    #[test]
+    fn test_materialize_point_in_time() {
+        let set = |value: &str, at| AttributeEvent::Set {
+            attribute: "current_project".to_string(),
+            value: value.to_string(),
+            expires_at: None,
+            source: AttributeSource::Stated,
+            at,
+        };
+        let events = vec![
+            set("muninn", 100),
+            set("huginn", 200),
+            AttributeEvent::Delete {
+                attribute: "current_project".to_string(),
+                at: 300,
+            },
+        ];
+
+        assert!(materialize(&events, 50).is_empty());
+        assert_eq!(materialize(&events, 150)["current_project"].value, "muninn");
+        assert_eq!(materialize(&events, 200)["current_project"].value, "huginn");
+        assert!(materialize(&events, 300).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_delete_is_recorded_in_history() {
+        let mut repo = FsAttributeRepo::new();
+        let user = format!("test_history_{}", uuid::Uuid::new_v4());
+
+        repo.save_attribute(&user, "city", "Cape Town", None, AttributeSource::Stated)
+            .await
+            .unwrap();
+        repo.delete_attribute(&user, "city").await.unwrap();
+
+        assert!(repo.get_attribute(&user, "city").await.is_err());
+        let history = repo.get_history(&user, "city").await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert!(matches!(history[1], AttributeEvent::Delete { .. }));
+        assert!(repo.delete_attribute(&user, "city").await.is_err());
+    }
 }
@@ -0,0 +1,56 @@
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::{encryption, get_user_root};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BriefingModel {
+    pub date: String,
+    pub content: String,
+    pub created_at: i64,
+}
+
+pub trait BriefingRepo: Send + Sync {
+    fn save_briefing(&mut self, user: &str, briefing: BriefingModel) -> Result<BriefingModel, ()>;
+    fn get_briefing(&self, user: &str, date: NaiveDate) -> Result<BriefingModel, ()>;
+}
+
+pub struct FsBriefingRepo {}
+
+impl FsBriefingRepo {
+    pub fn new() -> Self {
+        FsBriefingRepo {}
+    }
+}
+
+fn get_briefing_path(user: &str, date: &str) -> std::path::PathBuf {
+    get_user_root(user)
+        .join("briefings")
+        .join(format!("{}.json", date))
+}
+
+impl BriefingRepo for FsBriefingRepo {
+    fn save_briefing(&mut self, user: &str, briefing: BriefingModel) -> Result<BriefingModel, ()> {
+        let path = get_briefing_path(user, &briefing.date);
+        std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| {
+            error!("Error creating directory: {}", e);
+        })?;
+        let serialized = serde_json::to_string(&briefing).map_err(|_| ())?;
+        match std::fs::write(&path, encryption::seal(serialized.as_bytes())) {
+            Ok(_) => Ok(briefing),
+            Err(e) => {
+                error!("Error writing to file: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    fn get_briefing(&self, user: &str, date: NaiveDate) -> Result<BriefingModel, ()> {
+        let path = get_briefing_path(user, &date.format("%Y-%m-%d").to_string());
+        let content = encryption::read_to_string(&path).map_err(|_| ())?;
+        serde_json::from_str(&content).map_err(|e| {
+            error!("Error deserializing briefing: {}", e);
+        })
+    }
+}
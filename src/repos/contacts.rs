@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::{encryption, get_user_root};
+
+/// A person the user talks about, so questions like "when is Mark's
+/// birthday" can be answered with a lookup instead of a vector search
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ContactModel {
+    pub name: String,
+    #[serde(default)]
+    pub relation: Option<String>,
+    /// `YYYY-MM-DD`, or `MM-DD` when the year is unknown
+    #[serde(default)]
+    pub birthday: Option<String>,
+    #[serde(default)]
+    pub notes: Option<String>,
+    /// `manual` for contacts entered through the API, otherwise the name of
+    /// the pipeline that inferred them
+    #[serde(default = "manual_source")]
+    pub source: String,
+    #[serde(default)]
+    pub updated_at: i64,
+}
+
+fn manual_source() -> String {
+    "manual".to_string()
+}
+
+pub trait ContactRepo: Send + Sync {
+    fn save_contact(&mut self, user: &str, contact: ContactModel) -> Result<ContactModel, ()>;
+    fn get_contact(&self, user: &str, name: &str) -> Result<ContactModel, ()>;
+    fn get_contacts(&self, user: &str) -> Result<Vec<ContactModel>, ()>;
+}
+
+pub struct FsContactRepo {}
+
+impl FsContactRepo {
+    pub fn new() -> Self {
+        FsContactRepo {}
+    }
+}
+
+// Contacts are keyed case-insensitively so "mark" finds "Mark"
+fn contact_key(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+fn get_contacts_path(user: &str) -> std::path::PathBuf {
+    get_user_root(user).join("contacts.json")
+}
+
+fn read_contacts(user: &str) -> HashMap<String, ContactModel> {
+    match encryption::read_to_string(&get_contacts_path(user)) {
+        Ok(content) => match serde_json::from_str(&content) {
+            Ok(contacts) => contacts,
+            Err(e) => {
+                error!("Error deserializing contacts: {}", e);
+                HashMap::new()
+            }
+        },
+        Err(_) => HashMap::new(),
+    }
+}
+
+impl ContactRepo for FsContactRepo {
+    fn save_contact(&mut self, user: &str, contact: ContactModel) -> Result<ContactModel, ()> {
+        let mut contacts = read_contacts(user);
+        let key = contact_key(&contact.name);
+        // fill gaps from what we already know instead of erasing it
+        let contact = match contacts.remove(&key) {
+            Some(existing) => ContactModel {
+                name: contact.name,
+                relation: contact.relation.or(existing.relation),
+                birthday: contact.birthday.or(existing.birthday),
+                notes: contact.notes.or(existing.notes),
+                source: contact.source,
+                updated_at: chrono::Utc::now().timestamp(),
+            },
+            None => ContactModel {
+                updated_at: chrono::Utc::now().timestamp(),
+                ..contact
+            },
+        };
+        contacts.insert(key, contact.clone());
+
+        let path = get_contacts_path(user);
+        std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| {
+            error!("Error creating directory: {}", e);
+        })?;
+        let serialized = serde_json::to_string(&contacts).map_err(|_| ())?;
+        match std::fs::write(&path, encryption::seal(serialized.as_bytes())) {
+            Ok(_) => Ok(contact),
+            Err(e) => {
+                error!("Error writing to file: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    fn get_contact(&self, user: &str, name: &str) -> Result<ContactModel, ()> {
+        read_contacts(user).remove(&contact_key(name)).ok_or(())
+    }
+
+    fn get_contacts(&self, user: &str) -> Result<Vec<ContactModel>, ()> {
+        let mut contacts = read_contacts(user).into_values().collect::<Vec<_>>();
+        contacts.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(contacts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contact(name: &str, relation: Option<&str>, birthday: Option<&str>) -> ContactModel {
+        ContactModel {
+            name: name.to_string(),
+            relation: relation.map(str::to_string),
+            birthday: birthday.map(str::to_string),
+            notes: None,
+            source: "manual".to_string(),
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_contacts_round_trip_and_fill_gaps() {
+        let username = format!("test_contacts_{}", uuid::Uuid::new_v4());
+        let mut repo = FsContactRepo::new();
+        repo.save_contact(&username, contact("Mark", Some("brother"), None))
+            .unwrap();
+        repo.save_contact(&username, contact("Anna", None, Some("05-21")))
+            .unwrap();
+
+        let mark = repo.get_contact(&username, " mark ").unwrap();
+        assert_eq!(mark.name, "Mark");
+        assert_eq!(mark.relation.as_deref(), Some("brother"));
+        assert!(mark.updated_at > 0);
+
+        // saving again with only a birthday keeps the relation
+        repo.save_contact(&username, contact("MARK", None, Some("1990-03-14")))
+            .unwrap();
+        let mark = repo.get_contact(&username, "Mark").unwrap();
+        assert_eq!(mark.name, "MARK");
+        assert_eq!(mark.relation.as_deref(), Some("brother"));
+        assert_eq!(mark.birthday.as_deref(), Some("1990-03-14"));
+
+        let names = repo
+            .get_contacts(&username)
+            .unwrap()
+            .into_iter()
+            .map(|contact| contact.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["Anna", "MARK"]);
+        assert!(repo.get_contact(&username, "Lee").is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use std::{collections::HashMap, path::Path, sync::Mutex, time::SystemTime};
+
+use chrono::NaiveDate;
+
+use super::{
+    messages::{ChatModel, COLD_FILE},
+    RepoError,
+};
+
+/// Days kept when no size is configured
+pub const DEFAULT_CAPACITY: usize = 365;
+
+// What a day file looked like when it was read: which of the plain and the
+// cold file it was, when it was last modified and how long it was
+type Stamp = (bool, SystemTime, u64);
+
+struct Entry {
+    stamp: Stamp,
+    chats: Vec<ChatModel>,
+    // value of `State::clock` when the entry was last read or written
+    used_at: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<(String, NaiveDate), Entry>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+// The day file at `path` as it is on disk now, or its cold file when it was
+// compressed. `None` when the day has neither.
+fn stamp_of(path: &Path) -> Option<Stamp> {
+    let cold = path.with_file_name(COLD_FILE);
+    let (is_cold, metadata) = match std::fs::metadata(path) {
+        Ok(metadata) => (false, metadata),
+        Err(_) => (true, std::fs::metadata(cold).ok()?),
+    };
+    Some((is_cold, metadata.modified().ok()?, metadata.len()))
+}
+
+/// Parsed day files of the most recently read days, so repeated searches
+/// and history pages do not read and parse them again. An entry is used only
+/// while its file is unchanged since it was read, files changed by another
+/// process are read again.
+pub struct DayCache {
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+impl DayCache {
+    /// A cache of at most `capacity` days, which keeps nothing when it is 0
+    pub fn new(capacity: usize) -> DayCache {
+        DayCache {
+            capacity,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// The messages of the user's day file at `path`, read with `load`
+    /// unless the cached ones are still those on disk
+    pub fn get(
+        &self,
+        user: &str,
+        date: NaiveDate,
+        path: &Path,
+        load: impl FnOnce(&Path) -> Result<Vec<ChatModel>, RepoError>,
+    ) -> Result<Vec<ChatModel>, RepoError> {
+        if self.capacity == 0 {
+            return load(path);
+        }
+        let key = (user.to_string(), date);
+        let stamp = match stamp_of(path) {
+            Some(stamp) => stamp,
+            None => {
+                self.forget(user, date);
+                return load(path);
+            }
+        };
+        {
+            let mut state = self.state.lock().unwrap();
+            state.clock += 1;
+            let clock = state.clock;
+            let cached = match state.entries.get_mut(&key) {
+                Some(entry) if entry.stamp == stamp => {
+                    entry.used_at = clock;
+                    Some(entry.chats.clone())
+                }
+                _ => None,
+            };
+            if let Some(chats) = cached {
+                state.hits += 1;
+                return Ok(chats);
+            }
+            state.misses += 1;
+        }
+        let chats = load(path)?;
+        // a file written while it was read is read again next time
+        if stamp_of(path) == Some(stamp) {
+            self.store(key, stamp, chats.clone());
+        }
+        Ok(chats)
+    }
+
+    fn store(&self, key: (String, NaiveDate), stamp: Stamp, chats: Vec<ChatModel>) {
+        let mut state = self.state.lock().unwrap();
+        if state.entries.len() >= self.capacity && !state.entries.contains_key(&key) {
+            let oldest = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                state.entries.remove(&oldest);
+            }
+        }
+        state.clock += 1;
+        let used_at = state.clock;
+        state.entries.insert(
+            key,
+            Entry {
+                stamp,
+                chats,
+                used_at,
+            },
+        );
+    }
+
+    /// Drops the day, after it was written or removed
+    pub fn forget(&self, user: &str, date: NaiveDate) {
+        self.state
+            .lock()
+            .unwrap()
+            .entries
+            .remove(&(user.to_string(), date));
+    }
+
+    /// Drops every day of the user
+    pub fn forget_user(&self, user: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .entries
+            .retain(|(owner, _), _| owner != user);
+    }
+
+    /// Days held, hits and misses since startup
+    pub fn stats(&self) -> (usize, u64, u64) {
+        let state = self.state.lock().unwrap();
+        (state.entries.len(), state.hits, state.misses)
+    }
+}
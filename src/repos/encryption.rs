@@ -0,0 +1,207 @@
+use std::{io, path::Path, sync::OnceLock};
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use tracing::error;
+
+use crate::config::Config;
+
+/// Starts an encrypted file, followed by the nonce and the sealed contents
+const MAGIC: &[u8] = b"MUNINN-AES256GCM\n";
+
+/// Starts an encrypted line of a JSON lines file, followed by the base64 of
+/// the nonce and the sealed line
+const LINE_PREFIX: &str = "aes256gcm:";
+
+/// AES-256-GCM with a fresh random nonce for every file or line sealed
+pub struct Cipher {
+    key: LessSafeKey,
+}
+
+impl Cipher {
+    /// Cipher of a base64 encoded 32 byte key
+    pub fn from_base64(key: &str) -> Result<Cipher> {
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(key.trim())
+            .context("Encryption key is not base64")?;
+        let key = UnboundKey::new(&AES_256_GCM, &bytes)
+            .map_err(|_| anyhow::anyhow!("Encryption key must be 32 bytes"))?;
+        Ok(Cipher {
+            key: LessSafeKey::new(key),
+        })
+    }
+
+    /// The nonce followed by the encrypted `plain` and its tag
+    pub fn seal(&self, plain: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .expect("system random source failed");
+        let mut sealed = plain.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut sealed,
+            )
+            .expect("contents too large to encrypt");
+        [nonce.as_slice(), &sealed].concat()
+    }
+
+    /// Contents sealed by `seal`, `None` when they were sealed with another
+    /// key or altered since
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut plain = sealed.to_vec();
+        let len = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut plain)
+            .ok()?
+            .len();
+        plain.truncate(len);
+        Some(plain)
+    }
+}
+
+/// Cipher of the configured key, `None` when storage is not encrypted
+pub fn load(config: &Config) -> Result<Option<Cipher>> {
+    let key = match (
+        &config.storage.encryption_key,
+        &config.storage.encryption_key_file,
+    ) {
+        (Some(key), _) => key.clone(),
+        (None, Some(path)) => std::fs::read_to_string(path)
+            .with_context(|| format!("Error reading encryption key from {}", path.display()))?,
+        (None, None) => return Ok(None),
+    };
+    Cipher::from_base64(&key).map(Some)
+}
+
+static CIPHER: OnceLock<Option<Cipher>> = OnceLock::new();
+
+/// Loads the configured key once at startup, failing on an invalid one
+/// rather than writing files unencrypted
+pub fn init(config: &Config) -> Result<()> {
+    let cipher = load(config)?;
+    CIPHER.get_or_init(|| cipher);
+    Ok(())
+}
+
+/// The cipher loaded by `init`, loaded on first use elsewhere (tests)
+fn cipher() -> Option<&'static Cipher> {
+    CIPHER
+        .get_or_init(|| {
+            load(crate::config::get()).unwrap_or_else(|e| {
+                error!("{:#}, storing files unencrypted", e);
+                None
+            })
+        })
+        .as_ref()
+}
+
+fn undecryptable(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} cannot be decrypted with the configured key", what),
+    )
+}
+
+fn no_key() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "file is encrypted but no encryption key is configured",
+    )
+}
+
+/// `contents` as they are written to disk, encrypted when a key is
+/// configured
+pub fn seal(contents: &[u8]) -> Vec<u8> {
+    match cipher() {
+        Some(cipher) => [MAGIC, &cipher.seal(contents)].concat(),
+        None => contents.to_vec(),
+    }
+}
+
+/// Contents of a file written by `seal`. Files written before encryption
+/// was turned on are read as they are.
+pub fn open(contents: Vec<u8>) -> io::Result<Vec<u8>> {
+    let sealed = match contents.strip_prefix(MAGIC) {
+        Some(sealed) => sealed,
+        None => return Ok(contents),
+    };
+    let cipher = cipher().ok_or_else(no_key)?;
+    cipher.open(sealed).ok_or_else(|| undecryptable("file"))
+}
+
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    open(std::fs::read(path)?)
+}
+
+pub fn read_to_string(path: &Path) -> io::Result<String> {
+    String::from_utf8(read(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// A line of a JSON lines file as it is appended, encrypted on its own so
+/// the file can keep growing without being rewritten
+pub fn seal_line(line: &str) -> String {
+    match cipher() {
+        Some(cipher) => format!(
+            "{}{}",
+            LINE_PREFIX,
+            base64::engine::general_purpose::STANDARD.encode(cipher.seal(line.as_bytes()))
+        ),
+        None => line.to_string(),
+    }
+}
+
+/// A line written by `seal_line`, read as it is when it was not encrypted
+pub fn open_line(line: &str) -> io::Result<String> {
+    let sealed = match line.strip_prefix(LINE_PREFIX) {
+        Some(sealed) => sealed,
+        None => return Ok(line.to_string()),
+    };
+    let cipher = cipher().ok_or_else(no_key)?;
+    let plain = base64::engine::general_purpose::STANDARD
+        .decode(sealed)
+        .ok()
+        .and_then(|sealed| cipher.open(&sealed))
+        .ok_or_else(|| undecryptable("line"))?;
+    String::from_utf8(plain).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sealed_contents_only_open_with_their_key() {
+        let key = base64::engine::general_purpose::STANDARD.encode([7u8; 32]);
+        let cipher = Cipher::from_base64(&key).unwrap();
+        let sealed = cipher.seal(b"[{\"content\":\"secret\"}]");
+        assert!(!sealed.windows(6).any(|window| window == b"secret"));
+        assert_eq!(cipher.open(&sealed).unwrap(), b"[{\"content\":\"secret\"}]");
+        // a fresh nonce every time
+        assert_ne!(cipher.seal(b"same"), cipher.seal(b"same"));
+
+        let other = base64::engine::general_purpose::STANDARD.encode([8u8; 32]);
+        assert!(Cipher::from_base64(&other).unwrap().open(&sealed).is_none());
+        let mut altered = sealed.clone();
+        altered[NONCE_LEN] ^= 1;
+        assert!(cipher.open(&altered).is_none());
+
+        assert!(Cipher::from_base64("c2hvcnQ=").is_err());
+        assert!(Cipher::from_base64("not base64!").is_err());
+
+        // files and lines written before encryption are read as they are
+        assert_eq!(open(b"[]".to_vec()).unwrap(), b"[]");
+        assert_eq!(open_line("{\"a\":1}").unwrap(), "{\"a\":1}");
+    }
+}
@@ -0,0 +1,490 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+
+use super::{
+    messages::{CacheStats, ChatModel, HistoryPage, HistoryQuery, MessageRepo},
+    RepoError,
+};
+use crate::clients::{
+    embeddings::Similarity,
+    vectordb::{VectorDb, VectorPoint},
+};
+
+/// Most hits taken from the vector database for one search
+fn search_limit() -> usize {
+    std::env::var("VECTOR_DB_SEARCH_LIMIT")
+        .ok()
+        .and_then(|val| val.parse::<usize>().ok())
+        .unwrap_or(200)
+}
+
+fn points(chats: &[ChatModel]) -> Vec<VectorPoint> {
+    chats
+        .iter()
+        .filter(|chat| !chat.forgotten && !chat.low_value)
+        .filter_map(|chat| {
+            Some(VectorPoint {
+                hash: chat.hash.clone(),
+                embedding: chat.embedding.clone()?,
+                model: chat.embedding_model.clone()?,
+            })
+        })
+        .collect()
+}
+
+/// Keeps messages in `messages` and mirrors their embeddings into a vector
+/// database, which then answers embeddings searches. The messages stay the
+/// source of truth, so a failed write to the vector database is logged but
+/// does not fail the save.
+pub struct ExternalVectorRepo {
+    messages: Arc<Mutex<dyn MessageRepo>>,
+    vector_db: Arc<dyn VectorDb>,
+}
+
+impl ExternalVectorRepo {
+    pub fn new(messages: Arc<Mutex<dyn MessageRepo>>, vector_db: Arc<dyn VectorDb>) -> Self {
+        ExternalVectorRepo {
+            messages,
+            vector_db,
+        }
+    }
+
+    async fn mirror(&self, user: &str, chats: &[ChatModel]) {
+        let points = points(chats);
+        if points.is_empty() {
+            return;
+        }
+        if self.vector_db.upsert(user, points).await.is_err() {
+            error!(
+                "Error mirroring embeddings of {} to the vector database",
+                user
+            );
+        }
+    }
+
+    async fn unmirror(&self, user: &str, id: &str) {
+        if self
+            .vector_db
+            .delete(user, vec![id.to_string()])
+            .await
+            .is_err()
+        {
+            error!("Error removing {} from the vector database", id);
+        }
+    }
+}
+
+#[async_trait]
+impl MessageRepo for ExternalVectorRepo {
+    async fn save_chat(
+        &mut self,
+        date: NaiveDate,
+        user: String,
+        chat: ChatModel,
+    ) -> Result<ChatModel, RepoError> {
+        let chat = self
+            .messages
+            .lock()
+            .await
+            .save_chat(date, user.clone(), chat)
+            .await?;
+        self.mirror(&user, std::slice::from_ref(&chat)).await;
+        Ok(chat)
+    }
+
+    async fn save_chats(
+        &mut self,
+        date: NaiveDate,
+        user: String,
+        chats: Vec<ChatModel>,
+    ) -> Result<Vec<ChatModel>, RepoError> {
+        let chats = self
+            .messages
+            .lock()
+            .await
+            .save_chats(date, user.clone(), chats)
+            .await?;
+        self.mirror(&user, &chats).await;
+        Ok(chats)
+    }
+
+    async fn get_chat(&mut self, user: String, id: String) -> Result<ChatModel, RepoError> {
+        self.messages.lock().await.get_chat(user, id).await
+    }
+
+    async fn embeddings_search_for_user(
+        &self,
+        user: String,
+        query_vector: Vec<f32>,
+        model: &str,
+        similarity: Similarity,
+    ) -> Result<Vec<(f32, ChatModel)>, RepoError> {
+        let hits = match self
+            .vector_db
+            .search(&user, query_vector.clone(), model, search_limit())
+            .await
+        {
+            Ok(hits) => hits,
+            Err(_) => {
+                warn!(
+                    "Vector database search failed, searching the messages of {}",
+                    user
+                );
+                return self
+                    .messages
+                    .lock()
+                    .await
+                    .embeddings_search_for_user(user, query_vector, model, similarity)
+                    .await;
+            }
+        };
+
+        // the hits only carry hashes, the content comes from the messages
+        let mut chats = self
+            .messages
+            .lock()
+            .await
+            .get_all_for_user(user.clone())
+            .await?
+            .into_iter()
+            .filter(|chat| !chat.forgotten)
+            .map(|chat| (chat.hash.clone(), chat))
+            .collect::<HashMap<String, ChatModel>>();
+        let hit_count = hits.len();
+        let ranked = hits
+            .into_iter()
+            .filter_map(|hit| Some((hit.score, chats.remove(&hit.hash)?)))
+            .collect::<Vec<(f32, ChatModel)>>();
+        if ranked.len() < hit_count {
+            warn!(
+                "Vector database returned {} hashes of {} with no message",
+                hit_count - ranked.len(),
+                user
+            );
+        }
+        Ok(ranked)
+    }
+
+    async fn embeddings_search_between(
+        &self,
+        user: String,
+        query_vector: Vec<f32>,
+        model: &str,
+        similarity: Similarity,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+    ) -> Result<Vec<(f32, ChatModel)>, RepoError> {
+        if from.is_none() && to.is_none() {
+            return self
+                .embeddings_search_for_user(user, query_vector, model, similarity)
+                .await;
+        }
+        // the top hits of the vector database may all fall outside the
+        // days, so the messages of those days are scored here instead
+        self.messages
+            .lock()
+            .await
+            .embeddings_search_between(user, query_vector, model, similarity, from, to)
+            .await
+    }
+
+    async fn keyword_search_for_user(
+        &self,
+        user: String,
+        query: &str,
+    ) -> Result<Vec<(f32, ChatModel)>, RepoError> {
+        self.messages
+            .lock()
+            .await
+            .keyword_search_for_user(user, query)
+            .await
+    }
+
+    async fn get_all_for_user(&self, user: String) -> Result<Vec<ChatModel>, RepoError> {
+        self.messages.lock().await.get_all_for_user(user).await
+    }
+
+    async fn get_all_for_user_on_day(
+        &self,
+        user: String,
+        date: NaiveDate,
+    ) -> Result<Vec<ChatModel>, RepoError> {
+        self.messages
+            .lock()
+            .await
+            .get_all_for_user_on_day(user, date)
+            .await
+    }
+
+    async fn get_for_dates(
+        &self,
+        user: String,
+        dates: Vec<NaiveDate>,
+    ) -> Result<Vec<ChatModel>, RepoError> {
+        self.messages.lock().await.get_for_dates(user, dates).await
+    }
+
+    async fn get_history(
+        &self,
+        user: String,
+        query: &HistoryQuery,
+    ) -> Result<HistoryPage, RepoError> {
+        self.messages.lock().await.get_history(user, query).await
+    }
+
+    async fn get_users(&self) -> Result<Vec<String>, RepoError> {
+        self.messages.lock().await.get_users().await
+    }
+
+    async fn get_collections(&self, user: String) -> Result<Vec<String>, RepoError> {
+        self.messages.lock().await.get_collections(user).await
+    }
+
+    async fn redact_chat(&mut self, user: String, id: String) -> Result<ChatModel, RepoError> {
+        let redacted = self
+            .messages
+            .lock()
+            .await
+            .redact_chat(user.clone(), id.clone())
+            .await?;
+        self.unmirror(&user, &id).await;
+        Ok(redacted)
+    }
+
+    async fn delete_chat(&mut self, user: String, id: String) -> Result<(), RepoError> {
+        self.messages
+            .lock()
+            .await
+            .delete_chat(user.clone(), id.clone())
+            .await?;
+        self.unmirror(&user, &id).await;
+        Ok(())
+    }
+
+    async fn get_days(&self, user: String) -> Result<Vec<NaiveDate>, RepoError> {
+        self.messages.lock().await.get_days(user).await
+    }
+
+    async fn delete_day(&mut self, user: String, date: NaiveDate) -> Result<usize, RepoError> {
+        let mut messages = self.messages.lock().await;
+        let ids = messages
+            .get_all_for_user_on_day(user.clone(), date)
+            .await?
+            .into_iter()
+            .map(|chat| chat.hash)
+            .collect::<Vec<String>>();
+        let deleted = messages.delete_day(user.clone(), date).await?;
+        drop(messages);
+        if !ids.is_empty() && self.vector_db.delete(&user, ids).await.is_err() {
+            error!(
+                "Error removing messages of {} from the vector database",
+                date
+            );
+        }
+        Ok(deleted)
+    }
+
+    async fn replace_embeddings(
+        &mut self,
+        user: String,
+        embeddings: HashMap<String, Vec<f32>>,
+        model: &str,
+    ) -> Result<usize, RepoError> {
+        let points = embeddings
+            .iter()
+            .map(|(hash, embedding)| VectorPoint {
+                hash: hash.clone(),
+                embedding: embedding.clone(),
+                model: model.to_string(),
+            })
+            .collect::<Vec<VectorPoint>>();
+        let replaced = self
+            .messages
+            .lock()
+            .await
+            .replace_embeddings(user.clone(), embeddings, model)
+            .await?;
+        if !points.is_empty() && self.vector_db.upsert(&user, points).await.is_err() {
+            error!(
+                "Error mirroring new embeddings of {} to the vector database",
+                user
+            );
+        }
+        Ok(replaced)
+    }
+
+    async fn set_embedding(
+        &mut self,
+        user: String,
+        id: String,
+        embedding: Vec<f32>,
+        model: &str,
+    ) -> Result<(), RepoError> {
+        self.messages
+            .lock()
+            .await
+            .set_embedding(user.clone(), id.clone(), embedding.clone(), model)
+            .await?;
+        let point = VectorPoint {
+            hash: id,
+            embedding,
+            model: model.to_string(),
+        };
+        if self.vector_db.upsert(&user, vec![point]).await.is_err() {
+            error!(
+                "Error mirroring embedding of {} to the vector database",
+                user
+            );
+        }
+        Ok(())
+    }
+
+    async fn warm_up(&self) -> Result<usize, RepoError> {
+        self.messages.lock().await.warm_up().await
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        match self.messages.try_lock() {
+            Ok(messages) => messages.cache_stats(),
+            Err(_) => CacheStats::default(),
+        }
+    }
+
+    async fn compact_index(&self) -> Result<usize, RepoError> {
+        self.messages.lock().await.compact_index().await
+    }
+
+    async fn compress_days(&mut self, before: NaiveDate) -> Result<usize, RepoError> {
+        self.messages.lock().await.compress_days(before).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clients::vectordb::VectorHit, repos::messages::FsMessageRepo};
+
+    // Keeps points in memory and ranks them by cosine similarity
+    #[derive(Default)]
+    struct MemoryVectorDb {
+        points: std::sync::Mutex<HashMap<(String, String), VectorPoint>>,
+    }
+
+    #[async_trait]
+    impl VectorDb for MemoryVectorDb {
+        async fn upsert(&self, user: &str, points: Vec<VectorPoint>) -> Result<(), ()> {
+            let mut stored = self.points.lock().unwrap();
+            for point in points {
+                stored.insert((user.to_string(), point.hash.clone()), point);
+            }
+            Ok(())
+        }
+
+        async fn search(
+            &self,
+            user: &str,
+            vector: Vec<f32>,
+            model: &str,
+            limit: usize,
+        ) -> Result<Vec<VectorHit>, ()> {
+            let mut hits = self
+                .points
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|((owner, _), point)| owner == user && point.model == model)
+                .map(|(_, point)| VectorHit {
+                    hash: point.hash.clone(),
+                    score: Similarity::Cosine.score(&point.embedding, &vector),
+                })
+                .collect::<Vec<VectorHit>>();
+            hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+            hits.truncate(limit);
+            Ok(hits)
+        }
+
+        async fn delete(&self, user: &str, hashes: Vec<String>) -> Result<(), ()> {
+            let mut stored = self.points.lock().unwrap();
+            for hash in hashes {
+                stored.remove(&(user.to_string(), hash));
+            }
+            Ok(())
+        }
+    }
+
+    fn chat(hash: &str, embedding: Vec<f32>) -> ChatModel {
+        ChatModel {
+            embedding: Some(embedding),
+            timestamp: 0,
+            embedding_model: Some("mock".to_string()),
+            ..ChatModel::test(hash, &format!("message {}", hash))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_merges_hits_with_messages() {
+        let user = format!("test_external_vectors_{}", uuid::Uuid::new_v4());
+        let date = chrono::Utc::now().date_naive();
+        let mut repo = ExternalVectorRepo::new(
+            Arc::new(Mutex::new(FsMessageRepo::new())),
+            Arc::new(MemoryVectorDb::default()),
+        );
+        repo.save_chat(date, user.clone(), chat("near", vec![1.0, 0.0]))
+            .await
+            .unwrap();
+        repo.save_chat(date, user.clone(), chat("far", vec![0.0, 1.0]))
+            .await
+            .unwrap();
+        repo.delete_chat(user.clone(), "far".to_string())
+            .await
+            .unwrap();
+
+        let found = repo
+            .embeddings_search_for_user(user, vec![1.0, 0.1], "mock", Similarity::Cosine)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].1.hash, "near");
+        assert_eq!(found[0].1.content, "message near");
+    }
+
+    #[tokio::test]
+    async fn test_dated_search_reaches_past_the_top_hits() {
+        let user = format!("test_external_vectors_{}", uuid::Uuid::new_v4());
+        let today = chrono::Utc::now().date_naive();
+        let old = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        let mut repo = ExternalVectorRepo::new(
+            Arc::new(Mutex::new(FsMessageRepo::new())),
+            Arc::new(MemoryVectorDb::default()),
+        );
+        // more recent and closer messages than the vector database returns
+        let recent = (0..=search_limit())
+            .map(|i| chat(&format!("recent_{}", i), vec![1.0, 0.0]))
+            .collect::<Vec<ChatModel>>();
+        repo.save_chats(today, user.clone(), recent).await.unwrap();
+        repo.save_chat(old, user.clone(), chat("old", vec![0.5, 0.5]))
+            .await
+            .unwrap();
+
+        let found = repo
+            .embeddings_search_between(
+                user,
+                vec![1.0, 0.0],
+                "mock",
+                Similarity::Cosine,
+                Some(old),
+                Some(old),
+            )
+            .await
+            .unwrap();
+        let hashes = found
+            .iter()
+            .map(|(_, chat)| chat.hash.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(hashes, vec!["old"]);
+    }
+}
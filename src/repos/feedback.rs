@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::{encryption, get_user_root};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedbackSignal {
+    Useful,
+    Irrelevant,
+}
+
+/// What the user thought of a memory retrieved for a query
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FeedbackModel {
+    pub hash: String,
+    pub query: String,
+    pub query_embedding: Vec<f32>,
+    pub signal: FeedbackSignal,
+    pub created_at: i64,
+}
+
+pub trait FeedbackRepo: Send + Sync {
+    fn save_feedback(&mut self, user: &str, feedback: FeedbackModel) -> Result<FeedbackModel, ()>;
+    fn get_feedback(&self, user: &str) -> Result<Vec<FeedbackModel>, ()>;
+}
+
+pub struct FsFeedbackRepo {}
+
+impl FsFeedbackRepo {
+    pub fn new() -> Self {
+        FsFeedbackRepo {}
+    }
+}
+
+fn get_feedback_path(user: &str) -> std::path::PathBuf {
+    get_user_root(user).join("feedback.json")
+}
+
+impl FeedbackRepo for FsFeedbackRepo {
+    fn save_feedback(&mut self, user: &str, feedback: FeedbackModel) -> Result<FeedbackModel, ()> {
+        let mut all_feedback = self.get_feedback(user)?;
+        all_feedback.push(feedback.clone());
+
+        let path = get_feedback_path(user);
+        std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| {
+            error!("Error creating directory: {}", e);
+        })?;
+        let serialized = serde_json::to_string(&all_feedback).map_err(|_| ())?;
+        match std::fs::write(&path, encryption::seal(serialized.as_bytes())) {
+            Ok(_) => Ok(feedback),
+            Err(e) => {
+                error!("Error writing to file: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    fn get_feedback(&self, user: &str) -> Result<Vec<FeedbackModel>, ()> {
+        match encryption::read_to_string(&get_feedback_path(user)) {
+            Ok(content) => serde_json::from_str(&content).map_err(|e| {
+                error!("Error deserializing feedback: {}", e);
+            }),
+            Err(_) => Ok(vec![]),
+        }
+    }
+}
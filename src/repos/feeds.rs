@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::{get_storage_root, get_user_root};
+
+// Ids of ingested items kept per feed, older ones are forgotten
+const KEPT_SEEN: usize = 500;
+
+/// An RSS or Atom feed whose new items are remembered for a user
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct FeedModel {
+    /// Chosen by the user, the items are tagged `rss:<id>`
+    pub id: String,
+    pub url: String,
+    /// Title the feed gives itself
+    pub title: Option<String>,
+    /// Whether every new item is announced to webhooks and MQTT
+    #[serde(default)]
+    pub notify: bool,
+    /// Whether the page of every item is fetched rather than keeping the
+    /// summary the feed holds
+    #[serde(default)]
+    pub full_text: bool,
+    pub created_at: i64,
+    pub last_checked: Option<i64>,
+    /// Why the last poll failed, absent when it went well
+    pub last_error: Option<String>,
+    /// Ids of the items already remembered, newest first
+    #[serde(default)]
+    pub seen: Vec<String>,
+}
+
+pub trait FeedRepo: Send + Sync {
+    fn save_feed(&mut self, user: &str, feed: FeedModel) -> Result<FeedModel, ()>;
+    fn get_feeds(&self, user: &str) -> Result<Vec<FeedModel>, ()>;
+    fn delete_feed(&mut self, user: &str, id: &str) -> Result<(), ()>;
+    /// Adds the ids of newly remembered items and how the poll went, `Err`
+    /// when the feed was deleted in the meantime
+    fn record_poll(
+        &mut self,
+        user: &str,
+        id: &str,
+        seen: Vec<String>,
+        checked: i64,
+        error: Option<String>,
+    ) -> Result<(), ()>;
+    /// Users with at least one feed
+    fn get_users(&self) -> Result<Vec<String>, ()>;
+}
+
+pub struct FsFeedRepo {}
+
+impl FsFeedRepo {
+    pub fn new() -> Self {
+        FsFeedRepo {}
+    }
+}
+
+fn get_feeds_path(user: &str) -> std::path::PathBuf {
+    get_user_root(user).join("feeds.json")
+}
+
+fn read_feeds(user: &str) -> HashMap<String, FeedModel> {
+    match std::fs::read_to_string(get_feeds_path(user)) {
+        Ok(content) => match serde_json::from_str(&content) {
+            Ok(feeds) => feeds,
+            Err(e) => {
+                error!("Error deserializing feeds: {}", e);
+                HashMap::new()
+            }
+        },
+        Err(_) => HashMap::new(),
+    }
+}
+
+fn write_feeds(user: &str, feeds: &HashMap<String, FeedModel>) -> Result<(), ()> {
+    let path = get_feeds_path(user);
+    std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| {
+        error!("Error creating directory: {}", e);
+    })?;
+    let serialized = serde_json::to_string(feeds).map_err(|_| ())?;
+    std::fs::write(&path, serialized).map_err(|e| {
+        error!("Error writing to file: {}", e);
+    })
+}
+
+impl FeedRepo for FsFeedRepo {
+    fn save_feed(&mut self, user: &str, feed: FeedModel) -> Result<FeedModel, ()> {
+        let mut feeds = read_feeds(user);
+        feeds.insert(feed.id.clone(), feed.clone());
+        write_feeds(user, &feeds)?;
+        Ok(feed)
+    }
+
+    fn get_feeds(&self, user: &str) -> Result<Vec<FeedModel>, ()> {
+        let mut feeds = read_feeds(user).into_values().collect::<Vec<_>>();
+        feeds.sort_by_key(|feed| feed.created_at);
+        Ok(feeds)
+    }
+
+    fn delete_feed(&mut self, user: &str, id: &str) -> Result<(), ()> {
+        let mut feeds = read_feeds(user);
+        feeds.remove(id).ok_or(())?;
+        write_feeds(user, &feeds)
+    }
+
+    fn record_poll(
+        &mut self,
+        user: &str,
+        id: &str,
+        seen: Vec<String>,
+        checked: i64,
+        error: Option<String>,
+    ) -> Result<(), ()> {
+        let mut feeds = read_feeds(user);
+        let feed = feeds.get_mut(id).ok_or(())?;
+        for item in seen.into_iter().rev() {
+            if !feed.seen.contains(&item) {
+                feed.seen.insert(0, item);
+            }
+        }
+        feed.seen.truncate(KEPT_SEEN);
+        feed.last_checked = Some(checked);
+        feed.last_error = error;
+        write_feeds(user, &feeds)
+    }
+
+    fn get_users(&self) -> Result<Vec<String>, ()> {
+        let entries = match std::fs::read_dir(get_storage_root()) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(vec![]),
+        };
+        let mut users = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().join("feeds.json").is_file())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect::<Vec<String>>();
+        users.sort();
+        Ok(users)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_polls_keep_the_newest_items() {
+        let mut repo = FsFeedRepo::new();
+        let username = format!("test_feeds_{}", uuid::Uuid::new_v4());
+        let feed = FeedModel {
+            id: "blog".to_string(),
+            url: "https://blog.example/feed.xml".to_string(),
+            title: None,
+            notify: false,
+            full_text: false,
+            created_at: 0,
+            last_checked: None,
+            last_error: None,
+            seen: vec![],
+        };
+        repo.save_feed(&username, feed).unwrap();
+        assert!(repo.get_users().unwrap().contains(&username));
+
+        let items = (0..KEPT_SEEN + 10).map(|item| item.to_string()).collect();
+        repo.record_poll(&username, "blog", items, 5, None).unwrap();
+        repo.record_poll(
+            &username,
+            "blog",
+            vec!["new".to_string(), "0".to_string()],
+            6,
+            None,
+        )
+        .unwrap();
+        let feeds = repo.get_feeds(&username).unwrap();
+        assert_eq!(feeds[0].seen.len(), KEPT_SEEN);
+        assert_eq!(feeds[0].seen[..2], ["new", "0"]);
+        assert_eq!(feeds[0].last_checked, Some(6));
+
+        repo.delete_feed(&username, "blog").unwrap();
+        assert!(repo
+            .record_poll(&username, "blog", vec![], 7, Some("gone".to_string()))
+            .is_err());
+    }
+}
@@ -1,45 +1,442 @@
 use std::path::PathBuf;
 
 use async_trait::async_trait;
-use chrono::NaiveDate;
-use tracing::error;
+use chrono::{NaiveDate, NaiveTime};
+use sha2::{Digest, Sha256};
+use tracing::{error, warn};
 
-use crate::clients::{self, embeddings::EmbeddingsClient};
+use super::{
+    day_cache::{self, DayCache},
+    encryption,
+    vector_index::{self, IvfIndex, VectorIndexKind},
+    RepoError,
+};
+use crate::clients::embeddings::Similarity;
 
-#[derive(Clone, serde::Serialize, serde::Deserialize, Debug)]
+#[derive(Clone, serde::Serialize, serde::Deserialize, Debug, Default)]
 pub struct ChatModel {
     pub role: String,
     pub content: String,
     pub hash: String,
     pub embedding: Option<Vec<f32>>,
+    /// When the message was stored, in seconds since the epoch. Records that
+    /// predate it are given the start of their day when read.
+    #[serde(default)]
     pub timestamp: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversation_id: Option<String>,
+    /// Set when the user asked for the message to be forgotten, in which
+    /// case only the hash is kept
+    #[serde(default)]
+    pub forgotten: bool,
+    /// Trivial messages such as "ok" are stored without an embedding and
+    /// left out of searches
+    #[serde(default)]
+    pub low_value: bool,
+    /// Model that produced `embedding`, unknown for messages embedded before
+    /// it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedding_model: Option<String>,
+    /// When the client says the message was said, which can be before it
+    /// reached us
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub occurred_at: Option<i64>,
+    /// Whatever the client wants to keep with the message, such as where
+    /// it came from
+    #[serde(default, skip_serializing_if = "std::collections::HashMap::is_empty")]
+    pub metadata: std::collections::HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
 }
+
+/// Whether a message can be found by meaning yet
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddingStatus {
+    Ready,
+    /// Saved but still waiting for its embedding
+    Pending,
+    /// Trivial or forgotten, never embedded
+    Skipped,
+}
+
+impl ChatModel {
+    pub fn embedding_status(&self) -> EmbeddingStatus {
+        match (&self.embedding, self.low_value || self.forgotten) {
+            (Some(_), _) => EmbeddingStatus::Ready,
+            (None, true) => EmbeddingStatus::Skipped,
+            (None, false) => EmbeddingStatus::Pending,
+        }
+    }
+
+    /// When the message was said as far as we know, which is what orders
+    /// and ages it
+    pub fn said_at(&self) -> i64 {
+        self.occurred_at.unwrap_or(self.timestamp)
+    }
+
+    /// Whether the message was said on a day from `from` to `to`, an open
+    /// end taking in every day on that side
+    pub fn said_between(&self, from: Option<NaiveDate>, to: Option<NaiveDate>) -> bool {
+        if from.is_none() && to.is_none() {
+            return true;
+        }
+        let day = match chrono::DateTime::from_timestamp(self.said_at(), 0) {
+            Some(time) => time.date_naive(),
+            None => return false,
+        };
+        from.is_none_or(|from| day >= from) && to.is_none_or(|to| day <= to)
+    }
+
+    /// Whether the message has every one of the `filters`, each either a
+    /// tag or a `key=value` pair of its metadata
+    pub fn matches_tags(&self, filters: &[String]) -> bool {
+        filters.iter().all(|filter| match filter.split_once('=') {
+            Some((key, value)) => self.metadata.get(key).is_some_and(|v| v == value),
+            None => self.tags.contains(filter),
+        })
+    }
+}
+
+#[cfg(test)]
+impl ChatModel {
+    /// A message from the user, saved now and otherwise empty, for tests
+    /// to build on
+    pub(crate) fn test(hash: &str, content: &str) -> ChatModel {
+        ChatModel {
+            role: "user".to_string(),
+            content: content.to_string(),
+            hash: hash.to_string(),
+            timestamp: chrono::Utc::now().timestamp(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Sizes of the in-memory caches a message repo keeps
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct CacheStats {
+    pub indexed_users: usize,
+    pub indexed_messages: usize,
+    pub cached_chats: usize,
+    pub cached_days: usize,
+    pub day_cache_hits: u64,
+    pub day_cache_misses: u64,
+}
+
+/// One page of a user's history, in chronological order
+#[derive(Clone, Debug, Default)]
+pub struct HistoryQuery {
+    pub limit: usize,
+    pub offset: usize,
+    pub from_date: Option<NaiveDate>,
+    pub to_date: Option<NaiveDate>,
+    /// Only messages matching all of these, see ChatModel::matches_tags
+    pub tags: Vec<String>,
+}
+
+impl HistoryQuery {
+    pub fn includes(&self, date: NaiveDate) -> bool {
+        self.from_date.is_none_or(|from| date >= from)
+            && self.to_date.is_none_or(|to| date <= to)
+    }
+}
+
+pub struct HistoryPage {
+    pub messages: Vec<ChatModel>,
+    /// Offset of the following page, `None` when this was the last one
+    pub next_offset: Option<usize>,
+}
+
+fn paginate(chats: Vec<ChatModel>, query: &HistoryQuery) -> HistoryPage {
+    let total = chats.len();
+    let messages = chats
+        .into_iter()
+        .skip(query.offset)
+        .take(query.limit)
+        .collect::<Vec<ChatModel>>();
+    let end = query.offset + messages.len();
+    HistoryPage {
+        messages,
+        next_offset: (end < total).then_some(end),
+    }
+}
+
 pub struct FsMessageRepo{
     memory: std::collections::HashMap<(String, String), ChatModel>, // Update HashMap key to include user
+    // Searchable messages per user, filled by `warm_up` or on a user's first search
+    index: std::sync::RwLock<std::collections::HashMap<String, Vec<ChatModel>>>,
+    vector_index: VectorIndexKind,
+    // Per user vector indexes, persisted next to the user's messages
+    vectors: std::sync::RwLock<std::collections::HashMap<String, IvfIndex>>,
+    // Parsed day files of the most recently read days
+    days: DayCache,
 }
 
 #[async_trait]
 pub trait MessageRepo: Send + Sync {
-    fn save_chat(&mut self, date: NaiveDate, user: String, chat: ChatModel) -> ChatModel;
-    fn get_chat(&mut self, user: String, id: String) -> Result<ChatModel, ()>; // Add user parameter
+    async fn save_chat(
+        &mut self,
+        date: NaiveDate,
+        user: String,
+        chat: ChatModel,
+    ) -> Result<ChatModel, RepoError>;
+    /// Saves several messages of one day with a single write, so either all
+    /// of them are stored or none
+    async fn save_chats(
+        &mut self,
+        date: NaiveDate,
+        user: String,
+        chats: Vec<ChatModel>,
+    ) -> Result<Vec<ChatModel>, RepoError> {
+        let mut saved = vec![];
+        for chat in chats {
+            saved.push(self.save_chat(date, user.clone(), chat).await?);
+        }
+        Ok(saved)
+    }
+    async fn get_chat(&mut self, user: String, id: String) -> Result<ChatModel, RepoError>;
+    /// Scores the user's embedded messages against `query_vector`, best
+    /// first. Messages still waiting for their embedding are left out.
     async fn embeddings_search_for_user(
         &self,
         user: String,
         query_vector: Vec<f32>,
-    ) -> Vec<(f32, ChatModel)>;
-    fn get_all_for_user(&self, user: String) -> Result<Vec<ChatModel>, ()>;
-    fn get_all_for_user_on_day(&self, user: String, date: NaiveDate) -> Result<Vec<ChatModel>, ()>;
+        model: &str,
+        similarity: Similarity,
+    ) -> Result<Vec<(f32, ChatModel)>, RepoError>;
+    /// Like `embeddings_search_for_user`, scoring only the messages said
+    /// between `from` and `to`
+    async fn embeddings_search_between(
+        &self,
+        user: String,
+        query_vector: Vec<f32>,
+        model: &str,
+        similarity: Similarity,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+    ) -> Result<Vec<(f32, ChatModel)>, RepoError> {
+        Ok(self
+            .embeddings_search_for_user(user, query_vector, model, similarity)
+            .await?
+            .into_iter()
+            .filter(|(_, chat)| chat.said_between(from, to))
+            .collect())
+    }
+    /// Ranks the user's messages by how well their words match `query`,
+    /// best first, leaving out messages that share no word with it
+    async fn keyword_search_for_user(
+        &self,
+        user: String,
+        query: &str,
+    ) -> Result<Vec<(f32, ChatModel)>, RepoError> {
+        Ok(bm25_search(query, self.get_all_for_user(user).await?))
+    }
+    async fn get_all_for_user(&self, user: String) -> Result<Vec<ChatModel>, RepoError>;
+    async fn get_all_for_user_on_day(&self, user: String, date: NaiveDate) -> Result<Vec<ChatModel>, RepoError>;
+    /// Reads several days at once, returning messages in the order of `dates`
+    async fn get_for_dates(&self, user: String, dates: Vec<NaiveDate>) -> Result<Vec<ChatModel>, RepoError>;
+    /// Walks the user's history a page at a time
+    async fn get_history(&self, user: String, query: &HistoryQuery) -> Result<HistoryPage, RepoError> {
+        let chats = self
+            .get_all_for_user(user)
+            .await?
+            .into_iter()
+            .filter(|chat| match chrono::DateTime::from_timestamp(chat.timestamp, 0) {
+                Some(date) => query.includes(date.date_naive()),
+                None => false,
+            })
+            .filter(|chat| chat.matches_tags(&query.tags))
+            .collect();
+        Ok(paginate(chats, query))
+    }
+    /// Lists every user that has stored messages
+    async fn get_users(&self) -> Result<Vec<String>, RepoError>;
+    /// Names of the user's collections that hold messages, without the
+    /// default one
+    async fn get_collections(&self, user: String) -> Result<Vec<String>, RepoError>;
+    /// Wipes the content and embedding of a message but keeps its hash
+    async fn redact_chat(&mut self, user: String, id: String) -> Result<ChatModel, RepoError>;
+    /// Removes a message completely
+    async fn delete_chat(&mut self, user: String, id: String) -> Result<(), RepoError>;
+    /// Days the user has messages stored under, oldest first
+    async fn get_days(&self, user: String) -> Result<Vec<NaiveDate>, RepoError> {
+        let mut days = self
+            .get_all_for_user(user)
+            .await?
+            .iter()
+            .filter_map(|chat| chrono::DateTime::from_timestamp(chat.timestamp, 0))
+            .map(|time| time.date_naive())
+            .collect::<Vec<NaiveDate>>();
+        days.sort();
+        days.dedup();
+        Ok(days)
+    }
+    /// Removes every message stored under `date`, returning how many there
+    /// were
+    async fn delete_day(&mut self, user: String, date: NaiveDate) -> Result<usize, RepoError> {
+        let chats = self.get_all_for_user_on_day(user.clone(), date).await?;
+        for chat in chats.iter() {
+            self.delete_chat(user.clone(), chat.hash.clone()).await?;
+        }
+        Ok(chats.len())
+    }
+    /// Swaps in new embeddings from `model` keyed by message hash, leaving
+    /// every file untouched unless all of them could be prepared
+    async fn replace_embeddings(
+        &mut self,
+        user: String,
+        embeddings: std::collections::HashMap<String, Vec<f32>>,
+        model: &str,
+    ) -> Result<usize, RepoError>;
+    /// Stores the embedding of a message that was saved without one
+    async fn set_embedding(
+        &mut self,
+        user: String,
+        id: String,
+        embedding: Vec<f32>,
+        model: &str,
+    ) -> Result<(), RepoError> {
+        let embeddings = std::collections::HashMap::from([(id, embedding)]);
+        match self.replace_embeddings(user, embeddings, model).await? {
+            0 => Err(RepoError::NotFound),
+            _ => Ok(()),
+        }
+    }
+    /// Loads every user's messages into the search index ahead of the first
+    /// search, returning how many messages were indexed
+    async fn warm_up(&self) -> Result<usize, RepoError> {
+        Ok(0)
+    }
+    fn cache_stats(&self) -> CacheStats {
+        CacheStats::default()
+    }
+    /// Rebuilds the vector indexes from the stored embeddings, dropping
+    /// removed messages and centroids that drifted, returning how many were
+    /// rebuilt
+    async fn compact_index(&self) -> Result<usize, RepoError> {
+        Ok(0)
+    }
+    /// Moves the days of every user stored before `before` into compressed
+    /// cold storage, returning how many days were moved
+    async fn compress_days(&mut self, _before: NaiveDate) -> Result<usize, RepoError> {
+        Ok(0)
+    }
 }
 
 impl FsMessageRepo {
     pub fn new() -> FsMessageRepo {
+        FsMessageRepo::with_day_cache(day_cache::DEFAULT_CAPACITY)
+    }
+
+    /// A repo keeping the parsed files of at most `days` days in memory
+    pub fn with_day_cache(days: usize) -> FsMessageRepo {
         FsMessageRepo {
             memory: std::collections::HashMap::new(),
+            index: std::sync::RwLock::new(std::collections::HashMap::new()),
+            vector_index: VectorIndexKind::from_env(),
+            vectors: std::sync::RwLock::new(std::collections::HashMap::new()),
+            days: DayCache::new(days),
+        }
+    }
+
+    /// Moves the days of `user` and their collections from before `before`
+    /// into cold storage, returning how many days were moved
+    pub fn compress_days_of_user(
+        &mut self,
+        user: &str,
+        before: NaiveDate,
+    ) -> Result<usize, RepoError> {
+        let mut compressed = 0;
+        for key in keys_of_user(user) {
+            let dates = get_dates_for_user(key.clone());
+            for date in dates.into_iter().filter(|date| *date < before) {
+                let path = get_path_for_date(key.clone(), date).join("messages.json");
+                if compress_day(&path)? {
+                    self.days.forget(&key, date);
+                    compressed += 1;
+                }
+            }
+        }
+        Ok(compressed)
+    }
+
+    // The messages of the user's day, from the cache while the file is unchanged
+    fn read_day(&self, user: &str, date: NaiveDate) -> Result<Vec<ChatModel>, RepoError> {
+        let path = get_path_for_date(user.to_string(), date).join("messages.json");
+        self.days.get(user, date, &path, |path| get_from_fs(path.to_path_buf()))
+    }
+
+    fn write_day(&self, user: &str, date: NaiveDate, chats: &[ChatModel]) -> Result<(), RepoError> {
+        let path = get_path_for_date(user.to_string(), date).join("messages.json");
+        let written = write_to_fs(&path, chats);
+        self.days.forget(user, date);
+        written
+    }
+
+    // Runs `update` on the user's vector index and persists it. An index that
+    // has to be built first is built from what is already on disk, which
+    // includes the change, so `update` is skipped.
+    fn update_vectors(
+        &self,
+        user: &str,
+        chats: Option<&[ChatModel]>,
+        update: impl FnOnce(&mut IvfIndex),
+    ) {
+        let mut vectors = self.vectors.write().unwrap();
+        if let Some(index) = vectors.get_mut(user) {
+            update(index);
+            vector_index::save(user, index);
+            return;
+        }
+        let index = match vector_index::load(user) {
+            Some(mut index) => {
+                update(&mut index);
+                index
+            }
+            None => {
+                let chats = match chats {
+                    Some(chats) => chats.to_vec(),
+                    None => read_all_for_user(&self.days, user).unwrap_or_default(),
+                };
+                let mut index = IvfIndex::default();
+                for chat in chats.iter().filter(|chat| !chat.forgotten) {
+                    if let Some(embedding) = &chat.embedding {
+                        index.insert(&chat.hash, embedding);
+                    }
+                }
+                index
+            }
+        };
+        vector_index::save(user, &index);
+        vectors.insert(user.to_string(), index);
+    }
+
+    // Drops a message that was changed on disk from the search caches
+    fn forget_cached(&self, user: &str, id: &str) {
+        self.index.write().unwrap().remove(user);
+        if self.vector_index == VectorIndexKind::Ivf {
+            self.update_vectors(user, None, |index| index.remove(id));
         }
     }
+
+    // The user's messages from the search index, loading them on first use
+    fn indexed_chats(&self, user: &str) -> Result<Vec<ChatModel>, RepoError> {
+        if let Some(chats) = self.index.read().unwrap().get(user) {
+            return Ok(chats.clone());
+        }
+        let chats = read_all_for_user(&self.days, user)?;
+        self.index.write().unwrap().insert(user.to_string(), chats.clone());
+        Ok(chats)
+    }
+
+    fn forget_vectors(&self, user: &str) {
+        self.vectors.write().unwrap().remove(user);
+        vector_index::remove(user);
+    }
 }
 
-fn cosine_similarity(v1: &Vec<f32>, v2: &Vec<f32>) -> f32 {
+pub fn cosine_similarity(v1: &[f32], v2: &[f32]) -> f32 {
     let dot_product = v1.iter().zip(v2).map(|(a, b)| a * b).sum::<f32>();
     let magnitude_v1 = (v1.iter().map(|a| a.powi(2)).sum::<f32>()).sqrt();
     let magnitude_v2 = (v2.iter().map(|a| a.powi(2)).sum::<f32>()).sqrt();
@@ -47,150 +444,1451 @@ fn cosine_similarity(v1: &Vec<f32>, v2: &Vec<f32>) -> f32 {
     dot_product / magnitude_product
 }
 
+// BM25 tuning, the usual defaults
+const BM25_K1: f32 = 1.2;
+const BM25_B: f32 = 0.75;
+
+/// Lowercased words of `text`. Anything that is not a letter or digit splits
+/// words, except inside them so codes like `E-1042` or `user_id` stay whole.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| c.is_whitespace() || (!c.is_alphanumeric() && c != '-' && c != '_'))
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()))
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+/// Scores `chats` against `query` with BM25, best first
+pub fn bm25_search(query: &str, chats: Vec<ChatModel>) -> Vec<(f32, ChatModel)> {
+    let mut terms = tokenize(query);
+    terms.sort();
+    terms.dedup();
+    let chats = chats
+        .into_iter()
+        .filter(|chat| !chat.forgotten && !chat.low_value)
+        .collect::<Vec<ChatModel>>();
+    if terms.is_empty() || chats.is_empty() {
+        return vec![];
+    }
+
+    let documents = chats
+        .iter()
+        .map(|chat| tokenize(&chat.content))
+        .collect::<Vec<Vec<String>>>();
+    let count = documents.len() as f32;
+    let average_length = documents.iter().map(|words| words.len()).sum::<usize>() as f32 / count;
+    let idf = terms
+        .iter()
+        .map(|term| {
+            let with_term = documents
+                .iter()
+                .filter(|words| words.contains(term))
+                .count() as f32;
+            ((count - with_term + 0.5) / (with_term + 0.5) + 1.0).ln()
+        })
+        .collect::<Vec<f32>>();
+
+    let mut ranked = chats
+        .into_iter()
+        .zip(documents)
+        .filter_map(|(chat, words)| {
+            let length = words.len() as f32;
+            let score = terms
+                .iter()
+                .zip(&idf)
+                .map(|(term, idf)| {
+                    let frequency = words.iter().filter(|word| *word == term).count() as f32;
+                    idf * frequency * (BM25_K1 + 1.0)
+                        / (frequency
+                            + BM25_K1 * (1.0 - BM25_B + BM25_B * length / average_length.max(1.0)))
+                })
+                .sum::<f32>();
+            (score > 0.0).then_some((score, chat))
+        })
+        .collect::<Vec<(f32, ChatModel)>>();
+    ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+    ranked
+}
+
 fn get_root_path(user: String) -> std::path::PathBuf {
-    let dir = match std::env::var("MESSAGE_STORAGE_PATH") {
-        Ok(val) => std::path::PathBuf::from(val),
-        Err(_) => dirs::data_local_dir().unwrap(),
+    super::get_user_root(&user)
+}
+pub fn get_path_for_date(user: String, date: NaiveDate) -> std::path::PathBuf {
+    get_root_path(user.clone()).join(format!("{}", date.format("%Y-%m-%d")))
+}
+
+/// On-disk form of a chat. Message bodies live in the content store and are
+/// referenced by digest, so repeated bodies are only written once. Records
+/// without a digest predate the content store and carry their content inline.
+/// Next to each body the store counts the day files' records referring to it.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredChat {
+    #[serde(flatten)]
+    chat: ChatModel,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_digest: Option<String>,
+}
+
+fn get_content_path(digest: &str) -> PathBuf {
+    content_path_below(&super::get_storage_root(), digest)
+}
+
+fn content_path_below(root: &std::path::Path, digest: &str) -> PathBuf {
+    root.join(".content").join(&digest[..2]).join(digest)
+}
+
+// Where the number of records referring to the body at `content_path` is kept
+fn references_path(content_path: &std::path::Path) -> PathBuf {
+    content_path.with_extension("refs")
+}
+
+fn content_digest(content: &str) -> Option<String> {
+    match content.is_empty() {
+        true => None,
+        false => Some(format!("{:x}", Sha256::digest(content.as_bytes()))),
+    }
+}
+
+/// Where the content store keeps a message body, `None` for an empty one
+pub fn content_path(content: &str) -> Option<PathBuf> {
+    content_digest(content).map(|digest| get_content_path(&digest))
+}
+
+// The keys the user's messages are stored under, their own followed by those
+// of their collections
+fn keys_of_user(user: &str) -> Vec<String> {
+    let mut keys = vec![user.to_string()];
+    let collections = super::get_user_root(user).join(super::COLLECTIONS_FOLDER);
+    if let Ok(entries) = std::fs::read_dir(collections) {
+        let mut names = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().to_str().map(|name| name.to_string()))
+            .collect::<Vec<String>>();
+        names.sort();
+        keys.extend(names.iter().map(|name| super::collection_key(user, name)));
+    }
+    keys
+}
+
+// Serialises changes to the content store, whose bodies and reference counts
+// are shared by every repo of the process
+static CONTENT_STORE: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+// The digests the records of the day file at `path` refer to, once for each
+// record. Days in cold storage carry their content inline and refer to none.
+fn referenced_digests(path: &std::path::Path) -> Result<Vec<String>, RepoError> {
+    let content = match encryption::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(RepoError::Io(e)),
     };
+    let stored: Vec<StoredChat> = serde_json::from_str(&content)
+        .map_err(|e| RepoError::Corrupt(format!("{}: {}", path.display(), e)))?;
+    Ok(stored
+        .into_iter()
+        .filter_map(|chat| chat.content_digest)
+        .collect())
+}
 
-    let path = dir.join("muninn").join(user.clone());
-    path
+// How many more records refer to each body after a day file went from
+// referring to `before` to referring to `after`
+fn reference_changes(before: &[String], after: &[String]) -> Vec<(String, i64)> {
+    let mut changes = std::collections::BTreeMap::<&str, i64>::new();
+    for digest in before {
+        *changes.entry(digest).or_default() -= 1;
+    }
+    for digest in after {
+        *changes.entry(digest).or_default() += 1;
+    }
+    changes
+        .into_iter()
+        .filter(|(_, change)| *change != 0)
+        .map(|(digest, change)| (digest.to_string(), change))
+        .collect()
 }
-pub fn get_path_for_date(user: String, date: NaiveDate) -> std::path::PathBuf {
-    let path = get_root_path(user.clone()).join(format!("{}", date.format("%Y-%m-%d")));
-    path
+
+// Adds `change` to the records referring to a body and removes the body once
+// none do any more. A body without a count is referred to by none.
+fn add_references(digest: &str, change: i64) {
+    let content_path = get_content_path(digest);
+    let path = references_path(&content_path);
+    let count = std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|count| count.trim().parse::<i64>().ok())
+        .unwrap_or(0)
+        + change;
+    if count > 0 {
+        if let Err(e) = super::write_atomic(&path, count.to_string().as_bytes()) {
+            error!("Error counting references to content {}: {}", digest, e);
+        }
+        return;
+    }
+    for path in [content_path, path] {
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                error!("Error removing content {}: {}", digest, e)
+            }
+            _ => {}
+        }
+    }
+}
+
+// Applies what a day file no longer refers to once it was written or
+// removed. Bodies referred to anew are counted before the file is written,
+// so a crash in between leaves a body kept too long rather than lost.
+fn release_references(changes: &[(String, i64)]) {
+    for (digest, change) in changes.iter().filter(|(_, change)| *change < 0) {
+        add_references(digest, *change);
+    }
+}
+
+/// Counts the records below `root` referring to each body of the content
+/// store, which stores written before the counts were kept lack
+pub fn count_content_references(root: &std::path::Path) -> Result<(), ()> {
+    let mut references = std::collections::HashMap::<String, usize>::new();
+    count_references_below(root, &mut references)?;
+    for (digest, count) in references {
+        let content_path = content_path_below(root, &digest);
+        if !content_path.exists() {
+            warn!("Content {} is referred to but missing", digest);
+            continue;
+        }
+        super::write_atomic(&references_path(&content_path), count.to_string().as_bytes())
+            .map_err(|e| {
+                error!("Error counting references to content {}: {}", digest, e);
+            })?;
+    }
+    Ok(())
+}
+
+fn count_references_below(
+    dir: &std::path::Path,
+    references: &mut std::collections::HashMap<String, usize>,
+) -> Result<(), ()> {
+    let entries = std::fs::read_dir(dir).map_err(|e| {
+        error!("Error listing {:?}: {}", dir, e);
+    })?;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let name = entry.file_name();
+        if path.is_dir() {
+            // namespaces are storage roots of their own and migrated apart
+            if name != ".content" && name != ".namespaces" {
+                count_references_below(&path, references)?;
+            }
+        } else if name == "messages.json" {
+            // a day that cannot be read might refer to any body, counting
+            // without it could remove bodies still in use
+            let digests = referenced_digests(&path).map_err(|e| {
+                error!("Error reading {:?}: {}", path, e);
+            })?;
+            for digest in digests {
+                *references.entry(digest).or_default() += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+// The day holding message `id` of `user`, with its messages and the message's
+// position
+fn find_chat(
+    days: &DayCache,
+    user: &str,
+    id: &str,
+) -> Result<(NaiveDate, Vec<ChatModel>, usize), RepoError> {
+    for date in get_dates_for_user(user.to_string()) {
+        let path = get_path_for_date(user.to_string(), date).join("messages.json");
+        let chats = days.get(user, date, &path, |path| get_from_fs(path.to_path_buf()))?;
+        if let Some(position) = chats.iter().position(|chat| chat.hash == id) {
+            return Ok((date, chats, position));
+        }
+    }
+    Err(RepoError::NotFound)
+}
+
+// Writes the body to the content store unless an identical one is already there
+fn store_content(content: &str) -> Option<String> {
+    let digest = format!("{:x}", Sha256::digest(content.as_bytes()));
+    let path = get_content_path(&digest);
+    if !path.exists() {
+        if let Err(e) = std::fs::create_dir_all(path.parent().unwrap()) {
+            error!("Error creating content directory: {}", e);
+            return None;
+        }
+        if let Err(e) = super::write_atomic(&path, &encryption::seal(content.as_bytes())) {
+            error!("Error writing content {}: {}", digest, e);
+            return None;
+        }
+    }
+    Some(digest)
+}
+
+fn to_stored(chat: &ChatModel) -> StoredChat {
+    let digest = match chat.content.is_empty() {
+        true => None,
+        false => store_content(&chat.content),
+    };
+    let mut chat = chat.clone();
+    if digest.is_some() {
+        chat.content = "".to_string();
+    }
+    StoredChat {
+        chat,
+        content_digest: digest,
+    }
+}
+
+fn from_stored(stored: StoredChat) -> ChatModel {
+    let mut chat = stored.chat;
+    if let Some(digest) = stored.content_digest {
+        match encryption::read_to_string(&get_content_path(&digest)) {
+            Ok(content) => chat.content = content,
+            Err(e) => error!("Missing content {}: {}", digest, e),
+        }
+    }
+    chat
+}
+
+/// Name of a day file in cold storage, which holds the day's messages as
+/// zstd compressed JSON lines with their content inline
+pub const COLD_FILE: &str = "messages.jsonl.zst";
+
+// Compression level of cold day files, they are written once and rarely read
+const COLD_LEVEL: i32 = 19;
+
+fn cold_path(path: &std::path::Path) -> PathBuf {
+    path.with_file_name(COLD_FILE)
+}
+
+// The messages of the cold day file next to `path`, `None` when there is none
+fn read_cold(path: &std::path::Path) -> Result<Option<Vec<StoredChat>>, RepoError> {
+    let path = cold_path(path);
+    let compressed = match encryption::read(&path) {
+        Ok(compressed) => compressed,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(RepoError::Io(e)),
+    };
+    let corrupt = |e: String| RepoError::Corrupt(format!("{}: {}", path.display(), e));
+    let content = zstd::decode_all(compressed.as_slice()).map_err(|e| corrupt(e.to_string()))?;
+    let content = String::from_utf8(content).map_err(|e| corrupt(e.to_string()))?;
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(|e| corrupt(e.to_string())))
+        .collect::<Result<Vec<StoredChat>, RepoError>>()
+        .map(Some)
+}
+
+// Drops the cold day file next to `path` once `path` holds the day again
+fn remove_cold(path: &std::path::Path) {
+    match std::fs::remove_file(cold_path(path)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            error!("Error removing cold day file next to {:?}: {}", path, e)
+        }
+        _ => {}
+    }
+}
+
+// Moves the day file at `path` into cold storage, returning whether there
+// was one. The bodies it referred to are written inline, so the content
+// store can let go of them.
+fn compress_day(path: &std::path::Path) -> Result<bool, RepoError> {
+    let content = match encryption::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(RepoError::Io(e)),
+    };
+    let stored: Vec<StoredChat> = serde_json::from_str(&content)
+        .map_err(|e| RepoError::Corrupt(format!("{}: {}", path.display(), e)))?;
+    let digests = stored
+        .iter()
+        .filter_map(|chat| chat.content_digest.clone())
+        .collect::<Vec<String>>();
+    let mut lines = String::new();
+    for chat in stored.into_iter().map(from_stored) {
+        let inline = StoredChat {
+            chat,
+            content_digest: None,
+        };
+        let line =
+            serde_json::to_string(&inline).map_err(|e| RepoError::Corrupt(e.to_string()))?;
+        lines.push_str(&line);
+        lines.push('\n');
+    }
+    let compressed = zstd::encode_all(lines.as_bytes(), COLD_LEVEL)?;
+    super::write_atomic(&cold_path(path), &encryption::seal(&compressed))?;
+    let _store = CONTENT_STORE.lock().unwrap();
+    std::fs::remove_file(path)?;
+    release_references(&reference_changes(&digests, &[]));
+    Ok(true)
+}
+
+// A day without a file has no messages, a file that cannot be parsed is an
+// error rather than an empty day so it is never silently overwritten. Days in
+// cold storage are read from their compressed file.
+fn get_from_fs(path: PathBuf) -> Result<Vec<ChatModel>, RepoError> {
+    let chats: Vec<StoredChat> = match encryption::read_to_string(&path) {
+        Ok(content) => serde_json::from_str(&content)
+            .map_err(|e| RepoError::Corrupt(format!("{}: {}", path.display(), e)))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => match read_cold(&path)? {
+            Some(chats) => chats,
+            None => return Ok(vec![]),
+        },
+        Err(e) => return Err(RepoError::Io(e)),
+    };
+    let day_start = day_of(&path).map(|date| date.and_time(NaiveTime::MIN).and_utc().timestamp());
+    Ok(chats
+        .into_iter()
+        .map(from_stored)
+        .map(|mut chat| {
+            if chat.timestamp == 0 {
+                chat.timestamp = day_start.unwrap_or(0);
+            }
+            chat
+        })
+        .collect())
+}
+
+// The day a day file belongs to, from the name of its folder
+fn day_of(path: &std::path::Path) -> Option<NaiveDate> {
+    let folder = path.parent()?.file_name()?.to_str()?;
+    NaiveDate::parse_from_str(folder, "%Y-%m-%d").ok()
+}
+
+fn write_to_fs(path: &std::path::Path, chats: &[ChatModel]) -> Result<(), RepoError> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?; // create directory if it does not exist
+    }
+    let _store = CONTENT_STORE.lock().unwrap();
+    let stored = chats.iter().map(to_stored).collect::<Vec<StoredChat>>();
+    let serialized = serde_json::to_string(&stored)
+        .map_err(|e| RepoError::Corrupt(e.to_string()))?;
+
+    // only day files count as references, a staged copy replaces its day
+    // file without changing what it refers to
+    let is_day = path.file_name() == Some(std::ffi::OsStr::new("messages.json"));
+    let changes = match is_day {
+        true => reference_changes(
+            // a day that cannot be read is overwritten as if it had none,
+            // which at worst keeps its bodies too long
+            &referenced_digests(path).unwrap_or_default(),
+            &stored
+                .iter()
+                .filter_map(|chat| chat.content_digest.clone())
+                .collect::<Vec<String>>(),
+        ),
+        false => vec![],
+    };
+    for (digest, change) in changes.iter().filter(|(_, change)| *change > 0) {
+        add_references(digest, *change);
+    }
+
+    super::write_atomic(path, &encryption::seal(serialized.as_bytes())).map_err(|e| {
+        error!("Error writing to file: {}", e);
+        RepoError::Io(e)
+    })?;
+    release_references(&changes);
+    // a cold day that was written to is kept uncompressed until it is
+    // compressed again
+    if is_day {
+        remove_cold(path);
+    }
+    Ok(())
+}
+
+/// What `recover` found below the storage root
+#[derive(Debug, Default, PartialEq)]
+pub struct RecoveryReport {
+    /// Writes that finished but were not renamed into place yet
+    pub completed: usize,
+    /// Unfinished writes that were thrown away
+    pub discarded: usize,
+    /// Day files cut short by a crash, of which the complete messages were
+    /// kept
+    pub salvaged: usize,
+}
+
+// The messages of a day file that was cut short, up to the last one that
+// was written completely
+fn salvage(content: &str) -> Option<Vec<StoredChat>> {
+    let mut end = content.len();
+    while let Some(close) = content[..end].rfind('}') {
+        let candidate = format!("{}]", &content[..=close]);
+        if let Ok(chats) = serde_json::from_str::<Vec<StoredChat>>(&candidate) {
+            return Some(chats);
+        }
+        end = close;
+    }
+    content.trim_start().starts_with('[').then(Vec::new)
+}
+
+// Whether a staged write is complete and can replace the file it was for
+fn is_complete(staged: &std::path::Path, target: &std::path::Path) -> bool {
+    let content = match encryption::read(staged) {
+        Ok(content) => content,
+        Err(_) => return false,
+    };
+    match target.file_name().and_then(|name| name.to_str()) {
+        Some("messages.json") => serde_json::from_slice::<Vec<StoredChat>>(&content).is_ok(),
+        // bodies in the content store are named by their digest
+        Some(digest)
+            if target
+                .parent()
+                .and_then(|p| p.parent())
+                .and_then(|p| p.file_name())
+                == Some(std::ffi::OsStr::new(".content")) =>
+        {
+            format!("{:x}", Sha256::digest(&content)) == digest
+        }
+        _ => false,
+    }
+}
+
+fn recover_dir(dir: &std::path::Path, report: &mut RecoveryReport) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            recover_dir(&path, report);
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(target) = name.strip_suffix(".tmp") {
+            let target = path.with_file_name(target);
+            // the file a finished write was for is only missing when the
+            // crash came before the rename
+            if !target.exists() && is_complete(&path, &target) {
+                match std::fs::rename(&path, &target) {
+                    Ok(_) => report.completed += 1,
+                    Err(e) => error!("Error completing write of {:?}: {}", target, e),
+                }
+            } else if std::fs::remove_file(&path).is_ok() {
+                report.discarded += 1;
+            }
+        } else if name.ends_with(".json.reembed") {
+            // a re-embedding that was cut short left the originals in place
+            if std::fs::remove_file(&path).is_ok() {
+                report.discarded += 1;
+            }
+        } else if name == "messages.json" {
+            let content = match encryption::read_to_string(&path) {
+                Ok(content) => content,
+                Err(_) => continue,
+            };
+            if serde_json::from_str::<Vec<StoredChat>>(&content).is_ok() {
+                continue;
+            }
+            let chats = match salvage(&content) {
+                Some(chats) => chats,
+                None => {
+                    warn!("Could not salvage day file {:?}", path);
+                    continue;
+                }
+            };
+            // the damaged file is kept aside in case more can be read from it
+            let aside = path.with_file_name("messages.json.corrupt");
+            let serialized = serde_json::to_string(&chats).unwrap_or_else(|_| "[]".to_string());
+            match std::fs::rename(&path, &aside)
+                .and_then(|_| super::write_atomic(&path, &encryption::seal(serialized.as_bytes())))
+            {
+                Ok(_) => {
+                    warn!("Salvaged {} messages of {:?}", chats.len(), path);
+                    report.salvaged += 1;
+                }
+                Err(e) => error!("Error salvaging {:?}: {}", path, e),
+            }
+        }
+    }
 }
 
-fn get_from_fs(path: PathBuf) -> Vec<ChatModel> {
-    let chats: Vec<ChatModel> = match std::fs::read_to_string(&path) {
-        Ok(content) => serde_json::from_str(&content).unwrap(),
-        Err(_) => vec![],
+/// Finishes or throws away writes a crash interrupted below `root` and
+/// salvages day files that were cut short before writes were atomic. Run
+/// at startup, before anything reads the files.
+pub fn recover(root: &std::path::Path) -> RecoveryReport {
+    let mut report = RecoveryReport::default();
+    recover_dir(root, &mut report);
+    report
+}
+
+// Upper bound on day files read at the same time
+const MAX_PARALLEL_READS: usize = 8;
+
+fn read_dates_concurrently(
+    days: &DayCache,
+    user: String,
+    dates: &[NaiveDate],
+) -> Result<Vec<ChatModel>, RepoError> {
+    let mut chats: Vec<ChatModel> = vec![];
+    for batch in dates.chunks(MAX_PARALLEL_READS) {
+        let results = std::thread::scope(|scope| {
+            let handles = batch
+                .iter()
+                .map(|date| {
+                    let path = get_path_for_date(user.clone(), *date).join("messages.json");
+                    let user = user.as_str();
+                    scope.spawn(move || {
+                        days.get(user, *date, &path, |path| get_from_fs(path.to_path_buf()))
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| match handle.join() {
+                    Ok(chats) => chats,
+                    Err(_) => Err(RepoError::Corrupt("failed to read day file".to_string())),
+                })
+                .collect::<Vec<Result<Vec<ChatModel>, RepoError>>>()
+        });
+        for day in results {
+            chats.extend(day?);
+        }
+    }
+    Ok(chats)
+}
+
+// Dates that have a folder for the user, in ascending order
+fn read_all_for_user(days: &DayCache, user: &str) -> Result<Vec<ChatModel>, RepoError> {
+    read_dates_concurrently(days, user.to_string(), &get_dates_for_user(user.to_string()))
+}
+
+fn get_dates_for_user(user: String) -> Vec<NaiveDate> {
+    let path = get_root_path(user);
+    // Find all the subdirectories
+    let date_folders = match std::fs::read_dir(&path) {
+        Ok(val) => val,
+        Err(_) => return vec![],
     };
-    chats
+
+    let date_folders = date_folders
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .collect::<Vec<PathBuf>>();
+
+    let mut date_folders = date_folders
+        .iter()
+        .filter_map(|x| {
+            x.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|date_str| NaiveDate::parse_from_str(date_str, "%Y-%m-%d").ok())
+        })
+        .collect::<Vec<NaiveDate>>();
+
+    // Sort the dates in ascending order
+    date_folders.sort();
+    date_folders
 }
 
 #[async_trait]
 impl MessageRepo for FsMessageRepo {
-    fn save_chat(&mut self, date: NaiveDate, user: String, chat: ChatModel) -> ChatModel {
+    async fn save_chat(
+        &mut self,
+        date: NaiveDate,
+        user: String,
+        chat: ChatModel,
+    ) -> Result<ChatModel, RepoError> {
+        let mut chats = self.read_day(&user, date)?;
+        chats.push(chat.clone());
+        self.write_day(&user, date, &chats)?;
+
         let key = (chat.hash.clone(), user.clone());
         self.memory.insert(key, chat.clone());
 
-        let path = get_path_for_date(user.clone(), date).join("messages.json");
-
-        let mut chats = get_from_fs(path.clone());
-        chats.push(chat.clone());
+        if let Some(indexed) = self.index.write().unwrap().get_mut(&user) {
+            indexed.push(chat.clone());
+        }
+        if let (VectorIndexKind::Ivf, Some(embedding)) = (self.vector_index, &chat.embedding) {
+            self.update_vectors(&user, None, |index| index.insert(&chat.hash, embedding));
+        }
+        Ok(chat)
+    }
 
-        std::fs::create_dir_all(path.parent().unwrap()).unwrap(); // create directory if it does not exist
-        let serialized = serde_json::to_string(&chats).unwrap();
+    async fn save_chats(
+        &mut self,
+        date: NaiveDate,
+        user: String,
+        new_chats: Vec<ChatModel>,
+    ) -> Result<Vec<ChatModel>, RepoError> {
+        let mut chats = self.read_day(&user, date)?;
+        chats.extend(new_chats.iter().cloned());
+        self.write_day(&user, date, &chats)?;
 
-        match std::fs::write(&path, serialized) {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Error writing to file: {}", e)
-            }
+        for chat in new_chats.iter() {
+            self.memory
+                .insert((chat.hash.clone(), user.clone()), chat.clone());
         }
-        chat
+        if let Some(indexed) = self.index.write().unwrap().get_mut(&user) {
+            indexed.extend(new_chats.iter().cloned());
+        }
+        if self.vector_index == VectorIndexKind::Ivf {
+            self.update_vectors(&user, None, |index| {
+                for chat in new_chats.iter() {
+                    if let Some(embedding) = &chat.embedding {
+                        index.insert(&chat.hash, embedding);
+                    }
+                }
+            });
+        }
+        Ok(new_chats)
     }
 
-    fn get_chat(&mut self, user: String, id: String) -> Result<ChatModel, ()> {
+    async fn get_chat(&mut self, user: String, id: String) -> Result<ChatModel, RepoError> {
         let key = (id, user.clone());
-        let path = get_path_for_date(user.clone(), chrono::Local::now().date_naive())
-            .join("messages.json");
 
         match self.memory.get(&key) {
             Some(chat) => Ok(chat.clone()),
             None => {
-                let chats = get_from_fs(path);
+                let chats = self.read_day(&user, chrono::Local::now().date_naive())?;
                 for chat in chats {
                     let key = (chat.hash.clone(), user.clone());
                     self.memory.insert(key, chat.clone());
                 }
                 match self.memory.get(&key) {
                     Some(chat) => Ok(chat.clone()),
-                    None => {
-                        error!("Chat not found");
-                        Err(())
-                    }
+                    None => Err(RepoError::NotFound),
                 }
             }
         }
     }
-    fn get_all_for_user(&self, user: String) -> Result<Vec<ChatModel>, ()> {
-        let path = get_root_path(user.clone());
-        // Find all the subdirectories
-        let date_folders = match std::fs::read_dir(&path) {
-            Ok(val) => val,
-            Err(_) => return Ok(vec![]),
-        };
-
-        let date_folders = date_folders
-            .filter_map(|entry| entry.ok().map(|e| e.path()))
-            .collect::<Vec<PathBuf>>();
+    async fn keyword_search_for_user(
+        &self,
+        user: String,
+        query: &str,
+    ) -> Result<Vec<(f32, ChatModel)>, RepoError> {
+        Ok(bm25_search(query, self.indexed_chats(&user)?))
+    }
 
-        let mut date_folders = date_folders
-            .iter()
-            .filter_map(|x| {
-                x.file_name()
-                    .and_then(|name| name.to_str())
-                    .and_then(|date_str| NaiveDate::parse_from_str(date_str, "%Y-%m-%d").ok())
-            })
-            .collect::<Vec<NaiveDate>>();
+    async fn get_all_for_user(&self, user: String) -> Result<Vec<ChatModel>, RepoError> {
+        // yields between batches of days so a caller's timeout can give up
+        // on a long history instead of waiting for every day to be read
+        let mut chats = vec![];
+        for batch in get_dates_for_user(user.clone()).chunks(MAX_PARALLEL_READS) {
+            chats.extend(read_dates_concurrently(&self.days, user.clone(), batch)?);
+            tokio::task::yield_now().await;
+        }
+        Ok(chats)
+    }
 
-        // Sort the dates in ascending order
-        date_folders.sort();
+    async fn get_for_dates(&self, user: String, dates: Vec<NaiveDate>) -> Result<Vec<ChatModel>, RepoError> {
+        read_dates_concurrently(&self.days, user, &dates)
+    }
 
-        // Get from fs for each date and add to the list of chat models
-        let mut chats: Vec<ChatModel> = vec![];
-        for date in date_folders.iter() {
-            let path = get_path_for_date(user.clone(), *date).join("messages.json");
-            // Assuming get_from_fs returns a Vec<ChatModel>
-            let chat_models = get_from_fs(path);
-            for chat in chat_models {
-                chats.push(chat);
+    async fn get_history(&self, user: String, query: &HistoryQuery) -> Result<HistoryPage, RepoError> {
+        // only the day folders in range are read, and only until the page
+        // plus one more message is known so the next offset can be given
+        let dates = get_dates_for_user(user.clone())
+            .into_iter()
+            .filter(|date| query.includes(*date))
+            .collect::<Vec<NaiveDate>>();
+        let needed = query.offset + query.limit + 1;
+        let mut chats = vec![];
+        for batch in dates.chunks(MAX_PARALLEL_READS) {
+            chats.extend(
+                read_dates_concurrently(&self.days, user.clone(), batch)?
+                    .into_iter()
+                    .filter(|chat| chat.matches_tags(&query.tags)),
+            );
+            if chats.len() >= needed {
+                break;
             }
         }
-        Ok(chats)
+        Ok(paginate(chats, query))
     }
 
     async fn embeddings_search_for_user(
         &self,
         user: String,
         query_vector: Vec<f32>,
-    ) -> Vec<(f32, ChatModel)> {
-        let chats = self.get_all_for_user(user.clone());
-        let chats = match chats {
-            Ok(val) => val,
-            Err(_) => return vec![],
+        model: &str,
+        similarity: Similarity,
+    ) -> Result<Vec<(f32, ChatModel)>, RepoError> {
+        let chats = self.indexed_chats(&user)?;
+
+        // large histories only score the messages the vector index suggests,
+        // messages without a stored embedding are never in the index
+        let candidates = match self.vector_index {
+            VectorIndexKind::Ivf => {
+                if !self.vectors.read().unwrap().contains_key(&user) {
+                    self.update_vectors(&user, Some(&chats), |_| {});
+                }
+                self.vectors
+                    .read()
+                    .unwrap()
+                    .get(&user)
+                    .filter(|index| index.len() >= vector_index::MIN_INDEXED)
+                    .map(|index| index.candidates(&query_vector))
+            }
+            VectorIndexKind::Exact => None,
         };
+        let chats = match candidates {
+            Some(candidates) => chats
+                .into_iter()
+                .filter(|chat| chat.embedding.is_none() || candidates.contains(&chat.hash))
+                .collect(),
+            None => chats,
+        };
+
+        // vectors from another model or of another size cannot be compared
+        // with the query, they are skipped until the user is re-embedded
+        let (chats, mismatched): (Vec<ChatModel>, Vec<ChatModel>) =
+            chats.into_iter().partition(|chat| {
+                let same_model = chat
+                    .embedding_model
+                    .as_deref()
+                    .is_none_or(|embedding_model| embedding_model == model);
+                let same_size = chat
+                    .embedding
+                    .as_ref()
+                    .is_none_or(|embedding| embedding.len() == query_vector.len());
+                same_model && same_size
+            });
+        if !mismatched.is_empty() {
+            warn!(
+                "Skipped {} messages of {} embedded with another model than {}",
+                mismatched.len(),
+                user,
+                model
+            );
+        }
 
+        // messages still waiting for their embedding are found once it is
+        // stored, keyword searches find them meanwhile
         let mut ranked_chats: Vec<(f32, ChatModel)> = vec![];
-        let embedding_client = clients::embeddings::BarnstokkrClient::new();
+        for chat in chats
+            .into_iter()
+            .filter(|chat| !chat.forgotten && !chat.low_value)
+        {
+            let score = match &chat.embedding {
+                Some(embedding) => similarity.score(embedding, &query_vector),
+                None => continue,
+            };
+            ranked_chats.push((score, chat));
+        }
+        ranked_chats.sort_by(|a, b| b.0.total_cmp(&a.0));
 
-        for chat in chats {
-            let chat_embedding = match &chat.embedding {
-                Some(val) => val.clone(),
-                None => {
-                    let embedding = embedding_client.get_embeddings(chat.content.clone()).await;
-                    match embedding {
-                        Ok(val) => val,
-                        Err(_) => {
-                            error!("Failed to get embeddings");
-                            return vec![];
-                        }
-                    }
-                }
+        Ok(ranked_chats)
+    }
+
+    async fn get_all_for_user_on_day(&self, user: String, date: NaiveDate) -> Result<Vec<ChatModel>, RepoError> {
+        self.read_day(&user, date)
+    }
+
+    async fn get_users(&self) -> Result<Vec<String>, RepoError> {
+        let entries = match std::fs::read_dir(super::get_storage_root()) {
+            Ok(val) => val,
+            Err(_) => return Ok(vec![]),
+        };
+        let mut users = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().to_str().map(|name| name.to_string()))
+            // dot folders hold shared data such as the content store
+            .filter(|name| !name.starts_with('.'))
+            .collect::<Vec<String>>();
+        users.sort();
+        Ok(users)
+    }
+
+    async fn get_collections(&self, user: String) -> Result<Vec<String>, RepoError> {
+        let entries =
+            match std::fs::read_dir(super::get_user_root(&user).join(super::COLLECTIONS_FOLDER)) {
+                Ok(val) => val,
+                Err(_) => return Ok(vec![]),
             };
-            let similarity = cosine_similarity(&chat_embedding, &query_vector);
-            ranked_chats.push((similarity, chat));
+        let mut collections = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().to_str().map(|name| name.to_string()))
+            .collect::<Vec<String>>();
+        collections.sort();
+        Ok(collections)
+    }
+
+    async fn redact_chat(&mut self, user: String, id: String) -> Result<ChatModel, RepoError> {
+        let (date, mut chats, position) = find_chat(&self.days, &user, &id)?;
+        let chat = &mut chats[position];
+        chat.content = "".to_string();
+        chat.embedding = None;
+        chat.embedding_model = None;
+        chat.forgotten = true;
+        let redacted = chat.clone();
+        self.write_day(&user, date, &chats)?;
+
+        self.forget_cached(&user, &id);
+        self.memory.insert((id, user), redacted.clone());
+        Ok(redacted)
+    }
+
+    async fn delete_chat(&mut self, user: String, id: String) -> Result<(), RepoError> {
+        let (date, mut chats, position) = find_chat(&self.days, &user, &id)?;
+        chats.remove(position);
+        self.write_day(&user, date, &chats)?;
+
+        self.forget_cached(&user, &id);
+        self.memory.remove(&(id, user));
+        Ok(())
+    }
+
+    async fn get_days(&self, user: String) -> Result<Vec<NaiveDate>, RepoError> {
+        Ok(get_dates_for_user(user))
+    }
+
+    async fn delete_day(&mut self, user: String, date: NaiveDate) -> Result<usize, RepoError> {
+        let folder = get_path_for_date(user.clone(), date);
+        let chats = self.read_day(&user, date)?;
+        if chats.is_empty() {
+            return Ok(0);
         }
+        let _store = CONTENT_STORE.lock().unwrap();
+        let digests = referenced_digests(&folder.join("messages.json"))?;
+        for file in ["messages.json", COLD_FILE] {
+            match std::fs::remove_file(folder.join(file)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(RepoError::Io(e)),
+                _ => {}
+            }
+        }
+        release_references(&reference_changes(&digests, &[]));
+        // the folder stays when something else is kept in it
+        let _ = std::fs::remove_dir(&folder);
 
-        ranked_chats
+        // the indexes are rebuilt from what is left on the next search
+        self.days.forget(&user, date);
+        self.index.write().unwrap().remove(&user);
+        self.forget_vectors(&user);
+        for chat in chats.iter() {
+            self.memory.remove(&(chat.hash.clone(), user.clone()));
+        }
+        Ok(chats.len())
     }
 
-    fn get_all_for_user_on_day(&self, user: String, date: NaiveDate) -> Result<Vec<ChatModel>, ()> {
-        let path = get_path_for_date(user.clone(), date).join("messages.json");
-        let chats = get_from_fs(path);
-        Ok(chats)
+    async fn replace_embeddings(
+        &mut self,
+        user: String,
+        embeddings: std::collections::HashMap<String, Vec<f32>>,
+        model: &str,
+    ) -> Result<usize, RepoError> {
+        // every day file is staged next to the original first and only
+        // renamed into place once all of them were written
+        let mut staged = vec![];
+        let mut replaced = 0;
+        for date in get_dates_for_user(user.clone()) {
+            let path = get_path_for_date(user.clone(), date).join("messages.json");
+            let mut chats = self.read_day(&user, date)?;
+            for chat in chats.iter_mut() {
+                if let Some(embedding) = embeddings.get(&chat.hash) {
+                    chat.embedding = Some(embedding.clone());
+                    chat.embedding_model = Some(model.to_string());
+                    replaced += 1;
+                }
+            }
+            let staging = path.with_extension("json.reembed");
+            let _ = std::fs::remove_file(&staging);
+            if let Err(e) = write_to_fs(&staging, &chats) {
+                error!("Error staging embeddings for {}", date);
+                for (staging, _) in staged {
+                    let _ = std::fs::remove_file(staging);
+                }
+                return Err(e);
+            }
+            staged.push((staging, path));
+        }
+
+        for (staging, path) in staged {
+            std::fs::rename(&staging, &path).map_err(|e| {
+                error!("Error swapping embeddings into {:?}: {}", path, e);
+                RepoError::Io(e)
+            })?;
+            remove_cold(&path);
+        }
+        self.memory.retain(|(_, owner), _| *owner != user);
+        self.index.write().unwrap().remove(&user);
+        self.days.forget_user(&user);
+        // the lists were built from the old vectors and are rebuilt on demand
+        self.forget_vectors(&user);
+        Ok(replaced)
+    }
+
+    async fn set_embedding(
+        &mut self,
+        user: String,
+        id: String,
+        embedding: Vec<f32>,
+        model: &str,
+    ) -> Result<(), RepoError> {
+        let (date, mut chats, position) = find_chat(&self.days, &user, &id)?;
+        chats[position].embedding = Some(embedding.clone());
+        chats[position].embedding_model = Some(model.to_string());
+        let chat = chats[position].clone();
+        self.write_day(&user, date, &chats)?;
+
+        self.memory.insert((id.clone(), user.clone()), chat.clone());
+        if let Some(indexed) = self.index.write().unwrap().get_mut(&user) {
+            match indexed.iter_mut().find(|indexed| indexed.hash == id) {
+                Some(indexed) => *indexed = chat,
+                None => indexed.push(chat),
+            }
+        }
+        if self.vector_index == VectorIndexKind::Ivf {
+            self.update_vectors(&user, None, |index| index.insert(&id, &embedding));
+        }
+        Ok(())
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        let index = self.index.read().unwrap();
+        let (cached_days, day_cache_hits, day_cache_misses) = self.days.stats();
+        CacheStats {
+            indexed_users: index.len(),
+            indexed_messages: index.values().map(|chats| chats.len()).sum(),
+            cached_chats: self.memory.len(),
+            cached_days,
+            day_cache_hits,
+            day_cache_misses,
+        }
+    }
+
+    async fn warm_up(&self) -> Result<usize, RepoError> {
+        let mut index = std::collections::HashMap::new();
+        let mut indexed = 0;
+        for user in self.get_users().await? {
+            let chats = self.get_all_for_user(user.clone()).await?;
+            indexed += chats.len();
+            index.insert(user, chats);
+        }
+        *self.index.write().unwrap() = index;
+        Ok(indexed)
+    }
+
+    async fn compact_index(&self) -> Result<usize, RepoError> {
+        if self.vector_index == VectorIndexKind::Exact {
+            return Ok(0);
+        }
+        let users = self.get_users().await?;
+        for user in &users {
+            let chats = self.get_all_for_user(user.clone()).await?;
+            self.forget_vectors(user);
+            self.update_vectors(user, Some(&chats), |_| {});
+        }
+        Ok(users.len())
+    }
+
+    async fn compress_days(&mut self, before: NaiveDate) -> Result<usize, RepoError> {
+        let mut compressed = 0;
+        for user in self.get_users().await? {
+            compressed += self.compress_days_of_user(&user, before)?;
+        }
+        Ok(compressed)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_identical_content_is_stored_once() {
+        let mut repo = FsMessageRepo::new();
+        let user = format!("test_cas_{}", uuid::Uuid::new_v4());
+        let date = chrono::Utc::now().date_naive();
+        let body = format!("forwarded message {}", uuid::Uuid::new_v4());
+
+        repo.save_chat(date, user.clone(), ChatModel::test("first", &body)).await.unwrap();
+        repo.save_chat(date, user.clone(), ChatModel::test("second", &body)).await.unwrap();
+
+        let path = get_path_for_date(user.clone(), date).join("messages.json");
+        let raw = std::fs::read_to_string(path).unwrap();
+        assert!(!raw.contains(&body));
+
+        let digest = format!("{:x}", Sha256::digest(body.as_bytes()));
+        assert!(get_content_path(&digest).exists());
+        let references = std::fs::read_to_string(references_path(&get_content_path(&digest)));
+        assert_eq!(references.unwrap(), "2");
+
+        let chats = repo.get_all_for_user_on_day(user, date).await.unwrap();
+        assert_eq!(chats.len(), 2);
+        assert!(chats.iter().all(|chat| chat.content == body));
+    }
+
+    #[tokio::test]
+    async fn test_collections_keep_bodies_released_by_main_memory() {
+        let mut repo = FsMessageRepo::new();
+        let user = format!("test_cas_collection_{}", uuid::Uuid::new_v4());
+        let collection = super::super::collection_key(&user, "work");
+        let old = NaiveDate::from_ymd_opt(2000, 1, 2).unwrap();
+        let today = chrono::Utc::now().date_naive();
+        let body = format!("shared message {}", uuid::Uuid::new_v4());
+        repo.save_chat(old, user.clone(), ChatModel::test("old", &body)).await.unwrap();
+        repo.save_chat(today, user.clone(), ChatModel::test("main", &body)).await.unwrap();
+        repo.save_chat(today, collection.clone(), ChatModel::test("kept", &body)).await.unwrap();
+
+        repo.delete_chat(user.clone(), "main".to_string()).await.unwrap();
+        assert_eq!(repo.compress_days_of_user(&user, old.succ_opt().unwrap()).unwrap(), 1);
+        let digest = format!("{:x}", Sha256::digest(body.as_bytes()));
+        assert!(get_content_path(&digest).exists());
+        let chats = repo.get_all_for_user_on_day(collection, today).await.unwrap();
+        assert_eq!(chats[0].content, body);
+    }
+
+    #[tokio::test]
+    async fn test_cold_days_are_read_and_warmed_by_writes() {
+        let mut repo = FsMessageRepo::new();
+        let user = format!("test_cold_{}", uuid::Uuid::new_v4());
+        let date = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
+        let body = format!("old message {}", uuid::Uuid::new_v4());
+        repo.save_chat(date, user.clone(), ChatModel::test("old", &body)).await.unwrap();
+
+        let path = get_path_for_date(user.clone(), date).join("messages.json");
+        assert_eq!(repo.compress_days_of_user(&user, date.succ_opt().unwrap()).unwrap(), 1);
+        assert!(!path.exists());
+        assert!(cold_path(&path).exists());
+        // the body moved into the cold file
+        let digest = format!("{:x}", Sha256::digest(body.as_bytes()));
+        assert!(!get_content_path(&digest).exists());
+
+        let chats = repo.get_all_for_user_on_day(user.clone(), date).await.unwrap();
+        assert_eq!(chats[0].content, body);
+        let query = HistoryQuery {
+            limit: 10,
+            ..HistoryQuery::default()
+        };
+        let history = repo.get_history(user.clone(), &query).await.unwrap();
+        assert_eq!(history.messages.len(), 1);
+
+        repo.save_chat(date, user.clone(), ChatModel::test("new", "new message")).await.unwrap();
+        assert!(path.exists());
+        assert!(!cold_path(&path).exists());
+        let chats = repo.get_all_for_user_on_day(user.clone(), date).await.unwrap();
+        assert_eq!(chats.len(), 2);
+        assert_eq!(chats[0].content, body);
+
+        repo.compress_days_of_user(&user, date.succ_opt().unwrap()).unwrap();
+        assert_eq!(repo.delete_day(user.clone(), date).await.unwrap(), 2);
+        assert!(!cold_path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn test_index_follows_saves_after_first_search() {
+        let mut repo = FsMessageRepo::new();
+        let user = format!("test_index_{}", uuid::Uuid::new_v4());
+        let date = chrono::Utc::now().date_naive();
+        let indexed = |content: &str| ChatModel {
+            embedding: Some(vec![1.0, 0.0]),
+            ..ChatModel::test(&uuid::Uuid::new_v4().to_string(), content)
+        };
+
+        repo.save_chat(date, user.clone(), indexed("first")).await.unwrap();
+        let found = repo
+            .embeddings_search_for_user(user.clone(), vec![1.0, 0.0], "mock", Similarity::Cosine)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+
+        repo.save_chat(date, user.clone(), indexed("second")).await.unwrap();
+        let found = repo
+            .embeddings_search_for_user(user.clone(), vec![1.0, 0.0], "mock", Similarity::Cosine)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_search_covers_every_day() {
+        let mut repo = FsMessageRepo::new();
+        let user = format!("test_search_days_{}", uuid::Uuid::new_v4());
+        let embedded = |hash: &str| ChatModel {
+            embedding: Some(vec![1.0, 0.0]),
+            ..ChatModel::test(hash, hash)
+        };
+        let old = NaiveDate::from_ymd_opt(2023, 1, 5).unwrap();
+        let recent = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        let today = chrono::Utc::now().date_naive();
+        for (date, hash) in [(old, "old"), (recent, "recent"), (today, "today")] {
+            repo.save_chat(date, user.clone(), embedded(hash)).await.unwrap();
+        }
+
+        // also once the index is built from disk by a fresh repo
+        for repo in [repo, FsMessageRepo::new()] {
+            let found = repo
+                .embeddings_search_for_user(user.clone(), vec![1.0, 0.0], "mock", Similarity::Cosine)
+                .await
+                .unwrap();
+            let mut hashes = found.iter().map(|(_, c)| c.hash.as_str()).collect::<Vec<&str>>();
+            hashes.sort();
+            assert_eq!(hashes, vec!["old", "recent", "today"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_skips_messages_waiting_for_embeddings() {
+        let mut repo = FsMessageRepo::new();
+        let user = format!("test_search_pending_{}", uuid::Uuid::new_v4());
+        let date = chrono::Utc::now().date_naive();
+        let embedded = ChatModel {
+            embedding: Some(vec![1.0, 0.0]),
+            ..ChatModel::test("embedded", "Embedded")
+        };
+        repo.save_chat(date, user.clone(), embedded).await.unwrap();
+        repo.save_chat(date, user.clone(), ChatModel::test("pending", "Pending")).await.unwrap();
+
+        let found = repo
+            .embeddings_search_for_user(user, vec![1.0, 0.0], "mock", Similarity::Cosine)
+            .await
+            .unwrap();
+        let hashes = found.iter().map(|(_, c)| c.hash.as_str()).collect::<Vec<&str>>();
+        assert_eq!(hashes, vec!["embedded"]);
+    }
+
+    #[tokio::test]
+    async fn test_day_files_are_cached_until_they_change() {
+        let mut repo = FsMessageRepo::with_day_cache(2);
+        let user = format!("test_day_cache_{}", uuid::Uuid::new_v4());
+        let days = [1, 2, 3].map(|day| NaiveDate::from_ymd_opt(2024, 3, day).unwrap());
+        for date in days {
+            repo.save_chat(date, user.clone(), ChatModel::test(&date.to_string(), "Hello"))
+                .await
+                .unwrap();
+        }
+        let stats = |repo: &FsMessageRepo| {
+            let stats = repo.cache_stats();
+            (stats.cached_days, stats.day_cache_hits)
+        };
+
+        repo.get_all_for_user_on_day(user.clone(), days[0]).await.unwrap();
+        let read = repo.get_all_for_user_on_day(user.clone(), days[0]).await.unwrap();
+        assert_eq!(read.len(), 1);
+        let (_, hits) = stats(&repo);
+        assert!(hits >= 1);
+
+        // written by someone else, the file is read again
+        let mut other = FsMessageRepo::new();
+        other
+            .save_chat(days[0], user.clone(), ChatModel::test("other", "Hello"))
+            .await
+            .unwrap();
+        let read = repo.get_all_for_user_on_day(user.clone(), days[0]).await.unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(stats(&repo).1, hits);
+
+        // only the most recently read days are kept
+        assert_eq!(repo.get_all_for_user(user.clone()).await.unwrap().len(), 4);
+        assert_eq!(stats(&repo).0, 2);
+    }
+
+    #[tokio::test]
+    async fn test_history_pages_through_date_range() {
+        let mut repo = FsMessageRepo::new();
+        let user = format!("test_history_{}", uuid::Uuid::new_v4());
+        let day = |d| NaiveDate::from_ymd_opt(2024, 3, d).unwrap();
+        for (d, hash) in [(1, "a"), (2, "b"), (2, "c"), (3, "d")] {
+            repo.save_chat(day(d), user.clone(), ChatModel::test(hash, hash)).await.unwrap();
+        }
+
+        let query = HistoryQuery {
+            limit: 2,
+            offset: 0,
+            from_date: Some(day(2)),
+            to_date: None,
+            tags: vec![],
+        };
+        let page = repo.get_history(user.clone(), &query).await.unwrap();
+        let hashes = page.messages.iter().map(|c| c.hash.as_str()).collect::<Vec<&str>>();
+        assert_eq!(hashes, vec!["b", "c"]);
+        assert_eq!(page.next_offset, Some(2));
+
+        let query = HistoryQuery { offset: 2, ..query };
+        let page = repo.get_history(user, &query).await.unwrap();
+        assert_eq!(page.messages.len(), 1);
+        assert_eq!(page.next_offset, None);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_day_file_is_an_error() {
+        let repo = FsMessageRepo::new();
+        let user = format!("test_corrupt_{}", uuid::Uuid::new_v4());
+        let date = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        let path = get_path_for_date(user.clone(), date).join("messages.json");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "[{\"role\":").unwrap();
+        std::fs::create_dir_all(get_root_path(user.clone()).join("not-a-date")).unwrap();
+
+        let result = repo.get_all_for_user(user).await;
+        assert!(matches!(result, Err(RepoError::Corrupt(_))));
+    }
+
+    #[tokio::test]
+    async fn test_timestamps_are_kept_and_backfilled() {
+        let mut repo = FsMessageRepo::new();
+        let user = format!("test_timestamps_{}", uuid::Uuid::new_v4());
+        let date = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        let path = get_path_for_date(user.clone(), date).join("messages.json");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(
+            &path,
+            r#"[{"role":"user","content":"Before timestamps","hash":"old","embedding":null}]"#,
+        )
+        .unwrap();
+        let late = ChatModel {
+            occurred_at: Some(1_709_200_000),
+            ..ChatModel::test("late", "Sent from the plane")
+        };
+        repo.save_chat(date, user.clone(), late).await.unwrap();
+
+        let chats = repo.get_all_for_user_on_day(user, date).await.unwrap();
+        assert_eq!(chats[0].timestamp, 1_709_251_200);
+        assert_eq!(chats[0].said_at(), 1_709_251_200);
+        assert_eq!(chats[1].occurred_at, Some(1_709_200_000));
+        assert_eq!(chats[1].said_at(), 1_709_200_000);
+    }
+
+    #[test]
+    fn test_recover_finishes_interrupted_writes() {
+        let root = std::env::temp_dir().join(format!("muninn_recover_{}", uuid::Uuid::new_v4()));
+        let day = |date: &str| {
+            let dir = root.join("anna").join(date);
+            std::fs::create_dir_all(&dir).unwrap();
+            dir.join("messages.json")
+        };
+        let one = r#"[{"role":"user","content":"Parked on level 3","hash":"a","embedding":null,"timestamp":1}"#;
+        let two = r#",{"role":"user","content":"Parked on level 4","hash":"b","embedding":null,"timestamp":2}]"#;
+
+        // renamed into place when the crash came just before the rename
+        let finished = day("2024-03-01");
+        std::fs::write(
+            super::super::staging_path(&finished),
+            format!("{}{}", one, two),
+        )
+        .unwrap();
+        // thrown away when cut short, the old file stays
+        let kept = day("2024-03-02");
+        std::fs::write(&kept, "[]").unwrap();
+        std::fs::write(super::super::staging_path(&kept), &one[..20]).unwrap();
+        // written in place before writes were atomic and cut short
+        let damaged = day("2024-03-03");
+        std::fs::write(&damaged, format!("{}{}", one, &two[..30])).unwrap();
+
+        let report = recover(&root);
+        assert_eq!(
+            report,
+            RecoveryReport {
+                completed: 1,
+                discarded: 1,
+                salvaged: 1,
+            }
+        );
+        assert_eq!(get_from_fs(finished).unwrap().len(), 2);
+        assert!(get_from_fs(kept.clone()).unwrap().is_empty());
+        assert!(!super::super::staging_path(&kept).exists());
+        let salvaged = get_from_fs(damaged.clone()).unwrap();
+        assert_eq!(salvaged.len(), 1);
+        assert_eq!(salvaged[0].hash, "a");
+        assert!(damaged.with_file_name("messages.json.corrupt").exists());
+        assert_eq!(recover(&root), RecoveryReport::default());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_history_filters_by_tags_and_metadata() {
+        let mut repo = FsMessageRepo::new();
+        let user = format!("test_tags_{}", uuid::Uuid::new_v4());
+        let date = chrono::Utc::now().date_naive();
+        let tagged = |hash: &str, source: &str, tags: &[&str]| ChatModel {
+            metadata: std::collections::HashMap::from([(
+                "source".to_string(),
+                source.to_string(),
+            )]),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..ChatModel::test(hash, hash)
+        };
+        repo.save_chat(date, user.clone(), tagged("run", "telegram", &["health"])).await.unwrap();
+        repo.save_chat(date, user.clone(), tagged("doctor", "web", &["health"])).await.unwrap();
+        repo.save_chat(date, user.clone(), tagged("bills", "telegram", &[])).await.unwrap();
+
+        let query = HistoryQuery {
+            limit: 10,
+            tags: vec!["source=telegram".to_string(), "health".to_string()],
+            ..HistoryQuery::default()
+        };
+        let page = repo.get_history(user.clone(), &query).await.unwrap();
+        let hashes = page.messages.iter().map(|c| c.hash.as_str()).collect::<Vec<&str>>();
+        assert_eq!(hashes, vec!["run"]);
+
+        let stored = repo.get_chat(user, "doctor".to_string()).await.unwrap();
+        assert_eq!(stored.metadata["source"], "web");
+        assert_eq!(stored.tags, vec!["health"]);
+    }
+
+    #[tokio::test]
+    async fn test_search_skips_vectors_from_other_models() {
+        let mut repo = FsMessageRepo::new();
+        let user = format!("test_models_{}", uuid::Uuid::new_v4());
+        let date = chrono::Utc::now().date_naive();
+        let embedded = |hash: &str, embedding: Vec<f32>, model: Option<&str>| ChatModel {
+            embedding: Some(embedding),
+            embedding_model: model.map(|model| model.to_string()),
+            ..ChatModel::test(hash, hash)
+        };
+
+        repo.save_chat(date, user.clone(), embedded("same", vec![1.0, 0.0], Some("mock"))).await
+            .unwrap();
+        repo.save_chat(date, user.clone(), embedded("legacy", vec![1.0, 0.0], None)).await
+            .unwrap();
+        repo.save_chat(date, user.clone(), embedded("other", vec![1.0, 0.0], Some("ada"))).await
+            .unwrap();
+        repo.save_chat(date, user.clone(), embedded("wider", vec![1.0, 0.0, 0.0], None)).await
+            .unwrap();
+
+        let found = repo
+            .embeddings_search_for_user(user, vec![1.0, 0.0], "mock", Similarity::Cosine)
+            .await
+            .unwrap();
+        let mut hashes = found.iter().map(|(_, c)| c.hash.as_str()).collect::<Vec<&str>>();
+        hashes.sort();
+        assert_eq!(hashes, vec!["legacy", "same"]);
+    }
+
+    #[tokio::test]
+    async fn test_redact_keeps_hash_and_delete_removes_message() {
+        let mut repo = FsMessageRepo::new();
+        let user = format!("test_delete_{}", uuid::Uuid::new_v4());
+        let date = chrono::Utc::now().date_naive();
+        let body = format!("private message {}", uuid::Uuid::new_v4());
+        let digest = format!("{:x}", Sha256::digest(body.as_bytes()));
+
+        repo.save_chat(date, user.clone(), ChatModel::test("kept", &body)).await.unwrap();
+        repo.save_chat(date, user.clone(), ChatModel::test("copy", &body)).await.unwrap();
+
+        let redacted = repo.redact_chat(user.clone(), "kept".to_string()).await.unwrap();
+        assert!(redacted.forgotten);
+        assert_eq!(repo.get_chat(user.clone(), "kept".to_string()).await.unwrap().content, "");
+        // the copy still refers to the body
+        assert!(get_content_path(&digest).exists());
+
+        repo.delete_chat(user.clone(), "copy".to_string()).await.unwrap();
+        assert!(matches!(
+            repo.get_chat(user.clone(), "copy".to_string()).await,
+            Err(RepoError::NotFound)
+        ));
+        assert!(!get_content_path(&digest).exists());
+        assert_eq!(repo.get_all_for_user(user.clone()).await.unwrap().len(), 1);
+        assert!(matches!(
+            repo.delete_chat(user, "copy".to_string()).await,
+            Err(RepoError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn test_bm25_prefers_exact_terms() {
+        let chats = vec![
+            ChatModel::test("code", "the build failed with E-1042 again"),
+            ChatModel::test("other", "the build passed this time"),
+            ChatModel::test("noise", "lunch plans for friday"),
+        ];
+        let ranked = bm25_search("E-1042 build", chats);
+        let hashes = ranked.iter().map(|(_, c)| c.hash.as_str()).collect::<Vec<&str>>();
+        assert_eq!(hashes, vec!["code", "other"]);
+        assert!(bm25_search("", vec![ChatModel::test("a", "anything")]).is_empty());
+    }
+}
@@ -0,0 +1,300 @@
+use std::path::{Path, PathBuf};
+
+use tracing::{error, info};
+
+// Holds the number of the last migration applied to a storage root
+const VERSION_FILE: &str = ".schema_version";
+
+/// One change to the on-disk layout. Migrations run in version order and
+/// each one only ever runs once per storage root.
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub run: fn(&Path) -> Result<(), ()>,
+}
+
+/// Every known migration, oldest first
+pub fn migrations() -> Vec<Migration> {
+    vec![
+        Migration {
+            version: 1,
+            name: "baseline",
+            // the layout that predates versioning, only the version is recorded
+            run: |_| Ok(()),
+        },
+        Migration {
+            version: 2,
+            name: "rename_invalid_users",
+            run: rename_invalid_users,
+        },
+        Migration {
+            version: 3,
+            name: "count_content_references",
+            run: super::messages::count_content_references,
+        },
+    ]
+}
+
+// Users named before usernames were checked, with spaces or letters outside
+// ASCII, can no longer be asked for, so their folders are renamed to the
+// closest valid name with `-2`, `-3` and so on added when that is taken
+fn rename_invalid_users(root: &Path) -> Result<(), ()> {
+    let entries = std::fs::read_dir(root).map_err(|e| {
+        error!("Error listing users: {}", e);
+    })?;
+    let mut invalid = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| !name.starts_with('.') && !crate::username::is_valid(name))
+        .collect::<Vec<String>>();
+    invalid.sort();
+    for name in invalid {
+        let base = crate::username::replacement(&name);
+        let mut renamed = base.clone();
+        let mut suffix = 2;
+        while root.join(&renamed).exists() {
+            let suffix_text = format!("-{}", suffix);
+            let kept = base
+                .len()
+                .min(crate::username::MAX_LENGTH - suffix_text.len());
+            renamed = format!("{}{}", &base[..kept], suffix_text);
+            suffix += 1;
+        }
+        std::fs::rename(root.join(&name), root.join(&renamed)).map_err(|e| {
+            error!("Error renaming user {:?}: {}", name, e);
+        })?;
+        info!("Renamed user {:?} to {:?}", name, renamed);
+    }
+    Ok(())
+}
+
+pub struct MigrationOptions {
+    /// Report pending migrations without touching any files
+    pub dry_run: bool,
+    /// Copy the storage root aside before the first pending migration runs
+    pub backup: bool,
+}
+
+impl MigrationOptions {
+    pub fn from_env() -> MigrationOptions {
+        let flag = |name: &str, default: bool| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse::<bool>().ok())
+                .unwrap_or(default)
+        };
+        MigrationOptions {
+            dry_run: flag("MIGRATIONS_DRY_RUN", false),
+            backup: flag("MIGRATIONS_BACKUP", true),
+        }
+    }
+}
+
+pub fn read_version(root: &Path) -> u32 {
+    std::fs::read_to_string(root.join(VERSION_FILE))
+        .ok()
+        .and_then(|version| version.trim().parse::<u32>().ok())
+        .unwrap_or(0)
+}
+
+fn write_version(root: &Path, version: u32) -> Result<(), ()> {
+    std::fs::create_dir_all(root).map_err(|e| {
+        error!("Error creating directory: {}", e);
+    })?;
+    std::fs::write(root.join(VERSION_FILE), version.to_string()).map_err(|e| {
+        error!("Error writing schema version: {}", e);
+    })
+}
+
+/// Brings the storage below `root` up to the latest version, returning the
+/// names of the migrations that ran, or would run on a dry run
+pub fn migrate(
+    root: &Path,
+    migrations: &[Migration],
+    options: &MigrationOptions,
+) -> Result<Vec<&'static str>, ()> {
+    let latest = migrations.iter().map(|m| m.version).max().unwrap_or(0);
+
+    // a fresh install already has the latest layout
+    if !root.exists() {
+        if !options.dry_run {
+            write_version(root, latest)?;
+        }
+        return Ok(vec![]);
+    }
+
+    let current = read_version(root);
+    if current > latest {
+        error!(
+            "Storage is at schema version {} but this release only knows {}",
+            current, latest
+        );
+        return Err(());
+    }
+
+    let mut pending = migrations
+        .iter()
+        .filter(|migration| migration.version > current)
+        .collect::<Vec<&Migration>>();
+    pending.sort_by_key(|migration| migration.version);
+    let names = pending.iter().map(|migration| migration.name).collect();
+    if pending.is_empty() || options.dry_run {
+        return Ok(names);
+    }
+
+    if options.backup {
+        let backup = backup(root, current)?;
+        info!("Backed up storage to {}", backup.display());
+    }
+    for migration in pending {
+        info!(
+            "Running storage migration {} ({})",
+            migration.version, migration.name
+        );
+        (migration.run)(root)?;
+        write_version(root, migration.version)?;
+    }
+    Ok(names)
+}
+
+// Copies the storage root next to itself, named after the version it held
+fn backup(root: &Path, version: u32) -> Result<PathBuf, ()> {
+    let name = root
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let destination = root.with_file_name(format!(
+        "{}.backup-v{}-{}",
+        name,
+        version,
+        chrono::Utc::now().timestamp()
+    ));
+    copy_dir(root, &destination).map_err(|e| {
+        error!("Error backing up storage: {}", e);
+    })?;
+    Ok(destination)
+}
+
+fn copy_dir(source: &Path, destination: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(destination)?;
+    for entry in std::fs::read_dir(source)? {
+        let entry = entry?;
+        let target = destination.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_migrations() -> Vec<Migration> {
+        vec![
+            Migration {
+                version: 2,
+                name: "add_marker",
+                run: |root| std::fs::write(root.join("marker"), "2").map_err(|_| ()),
+            },
+            Migration {
+                version: 1,
+                name: "baseline",
+                run: |_| Ok(()),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_migrate_runs_pending_in_order_once() {
+        let root = std::env::temp_dir().join(format!("muninn_migrate_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        let options = MigrationOptions {
+            dry_run: true,
+            backup: false,
+        };
+
+        let planned = migrate(&root, &test_migrations(), &options).unwrap();
+        assert_eq!(planned, vec!["baseline", "add_marker"]);
+        assert_eq!(read_version(&root), 0);
+
+        let options = MigrationOptions {
+            dry_run: false,
+            backup: false,
+        };
+        migrate(&root, &test_migrations(), &options).unwrap();
+        assert_eq!(read_version(&root), 2);
+        assert!(root.join("marker").exists());
+
+        let applied = migrate(&root, &test_migrations(), &options).unwrap();
+        assert!(applied.is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_user_folders_are_renamed() {
+        let root = std::env::temp_dir().join(format!("muninn_users_{}", uuid::Uuid::new_v4()));
+        for name in ["alice", "john smith", "john_smith", "josé", ".content"] {
+            std::fs::create_dir_all(root.join(name)).unwrap();
+        }
+        std::fs::write(root.join("john smith").join("marker"), "john").unwrap();
+
+        rename_invalid_users(&root).unwrap();
+        let mut names = std::fs::read_dir(&root)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<String>>();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![".content", "alice", "john_smith", "john_smith-2", "jos_"]
+        );
+        assert!(root.join("john_smith-2").join("marker").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_references_to_content_are_counted() {
+        let root = std::env::temp_dir().join(format!("muninn_references_{}", uuid::Uuid::new_v4()));
+        let (kept, missing) = ("ab".repeat(32), "cd".repeat(32));
+        let day = |key: &str, digests: &[&str]| {
+            let dir = root.join(key).join("2024-03-01");
+            std::fs::create_dir_all(&dir).unwrap();
+            let records = digests
+                .iter()
+                .map(|digest| {
+                    serde_json::json!({
+                        "role": "user",
+                        "content": "",
+                        "hash": uuid::Uuid::new_v4().to_string(),
+                        "embedding": null,
+                        "content_digest": digest,
+                    })
+                    .to_string()
+                })
+                .collect::<Vec<String>>();
+            std::fs::write(
+                dir.join("messages.json"),
+                format!("[{}]", records.join(",")),
+            )
+            .unwrap();
+        };
+        day("alice", &[&kept, &kept]);
+        day("alice/collections/work", &[&kept, &missing]);
+        std::fs::create_dir_all(root.join(".content").join("ab")).unwrap();
+        std::fs::write(root.join(".content").join("ab").join(&kept), "body").unwrap();
+
+        super::super::messages::count_content_references(&root).unwrap();
+        let references = root
+            .join(".content")
+            .join("ab")
+            .join(format!("{}.refs", kept));
+        assert_eq!(std::fs::read_to_string(references).unwrap(), "3");
+        assert!(!root.join(".content").join("cd").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,2 +1,144 @@
 pub mod messages;
 pub mod attributes;
+pub mod threads;
+pub mod suppressions;
+pub mod presets;
+pub mod contacts;
+pub mod briefings;
+
+/// Failure of a storage operation
+#[derive(Debug)]
+pub enum RepoError {
+    NotFound,
+    Io(std::io::Error),
+    /// A stored file could not be parsed
+    Corrupt(String),
+    #[cfg(feature = "postgres")]
+    Database(sqlx::Error),
+}
+
+impl std::fmt::Display for RepoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RepoError::NotFound => write!(f, "not found"),
+            RepoError::Io(e) => write!(f, "storage error: {}", e),
+            RepoError::Corrupt(detail) => write!(f, "corrupt data: {}", detail),
+            #[cfg(feature = "postgres")]
+            RepoError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RepoError {}
+
+impl From<std::io::Error> for RepoError {
+    fn from(e: std::io::Error) -> Self {
+        RepoError::Io(e)
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl From<sqlx::Error> for RepoError {
+    fn from(e: sqlx::Error) -> Self {
+        RepoError::Database(e)
+    }
+}
+
+// Services report failures as `()`, the cause is logged on the way so `?`
+// keeps working on repo results
+impl From<RepoError> for () {
+    fn from(e: RepoError) {
+        tracing::error!("Repo error: {}", e);
+    }
+}
+
+/// Directory under which every user's data lives
+pub fn get_storage_root() -> std::path::PathBuf {
+    let dir = match &crate::config::get().storage.path {
+        Some(path) => path.clone(),
+        None => dirs::data_local_dir().unwrap(),
+    };
+
+    // namespaced deployments keep their data in a dot folder of their own,
+    // which un-namespaced instances skip when listing users
+    match crate::namespace::namespace() {
+        Some(namespace) => dir.join("muninn").join(".namespaces").join(namespace),
+        None => dir.join("muninn"),
+    }
+}
+
+pub fn get_user_root(user: &str) -> std::path::PathBuf {
+    // usernames are checked where they come in, one that slipped through
+    // gets a folder of its own rather than a path outside its user's
+    if !crate::username::is_valid_key(user) {
+        use sha2::Digest;
+        tracing::error!("Invalid user {:?}, storing it apart", user);
+        let digest = sha2::Sha256::digest(user.as_bytes());
+        return get_storage_root()
+            .join(".invalid")
+            .join(format!("{:x}", digest));
+    }
+    get_storage_root().join(user)
+}
+
+/// Folder below a user's root holding their named collections
+pub const COLLECTIONS_FOLDER: &str = "collections";
+
+/// Key a named collection is stored under in place of the username, which
+/// puts its files in a folder of the user's own
+pub fn collection_key(user: &str, collection: &str) -> String {
+    format!("{}/{}/{}", user, COLLECTIONS_FOLDER, collection)
+}
+
+/// Where `path` is written before it is renamed into place
+pub fn staging_path(path: &std::path::Path) -> std::path::PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.tmp", name))
+}
+
+/// Writes `contents` next to `path` and renames it into place, so a crash
+/// leaves either the old file or the new one but never half of either
+pub fn write_atomic(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let staging = staging_path(path);
+    let mut file = std::fs::File::create(&staging)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&staging, path)
+}
+
+/// Total size in bytes of every file below `path`
+pub fn get_directory_size(path: &std::path::Path) -> u64 {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => get_directory_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+pub mod saved_searches;
+pub mod feedback;
+pub mod migrations;
+pub mod vector_index;
+pub mod tokens;
+pub mod summaries;
+pub mod usage;
+pub mod external_vectors;
+pub mod webhooks;
+pub mod feeds;
+pub mod reminders;
+pub mod retention;
+pub mod encryption;
+pub mod storage_usage;
+pub mod object_storage;
+pub mod replication;
+pub mod day_cache;
+#[cfg(feature = "postgres")]
+pub mod postgres;
@@ -0,0 +1,633 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use super::{
+    attributes::{AttributeEvent, AttributeModel, AttributeRepo, AttributeSource},
+    get_user_root,
+    messages::{self, CacheStats, ChatModel, HistoryPage, HistoryQuery, MessageRepo},
+    RepoError,
+};
+use crate::clients::{embeddings::Similarity, objectstore::ObjectStore};
+
+// Size and modification time, which tell whether a file changed since it
+// was mirrored
+type FileState = (u64, Option<SystemTime>);
+
+fn file_state(path: &Path) -> Option<FileState> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()))
+}
+
+// Every file below `path`, or `path` itself when it is a file. Files being
+// staged are left out, they are mirrored once renamed into place.
+fn files_below(path: &Path, files: &mut Vec<PathBuf>) {
+    if path.is_file() {
+        if !path.to_string_lossy().ends_with(".tmp") {
+            files.push(path.to_path_buf());
+        }
+        return;
+    }
+    for entry in std::fs::read_dir(path).into_iter().flatten().flatten() {
+        files_below(&entry.path(), files);
+    }
+}
+
+/// Mirrors the files below a storage root to an object store, one object
+/// per file. The object store is the copy that outlives the host, the local
+/// files are a cache of it that answers every read.
+pub struct ObjectMirror {
+    store: Arc<dyn ObjectStore>,
+    root: PathBuf,
+    /// Prefix of the keys, without the trailing `/`
+    prefix: String,
+    mirrored: Mutex<HashMap<PathBuf, FileState>>,
+}
+
+impl ObjectMirror {
+    pub fn new(store: Arc<dyn ObjectStore>, root: PathBuf, prefix: &str) -> Self {
+        ObjectMirror {
+            store,
+            root,
+            prefix: prefix.trim_matches('/').to_string(),
+            mirrored: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn key(&self, path: &Path) -> Option<String> {
+        let relative = path.strip_prefix(&self.root).ok()?;
+        let parts = relative
+            .components()
+            .map(|part| part.as_os_str().to_str())
+            .collect::<Option<Vec<&str>>>()?;
+        Some(format!("{}/{}", self.prefix, parts.join("/")))
+    }
+
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        let relative = key.strip_prefix(&self.prefix)?.strip_prefix('/')?;
+        // keys that would climb out of the root are not ours
+        if relative
+            .split('/')
+            .any(|part| part.is_empty() || part == "..")
+        {
+            return None;
+        }
+        Some(self.root.join(relative))
+    }
+
+    /// Downloads every object missing from the local files, then uploads
+    /// the local files missing from the object store. Local files that are
+    /// already there are taken to match their objects.
+    pub async fn sync(&self) -> Result<usize, ()> {
+        let keys = self.store.list(&format!("{}/", self.prefix)).await?;
+        let mut restored = 0;
+        for key in keys {
+            let path = match self.path(&key) {
+                Some(path) => path,
+                None => continue,
+            };
+            if !path.exists() {
+                let contents = match self.store.get(&key).await? {
+                    Some(contents) => contents,
+                    None => continue,
+                };
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent).map_err(|e| {
+                        error!("Error creating {}: {}", parent.display(), e);
+                    })?;
+                }
+                super::write_atomic(&path, &contents).map_err(|e| {
+                    error!("Error restoring {}: {}", path.display(), e);
+                })?;
+                restored += 1;
+            }
+            if let Some(state) = file_state(&path) {
+                self.mirrored.lock().await.insert(path, state);
+            }
+        }
+        self.push(&self.root).await;
+        Ok(restored)
+    }
+
+    /// Uploads the files below `path` that changed since they were last
+    /// mirrored and removes the objects of mirrored files that are gone. A
+    /// file that fails to upload is logged and tried again on the next push
+    /// that covers it.
+    pub async fn push(&self, path: &Path) {
+        let mut files = vec![];
+        files_below(path, &mut files);
+        let mut mirrored = self.mirrored.lock().await;
+        for file in files {
+            let (state, key) = match (file_state(&file), self.key(&file)) {
+                (Some(state), Some(key)) => (state, key),
+                _ => continue,
+            };
+            if mirrored.get(&file) == Some(&state) {
+                continue;
+            }
+            let contents = match std::fs::read(&file) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    error!("Error reading {} to mirror it: {}", file.display(), e);
+                    continue;
+                }
+            };
+            if self.store.put(&key, contents).await.is_ok() {
+                mirrored.insert(file, state);
+            }
+        }
+        let gone = mirrored
+            .keys()
+            .filter(|file| file.starts_with(path) && !file.exists())
+            .cloned()
+            .collect::<Vec<PathBuf>>();
+        for file in gone {
+            let key = match self.key(&file) {
+                Some(key) => key,
+                None => continue,
+            };
+            if self.store.delete(&key).await.is_ok() {
+                mirrored.remove(&file);
+            }
+        }
+    }
+
+    async fn push_contents(&self, chats: &[ChatModel]) {
+        for path in chats
+            .iter()
+            .filter_map(|chat| messages::content_path(&chat.content))
+        {
+            self.push(&path).await;
+        }
+    }
+}
+
+/// Keeps messages in `messages` on the local filesystem and mirrors the day
+/// files and message bodies they are written to into an object store, for
+/// hosts whose disk does not outlive them
+pub struct ObjectStorageMessageRepo {
+    messages: Arc<Mutex<dyn MessageRepo>>,
+    mirror: Arc<ObjectMirror>,
+}
+
+impl ObjectStorageMessageRepo {
+    pub fn new(messages: Arc<Mutex<dyn MessageRepo>>, mirror: Arc<ObjectMirror>) -> Self {
+        ObjectStorageMessageRepo { messages, mirror }
+    }
+
+    // The message before it is changed, so its body can be mirrored once
+    // the content store let go of it. Every day is looked through, `get_chat`
+    // only knows the messages of today and those read since startup.
+    async fn before_change(&self, user: &str, id: &str) -> Vec<ChatModel> {
+        let messages = self.messages.lock().await;
+        let days = messages.get_days(user.to_string()).await.unwrap_or_default();
+        for date in days.into_iter().rev() {
+            let chats = messages
+                .get_all_for_user_on_day(user.to_string(), date)
+                .await
+                .unwrap_or_default();
+            if let Some(chat) = chats.into_iter().find(|chat| chat.hash == id) {
+                return vec![chat];
+            }
+        }
+        vec![]
+    }
+}
+
+#[async_trait]
+impl MessageRepo for ObjectStorageMessageRepo {
+    async fn save_chat(
+        &mut self,
+        date: NaiveDate,
+        user: String,
+        chat: ChatModel,
+    ) -> Result<ChatModel, RepoError> {
+        let chat = self
+            .messages
+            .lock()
+            .await
+            .save_chat(date, user.clone(), chat)
+            .await?;
+        self.mirror
+            .push(&messages::get_path_for_date(user, date))
+            .await;
+        self.mirror.push_contents(std::slice::from_ref(&chat)).await;
+        Ok(chat)
+    }
+
+    async fn save_chats(
+        &mut self,
+        date: NaiveDate,
+        user: String,
+        chats: Vec<ChatModel>,
+    ) -> Result<Vec<ChatModel>, RepoError> {
+        let chats = self
+            .messages
+            .lock()
+            .await
+            .save_chats(date, user.clone(), chats)
+            .await?;
+        self.mirror
+            .push(&messages::get_path_for_date(user, date))
+            .await;
+        self.mirror.push_contents(&chats).await;
+        Ok(chats)
+    }
+
+    async fn get_chat(&mut self, user: String, id: String) -> Result<ChatModel, RepoError> {
+        self.messages.lock().await.get_chat(user, id).await
+    }
+
+    async fn embeddings_search_for_user(
+        &self,
+        user: String,
+        query_vector: Vec<f32>,
+        model: &str,
+        similarity: Similarity,
+    ) -> Result<Vec<(f32, ChatModel)>, RepoError> {
+        self.messages
+            .lock()
+            .await
+            .embeddings_search_for_user(user, query_vector, model, similarity)
+            .await
+    }
+
+    async fn embeddings_search_between(
+        &self,
+        user: String,
+        query_vector: Vec<f32>,
+        model: &str,
+        similarity: Similarity,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+    ) -> Result<Vec<(f32, ChatModel)>, RepoError> {
+        self.messages
+            .lock()
+            .await
+            .embeddings_search_between(user, query_vector, model, similarity, from, to)
+            .await
+    }
+
+    async fn keyword_search_for_user(
+        &self,
+        user: String,
+        query: &str,
+    ) -> Result<Vec<(f32, ChatModel)>, RepoError> {
+        self.messages
+            .lock()
+            .await
+            .keyword_search_for_user(user, query)
+            .await
+    }
+
+    async fn get_all_for_user(&self, user: String) -> Result<Vec<ChatModel>, RepoError> {
+        self.messages.lock().await.get_all_for_user(user).await
+    }
+
+    async fn get_all_for_user_on_day(
+        &self,
+        user: String,
+        date: NaiveDate,
+    ) -> Result<Vec<ChatModel>, RepoError> {
+        self.messages
+            .lock()
+            .await
+            .get_all_for_user_on_day(user, date)
+            .await
+    }
+
+    async fn get_for_dates(
+        &self,
+        user: String,
+        dates: Vec<NaiveDate>,
+    ) -> Result<Vec<ChatModel>, RepoError> {
+        self.messages.lock().await.get_for_dates(user, dates).await
+    }
+
+    async fn get_history(
+        &self,
+        user: String,
+        query: &HistoryQuery,
+    ) -> Result<HistoryPage, RepoError> {
+        self.messages.lock().await.get_history(user, query).await
+    }
+
+    async fn get_users(&self) -> Result<Vec<String>, RepoError> {
+        self.messages.lock().await.get_users().await
+    }
+
+    async fn get_collections(&self, user: String) -> Result<Vec<String>, RepoError> {
+        self.messages.lock().await.get_collections(user).await
+    }
+
+    async fn redact_chat(&mut self, user: String, id: String) -> Result<ChatModel, RepoError> {
+        let before = self.before_change(&user, &id).await;
+        let redacted = self
+            .messages
+            .lock()
+            .await
+            .redact_chat(user.clone(), id)
+            .await?;
+        self.mirror.push(&get_user_root(&user)).await;
+        self.mirror.push_contents(&before).await;
+        Ok(redacted)
+    }
+
+    async fn delete_chat(&mut self, user: String, id: String) -> Result<(), RepoError> {
+        let before = self.before_change(&user, &id).await;
+        self.messages
+            .lock()
+            .await
+            .delete_chat(user.clone(), id)
+            .await?;
+        self.mirror.push(&get_user_root(&user)).await;
+        self.mirror.push_contents(&before).await;
+        Ok(())
+    }
+
+    async fn get_days(&self, user: String) -> Result<Vec<NaiveDate>, RepoError> {
+        self.messages.lock().await.get_days(user).await
+    }
+
+    async fn delete_day(&mut self, user: String, date: NaiveDate) -> Result<usize, RepoError> {
+        let mut messages = self.messages.lock().await;
+        let before = messages.get_all_for_user_on_day(user.clone(), date).await?;
+        let deleted = messages.delete_day(user.clone(), date).await?;
+        drop(messages);
+        self.mirror
+            .push(&messages::get_path_for_date(user, date))
+            .await;
+        self.mirror.push_contents(&before).await;
+        Ok(deleted)
+    }
+
+    async fn replace_embeddings(
+        &mut self,
+        user: String,
+        embeddings: HashMap<String, Vec<f32>>,
+        model: &str,
+    ) -> Result<usize, RepoError> {
+        let replaced = self
+            .messages
+            .lock()
+            .await
+            .replace_embeddings(user.clone(), embeddings, model)
+            .await?;
+        self.mirror.push(&get_user_root(&user)).await;
+        Ok(replaced)
+    }
+
+    async fn set_embedding(
+        &mut self,
+        user: String,
+        id: String,
+        embedding: Vec<f32>,
+        model: &str,
+    ) -> Result<(), RepoError> {
+        self.messages
+            .lock()
+            .await
+            .set_embedding(user.clone(), id, embedding, model)
+            .await?;
+        self.mirror.push(&get_user_root(&user)).await;
+        Ok(())
+    }
+
+    async fn warm_up(&self) -> Result<usize, RepoError> {
+        self.messages.lock().await.warm_up().await
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        match self.messages.try_lock() {
+            Ok(messages) => messages.cache_stats(),
+            Err(_) => CacheStats::default(),
+        }
+    }
+
+    async fn compact_index(&self) -> Result<usize, RepoError> {
+        let compacted = self.messages.lock().await.compact_index().await?;
+        self.mirror.push(&self.mirror.root).await;
+        Ok(compacted)
+    }
+
+    async fn compress_days(&mut self, before: NaiveDate) -> Result<usize, RepoError> {
+        let compressed = self.messages.lock().await.compress_days(before).await?;
+        info!("Mirroring {} compressed days", compressed);
+        self.mirror.push(&self.mirror.root).await;
+        Ok(compressed)
+    }
+}
+
+/// Keeps attributes in `attributes` on the local filesystem and mirrors the
+/// files they are written to into an object store
+pub struct ObjectStorageAttributeRepo {
+    attributes: Arc<Mutex<dyn AttributeRepo>>,
+    mirror: Arc<ObjectMirror>,
+}
+
+impl ObjectStorageAttributeRepo {
+    pub fn new(attributes: Arc<Mutex<dyn AttributeRepo>>, mirror: Arc<ObjectMirror>) -> Self {
+        ObjectStorageAttributeRepo { attributes, mirror }
+    }
+
+    async fn push_user(&self, user: &str) {
+        let root = get_user_root(user);
+        for file in [
+            "attributes.json",
+            "attribute_events.jsonl",
+            "expired_attributes.json",
+        ] {
+            self.mirror.push(&root.join(file)).await;
+        }
+    }
+}
+
+#[async_trait]
+impl AttributeRepo for ObjectStorageAttributeRepo {
+    async fn save_attribute(
+        &mut self,
+        user: &str,
+        attribute: &str,
+        value: &str,
+        expires_at: Option<i64>,
+        source: AttributeSource,
+    ) -> Result<AttributeModel, ()> {
+        let saved = self
+            .attributes
+            .lock()
+            .await
+            .save_attribute(user, attribute, value, expires_at, source)
+            .await?;
+        self.push_user(user).await;
+        Ok(saved)
+    }
+
+    async fn get_attribute(&mut self, user: &str, id: &str) -> Result<AttributeModel, ()> {
+        self.attributes.lock().await.get_attribute(user, id).await
+    }
+
+    async fn get_all_attributes(&mut self, user: &str) -> Result<Vec<AttributeModel>, ()> {
+        self.attributes.lock().await.get_all_attributes(user).await
+    }
+
+    async fn delete_attribute(&mut self, user: &str, attribute: &str) -> Result<(), ()> {
+        self.attributes
+            .lock()
+            .await
+            .delete_attribute(user, attribute)
+            .await?;
+        self.push_user(user).await;
+        Ok(())
+    }
+
+    async fn get_attribute_at(
+        &mut self,
+        user: &str,
+        attribute: &str,
+        at: i64,
+    ) -> Result<AttributeModel, ()> {
+        self.attributes
+            .lock()
+            .await
+            .get_attribute_at(user, attribute, at)
+            .await
+    }
+
+    async fn get_history(
+        &mut self,
+        user: &str,
+        attribute: &str,
+    ) -> Result<Vec<AttributeEvent>, ()> {
+        self.attributes
+            .lock()
+            .await
+            .get_history(user, attribute)
+            .await
+    }
+
+    async fn archive_expired(&mut self, now: i64) -> Result<Vec<(String, AttributeModel)>, ()> {
+        let archived = self.attributes.lock().await.archive_expired(now).await?;
+        let mut users = archived
+            .iter()
+            .map(|(user, _)| user.clone())
+            .collect::<Vec<String>>();
+        users.sort();
+        users.dedup();
+        for user in users {
+            self.push_user(&user).await;
+        }
+        Ok(archived)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keeps objects in memory
+    #[derive(Default)]
+    struct MemoryObjectStore {
+        objects: std::sync::Mutex<HashMap<String, Vec<u8>>>,
+    }
+
+    #[async_trait]
+    impl ObjectStore for MemoryObjectStore {
+        async fn put(&self, key: &str, body: Vec<u8>) -> Result<(), ()> {
+            self.objects.lock().unwrap().insert(key.to_string(), body);
+            Ok(())
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, ()> {
+            Ok(self.objects.lock().unwrap().get(key).cloned())
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), ()> {
+            self.objects.lock().unwrap().remove(key);
+            Ok(())
+        }
+
+        async fn list(&self, prefix: &str) -> Result<Vec<String>, ()> {
+            let objects = self.objects.lock().unwrap();
+            Ok(objects
+                .keys()
+                .filter(|key| key.starts_with(prefix))
+                .cloned()
+                .collect())
+        }
+    }
+
+    #[actix::test]
+    async fn test_files_are_mirrored_and_restored_on_a_new_host() {
+        let store = Arc::new(MemoryObjectStore::default());
+        let root = std::env::temp_dir().join(format!("muninn_s3_{}", uuid::Uuid::new_v4()));
+        let day = root.join("anna").join("2024-03-01");
+        std::fs::create_dir_all(&day).unwrap();
+        std::fs::write(day.join("messages.json"), "[1]").unwrap();
+        std::fs::write(day.join("messages.json.tmp"), "[").unwrap();
+
+        let mirror = ObjectMirror::new(store.clone(), root.clone(), "/muninn/");
+        assert_eq!(mirror.sync().await, Ok(0));
+        let keys = store.list("muninn/").await.unwrap();
+        assert_eq!(keys, vec!["muninn/anna/2024-03-01/messages.json"]);
+
+        // changes are uploaded, removed files are removed from the store
+        std::fs::write(root.join("anna").join("attributes.json"), "{}").unwrap();
+        std::fs::remove_dir_all(&day).unwrap();
+        mirror.push(&root.join("anna")).await;
+        let keys = store.list("muninn/").await.unwrap();
+        assert_eq!(keys, vec!["muninn/anna/attributes.json"]);
+
+        // a host starting empty gets the files back, keys outside the
+        // prefix or the root are left alone
+        store.put("muninn/../escape", b"x".to_vec()).await.unwrap();
+        store.put("other/anna/notes", b"x".to_vec()).await.unwrap();
+        let fresh = std::env::temp_dir().join(format!("muninn_s3_{}", uuid::Uuid::new_v4()));
+        let restored = ObjectMirror::new(store.clone(), fresh.clone(), "muninn");
+        assert_eq!(restored.sync().await, Ok(1));
+        let attributes = std::fs::read_to_string(fresh.join("anna").join("attributes.json"));
+        assert_eq!(attributes.unwrap(), "{}");
+        assert!(!fresh.join("anna").join("notes").exists());
+
+        std::fs::remove_dir_all(root).unwrap();
+        std::fs::remove_dir_all(fresh).unwrap();
+    }
+
+    #[actix::test]
+    async fn test_bodies_of_old_deleted_messages_are_removed_from_the_store() {
+        let store = Arc::new(MemoryObjectStore::default());
+        let root = super::super::get_storage_root();
+        let mirror = Arc::new(ObjectMirror::new(store.clone(), root.clone(), "muninn"));
+        let user = format!("test_s3_delete_{}", uuid::Uuid::new_v4());
+        let date = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        let body = format!("old message {}", uuid::Uuid::new_v4());
+        let chat = ChatModel {
+            timestamp: 0,
+            ..ChatModel::test("old", &body)
+        };
+        let mut repo = ObjectStorageMessageRepo::new(
+            Arc::new(Mutex::new(messages::FsMessageRepo::new())),
+            mirror.clone(),
+        );
+        repo.save_chat(date, user.clone(), chat).await.unwrap();
+        let content = messages::content_path(&body).unwrap();
+        let key = mirror.key(&content).unwrap();
+        assert!(store.get(&key).await.unwrap().is_some());
+
+        // after a restart the message is only known on disk
+        let mut repo = ObjectStorageMessageRepo::new(
+            Arc::new(Mutex::new(messages::FsMessageRepo::new())),
+            mirror.clone(),
+        );
+        repo.delete_chat(user.clone(), "old".to_string()).await.unwrap();
+        assert!(!content.exists());
+        assert!(store.get(&key).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,581 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use pgvector::Vector;
+use sqlx::{postgres::PgPoolOptions, postgres::PgRow, PgPool, Row};
+use tracing::{error, info};
+
+use super::{
+    attributes::{materialize, AttributeEvent, AttributeModel, AttributeRepo, AttributeSource},
+    messages::{ChatModel, HistoryPage, HistoryQuery, MessageRepo},
+    RepoError,
+};
+use crate::clients::embeddings::Similarity;
+
+/// Connects to the database at `url` and brings its schema up to date
+pub async fn connect(url: &str) -> Result<PgPool, sqlx::Error> {
+    let max_connections = std::env::var("DATABASE_MAX_CONNECTIONS")
+        .ok()
+        .and_then(|val| val.parse::<u32>().ok())
+        .unwrap_or(10);
+    let pool = PgPoolOptions::new()
+        .max_connections(max_connections)
+        .connect(url)
+        .await?;
+    sqlx::migrate!("./migrations/postgres").run(&pool).await?;
+    info!("Connected to Postgres with schema up to date");
+    Ok(pool)
+}
+
+const CHAT_COLUMNS: &str = "role, content, hash, embedding, embedding_model, timestamp, \
+                            conversation_id, forgotten, low_value, occurred_at, \
+                            metadata::TEXT AS metadata, tags";
+
+fn chat_from_row(row: &PgRow) -> Result<ChatModel, sqlx::Error> {
+    Ok(ChatModel {
+        role: row.try_get("role")?,
+        content: row.try_get("content")?,
+        hash: row.try_get("hash")?,
+        embedding: row
+            .try_get::<Option<Vector>, _>("embedding")?
+            .map(|embedding| embedding.to_vec()),
+        timestamp: row.try_get("timestamp")?,
+        conversation_id: row.try_get("conversation_id")?,
+        forgotten: row.try_get("forgotten")?,
+        low_value: row.try_get("low_value")?,
+        embedding_model: row.try_get("embedding_model")?,
+        occurred_at: row.try_get("occurred_at")?,
+        metadata: serde_json::from_str(row.try_get("metadata")?)
+            .map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+        tags: row.try_get("tags")?,
+    })
+}
+
+fn chats_from_rows(rows: Vec<PgRow>) -> Result<Vec<ChatModel>, RepoError> {
+    Ok(rows
+        .iter()
+        .map(chat_from_row)
+        .collect::<Result<Vec<ChatModel>, sqlx::Error>>()?)
+}
+
+async fn insert_chat<'e, E: sqlx::PgExecutor<'e>>(
+    executor: E,
+    date: NaiveDate,
+    user: &str,
+    chat: &ChatModel,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO messages (username, day, role, content, hash, embedding, embedding_model, \
+         timestamp, conversation_id, forgotten, low_value, occurred_at, metadata, tags) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13::JSONB, $14)",
+    )
+    .bind(user)
+    .bind(date)
+    .bind(&chat.role)
+    .bind(&chat.content)
+    .bind(&chat.hash)
+    .bind(chat.embedding.clone().map(Vector::from))
+    .bind(&chat.embedding_model)
+    .bind(chat.timestamp)
+    .bind(&chat.conversation_id)
+    .bind(chat.forgotten)
+    .bind(chat.low_value)
+    .bind(chat.occurred_at)
+    .bind(serde_json::to_string(&chat.metadata).unwrap_or_else(|_| "{}".to_string()))
+    .bind(&chat.tags)
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Messages stored in Postgres, searched with pgvector so that several
+/// instances can share one store
+pub struct PgMessageRepo {
+    pool: PgPool,
+}
+
+impl PgMessageRepo {
+    pub fn new(pool: PgPool) -> Self {
+        PgMessageRepo { pool }
+    }
+}
+
+#[async_trait]
+impl MessageRepo for PgMessageRepo {
+    async fn save_chat(
+        &mut self,
+        date: NaiveDate,
+        user: String,
+        chat: ChatModel,
+    ) -> Result<ChatModel, RepoError> {
+        insert_chat(&self.pool, date, &user, &chat).await?;
+        Ok(chat)
+    }
+
+    async fn save_chats(
+        &mut self,
+        date: NaiveDate,
+        user: String,
+        chats: Vec<ChatModel>,
+    ) -> Result<Vec<ChatModel>, RepoError> {
+        let mut transaction = self.pool.begin().await?;
+        for chat in chats.iter() {
+            insert_chat(&mut *transaction, date, &user, chat).await?;
+        }
+        transaction.commit().await?;
+        Ok(chats)
+    }
+
+    async fn get_chat(&mut self, user: String, id: String) -> Result<ChatModel, RepoError> {
+        let row = sqlx::query(&format!(
+            "SELECT {} FROM messages WHERE username = $1 AND hash = $2 ORDER BY id LIMIT 1",
+            CHAT_COLUMNS
+        ))
+        .bind(&user)
+        .bind(&id)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or(RepoError::NotFound)?;
+        Ok(chat_from_row(&row)?)
+    }
+
+    async fn embeddings_search_for_user(
+        &self,
+        user: String,
+        query_vector: Vec<f32>,
+        model: &str,
+        similarity: Similarity,
+    ) -> Result<Vec<(f32, ChatModel)>, RepoError> {
+        let score = match similarity {
+            Similarity::Cosine => "1 - (embedding <=> $1)",
+            Similarity::DotProduct => "(embedding <#> $1) * -1",
+            Similarity::Euclidean => "1 / (1 + (embedding <-> $1))",
+        };
+        // vectors from another model or of another size cannot be compared
+        // with the query, they are skipped until the user is re-embedded
+        let rows = sqlx::query(&format!(
+            "SELECT {}, ({})::REAL AS score FROM messages \
+             WHERE username = $2 AND NOT forgotten AND NOT low_value \
+             AND embedding IS NOT NULL AND vector_dims(embedding) = $3 \
+             AND (embedding_model IS NULL OR embedding_model = $4) \
+             ORDER BY score DESC",
+            CHAT_COLUMNS, score
+        ))
+        .bind(Vector::from(query_vector.clone()))
+        .bind(&user)
+        .bind(query_vector.len() as i32)
+        .bind(model)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| Ok((row.try_get::<f32, _>("score")?, chat_from_row(row)?)))
+            .collect::<Result<Vec<(f32, ChatModel)>, sqlx::Error>>()?)
+    }
+
+    async fn get_all_for_user(&self, user: String) -> Result<Vec<ChatModel>, RepoError> {
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM messages WHERE username = $1 ORDER BY day, id",
+            CHAT_COLUMNS
+        ))
+        .bind(&user)
+        .fetch_all(&self.pool)
+        .await?;
+        chats_from_rows(rows)
+    }
+
+    async fn get_all_for_user_on_day(
+        &self,
+        user: String,
+        date: NaiveDate,
+    ) -> Result<Vec<ChatModel>, RepoError> {
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM messages WHERE username = $1 AND day = $2 ORDER BY id",
+            CHAT_COLUMNS
+        ))
+        .bind(&user)
+        .bind(date)
+        .fetch_all(&self.pool)
+        .await?;
+        chats_from_rows(rows)
+    }
+
+    async fn get_for_dates(
+        &self,
+        user: String,
+        dates: Vec<NaiveDate>,
+    ) -> Result<Vec<ChatModel>, RepoError> {
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM messages WHERE username = $1 AND day = ANY($2) \
+             ORDER BY array_position($2, day), id",
+            CHAT_COLUMNS
+        ))
+        .bind(&user)
+        .bind(&dates)
+        .fetch_all(&self.pool)
+        .await?;
+        chats_from_rows(rows)
+    }
+
+    async fn get_history(
+        &self,
+        user: String,
+        query: &HistoryQuery,
+    ) -> Result<HistoryPage, RepoError> {
+        // `key=value` filters are matched against the metadata, the rest
+        // against the tags
+        let (pairs, tags): (Vec<&String>, Vec<&String>) =
+            query.tags.iter().partition(|filter| filter.contains('='));
+        let metadata = pairs
+            .iter()
+            .filter_map(|pair| pair.split_once('='))
+            .collect::<HashMap<&str, &str>>();
+        // one row past the page tells whether there is a next one
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM messages WHERE username = $1 \
+             AND ($2::DATE IS NULL OR day >= $2) AND ($3::DATE IS NULL OR day <= $3) \
+             AND tags @> $6 AND metadata @> $7::JSONB \
+             ORDER BY day, id OFFSET $4 LIMIT $5",
+            CHAT_COLUMNS
+        ))
+        .bind(&user)
+        .bind(query.from_date)
+        .bind(query.to_date)
+        .bind(query.offset as i64)
+        .bind(query.limit as i64 + 1)
+        .bind(tags)
+        .bind(serde_json::to_string(&metadata).unwrap_or_else(|_| "{}".to_string()))
+        .fetch_all(&self.pool)
+        .await?;
+        let mut messages = chats_from_rows(rows)?;
+        let next_offset = match messages.len() > query.limit {
+            true => Some(query.offset + query.limit),
+            false => None,
+        };
+        messages.truncate(query.limit);
+        Ok(HistoryPage {
+            messages,
+            next_offset,
+        })
+    }
+
+    async fn get_users(&self) -> Result<Vec<String>, RepoError> {
+        // collections are stored under keys of their own, see collection_key
+        let rows = sqlx::query(&format!(
+            "SELECT DISTINCT username FROM messages WHERE username NOT LIKE '%/{}/%' \
+             ORDER BY username",
+            super::COLLECTIONS_FOLDER
+        ))
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .iter()
+            .map(|row| row.try_get("username"))
+            .collect::<Result<Vec<String>, sqlx::Error>>()?)
+    }
+
+    async fn get_collections(&self, user: String) -> Result<Vec<String>, RepoError> {
+        let prefix = super::collection_key(&user, "");
+        let rows = sqlx::query(
+            "SELECT DISTINCT substr(username, length($1) + 1) AS collection FROM messages \
+             WHERE starts_with(username, $1) ORDER BY collection",
+        )
+        .bind(&prefix)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .iter()
+            .map(|row| row.try_get("collection"))
+            .collect::<Result<Vec<String>, sqlx::Error>>()?)
+    }
+
+    async fn redact_chat(&mut self, user: String, id: String) -> Result<ChatModel, RepoError> {
+        let row = sqlx::query(&format!(
+            "UPDATE messages SET content = '', embedding = NULL, embedding_model = NULL, \
+             forgotten = TRUE WHERE username = $1 AND hash = $2 RETURNING {}",
+            CHAT_COLUMNS
+        ))
+        .bind(&user)
+        .bind(&id)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or(RepoError::NotFound)?;
+        Ok(chat_from_row(&row)?)
+    }
+
+    async fn delete_chat(&mut self, user: String, id: String) -> Result<(), RepoError> {
+        let deleted = sqlx::query("DELETE FROM messages WHERE username = $1 AND hash = $2")
+            .bind(&user)
+            .bind(&id)
+            .execute(&self.pool)
+            .await?;
+        match deleted.rows_affected() {
+            0 => Err(RepoError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    async fn get_days(&self, user: String) -> Result<Vec<NaiveDate>, RepoError> {
+        let rows =
+            sqlx::query("SELECT DISTINCT day FROM messages WHERE username = $1 ORDER BY day")
+                .bind(&user)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(rows
+            .iter()
+            .map(|row| row.try_get("day"))
+            .collect::<Result<Vec<NaiveDate>, sqlx::Error>>()?)
+    }
+
+    async fn delete_day(&mut self, user: String, date: NaiveDate) -> Result<usize, RepoError> {
+        let deleted = sqlx::query("DELETE FROM messages WHERE username = $1 AND day = $2")
+            .bind(&user)
+            .bind(date)
+            .execute(&self.pool)
+            .await?;
+        Ok(deleted.rows_affected() as usize)
+    }
+
+    async fn replace_embeddings(
+        &mut self,
+        user: String,
+        embeddings: HashMap<String, Vec<f32>>,
+        model: &str,
+    ) -> Result<usize, RepoError> {
+        let mut transaction = self.pool.begin().await?;
+        let mut replaced = 0;
+        for (hash, embedding) in embeddings {
+            let updated = sqlx::query(
+                "UPDATE messages SET embedding = $1, embedding_model = $2 \
+                 WHERE username = $3 AND hash = $4",
+            )
+            .bind(Vector::from(embedding))
+            .bind(model)
+            .bind(&user)
+            .bind(&hash)
+            .execute(&mut *transaction)
+            .await?;
+            replaced += updated.rows_affected() as usize;
+        }
+        transaction.commit().await?;
+        Ok(replaced)
+    }
+}
+
+// Attribute failures are reported as `()` like the file backend does
+fn log_error(e: sqlx::Error) {
+    error!("Database error: {}", e);
+}
+
+fn attribute_from_row(row: &PgRow) -> Result<AttributeModel, sqlx::Error> {
+    Ok(AttributeModel {
+        attribute: row.try_get("attribute")?,
+        value: row.try_get("value")?,
+        expires_at: row.try_get("expires_at")?,
+        source: AttributeSource::parse(row.try_get("source")?),
+    })
+}
+
+fn event_from_row(row: &PgRow) -> Result<AttributeEvent, sqlx::Error> {
+    let attribute = row.try_get("attribute")?;
+    let at = row.try_get("at")?;
+    Ok(match row.try_get::<String, _>("kind")?.as_str() {
+        "delete" => AttributeEvent::Delete { attribute, at },
+        _ => AttributeEvent::Set {
+            attribute,
+            value: row
+                .try_get::<Option<String>, _>("value")?
+                .unwrap_or_default(),
+            expires_at: row.try_get("expires_at")?,
+            source: AttributeSource::parse(row.try_get("source")?),
+            at,
+        },
+    })
+}
+
+/// User attributes and their change log stored in Postgres
+pub struct PgAttributeRepo {
+    pool: PgPool,
+}
+
+impl PgAttributeRepo {
+    pub fn new(pool: PgPool) -> Self {
+        PgAttributeRepo { pool }
+    }
+
+    async fn get_events(&self, user: &str, attribute: &str) -> Result<Vec<AttributeEvent>, ()> {
+        let rows = sqlx::query(
+            "SELECT attribute, kind, value, expires_at, source, at FROM attribute_events \
+             WHERE username = $1 AND attribute = $2 ORDER BY id",
+        )
+        .bind(user)
+        .bind(attribute)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(log_error)?;
+        rows.iter()
+            .map(event_from_row)
+            .collect::<Result<Vec<AttributeEvent>, sqlx::Error>>()
+            .map_err(log_error)
+    }
+}
+
+#[async_trait]
+impl AttributeRepo for PgAttributeRepo {
+    async fn save_attribute(
+        &mut self,
+        user: &str,
+        attribute: &str,
+        value: &str,
+        expires_at: Option<i64>,
+        source: AttributeSource,
+    ) -> Result<AttributeModel, ()> {
+        let mut transaction = self.pool.begin().await.map_err(log_error)?;
+        sqlx::query(
+            "INSERT INTO attributes (username, attribute, value, expires_at, source) \
+             VALUES ($1, $2, $3, $4, $5) ON CONFLICT (username, attribute) \
+             DO UPDATE SET value = EXCLUDED.value, expires_at = EXCLUDED.expires_at, \
+             source = EXCLUDED.source",
+        )
+        .bind(user)
+        .bind(attribute)
+        .bind(value)
+        .bind(expires_at)
+        .bind(source.as_str())
+        .execute(&mut *transaction)
+        .await
+        .map_err(log_error)?;
+        sqlx::query(
+            "INSERT INTO attribute_events \
+             (username, attribute, kind, value, expires_at, source, at) \
+             VALUES ($1, $2, 'set', $3, $4, $5, $6)",
+        )
+        .bind(user)
+        .bind(attribute)
+        .bind(value)
+        .bind(expires_at)
+        .bind(source.as_str())
+        .bind(chrono::Utc::now().timestamp())
+        .execute(&mut *transaction)
+        .await
+        .map_err(log_error)?;
+        transaction.commit().await.map_err(log_error)?;
+
+        Ok(AttributeModel {
+            attribute: attribute.to_string(),
+            value: value.to_string(),
+            expires_at,
+            source,
+        })
+    }
+
+    async fn get_attribute(&mut self, user: &str, id: &str) -> Result<AttributeModel, ()> {
+        let row = sqlx::query(
+            "SELECT attribute, value, expires_at, source FROM attributes \
+             WHERE username = $1 AND attribute = $2 AND (expires_at IS NULL OR expires_at > $3)",
+        )
+        .bind(user)
+        .bind(id)
+        .bind(chrono::Utc::now().timestamp())
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(log_error)?
+        .ok_or(())?;
+        attribute_from_row(&row).map_err(log_error)
+    }
+
+    async fn get_all_attributes(&mut self, user: &str) -> Result<Vec<AttributeModel>, ()> {
+        let rows = sqlx::query(
+            "SELECT attribute, value, expires_at, source FROM attributes \
+             WHERE username = $1 AND (expires_at IS NULL OR expires_at > $2) ORDER BY attribute",
+        )
+        .bind(user)
+        .bind(chrono::Utc::now().timestamp())
+        .fetch_all(&self.pool)
+        .await
+        .map_err(log_error)?;
+        rows.iter()
+            .map(attribute_from_row)
+            .collect::<Result<Vec<AttributeModel>, sqlx::Error>>()
+            .map_err(log_error)
+    }
+
+    async fn delete_attribute(&mut self, user: &str, attribute: &str) -> Result<(), ()> {
+        let mut transaction = self.pool.begin().await.map_err(log_error)?;
+        let deleted = sqlx::query("DELETE FROM attributes WHERE username = $1 AND attribute = $2")
+            .bind(user)
+            .bind(attribute)
+            .execute(&mut *transaction)
+            .await
+            .map_err(log_error)?;
+        if deleted.rows_affected() == 0 {
+            return Err(());
+        }
+        sqlx::query(
+            "INSERT INTO attribute_events (username, attribute, kind, at) \
+             VALUES ($1, $2, 'delete', $3)",
+        )
+        .bind(user)
+        .bind(attribute)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(&mut *transaction)
+        .await
+        .map_err(log_error)?;
+        transaction.commit().await.map_err(log_error)
+    }
+
+    async fn get_attribute_at(
+        &mut self,
+        user: &str,
+        attribute: &str,
+        at: i64,
+    ) -> Result<AttributeModel, ()> {
+        let events = self.get_events(user, attribute).await?;
+        materialize(&events, at).remove(attribute).ok_or(())
+    }
+
+    async fn get_history(
+        &mut self,
+        user: &str,
+        attribute: &str,
+    ) -> Result<Vec<AttributeEvent>, ()> {
+        self.get_events(user, attribute).await
+    }
+
+    async fn archive_expired(&mut self, now: i64) -> Result<Vec<(String, AttributeModel)>, ()> {
+        let mut transaction = self.pool.begin().await.map_err(log_error)?;
+        let rows = sqlx::query(
+            "DELETE FROM attributes WHERE expires_at <= $1 \
+             RETURNING username, attribute, value, expires_at, source",
+        )
+        .bind(now)
+        .fetch_all(&mut *transaction)
+        .await
+        .map_err(log_error)?;
+
+        let mut archived = vec![];
+        for row in rows.iter() {
+            let user: String = row.try_get("username").map_err(log_error)?;
+            let attribute = attribute_from_row(row).map_err(log_error)?;
+            sqlx::query(
+                "INSERT INTO expired_attributes \
+                 (username, attribute, value, expires_at, source, archived_at) \
+                 VALUES ($1, $2, $3, $4, $5, $6)",
+            )
+            .bind(&user)
+            .bind(&attribute.attribute)
+            .bind(&attribute.value)
+            .bind(attribute.expires_at)
+            .bind(attribute.source.as_str())
+            .bind(now)
+            .execute(&mut *transaction)
+            .await
+            .map_err(log_error)?;
+            archived.push((user, attribute));
+        }
+        transaction.commit().await.map_err(log_error)?;
+        Ok(archived)
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::get_user_root;
+
+/// Named set of context assembly options, so different client applications
+/// can ask for appropriately sized contexts
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ContextPreset {
+    pub name: String,
+    /// Approximate number of tokens the assembled context may use
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    /// Maximum number of messages returned
+    #[serde(default)]
+    pub top_k: Option<usize>,
+    /// Only messages with one of these roles are included
+    #[serde(default)]
+    pub roles: Option<Vec<String>>,
+    /// Replace closed conversation threads with their summaries
+    #[serde(default = "enabled")]
+    pub thread_summaries: bool,
+    /// Summarize older history with the LLM when it grows long
+    #[serde(default = "enabled")]
+    pub rolling_summary: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl Default for ContextPreset {
+    fn default() -> Self {
+        ContextPreset {
+            name: "default".to_string(),
+            max_tokens: None,
+            top_k: None,
+            roles: None,
+            thread_summaries: true,
+            rolling_summary: true,
+        }
+    }
+}
+
+pub trait PresetRepo: Send + Sync {
+    fn save_preset(&mut self, user: &str, preset: ContextPreset) -> Result<ContextPreset, ()>;
+    fn get_preset(&self, user: &str, name: &str) -> Result<ContextPreset, ()>;
+    fn get_presets(&self, user: &str) -> Result<Vec<ContextPreset>, ()>;
+}
+
+pub struct FsPresetRepo {}
+
+impl FsPresetRepo {
+    pub fn new() -> Self {
+        FsPresetRepo {}
+    }
+}
+
+fn get_presets_path(user: &str) -> std::path::PathBuf {
+    get_user_root(user).join("presets.json")
+}
+
+fn read_presets(user: &str) -> HashMap<String, ContextPreset> {
+    match std::fs::read_to_string(get_presets_path(user)) {
+        Ok(content) => match serde_json::from_str(&content) {
+            Ok(presets) => presets,
+            Err(e) => {
+                error!("Error deserializing presets: {}", e);
+                HashMap::new()
+            }
+        },
+        Err(_) => HashMap::new(),
+    }
+}
+
+impl PresetRepo for FsPresetRepo {
+    fn save_preset(&mut self, user: &str, preset: ContextPreset) -> Result<ContextPreset, ()> {
+        let mut presets = read_presets(user);
+        presets.insert(preset.name.clone(), preset.clone());
+
+        let path = get_presets_path(user);
+        std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| {
+            error!("Error creating directory: {}", e);
+        })?;
+        let serialized = serde_json::to_string(&presets).map_err(|_| ())?;
+        match std::fs::write(&path, serialized) {
+            Ok(_) => Ok(preset),
+            Err(e) => {
+                error!("Error writing to file: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    fn get_preset(&self, user: &str, name: &str) -> Result<ContextPreset, ()> {
+        read_presets(user).remove(name).ok_or(())
+    }
+
+    fn get_presets(&self, user: &str) -> Result<Vec<ContextPreset>, ()> {
+        let mut presets = read_presets(user).into_values().collect::<Vec<_>>();
+        presets.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(presets)
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::{encryption, get_storage_root, get_user_root};
+
+/// Something a user asked to be reminded of
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ReminderModel {
+    pub id: String,
+    pub text: String,
+    /// Unix timestamp the reminder is due at
+    pub due_at: i64,
+    pub created_at: i64,
+    /// When the reminder was sent, absent while it is pending
+    pub fired_at: Option<i64>,
+}
+
+pub trait ReminderRepo: Send + Sync {
+    fn save_reminder(&mut self, user: &str, reminder: ReminderModel) -> Result<ReminderModel, ()>;
+    /// Every reminder of the user, the earliest due first
+    fn get_reminders(&self, user: &str) -> Result<Vec<ReminderModel>, ()>;
+    fn delete_reminder(&mut self, user: &str, id: &str) -> Result<(), ()>;
+    /// Records that the reminder was sent at `at`, `Err` when it was deleted
+    /// in the meantime
+    fn mark_fired(&mut self, user: &str, id: &str, at: i64) -> Result<(), ()>;
+    /// Users with at least one reminder
+    fn get_users(&self) -> Result<Vec<String>, ()>;
+}
+
+pub struct FsReminderRepo {}
+
+impl FsReminderRepo {
+    pub fn new() -> Self {
+        FsReminderRepo {}
+    }
+}
+
+fn get_reminders_path(user: &str) -> std::path::PathBuf {
+    get_user_root(user).join("reminders.json")
+}
+
+fn read_reminders(user: &str) -> HashMap<String, ReminderModel> {
+    match encryption::read_to_string(&get_reminders_path(user)) {
+        Ok(content) => match serde_json::from_str(&content) {
+            Ok(reminders) => reminders,
+            Err(e) => {
+                error!("Error deserializing reminders: {}", e);
+                HashMap::new()
+            }
+        },
+        Err(_) => HashMap::new(),
+    }
+}
+
+fn write_reminders(user: &str, reminders: &HashMap<String, ReminderModel>) -> Result<(), ()> {
+    let path = get_reminders_path(user);
+    std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| {
+        error!("Error creating directory: {}", e);
+    })?;
+    let serialized = serde_json::to_string(reminders).map_err(|_| ())?;
+    std::fs::write(&path, encryption::seal(serialized.as_bytes())).map_err(|e| {
+        error!("Error writing to file: {}", e);
+    })
+}
+
+impl ReminderRepo for FsReminderRepo {
+    fn save_reminder(&mut self, user: &str, reminder: ReminderModel) -> Result<ReminderModel, ()> {
+        let mut reminders = read_reminders(user);
+        reminders.insert(reminder.id.clone(), reminder.clone());
+        write_reminders(user, &reminders)?;
+        Ok(reminder)
+    }
+
+    fn get_reminders(&self, user: &str) -> Result<Vec<ReminderModel>, ()> {
+        let mut reminders = read_reminders(user).into_values().collect::<Vec<_>>();
+        reminders.sort_by_key(|reminder| (reminder.due_at, reminder.created_at));
+        Ok(reminders)
+    }
+
+    fn delete_reminder(&mut self, user: &str, id: &str) -> Result<(), ()> {
+        let mut reminders = read_reminders(user);
+        reminders.remove(id).ok_or(())?;
+        write_reminders(user, &reminders)
+    }
+
+    fn mark_fired(&mut self, user: &str, id: &str, at: i64) -> Result<(), ()> {
+        let mut reminders = read_reminders(user);
+        reminders.get_mut(id).ok_or(())?.fired_at = Some(at);
+        write_reminders(user, &reminders)
+    }
+
+    fn get_users(&self) -> Result<Vec<String>, ()> {
+        let entries = match std::fs::read_dir(get_storage_root()) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(vec![]),
+        };
+        let mut users = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().join("reminders.json").is_file())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect::<Vec<String>>();
+        users.sort();
+        Ok(users)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reminders_are_listed_by_due_time() {
+        let mut repo = FsReminderRepo::new();
+        let username = format!("test_reminders_{}", uuid::Uuid::new_v4());
+        let reminder = |id: &str, due_at| ReminderModel {
+            id: id.to_string(),
+            text: format!("Reminder {}", id),
+            due_at,
+            created_at: 0,
+            fired_at: None,
+        };
+        // due long after the reminders other tests fire
+        repo.save_reminder(&username, reminder("later", i64::MAX))
+            .unwrap();
+        repo.save_reminder(&username, reminder("sooner", i64::MAX - 1))
+            .unwrap();
+        assert!(repo.get_users().unwrap().contains(&username));
+
+        repo.mark_fired(&username, "sooner", 11).unwrap();
+        let reminders = repo.get_reminders(&username).unwrap();
+        assert_eq!(reminders[0].id, "sooner");
+        assert_eq!(reminders[0].fired_at, Some(11));
+        assert_eq!(reminders[1].fired_at, None);
+
+        repo.delete_reminder(&username, "sooner").unwrap();
+        assert!(repo.mark_fired(&username, "sooner", 12).is_err());
+        assert!(repo.delete_reminder(&username, "sooner").is_err());
+    }
+}
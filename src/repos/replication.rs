@@ -0,0 +1,510 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use futures::{future::BoxFuture, FutureExt};
+use tokio::sync::{mpsc, Mutex};
+use tracing::error;
+
+use super::{
+    attributes::{AttributeEvent, AttributeModel, AttributeRepo, AttributeSource},
+    messages::{CacheStats, ChatModel, HistoryPage, HistoryQuery, MessageRepo},
+    RepoError,
+};
+use crate::clients::embeddings::Similarity;
+
+// A write to the secondary, described for the log should it fail
+type Write = (String, BoxFuture<'static, Result<(), String>>);
+
+/// Applies writes to the secondary one at a time in the order they were
+/// made to the primary, in the background so the primary never waits on it.
+/// Writes are queued in memory, those still queued on shutdown are lost and
+/// left to reconciliation to repair.
+pub struct Replicator {
+    sender: mpsc::UnboundedSender<Write>,
+    pending: Arc<AtomicUsize>,
+    failed: Arc<AtomicUsize>,
+}
+
+impl Replicator {
+    /// Starts the task applying the writes, which runs for as long as the
+    /// replicator lives
+    pub fn start() -> Replicator {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Write>();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let failed = Arc::new(AtomicUsize::new(0));
+        let (applied, failures) = (pending.clone(), failed.clone());
+        tokio::spawn(async move {
+            while let Some((what, write)) = receiver.recv().await {
+                if let Err(e) = write.await {
+                    error!("Error replicating {}: {}", what, e);
+                    failures.fetch_add(1, Ordering::SeqCst);
+                }
+                applied.fetch_sub(1, Ordering::SeqCst);
+            }
+        });
+        Replicator {
+            sender,
+            pending,
+            failed,
+        }
+    }
+
+    fn send(&self, what: String, write: BoxFuture<'static, Result<(), String>>) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        if self.sender.send((what, write)).is_err() {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            self.failed.fetch_add(1, Ordering::SeqCst);
+            error!("Replication stopped, the secondary is missing a write");
+        }
+    }
+
+    /// Writes made to the primary that the secondary has yet to apply
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// Writes the secondary failed to apply since startup
+    pub fn failed(&self) -> usize {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    /// Waits for the secondary to catch up, `false` when it had not by
+    /// `timeout`
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        let started = tokio::time::Instant::now();
+        while self.pending() > 0 {
+            if started.elapsed() >= timeout {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        true
+    }
+}
+
+/// Secondary stores the primary ones are replicated to, with the replicator
+/// writing to them
+#[derive(Clone)]
+pub struct Replica {
+    pub message_repo: Arc<Mutex<dyn MessageRepo>>,
+    pub attribute_repo: Arc<Mutex<dyn AttributeRepo>>,
+    pub replicator: Arc<Replicator>,
+}
+
+/// Keeps messages in `primary`, which answers every read, and repeats each
+/// write to the replica's message repo in the background. A write that
+/// fails on the replica is logged, the reconciliation job then reports the
+/// difference it left.
+pub struct ReplicatedMessageRepo {
+    primary: Arc<Mutex<dyn MessageRepo>>,
+    replica: Replica,
+}
+
+impl ReplicatedMessageRepo {
+    pub fn new(primary: Arc<Mutex<dyn MessageRepo>>, replica: Replica) -> Self {
+        ReplicatedMessageRepo { primary, replica }
+    }
+
+    fn replicate<F>(&self, what: String, write: F)
+    where
+        F: FnOnce(Arc<Mutex<dyn MessageRepo>>) -> BoxFuture<'static, Result<(), RepoError>>,
+    {
+        let write = write(self.replica.message_repo.clone());
+        self.replica.replicator.send(
+            what,
+            write.map(|done| done.map_err(|e| e.to_string())).boxed(),
+        );
+    }
+}
+
+#[async_trait]
+impl MessageRepo for ReplicatedMessageRepo {
+    async fn save_chat(
+        &mut self,
+        date: NaiveDate,
+        user: String,
+        chat: ChatModel,
+    ) -> Result<ChatModel, RepoError> {
+        let chat = self
+            .primary
+            .lock()
+            .await
+            .save_chat(date, user.clone(), chat)
+            .await?;
+        let saved = chat.clone();
+        self.replicate(format!("message {}", chat.hash), move |replica| {
+            async move {
+                replica.lock().await.save_chat(date, user, saved).await?;
+                Ok(())
+            }
+            .boxed()
+        });
+        Ok(chat)
+    }
+
+    async fn save_chats(
+        &mut self,
+        date: NaiveDate,
+        user: String,
+        chats: Vec<ChatModel>,
+    ) -> Result<Vec<ChatModel>, RepoError> {
+        let chats = self
+            .primary
+            .lock()
+            .await
+            .save_chats(date, user.clone(), chats)
+            .await?;
+        let saved = chats.clone();
+        self.replicate(
+            format!("{} messages of {}", chats.len(), date),
+            move |replica| {
+                async move {
+                    replica.lock().await.save_chats(date, user, saved).await?;
+                    Ok(())
+                }
+                .boxed()
+            },
+        );
+        Ok(chats)
+    }
+
+    async fn get_chat(&mut self, user: String, id: String) -> Result<ChatModel, RepoError> {
+        self.primary.lock().await.get_chat(user, id).await
+    }
+
+    async fn embeddings_search_for_user(
+        &self,
+        user: String,
+        query_vector: Vec<f32>,
+        model: &str,
+        similarity: Similarity,
+    ) -> Result<Vec<(f32, ChatModel)>, RepoError> {
+        self.primary
+            .lock()
+            .await
+            .embeddings_search_for_user(user, query_vector, model, similarity)
+            .await
+    }
+
+    async fn embeddings_search_between(
+        &self,
+        user: String,
+        query_vector: Vec<f32>,
+        model: &str,
+        similarity: Similarity,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+    ) -> Result<Vec<(f32, ChatModel)>, RepoError> {
+        self.primary
+            .lock()
+            .await
+            .embeddings_search_between(user, query_vector, model, similarity, from, to)
+            .await
+    }
+
+    async fn keyword_search_for_user(
+        &self,
+        user: String,
+        query: &str,
+    ) -> Result<Vec<(f32, ChatModel)>, RepoError> {
+        self.primary
+            .lock()
+            .await
+            .keyword_search_for_user(user, query)
+            .await
+    }
+
+    async fn get_all_for_user(&self, user: String) -> Result<Vec<ChatModel>, RepoError> {
+        self.primary.lock().await.get_all_for_user(user).await
+    }
+
+    async fn get_all_for_user_on_day(
+        &self,
+        user: String,
+        date: NaiveDate,
+    ) -> Result<Vec<ChatModel>, RepoError> {
+        self.primary
+            .lock()
+            .await
+            .get_all_for_user_on_day(user, date)
+            .await
+    }
+
+    async fn get_for_dates(
+        &self,
+        user: String,
+        dates: Vec<NaiveDate>,
+    ) -> Result<Vec<ChatModel>, RepoError> {
+        self.primary.lock().await.get_for_dates(user, dates).await
+    }
+
+    async fn get_history(
+        &self,
+        user: String,
+        query: &HistoryQuery,
+    ) -> Result<HistoryPage, RepoError> {
+        self.primary.lock().await.get_history(user, query).await
+    }
+
+    async fn get_users(&self) -> Result<Vec<String>, RepoError> {
+        self.primary.lock().await.get_users().await
+    }
+
+    async fn get_collections(&self, user: String) -> Result<Vec<String>, RepoError> {
+        self.primary.lock().await.get_collections(user).await
+    }
+
+    async fn redact_chat(&mut self, user: String, id: String) -> Result<ChatModel, RepoError> {
+        let redacted = self
+            .primary
+            .lock()
+            .await
+            .redact_chat(user.clone(), id.clone())
+            .await?;
+        self.replicate(format!("redaction of {}", id), move |replica| {
+            async move {
+                replica.lock().await.redact_chat(user, id).await?;
+                Ok(())
+            }
+            .boxed()
+        });
+        Ok(redacted)
+    }
+
+    async fn delete_chat(&mut self, user: String, id: String) -> Result<(), RepoError> {
+        self.primary
+            .lock()
+            .await
+            .delete_chat(user.clone(), id.clone())
+            .await?;
+        self.replicate(format!("deletion of {}", id), move |replica| {
+            async move { replica.lock().await.delete_chat(user, id).await }.boxed()
+        });
+        Ok(())
+    }
+
+    async fn get_days(&self, user: String) -> Result<Vec<NaiveDate>, RepoError> {
+        self.primary.lock().await.get_days(user).await
+    }
+
+    async fn delete_day(&mut self, user: String, date: NaiveDate) -> Result<usize, RepoError> {
+        let deleted = self
+            .primary
+            .lock()
+            .await
+            .delete_day(user.clone(), date)
+            .await?;
+        self.replicate(format!("deletion of {}", date), move |replica| {
+            async move {
+                replica.lock().await.delete_day(user, date).await?;
+                Ok(())
+            }
+            .boxed()
+        });
+        Ok(deleted)
+    }
+
+    async fn replace_embeddings(
+        &mut self,
+        user: String,
+        embeddings: HashMap<String, Vec<f32>>,
+        model: &str,
+    ) -> Result<usize, RepoError> {
+        let replaced = self
+            .primary
+            .lock()
+            .await
+            .replace_embeddings(user.clone(), embeddings.clone(), model)
+            .await?;
+        let model = model.to_string();
+        self.replicate(format!("embeddings of {}", user), move |replica| {
+            async move {
+                replica
+                    .lock()
+                    .await
+                    .replace_embeddings(user, embeddings, &model)
+                    .await?;
+                Ok(())
+            }
+            .boxed()
+        });
+        Ok(replaced)
+    }
+
+    async fn set_embedding(
+        &mut self,
+        user: String,
+        id: String,
+        embedding: Vec<f32>,
+        model: &str,
+    ) -> Result<(), RepoError> {
+        self.primary
+            .lock()
+            .await
+            .set_embedding(user.clone(), id.clone(), embedding.clone(), model)
+            .await?;
+        let model = model.to_string();
+        self.replicate(format!("embedding of {}", id), move |replica| {
+            async move {
+                replica
+                    .lock()
+                    .await
+                    .set_embedding(user, id, embedding, &model)
+                    .await
+            }
+            .boxed()
+        });
+        Ok(())
+    }
+
+    async fn warm_up(&self) -> Result<usize, RepoError> {
+        self.primary.lock().await.warm_up().await
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        match self.primary.try_lock() {
+            Ok(primary) => primary.cache_stats(),
+            Err(_) => CacheStats::default(),
+        }
+    }
+
+    // indexes and cold storage are how each store keeps its own data, the
+    // replica maintains its own
+    async fn compact_index(&self) -> Result<usize, RepoError> {
+        self.primary.lock().await.compact_index().await
+    }
+
+    async fn compress_days(&mut self, before: NaiveDate) -> Result<usize, RepoError> {
+        self.primary.lock().await.compress_days(before).await
+    }
+}
+
+/// Keeps attributes in `primary`, which answers every read, and repeats
+/// each write to the replica's attribute repo in the background
+pub struct ReplicatedAttributeRepo {
+    primary: Arc<Mutex<dyn AttributeRepo>>,
+    replica: Replica,
+}
+
+impl ReplicatedAttributeRepo {
+    pub fn new(primary: Arc<Mutex<dyn AttributeRepo>>, replica: Replica) -> Self {
+        ReplicatedAttributeRepo { primary, replica }
+    }
+
+    fn replicate<F>(&self, what: String, write: F)
+    where
+        F: FnOnce(Arc<Mutex<dyn AttributeRepo>>) -> BoxFuture<'static, Result<(), ()>>,
+    {
+        let write = write(self.replica.attribute_repo.clone());
+        self.replica.replicator.send(
+            what,
+            write
+                .map(|done| done.map_err(|_| "the replica failed".to_string()))
+                .boxed(),
+        );
+    }
+}
+
+#[async_trait]
+impl AttributeRepo for ReplicatedAttributeRepo {
+    async fn save_attribute(
+        &mut self,
+        user: &str,
+        attribute: &str,
+        value: &str,
+        expires_at: Option<i64>,
+        source: AttributeSource,
+    ) -> Result<AttributeModel, ()> {
+        let saved = self
+            .primary
+            .lock()
+            .await
+            .save_attribute(user, attribute, value, expires_at, source)
+            .await?;
+        let (user, attribute, value) = (user.to_string(), attribute.to_string(), value.to_string());
+        self.replicate(format!("attribute {}", attribute), move |replica| {
+            async move {
+                replica
+                    .lock()
+                    .await
+                    .save_attribute(&user, &attribute, &value, expires_at, source)
+                    .await?;
+                Ok(())
+            }
+            .boxed()
+        });
+        Ok(saved)
+    }
+
+    async fn get_attribute(&mut self, user: &str, id: &str) -> Result<AttributeModel, ()> {
+        self.primary.lock().await.get_attribute(user, id).await
+    }
+
+    async fn get_all_attributes(&mut self, user: &str) -> Result<Vec<AttributeModel>, ()> {
+        self.primary.lock().await.get_all_attributes(user).await
+    }
+
+    async fn delete_attribute(&mut self, user: &str, attribute: &str) -> Result<(), ()> {
+        self.primary
+            .lock()
+            .await
+            .delete_attribute(user, attribute)
+            .await?;
+        let (user, attribute) = (user.to_string(), attribute.to_string());
+        self.replicate(
+            format!("deletion of attribute {}", attribute),
+            move |replica| {
+                async move {
+                    replica
+                        .lock()
+                        .await
+                        .delete_attribute(&user, &attribute)
+                        .await
+                }
+                .boxed()
+            },
+        );
+        Ok(())
+    }
+
+    async fn get_attribute_at(
+        &mut self,
+        user: &str,
+        attribute: &str,
+        at: i64,
+    ) -> Result<AttributeModel, ()> {
+        self.primary
+            .lock()
+            .await
+            .get_attribute_at(user, attribute, at)
+            .await
+    }
+
+    async fn get_history(
+        &mut self,
+        user: &str,
+        attribute: &str,
+    ) -> Result<Vec<AttributeEvent>, ()> {
+        self.primary.lock().await.get_history(user, attribute).await
+    }
+
+    async fn archive_expired(&mut self, now: i64) -> Result<Vec<(String, AttributeModel)>, ()> {
+        let archived = self.primary.lock().await.archive_expired(now).await?;
+        self.replicate("expired attributes".to_string(), move |replica| {
+            async move {
+                replica.lock().await.archive_expired(now).await?;
+                Ok(())
+            }
+            .boxed()
+        });
+        Ok(archived)
+    }
+}
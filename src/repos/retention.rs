@@ -0,0 +1,59 @@
+use tracing::error;
+
+use super::get_user_root;
+use crate::config::RetentionPolicy;
+
+/// Retention policies users chose for themselves, overriding the configured
+/// one
+pub trait RetentionRepo: Send + Sync {
+    /// The user's own policy, `None` when they have not set one
+    fn get_policy(&self, user: &str) -> Result<Option<RetentionPolicy>, ()>;
+    fn save_policy(&mut self, user: &str, policy: RetentionPolicy) -> Result<(), ()>;
+    /// Goes back to the configured policy
+    fn delete_policy(&mut self, user: &str) -> Result<(), ()>;
+}
+
+pub struct FsRetentionRepo {}
+
+impl FsRetentionRepo {
+    pub fn new() -> Self {
+        FsRetentionRepo {}
+    }
+}
+
+fn get_retention_path(user: &str) -> std::path::PathBuf {
+    get_user_root(user).join("retention.json")
+}
+
+impl RetentionRepo for FsRetentionRepo {
+    fn get_policy(&self, user: &str) -> Result<Option<RetentionPolicy>, ()> {
+        match std::fs::read_to_string(get_retention_path(user)) {
+            Ok(content) => serde_json::from_str(&content).map(Some).map_err(|e| {
+                error!("Error deserializing retention policy: {}", e);
+            }),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn save_policy(&mut self, user: &str, policy: RetentionPolicy) -> Result<(), ()> {
+        let path = get_retention_path(user);
+        std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| {
+            error!("Error creating directory: {}", e);
+        })?;
+        let serialized = serde_json::to_string(&policy).map_err(|_| ())?;
+        std::fs::write(&path, serialized).map_err(|e| {
+            error!("Error writing to file: {}", e);
+        })
+    }
+
+    fn delete_policy(&mut self, user: &str) -> Result<(), ()> {
+        match std::fs::remove_file(get_retention_path(user)) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => {
+                error!("Error removing retention policy: {}", e);
+                Err(())
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::{encryption, get_user_root};
+
+/// A named query that is re-run periodically so the user hears about new
+/// messages that match it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SavedSearchModel {
+    pub name: String,
+    pub query: String,
+    pub embedding: Vec<f32>,
+    pub threshold: f32,
+    /// Only messages newer than this are considered on the next run
+    pub last_checked: i64,
+    /// Messages that were still waiting for their embedding on the last run,
+    /// which are considered again on the next one
+    #[serde(default)]
+    pub unembedded: Vec<String>,
+    pub created_at: i64,
+}
+
+pub trait SavedSearchRepo: Send + Sync {
+    fn save_search(&mut self, user: &str, search: SavedSearchModel)
+        -> Result<SavedSearchModel, ()>;
+    fn get_searches(&self, user: &str) -> Result<Vec<SavedSearchModel>, ()>;
+    fn delete_search(&mut self, user: &str, name: &str) -> Result<(), ()>;
+}
+
+pub struct FsSavedSearchRepo {}
+
+impl FsSavedSearchRepo {
+    pub fn new() -> Self {
+        FsSavedSearchRepo {}
+    }
+}
+
+fn get_searches_path(user: &str) -> std::path::PathBuf {
+    get_user_root(user).join("saved_searches.json")
+}
+
+fn read_searches(user: &str) -> HashMap<String, SavedSearchModel> {
+    match encryption::read_to_string(&get_searches_path(user)) {
+        Ok(content) => match serde_json::from_str(&content) {
+            Ok(searches) => searches,
+            Err(e) => {
+                error!("Error deserializing saved searches: {}", e);
+                HashMap::new()
+            }
+        },
+        Err(_) => HashMap::new(),
+    }
+}
+
+fn write_searches(user: &str, searches: &HashMap<String, SavedSearchModel>) -> Result<(), ()> {
+    let path = get_searches_path(user);
+    std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| {
+        error!("Error creating directory: {}", e);
+    })?;
+    let serialized = serde_json::to_string(searches).map_err(|_| ())?;
+    std::fs::write(&path, encryption::seal(serialized.as_bytes())).map_err(|e| {
+        error!("Error writing to file: {}", e);
+    })
+}
+
+impl SavedSearchRepo for FsSavedSearchRepo {
+    fn save_search(
+        &mut self,
+        user: &str,
+        search: SavedSearchModel,
+    ) -> Result<SavedSearchModel, ()> {
+        let mut searches = read_searches(user);
+        searches.insert(search.name.clone(), search.clone());
+        write_searches(user, &searches)?;
+        Ok(search)
+    }
+
+    fn get_searches(&self, user: &str) -> Result<Vec<SavedSearchModel>, ()> {
+        let mut searches = read_searches(user).into_values().collect::<Vec<_>>();
+        searches.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(searches)
+    }
+
+    fn delete_search(&mut self, user: &str, name: &str) -> Result<(), ()> {
+        let mut searches = read_searches(user);
+        searches.remove(name).ok_or(())?;
+        write_searches(user, &searches)
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use super::{get_directory_size, get_user_root, COLLECTIONS_FOLDER};
+
+// How long a walk of a user's folder is answered from memory
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Bytes a user's folder takes on disk, by what they hold. Message bodies
+/// in the shared content store are not counted, they may belong to several
+/// users.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct StorageUsage {
+    /// Day folders, of the user and of their collections
+    pub messages: u64,
+    /// Day, week and month summaries and thread summaries
+    pub summaries: u64,
+    /// Attributes, the log of their changes and the expired ones
+    pub attributes: u64,
+    /// Search indexes
+    pub indexes: u64,
+    /// Everything else, such as webhooks, feeds and reminders
+    pub other: u64,
+    pub total: u64,
+    /// Unix timestamp of the walk the numbers come from
+    pub computed_at: i64,
+}
+
+pub trait StorageUsageRepo: Send + Sync {
+    /// What the user's folder takes, `None` when they have none
+    fn get_usage(&mut self, user: &str) -> Result<Option<StorageUsage>, ()>;
+}
+
+#[derive(Default)]
+pub struct FsStorageUsageRepo {
+    cache: HashMap<String, (Instant, StorageUsage)>,
+}
+
+impl FsStorageUsageRepo {
+    pub fn new() -> Self {
+        FsStorageUsageRepo::default()
+    }
+}
+
+fn file_size(path: &Path) -> u64 {
+    std::fs::metadata(path)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0)
+}
+
+fn is_day_folder(name: &str) -> bool {
+    chrono::NaiveDate::parse_from_str(name, "%Y-%m-%d").is_ok()
+}
+
+// Adds what is below the user or collection folder at `root` to `usage`
+fn walk(root: &Path, usage: &mut StorageUsage) {
+    let entries = match std::fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name == COLLECTIONS_FOLDER && path.is_dir() {
+            for collection in std::fs::read_dir(&path).into_iter().flatten().flatten() {
+                walk(&collection.path(), usage);
+            }
+            continue;
+        }
+        let size = match path.is_dir() {
+            true => get_directory_size(&path),
+            false => file_size(&path),
+        };
+        match name.as_str() {
+            _ if is_day_folder(&name) => usage.messages += size,
+            "summaries" | "thread_summaries.json" => usage.summaries += size,
+            "attributes.json" | "attribute_events.jsonl" | "expired_attributes.json" => {
+                usage.attributes += size
+            }
+            "vector_index.json" => usage.indexes += size,
+            _ => usage.other += size,
+        }
+        usage.total += size;
+    }
+}
+
+impl StorageUsageRepo for FsStorageUsageRepo {
+    fn get_usage(&mut self, user: &str) -> Result<Option<StorageUsage>, ()> {
+        if let Some((walked, usage)) = self.cache.get(user) {
+            if walked.elapsed() < CACHE_TTL {
+                return Ok(Some(usage.clone()));
+            }
+        }
+        let root = get_user_root(user);
+        if !root.is_dir() {
+            self.cache.remove(user);
+            return Ok(None);
+        }
+        let mut usage = StorageUsage {
+            computed_at: chrono::Utc::now().timestamp(),
+            ..StorageUsage::default()
+        };
+        walk(&root, &mut usage);
+        self.cache
+            .insert(user.to_string(), (Instant::now(), usage.clone()));
+        Ok(Some(usage))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_is_split_by_what_the_files_hold() {
+        let mut repo = FsStorageUsageRepo::new();
+        let username = format!("test_storage_usage_{}", uuid::Uuid::new_v4());
+        assert_eq!(repo.get_usage(&username), Ok(None));
+
+        let root = get_user_root(&username);
+        let notes = root
+            .join(COLLECTIONS_FOLDER)
+            .join("notes")
+            .join("2024-03-01");
+        std::fs::create_dir_all(&notes).unwrap();
+        std::fs::create_dir_all(root.join("2024-03-01")).unwrap();
+        std::fs::create_dir_all(root.join("summaries")).unwrap();
+        std::fs::write(root.join("2024-03-01").join("messages.json"), "[1234]").unwrap();
+        std::fs::write(notes.join("messages.json"), "[]").unwrap();
+        std::fs::write(root.join("summaries").join("2024-03-01.json"), "{}").unwrap();
+        std::fs::write(root.join("attributes.json"), "{\"a\":1}").unwrap();
+        std::fs::write(root.join("vector_index.json"), "{}").unwrap();
+        std::fs::write(root.join("webhooks.json"), "[]").unwrap();
+
+        let usage = repo.get_usage(&username).unwrap().unwrap();
+        assert_eq!(usage.messages, 8);
+        assert_eq!(usage.summaries, 2);
+        assert_eq!(usage.attributes, 7);
+        assert_eq!(usage.indexes, 2);
+        assert_eq!(usage.other, 2);
+        assert_eq!(usage.total, 21);
+
+        // answered from memory until the cache runs out
+        std::fs::write(root.join("reminders.json"), "[]").unwrap();
+        assert_eq!(repo.get_usage(&username).unwrap().unwrap(), usage);
+    }
+}
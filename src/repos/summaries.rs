@@ -0,0 +1,179 @@
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::{encryption, get_user_root};
+
+/// LLM summary of everything said on one day, or of a week or month rolled
+/// up from the daily summaries
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SummaryModel {
+    /// `YYYY-MM-DD` for a day, `YYYY-Www` for a week and `YYYY-MM` for a month
+    pub date: String,
+    pub summary: String,
+    /// Number of messages the summary was written from
+    pub message_count: usize,
+    pub created_at: i64,
+    /// Model that wrote the summary, none when there was nothing to summarize
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Version of the prompt the summary was written with
+    #[serde(default)]
+    pub prompt_version: Option<u32>,
+    /// Embedding of `summary`, searched alongside the messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedding: Option<Vec<f32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedding_model: Option<String>,
+}
+
+impl SummaryModel {
+    /// The summary as it is returned to clients, which have no use for the
+    /// embedding
+    pub fn without_embedding(self) -> SummaryModel {
+        SummaryModel {
+            embedding: None,
+            embedding_model: None,
+            ..self
+        }
+    }
+}
+
+pub trait SummaryRepo: Send + Sync {
+    fn save_summary(&mut self, user: &str, summary: SummaryModel) -> Result<SummaryModel, ()>;
+    fn get_summary(&self, user: &str, date: NaiveDate) -> Result<SummaryModel, ()>;
+    /// A stored week or month rollup, by the period in its `date`
+    fn get_rollup(&self, user: &str, period: &str) -> Result<SummaryModel, ()>;
+    /// Every stored summary and rollup of the user, ordered by `date`
+    fn get_summaries(&self, user: &str) -> Result<Vec<SummaryModel>, ()>;
+    /// The stored daily summaries from `from` to `to`, both included
+    fn list_summaries(
+        &self,
+        user: &str,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<SummaryModel>, ()> {
+        Ok(self
+            .get_summaries(user)?
+            .into_iter()
+            .filter(|summary| {
+                NaiveDate::parse_from_str(&summary.date, "%Y-%m-%d")
+                    .is_ok_and(|date| date >= from && date <= to)
+            })
+            .collect())
+    }
+}
+
+pub struct FsSummaryRepo {}
+
+impl FsSummaryRepo {
+    pub fn new() -> Self {
+        FsSummaryRepo {}
+    }
+}
+
+fn get_summary_path(user: &str, date: &str) -> std::path::PathBuf {
+    get_user_root(user)
+        .join("summaries")
+        .join(format!("{}.json", date))
+}
+
+fn read_summary(user: &str, date: &str) -> Result<SummaryModel, ()> {
+    let content = encryption::read_to_string(&get_summary_path(user, date)).map_err(|_| ())?;
+    serde_json::from_str(&content).map_err(|e| {
+        error!("Error deserializing summary: {}", e);
+    })
+}
+
+impl SummaryRepo for FsSummaryRepo {
+    fn save_summary(&mut self, user: &str, summary: SummaryModel) -> Result<SummaryModel, ()> {
+        let path = get_summary_path(user, &summary.date);
+        std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| {
+            error!("Error creating directory: {}", e);
+        })?;
+        let serialized = serde_json::to_string(&summary).map_err(|_| ())?;
+        match super::write_atomic(&path, &encryption::seal(serialized.as_bytes())) {
+            Ok(_) => Ok(summary),
+            Err(e) => {
+                error!("Error writing to file: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    fn get_summary(&self, user: &str, date: NaiveDate) -> Result<SummaryModel, ()> {
+        read_summary(user, &date.format("%Y-%m-%d").to_string())
+    }
+
+    fn get_rollup(&self, user: &str, period: &str) -> Result<SummaryModel, ()> {
+        read_summary(user, period)
+    }
+
+    fn get_summaries(&self, user: &str) -> Result<Vec<SummaryModel>, ()> {
+        let entries = match std::fs::read_dir(get_user_root(user).join("summaries")) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => {
+                error!("Error reading summaries: {}", e);
+                return Err(());
+            }
+        };
+        let mut summaries = entries
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                read_summary(user, name.strip_suffix(".json")?).ok()
+            })
+            .collect::<Vec<SummaryModel>>();
+        summaries.sort_by(|a, b| a.date.cmp(&b.date));
+        Ok(summaries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(date: &str) -> SummaryModel {
+        SummaryModel {
+            date: date.to_string(),
+            summary: format!("Summary of {}", date),
+            message_count: 2,
+            created_at: 0,
+            model: Some("mock".to_string()),
+            prompt_version: Some(1),
+            embedding: None,
+            embedding_model: None,
+        }
+    }
+
+    #[test]
+    fn test_list_summaries_keeps_days_in_range() {
+        let user = format!("test_summaries_{}", uuid::Uuid::new_v4());
+        let mut repo = FsSummaryRepo::new();
+        for date in [
+            "2024-02-28",
+            "2024-03-01",
+            "2024-03-03",
+            "2024-W09",
+            "2024-03",
+        ] {
+            repo.save_summary(&user, summary(date)).unwrap();
+        }
+
+        let listed = repo
+            .list_summaries(
+                &user,
+                NaiveDate::from_ymd_opt(2024, 3, 1).unwrap(),
+                NaiveDate::from_ymd_opt(2024, 3, 3).unwrap(),
+            )
+            .unwrap();
+        let dates = listed
+            .iter()
+            .map(|s| s.date.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(dates, vec!["2024-03-01", "2024-03-03"]);
+        assert_eq!(listed[0].model.as_deref(), Some("mock"));
+        assert_eq!(listed[0].prompt_version, Some(1));
+    }
+}
@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::{encryption, get_user_root};
+
+/// Rule recorded when a user asks to forget something, so that content
+/// similar to `description` keeps being excluded from context
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SuppressionModel {
+    pub id: String,
+    pub description: String,
+    pub embedding: Vec<f32>,
+    pub threshold: f32,
+    pub created_at: i64,
+}
+
+pub trait SuppressionRepo: Send + Sync {
+    fn save_suppression(
+        &mut self,
+        user: &str,
+        suppression: SuppressionModel,
+    ) -> Result<SuppressionModel, ()>;
+    fn get_suppressions(&self, user: &str) -> Result<Vec<SuppressionModel>, ()>;
+}
+
+pub struct FsSuppressionRepo {}
+
+impl FsSuppressionRepo {
+    pub fn new() -> Self {
+        FsSuppressionRepo {}
+    }
+}
+
+fn get_suppressions_path(user: &str) -> std::path::PathBuf {
+    get_user_root(user).join("suppressions.json")
+}
+
+impl SuppressionRepo for FsSuppressionRepo {
+    fn save_suppression(
+        &mut self,
+        user: &str,
+        suppression: SuppressionModel,
+    ) -> Result<SuppressionModel, ()> {
+        let mut suppressions = self.get_suppressions(user)?;
+        suppressions.push(suppression.clone());
+
+        let path = get_suppressions_path(user);
+        std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| {
+            error!("Error creating directory: {}", e);
+        })?;
+        let serialized = serde_json::to_string(&suppressions).map_err(|_| ())?;
+        match std::fs::write(&path, encryption::seal(serialized.as_bytes())) {
+            Ok(_) => Ok(suppression),
+            Err(e) => {
+                error!("Error writing to file: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    fn get_suppressions(&self, user: &str) -> Result<Vec<SuppressionModel>, ()> {
+        match encryption::read_to_string(&get_suppressions_path(user)) {
+            Ok(content) => serde_json::from_str(&content).map_err(|e| {
+                error!("Error deserializing suppressions: {}", e);
+            }),
+            Err(_) => Ok(vec![]),
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::{encryption, get_user_root};
+
+/// LLM summary standing in for the turns of a conversation that went quiet
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ThreadSummaryModel {
+    pub conversation_id: String,
+    pub summary: String,
+    /// Timestamp of the newest message covered by the summary
+    pub last_message_timestamp: i64,
+    pub created_at: i64,
+}
+
+pub trait ThreadSummaryRepo: Send + Sync {
+    fn save_summary(
+        &mut self,
+        user: &str,
+        summary: ThreadSummaryModel,
+    ) -> Result<ThreadSummaryModel, ()>;
+    fn get_summaries(&self, user: &str) -> Result<Vec<ThreadSummaryModel>, ()>;
+}
+
+pub struct FsThreadSummaryRepo {}
+
+impl FsThreadSummaryRepo {
+    pub fn new() -> Self {
+        FsThreadSummaryRepo {}
+    }
+}
+
+fn get_summaries_path(user: &str) -> std::path::PathBuf {
+    get_user_root(user).join("thread_summaries.json")
+}
+
+fn read_summaries(user: &str) -> HashMap<String, ThreadSummaryModel> {
+    match encryption::read_to_string(&get_summaries_path(user)) {
+        Ok(content) => match serde_json::from_str(&content) {
+            Ok(summaries) => summaries,
+            Err(e) => {
+                error!("Error deserializing thread summaries: {}", e);
+                HashMap::new()
+            }
+        },
+        Err(_) => HashMap::new(),
+    }
+}
+
+impl ThreadSummaryRepo for FsThreadSummaryRepo {
+    fn save_summary(
+        &mut self,
+        user: &str,
+        summary: ThreadSummaryModel,
+    ) -> Result<ThreadSummaryModel, ()> {
+        let mut summaries = read_summaries(user);
+        summaries.insert(summary.conversation_id.clone(), summary.clone());
+
+        let path = get_summaries_path(user);
+        std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| {
+            error!("Error creating directory: {}", e);
+        })?;
+        let serialized = serde_json::to_string(&summaries).map_err(|_| ())?;
+        match std::fs::write(&path, encryption::seal(serialized.as_bytes())) {
+            Ok(_) => Ok(summary),
+            Err(e) => {
+                error!("Error writing to file: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    fn get_summaries(&self, user: &str) -> Result<Vec<ThreadSummaryModel>, ()> {
+        Ok(read_summaries(user).into_values().collect())
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::error;
+
+use super::get_storage_root;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    /// Access to the owning user's data only
+    User,
+    /// Access to every user's data and to token management
+    Admin,
+}
+
+/// An issued API token. Only a digest of the secret is stored, the secret
+/// itself is shown once when the token is created.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TokenModel {
+    pub id: String,
+    pub username: String,
+    pub name: String,
+    pub scope: TokenScope,
+    pub created_at: i64,
+}
+
+pub fn digest(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+pub trait TokenRepo: Send + Sync {
+    /// Issues a new token, returning its secret alongside the stored model
+    fn create_token(
+        &mut self,
+        username: &str,
+        name: &str,
+        scope: TokenScope,
+    ) -> Result<(String, TokenModel), ()>;
+    fn find_token(&self, secret: &str) -> Option<TokenModel>;
+    fn get_tokens(&self, username: &str) -> Result<Vec<TokenModel>, ()>;
+    fn revoke_token(&mut self, username: &str, id: &str) -> Result<(), ()>;
+}
+
+pub struct FsTokenRepo {}
+
+impl FsTokenRepo {
+    pub fn new() -> Self {
+        FsTokenRepo {}
+    }
+}
+
+// Tokens are looked up by secret before the user is known, so they live in
+// one file keyed by digest rather than in each user's folder
+fn get_tokens_path() -> std::path::PathBuf {
+    get_storage_root().join(".auth").join("tokens.json")
+}
+
+fn read_tokens() -> HashMap<String, TokenModel> {
+    match std::fs::read_to_string(get_tokens_path()) {
+        Ok(content) => match serde_json::from_str(&content) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                error!("Error deserializing tokens: {}", e);
+                HashMap::new()
+            }
+        },
+        Err(_) => HashMap::new(),
+    }
+}
+
+fn write_tokens(tokens: &HashMap<String, TokenModel>) -> Result<(), ()> {
+    let path = get_tokens_path();
+    std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| {
+        error!("Error creating directory: {}", e);
+    })?;
+    let serialized = serde_json::to_string(tokens).map_err(|_| ())?;
+    std::fs::write(&path, serialized).map_err(|e| {
+        error!("Error writing to file: {}", e);
+    })
+}
+
+impl TokenRepo for FsTokenRepo {
+    fn create_token(
+        &mut self,
+        username: &str,
+        name: &str,
+        scope: TokenScope,
+    ) -> Result<(String, TokenModel), ()> {
+        let secret = format!("mun_{}", uuid::Uuid::new_v4().simple());
+        let digest = digest(&secret);
+        let token = TokenModel {
+            id: digest[..12].to_string(),
+            username: username.to_string(),
+            name: name.to_string(),
+            scope,
+            created_at: chrono::Utc::now().timestamp(),
+        };
+        let mut tokens = read_tokens();
+        tokens.insert(digest, token.clone());
+        write_tokens(&tokens)?;
+        Ok((secret, token))
+    }
+
+    fn find_token(&self, secret: &str) -> Option<TokenModel> {
+        read_tokens().remove(&digest(secret))
+    }
+
+    fn get_tokens(&self, username: &str) -> Result<Vec<TokenModel>, ()> {
+        let mut tokens = read_tokens()
+            .into_values()
+            .filter(|token| token.username == username)
+            .collect::<Vec<TokenModel>>();
+        tokens.sort_by_key(|token| token.created_at);
+        Ok(tokens)
+    }
+
+    fn revoke_token(&mut self, username: &str, id: &str) -> Result<(), ()> {
+        let mut tokens = read_tokens();
+        let before = tokens.len();
+        tokens.retain(|_, token| !(token.username == username && token.id == id));
+        if tokens.len() == before {
+            return Err(());
+        }
+        write_tokens(&tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_created_token_can_be_found_until_revoked() {
+        let mut repo = FsTokenRepo::new();
+        let username = format!("test_tokens_{}", uuid::Uuid::new_v4());
+
+        let (secret, token) = repo
+            .create_token(&username, "laptop", TokenScope::User)
+            .unwrap();
+        assert_eq!(repo.find_token(&secret).unwrap().username, username);
+        assert!(repo.find_token("mun_guess").is_none());
+        assert_eq!(repo.get_tokens(&username).unwrap().len(), 1);
+
+        repo.revoke_token(&username, &token.id).unwrap();
+        assert!(repo.find_token(&secret).is_none());
+        assert!(repo.revoke_token(&username, &token.id).is_err());
+    }
+}
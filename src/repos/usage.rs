@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::get_user_root;
+use crate::clients::chat::Usage;
+
+/// Tokens a user spent on one day
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageModel {
+    pub date: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    /// Number of completions the tokens were spent on
+    pub requests: u64,
+}
+
+pub trait UsageRepo: Send + Sync {
+    /// Adds `usage` to the user's total for `date`, returning the new total
+    fn record_usage(
+        &mut self,
+        user: &str,
+        date: NaiveDate,
+        usage: &Usage,
+    ) -> Result<UsageModel, ()>;
+    /// The user's total for `date`, zero when nothing was spent
+    fn get_usage(&self, user: &str, date: NaiveDate) -> Result<UsageModel, ()>;
+    /// Every day the user spent tokens on, oldest first
+    fn get_all_usage(&self, user: &str) -> Result<Vec<UsageModel>, ()>;
+}
+
+pub struct FsUsageRepo {}
+
+impl FsUsageRepo {
+    pub fn new() -> Self {
+        FsUsageRepo {}
+    }
+}
+
+fn get_usage_path(user: &str) -> std::path::PathBuf {
+    get_user_root(user).join("usage.json")
+}
+
+fn read_usage(user: &str) -> Result<BTreeMap<String, UsageModel>, ()> {
+    let content = match std::fs::read_to_string(get_usage_path(user)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => {
+            error!("Error reading usage: {}", e);
+            return Err(());
+        }
+    };
+    serde_json::from_str(&content).map_err(|e| {
+        error!("Error deserializing usage: {}", e);
+    })
+}
+
+impl UsageRepo for FsUsageRepo {
+    fn record_usage(
+        &mut self,
+        user: &str,
+        date: NaiveDate,
+        usage: &Usage,
+    ) -> Result<UsageModel, ()> {
+        let mut days = read_usage(user)?;
+        let key = date.format("%Y-%m-%d").to_string();
+        let day = days.entry(key.clone()).or_insert_with(|| UsageModel {
+            date: key,
+            ..UsageModel::default()
+        });
+        day.prompt_tokens += usage.prompt_tokens;
+        day.completion_tokens += usage.completion_tokens;
+        day.total_tokens += usage.total_tokens;
+        day.requests += 1;
+        let day = day.clone();
+
+        let path = get_usage_path(user);
+        std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| {
+            error!("Error creating directory: {}", e);
+        })?;
+        let serialized = serde_json::to_string(&days).map_err(|_| ())?;
+        match std::fs::write(&path, serialized) {
+            Ok(_) => Ok(day),
+            Err(e) => {
+                error!("Error writing to file: {}", e);
+                Err(())
+            }
+        }
+    }
+
+    fn get_usage(&self, user: &str, date: NaiveDate) -> Result<UsageModel, ()> {
+        let key = date.format("%Y-%m-%d").to_string();
+        Ok(read_usage(user)?
+            .remove(&key)
+            .unwrap_or_else(|| UsageModel {
+                date: key,
+                ..UsageModel::default()
+            }))
+    }
+
+    fn get_all_usage(&self, user: &str) -> Result<Vec<UsageModel>, ()> {
+        Ok(read_usage(user)?.into_values().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_adds_up_per_day() {
+        let mut repo = FsUsageRepo::new();
+        let username = format!("test_usage_{}", uuid::Uuid::new_v4());
+        let monday = NaiveDate::from_ymd_opt(2024, 3, 4).unwrap();
+        let tuesday = NaiveDate::from_ymd_opt(2024, 3, 5).unwrap();
+        let usage = Usage {
+            prompt_tokens: 100,
+            completion_tokens: 20,
+            total_tokens: 120,
+        };
+
+        assert_eq!(repo.get_usage(&username, monday).unwrap().total_tokens, 0);
+        repo.record_usage(&username, tuesday, &usage).unwrap();
+        repo.record_usage(&username, monday, &usage).unwrap();
+        let total = repo.record_usage(&username, monday, &usage).unwrap();
+        assert_eq!(total.prompt_tokens, 200);
+        assert_eq!(total.total_tokens, 240);
+        assert_eq!(total.requests, 2);
+
+        let days = repo.get_all_usage(&username).unwrap();
+        assert_eq!(days.len(), 2);
+        assert_eq!(days[0].date, "2024-03-04");
+        assert_eq!(days[1].total_tokens, 120);
+    }
+}
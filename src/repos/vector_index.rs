@@ -0,0 +1,151 @@
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::messages::cosine_similarity;
+
+/// How embeddings searches pick the messages they score
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VectorIndexKind {
+    /// Every message of the user is scored
+    Exact,
+    /// Only messages in the lists closest to the query are scored
+    Ivf,
+}
+
+impl VectorIndexKind {
+    pub fn from_env() -> VectorIndexKind {
+        match std::env::var("VECTOR_INDEX").as_deref() {
+            Ok("exact") => VectorIndexKind::Exact,
+            _ => VectorIndexKind::Ivf,
+        }
+    }
+}
+
+/// Below this many indexed messages an exact scan is fast enough and the
+/// index is not consulted
+pub const MIN_INDEXED: usize = 1024;
+
+fn env_usize(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(default)
+}
+
+/// Inverted file index over message embeddings. The first vectors seed the
+/// lists, later ones join the list with the nearest centroid which then
+/// moves towards them, so the index can grow one message at a time.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct IvfIndex {
+    centroids: Vec<Vec<f32>>,
+    lists: Vec<Vec<String>>,
+}
+
+impl IvfIndex {
+    pub fn len(&self) -> usize {
+        self.lists.iter().map(|list| list.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lists.iter().all(|list| list.is_empty())
+    }
+
+    fn nearest_lists(&self, vector: &[f32], count: usize) -> Vec<usize> {
+        let mut ranked = self
+            .centroids
+            .iter()
+            .enumerate()
+            .filter(|(_, centroid)| centroid.len() == vector.len())
+            .map(|(i, centroid)| (cosine_similarity(centroid, vector), i))
+            .collect::<Vec<(f32, usize)>>();
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+        ranked.into_iter().take(count).map(|(_, i)| i).collect()
+    }
+
+    pub fn insert(&mut self, hash: &str, embedding: &[f32]) {
+        if self.centroids.len() < env_usize("VECTOR_INDEX_LISTS", 64) {
+            self.centroids.push(embedding.to_vec());
+            self.lists.push(vec![hash.to_string()]);
+            return;
+        }
+        let nearest = match self.nearest_lists(embedding, 1).first() {
+            Some(nearest) => *nearest,
+            None => return,
+        };
+        let list = &mut self.lists[nearest];
+        list.push(hash.to_string());
+        let count = list.len() as f32;
+        for (c, e) in self.centroids[nearest].iter_mut().zip(embedding) {
+            *c += (e - *c) / count;
+        }
+    }
+
+    pub fn remove(&mut self, hash: &str) {
+        for list in self.lists.iter_mut() {
+            list.retain(|indexed| indexed != hash);
+        }
+    }
+
+    /// Hashes of the messages in the lists closest to `query`
+    pub fn candidates(&self, query: &[f32]) -> HashSet<String> {
+        self.nearest_lists(query, env_usize("VECTOR_INDEX_PROBES", 8))
+            .into_iter()
+            .flat_map(|i| self.lists[i].iter().cloned())
+            .collect()
+    }
+}
+
+fn get_index_path(user: &str) -> std::path::PathBuf {
+    super::get_user_root(user).join("vector_index.json")
+}
+
+pub fn load(user: &str) -> Option<IvfIndex> {
+    let content = std::fs::read_to_string(get_index_path(user)).ok()?;
+    match serde_json::from_str(&content) {
+        Ok(index) => Some(index),
+        Err(e) => {
+            error!("Discarding unreadable vector index for {}: {}", user, e);
+            None
+        }
+    }
+}
+
+pub fn save(user: &str, index: &IvfIndex) {
+    let path = get_index_path(user);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let serialized = serde_json::to_string(index).unwrap();
+    if let Err(e) = std::fs::write(path, serialized) {
+        error!("Error writing to file: {}", e)
+    }
+}
+
+pub fn remove(user: &str) {
+    let _ = std::fs::remove_file(get_index_path(user));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates_come_from_nearest_lists() {
+        let mut index = IvfIndex::default();
+        for i in 0..64 {
+            let angle = i as f32 * std::f32::consts::PI / 32.0;
+            index.insert(&format!("seed-{}", i), &[angle.cos(), angle.sin()]);
+        }
+        index.insert("east", &[1.0, 0.01]);
+        index.insert("west", &[-1.0, 0.01]);
+        assert_eq!(index.len(), 66);
+
+        let candidates = index.candidates(&[1.0, 0.0]);
+        assert!(candidates.contains("east"));
+        assert!(!candidates.contains("west"));
+
+        index.remove("east");
+        assert!(!index.candidates(&[1.0, 0.0]).contains("east"));
+    }
+}
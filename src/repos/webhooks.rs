@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::get_user_root;
+
+// Deliveries kept per webhook, older ones are dropped
+const KEPT_DELIVERIES: usize = 20;
+
+/// A URL that is told about a user's memory events
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WebhookModel {
+    pub id: String,
+    pub url: String,
+    /// Key the payloads are signed with, only shown when the webhook is
+    /// registered
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub secret: String,
+    /// Event types the webhook wants, every one when empty
+    #[serde(default)]
+    pub events: Vec<String>,
+    pub created_at: i64,
+    /// The latest deliveries, newest first
+    #[serde(default)]
+    pub deliveries: Vec<DeliveryModel>,
+}
+
+impl WebhookModel {
+    pub fn wants(&self, event: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|wanted| wanted == event)
+    }
+
+    pub fn without_secret(self) -> WebhookModel {
+        WebhookModel {
+            secret: String::new(),
+            ..self
+        }
+    }
+}
+
+/// One event sent to a webhook, with all its retries
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct DeliveryModel {
+    pub event: String,
+    pub delivered: bool,
+    pub attempts: u32,
+    /// Status of the last answer, absent when there was none
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub timestamp: i64,
+}
+
+pub trait WebhookRepo: Send + Sync {
+    fn save_webhook(&mut self, user: &str, webhook: WebhookModel) -> Result<WebhookModel, ()>;
+    fn get_webhooks(&self, user: &str) -> Result<Vec<WebhookModel>, ()>;
+    fn delete_webhook(&mut self, user: &str, id: &str) -> Result<(), ()>;
+    fn record_delivery(&mut self, user: &str, id: &str, delivery: DeliveryModel) -> Result<(), ()>;
+}
+
+pub struct FsWebhookRepo {}
+
+impl FsWebhookRepo {
+    pub fn new() -> Self {
+        FsWebhookRepo {}
+    }
+}
+
+fn get_webhooks_path(user: &str) -> std::path::PathBuf {
+    get_user_root(user).join("webhooks.json")
+}
+
+fn read_webhooks(user: &str) -> HashMap<String, WebhookModel> {
+    match std::fs::read_to_string(get_webhooks_path(user)) {
+        Ok(content) => match serde_json::from_str(&content) {
+            Ok(webhooks) => webhooks,
+            Err(e) => {
+                error!("Error deserializing webhooks: {}", e);
+                HashMap::new()
+            }
+        },
+        Err(_) => HashMap::new(),
+    }
+}
+
+fn write_webhooks(user: &str, webhooks: &HashMap<String, WebhookModel>) -> Result<(), ()> {
+    let path = get_webhooks_path(user);
+    std::fs::create_dir_all(path.parent().unwrap()).map_err(|e| {
+        error!("Error creating directory: {}", e);
+    })?;
+    let serialized = serde_json::to_string(webhooks).map_err(|_| ())?;
+    std::fs::write(&path, serialized).map_err(|e| {
+        error!("Error writing to file: {}", e);
+    })
+}
+
+impl WebhookRepo for FsWebhookRepo {
+    fn save_webhook(&mut self, user: &str, webhook: WebhookModel) -> Result<WebhookModel, ()> {
+        let mut webhooks = read_webhooks(user);
+        webhooks.insert(webhook.id.clone(), webhook.clone());
+        write_webhooks(user, &webhooks)?;
+        Ok(webhook)
+    }
+
+    fn get_webhooks(&self, user: &str) -> Result<Vec<WebhookModel>, ()> {
+        let mut webhooks = read_webhooks(user).into_values().collect::<Vec<_>>();
+        webhooks.sort_by_key(|webhook| webhook.created_at);
+        Ok(webhooks)
+    }
+
+    fn delete_webhook(&mut self, user: &str, id: &str) -> Result<(), ()> {
+        let mut webhooks = read_webhooks(user);
+        webhooks.remove(id).ok_or(())?;
+        write_webhooks(user, &webhooks)
+    }
+
+    fn record_delivery(&mut self, user: &str, id: &str, delivery: DeliveryModel) -> Result<(), ()> {
+        let mut webhooks = read_webhooks(user);
+        let webhook = webhooks.get_mut(id).ok_or(())?;
+        webhook.deliveries.insert(0, delivery);
+        webhook.deliveries.truncate(KEPT_DELIVERIES);
+        write_webhooks(user, &webhooks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deliveries_are_kept_newest_first() {
+        let mut repo = FsWebhookRepo::new();
+        let username = format!("test_webhooks_{}", uuid::Uuid::new_v4());
+        let webhook = WebhookModel {
+            id: "hook".to_string(),
+            url: "http://localhost:9000/hook".to_string(),
+            secret: "s3cret".to_string(),
+            events: vec!["message_saved".to_string()],
+            created_at: 0,
+            deliveries: vec![],
+        };
+        repo.save_webhook(&username, webhook).unwrap();
+
+        for timestamp in 0..25 {
+            let delivery = DeliveryModel {
+                event: "message_saved".to_string(),
+                delivered: true,
+                attempts: 1,
+                status: Some(200),
+                error: None,
+                timestamp,
+            };
+            repo.record_delivery(&username, "hook", delivery).unwrap();
+        }
+        let webhooks = repo.get_webhooks(&username).unwrap();
+        let deliveries = &webhooks[0].deliveries;
+        assert_eq!(deliveries.len(), KEPT_DELIVERIES);
+        assert_eq!(deliveries[0].timestamp, 24);
+        assert!(webhooks[0].wants("message_saved"));
+        assert!(!webhooks[0].wants("summary_ready"));
+
+        repo.delete_webhook(&username, "hook").unwrap();
+        assert!(repo
+            .record_delivery(&username, "hook", deliveries[0].clone())
+            .is_err());
+    }
+}
@@ -1,98 +1,716 @@
-use std::sync::mpsc::{self, Receiver, Sender};
-use std::{sync::Arc, time::Instant};
-use tokio::sync::Mutex;
-use tracing::info;
+use async_trait::async_trait;
+use chrono::{DateTime, Datelike, Duration, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, sync::Arc};
+use tokio::sync::{watch, Mutex};
+use tracing::{error, info, warn};
 
-use crate::handlers::events::MessageEvent;
+use crate::clients::embeddings::EmbeddingsClient;
+use crate::metrics::StorageMetrics;
+use crate::repos::attributes::AttributeRepo;
+use crate::repos::messages::MessageRepo;
+use crate::services::extraction::ExtractionService;
+use crate::services::feeds::FeedService;
+use crate::services::reembed::ReembedService;
+use crate::services::reminders::ReminderService;
+use crate::services::replication::ReplicationService;
+use crate::services::retention::RetentionService;
+use crate::services::saved_searches::SavedSearchService;
+use crate::services::summary::SummaryService;
+use crate::services::telegram::TelegramBot;
+use crate::services::threads::ThreadService;
 
+/// When a job runs, written as the usual five cron fields: minute, hour, day
+/// of month, month and day of week. Fields take `*`, numbers, ranges like
+/// `1-5`, steps like `*/15` and lists of those separated by commas.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // cron treats a restricted day of month and day of week as alternatives
+    any_day: bool,
+    any_weekday: bool,
+}
+
+// Bit mask of the values `field` allows between `min` and `max`
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut mask = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|s| *s > 0)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+                None => {
+                    let value = range.parse().ok()?;
+                    // a single value with a step runs from there to the end
+                    (value, if part.contains('/') { max } else { value })
+                }
+            },
+        };
+        if start < min || end > max || start > end {
+            return None;
+        }
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Some(mask)
+}
+
+impl CronSchedule {
+    pub fn parse(expression: &str) -> Option<CronSchedule> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            expression => expression,
+        };
+        let fields = expression.split_whitespace().collect::<Vec<&str>>();
+        if fields.len() != 5 {
+            return None;
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // both 0 and 7 are sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Some(CronSchedule {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    pub fn matches(&self, time: &DateTime<Utc>) -> bool {
+        let has = |mask: u64, value: u32| mask & (1 << value) != 0;
+        let day = has(self.days, time.day());
+        let weekday = has(self.weekdays, time.weekday().num_days_from_sunday());
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        has(self.minutes, time.minute())
+            && has(self.hours, time.hour())
+            && has(self.months, time.month())
+            && day
+    }
+
+    /// The first minute after `time` the schedule matches, within five years
+    pub fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut next = time.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let limit = time + Duration::days(5 * 366);
+        while next < limit {
+            if !self.months_match(&next) {
+                // skip ahead to the first day of the next month
+                let (year, month) = match next.month() {
+                    12 => (next.year() + 1, 1),
+                    month => (next.year(), month + 1),
+                };
+                next = next
+                    .with_day(1)?
+                    .with_hour(0)?
+                    .with_minute(0)?
+                    .with_month(month)?
+                    .with_year(year)?;
+                continue;
+            }
+            if self.matches(&next) {
+                return Some(next);
+            }
+            next += Duration::minutes(1);
+        }
+        None
+    }
+
+    fn months_match(&self, time: &DateTime<Utc>) -> bool {
+        self.months & (1 << time.month()) != 0
+    }
+}
+
+/// Work the scheduler runs on a cron schedule
+#[async_trait]
+pub trait Job: Send + Sync {
+    async fn run(&self) -> Result<(), ()>;
+}
+
+/// Runs registered jobs whenever their schedule comes up, until shut down
 pub struct Scheduler {
-    tasks: Arc<Mutex<Vec<(Instant, MessageEvent)>>>,
-    sender: Sender<MessageEvent>,
-    receiver: Arc<Mutex<Receiver<MessageEvent>>>,
-    stop: Arc<Mutex<bool>>,
-    sleep_duration: Arc<Mutex<u64>>
+    jobs: Vec<(&'static str, CronSchedule, Arc<dyn Job>)>,
+    statuses: Arc<JobStatuses>,
 }
 
 impl Scheduler {
-    pub fn new(sleep_duration: u64) -> Self {
-        let (sender, receiver) = mpsc::channel();
-        let tasks = Arc::new(Mutex::new(Vec::new()));
+    pub fn new(statuses: Arc<JobStatuses>) -> Self {
         Scheduler {
-            tasks,
-            sender,
-            receiver: Arc::new(Mutex::new(receiver)),
-            stop: Arc::new(Mutex::new(false)),
-            sleep_duration: Arc::new(Mutex::new(sleep_duration))
+            jobs: vec![],
+            statuses,
+        }
+    }
+
+    /// Adds `job` when `expression` is a valid schedule, which is read from
+    /// the environment variable named after the job, e.g.
+    /// `SCHEDULE_INDEX_COMPACTION`, falling back to `default`. Jobs without
+    /// either, or set to `off`, are not scheduled.
+    pub fn register(&mut self, name: &'static str, default: Option<&str>, job: Arc<dyn Job>) {
+        let variable = format!("SCHEDULE_{}", name.to_uppercase());
+        let expression = match std::env::var(&variable) {
+            Ok(expression) => expression,
+            Err(_) => match default {
+                Some(default) => default.to_string(),
+                None => return,
+            },
+        };
+        if expression.trim() == "off" {
+            return;
         }
+        match CronSchedule::parse(&expression) {
+            Some(schedule) => {
+                info!("Scheduled {} at \"{}\"", name, expression);
+                self.jobs.push((name, schedule, job));
+            }
+            None => error!("Invalid schedule \"{}\" in {}", expression, variable),
+        }
+    }
+
+    /// Starts every job. Once `shutdown` turns true no new runs start, runs
+    /// already in progress are finished before the returned task completes.
+    pub fn start(self, shutdown: watch::Receiver<bool>) -> tokio::task::JoinHandle<()> {
+        let runners = self
+            .jobs
+            .into_iter()
+            .map(|(name, schedule, job)| {
+                let statuses = self.statuses.clone();
+                let mut shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    loop {
+                        let now = Utc::now();
+                        let next = match schedule.next_after(now) {
+                            Some(next) => next,
+                            None => {
+                                warn!("Schedule of {} never comes up", name);
+                                return;
+                            }
+                        };
+                        let wait = (next - now).to_std().unwrap_or_default();
+                        tokio::select! {
+                            _ = tokio::time::sleep(wait) => {}
+                            _ = shutdown.wait_for(|stop| *stop) => return,
+                        }
+                        let ran = job.run().await;
+                        statuses.record(name, ran.is_ok());
+                        if ran.is_err() {
+                            error!("Scheduled job {} failed", name);
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<tokio::task::JoinHandle<()>>>();
+        tokio::spawn(async move {
+            futures::future::join_all(runners).await;
+        })
     }
+}
+
+/// Rebuilds the vector indexes so removed messages stop taking up lists
+pub struct IndexCompactionJob {
+    pub message_repo: Arc<Mutex<dyn MessageRepo>>,
+}
 
-    pub async fn add_task(&mut self, when: Instant, task: MessageEvent) {
-        self.tasks.lock().await.push((when, task));
+#[async_trait]
+impl Job for IndexCompactionJob {
+    async fn run(&self) -> Result<(), ()> {
+        let compacted = self.message_repo.lock().await.compact_index().await?;
+        info!("Compacted vector indexes of {} users", compacted);
+        Ok(())
     }
+}
+
+/// Compresses the day folders of every user that are older than `days`
+pub struct ColdStorageJob {
+    pub message_repo: Arc<Mutex<dyn MessageRepo>>,
+    pub days: u32,
+}
 
-    pub async fn stop(&self) {
-        let mut stop = self.stop.lock().await;
-        *stop = true;
+#[async_trait]
+impl Job for ColdStorageJob {
+    async fn run(&self) -> Result<(), ()> {
+        let before = Utc::now().date_naive() - Duration::days(self.days as i64);
+        let compressed = self
+            .message_repo
+            .lock()
+            .await
+            .compress_days(before)
+            .await
+            .map_err(|e| error!("Error compressing day folders: {}", e))?;
+        info!(
+            "Compressed {} day folders from before {}",
+            compressed, before
+        );
+        Ok(())
     }
+}
 
-    pub async fn get_task_count(&self) -> usize {
-        self.tasks.lock().await.len()
+/// Summarizes the day that just ended for every user who talked on it
+pub struct DailySummaryJob {
+    pub summary_service: SummaryService,
+}
+
+#[async_trait]
+impl Job for DailySummaryJob {
+    async fn run(&self) -> Result<(), ()> {
+        let yesterday = Utc::now().date_naive() - Duration::days(1);
+        let written = self
+            .summary_service
+            .summarize_users_for_date(yesterday)
+            .await?;
+        info!("Wrote {} daily summaries for {}", written, yesterday);
+        Ok(())
     }
+}
 
-    pub async fn start(&mut self) {
-        let stop = self.stop.clone();
-        let tasks = self.tasks.clone();
-        let sleep_duration = self.sleep_duration.clone();
+/// Sends every Telegram chat the summary of the day that just ended
+pub struct TelegramSummaryJob {
+    pub bot: Arc<TelegramBot>,
+}
 
-        tokio::spawn(async move {
-            loop {
-                let mut tasks = tasks.lock().await;
-                let stop = stop.lock().await;
-                let sleep_duration = sleep_duration.lock().await;
+#[async_trait]
+impl Job for TelegramSummaryJob {
+    async fn run(&self) -> Result<(), ()> {
+        let yesterday = Utc::now().date_naive() - Duration::days(1);
+        let sent = self.bot.send_summaries(yesterday).await?;
+        info!("Sent {} summaries of {} to Telegram", sent, yesterday);
+        Ok(())
+    }
+}
 
-                if *stop {
-                    break;
-                }
+/// Asks every Telegram chat about something its user mentioned yesterday
+pub struct TelegramCheckInJob {
+    pub bot: Arc<TelegramBot>,
+}
 
-                if tasks.is_empty() {
-                    tokio::time::sleep(tokio::time::Duration::from_secs(*sleep_duration)).await;
-                    continue;
-                }
+#[async_trait]
+impl Job for TelegramCheckInJob {
+    async fn run(&self) -> Result<(), ()> {
+        let yesterday = Utc::now().date_naive() - Duration::days(1);
+        let sent = self.bot.send_check_ins(yesterday).await?;
+        info!("Sent {} check-ins to Telegram", sent);
+        Ok(())
+    }
+}
+
+/// Infers attributes from what every user said yesterday
+pub struct AttributeExtractionJob {
+    pub extraction_service: ExtractionService,
+}
+
+#[async_trait]
+impl Job for AttributeExtractionJob {
+    async fn run(&self) -> Result<(), ()> {
+        let yesterday = Utc::now().date_naive() - Duration::days(1);
+        let saved = self
+            .extraction_service
+            .extract_users_for_date(yesterday)
+            .await?;
+        info!("Inferred {} attributes from {}", saved, yesterday);
+        Ok(())
+    }
+}
+
+/// Remembers the new items of every user's feeds
+pub struct FeedPollJob {
+    pub feed_service: FeedService,
+}
 
-                if let Some((when, task)) = tasks.first() {
-                    let now = Instant::now();
-                    if now >= *when {
-                        info!("Executing task: {:?}", task);
-                        tasks.remove(0);
+#[async_trait]
+impl Job for FeedPollJob {
+    async fn run(&self) -> Result<(), ()> {
+        let remembered = self.feed_service.poll_all(Utc::now().timestamp()).await?;
+        info!("Remembered {} new feed items", remembered);
+        Ok(())
+    }
+}
+
+/// Announces the reminders that came due
+pub struct ReminderJob {
+    pub reminder_service: ReminderService,
+}
+
+#[async_trait]
+impl Job for ReminderJob {
+    async fn run(&self) -> Result<(), ()> {
+        let fired = self
+            .reminder_service
+            .fire_due(Utc::now().timestamp())
+            .await?;
+        if fired > 0 {
+            info!("Sent {} reminders", fired);
+        }
+        Ok(())
+    }
+}
+
+/// Removes the messages every user's retention policy no longer keeps
+pub struct RetentionJob {
+    pub retention_service: RetentionService,
+}
+
+#[async_trait]
+impl Job for RetentionJob {
+    async fn run(&self) -> Result<(), ()> {
+        let today = Utc::now().date_naive();
+        let report = self.retention_service.enforce_all(today).await?;
+        info!(
+            "Retention removed {} messages of {} days, {} of them summarized",
+            report.messages, report.days, report.summarized
+        );
+        Ok(())
+    }
+}
+
+/// Compares the primary stores with their replica and copies the primary
+/// over what diverged, failing when that could not be done so the job status
+/// flags it
+pub struct ReconciliationJob {
+    pub replication_service: ReplicationService,
+}
+
+#[async_trait]
+impl Job for ReconciliationJob {
+    async fn run(&self) -> Result<(), ()> {
+        let report = self.replication_service.reconcile().await?;
+        for divergence in report.diverged.iter() {
+            match divergence.date {
+                Some(date) => warn!(
+                    "Replica of {} diverged on {}: {}",
+                    divergence.user, date, divergence.detail
+                ),
+                None => warn!(
+                    "Replica of {} diverged: {}",
+                    divergence.user, divergence.detail
+                ),
+            }
+        }
+        info!(
+            "Reconciled {} days of {} users with the replica, {} diverged",
+            report.days,
+            report.users,
+            report.diverged.len()
+        );
+        if report.diverged.is_empty() {
+            return Ok(());
+        }
+        let repaired = self
+            .replication_service
+            .repair(&report.diverged)
+            .await
+            .map_err(|_| error!("Error copying the primary stores over the replica"))?;
+        info!("Repaired {} divergences of the replica", repaired);
+        Ok(())
+    }
+}
+
+/// Re-embeds every user's messages with the configured client, e.g. after
+/// the embeddings model was changed
+pub struct ReembedJob {
+    pub reembed_service: ReembedService,
+    pub embeddings_client: Arc<Mutex<dyn EmbeddingsClient>>,
+}
+
+#[async_trait]
+impl Job for ReembedJob {
+    async fn run(&self) -> Result<(), ()> {
+        let users = self
+            .reembed_service
+            .message_repo
+            .lock()
+            .await
+            .get_users()
+            .await?;
+        let model = self.embeddings_client.lock().await.model();
+        let mut result = Ok(());
+        for user in users {
+            let reembedded = self
+                .reembed_service
+                .reembed(&user, &model, self.embeddings_client.clone())
+                .await;
+            if reembedded.is_err() {
+                error!("Error re-embedding messages of {}", user);
+                result = Err(());
+            }
+        }
+        result
+    }
+}
+
+/// Outcome of the runs of one background job
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct JobStatus {
+    pub runs: u64,
+    pub failures: u64,
+    pub last_run: Option<i64>,
+    pub last_success: Option<bool>,
+}
+
+/// Shared record of how the background jobs are doing, for the admin overview
+#[derive(Default)]
+pub struct JobStatuses {
+    jobs: std::sync::Mutex<BTreeMap<&'static str, JobStatus>>,
+}
+
+impl JobStatuses {
+    pub fn record(&self, job: &'static str, success: bool) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let status = jobs.entry(job).or_default();
+        status.runs += 1;
+        if !success {
+            status.failures += 1;
+        }
+        status.last_run = Some(chrono::Utc::now().timestamp());
+        status.last_success = Some(success);
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, JobStatus> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(job, status)| (job.to_string(), status.clone()))
+            .collect()
+    }
+}
+
+/// Periodically archives attributes that have passed their expiry so they
+/// stop showing up in lookups and context assembly
+pub fn start_attribute_expiry_job(
+    attribute_repo: Arc<Mutex<dyn AttributeRepo>>,
+    interval: tokio::time::Duration,
+    statuses: Arc<JobStatuses>,
+) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let now = chrono::Utc::now().timestamp();
+            let archived = attribute_repo.lock().await.archive_expired(now).await;
+            statuses.record("attribute_expiry", archived.is_ok());
+            match archived {
+                Ok(archived) => {
+                    for (username, attribute) in archived {
+                        info!(
+                            "Archived expired attribute {} for user {}",
+                            attribute.attribute, username
+                        );
                     }
                 }
-                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+                Err(_) => error!("Error archiving expired attributes"),
             }
-        });
+        }
+    });
+}
+
+/// Periodically closes conversations that have gone quiet for `idle_secs`
+/// by generating a summary for them
+pub fn start_thread_summary_job(
+    thread_service: ThreadService,
+    interval: tokio::time::Duration,
+    idle_secs: i64,
+    statuses: Arc<JobStatuses>,
+) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let now = chrono::Utc::now().timestamp();
+            let summarized = thread_service.summarize_idle_threads(idle_secs, now).await;
+            statuses.record("thread_summary", summarized.is_ok());
+            if summarized.is_err() {
+                error!("Error summarizing idle threads");
+            }
+        }
+    });
+}
+
+/// Periodically re-runs saved searches so users hear about new messages
+/// matching them
+pub fn start_saved_search_job(
+    saved_search_service: SavedSearchService,
+    interval: tokio::time::Duration,
+    statuses: Arc<JobStatuses>,
+) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let now = chrono::Utc::now().timestamp();
+            let checked = saved_search_service.check_saved_searches(now).await;
+            statuses.record("saved_searches", checked.is_ok());
+            if checked.is_err() {
+                error!("Error checking saved searches");
+            }
+        }
+    });
+}
+
+/// When the message search index is loaded into memory
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexLoadPolicy {
+    /// Everything is loaded before the server starts accepting requests
+    Eager,
+    /// Each user's messages are loaded on their first search
+    #[default]
+    Lazy,
+    /// The index is rebuilt on an interval, keeping first searches fast
+    Scheduled,
+}
+
+impl IndexLoadPolicy {
+    pub fn parse(value: &str) -> Option<IndexLoadPolicy> {
+        match value.to_lowercase().as_str() {
+            "eager" => Some(IndexLoadPolicy::Eager),
+            "lazy" => Some(IndexLoadPolicy::Lazy),
+            "scheduled" => Some(IndexLoadPolicy::Scheduled),
+            _ => None,
+        }
     }
 }
 
+/// Periodically reloads the message search index
+pub fn start_index_warmup_job(
+    message_repo: Arc<Mutex<dyn MessageRepo>>,
+    interval: tokio::time::Duration,
+    statuses: Arc<JobStatuses>,
+) {
+    tokio::spawn(async move {
+        loop {
+            let warmed = message_repo.lock().await.warm_up().await;
+            statuses.record("index_warmup", warmed.is_ok());
+            match warmed {
+                Ok(indexed) => info!("Warmed up index with {} messages", indexed),
+                Err(_) => error!("Error warming up index"),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+/// Periodically refreshes the storage gauges served on `/metrics`
+pub fn start_metrics_collector(
+    message_repo: Arc<Mutex<dyn MessageRepo>>,
+    storage_metrics: Arc<Mutex<StorageMetrics>>,
+    interval: tokio::time::Duration,
+    statuses: Arc<JobStatuses>,
+) {
+    tokio::spawn(async move {
+        loop {
+            let collected = StorageMetrics::collect(&*message_repo.lock().await).await;
+            statuses.record("metrics", collected.is_ok());
+            match collected {
+                Ok(collected) => *storage_metrics.lock().await = collected,
+                Err(_) => error!("Error collecting storage metrics"),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::handlers::events::MessageEvent;
-    use tokio::time::{self, Duration};
-
-    #[tokio::test]
-    async fn test_scheduler_with_single_item() {
-        let now = Instant::now();
-        let mut scheduler = Scheduler::new(1);
-        scheduler.start().await;
-
-        let task = MessageEvent {
-            username: "test".to_string(),
-            hash: "test".to_string(),
-            chat_id: 1,
+
+    #[test]
+    fn test_job_statuses_count_failures() {
+        let statuses = JobStatuses::default();
+        statuses.record("metrics", true);
+        statuses.record("metrics", false);
+
+        let snapshot = statuses.snapshot();
+        assert_eq!(snapshot["metrics"].runs, 2);
+        assert_eq!(snapshot["metrics"].failures, 1);
+        assert_eq!(snapshot["metrics"].last_success, Some(false));
+    }
+
+    #[test]
+    fn test_index_load_policy_parse() {
+        assert_eq!(IndexLoadPolicy::parse("Eager"), Some(IndexLoadPolicy::Eager));
+        assert_eq!(
+            IndexLoadPolicy::parse("scheduled"),
+            Some(IndexLoadPolicy::Scheduled)
+        );
+        assert_eq!(IndexLoadPolicy::parse("sometimes"), None);
+    }
+
+    #[test]
+    fn test_cron_schedule_finds_next_run() {
+        let at = |text: &str| {
+            DateTime::parse_from_rfc3339(text)
+                .unwrap()
+                .with_timezone(&Utc)
         };
-        scheduler.add_task(now + Duration::from_secs(5), task).await;
 
-        assert_eq!(scheduler.get_task_count().await, 1);
-        time::sleep(Duration::from_secs(10)).await;
-        assert_eq!(scheduler.get_task_count().await, 0);
+        let nightly = CronSchedule::parse("30 3 * * *").unwrap();
+        assert_eq!(
+            nightly.next_after(at("2024-03-01T12:00:00Z")),
+            Some(at("2024-03-02T03:30:00Z"))
+        );
+
+        let weekdays = CronSchedule::parse("*/15 9-17 * * 1-5").unwrap();
+        // a saturday
+        assert_eq!(
+            weekdays.next_after(at("2024-03-02T10:00:00Z")),
+            Some(at("2024-03-04T09:00:00Z"))
+        );
+
+        let leap_day = CronSchedule::parse("0 0 29 2 *").unwrap();
+        assert_eq!(
+            leap_day.next_after(at("2024-03-01T00:00:00Z")),
+            Some(at("2028-02-29T00:00:00Z"))
+        );
+
+        assert_eq!(CronSchedule::parse("@daily"), CronSchedule::parse("0 0 * * *"));
+        assert!(CronSchedule::parse("60 * * * *").is_none());
+        assert!(CronSchedule::parse("* * *").is_none());
+    }
+
+    struct CountingJob {
+        runs: Arc<Mutex<usize>>,
+    }
+
+    #[async_trait]
+    impl Job for CountingJob {
+        async fn run(&self) -> Result<(), ()> {
+            *self.runs.lock().await += 1;
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scheduler_runs_jobs_until_shutdown() {
+        let statuses = Arc::new(JobStatuses::default());
+        let runs = Arc::new(Mutex::new(0));
+        let mut scheduler = Scheduler::new(statuses.clone());
+        scheduler.register(
+            "test_counting",
+            Some("* * * * *"),
+            Arc::new(CountingJob { runs: runs.clone() }),
+        );
+        let (stop, shutdown) = watch::channel(false);
+        let handle = scheduler.start(shutdown);
+
+        tokio::time::sleep(std::time::Duration::from_secs(150)).await;
+        stop.send(true).unwrap();
+        handle.await.unwrap();
+
+        assert!(*runs.lock().await >= 2);
+        assert_eq!(statuses.snapshot()["test_counting"].runs as usize, *runs.lock().await);
     }
 }
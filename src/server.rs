@@ -0,0 +1,583 @@
+//! The HTTP server and the jobs that run next to it
+
+use std::sync::Arc;
+
+use actix_web::{web, App, HttpServer};
+use anyhow::Result;
+use tracing::{error, info};
+
+use crate::{
+    clients, config,
+    handlers::{
+        self,
+        admin::{
+            dedupe, get_embedding_queue, get_overview, get_stats, get_storage, get_users, reembed,
+            warm_up,
+        },
+        auth::{create_token, get_tokens, require_token, revoke_token},
+        briefing::get_briefing,
+        chat::{
+            complete, delete_chat, get_chat, get_collections, get_context_with, get_history, save_chat, save_chats, save_feedback,
+            search_chat,
+        },
+        contacts::{get_contact, get_contacts, save_contact},
+        events::{stream_events, stream_events_ws, test_mtqq},
+        export::{export_archive, export_transcript},
+        feeds::{delete_feed, get_feeds, register_feed},
+        import::{import_chatgpt, import_messages},
+        ingest::{ingest_audio, ingest_document, ingest_email, ingest_url},
+        memory::forget,
+        metrics::get_metrics,
+        openapi::{get_docs, get_openapi},
+        personas::{get_persona, get_personas, save_persona},
+        presets::{get_presets, save_preset},
+        rate_limit::rate_limit,
+        reminders::{create_reminder, delete_reminder, get_reminders},
+        request_id::trace_request,
+        retention::{delete_retention, get_retention, set_retention},
+        saved_searches::{delete_search, get_searches, save_search},
+        summary::{get_month_summary, get_summary, get_week_summary, list_summaries},
+        topics::get_topics,
+        usage::get_usage,
+        webhooks::{delete_webhook, get_webhooks, register_webhook},
+        user_attributes::{
+            delete_attribute, export_attributes, get_attribute, get_attribute_history,
+            extract_attributes, import_attributes, save_attribute,
+        },
+    },
+    repos, scheduler,
+    services::{self, saved_searches::SavedSearchService, threads::ThreadService},
+    tls, Resources,
+};
+
+async fn start_web_server(resources: Resources) -> Result<()>{
+    let server = resources.config.server.clone();
+    let max_body_bytes = server.max_body_bytes;
+    let data = web::Data::new(resources);
+
+    let http = HttpServer::new(move || {
+        App::new()
+            .app_data(data.clone())
+            // chat exports sent to the import endpoint can be large
+            .app_data(web::PayloadConfig::new(32 * 1024 * 1024))
+            .app_data(
+                web::JsonConfig::default()
+                    .limit(max_body_bytes)
+                    .error_handler(handlers::validation::json_error),
+            )
+            // inside the token check, so clients are told apart by token
+            .wrap(actix_web::middleware::from_fn(rate_limit))
+            .wrap(actix_web::middleware::from_fn(
+                handlers::validation::reject_invalid_usernames,
+            ))
+            .wrap(actix_web::middleware::from_fn(require_token))
+            // outermost, so rejected requests get an ID too
+            .wrap(actix_web::middleware::from_fn(trace_request))
+            .route(
+                "/api/v1/auth/{username}/tokens",
+                web::post().to(create_token),
+            )
+            .route("/api/v1/auth/{username}/tokens", web::get().to(get_tokens))
+            .route(
+                "/api/v1/auth/{username}/tokens/{id}",
+                web::delete().to(revoke_token),
+            )
+            .route(
+                "/api/v1/chat/{username}/collections",
+                web::get().to(get_collections),
+            )
+            // the chat routes again, for a named collection of the user
+            .service(
+                web::scope("/api/v1/chat/{username}/collections/{collection}")
+                    .route("", web::post().to(save_chat))
+                    .route("/context", web::post().to(get_context_with))
+                    .route("/batch", web::post().to(save_chats))
+                    .route("/complete", web::post().to(complete))
+                    .route("/history", web::get().to(get_history))
+                    .route("/search", web::post().to(search_chat))
+                    .route("/{id}", web::get().to(get_chat))
+                    .route("/{id}", web::delete().to(delete_chat)),
+            )
+            .route("/api/v1/chat/{username}", web::post().to(save_chat))
+            .route("/api/v1/chat/{username}/context", web::post().to(get_context_with))
+            .route("/api/v1/chat/{username}/batch", web::post().to(save_chats))
+            .route("/api/v1/chat/{username}/complete", web::post().to(complete))
+            .route(
+                "/api/v1/chat/{username}/history",
+                web::get().to(get_history),
+            )
+            .route("/api/v1/chat/{username}/{id}", web::get().to(get_chat))
+            .route("/api/v1/chat/{username}/{id}", web::delete().to(delete_chat))
+            .route(
+                "/api/v1/chat/{username}/search",
+                web::post().to(search_chat),
+            )
+            .route(
+                "/api/v1/chat/{username}/feedback",
+                web::post().to(save_feedback),
+            )
+            .route(
+                "/api/v1/summary/{username}",
+                web::get().to(list_summaries),
+            )
+            .route(
+                "/api/v1/summary/{username}/{date}",
+                web::get().to(get_summary),
+            )
+            .route(
+                "/api/v1/summary/{username}/week/{iso_week}",
+                web::get().to(get_week_summary),
+            )
+            .route(
+                "/api/v1/summary/{username}/month/{month}",
+                web::get().to(get_month_summary),
+            )
+            .route("/api/v1/topics/{username}", web::get().to(get_topics))
+            .route("/api/v1/usage/{username}", web::get().to(get_usage))
+            .route(
+                "/api/v1/attribute/{username}",
+                web::post().to(save_attribute),
+            )
+            .route(
+                "/api/v1/attribute/{username}",
+                web::get().to(export_attributes),
+            )
+            .route(
+                "/api/v1/attribute/{username}/import",
+                web::post().to(import_attributes),
+            )
+            .route(
+                "/api/v1/attribute/{username}/extract",
+                web::post().to(extract_attributes),
+            )
+            .route(
+                "/api/v1/attribute/{username}/{attribute}",
+                web::get().to(get_attribute),
+            )
+            .route(
+                "/api/v1/attribute/{username}/{attribute}",
+                web::delete().to(delete_attribute),
+            )
+            .route(
+                "/api/v1/attribute/{username}/{attribute}/history",
+                web::get().to(get_attribute_history),
+            )
+            .route("/metrics", web::get().to(get_metrics))
+            .route("/api/v1/openapi.json", web::get().to(get_openapi))
+            .route("/api/v1/docs", web::get().to(get_docs))
+            .route("/api/v1/admin/overview", web::get().to(get_overview))
+            .route("/api/v1/admin/users", web::get().to(get_users))
+            .route("/api/v1/admin/stats", web::get().to(get_stats))
+            .route(
+                "/api/v1/admin/storage/{username}",
+                web::get().to(get_storage),
+            )
+            .route("/api/v1/admin/warmup", web::post().to(warm_up))
+            .route(
+                "/api/v1/admin/embedding-queue",
+                web::get().to(get_embedding_queue),
+            )
+            .route(
+                "/api/v1/admin/reembed/{username}",
+                web::post().to(reembed),
+            )
+            .route("/api/v1/admin/{username}/dedupe", web::post().to(dedupe))
+            .route("/api/v1/events/{username}", web::get().to(test_mtqq))
+            .route(
+                "/api/v1/events/{username}/stream",
+                web::get().to(stream_events),
+            )
+            .route(
+                "/api/v1/events/{username}/ws",
+                web::get().to(stream_events_ws),
+            )
+            .route("/api/v1/memory/{username}/forget", web::post().to(forget))
+            .route("/api/v1/preset/{username}", web::post().to(save_preset))
+            .route("/api/v1/preset/{username}", web::get().to(get_presets))
+            .route("/api/v1/persona/{username}", web::post().to(save_persona))
+            .route("/api/v1/persona/{username}", web::get().to(get_personas))
+            .route(
+                "/api/v1/persona/{username}/{name}",
+                web::get().to(get_persona),
+            )
+            .route("/api/v1/briefing/{username}", web::get().to(get_briefing))
+            .route("/api/v1/import/{username}", web::post().to(import_messages))
+            .route(
+                "/api/v1/import/{username}/chatgpt",
+                web::post().to(import_chatgpt),
+            )
+            .route("/api/v1/export/{username}", web::get().to(export_archive))
+            .route(
+                "/api/v1/export/{username}/transcript",
+                web::get().to(export_transcript),
+            )
+            .route("/api/v1/contacts/{username}", web::get().to(get_contacts))
+            .route("/api/v1/contacts/{username}", web::post().to(save_contact))
+            .route(
+                "/api/v1/contacts/{username}/{name}",
+                web::get().to(get_contact),
+            )
+            .route("/api/v1/search/{username}/saved", web::post().to(save_search))
+            .route("/api/v1/search/{username}/saved", web::get().to(get_searches))
+            .route(
+                "/api/v1/search/{username}/saved/{name}",
+                web::delete().to(delete_search),
+            )
+            .route(
+                "/api/v1/webhooks/{username}",
+                web::post().to(register_webhook),
+            )
+            .route("/api/v1/webhooks/{username}", web::get().to(get_webhooks))
+            .route(
+                "/api/v1/webhooks/{username}/{id}",
+                web::delete().to(delete_webhook),
+            )
+            .route(
+                "/api/v1/ingest/{username}/email",
+                web::post().to(ingest_email),
+            )
+            .route(
+                "/api/v1/ingest/{username}/document",
+                web::post().to(ingest_document),
+            )
+            .route(
+                "/api/v1/ingest/{username}/audio",
+                web::post().to(ingest_audio),
+            )
+            .route("/api/v1/ingest/{username}/url", web::post().to(ingest_url))
+            .route("/api/v1/feeds/{username}", web::post().to(register_feed))
+            .route("/api/v1/feeds/{username}", web::get().to(get_feeds))
+            .route(
+                "/api/v1/feeds/{username}/{id}",
+                web::delete().to(delete_feed),
+            )
+            .route(
+                "/api/v1/reminder/{username}",
+                web::post().to(create_reminder),
+            )
+            .route("/api/v1/reminder/{username}", web::get().to(get_reminders))
+            .route(
+                "/api/v1/reminder/{username}/{id}",
+                web::delete().to(delete_reminder),
+            )
+            .route("/api/v1/retention/{username}", web::get().to(get_retention))
+            .route("/api/v1/retention/{username}", web::put().to(set_retention))
+            .route(
+                "/api/v1/retention/{username}",
+                web::delete().to(delete_retention),
+            )
+    });
+    let tls = match (&server.tls_cert, &server.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::server_config(cert, key)?),
+        (None, None) => None,
+        _ => anyhow::bail!("TLS needs both a certificate and a private key"),
+    };
+    let http = match (&server.socket, tls) {
+        (Some(_), Some(_)) => anyhow::bail!("TLS is not served on unix sockets"),
+        #[cfg(unix)]
+        (Some(socket), None) => {
+            remove_stale_socket(socket)?;
+            info!("Listening on unix socket {}", socket.display());
+            http.bind_uds(socket)?
+        }
+        #[cfg(not(unix))]
+        (Some(_), None) => anyhow::bail!("Unix sockets are not supported on this platform"),
+        (None, Some(tls)) => {
+            info!("Listening on https://{}:{}", server.host, server.port);
+            http.bind_rustls_0_23((server.host.as_str(), server.port), tls)?
+        }
+        (None, None) => {
+            info!("Listening on {}:{}", server.host, server.port);
+            http.bind((server.host.as_str(), server.port))?
+        }
+    };
+    http.run().await?;
+
+    Ok(())
+}
+
+/// Removes a socket left behind by an earlier run, binding fails otherwise
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+
+/// Migrates the storage, starts the scheduled jobs and serves the API until
+/// the server is asked to stop
+pub async fn run() -> Result<()> {
+    let config = config::init()?;
+    repos::encryption::init(config)?;
+
+    let migration_options = repos::migrations::MigrationOptions::from_env();
+    let migrations = repos::migrations::migrate(
+        &repos::get_storage_root(),
+        &repos::migrations::migrations(),
+        &migration_options,
+    )
+    .map_err(|_| anyhow::anyhow!("Storage migration failed"))?;
+    if migration_options.dry_run {
+        info!("Pending storage migrations: {:?}", migrations);
+        return Ok(());
+    }
+
+    if config.storage.database_url.is_none() {
+        let recovery = repos::messages::recover(&repos::get_storage_root());
+        if recovery != repos::messages::RecoveryReport::default() {
+            info!("Recovered interrupted writes: {:?}", recovery);
+        }
+    }
+
+    let mut resources = Resources::open().await?;
+
+    if config.models.background_embeddings {
+        let queue = Arc::new(services::embedding_queue::EmbeddingQueue::start(
+            resources.embeddings_client.clone(),
+            resources.message_repo.clone(),
+            config.models.embedding_attempts,
+            tokio::time::Duration::from_secs(2),
+        ));
+        let message_repo = resources.message_repo.clone();
+        let pending = queue.clone();
+        tokio::spawn(async move {
+            if pending.enqueue_pending(&*message_repo.lock().await).await.is_err() {
+                error!("Error queueing pending messages for embedding");
+            }
+        });
+        info!("Embedding messages in the background");
+        resources.embedding_queue = Some(queue);
+    }
+
+    match config.jobs.index_load_policy {
+        scheduler::IndexLoadPolicy::Eager => {
+            match resources.message_repo.lock().await.warm_up().await {
+                Ok(indexed) => info!("Loaded {} messages into the index", indexed),
+                Err(_) => error!("Error loading index at startup"),
+            }
+        }
+        scheduler::IndexLoadPolicy::Scheduled => {
+            scheduler::start_index_warmup_job(
+                resources.message_repo.clone(),
+                tokio::time::Duration::from_secs(config.jobs.index_warmup_interval_secs),
+                resources.job_statuses.clone(),
+            );
+        }
+        scheduler::IndexLoadPolicy::Lazy => {}
+    }
+
+    scheduler::start_attribute_expiry_job(
+        resources.user_attributes_repo.clone(),
+        tokio::time::Duration::from_secs(config.jobs.attribute_expiry_interval_secs),
+        resources.job_statuses.clone(),
+    );
+
+    scheduler::start_thread_summary_job(
+        ThreadService {
+            message_repo: resources.message_repo.clone(),
+            chat_client: resources.chat_client.clone(),
+            thread_repo: resources.thread_repo.clone(),
+            event_hub: resources.event_hub.clone(),
+        },
+        tokio::time::Duration::from_secs(60),
+        config.jobs.thread_idle_secs,
+        resources.job_statuses.clone(),
+    );
+
+    scheduler::start_saved_search_job(
+        SavedSearchService {
+            embedding_client: resources.embeddings_client.clone(),
+            message_repo: resources.message_repo.clone(),
+            saved_search_repo: resources.saved_search_repo.clone(),
+            event_hub: resources.event_hub.clone(),
+        },
+        tokio::time::Duration::from_secs(config.jobs.saved_search_interval_secs),
+        resources.job_statuses.clone(),
+    );
+
+    scheduler::start_metrics_collector(
+        resources.message_repo.clone(),
+        resources.storage_metrics.clone(),
+        tokio::time::Duration::from_secs(config.jobs.metrics_interval_secs),
+        resources.job_statuses.clone(),
+    );
+
+    let mut cron = scheduler::Scheduler::new(resources.job_statuses.clone());
+    // before the indexes are compacted, which drops what it removed
+    cron.register(
+        "retention",
+        Some("0 3 * * *"),
+        Arc::new(scheduler::RetentionJob {
+            retention_service: handlers::retention::retention_service(&resources),
+        }),
+    );
+    cron.register(
+        "index_compaction",
+        Some("30 3 * * *"),
+        Arc::new(scheduler::IndexCompactionJob {
+            message_repo: resources.message_repo.clone(),
+        }),
+    );
+    if config.storage.compress_after_days > 0 {
+        cron.register(
+            "cold_storage",
+            Some("0 4 * * *"),
+            Arc::new(scheduler::ColdStorageJob {
+                message_repo: resources.message_repo.clone(),
+                days: config.storage.compress_after_days,
+            }),
+        );
+    }
+    if let Some(replica) = &resources.replica {
+        let reconciliation = Arc::new(scheduler::ReconciliationJob {
+            replication_service: services::replication::ReplicationService {
+                message_repo: resources.message_repo.clone(),
+                attribute_repo: resources.user_attributes_repo.clone(),
+                replica: replica.clone(),
+            },
+        });
+        // a replica new to an install is sent the history written before
+        let initial = reconciliation.clone();
+        tokio::spawn(async move {
+            let _ = scheduler::Job::run(&*initial).await;
+        });
+        cron.register("replica_reconciliation", Some("0 5 * * *"), reconciliation);
+    }
+    let summary_hour = match config.jobs.daily_summary_hour {
+        hour if hour < 24 => hour,
+        hour => {
+            error!("Invalid daily_summary_hour {}, summarizing at 1", hour);
+            1
+        }
+    };
+    cron.register(
+        "daily_summary",
+        Some(&format!("0 {} * * *", summary_hour)),
+        Arc::new(scheduler::DailySummaryJob {
+            summary_service: services::summary::SummaryService {
+                message_repo: resources.message_repo.clone(),
+                chat_client: resources.chat_client.clone(),
+                summary_repo: resources.summary_repo.clone(),
+                embedding_client: resources.embeddings_client.clone(),
+            },
+        }),
+    );
+    // extraction costs a completion per user and day, it only runs when
+    // scheduled
+    cron.register(
+        "attribute_extraction",
+        None,
+        Arc::new(scheduler::AttributeExtractionJob {
+            extraction_service: services::extraction::ExtractionService {
+                message_repo: resources.message_repo.clone(),
+                chat_client: resources.chat_client.clone(),
+                attribute_repo: resources.user_attributes_repo.clone(),
+                contact_repo: resources.contact_repo.clone(),
+                event_hub: resources.event_hub.clone(),
+            },
+        }),
+    );
+    // re-embedding everything is expensive, it only runs when scheduled
+    cron.register(
+        "reembed",
+        None,
+        Arc::new(scheduler::ReembedJob {
+            reembed_service: services::reembed::ReembedService {
+                message_repo: resources.message_repo.clone(),
+                event_hub: resources.event_hub.clone(),
+            },
+            embeddings_client: resources.embeddings_client.clone(),
+        }),
+    );
+    cron.register(
+        "feed_poll",
+        Some("*/30 * * * *"),
+        Arc::new(scheduler::FeedPollJob {
+            feed_service: handlers::feeds::feed_service(&resources),
+        }),
+    );
+    cron.register(
+        "reminders",
+        Some("* * * * *"),
+        Arc::new(scheduler::ReminderJob {
+            reminder_service: handlers::reminders::reminder_service(&resources),
+        }),
+    );
+    if let Some(api) = clients::telegram::HttpTelegramApi::from_config(&config.telegram) {
+        let bot = Arc::new(services::telegram::TelegramBot {
+            api: Arc::new(api),
+            chats: services::telegram::parse_chats(&config.telegram.chats),
+            chat_service: services::chat::ChatService::from_resources(&resources),
+            summary_service: services::summary::SummaryService {
+                message_repo: resources.message_repo.clone(),
+                chat_client: resources.chat_client.clone(),
+                summary_repo: resources.summary_repo.clone(),
+                embedding_client: resources.embeddings_client.clone(),
+            },
+            event_hub: resources.event_hub.clone(),
+            max_content_length: config.server.max_content_length,
+            check_ins: Default::default(),
+        });
+        // after the daily summaries were written
+        cron.register(
+            "telegram_summary",
+            Some("0 8 * * *"),
+            Arc::new(scheduler::TelegramSummaryJob { bot: bot.clone() }),
+        );
+        cron.register(
+            "telegram_check_in",
+            Some("0 19 * * *"),
+            Arc::new(scheduler::TelegramCheckInJob { bot: bot.clone() }),
+        );
+        services::telegram::start_telegram_bot(bot);
+    }
+    let (stop_scheduler, shutdown) = tokio::sync::watch::channel(false);
+    let scheduled = cron.start(shutdown);
+
+    if let Some(event_bus) = clients::event_bus::MqttEventBus::from_config(&config.mqtt) {
+        clients::event_bus::start_event_publisher(
+            resources.event_hub.clone(),
+            Arc::new(event_bus),
+        );
+    }
+
+    clients::event_bus::start_sensor_subscriber(
+        &config.mqtt,
+        Arc::new(services::sensors::SensorService {
+            attribute_repo: resources.user_attributes_repo.clone(),
+            event_hub: resources.event_hub.clone(),
+            subscriptions: config.mqtt.subscriptions.clone(),
+        }),
+    );
+
+    if let Some(mqtt_host) = &config.mqtt.host {
+        clients::mqtt::start_mqtt_bridge(
+            resources.event_hub.clone(),
+            resources.user_attributes_repo.clone(),
+            mqtt_host.clone(),
+            config.mqtt.port,
+        );
+    }
+
+    services::webhooks::start_webhook_dispatcher(
+        resources.event_hub.clone(),
+        Arc::new(services::webhooks::WebhookService::new(
+            resources.webhook_repo.clone(),
+        )),
+    );
+
+    let served = start_web_server(resources).await;
+
+    // the server returns once it was asked to stop, let running jobs finish
+    info!("Waiting for scheduled jobs to finish");
+    let _ = stop_scheduler.send(true);
+    let _ = scheduled.await;
+    served
+}
+
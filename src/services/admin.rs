@@ -0,0 +1,171 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::{
+    clients::{embedding_cache::EmbeddingCacheStats, embeddings::EmbeddingsClient},
+    hub::EventHub,
+    repos::{
+        get_directory_size, get_storage_root, get_user_root,
+        messages::{CacheStats, MessageRepo},
+    },
+    scheduler::{JobStatus, JobStatuses},
+};
+
+// How long a downstream service may take to answer a health check
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+pub struct QueueDepths {
+    pub event_hub: usize,
+    pub event_subscribers: usize,
+}
+
+#[derive(Serialize)]
+pub struct DownstreamHealth {
+    pub embeddings: bool,
+    pub mqtt_configured: bool,
+}
+
+/// Instance-wide state for an ops dashboard
+#[derive(Serialize)]
+pub struct Overview {
+    pub version: &'static str,
+    pub namespace: Option<String>,
+    pub users: usize,
+    pub messages_today: usize,
+    pub jobs: BTreeMap<String, JobStatus>,
+    pub queues: QueueDepths,
+    pub downstream: DownstreamHealth,
+    pub caches: CacheStats,
+    pub embedding_cache: Option<EmbeddingCacheStats>,
+}
+
+/// What the instance holds for one user
+#[derive(Debug, Serialize)]
+pub struct UserSummary {
+    pub username: String,
+    pub messages: usize,
+    /// Days with messages stored under them
+    pub days: usize,
+    /// Unix timestamp of the user's latest message, absent without messages
+    pub last_active: Option<i64>,
+    pub bytes_on_disk: u64,
+}
+
+/// What the instance holds for all users together
+#[derive(Debug, Serialize)]
+pub struct InstanceStats {
+    pub users: usize,
+    pub messages: usize,
+    /// Messages with an embedding, the rest are trivial or still pending
+    pub embedded: usize,
+    pub forgotten: usize,
+    pub days: usize,
+    pub first_message: Option<i64>,
+    pub last_active: Option<i64>,
+    pub bytes_on_disk: u64,
+}
+
+pub struct AdminService {
+    pub message_repo: Arc<Mutex<dyn MessageRepo>>,
+    pub embedding_client: Arc<Mutex<dyn EmbeddingsClient>>,
+    pub event_hub: Arc<EventHub>,
+    pub job_statuses: Arc<JobStatuses>,
+}
+
+impl AdminService {
+    pub async fn overview(&self) -> Result<Overview, ()> {
+        let today = chrono::Utc::now().date_naive();
+        let (users, messages_today, caches) = {
+            let message_repo = self.message_repo.lock().await;
+            let users = message_repo.get_users().await?;
+            let mut messages_today = 0;
+            for user in &users {
+                messages_today += message_repo
+                    .get_all_for_user_on_day(user.clone(), today)
+                    .await?
+                    .len();
+            }
+            (users.len(), messages_today, message_repo.cache_stats())
+        };
+
+        let (embeddings, embedding_cache) = {
+            let embedding_client = self.embedding_client.lock().await;
+            // a text of its own each time, a cached embedding would hide a
+            // backend that is down
+            let probe = format!("health check {}", uuid::Uuid::new_v4());
+            let reachable = matches!(
+                tokio::time::timeout(HEALTH_CHECK_TIMEOUT, embedding_client.get_embeddings(probe))
+                    .await,
+                Ok(Ok(_))
+            );
+            (reachable, embedding_client.cache_stats())
+        };
+
+        Ok(Overview {
+            version: env!("CARGO_PKG_VERSION"),
+            namespace: crate::namespace::namespace(),
+            users,
+            messages_today,
+            jobs: self.job_statuses.snapshot(),
+            queues: QueueDepths {
+                event_hub: self.event_hub.queue_depth(),
+                event_subscribers: self.event_hub.subscriber_count(),
+            },
+            downstream: DownstreamHealth {
+                embeddings,
+                mqtt_configured: crate::config::get().mqtt.host.is_some(),
+            },
+            caches,
+            embedding_cache,
+        })
+    }
+
+    /// Every user with messages, by username
+    pub async fn users(&self) -> Result<Vec<UserSummary>, ()> {
+        let message_repo = self.message_repo.lock().await;
+        let mut summaries = vec![];
+        for user in message_repo.get_users().await? {
+            let chats = message_repo.get_all_for_user(user.clone()).await?;
+            let days = message_repo.get_days(user.clone()).await?;
+            summaries.push(UserSummary {
+                messages: chats.len(),
+                days: days.len(),
+                last_active: chats.iter().map(|chat| chat.timestamp).max(),
+                bytes_on_disk: get_directory_size(&get_user_root(&user)),
+                username: user,
+            });
+        }
+        Ok(summaries)
+    }
+
+    pub async fn stats(&self) -> Result<InstanceStats, ()> {
+        let message_repo = self.message_repo.lock().await;
+        let users = message_repo.get_users().await?;
+        let mut stats = InstanceStats {
+            users: users.len(),
+            messages: 0,
+            embedded: 0,
+            forgotten: 0,
+            days: 0,
+            first_message: None,
+            last_active: None,
+            bytes_on_disk: get_directory_size(&get_storage_root()),
+        };
+        for user in users {
+            let chats = message_repo.get_all_for_user(user.clone()).await?;
+            stats.messages += chats.len();
+            stats.embedded += chats.iter().filter(|chat| chat.embedding.is_some()).count();
+            stats.forgotten += chats.iter().filter(|chat| chat.forgotten).count();
+            stats.days += message_repo.get_days(user).await?.len();
+            for timestamp in chats.iter().map(|chat| chat.timestamp) {
+                stats.first_message =
+                    Some(stats.first_message.map_or(timestamp, |t| t.min(timestamp)));
+                stats.last_active = Some(stats.last_active.map_or(timestamp, |t| t.max(timestamp)));
+            }
+        }
+        Ok(stats)
+    }
+}
@@ -0,0 +1,188 @@
+use std::sync::Arc;
+
+use chrono::{Datelike, Duration, NaiveDate};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::{
+    clients::chat::{ChatClient, ChatError, Message},
+    repos::{
+        briefings::{BriefingModel, BriefingRepo},
+        contacts::{ContactModel, ContactRepo},
+        messages::MessageRepo,
+        threads::ThreadSummaryRepo,
+    },
+};
+
+// How far ahead birthdays are mentioned
+const BIRTHDAY_LOOKAHEAD_DAYS: i64 = 7;
+
+// Yesterday's messages beyond this are left out of the briefing prompt
+const MAX_MESSAGES: usize = 50;
+
+pub struct BriefingService {
+    pub message_repo: Arc<Mutex<dyn MessageRepo>>,
+    pub chat_client: Arc<Mutex<dyn ChatClient>>,
+    pub thread_repo: Arc<Mutex<dyn ThreadSummaryRepo>>,
+    pub contact_repo: Arc<Mutex<dyn ContactRepo>>,
+    pub briefing_repo: Arc<Mutex<dyn BriefingRepo>>,
+}
+
+// Days until the contact's next birthday, when it falls within the lookahead
+fn days_until_birthday(contact: &ContactModel, today: NaiveDate) -> Option<i64> {
+    let birthday = contact.birthday.as_ref()?;
+    let month_day = birthday.rsplitn(3, '-').collect::<Vec<&str>>();
+    let (day, month) = (
+        month_day.first()?.parse::<u32>().ok()?,
+        month_day.get(1)?.parse::<u32>().ok()?,
+    );
+    (0..=BIRTHDAY_LOOKAHEAD_DAYS).find(|offset| {
+        let date = today + Duration::days(*offset);
+        date.month() == month && date.day() == day
+    })
+}
+
+impl BriefingService {
+    /// Returns the briefing for `today`, composing and caching it on the
+    /// first request of the day
+    pub async fn get_briefing(
+        &self,
+        username: &str,
+        today: NaiveDate,
+    ) -> Result<BriefingModel, ()> {
+        if let Ok(briefing) = self
+            .briefing_repo
+            .lock()
+            .await
+            .get_briefing(username, today)
+        {
+            return Ok(briefing);
+        }
+
+        let sections = self.gather_sections(username, today).await?;
+        let content = match sections.is_empty() {
+            true => "Nothing to report today.".to_string(),
+            false => polish(&self.chat_client, sections)
+                .await
+                .map_err(|e| error!("Error composing briefing for {}: {}", username, e))?,
+        };
+
+        let briefing = BriefingModel {
+            date: today.format("%Y-%m-%d").to_string(),
+            content,
+            created_at: chrono::Utc::now().timestamp(),
+        };
+        info!("Composed briefing for {} on {}", username, briefing.date);
+        self.briefing_repo
+            .lock()
+            .await
+            .save_briefing(username, briefing)
+    }
+
+    async fn gather_sections(&self, username: &str, today: NaiveDate) -> Result<Vec<String>, ()> {
+        let yesterday = today - Duration::days(1);
+        let mut sections = vec![];
+
+        let messages = self
+            .message_repo
+            .lock()
+            .await
+            .get_all_for_user_on_day(username.to_string(), yesterday)
+            .await?;
+        let messages = messages
+            .iter()
+            .filter(|chat| !chat.content.is_empty() && !chat.forgotten)
+            .map(|chat| format!("{}: {}", chat.role, chat.content))
+            .collect::<Vec<String>>();
+        let messages = &messages[messages.len().saturating_sub(MAX_MESSAGES)..];
+        if !messages.is_empty() {
+            sections.push(format!(
+                "Yesterday's conversation:\n{}",
+                messages.join("\n")
+            ));
+        }
+
+        let start_of_yesterday = yesterday
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            .and_utc()
+            .timestamp();
+        let summaries = self
+            .thread_repo
+            .lock()
+            .await
+            .get_summaries(username)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|summary| summary.created_at >= start_of_yesterday)
+            .map(|summary| format!("- {}", summary.summary))
+            .collect::<Vec<String>>();
+        if !summaries.is_empty() {
+            sections.push(format!(
+                "Recently closed conversations:\n{}",
+                summaries.join("\n")
+            ));
+        }
+
+        let birthdays = self
+            .contact_repo
+            .lock()
+            .await
+            .get_contacts(username)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|contact| {
+                days_until_birthday(contact, today).map(|days| match days {
+                    0 => format!("- {} has a birthday today", contact.name),
+                    days => format!("- {} has a birthday in {} days", contact.name, days),
+                })
+            })
+            .collect::<Vec<String>>();
+        if !birthdays.is_empty() {
+            sections.push(format!("Upcoming birthdays:\n{}", birthdays.join("\n")));
+        }
+
+        Ok(sections)
+    }
+}
+
+async fn polish(
+    chat_client: &Mutex<dyn ChatClient>,
+    sections: Vec<String>,
+) -> Result<String, ChatError> {
+    let system_prompt = "Write a short, friendly morning briefing for the user from the notes below. Lead with anything time sensitive, skip small talk, and do not invent facts that are not in the notes.";
+    let context = vec![
+        Message {
+            role: "system".to_string(),
+            content: system_prompt.to_string(),
+        },
+        Message {
+            role: "user".to_string(),
+            content: sections.join("\n\n"),
+        },
+    ];
+
+    Ok(chat_client.lock().await.complete(context).await?.content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_days_until_birthday() {
+        let today = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        let contact = |birthday: &str| ContactModel {
+            name: "Mark".to_string(),
+            relation: None,
+            birthday: Some(birthday.to_string()),
+            notes: None,
+            source: "manual".to_string(),
+            updated_at: 0,
+        };
+
+        assert_eq!(days_until_birthday(&contact("1990-03-01"), today), Some(0));
+        assert_eq!(days_until_birthday(&contact("03-04"), today), Some(3));
+        assert_eq!(days_until_birthday(&contact("1990-05-01"), today), None);
+    }
+}
@@ -1,14 +1,31 @@
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use tracing::{error, info};
+use sha2::{Digest, Sha256};
+use tracing::{error, info, warn};
 
 use crate::{
     clients::{
-        chat::{GptClient, Message},
-        embeddings,
+        chat::{ChatClient, ChatError, ContextBuilder, Message, Role, Usage},
+        embeddings, models,
+    },
+    config::DedupMode,
+    repos::{
+        messages::{ChatModel, EmbeddingStatus, HistoryQuery},
+        RepoError,
+        presets::ContextPreset,
+        summaries::SummaryModel,
+        threads::ThreadSummaryModel,
+    },
+    services::{
+        context::{self, Candidate, PackedContext, Reason},
+        embedding_queue::{EmbeddingJob, EmbeddingQueue},
+        ranking::{self, Ranking},
+        dedup::DedupService, feedback::adjust_ranking, importance::is_trivial, latency::LatencyBudget,
+        memory::is_suppressed, usage::UsageService,
+        pii::{PiiScrubber, REDACTED_METADATA},
     },
-    repos::messages::ChatModel,
 };
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 use tokio::sync::Mutex;
 
 #[derive(Deserialize, Serialize)]
@@ -16,52 +33,444 @@ pub struct ChatRequest {
     pub role: String,
     pub content: String,
     pub hash: String,
+    #[serde(default)]
+    pub conversation_id: Option<String>,
+    /// When the message was said, in seconds since the epoch, for clients
+    /// that send messages after the fact
+    #[serde(default)]
+    pub occurred_at: Option<i64>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum SaveError {
+    /// The user already has this message and duplicates are rejected
+    Duplicate(ChatResponse),
+    Failed,
+}
+
+impl From<()> for SaveError {
+    fn from(_: ()) -> Self {
+        SaveError::Failed
+    }
+}
+
+impl From<RepoError> for SaveError {
+    fn from(e: RepoError) -> Self {
+        error!("Repo error: {}", e);
+        SaveError::Failed
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ContextQuery {
+    pub preset: Option<String>,
+    /// Persona whose system prompt opens the returned context
+    pub persona: Option<String>,
+    /// Packs the most relevant and recent memories into this many tokens
+    /// instead of returning the recent history
+    pub max_tokens: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct HistoryParams {
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    /// First day to include, as `YYYY-MM-DD`
+    pub from_date: Option<String>,
+    /// Last day to include, as `YYYY-MM-DD`
+    pub to_date: Option<String>,
+    /// Comma separated tags or `key=value` metadata pairs the messages
+    /// must all have, e.g. `source=telegram,health`
+    pub tags: Option<String>,
+}
+
+/// The tags in a comma separated list, without empty ones
+pub fn parse_tags(tags: &Option<String>) -> Vec<String> {
+    match tags {
+        Some(tags) => tags
+            .split(',')
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect(),
+        None => vec![],
+    }
+}
+
+// Page size when the client does not ask for one, and the largest allowed
+const DEFAULT_HISTORY_LIMIT: usize = 100;
+const MAX_HISTORY_LIMIT: usize = 1000;
+
+impl HistoryParams {
+    /// `None` when one of the dates is not a valid `YYYY-MM-DD`
+    pub fn to_query(&self) -> Option<HistoryQuery> {
+        let parse = |date: &Option<String>| match date {
+            Some(date) => chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").ok().map(Some),
+            None => Some(None),
+        };
+        Some(HistoryQuery {
+            limit: self
+                .limit
+                .unwrap_or(DEFAULT_HISTORY_LIMIT)
+                .min(MAX_HISTORY_LIMIT),
+            offset: self.offset.unwrap_or(0),
+            from_date: parse(&self.from_date)?,
+            to_date: parse(&self.to_date)?,
+            tags: parse_tags(&self.tags),
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct HistoryResponse {
+    pub messages: Vec<ChatResponse>,
+    pub next_offset: Option<usize>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DeleteMode {
+    /// Remove the message entirely
+    #[default]
+    Delete,
+    /// Keep the hash so references stay valid, but wipe content and embedding
+    Redact,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteParams {
+    #[serde(default)]
+    pub mode: DeleteMode,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    /// Rank by embedding similarity
+    #[default]
+    Semantic,
+    /// Rank by matching words, for exact terms such as names or error codes
+    Keyword,
+    /// Merge both, ranking messages that match either way
+    Hybrid,
 }
 
 #[derive(Deserialize)]
 pub struct SearchRequest {
     pub content: String,
+    #[serde(default)]
+    pub mode: SearchMode,
+    /// Most results to return, best first
+    pub limit: Option<usize>,
+    /// Results ranked below this are left out
+    pub min_score: Option<f32>,
+    /// Weighs recency and role into semantic and hybrid rankings
+    #[serde(default)]
+    pub ranking: Ranking,
+    /// Only messages with all of these tags or `key=value` metadata pairs
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Leave out results too close to a better one, such as retries and
+    /// repeated greetings, to make room for different ones
+    #[serde(default)]
+    pub diversify: bool,
+    /// Also rank the stored day, week and month summaries, which match a
+    /// whole stretch of time when no single message does. Not in keyword
+    /// searches or ones filtered by tags, document or dates.
+    #[serde(default)]
+    pub summaries: bool,
+    /// Only the chunks of this ingested email or document
+    #[serde(default)]
+    pub document_id: Option<String>,
+    /// First day to search, as `YYYY-MM-DD`
+    #[serde(default)]
+    pub from_date: Option<String>,
+    /// Last day to search, as `YYYY-MM-DD`
+    #[serde(default)]
+    pub to_date: Option<String>,
 }
 
-#[derive(Deserialize, Serialize, Clone)]
+// Results when the client does not ask for a number, and the most allowed
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+const MAX_SEARCH_LIMIT: usize = 500;
+
+impl SearchRequest {
+    /// A semantic search with the defaults a client gets
+    pub fn new(content: &str) -> Self {
+        SearchRequest {
+            content: content.to_string(),
+            mode: SearchMode::default(),
+            limit: None,
+            min_score: None,
+            ranking: Ranking::default(),
+            tags: vec![],
+            diversify: false,
+            summaries: false,
+            document_id: None,
+            from_date: None,
+            to_date: None,
+        }
+    }
+
+    fn limit(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_SEARCH_LIMIT)
+            .min(MAX_SEARCH_LIMIT)
+    }
+
+    /// First and last day searched, `None` when one of the dates is not a
+    /// valid `YYYY-MM-DD`
+    pub fn dates(&self) -> Option<(Option<chrono::NaiveDate>, Option<chrono::NaiveDate>)> {
+        let parse = |date: &Option<String>| match date {
+            Some(date) => chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").ok().map(Some),
+            None => Some(None),
+        };
+        Some((parse(&self.from_date)?, parse(&self.to_date)?))
+    }
+
+    // Whether the message was said on one of the days searched
+    fn includes_day_of(&self, chat: &ChatModel) -> bool {
+        let (from, to) = self.dates().unwrap_or_default();
+        chat.said_between(from, to)
+    }
+
+    // Sorts rankings best first and keeps the top ones above `min_score`
+    fn select(&self, ranked: Vec<(f32, ChatModel)>) -> Vec<SearchResponse> {
+        self.select_with_summaries(ranked, vec![])
+    }
+
+    // Like `select`, ranking `summaries` among the messages
+    fn select_with_summaries(
+        &self,
+        mut ranked: Vec<(f32, ChatModel)>,
+        summaries: Vec<(f32, SummaryModel)>,
+    ) -> Vec<SearchResponse> {
+        ranked.retain(|(_, chat)| chat.matches_tags(&self.tags) && self.includes_day_of(chat));
+        if let Some(document_id) = &self.document_id {
+            ranked.retain(|(_, chat)| chat.metadata.get("document_id") == Some(document_id));
+        }
+        let mut results = ranked
+            .into_iter()
+            .map(|(ranking, chat)| {
+                let embedding = chat.embedding.clone();
+                (ranking, embedding, SearchResponse::from_chat_model(chat, ranking))
+            })
+            .chain(summaries.into_iter().map(|(ranking, summary)| {
+                let embedding = summary.embedding.clone();
+                (ranking, embedding, SearchResponse::from_summary(summary, ranking))
+            }))
+            .collect::<Vec<(f32, Option<Vec<f32>>, SearchResponse)>>();
+        if let Some(min_score) = self.min_score {
+            results.retain(|(ranking, _, _)| *ranking >= min_score);
+        }
+        results.sort_by(|a, b| b.0.total_cmp(&a.0));
+        if self.diversify {
+            return ranking::diversify(results, self.limit());
+        }
+        results.truncate(self.limit());
+        results.into_iter().map(|(_, _, result)| result).collect()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CompleteRequest {
+    pub content: String,
+    #[serde(default)]
+    pub conversation_id: Option<String>,
+    /// Most memories to put in front of the model
+    pub limit: Option<usize>,
+    /// Model answering instead of the configured one, from
+    /// `models.allowed_chat_models`
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+// Memories recalled for a completion when the client does not ask for a number
+const DEFAULT_COMPLETE_MEMORIES: usize = 5;
+
+// Relevant memories and days of recent messages offered to a packed context
+const PACK_RELEVANT_LIMIT: usize = 50;
+const PACK_RECENT_DAYS: i64 = 2;
+
+#[derive(Serialize)]
+pub struct CompleteResponse {
+    pub reply: ChatResponse,
+    /// The memories the reply was given as context
+    pub memories: Vec<SearchResponse>,
+    /// Tokens the chat model spent on the reply
+    pub usage: Usage,
+}
+
+/// Why a completion could not be answered
+#[derive(Debug)]
+pub enum CompleteError {
+    /// The chat model failed, nothing was stored
+    Chat(ChatError),
+    /// The user has spent today's token quota
+    QuotaExceeded,
+    /// The request asked for a model that is not allowed
+    ModelNotAllowed(String),
+    /// Recalling memories or storing the messages failed
+    Internal,
+}
+
+impl From<()> for CompleteError {
+    fn from(_: ()) -> Self {
+        CompleteError::Internal
+    }
+}
+
+// Opens the context of a completion with the recalled memories, best first,
+// and ends it with the prompt
+fn completion_context(memories: &[SearchResponse], prompt: &str) -> Vec<Message> {
+    let mut context = ContextBuilder::new();
+    let system_prompt = "You are a helpful assistant with a long term memory of the user. Use the memories below when they are relevant to the user's message and ignore them when they are not.";
+    context.add_message(Role::System, system_prompt.to_string());
+    if !memories.is_empty() {
+        let memories = memories
+            .iter()
+            .map(|memory| format!("- {}: {}", memory.role, memory.content))
+            .collect::<Vec<String>>()
+            .join("\n");
+        context.add_message(Role::System, format!("Memories:\n{}", memories));
+    }
+    context.add_message(Role::User, prompt.to_string());
+    context.build()
+}
+
+// Hash of a message written by the server rather than sent by a client
+fn message_hash(role: &str, content: &str) -> String {
+    let now = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    let seed = format!("{}:{}:{}", role, content, now);
+    format!("{:x}", Sha256::digest(seed.as_bytes()))
+}
+
+// Share of the hybrid ranking that comes from the keyword score
+const HYBRID_KEYWORD_WEIGHT: f32 = 0.5;
+
+// Merges semantic and keyword results by hash. Keyword scores are unbounded,
+// so they are scaled by the best one before being weighed against similarity.
+fn merge_hybrid(
+    semantic: Vec<(f32, ChatModel)>,
+    keyword: Vec<(f32, ChatModel)>,
+) -> Vec<(f32, ChatModel)> {
+    let best = keyword.iter().map(|(score, _)| *score).fold(0.0, f32::max);
+    let mut merged: Vec<(f32, ChatModel)> = semantic
+        .into_iter()
+        .map(|(similarity, chat)| ((1.0 - HYBRID_KEYWORD_WEIGHT) * similarity, chat))
+        .collect();
+    for (score, chat) in keyword {
+        let score = HYBRID_KEYWORD_WEIGHT * score / best;
+        match merged.iter_mut().find(|(_, merged)| merged.hash == chat.hash) {
+            Some((ranking, _)) => *ranking += score,
+            None => merged.push((score, chat)),
+        }
+    }
+    merged.sort_by(|a, b| b.0.total_cmp(&a.0));
+    merged
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct ChatResponse {
     pub role: String,
     pub content: String,
     pub hash: String,
+    /// When the message was stored, absent for messages that never were
+    /// such as personas
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub occurred_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedding_status: Option<EmbeddingStatus>,
 }
 
 impl ChatResponse {
-    #[allow(dead_code)]
     pub fn new(role: String, content: String, hash: String) -> ChatResponse {
         ChatResponse {
             role,
             content,
             hash,
+            created_at: None,
+            occurred_at: None,
+            metadata: HashMap::new(),
+            tags: vec![],
+            embedding_status: None,
         }
     }
     pub fn from_model(model: ChatModel) -> ChatResponse {
         ChatResponse {
+            embedding_status: Some(model.embedding_status()),
             role: model.role,
             content: model.content,
             hash: model.hash,
+            created_at: Some(model.timestamp),
+            occurred_at: model.occurred_at,
+            metadata: model.metadata,
+            tags: model.tags,
         }
     }
 }
 
+/// What a search result is
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchResultKind {
+    #[default]
+    Message,
+    /// A day, week or month summary, its `hash` is the period it covers
+    Summary,
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct SearchResponse {
+    #[serde(default)]
+    pub kind: SearchResultKind,
     pub role: String,
     pub content: String,
     pub hash: String,
     pub ranking: f32,
+    pub timestamp: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub occurred_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
 }
 impl SearchResponse {
-    fn from_chat_model(clone: ChatModel, ranking: f32) -> SearchResponse {
+    pub(crate) fn from_chat_model(clone: ChatModel, ranking: f32) -> SearchResponse {
         SearchResponse {
+            kind: SearchResultKind::Message,
             role: clone.role,
             content: clone.content,
             hash: clone.hash,
             ranking,
+            timestamp: clone.timestamp,
+            occurred_at: clone.occurred_at,
+            metadata: clone.metadata,
+            tags: clone.tags,
+        }
+    }
+
+    fn from_summary(summary: SummaryModel, ranking: f32) -> SearchResponse {
+        SearchResponse {
+            kind: SearchResultKind::Summary,
+            role: Role::Assistant.to_string(),
+            content: summary.summary,
+            hash: summary.date,
+            ranking,
+            timestamp: summary.created_at,
+            occurred_at: None,
+            metadata: HashMap::new(),
+            tags: vec![],
         }
     }
 }
@@ -69,9 +478,24 @@ impl SearchResponse {
 #[derive(Clone)]
 pub struct ChatService {
     pub(crate) embedding_client: Arc<Mutex<dyn embeddings::EmbeddingsClient>>,
+    pub(crate) chat_client: Arc<Mutex<dyn ChatClient>>,
     pub(crate) message_repo: Arc<Mutex<dyn crate::repos::messages::MessageRepo>>,
+    pub(crate) thread_repo: Arc<Mutex<dyn crate::repos::threads::ThreadSummaryRepo>>,
+    pub(crate) suppression_repo: Arc<Mutex<dyn crate::repos::suppressions::SuppressionRepo>>,
+    pub(crate) feedback_repo: Arc<Mutex<dyn crate::repos::feedback::FeedbackRepo>>,
+    pub(crate) usage_repo: Arc<Mutex<dyn crate::repos::usage::UsageRepo>>,
+    /// Embeds saved messages in the background when set, instead of before
+    /// they are saved
+    pub(crate) embedding_queue: Option<Arc<EmbeddingQueue>>,
+    pub(crate) summary_repo: Arc<Mutex<dyn crate::repos::summaries::SummaryRepo>>,
+    /// Masks personal details of saved messages when set, for the users
+    /// who have scrubbing turned on
+    pub(crate) pii_scrubber: Option<PiiScrubber>,
 }
 
+// Messages of a batch that are embedded at the same time
+const BATCH_CONCURRENCY: usize = 8;
+
 // Splits the last 15 elements from the first
 fn split_first_from_last_relevant(chats: Vec<ChatModel>) -> (Vec<ChatModel>, Vec<ChatModel>) {
     let len = chats.len();
@@ -84,6 +508,7 @@ fn split_first_from_last_relevant(chats: Vec<ChatModel>) -> (Vec<ChatModel>, Vec
 }
 
 // checks if we are 15 messages since the last system message
+#[allow(dead_code)]
 fn check_last_system_message(chats: Vec<ChatModel>) -> bool {
     let len = chats.len();
     if len > 14 {
@@ -98,37 +523,211 @@ fn check_if_contains_system_message(chats: Vec<ChatModel>) -> bool {
     chats.iter().any(|chat| chat.role == "system")
 }
 
+// Rough token count, close enough for budgeting without a tokenizer
+pub fn estimate_tokens(text: &str) -> usize {
+    text.len().div_ceil(4)
+}
+
+// Keeps the newest messages that fit within the preset's limits
+fn limit_to_preset(chats: Vec<ChatModel>, preset: &ContextPreset) -> Vec<ChatModel> {
+    let mut used_tokens = 0;
+    let mut kept = vec![];
+    for chat in chats.into_iter().rev() {
+        if let Some(top_k) = preset.top_k {
+            if kept.len() >= top_k {
+                break;
+            }
+        }
+        if let Some(max_tokens) = preset.max_tokens {
+            let tokens = estimate_tokens(&chat.role) + estimate_tokens(&chat.content);
+            if used_tokens + tokens > max_tokens {
+                break;
+            }
+            used_tokens += tokens;
+        }
+        kept.push(chat);
+    }
+    kept.reverse();
+    kept
+}
+
+// Swaps the turns of closed threads for their stored summary, keeping the
+// summary where the thread started
+fn replace_summarized_threads(
+    chats: Vec<ChatModel>,
+    summaries: &[ThreadSummaryModel],
+) -> Vec<ChatModel> {
+    let summaries: HashMap<&String, &ThreadSummaryModel> = summaries
+        .iter()
+        .map(|summary| (&summary.conversation_id, summary))
+        .collect();
+    let mut inserted: Vec<String> = vec![];
+    let mut result = vec![];
+
+    for chat in chats {
+        let summary = chat
+            .conversation_id
+            .as_ref()
+            .and_then(|id| summaries.get(id))
+            .filter(|summary| chat.timestamp <= summary.last_message_timestamp);
+
+        match summary {
+            Some(summary) => {
+                if inserted.contains(&summary.conversation_id) {
+                    continue;
+                }
+                inserted.push(summary.conversation_id.clone());
+                result.push(ChatModel {
+                    role: "system".to_string(),
+                    content: format!(
+                        "{}\n{}",
+                        "The following is an LLM summary of an earlier conversation:",
+                        summary.summary
+                    ),
+                    hash: "".to_string(),
+                    embedding: None,
+                    timestamp: summary.last_message_timestamp,
+                    conversation_id: Some(summary.conversation_id.clone()),
+                    forgotten: false,
+                    low_value: false,
+                    embedding_model: None,
+                    occurred_at: None,
+                    metadata: HashMap::new(),
+                    tags: vec![],
+                });
+            }
+            None => result.push(chat),
+        }
+    }
+    result
+}
+
 impl ChatService {
+    /// The service over the shared clients and repos, scrubbing saved
+    /// messages as the configuration says
+    pub(crate) fn from_resources(resources: &crate::Resources) -> ChatService {
+        ChatService {
+            embedding_client: resources.embeddings_client.clone(),
+            chat_client: resources.chat_client.clone(),
+            message_repo: resources.message_repo.clone(),
+            thread_repo: resources.thread_repo.clone(),
+            suppression_repo: resources.suppression_repo.clone(),
+            feedback_repo: resources.feedback_repo.clone(),
+            usage_repo: resources.usage_repo.clone(),
+            embedding_queue: resources.embedding_queue.clone(),
+            summary_repo: resources.summary_repo.clone(),
+            pii_scrubber: Some(PiiScrubber::from_resources(resources)),
+        }
+    }
+
+    /// Mock clients and file repos over `message_repo`, without background
+    /// embeddings or scrubbing
+    #[cfg(test)]
+    pub(crate) fn for_tests(
+        message_repo: Arc<Mutex<dyn crate::repos::messages::MessageRepo>>,
+    ) -> ChatService {
+        use crate::repos::{
+            feedback::FsFeedbackRepo, summaries::FsSummaryRepo, suppressions::FsSuppressionRepo,
+            threads::FsThreadSummaryRepo, usage::FsUsageRepo,
+        };
+        ChatService {
+            embedding_client: Arc::new(Mutex::new(embeddings::MockEmbeddingsClient::new())),
+            chat_client: Arc::new(Mutex::new(crate::clients::chat::MockChatClient::new())),
+            message_repo,
+            thread_repo: Arc::new(Mutex::new(FsThreadSummaryRepo::new())),
+            suppression_repo: Arc::new(Mutex::new(FsSuppressionRepo::new())),
+            feedback_repo: Arc::new(Mutex::new(FsFeedbackRepo::new())),
+            usage_repo: Arc::new(Mutex::new(FsUsageRepo::new())),
+            embedding_queue: None,
+            summary_repo: Arc::new(Mutex::new(FsSummaryRepo::new())),
+            pii_scrubber: None,
+        }
+    }
+
+    fn usage(&self) -> UsageService {
+        UsageService {
+            usage_repo: self.usage_repo.clone(),
+        }
+    }
+
     pub async fn get_context(
         &self,
-        username: &String,
-        text: &String,
+        username: &str,
+        _text: &str,
+        preset: &ContextPreset,
+        budget: &LatencyBudget,
     ) -> Result<Vec<ChatResponse>, ()> {
-        let chats = self
+        // the last two days are read first so there is something to answer
+        // with if the full history takes too long to load
+        let today = chrono::Utc::now().date_naive();
+        let recent = self
             .message_repo
             .lock()
             .await
-            .get_all_for_user(username.clone());
+            .get_for_dates(username.to_string(), vec![today - chrono::Duration::days(1), today])
+            .await;
+        // the read is dropped, and the repo unlocked, when the budget gives
+        // up on it
+        let full_history = async {
+            self.message_repo
+                .lock()
+                .await
+                .get_all_for_user(username.to_string())
+                .await
+        };
+        // without the full history the context is built from the recent
+        // days, which fails the request only when they cannot be read either
+        let chats = match budget.run("retrieval", budget.retrieval, full_history).await {
+            Some(Ok(chats)) => chats,
+            Some(Err(_)) => {
+                error!("Failed to load message history");
+                recent?
+            }
+            None => {
+                warn!("Falling back to recent messages for {}", username);
+                recent?
+            }
+        };
 
-        // lets filter out any messages that might be blank
+        let suppressions = self
+            .suppression_repo
+            .lock()
+            .await
+            .get_suppressions(username)
+            .unwrap_or_default();
+
+        // lets filter out any messages that might be blank or were forgotten
         let chats = chats
-            .unwrap()
             .into_iter()
-            .filter(|chat| chat.content != "")
+            .filter(|chat| !chat.content.is_empty())
+            .filter(|chat| !is_suppressed(chat, &suppressions))
+            .filter(|chat| match &preset.roles {
+                Some(roles) => roles.contains(&chat.role),
+                None => true,
+            })
             .collect::<Vec<ChatModel>>();
 
+        let chats = if preset.thread_summaries {
+            let thread_summaries = self
+                .thread_repo
+                .lock()
+                .await
+                .get_summaries(username)
+                .unwrap_or_default();
+            replace_summarized_threads(chats, &thread_summaries)
+        } else {
+            chats
+        };
+
         let system_prompt = "Summarize the following content, picking out what would be important to keep in the context model for a chat with a large language model. This is intended to be read only by the model so don't worry about human readability, optimise for a language model.";
         let system_prompt = Message {
             role: "system".to_string(),
             content: system_prompt.to_string(),
         };
-        let mut summary_context = Vec::new();
-        summary_context.push(system_prompt);
-
-        let mut chatclient = GptClient::new();
+        let mut summary_context = vec![system_prompt];
 
         let len = chats.len();
-        let recent_history = if len > 15 {
+        let recent_history = if len > 15 && preset.rolling_summary {
             let (first, last) = split_first_from_last_relevant(chats);
             let (_, to_summarize) = split_first_from_last_relevant(first.clone());
 
@@ -137,36 +736,63 @@ impl ChatService {
             let mut final_result = last.clone();
 
             if contains_system_message {
-                let to_summarize: Vec<Message> = to_summarize
-                    .iter()
-                    .map(|chat| Message {
-                        role: chat.role.clone(),
-                        content: chat.content.clone(),
+                summary_context.extend(to_summarize.iter().map(|chat| Message {
+                    role: chat.role.clone(),
+                    content: chat.content.clone(),
+                }));
+                let summary = budget
+                    .run("completion", budget.completion, async {
+                        self.chat_client.lock().await.complete(summary_context).await
                     })
-                    .collect();
-                let result = chatclient.complete(to_summarize).await;
-                let system_summary = ChatModel {
-                    role: "system".to_string(),
-                    embedding: None,
-                    hash: "".to_string(),
-                    timestamp: chrono::Utc::now().timestamp(),
-                    content: format!(
-                        "{}\n{}",
-                        "The following is an LLM summary of the chat so far:", result
-                    ),
-                };
-                let today = chrono::Utc::now().date_naive();
-                let mut message_repo = self.message_repo.lock().await;
-                let _result =
-                    message_repo.save_chat(today, username.clone(), system_summary.clone());
-                final_result.push(system_summary);
+                    .await;
+                // without a summary in time the context is just the recent turns
+                if let Some(Err(e)) = &summary {
+                    error!("Error summarizing context: {}", e);
+                }
+                if let Some(Ok(result)) = summary {
+                    self.usage().record(username, &result.usage).await;
+                    let system_summary = ChatModel {
+                        role: "system".to_string(),
+                        embedding: None,
+                        hash: "".to_string(),
+                        timestamp: chrono::Utc::now().timestamp(),
+                        content: format!(
+                            "{}\n{}",
+                            "The following is an LLM summary of the chat so far:", result.content
+                        ),
+                        conversation_id: None,
+                        forgotten: false,
+                        low_value: false,
+                        embedding_model: None,
+                        occurred_at: None,
+                        metadata: HashMap::new(),
+                        tags: vec![],
+                    };
+                    let today = chrono::Utc::now().date_naive();
+                    let mut message_repo = self.message_repo.lock().await;
+                    if let Err(e) = message_repo
+                        .save_chat(today, username.to_string(), system_summary.clone())
+                        .await
+                    {
+                        error!("Error saving summary: {}", e);
+                    }
+                    final_result.push(system_summary);
+                }
             }
             final_result
         } else {
             chats
         };
+        // without an explicit budget the context is sized to the active model
+        let model = self.chat_client.lock().await.model();
+        let preset = ContextPreset {
+            max_tokens: preset
+                .max_tokens
+                .or_else(|| Some(models::context_budget(&model))),
+            ..preset.clone()
+        };
+        let recent_history = limit_to_preset(recent_history, &preset);
 
-        
         Ok(recent_history
             .iter()
             .map(|chat| {
@@ -177,19 +803,330 @@ impl ChatService {
             .collect())
     }
 
+    /// The memories most relevant to `text` and most recent that fit into
+    /// `max_tokens`, with how each of them ranked
+    pub async fn pack_context(
+        &self,
+        username: &str,
+        text: &str,
+        preset: &ContextPreset,
+        max_tokens: usize,
+    ) -> Result<PackedContext, ()> {
+        let relevant = match is_trivial(text) {
+            true => vec![],
+            false => {
+                let search = SearchRequest {
+                    content: text.to_string(),
+                    mode: SearchMode::Semantic,
+                    limit: Some(PACK_RELEVANT_LIMIT),
+                    min_score: None,
+                    ranking: Ranking::default(),
+                    tags: vec![],
+                    diversify: false,
+                    summaries: false,
+                    document_id: None,
+                    from_date: None,
+                    to_date: None,
+                };
+                self.search_chat(username, &search).await?
+            }
+        };
+        let today = chrono::Utc::now().date_naive();
+        let days = (0..PACK_RECENT_DAYS)
+            .rev()
+            .map(|days| today - chrono::Duration::days(days))
+            .collect();
+        let recent = self
+            .message_repo
+            .lock()
+            .await
+            .get_for_dates(username.to_string(), days)
+            .await?;
+        let suppressions = self
+            .suppression_repo
+            .lock()
+            .await
+            .get_suppressions(username)
+            .unwrap_or_default();
+
+        let mut candidates = relevant
+            .into_iter()
+            .map(|memory| Candidate {
+                role: memory.role,
+                content: memory.content,
+                hash: memory.hash,
+                timestamp: memory.timestamp,
+                occurred_at: memory.occurred_at,
+                relevance: memory.ranking,
+                reasons: vec![Reason::Relevant],
+            })
+            .collect::<Vec<Candidate>>();
+        candidates.extend(
+            recent
+                .into_iter()
+                .filter(|chat| !chat.forgotten && !chat.content.is_empty())
+                .filter(|chat| !is_suppressed(chat, &suppressions))
+                .map(|chat| Candidate {
+                    role: chat.role,
+                    content: chat.content,
+                    hash: chat.hash,
+                    timestamp: chat.timestamp,
+                    occurred_at: chat.occurred_at,
+                    relevance: 0.0,
+                    reasons: vec![Reason::Recent],
+                }),
+        );
+        candidates.retain(|candidate| match &preset.roles {
+            Some(roles) => roles.contains(&candidate.role),
+            None => true,
+        });
+
+        let packed = context::pack(candidates, max_tokens, chrono::Utc::now().timestamp());
+        info!(
+            "Packed {} memories into {} of {} tokens for {}",
+            packed.memories.len(),
+            packed.used_tokens,
+            max_tokens,
+            username
+        );
+        Ok(packed)
+    }
+
+    /// Answers `request` with the chat model, given the user's most relevant
+    /// memories as context. Both the prompt and the reply are stored, and are
+    /// returned in that order with the memories that were used.
+    pub async fn complete(
+        &self,
+        username: &str,
+        request: CompleteRequest,
+    ) -> Result<(ChatResponse, CompleteResponse), CompleteError> {
+        if let Some(model) = &request.model {
+            if !crate::config::get().models.allows_chat_model(model) {
+                return Err(CompleteError::ModelNotAllowed(model.clone()));
+            }
+        }
+        if self.usage().quota_exceeded(username).await? {
+            return Err(CompleteError::QuotaExceeded);
+        }
+
+        // recall before the prompt is stored so it does not find itself
+        let search = SearchRequest {
+            content: request.content.clone(),
+            mode: SearchMode::Semantic,
+            limit: Some(request.limit.unwrap_or(DEFAULT_COMPLETE_MEMORIES)),
+            min_score: None,
+            ranking: Ranking::default(),
+            tags: vec![],
+            diversify: false,
+            summaries: false,
+            document_id: None,
+            from_date: None,
+            to_date: None,
+        };
+        let memories = self.search_chat(username, &search).await?;
+
+        let context = completion_context(&memories, &request.content);
+        let completion = self
+            .chat_client
+            .lock()
+            .await
+            .complete_with(context, request.model.as_deref())
+            .await
+            .map_err(CompleteError::Chat)?;
+        self.usage().record(username, &completion.usage).await;
+        info!(
+            "Completed with {} in {} tokens",
+            completion.model, completion.usage.total_tokens
+        );
+
+        // every turn of a conversation is kept, repeating yourself included
+        let prompt = self
+            .save_chat(
+                username,
+                ChatRequest {
+                    role: Role::User.to_string(),
+                    hash: message_hash("user", &request.content),
+                    content: request.content,
+                    conversation_id: request.conversation_id.clone(),
+                    occurred_at: None,
+                    metadata: HashMap::new(),
+                    tags: vec![],
+                },
+                DedupMode::Allow,
+            )
+            .await
+            .map_err(|_| CompleteError::Internal)?;
+        let reply = self
+            .save_chat(
+                username,
+                ChatRequest {
+                    role: Role::Assistant.to_string(),
+                    hash: message_hash("assistant", &completion.content),
+                    content: completion.content,
+                    conversation_id: request.conversation_id,
+                    occurred_at: None,
+                    metadata: HashMap::new(),
+                    tags: vec![],
+                },
+                DedupMode::Allow,
+            )
+            .await
+            .map_err(|_| CompleteError::Internal)?;
+        Ok((
+            prompt,
+            CompleteResponse {
+                reply,
+                memories,
+                usage: completion.usage,
+            },
+        ))
+    }
+
+    /// Saves several messages at once, embedding up to `BATCH_CONCURRENCY`
+    /// of them at a time. Nothing is stored if any embedding fails.
+    pub async fn save_chats(
+        &self,
+        username: &str,
+        chats: Vec<ChatRequest>,
+    ) -> Result<Vec<ChatResponse>, ()> {
+        let mut scrubbed = vec![];
+        for chat in chats {
+            scrubbed.push(self.scrub(username, chat).await);
+        }
+        let chats = scrubbed;
+        let (embeddings, model) = {
+            let embeddings_client = self.embedding_client.lock().await;
+            let similarity = embeddings_client.similarity();
+            let background = self.embedding_queue.is_some();
+            // built in a loop rather than mapped, a closure here would keep
+            // the batch from being saved in spawned tasks
+            let mut requests = vec![];
+            for chat in chats.iter() {
+                let embeddings_client = &embeddings_client;
+                requests.push(async move {
+                    match background || is_trivial(&chat.content) {
+                        true => Ok(None),
+                        false => embeddings_client
+                            .get_embeddings(chat.content.clone())
+                            .await
+                            .map(|embedding| Some(similarity.prepare(embedding))),
+                    }
+                });
+            }
+            let embeddings = futures::stream::iter(requests)
+                .buffered(BATCH_CONCURRENCY)
+                .collect::<Vec<Result<Option<Vec<f32>>, ()>>>()
+                .await;
+            (embeddings, embeddings_client.model())
+        };
+
+        let timestamp = chrono::Utc::now().timestamp();
+        let mut chat_models = vec![];
+        for (chat, embedding) in chats.into_iter().zip(embeddings) {
+            let embedding = match embedding {
+                Ok(embedding) => embedding,
+                Err(_) => {
+                    error!("Failed to get embeddings for batch");
+                    return Err(());
+                }
+            };
+            chat_models.push(ChatModel {
+                role: chat.role,
+                low_value: is_trivial(&chat.content),
+                content: chat.content,
+                hash: chat.hash,
+                embedding_model: embedding.as_ref().map(|_| model.clone()),
+                embedding,
+                timestamp,
+                conversation_id: chat.conversation_id,
+                forgotten: false,
+                occurred_at: chat.occurred_at,
+                metadata: chat.metadata,
+                tags: chat.tags,
+            });
+        }
+
+        let today = chrono::Utc::now().date_naive();
+        let saved = self
+            .message_repo
+            .lock()
+            .await
+            .save_chats(today, username.to_string(), chat_models)
+            .await?;
+        info!("Saved batch of {} messages for {}", saved.len(), username);
+        self.queue_pending(username, &saved);
+        Ok(saved.into_iter().map(ChatResponse::from_model).collect())
+    }
+
+    // Masks the personal details of a message before it is embedded or
+    // stored, recording what was masked in its metadata
+    async fn scrub(&self, username: &str, mut chat: ChatRequest) -> ChatRequest {
+        if let Some(scrubber) = &self.pii_scrubber {
+            let scrubbed = scrubber.scrub(username, &chat.content).await;
+            if !scrubbed.kinds.is_empty() {
+                chat.content = scrubbed.content;
+                chat.metadata
+                    .insert(REDACTED_METADATA.to_string(), scrubbed.kinds.join(","));
+            }
+        }
+        chat
+    }
+
+    fn queue_pending(&self, username: &str, saved: &[ChatModel]) {
+        let queue = match &self.embedding_queue {
+            Some(queue) => queue,
+            None => return,
+        };
+        for chat in saved {
+            if chat.embedding_status() == EmbeddingStatus::Pending {
+                queue.enqueue(EmbeddingJob {
+                    username: username.to_string(),
+                    hash: chat.hash.clone(),
+                    content: chat.content.clone(),
+                });
+            }
+        }
+    }
+
+    /// Stores the message, unless `dedup` refuses one the user already has
     pub async fn save_chat(
         &self,
-        username: &String,
+        username: &str,
         chat: ChatRequest,
-    ) -> Result<ChatResponse, ()> {
-        let embeddings_client = self.embedding_client.lock().await;
-        let embeddings_result = embeddings_client.get_embeddings(chat.content.clone()).await;
+        dedup: DedupMode,
+    ) -> Result<ChatResponse, SaveError> {
+        let chat = self.scrub(username, chat).await;
+        let duplicate = match dedup {
+            DedupMode::Allow => None,
+            DedupMode::Reject | DedupMode::Overwrite => {
+                DedupService {
+                    message_repo: self.message_repo.clone(),
+                }
+                .find_duplicate(username, &chat.role, &chat.content)
+                .await?
+            }
+        };
+        if let (DedupMode::Reject, Some(duplicate)) = (dedup, &duplicate) {
+            return Err(SaveError::Duplicate(ChatResponse::from_model(
+                duplicate.clone(),
+            )));
+        }
 
-        let embeddings = match embeddings_result {
-            Ok(embeddings) => embeddings,
-            Err(_) => {
-                error!("Failed to get embeddings");
-                return Err(());
+        // trivial messages are kept for the record but never embedded
+        let low_value = is_trivial(&chat.content);
+        let (embeddings, embedding_model) = if low_value || self.embedding_queue.is_some() {
+            (None, None)
+        } else {
+            let embeddings_client = self.embedding_client.lock().await;
+            match embeddings_client.get_embeddings(chat.content.clone()).await {
+                Ok(embeddings) => (
+                    Some(embeddings_client.similarity().prepare(embeddings)),
+                    Some(embeddings_client.model()),
+                ),
+                Err(_) => {
+                    error!("Failed to get embeddings");
+                    return Err(SaveError::Failed);
+                }
             }
         };
 
@@ -197,44 +1134,121 @@ impl ChatService {
             role: chat.role.clone(),
             content: chat.content.clone(),
             hash: chat.hash.clone(),
-            embedding: None,
+            embedding: embeddings,
             timestamp: chrono::Utc::now().timestamp(),
+            conversation_id: chat.conversation_id.clone(),
+            forgotten: false,
+            low_value,
+            embedding_model,
+            occurred_at: chat.occurred_at,
+            metadata: chat.metadata.clone(),
+            tags: chat.tags.clone(),
         };
 
         let mut message_repo = self.message_repo.lock().await;
+        // the message it replaces goes only once the new one can be saved
+        if let Some(duplicate) = duplicate {
+            message_repo
+                .delete_chat(username.to_string(), duplicate.hash)
+                .await?;
+        }
         let today = chrono::Utc::now().date_naive();
-        let result = message_repo.save_chat(today, username.clone(), chat_model.clone());
+        let result = message_repo.save_chat(today, username.to_string(), chat_model.clone()).await?;
+        drop(message_repo);
+        self.queue_pending(username, std::slice::from_ref(&result));
         let chat_response = ChatResponse::from_model(result);
         Ok(chat_response)
     }
 
-    pub async fn get_chat(&self, username: &String, id: &String) -> Result<ChatResponse, ()> {
-        let chat = match self
+    pub async fn get_chat(&self, username: &str, id: &String) -> Result<ChatResponse, RepoError> {
+        let chat = self
             .message_repo
             .lock()
             .await
-            .get_chat(username.clone(), id.clone())
-        {
-            Ok(chat) => chat,
-            Err(_) => {
-                error!("Failed to get_chat");
-                return Err(());
-            }
-        };
+            .get_chat(username.to_string(), id.to_string())
+            .await?;
 
         let chat_response = ChatResponse::from_model(chat);
         Ok(chat_response.clone())
     }
 
+    pub async fn delete_chat(
+        &self,
+        username: &str,
+        id: &str,
+        mode: DeleteMode,
+    ) -> Result<(), RepoError> {
+        let mut message_repo = self.message_repo.lock().await;
+        match mode {
+            DeleteMode::Delete => {
+                message_repo
+                    .delete_chat(username.to_string(), id.to_string())
+                    .await
+            }
+            DeleteMode::Redact => message_repo
+                .redact_chat(username.to_string(), id.to_string())
+                .await
+                .map(|_| ()),
+        }
+    }
+
+    pub async fn get_history(
+        &self,
+        username: &str,
+        query: &HistoryQuery,
+    ) -> Result<HistoryResponse, ()> {
+        let page = self
+            .message_repo
+            .lock()
+            .await
+            .get_history(username.to_string(), query)
+            .await?;
+        Ok(HistoryResponse {
+            messages: page
+                .messages
+                .into_iter()
+                .filter(|chat| !chat.forgotten)
+                .map(ChatResponse::from_model)
+                .collect(),
+            next_offset: page.next_offset,
+        })
+    }
+
     pub async fn search_chat(
         &self,
-        username: &String,
-        query: &String,
+        username: &str,
+        request: &SearchRequest,
     ) -> Result<Vec<SearchResponse>, ()> {
+        let query = request.content.as_str();
+        let mode = request.mode;
+        let suppressions = self
+            .suppression_repo
+            .lock()
+            .await
+            .get_suppressions(username)
+            .unwrap_or_default();
+        let feedback = self
+            .feedback_repo
+            .lock()
+            .await
+            .get_feedback(username)
+            .unwrap_or_default();
         let repo = self.message_repo.lock().await;
 
+        let keyword = match mode {
+            SearchMode::Semantic => vec![],
+            _ => repo.keyword_search_for_user(username.to_string(), query).await?,
+        };
+        if mode == SearchMode::Keyword {
+            let founds = keyword
+                .into_iter()
+                .filter(|(_, chat)| !is_suppressed(chat, &suppressions))
+                .collect();
+            return Ok(request.select(founds));
+        }
+
         let embeddings_client = self.embedding_client.lock().await;
-        let query_vector = embeddings_client.get_embeddings(query.clone()).await;
+        let query_vector = embeddings_client.get_embeddings(query.to_string()).await;
         let query_vector = match query_vector {
             Ok(query_vector) => query_vector,
             Err(_) => {
@@ -243,14 +1257,59 @@ impl ChatService {
             }
         };
 
+        let (from, to) = request.dates().unwrap_or_default();
         let founds = repo
-            .embeddings_search_for_user(username.clone(), query_vector)
-            .await;
+            .embeddings_search_between(
+                username.to_string(),
+                query_vector.clone(),
+                &embeddings_client.model(),
+                embeddings_client.similarity(),
+                from,
+                to,
+            )
+            .await?;
+        let founds = match mode {
+            SearchMode::Hybrid => merge_hybrid(founds, keyword),
+            _ => founds,
+        };
         let founds = founds
-            .iter()
-            .map(|(similarity, chat)| SearchResponse::from_chat_model(chat.clone(), *similarity))
+            .into_iter()
+            .filter(|(_, chat)| !chat.low_value && !is_suppressed(chat, &suppressions))
+            .map(|(similarity, chat)| {
+                let ranking = adjust_ranking(similarity, &chat.hash, &query_vector, &feedback);
+                (ranking, chat)
+            })
             .collect();
-        Ok(founds)
+        let founds = request
+            .ranking
+            .apply(founds, chrono::Utc::now().timestamp());
+        let with_summaries = request.summaries
+            && request.tags.is_empty()
+            && request.document_id.is_none()
+            && request.from_date.is_none()
+            && request.to_date.is_none();
+        let summaries = match with_summaries {
+            true => {
+                let model = embeddings_client.model();
+                let similarity = embeddings_client.similarity();
+                self.summary_repo
+                    .lock()
+                    .await
+                    .get_summaries(username)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|summary| {
+                        if summary.embedding_model.as_ref() != Some(&model) {
+                            return None;
+                        }
+                        let score = similarity.score(summary.embedding.as_ref()?, &query_vector);
+                        Some((score, summary))
+                    })
+                    .collect()
+            }
+            false => vec![],
+        };
+        Ok(request.select_with_summaries(founds, summaries))
     }
 }
 
@@ -258,73 +1317,177 @@ impl ChatService {
 mod tests {
     use std::borrow::Borrow;
 
-    use crate::{clients::embeddings::MockEmbeddingsClient, repos::messages::MessageRepo};
+    use crate::{
+        clients::{
+            chat::MockChatClient,
+            embeddings::{MockEmbeddingsClient, Similarity},
+        },
+        repos::{
+            attributes::FsAttributeRepo,
+            messages::{FsMessageRepo, MessageRepo},
+            threads::ThreadSummaryRepo,
+        },
+    };
 
     use super::*;
     use async_trait::async_trait;
     use uuid::Uuid;
 
+    fn search_request(content: &str, mode: SearchMode) -> SearchRequest {
+        SearchRequest {
+            content: content.to_string(),
+            mode,
+            limit: None,
+            min_score: None,
+            ranking: Ranking::default(),
+            tags: vec![],
+            diversify: false,
+            summaries: false,
+            document_id: None,
+            from_date: None,
+            to_date: None,
+        }
+    }
+
     struct MockMessageRepo {
         chats: Vec<ChatModel>,
+        // day and user of every save_chat, in order
+        saved_to: Vec<(chrono::NaiveDate, String)>,
+        // how long reading the full history takes
+        history_delay: std::time::Duration,
     }
 
     impl MockMessageRepo {
         fn new() -> MockMessageRepo {
             MockMessageRepo {
-                chats: vec![ChatModel {
-                    role: "user".to_string(),
-                    content: "Hello".to_string(),
-                    hash: "123".to_string(),
-                    embedding: None,
-                    timestamp: chrono::Utc::now().timestamp(),
-                }],
+                saved_to: vec![],
+                history_delay: std::time::Duration::ZERO,
+                chats: vec![ChatModel::test("123", "Hello")],
             }
         }
     }
 
+    struct MockThreadSummaryRepo {
+        summaries: Vec<ThreadSummaryModel>,
+    }
+
+    impl MockThreadSummaryRepo {
+        fn new() -> MockThreadSummaryRepo {
+            MockThreadSummaryRepo { summaries: vec![] }
+        }
+    }
+
+    impl ThreadSummaryRepo for MockThreadSummaryRepo {
+        fn save_summary(
+            &mut self,
+            _user: &str,
+            summary: ThreadSummaryModel,
+        ) -> Result<ThreadSummaryModel, ()> {
+            self.summaries.push(summary.clone());
+            Ok(summary)
+        }
+
+        fn get_summaries(&self, _user: &str) -> Result<Vec<ThreadSummaryModel>, ()> {
+            Ok(self.summaries.clone())
+        }
+    }
+
     #[async_trait]
     impl MessageRepo for MockMessageRepo {
-        fn get_all_for_user_on_day(
+        async fn get_all_for_user_on_day(
             &self,
             _username: String,
             _date: chrono::NaiveDate,
-        ) -> Result<Vec<ChatModel>, ()> {
+        ) -> Result<Vec<ChatModel>, RepoError> {
             Ok(self.chats.clone())
         }
-        fn save_chat(
+        async fn save_chat(
             &mut self,
-            _date: chrono::NaiveDate,
-            _username: String,
+            date: chrono::NaiveDate,
+            username: String,
             chat: ChatModel,
-        ) -> ChatModel {
+        ) -> Result<ChatModel, RepoError> {
+            self.saved_to.push((date, username));
             self.chats.push(chat.clone());
-            chat
+            Ok(chat)
         }
 
-        fn get_chat(&mut self, _username: String, id: String) -> Result<ChatModel, ()> {
+        async fn get_chat(&mut self, _username: String, id: String) -> Result<ChatModel, RepoError> {
             let chat = self
                 .chats
                 .iter()
                 .find(|chat| chat.hash == id)
-                .unwrap()
+                .ok_or(RepoError::NotFound)?
                 .clone();
             Ok(chat)
         }
 
-        fn get_all_for_user(&self, _username: String) -> Result<Vec<ChatModel>, ()> {
+        async fn get_all_for_user(&self, _username: String) -> Result<Vec<ChatModel>, RepoError> {
+            tokio::time::sleep(self.history_delay).await;
             Ok(self.chats.clone())
         }
 
+        async fn get_for_dates(
+            &self,
+            _username: String,
+            _dates: Vec<chrono::NaiveDate>,
+        ) -> Result<Vec<ChatModel>, RepoError> {
+            Ok(self.chats.clone())
+        }
+
+        async fn get_users(&self) -> Result<Vec<String>, RepoError> {
+            Ok(vec!["test_user".to_string()])
+        }
+
+        async fn get_collections(&self, _username: String) -> Result<Vec<String>, RepoError> {
+            Ok(vec![])
+        }
+
+        async fn redact_chat(&mut self, _username: String, id: String) -> Result<ChatModel, RepoError> {
+            let chat = self.chats.iter_mut().find(|chat| chat.hash == id).ok_or(RepoError::NotFound)?;
+            chat.content = "".to_string();
+            chat.forgotten = true;
+            Ok(chat.clone())
+        }
+
+        async fn delete_chat(&mut self, _username: String, id: String) -> Result<(), RepoError> {
+            let position = self
+                .chats
+                .iter()
+                .position(|chat| chat.hash == id)
+                .ok_or(RepoError::NotFound)?;
+            self.chats.remove(position);
+            Ok(())
+        }
+
+        async fn replace_embeddings(
+            &mut self,
+            _username: String,
+            embeddings: HashMap<String, Vec<f32>>,
+            _model: &str,
+        ) -> Result<usize, RepoError> {
+            let mut replaced = 0;
+            for chat in self.chats.iter_mut() {
+                if let Some(embedding) = embeddings.get(&chat.hash) {
+                    chat.embedding = Some(embedding.clone());
+                    replaced += 1;
+                }
+            }
+            Ok(replaced)
+        }
+
         async fn embeddings_search_for_user(
             &self,
             _username: String,
             _query_vector: Vec<f32>,
-        ) -> Vec<(f32, ChatModel)> {
+            _model: &str,
+            _similarity: Similarity,
+        ) -> Result<Vec<(f32, ChatModel)>, RepoError> {
             let mut result = vec![];
             for chat in self.chats.iter() {
                 result.push((0.5, chat.clone()));
             }
-            result
+            Ok(result)
         }
     }
 
@@ -335,6 +1498,10 @@ mod tests {
             role: "user".to_string(),
             content: "Hello".to_string(),
             hash: id.clone(),
+            conversation_id: None,
+            occurred_at: None,
+            metadata: HashMap::new(),
+            tags: vec![],
         };
         let expected_hash = id.clone();
         let expected_role = chat.role.clone();
@@ -345,11 +1512,11 @@ mod tests {
 
         let chat_handler = ChatService {
             embedding_client: mock_embeddings.clone(),
-            message_repo: mock_repo.clone(),
+            ..ChatService::for_tests(mock_repo.clone())
         };
 
         chat_handler
-            .save_chat("test_user".to_string().borrow(), chat)
+            .save_chat("test_user".to_string().borrow(), chat, DedupMode::Allow)
             .await
             .unwrap();
 
@@ -361,6 +1528,83 @@ mod tests {
         assert_eq!(got_chat.role, expected_role);
         assert_eq!(got_chat.content, expected_content);
         assert_eq!(got_chat.hash, expected_hash);
+
+        // saved under the user from the route, on today's date
+        assert_eq!(
+            mock_repo.lock().await.saved_to,
+            vec![(chrono::Utc::now().date_naive(), "test_user".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_save_chats_keeps_order_and_skips_trivial_embeddings() {
+        let mock_repo = Arc::new(Mutex::new(MockMessageRepo::new()));
+        let chat_handler = ChatService::for_tests(mock_repo.clone());
+        let chats = ["I moved to Lisbon last week", "ok", "The flat has a balcony"]
+            .iter()
+            .enumerate()
+            .map(|(i, content)| ChatRequest {
+                role: "user".to_string(),
+                content: content.to_string(),
+                hash: format!("batch-{}", i),
+                conversation_id: None,
+                occurred_at: None,
+                metadata: HashMap::new(),
+                tags: vec![],
+            })
+            .collect::<Vec<ChatRequest>>();
+
+        let saved = chat_handler.save_chats("test_user", chats).await.unwrap();
+        assert_eq!(
+            saved.iter().map(|chat| chat.hash.as_str()).collect::<Vec<&str>>(),
+            vec!["batch-0", "batch-1", "batch-2"]
+        );
+
+        let stored = &mock_repo.lock().await.chats;
+        let stored = &stored[stored.len() - 3..];
+        assert!(stored[0].embedding.is_some());
+        assert!(stored[1].low_value && stored[1].embedding.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_saved_messages_are_scrubbed_before_storage() {
+        let mock_repo = Arc::new(Mutex::new(MockMessageRepo::new()));
+        let chat_client = Arc::new(Mutex::new(MockChatClient::new()));
+        let chat_handler = ChatService {
+            chat_client: chat_client.clone(),
+            pii_scrubber: Some(PiiScrubber {
+                attribute_repo: Arc::new(Mutex::new(FsAttributeRepo::new())),
+                chat_client,
+                enabled_by_default: true,
+                use_model: false,
+            }),
+            ..ChatService::for_tests(mock_repo.clone())
+        };
+        let username = format!("test_scrub_{}", Uuid::new_v4());
+        let chat = |hash: &str| ChatRequest {
+            role: "user".to_string(),
+            content: "Write to me at anna@example.com".to_string(),
+            hash: hash.to_string(),
+            conversation_id: None,
+            occurred_at: None,
+            metadata: HashMap::new(),
+            tags: vec![],
+        };
+
+        let saved = chat_handler
+            .save_chat(&username, chat("scrubbed-one"), DedupMode::Allow)
+            .await
+            .unwrap();
+        assert_eq!(saved.content, "Write to me at [email]");
+        assert_eq!(saved.metadata[REDACTED_METADATA], "email");
+
+        let saved = chat_handler
+            .save_chats(&username, vec![chat("scrubbed-batch")])
+            .await
+            .unwrap();
+        assert_eq!(saved[0].content, "Write to me at [email]");
+        let stored = mock_repo.lock().await.chats.clone();
+        assert!(stored.iter().all(|chat| !chat.content.contains("anna@")));
     }
 
     #[tokio::test]
@@ -370,10 +1614,10 @@ mod tests {
 
         let chat_handler = ChatService {
             embedding_client: mock_embeddings.clone(),
-            message_repo: mock_repo.clone(),
+            ..ChatService::for_tests(mock_repo.clone())
         };
 
-        let query = "Hello".to_string();
+        let query = search_request("Hello", SearchMode::Semantic);
         let founds = chat_handler
             .search_chat("test_user".to_string().borrow(), &query)
             .await
@@ -389,16 +1633,549 @@ mod tests {
 
         let chat_handler = ChatService {
             embedding_client: mock_embeddings.clone(),
-            message_repo: mock_repo.clone(),
+            ..ChatService::for_tests(mock_repo.clone())
         };
 
         let context = chat_handler
             .get_context(
                 "test_user".to_string().borrow(),
                 "my_message".to_string().borrow(),
+                &ContextPreset::default(),
+                &LatencyBudget::default(),
             )
             .await
             .unwrap();
         assert_eq!(context.len(), 1);
     }
+
+    #[tokio::test]
+    async fn test_slow_history_is_given_up_on_and_unlocked() {
+        let mut mock_repo = MockMessageRepo::new();
+        mock_repo.history_delay = std::time::Duration::from_secs(30);
+        let mock_repo = Arc::new(Mutex::new(mock_repo));
+        let chat_handler = ChatService::for_tests(mock_repo.clone());
+        let budget = LatencyBudget::new(
+            std::time::Duration::from_secs(5),
+            std::time::Duration::from_millis(20),
+            std::time::Duration::from_secs(5),
+        );
+
+        let context = chat_handler
+            .get_context("test_user", "hello", &ContextPreset::default(), &budget)
+            .await
+            .unwrap();
+        assert_eq!(context.len(), 1);
+        // nothing is left reading the history with the repo locked
+        assert!(mock_repo.try_lock().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_get_context_survives_an_unreadable_history() {
+        let username = format!("test_unreadable_{}", Uuid::new_v4());
+        let today = chrono::Utc::now().date_naive();
+        let message_repo = Arc::new(Mutex::new(FsMessageRepo::new()));
+        message_repo
+            .lock()
+            .await
+            .save_chat(
+                today,
+                username.clone(),
+                ChatModel::test("garage", "The car is at the garage"),
+            )
+            .await
+            .unwrap();
+        let corrupt = |date| {
+            let path = crate::repos::messages::get_path_for_date(username.clone(), date)
+                .join("messages.json");
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, "[{\"role\":").unwrap();
+        };
+        corrupt(today - chrono::Duration::days(30));
+        let chat_handler = ChatService::for_tests(message_repo);
+        let preset = ContextPreset::default();
+        let budget = LatencyBudget::default();
+        let context = || chat_handler.get_context(&username, "where is the car", &preset, &budget);
+
+        // an old day that cannot be read leaves the recent days
+        let recent = context().await.unwrap();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].content, "The car is at the garage");
+
+        // and when those cannot be read either the request fails
+        corrupt(today);
+        assert!(context().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_context_replaces_summarized_thread() {
+        let mut mock_repo = MockMessageRepo::new();
+        for (i, content) in ["Shall we plan the trip?", "Yes, Tuesday works"].iter().enumerate() {
+            mock_repo.chats.push(ChatModel {
+                timestamp: 100 + i as i64,
+                conversation_id: Some("trip".to_string()),
+                ..ChatModel::test(&format!("thread-{}", i), content)
+            });
+        }
+        let mut thread_repo = MockThreadSummaryRepo::new();
+        thread_repo.summaries.push(ThreadSummaryModel {
+            conversation_id: "trip".to_string(),
+            summary: "Trip planned for Tuesday".to_string(),
+            last_message_timestamp: 101,
+            created_at: 200,
+        });
+
+        let chat_handler = ChatService {
+            thread_repo: Arc::new(Mutex::new(thread_repo)),
+            ..ChatService::for_tests(Arc::new(Mutex::new(mock_repo)))
+        };
+
+        let context = chat_handler
+            .get_context(
+                "test_user",
+                "my_message",
+                &ContextPreset::default(),
+                &LatencyBudget::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(context.len(), 2);
+        assert_eq!(context[1].role, "system");
+        assert!(context[1].content.contains("Trip planned for Tuesday"));
+    }
+
+    #[tokio::test]
+    async fn test_get_context_respects_preset_limits() {
+        let mut mock_repo = MockMessageRepo::new();
+        for i in 0..5 {
+            mock_repo.chats.push(ChatModel {
+                role: "assistant".to_string(),
+                timestamp: 100 + i,
+                ..ChatModel::test(&format!("reply-{}", i), &format!("Reply number {}", i))
+            });
+        }
+
+        let chat_handler = ChatService::for_tests(Arc::new(Mutex::new(mock_repo)));
+
+        let preset = ContextPreset {
+            name: "watch".to_string(),
+            top_k: Some(2),
+            roles: Some(vec!["assistant".to_string()]),
+            ..ContextPreset::default()
+        };
+        let context = chat_handler
+            .get_context("test_user", "my_message", &preset, &LatencyBudget::default())
+            .await
+            .unwrap();
+        assert_eq!(context.len(), 2);
+        assert_eq!(context[1].hash, "reply-4");
+    }
+
+    #[tokio::test]
+    async fn test_trivial_message_is_stored_but_not_indexed() {
+        let chat_handler = ChatService::for_tests(Arc::new(Mutex::new(MockMessageRepo {
+            chats: vec![],
+            ..MockMessageRepo::new()
+        })));
+        let chat = ChatRequest {
+            role: "user".to_string(),
+            content: "thanks!".to_string(),
+            hash: "trivial".to_string(),
+            conversation_id: None,
+            occurred_at: None,
+            metadata: HashMap::new(),
+            tags: vec![],
+        };
+        chat_handler
+            .save_chat("test_user", chat, DedupMode::Allow)
+            .await
+            .unwrap();
+
+        let stored = chat_handler
+            .message_repo
+            .lock()
+            .await
+            .get_chat("test_user".to_string(), "trivial".to_string())
+            .await
+            .unwrap();
+        assert!(stored.low_value);
+        assert!(stored.embedding.is_none());
+
+        let founds = chat_handler
+            .search_chat("test_user", &search_request("thanks", SearchMode::Semantic))
+            .await
+            .unwrap();
+        assert!(founds.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_users_do_not_see_each_others_messages() {
+        let chat_handler = ChatService::for_tests(Arc::new(Mutex::new(FsMessageRepo::new())));
+        let alice = format!("test_alice_{}", Uuid::new_v4());
+        let bob = format!("test_bob_{}", Uuid::new_v4());
+        let hash = Uuid::new_v4().to_string();
+        let chat = ChatRequest {
+            role: "user".to_string(),
+            content: "My locker code is 4512".to_string(),
+            hash: hash.clone(),
+            conversation_id: None,
+            occurred_at: None,
+            metadata: HashMap::new(),
+            tags: vec![],
+        };
+        chat_handler
+            .save_chat(&alice, chat, DedupMode::Allow)
+            .await
+            .unwrap();
+
+        assert!(chat_handler.get_chat(&alice, &hash).await.is_ok());
+        assert!(chat_handler.get_chat(&bob, &hash).await.is_err());
+
+        for mode in [SearchMode::Semantic, SearchMode::Keyword] {
+            let founds = chat_handler
+                .search_chat(&alice, &search_request("locker code", mode))
+                .await
+                .unwrap();
+            assert_eq!(founds.len(), 1);
+            let founds = chat_handler
+                .search_chat(&bob, &search_request("locker code", mode))
+                .await
+                .unwrap();
+            assert!(founds.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pack_context_stays_within_budget() {
+        let chat_handler = ChatService::for_tests(Arc::new(Mutex::new(FsMessageRepo::new())));
+        let username = format!("test_pack_{}", Uuid::new_v4());
+        for content in ["My sister Ada lives in Oslo", "Ada's birthday is in May"] {
+            let chat = ChatRequest {
+                role: "user".to_string(),
+                content: content.to_string(),
+                hash: Uuid::new_v4().to_string(),
+                conversation_id: None,
+                occurred_at: None,
+                metadata: HashMap::new(),
+                tags: vec![],
+            };
+            chat_handler
+                .save_chat(&username, chat, DedupMode::Allow)
+                .await
+                .unwrap();
+        }
+        let budget = context::message_tokens("user", "My sister Ada lives in Oslo");
+
+        let packed = chat_handler
+            .pack_context(&username, "Where does Ada live?", &ContextPreset::default(), budget)
+            .await
+            .unwrap();
+        assert_eq!(packed.messages.len(), 1);
+        assert!(packed.used_tokens <= budget);
+        assert_eq!(packed.skipped, 1);
+        assert_eq!(
+            packed.memories[0].reasons,
+            vec![Reason::Relevant, Reason::Recent]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_save_chat_handles_duplicates() {
+        let message_repo = Arc::new(Mutex::new(FsMessageRepo::new()));
+        let chat_handler = ChatService::for_tests(message_repo.clone());
+        let username = format!("test_duplicates_{}", Uuid::new_v4());
+        let chat = |hash: &str| ChatRequest {
+            role: "user".to_string(),
+            content: "ok".to_string(),
+            hash: hash.to_string(),
+            conversation_id: None,
+            occurred_at: None,
+            metadata: HashMap::new(),
+            tags: vec![],
+        };
+        let stored = || async {
+            message_repo
+                .lock()
+                .await
+                .get_all_for_user(username.clone())
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|chat| chat.hash)
+                .collect::<Vec<String>>()
+        };
+
+        chat_handler
+            .save_chat(&username, chat("first"), DedupMode::Reject)
+            .await
+            .unwrap();
+        match chat_handler
+            .save_chat(&username, chat("second"), DedupMode::Reject)
+            .await
+        {
+            Err(SaveError::Duplicate(existing)) => assert_eq!(existing.hash, "first"),
+            _ => panic!("duplicate was not rejected"),
+        }
+        assert_eq!(stored().await, vec!["first"]);
+
+        chat_handler
+            .save_chat(&username, chat("second"), DedupMode::Overwrite)
+            .await
+            .unwrap();
+        assert_eq!(stored().await, vec!["second"]);
+
+        chat_handler
+            .save_chat(&username, chat("third"), DedupMode::Allow)
+            .await
+            .unwrap();
+        assert_eq!(stored().await.len(), 2);
+    }
+
+    struct FailingEmbeddingsClient {}
+
+    #[async_trait]
+    impl embeddings::EmbeddingsClient for FailingEmbeddingsClient {
+        async fn get_embeddings(&self, _: String) -> Result<Vec<f32>, ()> {
+            Err(())
+        }
+
+        fn model(&self) -> String {
+            "failing".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_overwrite_keeps_the_original_when_embedding_fails() {
+        let message_repo = Arc::new(Mutex::new(FsMessageRepo::new()));
+        let service =
+            |embedding_client: Arc<Mutex<dyn embeddings::EmbeddingsClient>>| ChatService {
+                embedding_client,
+                ..ChatService::for_tests(message_repo.clone())
+            };
+        let username = format!("test_overwrite_{}", Uuid::new_v4());
+        let chat = |hash: &str| ChatRequest {
+            role: "user".to_string(),
+            content: "The dentist moved to Thursday".to_string(),
+            hash: hash.to_string(),
+            conversation_id: None,
+            occurred_at: None,
+            metadata: HashMap::new(),
+            tags: vec![],
+        };
+
+        service(Arc::new(Mutex::new(MockEmbeddingsClient::new())))
+            .save_chat(&username, chat("original"), DedupMode::Allow)
+            .await
+            .unwrap();
+        let result = service(Arc::new(Mutex::new(FailingEmbeddingsClient {})))
+            .save_chat(&username, chat("replacement"), DedupMode::Overwrite)
+            .await;
+        assert!(matches!(result, Err(SaveError::Failed)));
+
+        let stored = message_repo
+            .lock()
+            .await
+            .get_all_for_user(username.clone())
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].hash, "original");
+    }
+
+    #[tokio::test]
+    async fn test_background_embeddings_save_before_embedding() {
+        let message_repo = Arc::new(Mutex::new(FsMessageRepo::new()));
+        let embedding_client = Arc::new(Mutex::new(MockEmbeddingsClient::new()));
+        let queue = Arc::new(EmbeddingQueue::start(
+            embedding_client.clone(),
+            message_repo.clone(),
+            1,
+            std::time::Duration::from_millis(1),
+        ));
+        let chat_handler = ChatService {
+            embedding_client,
+            embedding_queue: Some(queue.clone()),
+            ..ChatService::for_tests(message_repo.clone())
+        };
+        let username = format!("test_background_{}", Uuid::new_v4());
+        let chat = ChatRequest {
+            role: "user".to_string(),
+            content: "The boiler is serviced every March".to_string(),
+            hash: "boiler".to_string(),
+            conversation_id: None,
+            occurred_at: None,
+            metadata: HashMap::new(),
+            tags: vec![],
+        };
+
+        let saved = chat_handler
+            .save_chat(&username, chat, DedupMode::Allow)
+            .await
+            .unwrap();
+        assert_eq!(saved.embedding_status, Some(EmbeddingStatus::Pending));
+        for _ in 0..200 {
+            if queue.stats().depth == 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        let stored = chat_handler
+            .get_chat(&username, &"boiler".to_string())
+            .await
+            .unwrap();
+        assert_eq!(stored.embedding_status, Some(EmbeddingStatus::Ready));
+    }
+
+    #[test]
+    fn test_hybrid_merges_results_by_hash() {
+        let chat = |hash: &str| ChatModel {
+            timestamp: 0,
+            ..ChatModel::test(hash, hash)
+        };
+        let semantic = vec![(0.9, chat("close")), (0.4, chat("both"))];
+        let keyword = vec![(6.0, chat("both")), (3.0, chat("word"))];
+
+        let merged = merge_hybrid(semantic, keyword);
+        let hashes = merged.iter().map(|(_, c)| c.hash.as_str()).collect::<Vec<&str>>();
+        assert_eq!(hashes, vec!["both", "close", "word"]);
+        assert!((merged[0].0 - 0.7).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_search_keeps_top_results_above_min_score() {
+        let chat = |hash: &str| ChatModel {
+            timestamp: 0,
+            ..ChatModel::test(hash, hash)
+        };
+        let request = SearchRequest {
+            limit: Some(2),
+            min_score: Some(0.3),
+            ..search_request("query", SearchMode::Semantic)
+        };
+        let ranked = vec![
+            (0.5, chat("middle")),
+            (0.1, chat("weak")),
+            (0.9, chat("best")),
+            (0.4, chat("cut")),
+        ];
+
+        let selected = request.select(ranked);
+        let hashes = selected.iter().map(|r| r.hash.as_str()).collect::<Vec<&str>>();
+        assert_eq!(hashes, vec!["best", "middle"]);
+    }
+
+    #[test]
+    fn test_search_keeps_chunks_of_the_document() {
+        let chat = |hash: &str, document_id: Option<&str>| ChatModel {
+            timestamp: 0,
+            metadata: document_id
+                .map(|id| HashMap::from([("document_id".to_string(), id.to_string())]))
+                .unwrap_or_default(),
+            ..ChatModel::test(hash, hash)
+        };
+        let request = SearchRequest {
+            document_id: Some("document-1".to_string()),
+            ..search_request("query", SearchMode::Semantic)
+        };
+        let ranked = vec![
+            (0.9, chat("said", None)),
+            (0.5, chat("other", Some("document-2"))),
+            (0.4, chat("chunk", Some("document-1"))),
+        ];
+
+        let selected = request.select(ranked);
+        let hashes = selected
+            .iter()
+            .map(|r| r.hash.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(hashes, vec!["chunk"]);
+    }
+
+    #[test]
+    fn test_search_keeps_messages_of_the_dates() {
+        let chat = |hash: &str, timestamp: i64, occurred_at: Option<i64>| ChatModel {
+            timestamp,
+            occurred_at,
+            ..ChatModel::test(hash, hash)
+        };
+        // 2024-03-01, 2024-03-05 and 2024-03-09
+        let (first, fifth, ninth) = (1_709_294_400, 1_709_640_000, 1_709_985_600);
+        let ranked = vec![
+            (0.9, chat("before", first, None)),
+            (0.8, chat("within", fifth, None)),
+            // said before it was stored, the day it was said counts
+            (0.7, chat("recalled", ninth, Some(fifth))),
+            (0.6, chat("after", ninth, None)),
+        ];
+        let request = SearchRequest {
+            from_date: Some("2024-03-02".to_string()),
+            to_date: Some("2024-03-08".to_string()),
+            ..search_request("query", SearchMode::Semantic)
+        };
+        let selected = request.select(ranked.clone());
+        let hashes = selected
+            .iter()
+            .map(|r| r.hash.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(hashes, vec!["within", "recalled"]);
+
+        let request = SearchRequest {
+            from_date: Some("2024-03-05".to_string()),
+            ..search_request("query", SearchMode::Semantic)
+        };
+        assert_eq!(request.select(ranked).len(), 3);
+        let invalid = SearchRequest {
+            to_date: Some("March".to_string()),
+            ..search_request("query", SearchMode::Semantic)
+        };
+        assert!(invalid.dates().is_none());
+    }
+
+    #[test]
+    fn test_summaries_are_ranked_among_messages() {
+        let chat = ChatModel {
+            timestamp: 0,
+            ..ChatModel::test("fever", "Took the day off with a fever")
+        };
+        let summary = SummaryModel {
+            date: "2024-W09".to_string(),
+            summary: "A week spent sick at home".to_string(),
+            message_count: 12,
+            created_at: 0,
+            model: None,
+            prompt_version: None,
+            embedding: None,
+            embedding_model: None,
+        };
+        let request = search_request("the week I was sick", SearchMode::Semantic);
+
+        let selected = request.select_with_summaries(vec![(0.4, chat)], vec![(0.8, summary)]);
+        assert_eq!(selected.len(), 2);
+        assert_eq!(selected[0].kind, SearchResultKind::Summary);
+        assert_eq!(selected[0].hash, "2024-W09");
+        assert_eq!(selected[1].kind, SearchResultKind::Message);
+    }
+
+    #[test]
+    fn test_completion_context_puts_memories_before_prompt() {
+        let memory = SearchResponse {
+            kind: SearchResultKind::Message,
+            role: "user".to_string(),
+            content: "My sister is called Ada".to_string(),
+            hash: "ada".to_string(),
+            ranking: 0.9,
+            timestamp: 0,
+            occurred_at: None,
+            metadata: HashMap::new(),
+            tags: vec![],
+        };
+
+        let context = completion_context(&[memory], "What is my sister's name?");
+        assert_eq!(context.len(), 3);
+        assert_eq!(context[0].role, "system");
+        assert!(context[1].content.contains("- user: My sister is called Ada"));
+        assert_eq!(context[2].role, "user");
+        assert_eq!(context[2].content, "What is my sister's name?");
+
+        assert_eq!(completion_context(&[], "Hi").len(), 2);
+    }
 }
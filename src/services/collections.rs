@@ -0,0 +1,135 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::repos::{collection_key, messages::MessageRepo};
+
+/// Collection for routes without one, stored under the plain username so
+/// memories saved before collections existed stay where they are
+pub const DEFAULT_COLLECTION: &str = "default";
+
+/// Letters, digits, `-` and `_`, so names are safe as folder names
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Key the user's memories in `collection` are stored under, `None` when the
+/// collection name is not valid
+pub fn storage_key(username: &str, collection: Option<&str>) -> Option<String> {
+    match collection {
+        None | Some(DEFAULT_COLLECTION) => Some(username.to_string()),
+        Some(collection) if is_valid_name(collection) => Some(collection_key(username, collection)),
+        Some(_) => None,
+    }
+}
+
+/// Path of the chat routes, with or without a collection segment
+#[derive(Deserialize)]
+pub struct CollectionPath {
+    pub username: String,
+    pub collection: Option<String>,
+}
+
+impl CollectionPath {
+    pub fn storage_key(&self) -> Option<String> {
+        storage_key(&self.username, self.collection.as_deref())
+    }
+}
+
+/// Path of the routes for a single message
+#[derive(Deserialize)]
+pub struct CollectionChatPath {
+    pub username: String,
+    pub collection: Option<String>,
+    pub id: String,
+}
+
+impl CollectionChatPath {
+    pub fn storage_key(&self) -> Option<String> {
+        storage_key(&self.username, self.collection.as_deref())
+    }
+}
+
+#[derive(Serialize)]
+pub struct CollectionsResponse {
+    pub collections: Vec<String>,
+}
+
+pub struct CollectionService {
+    pub(crate) message_repo: Arc<Mutex<dyn MessageRepo>>,
+}
+
+impl CollectionService {
+    /// The default collection followed by the user's named ones
+    pub async fn get_collections(&self, username: &str) -> Result<CollectionsResponse, ()> {
+        let named = self
+            .message_repo
+            .lock()
+            .await
+            .get_collections(username.to_string())
+            .await?;
+        let mut collections = vec![DEFAULT_COLLECTION.to_string()];
+        collections.extend(
+            named
+                .into_iter()
+                .filter(|collection| collection != DEFAULT_COLLECTION),
+        );
+        Ok(CollectionsResponse { collections })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repos::messages::{ChatModel, FsMessageRepo};
+
+    #[test]
+    fn test_storage_keys() {
+        assert_eq!(storage_key("mark", None).unwrap(), "mark");
+        assert_eq!(storage_key("mark", Some("default")).unwrap(), "mark");
+        assert_eq!(
+            storage_key("mark", Some("work")).unwrap(),
+            "mark/collections/work"
+        );
+        assert!(storage_key("mark", Some("../anna")).is_none());
+        assert!(storage_key("mark", Some("")).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_collections_are_listed_and_kept_apart() {
+        let username = format!("test_collections_{}", uuid::Uuid::new_v4());
+        let message_repo = Arc::new(Mutex::new(FsMessageRepo::new()));
+        let work = storage_key(&username, Some("work")).unwrap();
+        let chat = ChatModel {
+            low_value: true,
+            ..ChatModel::test("standup", "Standup moved to ten")
+        };
+        message_repo
+            .lock()
+            .await
+            .save_chat(chrono::Utc::now().date_naive(), work.clone(), chat)
+            .await
+            .unwrap();
+
+        let service = CollectionService {
+            message_repo: message_repo.clone(),
+        };
+        assert_eq!(
+            service
+                .get_collections(&username)
+                .await
+                .unwrap()
+                .collections,
+            vec!["default", "work"]
+        );
+
+        let repo = message_repo.lock().await;
+        assert_eq!(repo.get_all_for_user(work).await.unwrap().len(), 1);
+        assert!(repo.get_all_for_user(username).await.unwrap().is_empty());
+    }
+}
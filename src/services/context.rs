@@ -0,0 +1,206 @@
+use serde::Serialize;
+
+use crate::{
+    clients::models::count_tokens,
+    services::{chat::ChatResponse, ranking::recency},
+};
+
+// Share of a memory's score that comes from how relevant it is to the
+// request, the rest comes from how recent it is
+const RELEVANCE_WEIGHT: f32 = 0.7;
+
+// Days after which a memory counts half as recent
+const RECENCY_HALF_LIFE_DAYS: f32 = 7.0;
+
+/// Why a memory was considered for the context
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Reason {
+    /// Similar to the request
+    Relevant,
+    /// Said in the last days
+    Recent,
+}
+
+/// A message that could go into the context, with what it is known for
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub role: String,
+    pub content: String,
+    pub hash: String,
+    pub timestamp: i64,
+    pub occurred_at: Option<i64>,
+    /// Similarity to the request, 0 for memories that are only recent
+    pub relevance: f32,
+    pub reasons: Vec<Reason>,
+}
+
+impl Candidate {
+    fn said_at(&self) -> i64 {
+        self.occurred_at.unwrap_or(self.timestamp)
+    }
+}
+
+/// A memory that made it into the context and how it scored
+#[derive(Serialize, Clone, Debug)]
+pub struct IncludedMemory {
+    pub hash: String,
+    pub relevance: f32,
+    /// 1 for a message said now, halving every week
+    pub recency: f32,
+    pub score: f32,
+    pub tokens: usize,
+    pub reasons: Vec<Reason>,
+}
+
+#[derive(Serialize)]
+pub struct PackedContext {
+    /// The included memories oldest first, ready to send
+    pub messages: Vec<ChatResponse>,
+    /// The included memories best first
+    pub memories: Vec<IncludedMemory>,
+    pub max_tokens: usize,
+    pub used_tokens: usize,
+    /// Candidates left out because they did not fit
+    pub skipped: usize,
+}
+
+/// Tokens a message takes up in the context, as it is sent
+pub fn message_tokens(role: &str, content: &str) -> usize {
+    count_tokens(&format!("{}: {}", role, content))
+}
+
+/// Ranks the candidates by relevance and recency and packs the best ones
+/// that fit into `max_tokens`. A memory that does not fit is skipped, so a
+/// smaller one ranked below it can still make it in. Candidates found more
+/// than once are merged by hash.
+pub fn pack(candidates: Vec<Candidate>, max_tokens: usize, now: i64) -> PackedContext {
+    let mut merged: Vec<Candidate> = vec![];
+    for candidate in candidates {
+        match merged
+            .iter_mut()
+            .find(|merged| merged.hash == candidate.hash)
+        {
+            Some(merged) => {
+                merged.relevance = merged.relevance.max(candidate.relevance);
+                for reason in candidate.reasons {
+                    if !merged.reasons.contains(&reason) {
+                        merged.reasons.push(reason);
+                    }
+                }
+            }
+            None => merged.push(candidate),
+        }
+    }
+
+    let mut ranked = merged
+        .into_iter()
+        .map(|candidate| {
+            let recency = recency(candidate.said_at(), now, RECENCY_HALF_LIFE_DAYS);
+            let score = RELEVANCE_WEIGHT * candidate.relevance + (1.0 - RELEVANCE_WEIGHT) * recency;
+            (score, recency, candidate)
+        })
+        .collect::<Vec<(f32, f32, Candidate)>>();
+    ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut used_tokens = 0;
+    let mut skipped = 0;
+    let mut included = vec![];
+    for (score, recency, candidate) in ranked {
+        let tokens = message_tokens(&candidate.role, &candidate.content);
+        if used_tokens + tokens > max_tokens {
+            skipped += 1;
+            continue;
+        }
+        used_tokens += tokens;
+        included.push((score, recency, tokens, candidate));
+    }
+
+    let memories = included
+        .iter()
+        .map(|(score, recency, tokens, candidate)| IncludedMemory {
+            hash: candidate.hash.clone(),
+            relevance: candidate.relevance,
+            recency: *recency,
+            score: *score,
+            tokens: *tokens,
+            reasons: candidate.reasons.clone(),
+        })
+        .collect();
+    included.sort_by_key(|(_, _, _, candidate)| candidate.said_at());
+    let messages = included
+        .into_iter()
+        .map(|(_, _, _, candidate)| ChatResponse {
+            created_at: Some(candidate.timestamp),
+            occurred_at: candidate.occurred_at,
+            ..ChatResponse::new(candidate.role, candidate.content, candidate.hash)
+        })
+        .collect();
+
+    PackedContext {
+        messages,
+        memories,
+        max_tokens,
+        used_tokens,
+        skipped,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(hash: &str, content: &str, age_days: i64, relevance: f32) -> Candidate {
+        Candidate {
+            role: "user".to_string(),
+            content: content.to_string(),
+            hash: hash.to_string(),
+            timestamp: 1_000_000 - age_days * 86_400,
+            occurred_at: None,
+            relevance,
+            reasons: match relevance > 0.0 {
+                true => vec![Reason::Relevant],
+                false => vec![Reason::Recent],
+            },
+        }
+    }
+
+    #[test]
+    fn test_pack_ranks_and_fits_the_budget() {
+        let long = "word ".repeat(50);
+        let candidates = vec![
+            candidate("recent", "Dinner with Anna tonight", 0, 0.0),
+            candidate("relevant", "Anna is allergic to nuts", 30, 0.9),
+            candidate("long", &long, 1, 0.95),
+            candidate("old", "Anna moved to Berlin", 365, 0.2),
+            // found again by the recency pass
+            candidate("relevant", "Anna is allergic to nuts", 30, 0.0),
+        ];
+        let budget = message_tokens("user", "Dinner with Anna tonight")
+            + message_tokens("user", "Anna is allergic to nuts")
+            + message_tokens("user", "Anna moved to Berlin");
+
+        let packed = pack(candidates, budget, 1_000_000);
+
+        let ranked = packed
+            .memories
+            .iter()
+            .map(|memory| memory.hash.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(ranked, vec!["relevant", "recent", "old"]);
+        assert_eq!(
+            packed.memories[0].reasons,
+            vec![Reason::Relevant, Reason::Recent]
+        );
+        assert!((packed.memories[1].recency - 1.0).abs() < 1e-6);
+        assert_eq!(packed.skipped, 1);
+        assert_eq!(packed.used_tokens, budget);
+
+        let chronological = packed
+            .messages
+            .iter()
+            .map(|message| message.hash.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(chronological, vec!["old", "relevant", "recent"]);
+    }
+}
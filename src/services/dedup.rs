@@ -0,0 +1,127 @@
+use std::{collections::HashSet, sync::Arc};
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::repos::messages::{ChatModel, MessageRepo};
+
+/// Whether `chat` says the same as a message with `role` and `content`.
+/// Forgotten messages have had their content wiped and never match.
+pub fn is_duplicate(chat: &ChatModel, role: &str, content: &str) -> bool {
+    !chat.forgotten && chat.role == role && chat.content == content
+}
+
+#[derive(Serialize, Debug)]
+pub struct DedupReport {
+    /// Messages left, one for each role and content
+    pub kept: usize,
+    pub removed: usize,
+}
+
+pub struct DedupService {
+    pub message_repo: Arc<Mutex<dyn MessageRepo>>,
+}
+
+impl DedupService {
+    /// The stored message with the same role and content, the oldest if
+    /// there are several
+    pub async fn find_duplicate(
+        &self,
+        username: &str,
+        role: &str,
+        content: &str,
+    ) -> Result<Option<ChatModel>, ()> {
+        Ok(self
+            .message_repo
+            .lock()
+            .await
+            .get_all_for_user(username.to_string())
+            .await?
+            .into_iter()
+            .filter(|chat| is_duplicate(chat, role, content))
+            .min_by_key(|chat| chat.timestamp))
+    }
+
+    /// Removes every message that repeats an older one, keeping the first
+    /// time something was said
+    pub async fn dedupe(&self, username: &str) -> Result<DedupReport, ()> {
+        let mut message_repo = self.message_repo.lock().await;
+        let mut chats = message_repo.get_all_for_user(username.to_string()).await?;
+        chats.sort_by_key(|chat| chat.timestamp);
+
+        let mut seen = HashSet::new();
+        let mut kept = 0;
+        let mut removed = 0;
+        for chat in chats {
+            if chat.forgotten || seen.insert((chat.role.clone(), chat.content.clone())) {
+                kept += 1;
+                continue;
+            }
+            message_repo
+                .delete_chat(username.to_string(), chat.hash.clone())
+                .await?;
+            removed += 1;
+        }
+        info!("Removed {} duplicate messages for {}", removed, username);
+        Ok(DedupReport { kept, removed })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repos::messages::FsMessageRepo;
+
+    #[tokio::test]
+    async fn test_dedupe_keeps_the_first_copy() {
+        let username = format!("test_dedupe_{}", uuid::Uuid::new_v4());
+        let message_repo = Arc::new(Mutex::new(FsMessageRepo::new()));
+        let today = chrono::Utc::now().date_naive();
+        for (hash, role, content, timestamp) in [
+            ("first", "user", "Parked on level 3", 100),
+            ("again", "user", "Parked on level 3", 200),
+            ("reply", "assistant", "Parked on level 3", 300),
+            ("other", "user", "Parked on level 4", 400),
+        ] {
+            let chat = ChatModel {
+                role: role.to_string(),
+                timestamp,
+                low_value: true,
+                ..ChatModel::test(hash, content)
+            };
+            message_repo
+                .lock()
+                .await
+                .save_chat(today, username.clone(), chat)
+                .await
+                .unwrap();
+        }
+        let service = DedupService {
+            message_repo: message_repo.clone(),
+        };
+
+        let duplicate = service
+            .find_duplicate(&username, "user", "Parked on level 3")
+            .await
+            .unwrap();
+        assert_eq!(duplicate.unwrap().hash, "first");
+
+        let report = service.dedupe(&username).await.unwrap();
+        assert_eq!(report.removed, 1);
+        assert_eq!(report.kept, 3);
+        let hashes = message_repo
+            .lock()
+            .await
+            .get_all_for_user(username.clone())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|chat| chat.hash)
+            .collect::<Vec<String>>();
+        assert!(!hashes.contains(&"again".to_string()));
+        assert!(hashes.contains(&"first".to_string()));
+
+        assert_eq!(service.dedupe(&username).await.unwrap().removed, 0);
+    }
+}
@@ -0,0 +1,292 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{error, info, warn};
+
+use crate::{
+    clients::embeddings::EmbeddingsClient,
+    repos::messages::{EmbeddingStatus, MessageRepo},
+};
+
+/// A saved message waiting for its embedding
+#[derive(Clone, Debug)]
+pub struct EmbeddingJob {
+    pub username: String,
+    pub hash: String,
+    pub content: String,
+}
+
+#[derive(Default)]
+struct Counters {
+    depth: AtomicUsize,
+    embedded: AtomicUsize,
+    retries: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct QueueStats {
+    /// Messages waiting, the one being embedded included
+    pub depth: usize,
+    pub embedded: usize,
+    pub retries: usize,
+    /// Messages given up on, they stay pending until re-embedded
+    pub failed: usize,
+}
+
+impl QueueStats {
+    /// The counters as OpenMetrics samples
+    pub fn render(&self, out: &mut String, labels: &str) {
+        let samples = [
+            (
+                "muninn_embedding_queue_depth",
+                "gauge",
+                "Messages waiting to be embedded",
+                self.depth,
+            ),
+            (
+                "muninn_embedding_queue_embedded",
+                "counter",
+                "Messages embedded in the background",
+                self.embedded,
+            ),
+            (
+                "muninn_embedding_queue_retries",
+                "counter",
+                "Background embeddings tried again",
+                self.retries,
+            ),
+            (
+                "muninn_embedding_queue_failed",
+                "counter",
+                "Background embeddings given up on",
+                self.failed,
+            ),
+        ];
+        for (name, kind, help, value) in samples {
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let name = match kind {
+                "counter" => format!("{}_total", name),
+                _ => name.to_string(),
+            };
+            let _ = writeln!(out, "{}{} {}", name, labels, value);
+        }
+    }
+}
+
+/// Embeds saved messages one at a time in the background, so saving does
+/// not wait for the embeddings backend or fail when it is down
+pub struct EmbeddingQueue {
+    sender: mpsc::UnboundedSender<EmbeddingJob>,
+    counters: Arc<Counters>,
+}
+
+struct Worker {
+    embedding_client: Arc<Mutex<dyn EmbeddingsClient>>,
+    message_repo: Arc<Mutex<dyn MessageRepo>>,
+    attempts: u32,
+    retry_delay: Duration,
+    counters: Arc<Counters>,
+}
+
+impl Worker {
+    async fn run(self, mut receiver: mpsc::UnboundedReceiver<EmbeddingJob>) {
+        while let Some(job) = receiver.recv().await {
+            self.process(&job).await;
+            self.counters.depth.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    // Tries the job with a doubling delay between attempts
+    async fn process(&self, job: &EmbeddingJob) {
+        let mut delay = self.retry_delay;
+        for attempt in 1..=self.attempts.max(1) {
+            if attempt > 1 {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                self.counters.retries.fetch_add(1, Ordering::Relaxed);
+            }
+            let (embedding, model) = {
+                let embedding_client = self.embedding_client.lock().await;
+                let embedding = embedding_client.get_embeddings(job.content.clone()).await;
+                let embedding = embedding.map(|e| embedding_client.similarity().prepare(e));
+                (embedding, embedding_client.model())
+            };
+            let embedding = match embedding {
+                Ok(embedding) => embedding,
+                Err(_) => {
+                    warn!("Embedding {} failed on attempt {}", job.hash, attempt);
+                    continue;
+                }
+            };
+            let saved = self
+                .message_repo
+                .lock()
+                .await
+                .set_embedding(job.username.clone(), job.hash.clone(), embedding, &model)
+                .await;
+            match saved {
+                Ok(_) => {
+                    self.counters.embedded.fetch_add(1, Ordering::Relaxed);
+                }
+                // deleted while it waited, there is nothing left to embed
+                Err(e) => warn!("Could not store embedding of {}: {}", job.hash, e),
+            }
+            return;
+        }
+        error!(
+            "Giving up embedding {} for {} after {} attempts",
+            job.hash, job.username, self.attempts
+        );
+        self.counters.failed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl EmbeddingQueue {
+    /// Starts the worker, which runs until the queue is dropped
+    pub fn start(
+        embedding_client: Arc<Mutex<dyn EmbeddingsClient>>,
+        message_repo: Arc<Mutex<dyn MessageRepo>>,
+        attempts: u32,
+        retry_delay: Duration,
+    ) -> EmbeddingQueue {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let counters = Arc::new(Counters::default());
+        let worker = Worker {
+            embedding_client,
+            message_repo,
+            attempts,
+            retry_delay,
+            counters: counters.clone(),
+        };
+        tokio::spawn(worker.run(receiver));
+        EmbeddingQueue { sender, counters }
+    }
+
+    pub fn enqueue(&self, job: EmbeddingJob) {
+        self.counters.depth.fetch_add(1, Ordering::Relaxed);
+        if self.sender.send(job).is_err() {
+            self.counters.depth.fetch_sub(1, Ordering::Relaxed);
+            error!("Embedding queue is closed");
+        }
+    }
+
+    /// Queues every stored message that is still pending, such as the ones
+    /// left when the server last stopped, returning how many were queued
+    pub async fn enqueue_pending(&self, message_repo: &dyn MessageRepo) -> Result<usize, ()> {
+        let mut queued = 0;
+        for username in message_repo.get_users().await? {
+            for chat in message_repo.get_all_for_user(username.clone()).await? {
+                if chat.embedding_status() != EmbeddingStatus::Pending {
+                    continue;
+                }
+                self.enqueue(EmbeddingJob {
+                    username: username.clone(),
+                    hash: chat.hash,
+                    content: chat.content,
+                });
+                queued += 1;
+            }
+        }
+        info!("Queued {} pending messages for embedding", queued);
+        Ok(queued)
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.counters.depth.load(Ordering::Relaxed),
+            embedded: self.counters.embedded.load(Ordering::Relaxed),
+            retries: self.counters.retries.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repos::messages::{ChatModel, FsMessageRepo};
+
+    // Fails the first calls the way a backend that is still starting would
+    struct FlakyEmbeddingsClient {
+        failures: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl EmbeddingsClient for FlakyEmbeddingsClient {
+        async fn get_embeddings(&self, _: String) -> Result<Vec<f32>, ()> {
+            match self
+                .failures
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            {
+                Ok(_) => Err(()),
+                Err(_) => Ok(vec![1.0, 0.0]),
+            }
+        }
+
+        fn model(&self) -> String {
+            "flaky".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pending_message_is_embedded_after_retries() {
+        let username = format!("test_queue_{}", uuid::Uuid::new_v4());
+        let message_repo = Arc::new(Mutex::new(FsMessageRepo::new()));
+        let chat = ChatModel::test("key", "The spare key is under the blue pot");
+        assert_eq!(chat.embedding_status(), EmbeddingStatus::Pending);
+        message_repo
+            .lock()
+            .await
+            .save_chat(chrono::Utc::now().date_naive(), username.clone(), chat)
+            .await
+            .unwrap();
+
+        let queue = EmbeddingQueue::start(
+            Arc::new(Mutex::new(FlakyEmbeddingsClient {
+                failures: AtomicUsize::new(2),
+            })),
+            message_repo.clone(),
+            3,
+            Duration::from_millis(1),
+        );
+        queue.enqueue(EmbeddingJob {
+            username: username.clone(),
+            hash: "key".to_string(),
+            content: "The spare key is under the blue pot".to_string(),
+        });
+        for _ in 0..200 {
+            if queue.stats().depth == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        assert_eq!(
+            queue.stats(),
+            QueueStats {
+                depth: 0,
+                embedded: 1,
+                retries: 2,
+                failed: 0,
+            }
+        );
+        let stored = message_repo
+            .lock()
+            .await
+            .get_chat(username, "key".to_string())
+            .await
+            .unwrap();
+        assert_eq!(stored.embedding_status(), EmbeddingStatus::Ready);
+        assert_eq!(stored.embedding_model.as_deref(), Some("flaky"));
+    }
+}
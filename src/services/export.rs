@@ -0,0 +1,521 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use futures::{stream::BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::repos::{
+    attributes::{AttributeModel, AttributeRepo},
+    collection_key,
+    contacts::ContactRepo,
+    messages::{ChatModel, HistoryQuery, MessageRepo},
+    summaries::{SummaryModel, SummaryRepo},
+};
+
+#[derive(Deserialize)]
+pub struct TranscriptQuery {
+    #[serde(default)]
+    pub anonymize: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TranscriptEntry {
+    pub role: String,
+    pub content: String,
+    pub hash: String,
+    pub timestamp: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conversation_id: Option<String>,
+}
+
+impl TranscriptEntry {
+    fn from_model(chat: ChatModel) -> TranscriptEntry {
+        TranscriptEntry {
+            role: chat.role,
+            content: chat.content,
+            hash: chat.hash,
+            timestamp: chat.timestamp,
+            conversation_id: chat.conversation_id,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ArchiveQuery {
+    /// First day to include, as `YYYY-MM-DD`
+    pub from_date: Option<String>,
+    /// Last day to include, as `YYYY-MM-DD`
+    pub to_date: Option<String>,
+}
+
+// Messages read from the repo for each chunk of the archive
+const ARCHIVE_PAGE_SIZE: usize = 500;
+
+impl ArchiveQuery {
+    /// `None` when one of the dates is not a valid `YYYY-MM-DD`
+    pub fn to_query(&self) -> Option<HistoryQuery> {
+        let parse = |date: &Option<String>| match date {
+            Some(date) => chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .ok()
+                .map(Some),
+            None => Some(None),
+        };
+        Some(HistoryQuery {
+            limit: ARCHIVE_PAGE_SIZE,
+            offset: 0,
+            from_date: parse(&self.from_date)?,
+            to_date: parse(&self.to_date)?,
+            tags: vec![],
+        })
+    }
+}
+
+/// One line of a user's archive, tagged with what it holds
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ArchiveRecord {
+    Message {
+        /// Named collection the message is kept in, absent for the default
+        #[serde(skip_serializing_if = "Option::is_none")]
+        collection: Option<String>,
+        #[serde(flatten)]
+        message: ChatModel,
+    },
+    Attribute(AttributeModel),
+    Summary(SummaryModel),
+}
+
+// Where the archive's message pages have got to, collection by collection
+struct ArchivePages {
+    message_repo: Arc<Mutex<dyn MessageRepo>>,
+    collections: VecDeque<(Option<String>, String)>,
+    query: HistoryQuery,
+}
+
+/// Hands out stable placeholders so the same identifier is always replaced
+/// with the same pseudonym across a whole export
+#[derive(Default)]
+pub struct Pseudonymizer {
+    // lowercased identifier to kind, longest identifiers are replaced first
+    known: Vec<(String, &'static str)>,
+    assigned: HashMap<String, String>,
+    counters: HashMap<&'static str, usize>,
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+impl Pseudonymizer {
+    /// Registers an identifier the user's memory already knows about, such
+    /// as a contact name or an attribute value
+    pub fn add_entity(&mut self, entity: &str, kind: &'static str) {
+        let entity = entity.trim().to_lowercase();
+        if entity.chars().count() < 3 || self.known.iter().any(|(known, _)| *known == entity) {
+            return;
+        }
+        self.known.push((entity, kind));
+        self.known
+            .sort_by_key(|(known, _)| std::cmp::Reverse(known.chars().count()));
+    }
+
+    fn pseudonym(&mut self, entity: &str, kind: &'static str) -> String {
+        let key = entity.to_lowercase();
+        if let Some(pseudonym) = self.assigned.get(&key) {
+            return pseudonym.clone();
+        }
+        let counter = self.counters.entry(kind).or_insert(0);
+        *counter += 1;
+        let pseudonym = format!("[{}-{}]", kind, counter);
+        self.assigned.insert(key, pseudonym.clone());
+        pseudonym
+    }
+
+    pub fn anonymize(&mut self, text: &str) -> String {
+        let mut text = text.to_string();
+        for (entity, kind) in self.known.clone() {
+            text = self.replace_entity(&text, &entity, kind);
+        }
+        self.replace_proper_nouns(&text)
+    }
+
+    // Case insensitive whole word replacement of a known identifier
+    fn replace_entity(&mut self, text: &str, entity: &str, kind: &'static str) -> String {
+        let chars = text.chars().collect::<Vec<char>>();
+        let needle = entity.chars().collect::<Vec<char>>();
+        let mut result = String::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let end = i + needle.len();
+            let matches = end <= chars.len()
+                && chars[i..end]
+                    .iter()
+                    .zip(needle.iter())
+                    .all(|(a, b)| a.to_lowercase().eq(b.to_lowercase()))
+                && (i == 0 || !is_word_char(chars[i - 1]))
+                && (end == chars.len() || !is_word_char(chars[end]));
+            if matches {
+                result.push_str(&self.pseudonym(entity, kind));
+                i = end;
+            } else {
+                result.push(chars[i]);
+                i += 1;
+            }
+        }
+        result
+    }
+
+    // Capitalized words in the middle of a sentence are most likely names of
+    // people or places the memory does not know about yet, once seen they are
+    // replaced wherever they appear
+    fn replace_proper_nouns(&mut self, text: &str) -> String {
+        let mut result = String::new();
+        let mut sentence_start = true;
+        for token in text.split_inclusive(char::is_whitespace) {
+            let word = token.trim_end();
+            let core = word.trim_matches(|c: char| !is_word_char(c));
+            let is_capitalized = core.chars().next().is_some_and(|c| c.is_uppercase())
+                && core.chars().skip(1).all(|c| c.is_lowercase());
+            let is_proper_noun = (!sentence_start && is_capitalized && core != "I")
+                || (!core.is_empty() && self.assigned.contains_key(&core.to_lowercase()));
+            if is_proper_noun {
+                let start = word.find(core).unwrap_or(0);
+                let pseudonym = self.pseudonym(core, "name");
+                result.push_str(&word[..start]);
+                result.push_str(&pseudonym);
+                result.push_str(&token[start + core.len()..]);
+            } else {
+                result.push_str(token);
+            }
+            if !word.is_empty() {
+                sentence_start = word.ends_with(['.', '!', '?']);
+            }
+        }
+        result
+    }
+}
+
+pub struct ExportService {
+    pub message_repo: Arc<Mutex<dyn MessageRepo>>,
+    pub contact_repo: Arc<Mutex<dyn ContactRepo>>,
+    pub attribute_repo: Arc<Mutex<dyn AttributeRepo>>,
+    pub summary_repo: Arc<Mutex<dyn SummaryRepo>>,
+}
+
+impl ExportService {
+    /// Every non forgotten message of the user in chronological order,
+    /// optionally with identifiers replaced by consistent pseudonyms
+    pub async fn export_transcript(
+        &self,
+        username: &str,
+        anonymize: bool,
+    ) -> Result<Vec<TranscriptEntry>, ()> {
+        let chats = self
+            .message_repo
+            .lock()
+            .await
+            .get_all_for_user(username.to_string())
+            .await?
+            .into_iter()
+            .filter(|chat| !chat.forgotten)
+            .map(TranscriptEntry::from_model)
+            .collect::<Vec<TranscriptEntry>>();
+
+        if !anonymize {
+            return Ok(chats);
+        }
+
+        let mut pseudonymizer = Pseudonymizer::default();
+        for contact in self
+            .contact_repo
+            .lock()
+            .await
+            .get_contacts(username)
+            .unwrap_or_default()
+        {
+            pseudonymizer.add_entity(&contact.name, "person");
+        }
+        for attribute in self
+            .attribute_repo
+            .lock()
+            .await
+            .get_all_attributes(username)
+            .await
+            .unwrap_or_default()
+        {
+            pseudonymizer.add_entity(&attribute.value, "detail");
+        }
+        pseudonymizer.add_entity(username, "user");
+
+        info!("Exporting anonymized transcript for {}", username);
+        Ok(chats
+            .into_iter()
+            .map(|chat| TranscriptEntry {
+                content: pseudonymizer.anonymize(&chat.content),
+                ..chat
+            })
+            .collect())
+    }
+
+    /// Everything stored for the user: attributes and summaries up front,
+    /// followed by the messages of every collection a page at a time so
+    /// large histories are never held in memory at once. Forgotten messages
+    /// are included, it is a copy of what is kept. With a date range only
+    /// the messages and daily summaries of those days are included.
+    pub async fn export_archive(
+        &self,
+        username: &str,
+        query: HistoryQuery,
+    ) -> Result<BoxStream<'static, Result<Vec<ArchiveRecord>, ()>>, ()> {
+        let mut records = self
+            .attribute_repo
+            .lock()
+            .await
+            .get_all_attributes(username)
+            .await?
+            .into_iter()
+            .map(ArchiveRecord::Attribute)
+            .collect::<Vec<ArchiveRecord>>();
+        let filtered = query.from_date.is_some() || query.to_date.is_some();
+        records.extend(
+            self.summary_repo
+                .lock()
+                .await
+                .get_summaries(username)?
+                .into_iter()
+                .filter(|summary| {
+                    match chrono::NaiveDate::parse_from_str(&summary.date, "%Y-%m-%d") {
+                        Ok(date) => query.includes(date),
+                        // week and month rollups span days outside the range
+                        Err(_) => !filtered,
+                    }
+                })
+                .map(ArchiveRecord::Summary),
+        );
+
+        let named = self
+            .message_repo
+            .lock()
+            .await
+            .get_collections(username.to_string())
+            .await
+            .map_err(|e| error!("Error listing collections: {}", e))?;
+        let mut collections = VecDeque::from([(None, username.to_string())]);
+        collections.extend(
+            named
+                .into_iter()
+                .map(|name| (Some(name.clone()), collection_key(username, &name))),
+        );
+
+        let pages = ArchivePages {
+            message_repo: self.message_repo.clone(),
+            collections,
+            query,
+        };
+        let messages = futures::stream::unfold(Some(pages), |pages| async move {
+            let mut pages = pages?;
+            let (collection, key) = pages.collections.front()?.clone();
+            let page = pages
+                .message_repo
+                .lock()
+                .await
+                .get_history(key, &pages.query)
+                .await;
+            match page {
+                Ok(page) => {
+                    match page.next_offset {
+                        Some(offset) => pages.query.offset = offset,
+                        None => {
+                            pages.collections.pop_front();
+                            pages.query.offset = 0;
+                        }
+                    }
+                    let records = page
+                        .messages
+                        .into_iter()
+                        .map(|message| ArchiveRecord::Message {
+                            collection: collection.clone(),
+                            message,
+                        })
+                        .collect();
+                    Some((Ok(records), Some(pages)))
+                }
+                Err(e) => {
+                    error!("Error reading messages for archive: {}", e);
+                    Some((Err(()), None))
+                }
+            }
+        });
+
+        info!("Exporting archive for {}", username);
+        Ok(futures::stream::iter([Ok(records)]).chain(messages).boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repos::{
+        attributes::{AttributeSource, FsAttributeRepo},
+        contacts::FsContactRepo,
+        messages::FsMessageRepo,
+        summaries::FsSummaryRepo,
+    };
+    use chrono::NaiveDate;
+
+    fn chat(content: &str, date: NaiveDate) -> ChatModel {
+        ChatModel {
+            timestamp: date.and_hms_opt(12, 0, 0).unwrap().and_utc().timestamp(),
+            low_value: true,
+            ..ChatModel::test(content, content)
+        }
+    }
+
+    fn summary(date: &str) -> SummaryModel {
+        SummaryModel {
+            date: date.to_string(),
+            summary: format!("Summary of {}", date),
+            message_count: 1,
+            created_at: 0,
+            model: None,
+            prompt_version: None,
+            embedding: None,
+            embedding_model: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_archive_holds_every_collection_and_respects_dates() {
+        let username = format!("test_archive_{}", uuid::Uuid::new_v4());
+        let january = NaiveDate::from_ymd_opt(2024, 1, 10).unwrap();
+        let february = NaiveDate::from_ymd_opt(2024, 2, 10).unwrap();
+        let message_repo = Arc::new(Mutex::new(FsMessageRepo::new()));
+        {
+            let mut repo = message_repo.lock().await;
+            repo.save_chat(
+                january,
+                username.clone(),
+                chat("Parked on level 3", january),
+            )
+            .await
+            .unwrap();
+            repo.save_chat(
+                february,
+                collection_key(&username, "work"),
+                chat("Standup moved to ten", february),
+            )
+            .await
+            .unwrap();
+        }
+        let attribute_repo = Arc::new(Mutex::new(FsAttributeRepo::new()));
+        attribute_repo
+            .lock()
+            .await
+            .save_attribute(
+                &username,
+                "city",
+                "Cape Town",
+                None,
+                AttributeSource::Stated,
+            )
+            .await
+            .unwrap();
+        let summary_repo = Arc::new(Mutex::new(FsSummaryRepo::new()));
+        for date in ["2024-01-10", "2024-02-10", "2024-02"] {
+            summary_repo
+                .lock()
+                .await
+                .save_summary(&username, summary(date))
+                .unwrap();
+        }
+        let service = ExportService {
+            message_repo,
+            contact_repo: Arc::new(Mutex::new(FsContactRepo::new())),
+            attribute_repo,
+            summary_repo,
+        };
+
+        let export = |query: ArchiveQuery| {
+            let service = &service;
+            let username = username.clone();
+            async move {
+                let records = service
+                    .export_archive(&username, query.to_query().unwrap())
+                    .await
+                    .unwrap()
+                    .collect::<Vec<_>>()
+                    .await;
+                records
+                    .into_iter()
+                    .flat_map(|page| page.unwrap())
+                    .map(|record| serde_json::to_value(record).unwrap())
+                    .collect::<Vec<serde_json::Value>>()
+            }
+        };
+
+        let everything = export(ArchiveQuery {
+            from_date: None,
+            to_date: None,
+        })
+        .await;
+        let types = everything
+            .iter()
+            .map(|record| record["type"].as_str().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            types,
+            vec![
+                "attribute",
+                "summary",
+                "summary",
+                "summary",
+                "message",
+                "message"
+            ]
+        );
+        assert_eq!(everything[4]["content"], "Parked on level 3");
+        assert!(everything[4].get("collection").is_none());
+        assert_eq!(everything[5]["collection"], "work");
+
+        let february_only = export(ArchiveQuery {
+            from_date: Some("2024-02-01".to_string()),
+            to_date: None,
+        })
+        .await;
+        let contents = february_only
+            .iter()
+            .filter_map(|record| record.get("content").or(record.get("summary")))
+            .collect::<Vec<&serde_json::Value>>();
+        assert_eq!(
+            contents,
+            vec!["Summary of 2024-02-10", "Standup moved to ten"]
+        );
+
+        assert!(ArchiveQuery {
+            from_date: Some("last week".to_string()),
+            to_date: None,
+        }
+        .to_query()
+        .is_none());
+    }
+
+    #[test]
+    fn test_pseudonyms_are_consistent() {
+        let mut pseudonymizer = Pseudonymizer::default();
+        pseudonymizer.add_entity("Mark", "person");
+        pseudonymizer.add_entity("Cape Town", "detail");
+
+        let first = pseudonymizer.anonymize("I met mark in Cape Town. Mark says hi to Anna.");
+        let second = pseudonymizer.anonymize("Anna moved to cape town");
+
+        assert_eq!(
+            first,
+            "I met [person-1] in [detail-1]. [person-1] says hi to [name-1]."
+        );
+        assert_eq!(second, "[name-1] moved to [detail-1]");
+    }
+}
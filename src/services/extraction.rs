@@ -0,0 +1,413 @@
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::NaiveDate;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::{
+    clients::chat::{ChatClient, Message},
+    hub::{EventHub, HubEvent},
+    repos::{
+        attributes::{AttributeModel, AttributeRepo, AttributeSource},
+        contacts::{ContactModel, ContactRepo},
+        messages::{ChatModel, MessageRepo},
+    },
+};
+
+const EXTRACTION_PROMPT: &str = "Below is what the user said today. Reply with a JSON object of facts about the user that will stay true for a while, keyed by short snake_case names. Use \"timezone\" for their IANA time zone, \"interests\" for a comma separated list of their interests and \"people\" for a comma separated list of the people they talked about, with how they know them in brackets, and \"birthdays\" for a comma separated list of the people whose birthday they mentioned, with the date in brackets as YYYY-MM-DD or MM-DD when the year is unknown. Other lasting facts such as \"city\" or \"occupation\" are welcome. Only include what the user clearly said, leave out anything guessed, and reply with {} when there is nothing.";
+
+/// Whether `name` can be stored as an inferred attribute. Dotted names are
+/// reserved for attributes such as personas that are not facts.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// Reads the attributes out of the model's reply, which may wrap the JSON
+/// object in prose or a code block
+pub fn parse_attributes(reply: &str) -> HashMap<String, String> {
+    let object = match (reply.find('{'), reply.rfind('}')) {
+        (Some(start), Some(end)) if start < end => &reply[start..=end],
+        _ => return HashMap::new(),
+    };
+    let values: HashMap<String, Value> = match serde_json::from_str(object) {
+        Ok(values) => values,
+        Err(e) => {
+            warn!("Extraction reply is not a JSON object: {}", e);
+            return HashMap::new();
+        }
+    };
+    values
+        .into_iter()
+        .filter(|(name, _)| is_valid_name(name))
+        .filter_map(|(name, value)| {
+            let value = match value {
+                Value::String(value) => value,
+                Value::Number(value) => value.to_string(),
+                Value::Array(values) => values
+                    .iter()
+                    .filter_map(|value| value.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", "),
+                _ => return None,
+            };
+            let value = value.trim().to_string();
+            (!value.is_empty()).then_some((name, value))
+        })
+        .collect()
+}
+
+/// Splits a list such as `Anna (sister), Mark (colleague)` into names and
+/// what is in their brackets, leaving out names without brackets
+fn parse_bracketed(value: &str) -> Vec<(String, String)> {
+    value
+        .split(',')
+        .filter_map(|entry| {
+            let (name, rest) = entry.split_once('(')?;
+            let detail = rest.strip_suffix(')').unwrap_or(rest).trim();
+            let name = name.trim();
+            (!name.is_empty() && !detail.is_empty()).then(|| (name.to_string(), detail.to_string()))
+        })
+        .collect()
+}
+
+// Birthdays are kept as YYYY-MM-DD, or MM-DD when the year is unknown
+fn is_valid_birthday(birthday: &str) -> bool {
+    chrono::NaiveDate::parse_from_str(birthday, "%Y-%m-%d").is_ok()
+        || chrono::NaiveDate::parse_from_str(&format!("2000-{}", birthday), "%Y-%m-%d").is_ok()
+}
+
+/// The contacts described by the `people` and `birthdays` attributes of an
+/// extraction reply, at most one per name
+pub fn parse_contacts(attributes: &HashMap<String, String>) -> Vec<ContactModel> {
+    let mut contacts: Vec<ContactModel> = vec![];
+    let people = attributes.get("people").map(|v| parse_bracketed(v));
+    let birthdays = attributes.get("birthdays").map(|v| parse_bracketed(v));
+    let facts = people
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, relation)| (name, Some(relation), None))
+        .chain(
+            birthdays
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|(_, birthday)| is_valid_birthday(birthday))
+                .map(|(name, birthday)| (name, None, Some(birthday))),
+        );
+    for (name, relation, birthday) in facts {
+        match contacts
+            .iter_mut()
+            .find(|contact| contact.name.eq_ignore_ascii_case(&name))
+        {
+            Some(contact) => {
+                contact.relation = contact.relation.take().or(relation);
+                contact.birthday = contact.birthday.take().or(birthday);
+            }
+            None => contacts.push(ContactModel {
+                name,
+                relation,
+                birthday,
+                notes: None,
+                source: "extraction".to_string(),
+                updated_at: 0,
+            }),
+        }
+    }
+    contacts
+}
+
+#[derive(Deserialize)]
+pub struct ExtractionQuery {
+    /// Day whose messages are read, as `YYYY-MM-DD`, today when absent
+    pub date: Option<String>,
+}
+
+pub struct ExtractionService {
+    pub message_repo: Arc<Mutex<dyn MessageRepo>>,
+    pub chat_client: Arc<Mutex<dyn ChatClient>>,
+    pub attribute_repo: Arc<Mutex<dyn AttributeRepo>>,
+    pub contact_repo: Arc<Mutex<dyn ContactRepo>>,
+    pub event_hub: Arc<EventHub>,
+}
+
+impl ExtractionService {
+    /// Infers attributes from what the user said on `date` and saves the
+    /// ones that are new or changed as inferred. Attributes the user set
+    /// themselves are never replaced. The people and birthdays mentioned
+    /// also fill the gaps in the user's contacts.
+    pub async fn extract_for_date(
+        &self,
+        user: &str,
+        date: NaiveDate,
+    ) -> Result<Vec<AttributeModel>, ()> {
+        let messages = self
+            .message_repo
+            .lock()
+            .await
+            .get_all_for_user_on_day(user.to_string(), date)
+            .await?
+            .into_iter()
+            .filter(|chat| chat.role == "user" && !chat.forgotten && !chat.low_value)
+            .collect::<Vec<ChatModel>>();
+        if messages.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let transcript = messages
+            .iter()
+            .map(|chat| chat.content.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n");
+        let context = vec![
+            Message {
+                role: "system".to_string(),
+                content: EXTRACTION_PROMPT.to_string(),
+            },
+            Message {
+                role: "user".to_string(),
+                content: transcript,
+            },
+        ];
+        let reply = self
+            .chat_client
+            .lock()
+            .await
+            .complete(context)
+            .await
+            .map_err(|e| error!("Error extracting attributes for {}: {}", user, e))?;
+
+        let attributes = parse_attributes(&reply.content);
+        self.save_contacts(user, &attributes).await;
+
+        let mut attribute_repo = self.attribute_repo.lock().await;
+        let existing = attribute_repo
+            .get_all_attributes(user)
+            .await?
+            .into_iter()
+            .map(|attribute| (attribute.attribute.clone(), attribute))
+            .collect::<HashMap<String, AttributeModel>>();
+        let mut saved = vec![];
+        for (name, value) in attributes {
+            match existing.get(&name) {
+                Some(current) if current.source.is_stated() || current.value == value => continue,
+                _ => {}
+            }
+            let attribute = attribute_repo
+                .save_attribute(user, &name, &value, None, AttributeSource::Inferred)
+                .await?;
+            self.event_hub.publish(HubEvent::AttributeChanged {
+                username: user.to_string(),
+                attribute: name,
+            });
+            saved.push(attribute);
+        }
+        info!(
+            "Inferred {} attributes for {} from {}",
+            saved.len(),
+            user,
+            date
+        );
+        Ok(saved)
+    }
+
+    // Only what a contact is missing is filled in, so contacts entered by
+    // hand keep their relation and birthday
+    async fn save_contacts(&self, user: &str, attributes: &HashMap<String, String>) {
+        let mut contact_repo = self.contact_repo.lock().await;
+        for contact in parse_contacts(attributes) {
+            let contact = match contact_repo.get_contact(user, &contact.name) {
+                Ok(existing) => {
+                    let relation = contact.relation.filter(|_| existing.relation.is_none());
+                    let birthday = contact.birthday.filter(|_| existing.birthday.is_none());
+                    if relation.is_none() && birthday.is_none() {
+                        continue;
+                    }
+                    ContactModel {
+                        name: existing.name,
+                        relation,
+                        birthday,
+                        source: existing.source,
+                        ..contact
+                    }
+                }
+                Err(_) => contact,
+            };
+            if contact_repo.save_contact(user, contact).is_err() {
+                error!("Error saving extracted contacts for {}", user);
+            }
+        }
+    }
+
+    /// Runs the extraction for every user, returning how many attributes
+    /// were saved
+    pub async fn extract_users_for_date(&self, date: NaiveDate) -> Result<usize, ()> {
+        let users = self.message_repo.lock().await.get_users().await?;
+        let mut saved = 0;
+        for user in users {
+            match self.extract_for_date(&user, date).await {
+                Ok(attributes) => saved += attributes.len(),
+                Err(_) => error!("Error extracting attributes of {} for {}", date, user),
+            }
+        }
+        Ok(saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clients::chat::{ChatCompletion, ChatError, Usage},
+        repos::{attributes::FsAttributeRepo, contacts::FsContactRepo, messages::FsMessageRepo},
+    };
+
+    struct FixedChatClient {
+        reply: String,
+    }
+
+    #[async_trait::async_trait]
+    impl ChatClient for FixedChatClient {
+        fn model(&self) -> String {
+            "fixed".to_string()
+        }
+
+        async fn complete_with(
+            &mut self,
+            _: Vec<Message>,
+            _: Option<&str>,
+        ) -> Result<ChatCompletion, ChatError> {
+            Ok(ChatCompletion {
+                content: self.reply.clone(),
+                model: self.model(),
+                usage: Usage::default(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_parse_attributes_from_wrapped_reply() {
+        let reply = "Here you go:\n```json\n{\"timezone\": \"Africa/Johannesburg\", \"interests\": [\"climbing\", \"jazz\"], \"Bad Name\": \"x\", \"persona.work\": \"x\", \"age\": 34, \"city\": \"\"}\n```";
+        let attributes = parse_attributes(reply);
+        assert_eq!(attributes.len(), 3);
+        assert_eq!(attributes["timezone"], "Africa/Johannesburg");
+        assert_eq!(attributes["interests"], "climbing, jazz");
+        assert_eq!(attributes["age"], "34");
+        assert!(parse_attributes("Nothing to report").is_empty());
+    }
+
+    #[test]
+    fn test_parse_contacts_from_people_and_birthdays() {
+        let attributes = HashMap::from([
+            (
+                "people".to_string(),
+                "Anna (climbing partner), Mark (brother), Sam".to_string(),
+            ),
+            (
+                "birthdays".to_string(),
+                "mark (1990-03-14), Lee (07-02), Kim (some time in June)".to_string(),
+            ),
+        ]);
+        let contacts = parse_contacts(&attributes);
+        assert_eq!(contacts.len(), 3);
+        assert_eq!(contacts[0].name, "Anna");
+        assert_eq!(contacts[0].relation.as_deref(), Some("climbing partner"));
+        assert_eq!(contacts[1].name, "Mark");
+        assert_eq!(contacts[1].relation.as_deref(), Some("brother"));
+        assert_eq!(contacts[1].birthday.as_deref(), Some("1990-03-14"));
+        assert_eq!(contacts[2].name, "Lee");
+        assert_eq!(contacts[2].birthday.as_deref(), Some("07-02"));
+        assert!(contacts
+            .iter()
+            .all(|contact| contact.source == "extraction"));
+    }
+
+    #[tokio::test]
+    async fn test_inferred_attributes_do_not_replace_stated_ones() {
+        let username = format!("test_extraction_{}", uuid::Uuid::new_v4());
+        let date = chrono::Utc::now().date_naive();
+        let message_repo = Arc::new(Mutex::new(FsMessageRepo::new()));
+        message_repo
+            .lock()
+            .await
+            .save_chat(
+                date,
+                username.clone(),
+                ChatModel::test("climbing", "Back in Cape Town, climbing with Anna tomorrow"),
+            )
+            .await
+            .unwrap();
+        let attribute_repo = Arc::new(Mutex::new(FsAttributeRepo::new()));
+        attribute_repo
+            .lock()
+            .await
+            .save_attribute(
+                &username,
+                "city",
+                "Stellenbosch",
+                None,
+                AttributeSource::Stated,
+            )
+            .await
+            .unwrap();
+        let contact_repo = Arc::new(Mutex::new(FsContactRepo::new()));
+        contact_repo
+            .lock()
+            .await
+            .save_contact(
+                &username,
+                ContactModel {
+                    name: "Anna".to_string(),
+                    relation: Some("sister".to_string()),
+                    birthday: None,
+                    notes: None,
+                    source: "manual".to_string(),
+                    updated_at: 0,
+                },
+            )
+            .unwrap();
+        let service = ExtractionService {
+            message_repo,
+            chat_client: Arc::new(Mutex::new(FixedChatClient {
+                reply: r#"{"city": "Cape Town", "people": "Anna (climbing partner)", "birthdays": "Anna (05-21)"}"#.to_string(),
+            })),
+            attribute_repo: attribute_repo.clone(),
+            contact_repo: contact_repo.clone(),
+            event_hub: Arc::new(EventHub::new(8)),
+        };
+
+        let mut saved = service.extract_for_date(&username, date).await.unwrap();
+        saved.sort_by(|a, b| a.attribute.cmp(&b.attribute));
+        assert_eq!(saved.len(), 2);
+        assert_eq!(saved[0].attribute, "birthdays");
+        assert_eq!(saved[1].attribute, "people");
+
+        // the birthday fills a gap, the relation entered by hand stays
+        let anna = contact_repo
+            .lock()
+            .await
+            .get_contact(&username, "anna")
+            .unwrap();
+        assert_eq!(anna.relation.as_deref(), Some("sister"));
+        assert_eq!(anna.birthday.as_deref(), Some("05-21"));
+        assert_eq!(anna.source, "manual");
+
+        let mut repo = attribute_repo.lock().await;
+        let city = repo.get_attribute(&username, "city").await.unwrap();
+        assert_eq!(city.value, "Stellenbosch");
+        let people = repo.get_attribute(&username, "people").await.unwrap();
+        assert_eq!(people.source, AttributeSource::Inferred);
+        drop(repo);
+
+        // a second run with the same reply has nothing new to save
+        assert!(service
+            .extract_for_date(&username, date)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,114 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::{
+    clients::embeddings::EmbeddingsClient,
+    repos::{
+        feedback::{FeedbackModel, FeedbackRepo, FeedbackSignal},
+        messages::cosine_similarity,
+    },
+};
+
+// How similar an earlier query must be for its feedback to count
+const QUERY_SIMILARITY: f32 = 0.8;
+// Largest change a single piece of feedback makes to a ranking
+const FEEDBACK_WEIGHT: f32 = 0.1;
+
+#[derive(Deserialize)]
+pub struct FeedbackRequest {
+    pub query: String,
+    pub hash: String,
+    pub signal: FeedbackSignal,
+}
+
+/// Nudges the ranking of a search result up or down according to earlier
+/// feedback on the same message for similar queries
+pub fn adjust_ranking(
+    ranking: f32,
+    hash: &str,
+    query_vector: &[f32],
+    feedback: &[FeedbackModel],
+) -> f32 {
+    feedback
+        .iter()
+        .filter(|feedback| feedback.hash == hash)
+        .filter(|feedback| feedback.query_embedding.len() == query_vector.len())
+        .fold(ranking, |ranking, feedback| {
+            let similarity = cosine_similarity(&feedback.query_embedding, query_vector);
+            if similarity < QUERY_SIMILARITY {
+                return ranking;
+            }
+            match feedback.signal {
+                FeedbackSignal::Useful => ranking + FEEDBACK_WEIGHT * similarity,
+                FeedbackSignal::Irrelevant => ranking - FEEDBACK_WEIGHT * similarity,
+            }
+        })
+}
+
+pub struct FeedbackService {
+    pub embedding_client: Arc<Mutex<dyn EmbeddingsClient>>,
+    pub feedback_repo: Arc<Mutex<dyn FeedbackRepo>>,
+}
+
+impl FeedbackService {
+    pub async fn save_feedback(
+        &self,
+        username: &str,
+        request: FeedbackRequest,
+    ) -> Result<FeedbackModel, ()> {
+        let query_embedding = self
+            .embedding_client
+            .lock()
+            .await
+            .get_embeddings(request.query.clone())
+            .await
+            .map_err(|_| {
+                error!("Failed to get embeddings");
+            })?;
+
+        let feedback = FeedbackModel {
+            hash: request.hash,
+            query: request.query,
+            query_embedding,
+            signal: request.signal,
+            created_at: chrono::Utc::now().timestamp(),
+        };
+        info!(
+            "Recorded {:?} feedback on {} for user {}",
+            feedback.signal, feedback.hash, username
+        );
+        self.feedback_repo
+            .lock()
+            .await
+            .save_feedback(username, feedback)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adjust_ranking() {
+        let feedback = |hash: &str, query_embedding: Vec<f32>, signal| FeedbackModel {
+            hash: hash.to_string(),
+            query: "query".to_string(),
+            query_embedding,
+            signal,
+            created_at: 0,
+        };
+        let feedback = vec![
+            feedback("useful", vec![1.0, 0.0], FeedbackSignal::Useful),
+            feedback("irrelevant", vec![1.0, 0.0], FeedbackSignal::Irrelevant),
+            feedback("other_query", vec![0.0, 1.0], FeedbackSignal::Useful),
+        ];
+        let query = vec![1.0, 0.0];
+
+        assert!(adjust_ranking(0.5, "useful", &query, &feedback) > 0.5);
+        assert!(adjust_ranking(0.5, "irrelevant", &query, &feedback) < 0.5);
+        assert_eq!(adjust_ranking(0.5, "other_query", &query, &feedback), 0.5);
+    }
+}
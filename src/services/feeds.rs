@@ -0,0 +1,348 @@
+use std::{fmt, sync::Arc};
+
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+
+use crate::{
+    clients::web::{FetchError, PageClient, MAX_PAGE_BYTES},
+    hub::{EventHub, HubEvent},
+    repos::feeds::{FeedModel, FeedRepo},
+    services::ingest::{
+        bookmark::{page_document, site, BookmarkRequest},
+        decode_charset,
+        feed::{parse_feed, Feed},
+        page_charset, IngestService,
+    },
+};
+
+// Most new items remembered from one feed per poll, the rest are left for
+// the next one
+const MAX_ITEMS_PER_POLL: usize = 20;
+
+#[derive(Deserialize)]
+pub struct FeedRequest {
+    /// An http:// or https:// URL of an RSS or Atom feed
+    pub url: String,
+    /// Short name the items are tagged with as `rss:<id>`, the site of the
+    /// feed when left out
+    pub id: Option<String>,
+    #[serde(default)]
+    pub notify: bool,
+    #[serde(default)]
+    pub full_text: bool,
+}
+
+/// Why a feed could not be registered or polled
+#[derive(Debug, PartialEq)]
+pub enum FeedError {
+    Fetch(FetchError),
+    /// The URL points to something other than RSS or Atom
+    NotAFeed,
+    /// The feed could not be stored, logged
+    Storage,
+}
+
+impl fmt::Display for FeedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FeedError::Fetch(FetchError::Failed) => write!(f, "the feed could not be fetched"),
+            FeedError::Fetch(FetchError::Status(status)) => {
+                write!(f, "the feed answered {}", status)
+            }
+            FeedError::Fetch(FetchError::TooLarge) => write!(
+                f,
+                "the feed is larger than {} MB",
+                MAX_PAGE_BYTES / 1024 / 1024
+            ),
+            FeedError::NotAFeed => write!(f, "not an RSS or Atom feed"),
+            FeedError::Storage => write!(f, "the feed could not be stored"),
+        }
+    }
+}
+
+/// Follows users' feeds, remembering every item that was not seen before
+pub struct FeedService {
+    pub feed_repo: Arc<Mutex<dyn FeedRepo>>,
+    pub page_client: Arc<dyn PageClient>,
+    pub ingest_service: IngestService,
+    pub event_hub: Arc<EventHub>,
+}
+
+impl FeedService {
+    async fn read(&self, url: &str) -> Result<Feed, FeedError> {
+        let page = self
+            .page_client
+            .fetch(url)
+            .await
+            .map_err(FeedError::Fetch)?;
+        let xml = decode_charset(&page.body, &page_charset(&page));
+        parse_feed(&xml).ok_or(FeedError::NotAFeed)
+    }
+
+    /// Reads the feed once to check it is one and stores a checked request.
+    /// Registering an id again replaces its feed, keeping the items already
+    /// seen when the URL stayed the same.
+    pub async fn register(
+        &self,
+        username: &str,
+        request: FeedRequest,
+    ) -> Result<FeedModel, FeedError> {
+        let feed = self.read(&request.url).await?;
+        let id = request
+            .id
+            .or_else(|| site(&request.url).map(str::to_lowercase))
+            .unwrap_or_else(|| "feed".to_string());
+        let existing = self
+            .feed_repo
+            .lock()
+            .await
+            .get_feeds(username)
+            .map_err(|_| FeedError::Storage)?
+            .into_iter()
+            .find(|existing| existing.id == id && existing.url == request.url);
+        let model = FeedModel {
+            id,
+            url: request.url,
+            title: feed.title,
+            notify: request.notify,
+            full_text: request.full_text,
+            created_at: chrono::Utc::now().timestamp(),
+            last_checked: None,
+            last_error: None,
+            seen: existing.map(|existing| existing.seen).unwrap_or_default(),
+        };
+        self.feed_repo
+            .lock()
+            .await
+            .save_feed(username, model)
+            .map_err(|_| FeedError::Storage)
+    }
+
+    /// Polls every feed of every user and returns how many new items were
+    /// remembered. Feeds that cannot be read are recorded as failed on the
+    /// feed, only storage errors fail the run.
+    pub async fn poll_all(&self, now: i64) -> Result<usize, ()> {
+        let users = self.feed_repo.lock().await.get_users()?;
+        let mut remembered = 0;
+        for user in users {
+            let feeds = self.feed_repo.lock().await.get_feeds(&user)?;
+            for feed in feeds {
+                remembered += self.poll(&user, &feed, now).await?;
+            }
+        }
+        Ok(remembered)
+    }
+
+    /// Remembers the items of `feed` that were not seen before and returns
+    /// how many there were
+    pub async fn poll(&self, username: &str, feed: &FeedModel, now: i64) -> Result<usize, ()> {
+        let items = match self.read(&feed.url).await {
+            Ok(read) => read.items,
+            Err(e) => {
+                warn!("Error polling feed {} of {}: {}", feed.id, username, e);
+                self.record(username, feed, vec![], now, Some(e.to_string()))
+                    .await;
+                return Ok(0);
+            }
+        };
+        let mut new_items = items;
+        new_items.retain(|item| !feed.seen.contains(&item.id));
+        new_items.truncate(MAX_ITEMS_PER_POLL);
+
+        let mut seen = vec![];
+        let mut remembered = 0;
+        let mut failure = None;
+        for item in new_items {
+            let item_id = item.id.clone();
+            let url = item.link.clone();
+            let mut document = item.into_document(&feed.id, &feed.url);
+            if feed.full_text {
+                if let Some(text) = self.article(url.as_deref()).await {
+                    document.text = text;
+                }
+            }
+            // items that only have a title are remembered by it
+            if document.text.trim().is_empty() {
+                match document.title.take() {
+                    Some(title) => document.text = title,
+                    None => {
+                        seen.push(item_id);
+                        continue;
+                    }
+                }
+            }
+            let title = document.title.clone();
+            match self.ingest_service.ingest(username, document).await {
+                Ok(report) => {
+                    seen.push(item_id);
+                    remembered += 1;
+                    for chat in report.messages.iter() {
+                        self.event_hub.publish(HubEvent::MessageSaved {
+                            username: username.to_string(),
+                            hash: chat.hash.clone(),
+                            role: chat.role.clone(),
+                        });
+                    }
+                    if feed.notify {
+                        self.event_hub.publish(HubEvent::FeedItem {
+                            username: username.to_string(),
+                            feed: feed.id.clone(),
+                            title,
+                            url,
+                            document_id: report.document_id,
+                        });
+                    }
+                }
+                // the remaining items are tried again on the next poll
+                Err(()) => {
+                    error!(
+                        "Error remembering an item of feed {} for {}",
+                        feed.id, username
+                    );
+                    failure = Some("new items could not be stored".to_string());
+                    break;
+                }
+            }
+        }
+        self.record(username, feed, seen, now, failure).await;
+        Ok(remembered)
+    }
+
+    // The article on the page of an item, `None` when it cannot be read
+    async fn article(&self, url: Option<&str>) -> Option<String> {
+        let url = url?;
+        let page = self.page_client.fetch(url).await.ok()?;
+        let bookmark = BookmarkRequest {
+            url: url.to_string(),
+            ..BookmarkRequest::default()
+        };
+        page_document(bookmark, page).map(|document| document.text)
+    }
+
+    async fn record(
+        &self,
+        username: &str,
+        feed: &FeedModel,
+        seen: Vec<String>,
+        now: i64,
+        failure: Option<String>,
+    ) {
+        let recorded = self
+            .feed_repo
+            .lock()
+            .await
+            .record_poll(username, &feed.id, seen, now, failure);
+        // a feed deleted while it was polled stays deleted
+        if recorded.is_err() {
+            warn!("Feed {} of {} is gone", feed.id, username);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::{
+        clients::web::Page,
+        repos::{feeds::FsFeedRepo, messages::FsMessageRepo},
+        services::chat::ChatService,
+    };
+
+    // Serves the same feed for every URL
+    struct FeedPageClient {
+        xml: &'static str,
+    }
+
+    #[async_trait]
+    impl PageClient for FeedPageClient {
+        async fn fetch(&self, url: &str) -> Result<Page, FetchError> {
+            Ok(Page {
+                url: url.to_string(),
+                content_type: "application/rss+xml".to_string(),
+                body: self.xml.as_bytes().to_vec(),
+            })
+        }
+    }
+
+    fn service(xml: &'static str) -> FeedService {
+        FeedService {
+            feed_repo: Arc::new(Mutex::new(FsFeedRepo::new())),
+            page_client: Arc::new(FeedPageClient { xml }),
+            ingest_service: IngestService {
+                chat_service: ChatService::for_tests(Arc::new(Mutex::new(FsMessageRepo::new()))),
+                max_content_length: 10_000,
+                chunk_chars: 1500,
+                chunk_overlap: 200,
+            },
+            event_hub: Arc::new(EventHub::new(16)),
+        }
+    }
+
+    const FEED: &str = "<rss><channel><title>Notes</title>\
+        <item><guid>2</guid><title>Second</title><description>Two</description></item>\
+        <item><guid>1</guid><title>First</title></item>\
+        </channel></rss>";
+
+    #[actix::test]
+    async fn test_new_items_are_remembered_once() {
+        let service = service(FEED);
+        let username = format!("test_feeds_{}", uuid::Uuid::new_v4());
+        let mut events = service.event_hub.subscribe(&username);
+        let request = FeedRequest {
+            url: "https://notes.example/feed.xml".to_string(),
+            id: None,
+            notify: true,
+            full_text: false,
+        };
+        let feed = service.register(&username, request).await.unwrap();
+        assert_eq!(feed.id, "notes.example");
+        assert_eq!(feed.title.as_deref(), Some("Notes"));
+
+        assert_eq!(service.poll(&username, &feed, 10).await, Ok(2));
+        let feed = service.feed_repo.lock().await.get_feeds(&username).unwrap()[0].clone();
+        assert_eq!(feed.seen, vec!["2", "1"]);
+        assert_eq!(feed.last_checked, Some(10));
+        assert_eq!(service.poll(&username, &feed, 11).await, Ok(0));
+
+        let announced = loop {
+            match events.try_recv().unwrap() {
+                HubEvent::FeedItem { feed, title, .. } => break (feed, title),
+                _ => continue,
+            }
+        };
+        assert_eq!(
+            announced,
+            ("notes.example".to_string(), Some("Second".to_string()))
+        );
+        let chats = service
+            .ingest_service
+            .chat_service
+            .message_repo
+            .lock()
+            .await
+            .get_all_for_user(username.clone())
+            .await
+            .unwrap();
+        assert!(chats
+            .iter()
+            .all(|chat| chat.tags.contains(&"rss:notes.example".to_string())));
+        // the item without text is remembered by its title
+        assert!(chats.iter().any(|chat| chat.content == "First"));
+    }
+
+    #[actix::test]
+    async fn test_pages_that_are_not_feeds_are_refused() {
+        let service = service("<html><body>Hello</body></html>");
+        let request = FeedRequest {
+            url: "https://notes.example/".to_string(),
+            id: Some("notes".to_string()),
+            notify: false,
+            full_text: false,
+        };
+        let registered = service.register("test_feeds_refused", request).await;
+        assert_eq!(registered.err(), Some(FeedError::NotAFeed));
+    }
+}
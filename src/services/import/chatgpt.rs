@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::error;
+
+use super::{ImportedMessage, Importer};
+
+#[derive(Deserialize)]
+struct Conversation {
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    conversation_id: Option<String>,
+    #[serde(default)]
+    create_time: Option<f64>,
+    #[serde(default)]
+    current_node: Option<String>,
+    mapping: HashMap<String, Node>,
+}
+
+#[derive(Deserialize)]
+struct Node {
+    #[serde(default)]
+    message: Option<Message>,
+    #[serde(default)]
+    parent: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    author: Author,
+    #[serde(default)]
+    create_time: Option<f64>,
+    content: Content,
+}
+
+#[derive(Deserialize)]
+struct Author {
+    role: String,
+}
+
+#[derive(Deserialize)]
+struct Content {
+    #[serde(default)]
+    parts: Vec<Value>,
+}
+
+impl Content {
+    // Images and other attachments are objects among the parts, only the
+    // text is kept
+    fn text(&self) -> String {
+        self.parts
+            .iter()
+            .filter_map(|part| part.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n")
+    }
+}
+
+impl Conversation {
+    // The messages of the branch the user last looked at, oldest first.
+    // Edited prompts and regenerated answers leave other branches in the
+    // mapping, which are not part of the conversation as it was kept.
+    fn messages(&self) -> Vec<&Message> {
+        let mut messages = vec![];
+        let mut node = self
+            .current_node
+            .as_ref()
+            .and_then(|id| self.mapping.get(id));
+        // a malformed export could loop, no branch is longer than the mapping
+        let mut steps = 0;
+        while let Some(current) = node.filter(|_| steps < self.mapping.len()) {
+            if let Some(message) = &current.message {
+                messages.push(message);
+            }
+            node = current.parent.as_ref().and_then(|id| self.mapping.get(id));
+            steps += 1;
+        }
+        messages.reverse();
+        messages
+    }
+}
+
+/// The conversations.json of a ChatGPT data export
+pub struct ChatGptImporter {}
+
+impl Importer for ChatGptImporter {
+    fn name(&self) -> &'static str {
+        "chatgpt"
+    }
+
+    fn detect(&self, data: &str) -> bool {
+        data.trim_start().starts_with('[') && data.contains("\"mapping\"")
+    }
+
+    fn parse(&self, data: &str) -> Result<Vec<ImportedMessage>, ()> {
+        let conversations: Vec<Conversation> = serde_json::from_str(data).map_err(|e| {
+            error!("Error parsing ChatGPT export: {}", e);
+        })?;
+        let mut imported = vec![];
+        for conversation in conversations.iter() {
+            let conversation_id = conversation
+                .conversation_id
+                .as_ref()
+                .or(conversation.id.as_ref())
+                .map(|id| format!("chatgpt-{}", id));
+            for message in conversation.messages() {
+                // system prompts and tool calls are not what was said
+                if message.author.role != "user" && message.author.role != "assistant" {
+                    continue;
+                }
+                let timestamp = match message.create_time.or(conversation.create_time) {
+                    Some(timestamp) => timestamp as i64,
+                    None => continue,
+                };
+                imported.push(ImportedMessage {
+                    role: message.author.role.clone(),
+                    content: message.content.text(),
+                    timestamp,
+                    conversation_id: conversation_id.clone(),
+                });
+            }
+        }
+        Ok(imported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_follows_the_current_branch() {
+        let export = r#"[{
+            "id": "c1",
+            "create_time": 1700000000.5,
+            "current_node": "answer",
+            "mapping": {
+                "root": {"message": null, "parent": null},
+                "system": {"message": {"author": {"role": "system"}, "create_time": null,
+                    "content": {"content_type": "text", "parts": [""]}}, "parent": "root"},
+                "first": {"message": {"author": {"role": "user"}, "create_time": 1700000010.0,
+                    "content": {"content_type": "text", "parts": ["Where is Muninn from?"]}}, "parent": "system"},
+                "edited": {"message": {"author": {"role": "user"}, "create_time": 1700000020.0,
+                    "content": {"content_type": "text", "parts": ["Who is Muninn?"]}}, "parent": "system"},
+                "answer": {"message": {"author": {"role": "assistant"}, "create_time": 1700000030.0,
+                    "content": {"content_type": "text", "parts": ["One of Odin's ravens."]}}, "parent": "edited"}
+            }
+        }]"#;
+
+        assert!(ChatGptImporter {}.detect(export));
+        let messages = ChatGptImporter {}.parse(export).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].content, "Who is Muninn?");
+        assert_eq!(messages[0].role, "user");
+        assert_eq!(messages[1].role, "assistant");
+        assert_eq!(messages[1].timestamp, 1700000030);
+        assert_eq!(messages[1].conversation_id.as_deref(), Some("chatgpt-c1"));
+    }
+}
@@ -0,0 +1,93 @@
+use tracing::error;
+
+use super::{parse_timestamp, ImportedMessage, Importer};
+
+/// Generic CSV with a header row. `content` and `timestamp` columns are
+/// required, `role`, `sender` and `conversation_id` are optional.
+pub struct CsvImporter {}
+
+impl Importer for CsvImporter {
+    fn name(&self) -> &'static str {
+        "csv"
+    }
+
+    fn detect(&self, data: &str) -> bool {
+        let header = data.lines().next().unwrap_or_default().to_lowercase();
+        let columns = header
+            .split(',')
+            .map(|column| column.trim())
+            .collect::<Vec<&str>>();
+        columns.contains(&"content") && columns.contains(&"timestamp")
+    }
+
+    fn parse(&self, data: &str) -> Result<Vec<ImportedMessage>, ()> {
+        let mut reader = ::csv::Reader::from_reader(data.as_bytes());
+        let headers = reader
+            .headers()
+            .map_err(|e| {
+                error!("Error reading csv header: {}", e);
+            })?
+            .iter()
+            .map(|header| header.trim().to_lowercase())
+            .collect::<Vec<String>>();
+        let column = |name: &str| headers.iter().position(|header| header == name);
+        let (content, timestamp) = match (column("content"), column("timestamp")) {
+            (Some(content), Some(timestamp)) => (content, timestamp),
+            _ => {
+                error!("CSV import needs content and timestamp columns");
+                return Err(());
+            }
+        };
+        let (role, sender, conversation_id) =
+            (column("role"), column("sender"), column("conversation_id"));
+
+        let mut messages = vec![];
+        for record in reader.records() {
+            let record = record.map_err(|e| {
+                error!("Error reading csv record: {}", e);
+            })?;
+            let timestamp = match record.get(timestamp).and_then(parse_timestamp) {
+                Some(timestamp) => timestamp,
+                None => {
+                    error!("Skipping csv record without a readable timestamp");
+                    continue;
+                }
+            };
+            let content = record.get(content).unwrap_or_default().to_string();
+            let content = match sender.and_then(|sender| record.get(sender)) {
+                Some(sender) if !sender.is_empty() => format!("{}: {}", sender, content),
+                _ => content,
+            };
+            messages.push(ImportedMessage {
+                role: role
+                    .and_then(|role| record.get(role))
+                    .filter(|role| !role.is_empty())
+                    .unwrap_or("user")
+                    .to_string(),
+                content,
+                timestamp,
+                conversation_id: conversation_id
+                    .and_then(|conversation_id| record.get(conversation_id))
+                    .filter(|conversation_id| !conversation_id.is_empty())
+                    .map(|conversation_id| conversation_id.to_string()),
+            });
+        }
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_csv() {
+        let data = "timestamp,sender,content\n2024-01-01T10:00:00Z,Anna,\"Dinner, then a movie?\"";
+        let messages = CsvImporter {}.parse(data).unwrap();
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].role, "user");
+        assert_eq!(messages[0].content, "Anna: Dinner, then a movie?");
+        assert_eq!(messages[0].timestamp, 1704103200);
+    }
+}
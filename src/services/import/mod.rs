@@ -0,0 +1,348 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+
+use chrono::{DateTime, NaiveDate};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::{
+    clients::embeddings::EmbeddingsClient,
+    hub::{EventHub, HubEvent},
+    repos::messages::{ChatModel, MessageRepo},
+    services::{
+        importance::is_trivial,
+        pii::{PiiScrubber, REDACTED_METADATA},
+    },
+};
+
+pub mod chatgpt;
+pub mod csv;
+pub mod telegram;
+pub mod whatsapp;
+
+// Messages embedded concurrently before progress is reported
+const BATCH_SIZE: usize = 16;
+
+/// A message read from an export, before it is embedded and stored
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImportedMessage {
+    pub role: String,
+    pub content: String,
+    pub timestamp: i64,
+    pub conversation_id: Option<String>,
+}
+
+impl ImportedMessage {
+    // Stable across runs so importing the same export twice is a no-op
+    fn hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.role.as_bytes());
+        hasher.update(self.timestamp.to_le_bytes());
+        hasher.update(self.content.as_bytes());
+        format!("import-{:x}", hasher.finalize())
+    }
+}
+
+/// One chat export format. Importers only parse, batching, deduplication
+/// and progress reporting are shared by `ImportService`.
+pub trait Importer: Send + Sync {
+    /// Name used to pick the importer with `?format=`
+    fn name(&self) -> &'static str;
+    /// Whether `data` looks like this importer's format
+    fn detect(&self, data: &str) -> bool;
+    fn parse(&self, data: &str) -> Result<Vec<ImportedMessage>, ()>;
+}
+
+/// Every known importer, in the order format detection tries them
+pub fn importers() -> Vec<Box<dyn Importer>> {
+    vec![
+        Box::new(chatgpt::ChatGptImporter {}),
+        Box::new(telegram::TelegramImporter {}),
+        Box::new(whatsapp::WhatsAppImporter {}),
+        Box::new(csv::CsvImporter {}),
+    ]
+}
+
+/// The importer called `format`, or the first one that recognises `data`
+pub fn find_importer(format: Option<&str>, data: &str) -> Option<Box<dyn Importer>> {
+    importers().into_iter().find(|importer| match format {
+        Some(format) => importer.name() == format,
+        None => importer.detect(data),
+    })
+}
+
+/// Reads a timestamp given as unix seconds, RFC 3339 or `YYYY-MM-DD HH:MM:SS`
+pub fn parse_timestamp(value: &str) -> Option<i64> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<i64>() {
+        return Some(seconds);
+    }
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Some(date.timestamp());
+    }
+    chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .map(|date| date.and_utc().timestamp())
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    pub format: Option<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ImportReport {
+    pub format: String,
+    pub imported: usize,
+    pub duplicates: usize,
+    pub failed: usize,
+}
+
+pub struct ImportService {
+    pub embedding_client: Arc<Mutex<dyn EmbeddingsClient>>,
+    pub message_repo: Arc<Mutex<dyn MessageRepo>>,
+    pub event_hub: Arc<EventHub>,
+    /// Masks personal details of imported messages as saved ones are
+    pub pii_scrubber: Option<PiiScrubber>,
+}
+
+impl ImportService {
+    // The message as it is stored, masked when the user has scrubbing on.
+    // The hash is taken from the export so importing it again is still
+    // recognised.
+    async fn prepare(&self, username: &str, format: &str, message: &ImportedMessage) -> ChatModel {
+        // lets history and searches be filtered to `source=telegram`
+        let mut metadata = HashMap::from([("source".to_string(), format.to_string())]);
+        let content = match &self.pii_scrubber {
+            Some(scrubber) => {
+                let scrubbed = scrubber.scrub(username, &message.content).await;
+                if !scrubbed.kinds.is_empty() {
+                    metadata.insert(REDACTED_METADATA.to_string(), scrubbed.kinds.join(","));
+                }
+                scrubbed.content
+            }
+            None => message.content.clone(),
+        };
+        ChatModel {
+            role: message.role.clone(),
+            content,
+            hash: message.hash(),
+            embedding: None,
+            timestamp: message.timestamp,
+            conversation_id: message.conversation_id.clone(),
+            forgotten: false,
+            low_value: false,
+            embedding_model: None,
+            occurred_at: None,
+            metadata,
+            tags: vec![],
+        }
+    }
+
+    // Embeds the batch concurrently, trivial messages are kept unembedded
+    async fn embed(&self, batch: &[ChatModel]) -> Vec<Result<ChatModel, ()>> {
+        let embedding_client = self.embedding_client.lock().await;
+        let model = embedding_client.model();
+        let embeddings = futures::future::join_all(batch.iter().map(|chat| async {
+            match is_trivial(&chat.content) {
+                true => Ok(None),
+                false => embedding_client
+                    .get_embeddings(chat.content.clone())
+                    .await
+                    .map(|embedding| Some(embedding_client.similarity().prepare(embedding))),
+            }
+        }))
+        .await;
+        batch
+            .iter()
+            .zip(embeddings)
+            .map(|(chat, embedding)| match embedding {
+                Ok(embedding) => Ok(ChatModel {
+                    low_value: embedding.is_none(),
+                    embedding_model: embedding.as_ref().map(|_| model.clone()),
+                    embedding,
+                    ..chat.clone()
+                }),
+                Err(_) => {
+                    error!("Failed to get embeddings for imported message");
+                    Err(())
+                }
+            })
+            .collect()
+    }
+
+    pub async fn import(
+        &self,
+        username: &str,
+        importer: &dyn Importer,
+        data: &str,
+    ) -> Result<ImportReport, ()> {
+        let messages = importer.parse(data)?;
+        let mut report = ImportReport {
+            format: importer.name().to_string(),
+            ..ImportReport::default()
+        };
+
+        let mut known = self
+            .message_repo
+            .lock()
+            .await
+            .get_all_for_user(username.to_string()).await?
+            .into_iter()
+            .map(|chat| chat.hash)
+            .collect::<HashSet<String>>();
+        let messages = messages
+            .into_iter()
+            .filter(|message| !message.content.trim().is_empty())
+            .filter(|message| {
+                let is_new = known.insert(message.hash());
+                if !is_new {
+                    report.duplicates += 1;
+                }
+                is_new
+            })
+            .collect::<Vec<ImportedMessage>>();
+        let total = messages.len();
+        // every day file is written once, however many of its messages the
+        // export holds
+        let mut days: BTreeMap<NaiveDate, Vec<ChatModel>> = BTreeMap::new();
+        for message in messages.iter() {
+            match DateTime::from_timestamp(message.timestamp, 0) {
+                Some(date) => days
+                    .entry(date.date_naive())
+                    .or_default()
+                    .push(self.prepare(username, &report.format, message).await),
+                None => report.failed += 1,
+            }
+        }
+
+        for (date, chats) in days {
+            let mut embedded = vec![];
+            for batch in chats.chunks(BATCH_SIZE) {
+                for chat in self.embed(batch).await {
+                    match chat {
+                        Ok(chat) => embedded.push(chat),
+                        Err(_) => report.failed += 1,
+                    }
+                }
+            }
+            let count = embedded.len();
+            match self
+                .message_repo
+                .lock()
+                .await
+                .save_chats(date, username.to_string(), embedded)
+                .await
+            {
+                Ok(_) => report.imported += count,
+                Err(e) => {
+                    error!("Error saving imported messages of {}: {}", date, e);
+                    report.failed += count;
+                }
+            }
+
+            self.event_hub.publish(HubEvent::ImportProgress {
+                username: username.to_string(),
+                format: report.format.clone(),
+                done: report.imported + report.failed,
+                total,
+            });
+        }
+
+        info!(
+            "Imported {} {} messages for {}, skipped {} duplicates",
+            report.imported, report.format, username, report.duplicates
+        );
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clients::{chat::MockChatClient, embeddings::MockEmbeddingsClient},
+        repos::{attributes::FsAttributeRepo, messages::FsMessageRepo},
+    };
+
+    #[test]
+    fn test_find_importer_detects_format() {
+        let whatsapp = "12/31/23, 9:41 PM - Alice: Happy new year!";
+        let csv = "timestamp,role,content\n1700000000,user,hello there";
+        let chatgpt = r#"[{"title": "Ravens", "mapping": {}}]"#;
+
+        assert_eq!(find_importer(None, whatsapp).unwrap().name(), "whatsapp");
+        assert_eq!(find_importer(None, csv).unwrap().name(), "csv");
+        assert_eq!(find_importer(None, chatgpt).unwrap().name(), "chatgpt");
+        assert_eq!(find_importer(Some("csv"), whatsapp).unwrap().name(), "csv");
+        assert!(find_importer(None, "just some text").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_importing_twice_skips_duplicates() {
+        let username = format!("test_import_{}", uuid::Uuid::new_v4());
+        let service = ImportService {
+            embedding_client: Arc::new(Mutex::new(MockEmbeddingsClient::new())),
+            message_repo: Arc::new(Mutex::new(FsMessageRepo::new())),
+            event_hub: Arc::new(EventHub::new(16)),
+            pii_scrubber: None,
+        };
+        let data = "timestamp,role,content\n1700000000,user,I moved to Lisbon\n1700000060,assistant,ok\n1700172800,user,The flat has a balcony";
+
+        let importer = csv::CsvImporter {};
+        let first = service.import(&username, &importer, data).await.unwrap();
+        assert_eq!(first.imported, 3);
+        // saved under the days they were said on
+        let days = service
+            .message_repo
+            .lock()
+            .await
+            .get_days(username.clone())
+            .await
+            .unwrap();
+        assert_eq!(days.len(), 2);
+
+        let second = service.import(&username, &importer, data).await.unwrap();
+        assert_eq!(second.imported, 0);
+        assert_eq!(second.duplicates, 3);
+    }
+
+    #[tokio::test]
+    async fn test_imported_messages_are_scrubbed() {
+        let username = format!("test_import_scrub_{}", uuid::Uuid::new_v4());
+        let message_repo = Arc::new(Mutex::new(FsMessageRepo::new()));
+        let service = ImportService {
+            embedding_client: Arc::new(Mutex::new(MockEmbeddingsClient::new())),
+            message_repo: message_repo.clone(),
+            event_hub: Arc::new(EventHub::new(16)),
+            pii_scrubber: Some(PiiScrubber {
+                attribute_repo: Arc::new(Mutex::new(FsAttributeRepo::new())),
+                chat_client: Arc::new(Mutex::new(MockChatClient::new())),
+                enabled_by_default: true,
+                use_model: false,
+            }),
+        };
+        let data = "timestamp,role,content\n1700000000,user,Mail me at anna@example.com\n1700000060,user,Call +1 415 555 0100";
+
+        let importer = csv::CsvImporter {};
+        let first = service.import(&username, &importer, data).await.unwrap();
+        assert_eq!(first.imported, 2);
+        let stored = message_repo
+            .lock()
+            .await
+            .get_all_for_user(username.clone())
+            .await
+            .unwrap();
+        assert_eq!(stored[0].content, "Mail me at [email]");
+        assert_eq!(stored[0].metadata[REDACTED_METADATA], "email");
+        assert!(!stored[1].content.contains("555"));
+
+        // the export is still recognised when imported again
+        let second = service.import(&username, &importer, data).await.unwrap();
+        assert_eq!(second.duplicates, 2);
+    }
+}
@@ -0,0 +1,172 @@
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::error;
+
+use super::{parse_timestamp, ImportedMessage, Importer};
+
+#[derive(Deserialize)]
+struct Chat {
+    #[serde(default, rename = "type")]
+    kind: String,
+    #[serde(default)]
+    id: Option<i64>,
+    #[serde(default)]
+    messages: Vec<Message>,
+}
+
+// A full account export holds every chat, a single chat export is the chat
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Export {
+    Account { chats: ChatList },
+    Chat(Chat),
+}
+
+#[derive(Deserialize)]
+struct ChatList {
+    list: Vec<Chat>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    #[serde(default, rename = "type")]
+    kind: String,
+    #[serde(default)]
+    date: Option<String>,
+    #[serde(default)]
+    date_unixtime: Option<String>,
+    #[serde(default)]
+    from: Option<String>,
+    #[serde(default)]
+    from_id: Option<String>,
+    #[serde(default)]
+    text: Value,
+}
+
+impl Message {
+    // Formatted text comes as a list of plain strings and entities such as
+    // links or bold runs, which all carry their text
+    fn text(&self) -> String {
+        match &self.text {
+            Value::String(text) => text.clone(),
+            Value::Array(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    Value::String(text) => Some(text.as_str()),
+                    part => part.get("text").and_then(|text| text.as_str()),
+                })
+                .collect(),
+            _ => String::new(),
+        }
+    }
+
+    fn timestamp(&self) -> Option<i64> {
+        self.date_unixtime
+            .as_deref()
+            .and_then(parse_timestamp)
+            .or_else(|| {
+                let date = self.date.as_deref()?;
+                chrono::NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S")
+                    .ok()
+                    .map(|date| date.and_utc().timestamp())
+            })
+    }
+}
+
+impl Chat {
+    // In a chat with a bot the bot's messages are the assistant's. In other
+    // chats everyone is a user, named in front of what they said.
+    fn to_imported(&self, message: &Message) -> Option<ImportedMessage> {
+        if message.kind != "message" {
+            return None;
+        }
+        let text = message.text();
+        let is_bot = self.kind == "bot_chat"
+            && self.id.is_some()
+            && message.from_id == self.id.map(|id| format!("user{}", id));
+        let (role, content) = match (is_bot, &message.from) {
+            (true, _) => ("assistant", text),
+            (false, Some(from)) if self.kind != "bot_chat" => {
+                ("user", format!("{}: {}", from, text))
+            }
+            (false, _) => ("user", text),
+        };
+        Some(ImportedMessage {
+            role: role.to_string(),
+            content,
+            timestamp: message.timestamp()?,
+            conversation_id: self.id.map(|id| format!("telegram-{}", id)),
+        })
+    }
+}
+
+/// The result.json of a Telegram Desktop export, of one chat or the whole
+/// account
+pub struct TelegramImporter {}
+
+impl Importer for TelegramImporter {
+    fn name(&self) -> &'static str {
+        "telegram"
+    }
+
+    fn detect(&self, data: &str) -> bool {
+        data.trim_start().starts_with('{')
+            && data.contains("\"messages\"")
+            && data.contains("\"from_id\"")
+    }
+
+    fn parse(&self, data: &str) -> Result<Vec<ImportedMessage>, ()> {
+        let export: Export = serde_json::from_str(data).map_err(|e| {
+            error!("Error parsing Telegram export: {}", e);
+        })?;
+        let chats = match export {
+            Export::Account { chats } => chats.list,
+            Export::Chat(chat) => vec![chat],
+        };
+        Ok(chats
+            .iter()
+            .flat_map(|chat| {
+                chat.messages
+                    .iter()
+                    .filter_map(|message| chat.to_imported(message))
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bot_chat_and_account_export() {
+        let bot_chat = r#"{
+            "name": "Muninn",
+            "type": "bot_chat",
+            "id": 42,
+            "messages": [
+                {"id": 1, "type": "service", "date": "2024-01-01T10:00:00", "action": "start"},
+                {"id": 2, "type": "message", "date": "2024-01-01T10:00:05", "date_unixtime": "1704103205",
+                 "from": "Mark", "from_id": "user7", "text": "Where did I park?"},
+                {"id": 3, "type": "message", "date": "2024-01-01T10:00:09",
+                 "from": "Muninn", "from_id": "user42", "text": ["Level ", {"type": "bold", "text": "3"}]}
+            ]
+        }"#;
+        assert!(TelegramImporter {}.detect(bot_chat));
+        let messages = TelegramImporter {}.parse(bot_chat).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].role, "user");
+        assert_eq!(messages[0].content, "Where did I park?");
+        assert_eq!(messages[0].timestamp, 1704103205);
+        assert_eq!(messages[1].role, "assistant");
+        assert_eq!(messages[1].content, "Level 3");
+        assert_eq!(messages[1].timestamp, 1704103209);
+        assert_eq!(messages[1].conversation_id.as_deref(), Some("telegram-42"));
+
+        let account = r#"{"chats": {"list": [{"type": "personal_chat", "id": 9, "messages": [
+            {"id": 1, "type": "message", "date_unixtime": "1704103205", "from": "Anna", "from_id": "user9", "text": "Hi"}
+        ]}]}}"#;
+        let messages = TelegramImporter {}.parse(account).unwrap();
+        assert_eq!(messages[0].content, "Anna: Hi");
+    }
+}
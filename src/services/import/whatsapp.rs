@@ -0,0 +1,99 @@
+use chrono::NaiveDateTime;
+
+use super::{ImportedMessage, Importer};
+
+// Date layouts WhatsApp uses depending on the phone's locale
+const TIMESTAMP_FORMATS: &[&str] = &[
+    "%m/%d/%y, %I:%M %p",
+    "%m/%d/%y, %I:%M:%S %p",
+    "%d/%m/%Y, %H:%M",
+    "%d/%m/%Y, %H:%M:%S",
+    "%d/%m/%y, %H:%M",
+    "%d.%m.%y, %H:%M",
+    "%Y-%m-%d, %H:%M",
+];
+
+/// Plain text chat export from WhatsApp, as produced by Android
+/// (`12/31/23, 9:41 PM - Alice: text`) or iOS (`[31/12/2023, 21:41:05] Alice: text`)
+pub struct WhatsAppImporter {}
+
+fn parse_timestamp(value: &str) -> Option<i64> {
+    // newer exports separate the time with a narrow no-break space
+    let value = value.replace(['\u{202f}', '\u{a0}'], " ");
+    TIMESTAMP_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(&value, format).ok())
+        .map(|date| date.and_utc().timestamp())
+}
+
+// Splits a line that starts a message into its timestamp and the rest
+fn parse_header(line: &str) -> Option<(i64, &str)> {
+    let (timestamp, rest) = match line.strip_prefix('[') {
+        Some(line) => line.split_once("] ")?,
+        None => line.split_once(" - ")?,
+    };
+    Some((parse_timestamp(timestamp)?, rest))
+}
+
+impl Importer for WhatsAppImporter {
+    fn name(&self) -> &'static str {
+        "whatsapp"
+    }
+
+    fn detect(&self, data: &str) -> bool {
+        data.lines()
+            .take(5)
+            .any(|line| parse_header(line).is_some())
+    }
+
+    fn parse(&self, data: &str) -> Result<Vec<ImportedMessage>, ()> {
+        let mut messages: Vec<ImportedMessage> = vec![];
+        // system notices such as "Bob joined" have no sender and end the
+        // message before them
+        let mut continues = false;
+        for line in data.lines() {
+            match parse_header(line) {
+                Some((timestamp, rest)) => match rest.split_once(": ") {
+                    Some((sender, text)) => {
+                        messages.push(ImportedMessage {
+                            role: "user".to_string(),
+                            content: format!("{}: {}", sender, text),
+                            timestamp,
+                            conversation_id: Some("whatsapp".to_string()),
+                        });
+                        continues = true;
+                    }
+                    None => continues = false,
+                },
+                // anything else continues a multi-line message
+                None => {
+                    if let Some(last) = messages.last_mut().filter(|_| continues) {
+                        last.content.push('\n');
+                        last.content.push_str(line);
+                    }
+                }
+            }
+        }
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_android_and_ios_exports() {
+        let android = "12/31/23, 9:41 PM - Alice: Happy new year!\nSee you tomorrow\n12/31/23, 9:42 PM - Bob joined";
+        let messages = WhatsAppImporter {}.parse(android).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0].content,
+            "Alice: Happy new year!\nSee you tomorrow"
+        );
+
+        let ios = "[31/12/2023, 21:41:05] Alice: Happy new year!";
+        let messages = WhatsAppImporter {}.parse(ios).unwrap();
+        assert_eq!(messages[0].timestamp, 1704058865);
+    }
+}
@@ -0,0 +1,57 @@
+// Replies that carry no information worth recalling later. Yes and no are
+// left out, they answer whatever was asked before them.
+const TRIVIAL_PHRASES: &[&str] = &[
+    "ok",
+    "okay",
+    "k",
+    "kk",
+    "sure",
+    "thanks",
+    "thank you",
+    "thx",
+    "ty",
+    "cool",
+    "nice",
+    "great",
+    "got it",
+    "lol",
+    "haha",
+    "hi",
+    "hello",
+    "hey",
+    "bye",
+    "good night",
+    "np",
+];
+
+/// Cheap check for messages that are not worth embedding, such as
+/// acknowledgements or messages made up only of emoji and punctuation
+pub fn is_trivial(content: &str) -> bool {
+    let normalized = content
+        .trim()
+        .trim_matches(|c: char| c.is_ascii_punctuation() || c.is_whitespace())
+        .to_lowercase();
+
+    if !normalized.chars().any(|c| c.is_alphanumeric()) {
+        return true;
+    }
+    TRIVIAL_PHRASES.contains(&normalized.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_trivial() {
+        assert!(is_trivial("ok"));
+        assert!(is_trivial("  Thanks! "));
+        assert!(is_trivial("🎉🎉"));
+        assert!(is_trivial("..."));
+        assert!(is_trivial("👍"));
+        assert!(!is_trivial("Yes"));
+        assert!(!is_trivial("nope"));
+        assert!(!is_trivial("ok, book the table for friday"));
+        assert!(!is_trivial("My sister's name is Anna"));
+    }
+}
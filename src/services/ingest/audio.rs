@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use super::IngestDocument;
+use crate::{clients::transcription::Transcription, services::chat::parse_tags};
+
+/// Audio formats Whisper takes, by their file extension
+pub const AUDIO_FORMATS: [&str; 7] = ["mp3", "m4a", "mp4", "wav", "ogg", "webm", "flac"];
+
+#[derive(Deserialize, Default)]
+pub struct AudioQuery {
+    /// Its extension tells the format when the content type does not
+    pub filename: Option<String>,
+    /// When the note was recorded, as RFC 3339 or seconds since the epoch,
+    /// the time of the upload when left out
+    pub recorded_at: Option<String>,
+    pub title: Option<String>,
+    /// Tags for the transcription besides `audio`, separated by commas
+    pub tags: Option<String>,
+}
+
+impl AudioQuery {
+    /// Seconds since the epoch of `recorded_at`, `Err` when it cannot be read
+    pub fn recorded_at(&self) -> Result<Option<i64>, ()> {
+        let recorded_at = match &self.recorded_at {
+            Some(recorded_at) => recorded_at.trim(),
+            None => return Ok(None),
+        };
+        if let Ok(seconds) = recorded_at.parse::<i64>() {
+            return Ok(Some(seconds));
+        }
+        chrono::DateTime::parse_from_rfc3339(recorded_at)
+            .map(|date| Some(date.timestamp()))
+            .map_err(|_| ())
+    }
+}
+
+/// Extension of the audio format named by the content type, the file name
+/// or else the first bytes of `audio`
+pub fn audio_format(query: &AudioQuery, content_type: &str, audio: &[u8]) -> Option<&'static str> {
+    let by_type = match content_type {
+        "audio/mpeg" | "audio/mp3" => Some("mp3"),
+        "audio/mp4" | "audio/m4a" | "audio/x-m4a" => Some("m4a"),
+        "video/mp4" => Some("mp4"),
+        "audio/wav" | "audio/x-wav" | "audio/wave" | "audio/vnd.wave" => Some("wav"),
+        "audio/ogg" | "audio/opus" => Some("ogg"),
+        "audio/webm" | "video/webm" => Some("webm"),
+        "audio/flac" | "audio/x-flac" => Some("flac"),
+        _ => None,
+    };
+    let by_name = || {
+        let (_, extension) = query.filename.as_deref()?.rsplit_once('.')?;
+        let extension = extension.to_lowercase();
+        AUDIO_FORMATS
+            .into_iter()
+            .find(|format| *format == extension)
+    };
+    let by_content = || match audio {
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some("wav"),
+        [b'I', b'D', b'3', ..] | [0xff, 0xfb | 0xf3 | 0xf2, ..] => Some("mp3"),
+        [b'O', b'g', b'g', b'S', ..] => Some("ogg"),
+        [b'f', b'L', b'a', b'C', ..] => Some("flac"),
+        [0x1a, 0x45, 0xdf, 0xa3, ..] => Some("webm"),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some("m4a"),
+        _ => None,
+    };
+    by_type.or_else(by_name).or_else(by_content)
+}
+
+/// Seconds of audio in a WAV file, from its format and data chunks
+pub fn wav_duration(audio: &[u8]) -> Option<f64> {
+    if audio.get(..4)? != b"RIFF" || audio.get(8..12)? != b"WAVE" {
+        return None;
+    }
+    let mut byte_rate = None;
+    let mut offset = 12;
+    while let Some(header) = audio.get(offset..offset + 8) {
+        let size = u32::from_le_bytes(header[4..8].try_into().ok()?) as usize;
+        let data = offset + 8;
+        match &header[..4] {
+            b"fmt " => {
+                let rate = audio.get(data + 8..data + 12)?;
+                byte_rate = Some(u32::from_le_bytes(rate.try_into().ok()?));
+            }
+            b"data" => {
+                let byte_rate = byte_rate.filter(|rate| *rate > 0)?;
+                // streamed files leave the size at its largest
+                let size = size.min(audio.len() - data);
+                return Some(size as f64 / byte_rate as f64);
+            }
+            _ => {}
+        }
+        // chunks are padded to an even length
+        offset = data + size + size % 2;
+    }
+    None
+}
+
+/// The transcription of an audio note, tagged `audio` and dated when it was
+/// recorded
+pub fn transcript_document(
+    audio: &[u8],
+    transcription: Transcription,
+    query: AudioQuery,
+    recorded_at: i64,
+) -> IngestDocument {
+    let duration = transcription.duration.or_else(|| wav_duration(audio));
+    let mut metadata = HashMap::from([("recorded_at".to_string(), recorded_at.to_string())]);
+    if let Some(duration) = duration {
+        metadata.insert("duration_secs".to_string(), format!("{:.1}", duration));
+    }
+    if let Some(language) = transcription.language {
+        metadata.insert("language".to_string(), language);
+    }
+    if let Some(filename) = query.filename {
+        metadata.insert("filename".to_string(), filename);
+    }
+    let mut tags = vec!["audio".to_string()];
+    for tag in parse_tags(&query.tags) {
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    IngestDocument {
+        source: "audio",
+        id: format!("{:x}", Sha256::digest(audio)),
+        title: query.title,
+        text: transcription.text,
+        occurred_at: Some(recorded_at),
+        metadata,
+        tags,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A second of 8 kHz, 16 bit mono silence with a chunk before the data
+    fn wav() -> Vec<u8> {
+        let mut wav = b"RIFF\0\0\0\0WAVEfmt ".to_vec();
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&[1, 0, 1, 0]);
+        wav.extend_from_slice(&8000u32.to_le_bytes());
+        wav.extend_from_slice(&16000u32.to_le_bytes());
+        wav.extend_from_slice(&[2, 0, 16, 0]);
+        wav.extend_from_slice(b"LIST");
+        wav.extend_from_slice(&3u32.to_le_bytes());
+        wav.extend_from_slice(&[0, 0, 0, 0]);
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&16000u32.to_le_bytes());
+        wav.extend_from_slice(&[0; 16000]);
+        wav
+    }
+
+    #[test]
+    fn test_formats_and_wav_durations_are_read() {
+        let query = AudioQuery::default();
+        assert_eq!(audio_format(&query, "audio/mpeg", b""), Some("mp3"));
+        assert_eq!(audio_format(&query, "", &wav()), Some("wav"));
+        assert_eq!(audio_format(&query, "", b"OggS\0\x02"), Some("ogg"));
+        let named = AudioQuery {
+            filename: Some("Memo.M4A".to_string()),
+            ..AudioQuery::default()
+        };
+        assert_eq!(
+            audio_format(&named, "application/octet-stream", b""),
+            Some("m4a")
+        );
+        assert_eq!(audio_format(&query, "text/plain", b"hello"), None);
+
+        assert_eq!(wav_duration(&wav()), Some(1.0));
+        assert_eq!(wav_duration(b"RIFF\0\0\0\0WAVE"), None);
+        assert_eq!(wav_duration(b"OggS"), None);
+    }
+
+    #[test]
+    fn test_transcripts_are_tagged_and_dated() {
+        let query = AudioQuery {
+            recorded_at: Some("2023-11-14T22:13:20Z".to_string()),
+            tags: Some("groceries, audio".to_string()),
+            ..AudioQuery::default()
+        };
+        let recorded_at = query.recorded_at().unwrap().unwrap();
+        assert_eq!(recorded_at, 1_700_000_000);
+        let transcription = Transcription {
+            text: "Buy milk and eggs.".to_string(),
+            duration: None,
+            language: Some("english".to_string()),
+        };
+        let document = transcript_document(&wav(), transcription, query, recorded_at);
+        assert_eq!(document.text, "Buy milk and eggs.");
+        assert_eq!(document.occurred_at, Some(1_700_000_000));
+        assert_eq!(document.tags, vec!["audio", "groceries"]);
+        assert_eq!(document.metadata["duration_secs"], "1.0");
+        assert_eq!(document.metadata["language"], "english");
+
+        let unreadable = AudioQuery {
+            recorded_at: Some("last tuesday".to_string()),
+            ..AudioQuery::default()
+        };
+        assert!(unreadable.recorded_at().is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use super::{decode_charset, html, page_charset, pdf, IngestDocument};
+use crate::clients::web::Page;
+
+/// A link to remember the article of
+#[derive(Deserialize, Debug, Default, PartialEq)]
+pub struct BookmarkRequest {
+    /// An http:// or https:// URL
+    pub url: String,
+    /// Taken from the page when left out
+    pub title: Option<String>,
+    /// Tags besides `bookmark` and the site
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// The host of `url`, without its port
+pub fn site(url: &str) -> Option<&str> {
+    let (_, address) = url.split_once("://")?;
+    let authority = address.split(['/', '?', '#']).next()?;
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let host = match host.strip_prefix('[') {
+        // IPv6 addresses keep their colons
+        Some(ipv6) => ipv6.split(']').next()?,
+        None => host.split(':').next()?,
+    };
+    match host.is_empty() {
+        true => None,
+        false => Some(host),
+    }
+}
+
+/// The main text of a fetched page, `None` when it has none or is neither
+/// HTML, plain text nor a PDF
+pub fn page_document(request: BookmarkRequest, page: Page) -> Option<IngestDocument> {
+    let media_type = page
+        .content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    let (title, published_at, text) =
+        if page.body.starts_with(b"%PDF-") || media_type == "application/pdf" {
+            (None, None, pdf::extract_text(&page.body)?)
+        } else {
+            let body = decode_charset(&page.body, &page_charset(&page));
+            match media_type.as_str() {
+                "text/plain" | "text/markdown" => (None, None, body.replace("\r\n", "\n")),
+                "text/html" | "application/xhtml+xml" | "" => {
+                    let article = html::read_article(&body);
+                    (article.title, article.published_at, article.text)
+                }
+                _ => return None,
+            }
+        };
+    if text.trim().is_empty() {
+        return None;
+    }
+
+    // a page is the same one whichever part of it was linked to
+    let url = page.url.split('#').next().unwrap_or_default().to_string();
+    let title = request.title.or(title);
+    let mut metadata = HashMap::from([("url".to_string(), url.clone())]);
+    if let Some(title) = &title {
+        metadata.insert("title".to_string(), title.clone());
+    }
+    let mut tags = vec!["bookmark".to_string()];
+    if let Some(site) = site(&url) {
+        metadata.insert("site".to_string(), site.to_string());
+        tags.push(site.to_string());
+    }
+    for tag in request.tags {
+        let tag = tag.trim().to_string();
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    Some(IngestDocument {
+        source: "url",
+        id: url,
+        title,
+        text,
+        occurred_at: published_at,
+        metadata,
+        tags,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(content_type: &str, body: &[u8]) -> Page {
+        Page {
+            url: "https://blog.example:8443/posts/notes#comments".to_string(),
+            content_type: content_type.to_string(),
+            body: body.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_pages_are_tagged_with_their_site_and_title() {
+        let request = BookmarkRequest {
+            url: "https://blog.example:8443/posts/notes#comments".to_string(),
+            title: None,
+            tags: vec!["reading".to_string(), "bookmark".to_string()],
+        };
+        let html = b"<html><head><meta charset=\"iso-8859-1\"><title>Caf\xe9 notes</title></head>\
+            <body><article><p>Written in a caf\xe9.</p></article></body></html>";
+        let document = page_document(request, page("text/html", html)).unwrap();
+        assert_eq!(document.title.as_deref(), Some("Café notes"));
+        assert_eq!(document.text, "Written in a café.");
+        assert_eq!(document.id, "https://blog.example:8443/posts/notes");
+        assert_eq!(document.metadata["url"], document.id);
+        assert_eq!(document.metadata["title"], "Café notes");
+        assert_eq!(document.tags, vec!["bookmark", "blog.example", "reading"]);
+    }
+
+    #[test]
+    fn test_pages_without_text_have_none() {
+        let request = || BookmarkRequest {
+            url: "https://blog.example/".to_string(),
+            ..BookmarkRequest::default()
+        };
+        assert_eq!(
+            page_document(request(), page("image/png", b"\x89PNG")),
+            None
+        );
+        assert_eq!(
+            page_document(request(), page("text/html", b"<nav>Home</nav>")),
+            None
+        );
+        let text = page_document(request(), page("text/plain", b"Plain\r\nnotes")).unwrap();
+        assert_eq!(text.text, "Plain\nnotes");
+
+        assert_eq!(site("https://ana@[::1]:80/x"), Some("::1"));
+        assert_eq!(site("http://example.org?q=1"), Some("example.org"));
+        assert_eq!(site("example.org"), None);
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use super::{pdf, IngestDocument};
+
+#[derive(Deserialize, Default)]
+pub struct DocumentQuery {
+    /// `text`, `markdown` or `pdf`, detected when left out
+    pub format: Option<String>,
+    /// Put in front of every chunk, markdown documents default to their
+    /// first heading and the others to their file name
+    pub title: Option<String>,
+    pub filename: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DocumentFormat {
+    Text,
+    Markdown,
+    Pdf,
+}
+
+impl DocumentFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DocumentFormat::Text => "text",
+            DocumentFormat::Markdown => "markdown",
+            DocumentFormat::Pdf => "pdf",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<DocumentFormat> {
+        match name {
+            "text" | "txt" => Some(DocumentFormat::Text),
+            "markdown" | "md" => Some(DocumentFormat::Markdown),
+            "pdf" => Some(DocumentFormat::Pdf),
+            _ => None,
+        }
+    }
+
+    /// The format `query` names, or else the one the content type, the file
+    /// extension or the document itself points to. `None` for formats that
+    /// cannot be read.
+    pub fn detect(
+        query: &DocumentQuery,
+        content_type: &str,
+        document: &[u8],
+    ) -> Option<DocumentFormat> {
+        if let Some(format) = &query.format {
+            return DocumentFormat::from_name(&format.to_lowercase());
+        }
+        if document.starts_with(b"%PDF-") {
+            return Some(DocumentFormat::Pdf);
+        }
+        match content_type {
+            "application/pdf" => return Some(DocumentFormat::Pdf),
+            "text/markdown" | "text/x-markdown" => return Some(DocumentFormat::Markdown),
+            "text/plain" => return Some(DocumentFormat::Text),
+            _ => {}
+        }
+        let extension = query
+            .filename
+            .as_deref()
+            .and_then(|filename| filename.rsplit_once('.'))
+            .map(|(_, extension)| extension.to_lowercase());
+        if let Some(format) = extension.as_deref().and_then(DocumentFormat::from_name) {
+            return Some(format);
+        }
+        match std::str::from_utf8(document) {
+            Ok(_) => Some(DocumentFormat::Text),
+            Err(_) => None,
+        }
+    }
+}
+
+/// The text of an uploaded document, `None` when none can be read from it
+pub fn read_document(
+    document: &[u8],
+    format: DocumentFormat,
+    query: DocumentQuery,
+) -> Option<IngestDocument> {
+    let text = match format {
+        DocumentFormat::Pdf => pdf::extract_text(document)?,
+        DocumentFormat::Text | DocumentFormat::Markdown => String::from_utf8_lossy(document)
+            .trim_start_matches('\u{feff}')
+            .replace("\r\n", "\n"),
+    };
+    if text.trim().is_empty() {
+        return None;
+    }
+    let heading = match format {
+        DocumentFormat::Markdown => text
+            .lines()
+            .find_map(|line| line.strip_prefix("# "))
+            .map(|heading| heading.trim().to_string()),
+        _ => None,
+    };
+    let title = query.title.or(heading).or(query.filename.clone());
+
+    let mut metadata = HashMap::from([("format".to_string(), format.name().to_string())]);
+    if let Some(filename) = query.filename {
+        metadata.insert("filename".to_string(), filename);
+    }
+    Some(IngestDocument {
+        source: "document",
+        // uploading the same file again gives the same document
+        id: format!("{:x}", Sha256::digest(document)),
+        title,
+        text,
+        occurred_at: None,
+        metadata,
+        tags: vec![],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats_are_detected() {
+        let named = |filename: &str| DocumentQuery {
+            filename: Some(filename.to_string()),
+            ..DocumentQuery::default()
+        };
+        let query = DocumentQuery::default();
+        assert_eq!(
+            DocumentFormat::detect(&query, "", b"%PDF-1.7"),
+            Some(DocumentFormat::Pdf)
+        );
+        assert_eq!(
+            DocumentFormat::detect(&query, "text/markdown", b"# Notes"),
+            Some(DocumentFormat::Markdown)
+        );
+        assert_eq!(
+            DocumentFormat::detect(&named("Notes.MD"), "application/octet-stream", b"# Notes"),
+            Some(DocumentFormat::Markdown)
+        );
+        assert_eq!(
+            DocumentFormat::detect(&query, "", b"Plain notes"),
+            Some(DocumentFormat::Text)
+        );
+        assert_eq!(
+            DocumentFormat::detect(&query, "", &[0xff, 0xfe, 0x00]),
+            None
+        );
+        let query = DocumentQuery {
+            format: Some("docx".to_string()),
+            ..DocumentQuery::default()
+        };
+        assert_eq!(DocumentFormat::detect(&query, "text/plain", b"Notes"), None);
+    }
+
+    #[test]
+    fn test_markdown_is_titled_by_its_heading() {
+        let query = DocumentQuery {
+            filename: Some("trip.md".to_string()),
+            ..DocumentQuery::default()
+        };
+        let document = read_document(
+            b"# Lisbon trip\r\n\r\nFlights on the 3rd.",
+            DocumentFormat::Markdown,
+            query,
+        )
+        .unwrap();
+        assert_eq!(document.title.as_deref(), Some("Lisbon trip"));
+        assert_eq!(document.text, "# Lisbon trip\n\nFlights on the 3rd.");
+        assert_eq!(document.metadata["filename"], "trip.md");
+        assert_eq!(document.metadata["format"], "markdown");
+
+        let empty = read_document(b" \n", DocumentFormat::Text, DocumentQuery::default());
+        assert_eq!(empty, None);
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::HashMap;
+
+use base64::Engine;
+use serde::Deserialize;
+
+use super::{decode_charset, html::html_to_text, IngestDocument};
+
+/// An email already taken apart by the client
+#[derive(Deserialize, Debug, Default, PartialEq)]
+pub struct EmailRequest {
+    pub subject: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// An RFC 2822 date as in the `Date` header, or an RFC 3339 one
+    pub date: Option<String>,
+    pub message_id: Option<String>,
+    /// The text of the email, quoted replies and signatures are left out
+    /// when it is stored
+    pub body: String,
+}
+
+impl EmailRequest {
+    pub fn into_document(self) -> IngestDocument {
+        let occurred_at = self.date.as_deref().and_then(parse_date);
+        let text = clean_body(&self.body);
+        // emails without a Message-ID are told apart by what they say
+        let id = self.message_id.clone().unwrap_or_else(|| {
+            format!(
+                "{}\n{}\n{}\n{}",
+                self.from.as_deref().unwrap_or_default(),
+                self.date.as_deref().unwrap_or_default(),
+                self.subject.as_deref().unwrap_or_default(),
+                text
+            )
+        });
+        let metadata = [
+            ("subject", &self.subject),
+            ("from", &self.from),
+            ("to", &self.to),
+            ("message_id", &self.message_id),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_string(), value.clone()?)))
+        .collect::<HashMap<String, String>>();
+        IngestDocument {
+            source: "email",
+            id,
+            title: self.subject,
+            text,
+            occurred_at,
+            metadata,
+            tags: vec![],
+        }
+    }
+}
+
+/// Seconds since the epoch of an RFC 2822 or RFC 3339 date
+pub fn parse_date(date: &str) -> Option<i64> {
+    // mailers often add the zone name, as in `+0000 (UTC)`
+    let date = date.split_once(" (").map_or(date, |(date, _)| date).trim();
+    chrono::DateTime::parse_from_rfc2822(date)
+        .or_else(|_| chrono::DateTime::parse_from_rfc3339(date))
+        .ok()
+        .map(|date| date.timestamp())
+}
+
+/// Takes apart a raw RFC 822 email, using its plain text part and falling
+/// back to its HTML one. `None` when `raw` does not start with headers.
+pub fn parse_rfc822(raw: &str) -> Option<EmailRequest> {
+    let raw = raw.replace("\r\n", "\n");
+    let (headers, body) = split_entity(raw.trim_start_matches('\n'))?;
+    if headers.is_empty() {
+        return None;
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| decode_words(value))
+            .filter(|value| !value.is_empty())
+    };
+    let body = match text_of(&headers, body) {
+        Some((text, false)) => text,
+        Some((html, true)) => html_to_text(&html),
+        None => String::new(),
+    };
+    Some(EmailRequest {
+        subject: header("subject"),
+        from: header("from"),
+        to: header("to"),
+        date: header("date"),
+        message_id: header("message-id"),
+        body,
+    })
+}
+
+/// The text of an email without the replies it quotes or its signature
+pub fn clean_body(body: &str) -> String {
+    let lines = body.lines().map(str::trim_end).collect::<Vec<&str>>();
+    let mut kept: Vec<&str> = vec![];
+    for (index, line) in lines.iter().enumerate() {
+        if *line == "--" || *line == "-- " {
+            break;
+        }
+        if line.starts_with('>') {
+            continue;
+        }
+        // the "On Monday, Ana wrote:" line above a quote
+        let quotes_next = lines[index + 1..]
+            .iter()
+            .find(|line| !line.trim().is_empty())
+            .is_some_and(|line| line.starts_with('>'));
+        if line.ends_with("wrote:") && quotes_next {
+            continue;
+        }
+        kept.push(line);
+    }
+    let mut text = String::new();
+    for paragraph in kept.join("\n").split("\n\n") {
+        let paragraph = paragraph.trim_matches('\n');
+        if paragraph.trim().is_empty() {
+            continue;
+        }
+        if !text.is_empty() {
+            text.push_str("\n\n");
+        }
+        text.push_str(paragraph);
+    }
+    text.trim().to_string()
+}
+
+// The headers of an entity, lowercased and unfolded, and its body
+fn split_entity(entity: &str) -> Option<(Vec<(String, String)>, &str)> {
+    let (head, body) = match entity.split_once("\n\n") {
+        Some((head, body)) => (head, body),
+        None => (entity, ""),
+    };
+    let mut headers: Vec<(String, String)> = vec![];
+    for line in head.lines() {
+        if line.starts_with([' ', '\t']) {
+            let (_, value) = headers.last_mut()?;
+            value.push(' ');
+            value.push_str(line.trim());
+            continue;
+        }
+        let (name, value) = line.split_once(':')?;
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return None;
+        }
+        headers.push((name.to_lowercase(), value.trim().to_string()));
+    }
+    Some((headers, body))
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> &'a str {
+    headers
+        .iter()
+        .find(|(key, _)| key == name)
+        .map_or("", |(_, value)| value.as_str())
+}
+
+// The value of `name` in a header such as `text/plain; charset="utf-8"`
+fn parameter(value: &str, name: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|parameter| {
+        let (key, value) = parameter.split_once('=')?;
+        match key.trim().eq_ignore_ascii_case(name) {
+            true => Some(value.trim().trim_matches('"').to_string()),
+            false => None,
+        }
+    })
+}
+
+// The text of an entity and whether it is HTML, preferring plain text in
+// multipart emails and leaving attachments out
+fn text_of(headers: &[(String, String)], body: &str) -> Option<(String, bool)> {
+    let content_type = match header(headers, "content-type") {
+        "" => "text/plain",
+        content_type => content_type,
+    };
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    if header(headers, "content-disposition")
+        .to_lowercase()
+        .starts_with("attachment")
+    {
+        return None;
+    }
+    if media_type.starts_with("multipart/") {
+        let boundary = format!("--{}", parameter(content_type, "boundary")?);
+        let parts = body
+            .split(boundary.as_str())
+            .skip(1)
+            .take_while(|part| !part.starts_with("--"))
+            .filter_map(|part| {
+                let part = part.strip_prefix('\n').unwrap_or(part);
+                // a part without headers starts with the blank line
+                match part.strip_prefix('\n') {
+                    Some(body) => text_of(&[], body),
+                    None => {
+                        let (headers, body) = split_entity(part)?;
+                        text_of(&headers, body)
+                    }
+                }
+            })
+            .collect::<Vec<(String, bool)>>();
+        let plain = parts.iter().find(|(_, html)| !html);
+        return plain.or(parts.first()).cloned();
+    }
+    let html = match media_type.as_str() {
+        "text/plain" => false,
+        "text/html" => true,
+        _ => return None,
+    };
+    let bytes = match header(headers, "content-transfer-encoding")
+        .to_lowercase()
+        .as_str()
+    {
+        "base64" => {
+            let encoded = body
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .collect::<String>();
+            base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .ok()?
+        }
+        "quoted-printable" => decode_quoted_printable(body, false),
+        _ => body.as_bytes().to_vec(),
+    };
+    let charset = parameter(content_type, "charset").unwrap_or_default();
+    Some((decode_charset(&bytes, &charset), html))
+}
+
+// Quoted printable bodies, or the Q encoding of headers where `_` is a space
+fn decode_quoted_printable(text: &str, header: bool) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut decoded = vec![];
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'=' if bytes.get(index + 1) == Some(&b'\n') => index += 2,
+            b'=' => {
+                let byte = text
+                    .get(index + 1..index + 3)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match byte {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        index += 3;
+                    }
+                    None => {
+                        decoded.push(b'=');
+                        index += 1;
+                    }
+                }
+            }
+            b'_' if header => {
+                decoded.push(b' ');
+                index += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+    decoded
+}
+
+// Decodes the RFC 2047 words of a header, such as `=?utf-8?Q?Caf=C3=A9?=`
+fn decode_words(value: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = value;
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        let word = rest[start + 2..].splitn(4, '?').collect::<Vec<&str>>();
+        let text = match word.as_slice() {
+            [charset, encoding, text, end] if end.starts_with('=') => {
+                let bytes = match encoding.to_ascii_uppercase().as_str() {
+                    "B" => base64::engine::general_purpose::STANDARD.decode(text).ok(),
+                    "Q" => Some(decode_quoted_printable(text, true)),
+                    _ => None,
+                };
+                bytes.map(|bytes| {
+                    let length = charset.len() + encoding.len() + text.len() + 6;
+                    (decode_charset(&bytes, charset), length)
+                })
+            }
+            _ => None,
+        };
+        let (text, length) = match text {
+            Some(text) => text,
+            None => {
+                decoded.push_str(&rest[..start + 2]);
+                rest = &rest[start + 2..];
+                after_word = false;
+                continue;
+            }
+        };
+        // the space between two encoded words is not part of the text
+        let between = &rest[..start];
+        if !(after_word && between.trim().is_empty()) {
+            decoded.push_str(between);
+        }
+        decoded.push_str(&text);
+        rest = &rest[start + length..];
+        after_word = true;
+    }
+    decoded.push_str(rest);
+    decoded.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_emails_are_parsed() {
+        let raw = "From: Ana <ana@example.com>\r\n\
+            To: me@example.com\r\n\
+            Subject: =?utf-8?Q?Caf=C3=A9?= =?utf-8?B?IHRvbW9ycm93?=\r\n\
+            Date: Tue, 14 Nov 2023 22:13:20 +0000 (UTC)\r\n\
+            Message-ID: <1@example.com>\r\n\
+            Content-Transfer-Encoding: quoted-printable\r\n\
+            \r\n\
+            Shall we meet at ten? The caf=C3=A9 on the =\r\n\
+            corner.\r\n\
+            \r\n\
+            On Monday, Bob wrote:\r\n\
+            > Coffee this week?\r\n\
+            \r\n\
+            -- \r\n\
+            Ana\r\n";
+        let email = parse_rfc822(raw).unwrap();
+        assert_eq!(email.subject.as_deref(), Some("Café tomorrow"));
+        assert_eq!(email.from.as_deref(), Some("Ana <ana@example.com>"));
+        assert_eq!(email.message_id.as_deref(), Some("<1@example.com>"));
+
+        let document = email.into_document();
+        assert_eq!(
+            document.text,
+            "Shall we meet at ten? The café on the corner."
+        );
+        assert_eq!(document.occurred_at, Some(1_700_000_000));
+        assert_eq!(document.metadata["subject"], "Café tomorrow");
+        assert_eq!(document.id, "<1@example.com>");
+    }
+
+    #[test]
+    fn test_plain_parts_are_preferred_and_attachments_skipped() {
+        let raw = "Subject: Notes\n\
+            Content-Type: multipart/mixed; boundary=\"outer\"\n\
+            \n\
+            --outer\n\
+            Content-Type: multipart/alternative; boundary=inner\n\
+            \n\
+            --inner\n\
+            Content-Type: text/html; charset=utf-8\n\
+            \n\
+            <p>Hello <b>there</b></p>\n\
+            --inner\n\
+            Content-Type: text/plain; charset=utf-8\n\
+            Content-Transfer-Encoding: base64\n\
+            \n\
+            SGVsbG8gdGhlcmU=\n\
+            --inner--\n\
+            --outer\n\
+            Content-Type: text/plain\n\
+            Content-Disposition: attachment; filename=notes.txt\n\
+            \n\
+            Not this\n\
+            --outer--\n";
+        assert_eq!(parse_rfc822(raw).unwrap().body, "Hello there");
+
+        let raw = "Subject: News\nContent-Type: text/html\n\n\
+            <html><head><style>p {}</style></head>\
+            <body><p>Fish &amp; chips</p><p>Tonight</p></body></html>";
+        let document = parse_rfc822(raw).unwrap().into_document();
+        assert_eq!(document.text, "Fish & chips\n\nTonight");
+    }
+
+    #[test]
+    fn test_text_without_headers_is_not_an_email() {
+        assert_eq!(parse_rfc822("Hello there, how are you?"), None);
+        assert_eq!(parse_rfc822(""), None);
+        assert_eq!(parse_date("2023-11-14T22:13:20Z"), Some(1_700_000_000));
+        assert_eq!(parse_date("yesterday"), None);
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+
+use super::{
+    email::parse_date,
+    html::{attribute, decode_entities, html_to_text},
+    IngestDocument,
+};
+
+/// An RSS or Atom feed
+#[derive(Debug, Default, PartialEq)]
+pub struct Feed {
+    pub title: Option<String>,
+    /// In the order of the feed, mostly newest first
+    pub items: Vec<FeedItem>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct FeedItem {
+    /// The guid or id of the item, or else its link or title
+    pub id: String,
+    pub title: Option<String>,
+    pub link: Option<String>,
+    /// In seconds since the epoch
+    pub published_at: Option<i64>,
+    /// Plain text of the content or summary
+    pub text: String,
+}
+
+impl FeedItem {
+    /// The item as memory of feed `feed`, tagged `rss` and `rss:<feed>`
+    pub fn into_document(self, feed: &str, feed_url: &str) -> IngestDocument {
+        let mut metadata = HashMap::from([("feed".to_string(), feed.to_string())]);
+        if let Some(link) = &self.link {
+            metadata.insert("url".to_string(), link.clone());
+        }
+        IngestDocument {
+            source: "rss",
+            // the same item id may turn up in another feed
+            id: format!("{}\n{}", feed_url, self.id),
+            title: self.title,
+            text: self.text,
+            occurred_at: self.published_at,
+            metadata,
+            tags: vec!["rss".to_string(), format!("rss:{}", feed)],
+        }
+    }
+}
+
+/// Reads an RSS 2.0, RSS 1.0 or Atom feed, `None` for other documents
+pub fn parse_feed(xml: &str) -> Option<Feed> {
+    let document = parse_xml(xml);
+    let root = document.children().next()?;
+    let channel = match name(&root.name) {
+        "rss" => root.child("channel")?,
+        "feed" | "rdf:rdf" => root,
+        _ => return None,
+    };
+    let mut items = vec![];
+    root.descendants("item", &mut items);
+    root.descendants("entry", &mut items);
+    Some(Feed {
+        title: channel
+            .child("title")
+            .map(Element::text)
+            .filter(|t| !t.is_empty()),
+        items: items.into_iter().filter_map(read_item).collect(),
+    })
+}
+
+fn read_item(item: &Element) -> Option<FeedItem> {
+    let text_of = |child: &str| {
+        item.child(child)
+            .map(Element::text)
+            .filter(|text| !text.is_empty())
+    };
+    let title = text_of("title").map(|title| html_to_text(&title).trim().to_string());
+    // RSS has the link as text, Atom as the href of its alternate link
+    let link = item
+        .children()
+        .filter(|child| name(&child.name) == "link")
+        .find_map(|link| match attribute(&link.tag, "href") {
+            Some(href) => match attribute(&link.tag, "rel").as_deref() {
+                None | Some("alternate") => Some(href),
+                _ => None,
+            },
+            None => Some(link.text()).filter(|text| !text.is_empty()),
+        });
+    let published_at = ["pubdate", "published", "dc:date", "updated"]
+        .iter()
+        .find_map(|child| text_of(child).and_then(|date| parse_date(&date)));
+    let content = ["content:encoded", "content", "description", "summary"]
+        .iter()
+        .find_map(|child| text_of(child))
+        .unwrap_or_default();
+    let text = html_to_text(&content).trim().to_string();
+    let id = text_of("guid")
+        .or_else(|| text_of("id"))
+        .or_else(|| link.clone())
+        .or_else(|| title.clone())?;
+    Some(FeedItem {
+        id,
+        title,
+        link,
+        published_at,
+        text,
+    })
+}
+
+// Element names are compared in lowercase, Atom feeds sometimes spell out
+// their namespace
+fn name(element: &str) -> &str {
+    element.strip_prefix("atom:").unwrap_or(element)
+}
+
+enum Node {
+    Element(Element),
+    Text(String),
+}
+
+#[derive(Default)]
+struct Element {
+    name: String,
+    // everything between `<` and `>`, for the attributes
+    tag: String,
+    nodes: Vec<Node>,
+}
+
+impl Element {
+    fn children(&self) -> impl Iterator<Item = &Element> {
+        self.nodes.iter().filter_map(|node| match node {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+
+    fn child(&self, wanted: &str) -> Option<&Element> {
+        self.children().find(|child| name(&child.name) == wanted)
+    }
+
+    fn descendants<'a>(&'a self, wanted: &str, found: &mut Vec<&'a Element>) {
+        for child in self.children() {
+            match name(&child.name) == wanted {
+                true => found.push(child),
+                false => child.descendants(wanted, found),
+            }
+        }
+    }
+
+    /// The text of the element and everything in it, trimmed
+    fn text(&self) -> String {
+        fn collect(element: &Element, text: &mut String) {
+            for node in &element.nodes {
+                match node {
+                    Node::Text(part) => text.push_str(part),
+                    Node::Element(child) => collect(child, text),
+                }
+            }
+        }
+        let mut text = String::new();
+        collect(self, &mut text);
+        text.trim().to_string()
+    }
+}
+
+// A tolerant reading of XML into elements, closing whatever is left open
+fn parse_xml(xml: &str) -> Element {
+    let mut stack = vec![Element::default()];
+    let mut rest = xml;
+    while !rest.is_empty() {
+        let start = rest.find('<').unwrap_or(rest.len());
+        if start > 0 {
+            let text = decode_entities(&rest[..start]);
+            if let Some(parent) = stack.last_mut() {
+                parent.nodes.push(Node::Text(text));
+            }
+            rest = &rest[start..];
+            continue;
+        }
+        if let Some(cdata) = rest.strip_prefix("<![CDATA[") {
+            let end = cdata.find("]]>").unwrap_or(cdata.len());
+            if let Some(parent) = stack.last_mut() {
+                parent.nodes.push(Node::Text(cdata[..end].to_string()));
+            }
+            rest = cdata.get(end + 3..).unwrap_or_default();
+            continue;
+        }
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+        if tag.starts_with(['?', '!']) {
+            continue;
+        }
+        let element_name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        if tag.starts_with('/') {
+            if let Some(index) = stack.iter().rposition(|open| open.name == element_name) {
+                // the document itself stays at the bottom of the stack
+                while stack.len() > index.max(1) {
+                    close(&mut stack);
+                }
+            }
+            continue;
+        }
+        let element = Element {
+            name: element_name,
+            tag: tag.trim_end_matches('/').to_string(),
+            nodes: vec![],
+        };
+        match tag.ends_with('/') {
+            true => {
+                if let Some(parent) = stack.last_mut() {
+                    parent.nodes.push(Node::Element(element));
+                }
+            }
+            false => stack.push(element),
+        }
+    }
+    while stack.len() > 1 {
+        close(&mut stack);
+    }
+    stack.pop().unwrap_or_default()
+}
+
+fn close(stack: &mut Vec<Element>) {
+    if let Some(element) = stack.pop() {
+        if let Some(parent) = stack.last_mut() {
+            parent.nodes.push(Node::Element(element));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rss_items_are_read() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <rss version="2.0" xmlns:content="http://purl.org/rss/1.0/modules/content/">
+            <channel>
+              <title>Example &amp; Co</title>
+              <link>https://blog.example/</link>
+              <item>
+                <title>Second post</title>
+                <link>https://blog.example/2</link>
+                <guid isPermaLink="false">post-2</guid>
+                <pubDate>Tue, 14 Nov 2023 22:13:20 +0000</pubDate>
+                <description>&lt;p&gt;A summary&lt;/p&gt;</description>
+                <content:encoded><![CDATA[<p>The <b>whole</b> post.</p><p>Two paragraphs & more.</p>]]></content:encoded>
+              </item>
+              <item>
+                <link>https://blog.example/1</link>
+                <description>Only a link and a line</description>
+              </item>
+            </channel>
+            </rss>"#;
+        let feed = parse_feed(xml).unwrap();
+        assert_eq!(feed.title.as_deref(), Some("Example & Co"));
+        assert_eq!(
+            feed.items[0],
+            FeedItem {
+                id: "post-2".to_string(),
+                title: Some("Second post".to_string()),
+                link: Some("https://blog.example/2".to_string()),
+                published_at: Some(1_700_000_000),
+                text: "The whole post.\n\nTwo paragraphs & more.".to_string(),
+            }
+        );
+        assert_eq!(feed.items[1].id, "https://blog.example/1");
+        assert_eq!(feed.items[1].text, "Only a link and a line");
+
+        let document = feed
+            .items
+            .into_iter()
+            .next()
+            .unwrap()
+            .into_document("blog", "u");
+        assert_eq!(document.tags, vec!["rss", "rss:blog"]);
+        assert_eq!(document.metadata["url"], "https://blog.example/2");
+        assert_eq!(document.occurred_at, Some(1_700_000_000));
+    }
+
+    #[test]
+    fn test_atom_entries_are_read() {
+        let xml = r#"<feed xmlns="http://www.w3.org/2005/Atom">
+            <title type="text">Notes</title>
+            <entry>
+              <id>tag:notes.example,2023:1</id>
+              <title type="html">Lunch &amp;amp; &lt;b&gt;notes&lt;/b&gt;</title>
+              <link rel="replies" href="https://notes.example/1#comments"/>
+              <link href="https://notes.example/1"/>
+              <updated>2023-11-14T22:13:20Z</updated>
+              <content type="xhtml"><div xmlns="http://www.w3.org/1999/xhtml">Met at <em>noon</em>.</div></content>
+            </entry>
+            </feed>"#;
+        let feed = parse_feed(xml).unwrap();
+        assert_eq!(feed.title.as_deref(), Some("Notes"));
+        let entry = &feed.items[0];
+        assert_eq!(entry.id, "tag:notes.example,2023:1");
+        assert_eq!(entry.title.as_deref(), Some("Lunch & notes"));
+        assert_eq!(entry.link.as_deref(), Some("https://notes.example/1"));
+        assert_eq!(entry.published_at, Some(1_700_000_000));
+        assert_eq!(entry.text, "Met at noon.");
+        assert_eq!(parse_feed("<html><body>Not a feed</body></html>"), None);
+    }
+}
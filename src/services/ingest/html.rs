@@ -0,0 +1,607 @@
+// Elements whose content runs as raw text up to their closing tag
+const RAW_TEXT: [&str; 5] = ["script", "style", "textarea", "template", "title"];
+
+// Elements without a closing tag
+const VOID: [&str; 14] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+// Elements that start a new block of text
+const BLOCKS: [&str; 25] = [
+    "p",
+    "div",
+    "section",
+    "article",
+    "main",
+    "li",
+    "ul",
+    "ol",
+    "dl",
+    "dt",
+    "dd",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "blockquote",
+    "pre",
+    "table",
+    "tr",
+    "td",
+    "th",
+    "figcaption",
+    "hr",
+];
+
+// Elements of the page around an article rather than in it
+const FURNITURE: [&str; 13] = [
+    "head", "nav", "header", "footer", "aside", "form", "noscript", "svg", "iframe", "button",
+    "select", "figure", "dialog",
+];
+
+// Words in the class or id of page furniture
+const FURNITURE_HINTS: [&str; 17] = [
+    "comment",
+    "comments",
+    "sidebar",
+    "share",
+    "sharing",
+    "social",
+    "related",
+    "promo",
+    "cookie",
+    "cookies",
+    "newsletter",
+    "subscribe",
+    "breadcrumb",
+    "breadcrumbs",
+    "advert",
+    "banner",
+    "menu",
+];
+
+// Pages with less text than this once furniture is left out are read again
+// without looking at class names, which some sites put on the whole body
+const MIN_ARTICLE_CHARS: usize = 200;
+
+/// The main text of a web page and what it is called
+#[derive(Debug, Default, PartialEq)]
+pub struct Article {
+    pub title: Option<String>,
+    /// When the page says it was published, in seconds since the epoch
+    pub published_at: Option<i64>,
+    pub text: String,
+}
+
+/// Reads the article a page holds, leaving out navigation, sidebars,
+/// comments and lists of links around it
+pub fn read_article(html: &str) -> Article {
+    let (title, published_at) = head(html);
+    let mut text = main_text(html, true);
+    if text.chars().count() < MIN_ARTICLE_CHARS {
+        let unhinted = main_text(html, false);
+        if unhinted.len() > text.len() {
+            text = unhinted;
+        }
+    }
+    Article {
+        title,
+        published_at,
+        text,
+    }
+}
+
+/// The readable text of an HTML body, with a blank line between blocks
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut skipped: Option<String> = None;
+    for token in Tokens::new(html) {
+        match token {
+            Token::Close(name) if skipped.as_ref() == Some(&name) => skipped = None,
+            _ if skipped.is_some() => {}
+            Token::Open { name, .. } if ["style", "script", "head"].contains(&name.as_str()) => {
+                skipped = Some(name)
+            }
+            Token::Open { name, .. } | Token::Close(name) => match name.as_str() {
+                "br" => text.push('\n'),
+                "p" | "div" | "tr" | "li" | "h1" | "h2" | "h3" | "blockquote" => {
+                    text.push_str("\n\n")
+                }
+                _ => {}
+            },
+            Token::Text(part) => text.push_str(&decode_entities(part)),
+        }
+    }
+    let mut lines: Vec<String> = vec![];
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<&str>>().join(" ");
+        // blocks that end and start give one blank line between them
+        if line.is_empty() && lines.last().is_none_or(|last| last.is_empty()) {
+            continue;
+        }
+        lines.push(line);
+    }
+    lines.join("\n")
+}
+
+/// Replaces character references such as `&amp;` and `&#8217;`
+pub fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        let entity = rest
+            .find(';')
+            .filter(|end| *end <= 10)
+            .map(|end| &rest[..end]);
+        match entity.and_then(|entity| Some((entity, entity_char(entity)?))) {
+            Some((entity, decoded_char)) => {
+                decoded.push(decoded_char);
+                rest = &rest[entity.len() + 1..];
+            }
+            None => decoded.push('&'),
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+fn entity_char(entity: &str) -> Option<char> {
+    if let Some(number) = entity.strip_prefix('#') {
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+    let decoded = match entity {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        "ndash" => '–',
+        "mdash" => '—',
+        "hellip" => '…',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "laquo" => '«',
+        "raquo" => '»',
+        "copy" => '©',
+        "reg" => '®',
+        "trade" => '™',
+        "euro" => '€',
+        "pound" => '£',
+        "deg" => '°',
+        "middot" => '·',
+        "bull" => '•',
+        _ => return None,
+    };
+    Some(decoded)
+}
+
+/// The value of attribute `name` in the inside of a tag, such as
+/// `meta name="author" content="Ana"`
+pub fn attribute(tag: &str, name: &str) -> Option<String> {
+    let (_, mut rest) = tag.split_once(|c: char| c.is_whitespace())?;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+        if rest.is_empty() {
+            return None;
+        }
+        let key_end = rest
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '/')
+            .unwrap_or(rest.len());
+        let key = &rest[..key_end];
+        rest = rest[key_end..].trim_start();
+        let value = match rest.strip_prefix('=') {
+            Some(value) => {
+                let value = value.trim_start();
+                match value.chars().next() {
+                    Some(quote @ ('"' | '\'')) => {
+                        let end = value[1..].find(quote).map_or(value.len(), |end| end + 1);
+                        rest = value.get(end + 1..).unwrap_or_default();
+                        &value[1..end]
+                    }
+                    _ => {
+                        let end = value.find(char::is_whitespace).unwrap_or(value.len());
+                        rest = &value[end..];
+                        &value[..end]
+                    }
+                }
+            }
+            None => "",
+        };
+        if key.eq_ignore_ascii_case(name) {
+            return Some(decode_entities(value));
+        }
+    }
+}
+
+enum Token<'a> {
+    Text(&'a str),
+    /// An opening tag, with its lowercase name and everything between `<`
+    /// and `>`
+    Open {
+        name: String,
+        tag: &'a str,
+    },
+    Close(String),
+}
+
+// The tags and text of a page, skipping comments and doctypes
+struct Tokens<'a> {
+    html: &'a str,
+    // the raw text element whose content comes next
+    raw: Option<String>,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(html: &'a str) -> Self {
+        Tokens { html, raw: None }
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        loop {
+            if self.html.is_empty() {
+                return None;
+            }
+            if let Some(name) = self.raw.take() {
+                // ASCII lowercase keeps the byte offsets of the original
+                let close = self
+                    .html
+                    .to_ascii_lowercase()
+                    .find(&format!("</{}", name))
+                    .unwrap_or(self.html.len());
+                let (text, rest) = self.html.split_at(close);
+                self.html = rest;
+                return Some(Token::Text(text));
+            }
+            match self.html.find('<') {
+                Some(0) => {}
+                Some(start) => {
+                    let (text, rest) = self.html.split_at(start);
+                    self.html = rest;
+                    return Some(Token::Text(text));
+                }
+                None => {
+                    let text = self.html;
+                    self.html = "";
+                    return Some(Token::Text(text));
+                }
+            }
+            if let Some(comment) = self.html.strip_prefix("<!--") {
+                self.html = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+                continue;
+            }
+            // a `<` that starts no tag, as in `a < b`
+            if !self.html[1..].starts_with(|c: char| c.is_ascii_alphabetic() || "/!?".contains(c)) {
+                self.html = &self.html[1..];
+                return Some(Token::Text("<"));
+            }
+            let end = match self.html.find('>') {
+                Some(end) => end,
+                None => {
+                    self.html = "";
+                    return None;
+                }
+            };
+            let tag = &self.html[1..end];
+            self.html = &self.html[end + 1..];
+            if tag.starts_with(['!', '?']) {
+                continue;
+            }
+            let name = tag
+                .trim_start_matches('/')
+                .split(|c: char| c.is_whitespace() || c == '/')
+                .next()
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            if tag.starts_with('/') {
+                return Some(Token::Close(name));
+            }
+            if RAW_TEXT.contains(&name.as_str()) && !tag.ends_with('/') {
+                self.raw = Some(name.clone());
+            }
+            return Some(Token::Open { name, tag });
+        }
+    }
+}
+
+fn collapse(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+// The title of a page and when it was published, from its metadata or else
+// its `<title>` or first heading
+fn head(html: &str) -> (Option<String>, Option<i64>) {
+    let mut og_title = None;
+    let mut title = None;
+    let mut heading: Option<String> = None;
+    let mut published_at = None;
+    let mut inside = None;
+    for token in Tokens::new(html) {
+        match token {
+            Token::Open { name, tag } if name == "meta" => {
+                let property = attribute(tag, "property")
+                    .or_else(|| attribute(tag, "name"))
+                    .unwrap_or_default()
+                    .to_lowercase();
+                let content = attribute(tag, "content").map(|content| collapse(&content));
+                match property.as_str() {
+                    "og:title" if og_title.is_none() => og_title = content,
+                    "article:published_time" if published_at.is_none() => {
+                        published_at = content
+                            .and_then(|content| chrono::DateTime::parse_from_rfc3339(&content).ok())
+                            .map(|date| date.timestamp())
+                    }
+                    _ => {}
+                }
+            }
+            Token::Open { name, .. } if name == "title" && title.is_none() => {
+                inside = Some((name, String::new()))
+            }
+            Token::Open { name, .. } if name == "h1" && heading.is_none() => {
+                inside = Some((name, String::new()))
+            }
+            Token::Text(text) => {
+                if let Some((_, collected)) = &mut inside {
+                    collected.push_str(text);
+                }
+            }
+            Token::Close(name) if inside.as_ref().is_some_and(|(open, _)| *open == name) => {
+                if let Some((name, collected)) = inside.take() {
+                    let collected = collapse(&decode_entities(&collected));
+                    match name.as_str() {
+                        "title" => title = Some(collected),
+                        _ => heading = Some(collected),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    let title = og_title
+        .filter(|title| !title.is_empty())
+        .or(title.filter(|title| !title.is_empty()))
+        .or(heading.filter(|heading| !heading.is_empty()));
+    (title, published_at)
+}
+
+// What is open around the text that comes next
+struct Open {
+    name: String,
+    furniture: bool,
+    main: bool,
+    link: bool,
+    pre: bool,
+}
+
+#[derive(Default)]
+struct Block {
+    text: String,
+    // characters besides whitespace, and those of them in links
+    chars: usize,
+    link_chars: usize,
+    heading: bool,
+    // inside an `<article>` or `<main>`
+    main: bool,
+}
+
+impl Block {
+    fn words(&self) -> usize {
+        self.text.split_whitespace().count()
+    }
+}
+
+fn hinted(tag: &str) -> bool {
+    ["class", "id"].iter().any(|name| {
+        attribute(tag, name).is_some_and(|value| {
+            value
+                .to_lowercase()
+                .split(|c: char| !c.is_alphanumeric())
+                .any(|word| FURNITURE_HINTS.contains(&word))
+        })
+    })
+}
+
+// The blocks of text outside of page furniture, only those in the article
+// when it holds most of them, and without lists of links
+fn main_text(html: &str, hints: bool) -> String {
+    let mut open: Vec<Open> = vec![];
+    let mut blocks: Vec<Block> = vec![];
+    let mut block = Block::default();
+    let flush = |block: &mut Block, blocks: &mut Vec<Block>| {
+        let done = std::mem::take(block);
+        if done.chars > 0 {
+            blocks.push(Block {
+                text: done
+                    .text
+                    .lines()
+                    .map(str::trim_end)
+                    .collect::<Vec<&str>>()
+                    .join("\n")
+                    .trim()
+                    .to_string(),
+                ..done
+            });
+        }
+    };
+    for token in Tokens::new(html) {
+        match token {
+            Token::Open { name, tag } => {
+                if BLOCKS.contains(&name.as_str()) {
+                    flush(&mut block, &mut blocks);
+                    block.heading =
+                        matches!(name.as_str(), "h1" | "h2" | "h3" | "h4" | "h5" | "h6");
+                }
+                if name == "br" && !block.text.is_empty() {
+                    block.text.push('\n');
+                }
+                if VOID.contains(&name.as_str()) || tag.ends_with('/') {
+                    continue;
+                }
+                let parent = open.last();
+                let structural = ["html", "body", "main", "article"].contains(&name.as_str());
+                let furniture = FURNITURE.contains(&name.as_str())
+                    || RAW_TEXT.contains(&name.as_str())
+                    || (hints && !structural && hinted(tag));
+                let main = name == "main"
+                    || name == "article"
+                    || attribute(tag, "role").is_some_and(|role| role == "main");
+                open.push(Open {
+                    furniture: furniture || parent.is_some_and(|parent| parent.furniture),
+                    main: main || parent.is_some_and(|parent| parent.main),
+                    // headings often link to themselves, which is not a link away
+                    link: (name == "a"
+                        && !attribute(tag, "href")
+                            .is_some_and(|href| href.len() > 1 && href.starts_with('#')))
+                        || parent.is_some_and(|parent| parent.link),
+                    pre: name == "pre" || parent.is_some_and(|parent| parent.pre),
+                    name,
+                });
+            }
+            Token::Close(name) => {
+                // closing an element closes whatever was left open inside it
+                if let Some(index) = open.iter().rposition(|element| element.name == name) {
+                    open.truncate(index);
+                }
+                if BLOCKS.contains(&name.as_str()) {
+                    flush(&mut block, &mut blocks);
+                }
+            }
+            Token::Text(text) => {
+                let (link, pre) = match open.last() {
+                    Some(element) if element.furniture => continue,
+                    Some(element) => {
+                        block.main |= element.main;
+                        (element.link, element.pre)
+                    }
+                    None => (false, false),
+                };
+                for c in decode_entities(text).chars() {
+                    if c.is_whitespace() && !pre {
+                        if !block.text.is_empty() && !block.text.ends_with([' ', '\n']) {
+                            block.text.push(' ');
+                        }
+                        continue;
+                    }
+                    block.text.push(c);
+                    if !c.is_whitespace() {
+                        block.chars += 1;
+                        if link {
+                            block.link_chars += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    flush(&mut block, &mut blocks);
+
+    let total = blocks.iter().map(|block| block.chars).sum::<usize>();
+    let in_main = blocks
+        .iter()
+        .filter(|block| block.main)
+        .map(|block| block.chars)
+        .sum::<usize>();
+    let main_only = in_main > 0 && in_main * 4 >= total;
+    let kept = blocks
+        .into_iter()
+        .filter(|block| !main_only || block.main)
+        .filter(|block| block.link_chars * 2 <= block.chars)
+        // outside of an article short lines are mostly bylines and buttons
+        .filter(|block| {
+            main_only
+                || block.heading
+                || block.words() >= 4
+                || block.text.ends_with(['.', '!', '?', ':'])
+        })
+        .collect::<Vec<Block>>();
+    let mut paragraphs = vec![];
+    for (index, block) in kept.iter().enumerate() {
+        // headings with nothing kept under them
+        let next_is_heading = kept.get(index + 1).is_none_or(|next| next.heading);
+        if block.heading && next_is_heading {
+            continue;
+        }
+        paragraphs.push(block.text.as_str());
+    }
+    paragraphs.join("\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_articles_are_read_without_the_page_around_them() {
+        let html = r##"<!DOCTYPE html>
+            <html><head>
+            <title>Kept &amp; shared | Example Blog</title>
+            <meta property="og:title" content="Kept &amp; shared">
+            <meta property="article:published_time" content="2023-11-14T22:13:20Z">
+            <script>if (a < b) { document.write("<p>no</p>") }</script>
+            </head>
+            <body class="page has-sidebar">
+            <nav><a href="/">Home</a> <a href="/about">About</a></nav>
+            <article>
+              <h1><a href="#kept">Kept &amp; shared</a></h1>
+              <p>Notes are worth keeping when they can be found again, so
+              <a href="/search">search</a> matters more than folders.</p>
+              <!-- an ad -->
+              <div class="share-buttons"><a href="#">Share on Mastodon</a></div>
+              <pre>cargo run
+  --release</pre>
+              <h2>Related</h2>
+              <ul><li><a href="/a">Another post</a></li></ul>
+            </article>
+            <aside><p>About the author and what they write.</p></aside>
+            <footer>&copy; 2023</footer>
+            </body></html>"##;
+        let article = read_article(html);
+        assert_eq!(article.title.as_deref(), Some("Kept & shared"));
+        assert_eq!(article.published_at, Some(1_700_000_000));
+        assert_eq!(
+            article.text,
+            "Kept & shared\n\n\
+            Notes are worth keeping when they can be found again, so search matters more than \
+            folders.\n\n\
+            cargo run\n  --release"
+        );
+    }
+
+    #[test]
+    fn test_pages_without_an_article_drop_their_menus() {
+        let html = "<title>Plain page</title>\
+            <div id=\"menu\"><a href=\"/\">Home</a></div>\
+            <div><p>Posted by Ana</p><p>This page has no article element at all.</p>\
+            <p><a href=\"/1\">First post</a> <a href=\"/2\">Second post</a> and more</p></div>";
+        let article = read_article(html);
+        assert_eq!(article.title.as_deref(), Some("Plain page"));
+        assert_eq!(article.text, "This page has no article element at all.");
+        assert_eq!(
+            decode_entities("caf&eacute; &#8217;&#x2019; &amp;lt;"),
+            "caf&eacute; ’’ &lt;"
+        );
+        assert_eq!(
+            attribute(
+                "a href='/x' data-x=1 hidden title=\"A &quot;B&quot;\"",
+                "title"
+            )
+            .as_deref(),
+            Some("A \"B\"")
+        );
+    }
+}
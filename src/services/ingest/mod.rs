@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::{
+    clients::{chat::Role, web::Page},
+    services::chat::{ChatRequest, ChatResponse, ChatService},
+};
+
+pub mod audio;
+pub mod bookmark;
+pub mod document;
+pub mod email;
+pub mod feed;
+pub mod html;
+pub mod pdf;
+
+// Most characters of the title put in front of every chunk
+const MAX_TITLE_CHARS: usize = 200;
+
+/// Text from outside of a chat, such as an email, to be stored as searchable
+/// memory
+#[derive(Clone, Debug, PartialEq)]
+pub struct IngestDocument {
+    /// Kind of text, stored as the `source` metadata of every chunk
+    pub source: &'static str,
+    /// Identifies the text, so ingesting it again gives the same hashes
+    pub id: String,
+    pub title: Option<String>,
+    pub text: String,
+    /// When the text was written, in seconds since the epoch
+    pub occurred_at: Option<i64>,
+    /// Stored on every chunk next to `source`
+    pub metadata: HashMap<String, String>,
+    /// Tags of every chunk
+    pub tags: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct IngestReport {
+    pub source: &'static str,
+    /// Stored as the `document_id` metadata of every chunk, searches can be
+    /// limited to it
+    pub document_id: String,
+    pub title: Option<String>,
+    /// Chunks the text was stored as, in order
+    pub messages: Vec<ChatResponse>,
+}
+
+/// Text of `bytes` in `charset`, where Latin-1 is read as Windows-1252 is
+/// close enough and anything else as UTF-8
+pub fn decode_charset(bytes: &[u8], charset: &str) -> String {
+    match charset.to_lowercase().as_str() {
+        "iso-8859-1" | "latin1" | "windows-1252" => {
+            bytes.iter().map(|byte| *byte as char).collect()
+        }
+        _ => String::from_utf8_lossy(bytes).to_string(),
+    }
+}
+
+/// Charset of a fetched page, as the server says or else as the page says
+/// in its first bytes, with `charset=` in HTML and `encoding=` in XML
+pub fn page_charset(page: &Page) -> String {
+    let declared = |text: &[u8], key: &str| {
+        let text = String::from_utf8_lossy(text).to_lowercase();
+        let (_, rest) = text.split_once(key)?;
+        let charset = rest
+            .trim_start_matches(['"', '\''])
+            .split(|c: char| !c.is_alphanumeric() && c != '-' && c != '_')
+            .next()?
+            .to_string();
+        Some(charset)
+    };
+    let head = &page.body[..page.body.len().min(1024)];
+    declared(page.content_type.as_bytes(), "charset=")
+        .or_else(|| declared(head, "charset="))
+        .or_else(|| declared(head, "encoding="))
+        .unwrap_or_default()
+}
+
+/// Splits `text` into chunks of at most `max_chars` characters, each one
+/// after the first starting with up to `overlap` characters of the words
+/// that end the one before it
+pub fn chunk(text: &str, max_chars: usize, overlap: usize) -> Vec<String> {
+    let overlap = overlap.min(max_chars / 2);
+    let pieces = split(text, max_chars - overlap);
+    let mut chunks = Vec::with_capacity(pieces.len());
+    for (index, piece) in pieces.iter().enumerate() {
+        let tail = match index {
+            0 => String::new(),
+            _ => tail(&pieces[index - 1], overlap),
+        };
+        match tail.is_empty() {
+            true => chunks.push(piece.clone()),
+            false => chunks.push(format!("{} {}", tail, piece)),
+        }
+    }
+    chunks
+}
+
+// The last words of `text` that fit in `max_chars` with a space after them
+fn tail(text: &str, max_chars: usize) -> String {
+    let mut length = 0;
+    let mut words = text
+        .split_whitespace()
+        .rev()
+        .take_while(|word| {
+            length += word.chars().count() + 1;
+            length <= max_chars
+        })
+        .collect::<Vec<&str>>();
+    words.reverse();
+    words.join(" ")
+}
+
+// Splits `text` into pieces of at most `max_chars` characters. Paragraphs
+// stay together when they fit, longer ones are broken between words.
+fn split(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut current = String::new();
+    let paragraphs = text
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|paragraph| !paragraph.is_empty());
+    for paragraph in paragraphs {
+        let length = current.chars().count() + 2 + paragraph.chars().count();
+        if !current.is_empty() && length > max_chars {
+            chunks.push(std::mem::take(&mut current));
+        }
+        let mut separator = "\n\n";
+        for word in paragraph.split_whitespace() {
+            let mut word = word;
+            // a single word longer than a chunk
+            while word.chars().count() > max_chars {
+                if !current.is_empty() {
+                    chunks.push(std::mem::take(&mut current));
+                }
+                let cut = word.char_indices().nth(max_chars).unwrap().0;
+                chunks.push(word[..cut].to_string());
+                word = &word[cut..];
+            }
+            if current.is_empty() {
+                separator = "";
+            }
+            let length = current.chars().count() + separator.len() + word.chars().count();
+            if length > max_chars {
+                chunks.push(std::mem::take(&mut current));
+                separator = "";
+            }
+            current.push_str(separator);
+            current.push_str(word);
+            separator = " ";
+        }
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Stores outside texts as messages, a chunk at a time, so search and
+/// context recall them like anything said in a chat
+pub struct IngestService {
+    pub chat_service: ChatService,
+    /// Longest message content the server accepts
+    pub max_content_length: usize,
+    pub chunk_chars: usize,
+    pub chunk_overlap: usize,
+}
+
+impl IngestService {
+    /// Embeds and stores every chunk of `document`, nothing is stored when
+    /// one of them fails
+    pub async fn ingest(
+        &self,
+        username: &str,
+        document: IngestDocument,
+    ) -> Result<IngestReport, ()> {
+        let title = document
+            .title
+            .as_deref()
+            .map(str::trim)
+            .filter(|title| !title.is_empty())
+            .map(|title| title.chars().take(MAX_TITLE_CHARS).collect::<String>());
+        // the title is repeated on every chunk so each one says what it is from
+        let heading = title.as_ref().map(|title| format!("{}\n\n", title));
+        let room = self.max_content_length.saturating_sub(
+            heading
+                .as_ref()
+                .map_or(0, |heading| heading.chars().count()),
+        );
+        let chunks = chunk(
+            &document.text,
+            self.chunk_chars.min(room).max(1),
+            self.chunk_overlap,
+        );
+
+        let id = format!("{:x}", Sha256::digest(document.id.as_bytes()));
+        let document_id = format!("{}-{}", document.source, &id[..32]);
+        let count = chunks.len();
+        let requests = chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut metadata = document.metadata.clone();
+                metadata.insert("source".to_string(), document.source.to_string());
+                metadata.insert("document_id".to_string(), document_id.clone());
+                metadata.insert("chunk".to_string(), format!("{}/{}", index + 1, count));
+                ChatRequest {
+                    role: Role::User.to_string(),
+                    content: format!("{}{}", heading.as_deref().unwrap_or_default(), chunk),
+                    hash: format!("{}-{}", document_id, index),
+                    conversation_id: Some(document_id.clone()),
+                    occurred_at: document.occurred_at,
+                    metadata,
+                    tags: document.tags.clone(),
+                }
+            })
+            .collect::<Vec<ChatRequest>>();
+        let messages = self.chat_service.save_chats(username, requests).await?;
+        info!(
+            "Ingested {} as {} chunks for {}",
+            document.source,
+            messages.len(),
+            username
+        );
+        Ok(IngestReport {
+            source: document.source,
+            document_id,
+            title,
+            messages,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::Mutex;
+
+    use super::*;
+    use crate::repos::messages::FsMessageRepo;
+
+    #[test]
+    fn test_chunks_keep_paragraphs_and_fit() {
+        assert_eq!(
+            chunk("One two.\n\nThree four.", 100, 0),
+            vec!["One two.\n\nThree four.".to_string()]
+        );
+        assert_eq!(
+            chunk("One two.\n\n\n\nThree four.", 12, 0),
+            vec!["One two.".to_string(), "Three four.".to_string()]
+        );
+        assert_eq!(
+            chunk("alpha beta gamma delta", 11, 0),
+            vec!["alpha beta".to_string(), "gamma delta".to_string()]
+        );
+        assert_eq!(
+            chunk("abcdefghij", 4, 0),
+            vec!["abcd".to_string(), "efgh".to_string(), "ij".to_string()]
+        );
+        assert!(chunk(" \n\n ", 10, 0).is_empty());
+    }
+
+    #[test]
+    fn test_chunks_overlap() {
+        assert_eq!(
+            chunk("one two three four five six", 16, 6),
+            vec![
+                "one two".to_string(),
+                "two three four".to_string(),
+                "four five six".to_string(),
+            ]
+        );
+        // never more than half of a chunk
+        assert_eq!(
+            chunk("aaaa bbbb cccc", 10, 50),
+            vec![
+                "aaaa".to_string(),
+                "aaaa bbbb".to_string(),
+                "bbbb cccc".to_string()
+            ]
+        );
+    }
+
+    #[actix::test]
+    async fn test_documents_are_stored_in_chunks() {
+        let service = IngestService {
+            chat_service: ChatService::for_tests(Arc::new(Mutex::new(FsMessageRepo::new()))),
+            max_content_length: 40,
+            chunk_chars: 1500,
+            chunk_overlap: 0,
+        };
+        let username = format!("test_ingest_{}", uuid::Uuid::new_v4());
+        let document = IngestDocument {
+            source: "email",
+            id: "<1@example.com>".to_string(),
+            title: Some("Trip".to_string()),
+            text: "The train leaves at nine.\n\nSeats are in coach four.".to_string(),
+            occurred_at: Some(1_700_000_000),
+            metadata: HashMap::from([("from".to_string(), "ana@example.com".to_string())]),
+            tags: vec![],
+        };
+        let report = service.ingest(&username, document.clone()).await.unwrap();
+        let contents = report
+            .messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            contents,
+            vec![
+                "Trip\n\nThe train leaves at nine.",
+                "Trip\n\nSeats are in coach four."
+            ]
+        );
+        let first = &report.messages[0];
+        assert_eq!(first.metadata["source"], "email");
+        assert_eq!(first.metadata["from"], "ana@example.com");
+        assert_eq!(first.metadata["chunk"], "1/2");
+        assert_eq!(first.metadata["document_id"], report.document_id);
+        assert_eq!(first.occurred_at, Some(1_700_000_000));
+
+        // the same document gets the same hashes
+        let again = service.ingest(&username, document).await.unwrap();
+        assert_eq!(again.messages[1].hash, report.messages[1].hash);
+    }
+}
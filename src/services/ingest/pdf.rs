@@ -0,0 +1,358 @@
+use std::io::Read;
+
+use flate2::read::ZlibDecoder;
+
+// Streams that hold fonts, images or other objects rather than page content
+const NOT_CONTENT: [&[u8]; 5] = [b"/Image", b"/FontFile", b"/ObjStm", b"/XRef", b"/Metadata"];
+
+/// The text a PDF shows, read from the text operators of its page content.
+/// Scanned pages have no text and fonts with an encoding of their own come
+/// out unreadable, so `None` when nothing readable is found.
+pub fn extract_text(pdf: &[u8]) -> Option<String> {
+    if !pdf.starts_with(b"%PDF-") {
+        return None;
+    }
+    let mut text = String::new();
+    for (dictionary, data) in streams(pdf) {
+        if NOT_CONTENT.iter().any(|kind| contains(dictionary, kind)) {
+            continue;
+        }
+        let content = match (
+            contains(dictionary, b"/Filter"),
+            contains(dictionary, b"/FlateDecode"),
+        ) {
+            (false, _) => data.to_vec(),
+            (true, true) => {
+                let mut content = vec![];
+                match ZlibDecoder::new(data).read_to_end(&mut content) {
+                    Ok(_) => content,
+                    Err(_) => continue,
+                }
+            }
+            // images and other encodings hold no text
+            (true, false) => continue,
+        };
+        let page = show_text(&content);
+        let page = page.trim();
+        if page.is_empty() {
+            continue;
+        }
+        if !text.is_empty() {
+            text.push_str("\n\n");
+        }
+        text.push_str(page);
+    }
+    let shown = text.chars().filter(|c| !c.is_whitespace()).count();
+    let readable = text.chars().filter(|c| c.is_alphanumeric()).count();
+    match shown > 0 && readable * 2 >= shown {
+        true => Some(text),
+        false => None,
+    }
+}
+
+fn contains(bytes: &[u8], needle: &[u8]) -> bool {
+    find(bytes, needle, 0).is_some()
+}
+
+fn find(bytes: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    bytes
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|position| from + position)
+}
+
+// The dictionary and data of every stream object, in the order of the file
+fn streams(pdf: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut streams = vec![];
+    let mut from = 0;
+    while let Some(start) = find(pdf, b"stream", from) {
+        from = start + 6;
+        // `endstream` ends a stream, a stream follows the `>>` of its dictionary
+        let before = pdf[..start].trim_ascii_end();
+        if !before.ends_with(b">>") {
+            continue;
+        }
+        let data_start = match &pdf[from..] {
+            [b'\r', b'\n', ..] => from + 2,
+            [b'\n', ..] | [b'\r', ..] => from + 1,
+            _ => continue,
+        };
+        let end = match find(pdf, b"endstream", data_start) {
+            Some(end) => end,
+            None => break,
+        };
+        let object = pdf[..start]
+            .windows(3)
+            .rposition(|window| window == b"obj")
+            .unwrap_or(0);
+        streams.push((&pdf[object..start], &pdf[data_start..end]));
+        from = end + 9;
+    }
+    streams
+}
+
+enum Operand {
+    Number(f32),
+    Text(String),
+    ArrayStart,
+    Array(Vec<Operand>),
+    Other,
+}
+
+fn is_delimiter(byte: u8) -> bool {
+    byte.is_ascii_whitespace() || b"()<>[]{}/%".contains(&byte)
+}
+
+// Runs the text operators of a content stream, breaking lines where the
+// text moves down
+fn show_text(content: &[u8]) -> String {
+    let mut text = String::new();
+    let mut operands: Vec<Operand> = vec![];
+    let mut index = 0;
+    while index < content.len() {
+        match content[index] {
+            b'(' => {
+                let (string, end) = literal_string(content, index + 1);
+                operands.push(Operand::Text(string));
+                index = end;
+            }
+            b'<' if content.get(index + 1) == Some(&b'<') => {
+                operands.push(Operand::Other);
+                index += 2;
+            }
+            b'<' => {
+                let end = find(content, b">", index).unwrap_or(content.len());
+                operands.push(Operand::Text(hex_string(&content[index + 1..end])));
+                index = end + 1;
+            }
+            b'[' => {
+                operands.push(Operand::ArrayStart);
+                index += 1;
+            }
+            b']' => {
+                let start = operands
+                    .iter()
+                    .rposition(|operand| matches!(operand, Operand::ArrayStart))
+                    .unwrap_or(0);
+                let items = operands.split_off(start).into_iter().skip(1).collect();
+                operands.push(Operand::Array(items));
+                index += 1;
+            }
+            b'%' => {
+                index = find(content, b"\n", index).unwrap_or(content.len());
+            }
+            byte if byte.is_ascii_whitespace() || b">{}".contains(&byte) => index += 1,
+            _ => {
+                let start = index;
+                index += 1;
+                while index < content.len() && !is_delimiter(content[index]) {
+                    index += 1;
+                }
+                let token = String::from_utf8_lossy(&content[start..index]);
+                if let Ok(number) = token.parse::<f32>() {
+                    operands.push(Operand::Number(number));
+                    continue;
+                }
+                if token.starts_with('/') {
+                    operands.push(Operand::Other);
+                    continue;
+                }
+                run(&token, &operands, &mut text);
+                // inline images are binary up to their end
+                if token == "BI" {
+                    index = find(content, b"EI", index).map_or(content.len(), |end| end + 2);
+                }
+                operands.clear();
+            }
+        }
+    }
+    text
+}
+
+fn run(operator: &str, operands: &[Operand], text: &mut String) {
+    let new_line = |text: &mut String| {
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+    };
+    match operator {
+        "Tj" => {
+            if let Some(Operand::Text(string)) = operands.last() {
+                text.push_str(string);
+            }
+        }
+        "'" | "\"" => {
+            new_line(text);
+            if let Some(Operand::Text(string)) = operands.last() {
+                text.push_str(string);
+            }
+        }
+        "TJ" => {
+            if let Some(Operand::Array(items)) = operands.last() {
+                for item in items {
+                    match item {
+                        Operand::Text(string) => text.push_str(string),
+                        // a wide gap, in thousandths of the font size
+                        Operand::Number(gap) if *gap < -200.0 && !text.ends_with(' ') => {
+                            text.push(' ')
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        "Td" | "TD" => match operands {
+            [.., Operand::Number(_), Operand::Number(y)] if *y == 0.0 => {
+                if !text.is_empty() && !text.ends_with(char::is_whitespace) {
+                    text.push(' ');
+                }
+            }
+            _ => new_line(text),
+        },
+        "T*" | "Tm" | "ET" => new_line(text),
+        _ => {}
+    }
+}
+
+// A `(literal string)`, starting after its opening parenthesis, and where it
+// ends
+fn literal_string(content: &[u8], start: usize) -> (String, usize) {
+    let mut bytes = vec![];
+    let mut depth = 0;
+    let mut index = start;
+    while index < content.len() {
+        let byte = content[index];
+        index += 1;
+        match byte {
+            b'(' => {
+                depth += 1;
+                bytes.push(byte);
+            }
+            b')' if depth == 0 => break,
+            b')' => {
+                depth -= 1;
+                bytes.push(byte);
+            }
+            b'\\' => {
+                let escaped = match content.get(index) {
+                    Some(escaped) => *escaped,
+                    None => break,
+                };
+                index += 1;
+                match escaped {
+                    b'n' => bytes.push(b'\n'),
+                    b'r' => bytes.push(b'\r'),
+                    b't' => bytes.push(b'\t'),
+                    b'b' | b'f' => {}
+                    b'\r' | b'\n' => {
+                        if escaped == b'\r' && content.get(index) == Some(&b'\n') {
+                            index += 1;
+                        }
+                    }
+                    b'0'..=b'7' => {
+                        let mut code = (escaped - b'0') as u32;
+                        for _ in 0..2 {
+                            match content.get(index) {
+                                Some(digit @ b'0'..=b'7') => {
+                                    code = code * 8 + (digit - b'0') as u32;
+                                    index += 1;
+                                }
+                                _ => break,
+                            }
+                        }
+                        bytes.push(code as u8);
+                    }
+                    other => bytes.push(other),
+                }
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    (decode(&bytes), index)
+}
+
+fn hex_string(hex: &[u8]) -> String {
+    let digits = hex
+        .iter()
+        .filter(|byte| byte.is_ascii_hexdigit())
+        .map(|byte| (*byte as char).to_digit(16).unwrap_or_default() as u8)
+        .collect::<Vec<u8>>();
+    let bytes = digits
+        .chunks(2)
+        .map(|pair| (pair[0] << 4) | pair.get(1).copied().unwrap_or(0))
+        .collect::<Vec<u8>>();
+    decode(&bytes)
+}
+
+// Strings are UTF-16 when they start with its byte order mark, and close
+// enough to Latin-1 otherwise
+fn decode(bytes: &[u8]) -> String {
+    match bytes {
+        [0xfe, 0xff, rest @ ..] => {
+            let units = rest
+                .chunks(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]))
+                .collect::<Vec<u16>>();
+            String::from_utf16_lossy(&units)
+        }
+        _ => bytes.iter().map(|byte| *byte as char).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::ZlibEncoder, Compression};
+
+    use super::*;
+
+    fn pdf(streams: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut pdf = b"%PDF-1.4\n".to_vec();
+        for (index, (dictionary, data)) in streams.iter().enumerate() {
+            let header = format!(
+                "{} 0 obj\n<< /Length {} {} >>\nstream\n",
+                index + 1,
+                data.len(),
+                dictionary
+            );
+            pdf.extend_from_slice(header.as_bytes());
+            pdf.extend_from_slice(data);
+            pdf.extend_from_slice(b"\nendstream\nendobj\n");
+        }
+        pdf.extend_from_slice(b"%%EOF\n");
+        pdf
+    }
+
+    #[test]
+    fn test_text_is_read_from_page_content() {
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder
+            .write_all(b"BT [(Sec) -20 (ond) -300 (page)] TJ 0 -14 Td <4c696e65> Tj ET")
+            .unwrap();
+        let compressed = encoder.finish().unwrap();
+        let pdf = pdf(&[
+            (
+                "",
+                b"BT /F1 12 Tf 72 712 Td (Hello \\(PDF\\) w\\157rld) Tj ET".to_vec(),
+            ),
+            ("/Subtype /Image /Filter /DCTDecode", vec![0xff, 0xd8, 0xff]),
+            ("/Filter /FlateDecode", compressed),
+        ]);
+        assert_eq!(
+            extract_text(&pdf).as_deref(),
+            Some("Hello (PDF) world\n\nSecond page\nLine")
+        );
+    }
+
+    #[test]
+    fn test_pdfs_without_readable_text_have_none() {
+        assert_eq!(extract_text(b"Hello"), None);
+        let scanned = pdf(&[("/Subtype /Image /Filter /DCTDecode", vec![0xff, 0xd8])]);
+        assert_eq!(extract_text(&scanned), None);
+        // glyph ids of a font with its own encoding
+        let encoded = pdf(&[("", b"BT <0012001f00340002> Tj ET".to_vec())]);
+        assert_eq!(extract_text(&encoded), None);
+    }
+}
@@ -0,0 +1,90 @@
+use std::{future::Future, time::Duration};
+
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::config::ContextConfig;
+
+/// Time a context request may take overall and per stage, so interactive
+/// clients get an answer within a predictable bound even when a stage runs
+/// long
+#[derive(Clone, Debug)]
+pub struct LatencyBudget {
+    deadline: Instant,
+    pub retrieval: Duration,
+    pub completion: Duration,
+}
+
+impl Default for LatencyBudget {
+    /// The budget of the default settings, starting now
+    fn default() -> Self {
+        LatencyBudget::from_config(&ContextConfig::default())
+    }
+}
+
+impl LatencyBudget {
+    pub fn new(total: Duration, retrieval: Duration, completion: Duration) -> Self {
+        LatencyBudget {
+            deadline: Instant::now() + total,
+            retrieval,
+            completion,
+        }
+    }
+
+    /// Budget of the `[context]` settings, starting now
+    pub fn from_config(config: &ContextConfig) -> Self {
+        LatencyBudget::new(
+            Duration::from_millis(config.deadline_ms),
+            Duration::from_millis(config.retrieval_timeout_ms),
+            Duration::from_millis(config.completion_timeout_ms),
+        )
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+
+    /// Runs a stage for at most its own limit or whatever is left of the
+    /// overall deadline, returning `None` when it ran out of time
+    pub async fn run<F: Future>(
+        &self,
+        stage: &str,
+        limit: Duration,
+        future: F,
+    ) -> Option<F::Output> {
+        let limit = limit.min(self.remaining());
+        match tokio::time::timeout(limit, future).await {
+            Ok(output) => Some(output),
+            Err(_) => {
+                warn!("Context stage {} ran out of its {:?} budget", stage, limit);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stage_is_bounded_by_deadline() {
+        let budget = LatencyBudget::new(
+            Duration::from_millis(20),
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+        );
+
+        let slow = budget
+            .run(
+                "slow",
+                budget.retrieval,
+                tokio::time::sleep(Duration::from_secs(5)),
+            )
+            .await;
+        assert!(slow.is_none());
+
+        let fast = budget.run("fast", budget.completion, async { 1 }).await;
+        assert_eq!(fast, Some(1));
+    }
+}
@@ -0,0 +1,262 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::{
+    clients::embeddings::EmbeddingsClient,
+    repos::{
+        messages::{cosine_similarity, ChatModel, MessageRepo},
+        suppressions::{SuppressionModel, SuppressionRepo},
+    },
+    services::chat::SearchResponse,
+};
+
+#[derive(Deserialize)]
+pub struct ForgetRequest {
+    pub description: String,
+    /// Without confirmation the matching memories are only listed
+    #[serde(default)]
+    pub confirm: bool,
+    #[serde(default = "default_threshold")]
+    pub threshold: f32,
+}
+
+fn default_threshold() -> f32 {
+    0.8
+}
+
+#[derive(Serialize)]
+pub struct ForgetResponse {
+    pub confirmed: bool,
+    pub matches: Vec<SearchResponse>,
+    pub suppression_id: Option<String>,
+}
+
+pub struct MemoryService {
+    pub embedding_client: Arc<Mutex<dyn EmbeddingsClient>>,
+    pub message_repo: Arc<Mutex<dyn MessageRepo>>,
+    pub suppression_repo: Arc<Mutex<dyn SuppressionRepo>>,
+}
+
+/// Whether a message is close enough to something the user asked to forget
+pub fn is_suppressed(chat: &ChatModel, suppressions: &[SuppressionModel]) -> bool {
+    if chat.forgotten {
+        return true;
+    }
+    match &chat.embedding {
+        Some(embedding) => suppressions.iter().any(|suppression| {
+            embedding.len() == suppression.embedding.len()
+                && cosine_similarity(embedding, &suppression.embedding) >= suppression.threshold
+        }),
+        None => false,
+    }
+}
+
+impl MemoryService {
+    pub async fn forget(
+        &self,
+        username: &str,
+        request: ForgetRequest,
+    ) -> Result<ForgetResponse, ()> {
+        let embedding_client = self.embedding_client.lock().await;
+        let model = embedding_client.model();
+        let similarity = embedding_client.similarity();
+        let query_vector = embedding_client
+            .get_embeddings(request.description.clone())
+            .await;
+        drop(embedding_client);
+        let query_vector = match query_vector {
+            Ok(query_vector) => query_vector,
+            Err(_) => {
+                error!("Failed to get embeddings");
+                return Err(());
+            }
+        };
+
+        let mut message_repo = self.message_repo.lock().await;
+        let matches = message_repo
+            .embeddings_search_for_user(username.to_string(), query_vector.clone(), &model, similarity)
+            .await?
+            .into_iter()
+            .filter(|(similarity, _)| *similarity >= request.threshold)
+            .collect::<Vec<(f32, ChatModel)>>();
+
+        if !request.confirm {
+            return Ok(ForgetResponse {
+                confirmed: false,
+                matches: matches
+                    .into_iter()
+                    .map(|(similarity, chat)| SearchResponse::from_chat_model(chat, similarity))
+                    .collect(),
+                suppression_id: None,
+            });
+        }
+
+        let mut forgotten = vec![];
+        for (similarity, chat) in matches {
+            match message_repo
+                .redact_chat(username.to_string(), chat.hash.clone())
+                .await
+            {
+                Ok(tombstone) => {
+                    forgotten.push(SearchResponse::from_chat_model(tombstone, similarity))
+                }
+                Err(_) => error!("Failed to forget chat {}", chat.hash),
+            }
+        }
+
+        let suppression = SuppressionModel {
+            id: uuid::Uuid::new_v4().to_string(),
+            description: request.description,
+            embedding: query_vector,
+            threshold: request.threshold,
+            created_at: chrono::Utc::now().timestamp(),
+        };
+        let suppression = self
+            .suppression_repo
+            .lock()
+            .await
+            .save_suppression(username, suppression)?;
+
+        info!(
+            "Forgot {} messages for user {} and recorded suppression {}",
+            forgotten.len(),
+            username,
+            suppression.id
+        );
+
+        Ok(ForgetResponse {
+            confirmed: true,
+            matches: forgotten,
+            suppression_id: Some(suppression.id),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::{
+        repos::{
+            messages::FsMessageRepo, presets::ContextPreset, suppressions::FsSuppressionRepo,
+        },
+        services::{chat::ChatService, latency::LatencyBudget},
+    };
+
+    // Everything about the old job lands on one vector and the rest on another
+    struct TopicEmbeddingsClient {}
+
+    #[async_trait]
+    impl EmbeddingsClient for TopicEmbeddingsClient {
+        async fn get_embeddings(&self, text: String) -> Result<Vec<f32>, ()> {
+            match text.contains("old job") {
+                true => Ok(vec![1.0, 0.0]),
+                false => Ok(vec![0.0, 1.0]),
+            }
+        }
+
+        fn model(&self) -> String {
+            "topic".to_string()
+        }
+    }
+
+    fn chat(hash: &str, content: &str, embedding: Vec<f32>) -> ChatModel {
+        ChatModel {
+            embedding: Some(embedding),
+            embedding_model: Some("topic".to_string()),
+            ..ChatModel::test(hash, content)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forget_previews_then_tombstones_and_suppresses() {
+        let username = format!("test_forget_{}", uuid::Uuid::new_v4());
+        let today = chrono::Utc::now().date_naive();
+        let message_repo = Arc::new(Mutex::new(FsMessageRepo::new()));
+        let suppression_repo = Arc::new(Mutex::new(FsSuppressionRepo::new()));
+        let embedding_client = Arc::new(Mutex::new(TopicEmbeddingsClient {}));
+        for chat in [
+            chat("job", "I hated my old job at the bank", vec![1.0, 0.0]),
+            chat("garden", "The tomatoes are ripening", vec![0.0, 1.0]),
+        ] {
+            message_repo
+                .lock()
+                .await
+                .save_chat(today, username.clone(), chat)
+                .await
+                .unwrap();
+        }
+        let service = MemoryService {
+            embedding_client: embedding_client.clone(),
+            message_repo: message_repo.clone(),
+            suppression_repo: suppression_repo.clone(),
+        };
+        let request = |confirm| ForgetRequest {
+            description: "my old job".to_string(),
+            confirm,
+            threshold: default_threshold(),
+        };
+
+        // without confirmation nothing changes
+        let preview = service.forget(&username, request(false)).await.unwrap();
+        assert!(!preview.confirmed);
+        assert!(preview.suppression_id.is_none());
+        assert_eq!(preview.matches.len(), 1);
+        assert_eq!(preview.matches[0].content, "I hated my old job at the bank");
+        let stored = message_repo
+            .lock()
+            .await
+            .get_chat(username.clone(), "job".to_string())
+            .await
+            .unwrap();
+        assert!(!stored.forgotten);
+
+        let confirmed = service.forget(&username, request(true)).await.unwrap();
+        assert!(confirmed.confirmed);
+        assert!(confirmed.suppression_id.is_some());
+        assert_eq!(confirmed.matches.len(), 1);
+        let stored = message_repo
+            .lock()
+            .await
+            .get_chat(username.clone(), "job".to_string())
+            .await
+            .unwrap();
+        assert!(stored.forgotten);
+        assert!(stored.content.is_empty());
+
+        // said again after forgetting, it is kept out of context by the rule
+        message_repo
+            .lock()
+            .await
+            .save_chat(
+                today,
+                username.clone(),
+                chat("again", "Still thinking about the old job", vec![1.0, 0.0]),
+            )
+            .await
+            .unwrap();
+        let chat_service = ChatService {
+            embedding_client,
+            suppression_repo,
+            ..ChatService::for_tests(message_repo)
+        };
+        let context = chat_service
+            .get_context(
+                &username,
+                "what did I do for work",
+                &ContextPreset::default(),
+                &LatencyBudget::default(),
+            )
+            .await
+            .unwrap();
+        let contents = context
+            .iter()
+            .map(|chat| chat.content.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(contents, vec!["The tomatoes are ripening"]);
+    }
+}
@@ -1,3 +1,33 @@
 pub mod chat;
 pub mod summary;
 pub mod user_attributes;
+pub mod threads;
+pub mod memory;
+pub mod briefing;
+pub mod importance;
+pub mod export;
+pub mod saved_searches;
+pub mod personas;
+pub mod reembed;
+pub mod feedback;
+pub mod import;
+pub mod latency;
+pub mod admin;
+pub mod usage;
+pub mod collections;
+pub mod dedup;
+pub mod extraction;
+pub mod context;
+pub mod ranking;
+pub mod embedding_queue;
+pub mod topics;
+pub mod validation;
+pub mod webhooks;
+pub mod telegram;
+pub mod ingest;
+pub mod feeds;
+pub mod sensors;
+pub mod reminders;
+pub mod retention;
+pub mod pii;
+pub mod replication;
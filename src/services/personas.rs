@@ -0,0 +1,121 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::repos::attributes::{AttributeRepo, AttributeSource};
+
+// Personas are kept as attributes named `persona.<name>` holding JSON
+const PERSONA_PREFIX: &str = "persona.";
+
+/// A named way for the assistant to present itself, several of which can
+/// share the same memory
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PersonaModel {
+    pub name: String,
+    pub system_prompt: String,
+    #[serde(default)]
+    pub tone: Option<String>,
+    /// Tools the client may offer the model while this persona is active
+    #[serde(default)]
+    pub tools: Vec<String>,
+    /// Model the client should use, falling back to its own default
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+impl PersonaModel {
+    /// The persona as the system message that opens a context
+    pub fn system_message(&self) -> String {
+        match &self.tone {
+            Some(tone) => format!("{}\nTone: {}", self.system_prompt, tone),
+            None => self.system_prompt.clone(),
+        }
+    }
+}
+
+pub struct PersonaService {
+    pub attribute_repo: Arc<Mutex<dyn AttributeRepo>>,
+}
+
+impl PersonaService {
+    pub async fn save_persona(
+        &self,
+        username: &str,
+        persona: PersonaModel,
+    ) -> Result<PersonaModel, ()> {
+        let value = serde_json::to_string(&persona).map_err(|_| ())?;
+        self.attribute_repo
+            .lock()
+            .await
+            .save_attribute(
+                username,
+                &format!("{}{}", PERSONA_PREFIX, persona.name),
+                &value,
+                None,
+                AttributeSource::Stated,
+            )
+            .await?;
+
+        info!("Saved persona {} for user {}", persona.name, username);
+        Ok(persona)
+    }
+
+    pub async fn get_persona(&self, username: &str, name: &str) -> Result<PersonaModel, ()> {
+        let attribute = self
+            .attribute_repo
+            .lock()
+            .await
+            .get_attribute(username, &format!("{}{}", PERSONA_PREFIX, name))
+            .await?;
+
+        serde_json::from_str(&attribute.value).map_err(|e| {
+            error!("Error deserializing persona {}: {}", name, e);
+        })
+    }
+
+    pub async fn get_personas(&self, username: &str) -> Result<Vec<PersonaModel>, ()> {
+        let attributes = self
+            .attribute_repo
+            .lock()
+            .await
+            .get_all_attributes(username)
+            .await?;
+
+        Ok(attributes
+            .into_iter()
+            .filter(|attribute| attribute.attribute.starts_with(PERSONA_PREFIX))
+            .filter_map(|attribute| serde_json::from_str(&attribute.value).ok())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repos::attributes::FsAttributeRepo;
+
+    #[tokio::test]
+    async fn test_personas_roundtrip_through_attributes() {
+        let username = format!("test_persona_{}", uuid::Uuid::new_v4());
+        let service = PersonaService {
+            attribute_repo: Arc::new(Mutex::new(FsAttributeRepo::new())),
+        };
+        let persona = PersonaModel {
+            name: "work".to_string(),
+            system_prompt: "You are a concise assistant.".to_string(),
+            tone: Some("terse".to_string()),
+            tools: vec!["calendar".to_string()],
+            model: None,
+        };
+
+        service.save_persona(&username, persona).await.unwrap();
+
+        let work = service.get_persona(&username, "work").await.unwrap();
+        assert_eq!(work.tools, vec!["calendar".to_string()]);
+        assert!(work.system_message().ends_with("Tone: terse"));
+        assert_eq!(service.get_personas(&username).await.unwrap().len(), 1);
+        assert!(service.get_persona(&username, "home").await.is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use std::sync::{Arc, OnceLock};
+
+use regex::Regex;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::{
+    clients::chat::{ChatClient, Message},
+    repos::attributes::AttributeRepo,
+};
+
+/// Attribute turning scrubbing on or off for a user, `true` or `false`
+pub const PII_ATTRIBUTE: &str = "scrub_pii";
+
+/// Metadata of a message whose content was masked, listing what was found
+pub const REDACTED_METADATA: &str = "pii_redacted";
+
+const PII_PROMPT: &str = "Below is a message. Reply with a JSON array of the exact pieces of text in it that identify a person, such as full names, street addresses, account numbers and ID numbers, copied as they appear. Leave out anything general such as cities or first names on their own, and reply with [] when there is nothing.";
+
+struct Patterns {
+    email: Regex,
+    card: Regex,
+    phone: Regex,
+}
+
+fn patterns() -> &'static Patterns {
+    static PATTERNS: OnceLock<Patterns> = OnceLock::new();
+    PATTERNS.get_or_init(|| Patterns {
+        email: Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}")
+            .unwrap(),
+        card: Regex::new(r"\b\d(?:[ -]?\d){12,18}\b").unwrap(),
+        phone: Regex::new(r"\+?\(?\d[\d ().-]{6,}\d").unwrap(),
+    })
+}
+
+// Whether the digits pass the Luhn check card numbers carry, which dates,
+// amounts and most other long numbers do not
+fn is_luhn_valid(digits: &[u32]) -> bool {
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, digit)| match i % 2 {
+            1 if *digit * 2 > 9 => *digit * 2 - 9,
+            1 => *digit * 2,
+            _ => *digit,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+fn digits(text: &str) -> Vec<u32> {
+    text.chars().filter_map(|c| c.to_digit(10)).collect()
+}
+
+/// `content` with emails, card numbers and phone numbers replaced by
+/// `[email]`, `[card]` and `[phone]`, with the kinds that were found
+pub fn mask(content: &str) -> (String, Vec<&'static str>) {
+    let patterns = patterns();
+    let mut kinds = vec![];
+
+    let masked = patterns.email.replace_all(content, "[email]");
+    if masked != content {
+        kinds.push("email");
+    }
+    let before = masked.to_string();
+    let masked = patterns
+        .card
+        .replace_all(&before, |found: &regex::Captures| {
+            match is_luhn_valid(&digits(&found[0])) {
+                true => "[card]".to_string(),
+                false => found[0].to_string(),
+            }
+        })
+        .to_string();
+    if masked != before {
+        kinds.push("card");
+    }
+    // numbers of 9 to 15 digits, which leaves out dates and times
+    let before = masked.clone();
+    let masked = patterns
+        .phone
+        .replace_all(&masked, |found: &regex::Captures| {
+            match (9..=15).contains(&digits(&found[0]).len()) {
+                true => "[phone]".to_string(),
+                false => found[0].to_string(),
+            }
+        })
+        .to_string();
+    if masked != before {
+        kinds.push("phone");
+    }
+    (masked, kinds)
+}
+
+/// Reads the pieces of text the model found out of its reply, which may
+/// wrap the JSON array in prose or a code block
+fn parse_found(reply: &str) -> Vec<String> {
+    let array = match (reply.find('['), reply.rfind(']')) {
+        (Some(start), Some(end)) if start < end => &reply[start..=end],
+        _ => return vec![],
+    };
+    serde_json::from_str::<Vec<String>>(array)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|found| found.trim().to_string())
+        // too short to tell apart from ordinary words
+        .filter(|found| found.chars().count() > 2)
+        .collect()
+}
+
+/// What is stored of a message once personal details are masked
+#[derive(Debug, PartialEq)]
+pub struct Scrubbed {
+    pub content: String,
+    /// Kinds of details that were masked, empty when nothing was
+    pub kinds: Vec<&'static str>,
+}
+
+/// Masks personal details in messages before they are stored, for users
+/// who have scrubbing turned on
+#[derive(Clone)]
+pub struct PiiScrubber {
+    pub attribute_repo: Arc<Mutex<dyn AttributeRepo>>,
+    pub chat_client: Arc<Mutex<dyn ChatClient>>,
+    /// Whether users without the `scrub_pii` attribute are scrubbed
+    pub enabled_by_default: bool,
+    /// Also asks the chat model for details the patterns miss
+    pub use_model: bool,
+}
+
+impl PiiScrubber {
+    /// Scrubs as the configuration and each user's attribute say
+    pub(crate) fn from_resources(resources: &crate::Resources) -> PiiScrubber {
+        PiiScrubber {
+            attribute_repo: resources.user_attributes_repo.clone(),
+            chat_client: resources.chat_client.clone(),
+            enabled_by_default: resources.config.storage.scrub_pii,
+            use_model: resources.config.storage.scrub_pii_with_model,
+        }
+    }
+
+    pub async fn is_enabled(&self, username: &str) -> bool {
+        // collections are scrubbed as their owner is
+        let owner = username.split('/').next().unwrap_or(username);
+        let attribute = self
+            .attribute_repo
+            .lock()
+            .await
+            .get_attribute(owner, PII_ATTRIBUTE)
+            .await;
+        match attribute.map(|attribute| attribute.value.trim().to_lowercase()) {
+            Ok(value) if value == "true" || value == "on" => true,
+            Ok(value) if value == "false" || value == "off" => false,
+            _ => self.enabled_by_default,
+        }
+    }
+
+    /// The content as it is stored for the user. A model pass that fails is
+    /// skipped, the patterns have masked what they could by then.
+    pub async fn scrub(&self, username: &str, content: &str) -> Scrubbed {
+        if content.is_empty() || !self.is_enabled(username).await {
+            return Scrubbed {
+                content: content.to_string(),
+                kinds: vec![],
+            };
+        }
+        let (mut masked, mut kinds) = mask(content);
+        if self.use_model {
+            let context = vec![
+                Message {
+                    role: "system".to_string(),
+                    content: PII_PROMPT.to_string(),
+                },
+                Message {
+                    role: "user".to_string(),
+                    content: masked.clone(),
+                },
+            ];
+            match self.chat_client.lock().await.complete(context).await {
+                Ok(reply) => {
+                    let before = masked.clone();
+                    for found in parse_found(&reply.content) {
+                        masked = masked.replace(&found, "[personal]");
+                    }
+                    if masked != before {
+                        kinds.push("personal");
+                    }
+                }
+                Err(e) => warn!("Skipping model pass of PII scrubbing: {}", e),
+            }
+        }
+        Scrubbed {
+            content: masked,
+            kinds,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clients::chat::{ChatCompletion, ChatError, Usage},
+        repos::attributes::{AttributeSource, FsAttributeRepo},
+    };
+
+    struct FixedChatClient {
+        reply: String,
+    }
+
+    #[async_trait::async_trait]
+    impl ChatClient for FixedChatClient {
+        fn model(&self) -> String {
+            "fixed".to_string()
+        }
+
+        async fn complete_with(
+            &mut self,
+            _: Vec<Message>,
+            _: Option<&str>,
+        ) -> Result<ChatCompletion, ChatError> {
+            Ok(ChatCompletion {
+                content: self.reply.clone(),
+                model: self.model(),
+                usage: Usage::default(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_contact_details_and_cards_are_masked() {
+        let (masked, kinds) =
+            mask("Mail anna.k@example.co.uk or call +47 912 34 567, card 4111 1111 1111 1111");
+        assert_eq!(masked, "Mail [email] or call [phone], card [card]");
+        assert_eq!(kinds, vec!["email", "card", "phone"]);
+
+        // dates, times, amounts and numbers failing the Luhn check are kept
+        let kept = "On 2024-03-01 at 10:30 I paid 1,250.00 for order 1234 5678 9012 3456";
+        assert_eq!(mask(kept), (kept.to_string(), vec![]));
+    }
+
+    #[actix::test]
+    async fn test_users_choose_whether_they_are_scrubbed() {
+        let username = format!("test_pii_{}", uuid::Uuid::new_v4());
+        let scrubber = PiiScrubber {
+            attribute_repo: Arc::new(Mutex::new(FsAttributeRepo::new())),
+            chat_client: Arc::new(Mutex::new(FixedChatClient {
+                reply: "Found: [\"Anna Karlsen\", \"x\"]".to_string(),
+            })),
+            enabled_by_default: false,
+            use_model: true,
+        };
+        let content = "Anna Karlsen's email is anna@example.com";
+        let kept = scrubber.scrub(&username, content).await;
+        assert_eq!(kept.content, content);
+        assert!(kept.kinds.is_empty());
+
+        scrubber
+            .attribute_repo
+            .lock()
+            .await
+            .save_attribute(
+                &username,
+                PII_ATTRIBUTE,
+                "true",
+                None,
+                AttributeSource::Stated,
+            )
+            .await
+            .unwrap();
+        let scrubbed = scrubber.scrub(&username, content).await;
+        assert_eq!(scrubbed.content, "[personal]'s email is [email]");
+        assert_eq!(scrubbed.kinds, vec!["email", "personal"]);
+        // collections follow their owner
+        let collection = format!("{}/collections/notes", username);
+        assert!(scrubber.is_enabled(&collection).await);
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::repos::messages::{cosine_similarity, ChatModel};
+
+/// How much a message said `timestamp` still counts at `now`: 1 when it was
+/// just said, halving every `half_life_days`
+pub fn recency(timestamp: i64, now: i64, half_life_days: f32) -> f32 {
+    if half_life_days <= 0.0 {
+        return 1.0;
+    }
+    let age_days = (now - timestamp).max(0) as f32 / 86_400.0;
+    0.5_f32.powf(age_days / half_life_days)
+}
+
+// Share of a diversified ranking that comes from relevance rather than from
+// being unlike the results picked before
+const DIVERSITY_LAMBDA: f32 = 0.7;
+
+// Results considered for each one kept when diversifying
+const DIVERSITY_POOL: usize = 4;
+
+/// Picks `limit` of the best first `ranked` results by maximal marginal
+/// relevance, so a result close to one already picked, such as a repeated
+/// greeting, gives way to a different one. Results without an embedding
+/// count as unlike every other.
+pub fn diversify<T>(ranked: Vec<(f32, Option<Vec<f32>>, T)>, limit: usize) -> Vec<T> {
+    let mut pool = ranked
+        .into_iter()
+        .take(limit.saturating_mul(DIVERSITY_POOL))
+        .collect::<Vec<(f32, Option<Vec<f32>>, T)>>();
+    let mut picked: Vec<(Option<Vec<f32>>, T)> = vec![];
+    while picked.len() < limit && !pool.is_empty() {
+        let marginal = |(relevance, embedding, _): &(f32, Option<Vec<f32>>, T)| {
+            let redundancy = picked
+                .iter()
+                .filter_map(|(other, _)| {
+                    Some(cosine_similarity(embedding.as_ref()?, other.as_ref()?))
+                })
+                // NaN from zero vectors counts as unrelated
+                .fold(0.0, f32::max);
+            DIVERSITY_LAMBDA * relevance - (1.0 - DIVERSITY_LAMBDA) * redundancy
+        };
+        let best = pool
+            .iter()
+            .enumerate()
+            .max_by(|a, b| marginal(a.1).total_cmp(&marginal(b.1)).then(b.0.cmp(&a.0)))
+            .map(|(index, _)| index)
+            .unwrap_or(0);
+        let (_, embedding, result) = pool.remove(best);
+        picked.push((embedding, result));
+    }
+    picked.into_iter().map(|(_, result)| result).collect()
+}
+
+/// How similarity, recency and role are weighed into a search ranking. The
+/// default ranks on similarity alone.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Ranking {
+    /// Share of the ranking that comes from recency instead of similarity,
+    /// between 0 and 1
+    pub recency_weight: f32,
+    /// Days after which a message counts half as recent
+    pub half_life_days: f32,
+    /// Multiplies the ranking of messages by their role, e.g.
+    /// `{"assistant": 0.5}`. Roles that are not listed count fully.
+    pub role_weights: HashMap<String, f32>,
+}
+
+impl Default for Ranking {
+    fn default() -> Self {
+        Ranking {
+            recency_weight: 0.0,
+            half_life_days: 30.0,
+            role_weights: HashMap::new(),
+        }
+    }
+}
+
+impl Ranking {
+    pub fn is_default(&self) -> bool {
+        *self == Ranking::default()
+    }
+
+    /// The ranking of `chat` found with `similarity`
+    pub fn score(&self, similarity: f32, chat: &ChatModel, now: i64) -> f32 {
+        let weight = self.recency_weight.clamp(0.0, 1.0);
+        let recency = recency(chat.said_at(), now, self.half_life_days);
+        let role_weight = self.role_weights.get(&chat.role).copied().unwrap_or(1.0);
+        ((1.0 - weight) * similarity + weight * recency) * role_weight
+    }
+
+    /// Re-ranks search results, best first
+    pub fn apply(&self, ranked: Vec<(f32, ChatModel)>, now: i64) -> Vec<(f32, ChatModel)> {
+        if self.is_default() {
+            return ranked;
+        }
+        let mut ranked = ranked
+            .into_iter()
+            .map(|(similarity, chat)| (self.score(similarity, &chat, now), chat))
+            .collect::<Vec<(f32, ChatModel)>>();
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+        ranked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(hash: &str, role: &str, age_days: i64) -> ChatModel {
+        ChatModel {
+            role: role.to_string(),
+            timestamp: 100 * 86_400 - age_days * 86_400,
+            ..ChatModel::test(hash, hash)
+        }
+    }
+
+    fn hashes(ranked: &[(f32, ChatModel)]) -> Vec<&str> {
+        ranked.iter().map(|(_, chat)| chat.hash.as_str()).collect()
+    }
+
+    #[test]
+    fn test_recency_and_roles_reorder_results() {
+        let now = 100 * 86_400;
+        let results = vec![
+            (0.9, chat("years_old", "user", 60)),
+            (0.8, chat("yesterday", "user", 1)),
+            (0.85, chat("reply", "assistant", 0)),
+        ];
+        let unchanged = Ranking::default().apply(results.clone(), now);
+        assert_eq!(hashes(&unchanged), vec!["years_old", "yesterday", "reply"]);
+
+        let ranking = Ranking {
+            recency_weight: 0.5,
+            half_life_days: 7.0,
+            role_weights: HashMap::from([("assistant".to_string(), 0.5)]),
+        };
+        let ranked = ranking.apply(results, now);
+        assert_eq!(hashes(&ranked), vec!["yesterday", "reply", "years_old"]);
+        assert!((recency(now, now, 7.0) - 1.0).abs() < 1e-6);
+        assert!((recency(now - 7 * 86_400, now, 7.0) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_diversify_skips_near_duplicates() {
+        let ranked = vec![
+            (0.9, Some(vec![1.0, 0.0]), "hello"),
+            (0.89, Some(vec![1.0, 0.01]), "hello again"),
+            (0.7, Some(vec![0.0, 1.0]), "locker code"),
+            (0.6, None, "unembedded"),
+        ];
+        assert_eq!(
+            diversify(ranked.clone(), 3),
+            vec!["hello", "locker code", "unembedded"]
+        );
+        assert_eq!(diversify(ranked, 1), vec!["hello"]);
+    }
+}
@@ -0,0 +1,166 @@
+use std::{collections::HashMap, sync::Arc};
+
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::{
+    clients::embeddings::EmbeddingsClient,
+    hub::{EventHub, HubEvent},
+    repos::messages::{ChatModel, MessageRepo},
+};
+
+// Messages embedded concurrently before progress is reported
+const BATCH_SIZE: usize = 16;
+
+pub struct ReembedService {
+    pub message_repo: Arc<Mutex<dyn MessageRepo>>,
+    pub event_hub: Arc<EventHub>,
+}
+
+impl ReembedService {
+    /// Messages that carry an embedding and would be re-embedded
+    pub async fn get_embeddable(&self, username: &str) -> Result<Vec<ChatModel>, ()> {
+        Ok(self
+            .message_repo
+            .lock()
+            .await
+            .get_all_for_user(username.to_string())
+            .await?
+            .into_iter()
+            .filter(|chat| !chat.forgotten && !chat.low_value && !chat.content.is_empty())
+            .collect())
+    }
+
+    /// Embeds every message with `client` and swaps the vectors in once all
+    /// of them succeeded, so a failed run leaves the old vectors in place.
+    /// The client is only locked for one batch at a time so it can be the
+    /// one serving live requests.
+    pub async fn reembed(
+        &self,
+        username: &str,
+        model: &str,
+        client: Arc<Mutex<dyn EmbeddingsClient>>,
+    ) -> Result<usize, ()> {
+        let chats = self.get_embeddable(username).await?;
+        let total = chats.len();
+        let mut embeddings = HashMap::new();
+        let embedding_model = client.lock().await.model();
+
+        for batch in chats.chunks(BATCH_SIZE) {
+            let client = client.lock().await;
+            let results = futures::future::join_all(
+                batch
+                    .iter()
+                    .map(|chat| client.get_embeddings(chat.content.clone())),
+            )
+            .await;
+            for (chat, result) in batch.iter().zip(results) {
+                match result {
+                    Ok(embedding) => {
+                        embeddings.insert(chat.hash.clone(), client.similarity().prepare(embedding));
+                    }
+                    Err(_) => {
+                        error!("Failed to re-embed {} for {}", chat.hash, username);
+                        self.finish(username, model, false);
+                        return Err(());
+                    }
+                }
+            }
+            self.event_hub.publish(HubEvent::ReembedProgress {
+                username: username.to_string(),
+                done: embeddings.len(),
+                total,
+            });
+        }
+
+        let replaced = self
+            .message_repo
+            .lock()
+            .await
+            .replace_embeddings(username.to_string(), embeddings, &embedding_model)
+            .await;
+        self.finish(username, model, replaced.is_ok());
+        if let Ok(replaced) = replaced {
+            info!(
+                "Re-embedded {} messages for {} with {}",
+                replaced, username, model
+            );
+        }
+        Ok(replaced?)
+    }
+
+    fn finish(&self, username: &str, model: &str, success: bool) {
+        self.event_hub.publish(HubEvent::ReembedFinished {
+            username: username.to_string(),
+            model: model.to_string(),
+            success,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clients::embeddings::MockEmbeddingsClient, repos::messages::FsMessageRepo};
+
+    #[tokio::test]
+    async fn test_reembed_swaps_vectors_and_reports_progress() {
+        let username = format!("test_reembed_{}", uuid::Uuid::new_v4());
+        let message_repo = Arc::new(Mutex::new(FsMessageRepo::new()));
+        let event_hub = Arc::new(EventHub::new(16));
+        let mut events = event_hub.subscribe(&username);
+
+        let date = chrono::Utc::now().date_naive();
+        for hash in ["first", "second"] {
+            message_repo.lock().await.save_chat(
+                date,
+                username.clone(),
+                ChatModel {
+                    embedding: Some(vec![1.0, 0.0]),
+                    embedding_model: Some("all-minilm".to_string()),
+                    ..ChatModel::test(hash, &format!("message {}", hash))
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        let service = ReembedService {
+            message_repo: message_repo.clone(),
+            event_hub: event_hub.clone(),
+        };
+        let replaced = service
+            .reembed(
+                &username,
+                "mock",
+                Arc::new(Mutex::new(MockEmbeddingsClient::new())),
+            )
+            .await
+            .unwrap();
+        assert_eq!(replaced, 2);
+
+        let chats = message_repo
+            .lock()
+            .await
+            .get_all_for_user(username.clone())
+            .await
+            .unwrap();
+        assert!(chats
+            .iter()
+            .all(|chat| chat.embedding.as_ref().unwrap().len() == 3
+                && chat.embedding_model.as_deref() == Some("mock")));
+
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            HubEvent::ReembedProgress {
+                done: 2,
+                total: 2,
+                ..
+            }
+        ));
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            HubEvent::ReembedFinished { success: true, .. }
+        ));
+    }
+}
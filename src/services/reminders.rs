@@ -0,0 +1,315 @@
+use std::{fmt, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::{
+    clients::chat::{ChatClient, ChatError, Message},
+    hub::{EventHub, HubEvent},
+    repos::{
+        attributes::AttributeRepo,
+        reminders::{ReminderModel, ReminderRepo},
+    },
+};
+
+const REMINDER_PROMPT: &str = "The user asks to be reminded of something. Reply with a JSON object holding \"text\", what to remind them of as a short note such as \"Call Ana\", and \"due_at\", when to remind them as an RFC 3339 timestamp with their UTC offset. A day without a time means 9 in the morning, a time without a day means the next time it comes. Reply with {} when the user does not say when.";
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ReminderRequest {
+    /// What to be reminded of. Without `due_at` it says when as well, such
+    /// as "remind me Friday at 5 to call Ana", and is read by the chat model.
+    pub text: String,
+    /// RFC 3339 or seconds since the epoch
+    pub due_at: Option<String>,
+}
+
+/// Why a reminder could not be created
+#[derive(Debug)]
+pub enum ReminderError {
+    /// The text does not say when the reminder is due
+    NoTime,
+    /// The reminder would be due before now
+    Past,
+    Chat(ChatError),
+    /// The reminder could not be stored, logged
+    Storage,
+}
+
+impl fmt::Display for ReminderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReminderError::NoTime => write!(f, "no time could be read from the text"),
+            ReminderError::Past => write!(f, "the reminder would be due in the past"),
+            ReminderError::Chat(e) => write!(f, "{}", e),
+            ReminderError::Storage => write!(f, "the reminder could not be stored"),
+        }
+    }
+}
+
+/// Reads RFC 3339 timestamps and seconds since the epoch
+pub fn parse_due_at(value: &str) -> Option<i64> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<i64>() {
+        return Some(seconds);
+    }
+    DateTime::parse_from_rfc3339(value)
+        .ok()
+        .map(|due_at| due_at.timestamp())
+}
+
+/// Reads the note and due time out of the model's reply, which may wrap the
+/// JSON object in prose or a code block
+pub fn parse_reminder(reply: &str) -> Option<(Option<String>, i64)> {
+    let object = match (reply.find('{'), reply.rfind('}')) {
+        (Some(start), Some(end)) if start < end => &reply[start..=end],
+        _ => return None,
+    };
+    let reminder: Value = serde_json::from_str(object).ok()?;
+    let due_at = parse_due_at(reminder["due_at"].as_str()?)?;
+    let text = reminder["text"]
+        .as_str()
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .map(str::to_string);
+    Some((text, due_at))
+}
+
+/// Keeps reminders and announces them when they are due
+pub struct ReminderService {
+    pub reminder_repo: Arc<Mutex<dyn ReminderRepo>>,
+    pub attribute_repo: Arc<Mutex<dyn AttributeRepo>>,
+    pub chat_client: Arc<Mutex<dyn ChatClient>>,
+    pub event_hub: Arc<EventHub>,
+}
+
+impl ReminderService {
+    /// Stores a reminder, asking the chat model when it is due if the
+    /// request does not say
+    pub async fn create(
+        &self,
+        username: &str,
+        request: ReminderRequest,
+        now: i64,
+    ) -> Result<ReminderModel, ReminderError> {
+        let (text, due_at) = match &request.due_at {
+            Some(due_at) => (request.text.trim().to_string(), parse_due_at(due_at)),
+            None => {
+                let (text, due_at) = self.read_text(username, &request.text, now).await?;
+                let text = text.unwrap_or_else(|| request.text.trim().to_string());
+                (text, Some(due_at))
+            }
+        };
+        let due_at = due_at.ok_or(ReminderError::NoTime)?;
+        if due_at < now {
+            return Err(ReminderError::Past);
+        }
+        let reminder = ReminderModel {
+            id: uuid::Uuid::new_v4().to_string(),
+            text,
+            due_at,
+            created_at: now,
+            fired_at: None,
+        };
+        self.reminder_repo
+            .lock()
+            .await
+            .save_reminder(username, reminder)
+            .map_err(|_| ReminderError::Storage)
+    }
+
+    // What and when the user asked to be reminded, read by the chat model
+    async fn read_text(
+        &self,
+        username: &str,
+        text: &str,
+        now: i64,
+    ) -> Result<(Option<String>, i64), ReminderError> {
+        let now = DateTime::<Utc>::from_timestamp(now, 0).unwrap_or_default();
+        let timezone = self
+            .attribute_repo
+            .lock()
+            .await
+            .get_attribute(username, "timezone")
+            .await
+            .map(|timezone| timezone.value)
+            .unwrap_or_else(|_| "UTC".to_string());
+        let context = vec![
+            Message {
+                role: "system".to_string(),
+                content: REMINDER_PROMPT.to_string(),
+            },
+            Message {
+                role: "system".to_string(),
+                content: format!(
+                    "It is now {} UTC, the user's time zone is {}.",
+                    now.format("%A %Y-%m-%d %H:%M"),
+                    timezone
+                ),
+            },
+            Message {
+                role: "user".to_string(),
+                content: text.to_string(),
+            },
+        ];
+        let reply = self
+            .chat_client
+            .lock()
+            .await
+            .complete(context)
+            .await
+            .map_err(ReminderError::Chat)?;
+        parse_reminder(&reply.content).ok_or(ReminderError::NoTime)
+    }
+
+    /// Announces every pending reminder due by `now` and returns how many
+    /// there were
+    pub async fn fire_due(&self, now: i64) -> Result<usize, ()> {
+        let users = self.reminder_repo.lock().await.get_users()?;
+        let mut fired = 0;
+        for user in users {
+            let due = self
+                .reminder_repo
+                .lock()
+                .await
+                .get_reminders(&user)?
+                .into_iter()
+                .filter(|reminder| reminder.fired_at.is_none() && reminder.due_at <= now)
+                .collect::<Vec<ReminderModel>>();
+            for reminder in due {
+                // reminders deleted in the meantime are not announced
+                if self
+                    .reminder_repo
+                    .lock()
+                    .await
+                    .mark_fired(&user, &reminder.id, now)
+                    .is_err()
+                {
+                    warn!("Reminder {} of {} is gone", reminder.id, user);
+                    continue;
+                }
+                info!("Reminding {} of {}", user, reminder.id);
+                self.event_hub.publish(HubEvent::ReminderDue {
+                    username: user.clone(),
+                    id: reminder.id,
+                    text: reminder.text,
+                    due_at: reminder.due_at,
+                });
+                fired += 1;
+            }
+        }
+        Ok(fired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clients::chat::{ChatCompletion, Usage},
+        repos::{attributes::FsAttributeRepo, reminders::FsReminderRepo},
+    };
+
+    struct FixedChatClient {
+        reply: String,
+    }
+
+    #[async_trait::async_trait]
+    impl ChatClient for FixedChatClient {
+        fn model(&self) -> String {
+            "fixed".to_string()
+        }
+
+        async fn complete_with(
+            &mut self,
+            _: Vec<Message>,
+            _: Option<&str>,
+        ) -> Result<ChatCompletion, ChatError> {
+            Ok(ChatCompletion {
+                content: self.reply.clone(),
+                model: self.model(),
+                usage: Usage::default(),
+            })
+        }
+    }
+
+    fn service(reply: &str) -> ReminderService {
+        ReminderService {
+            reminder_repo: Arc::new(Mutex::new(FsReminderRepo::new())),
+            attribute_repo: Arc::new(Mutex::new(FsAttributeRepo::new())),
+            chat_client: Arc::new(Mutex::new(FixedChatClient {
+                reply: reply.to_string(),
+            })),
+            event_hub: Arc::new(EventHub::new(16)),
+        }
+    }
+
+    #[test]
+    fn test_parse_reminder_from_wrapped_reply() {
+        let reply = "Sure:\n```json\n{\"text\": \"Call Ana\", \"due_at\": \"2023-11-17T17:00:00+02:00\"}\n```";
+        assert_eq!(
+            parse_reminder(reply),
+            Some((Some("Call Ana".to_string()), 1_700_233_200))
+        );
+        assert_eq!(parse_reminder("{}"), None);
+        assert_eq!(parse_reminder("{\"due_at\": \"friday\"}"), None);
+        assert_eq!(parse_due_at("1700000000"), Some(1_700_000_000));
+    }
+
+    #[actix::test]
+    async fn test_reminders_are_read_and_fired_once() {
+        let service =
+            service("{\"text\": \"Call Ana\", \"due_at\": \"2023-11-17T17:00:00+02:00\"}");
+        let username = format!("test_reminders_{}", uuid::Uuid::new_v4());
+        let mut events = service.event_hub.subscribe(&username);
+        let request = ReminderRequest {
+            text: "remind me Friday at 5 to call Ana".to_string(),
+            due_at: None,
+        };
+        let reminder = service
+            .create(&username, request, 1_700_000_000)
+            .await
+            .unwrap();
+        assert_eq!(reminder.text, "Call Ana");
+        assert_eq!(reminder.due_at, 1_700_233_200);
+
+        let past = ReminderRequest {
+            text: "Water the plants".to_string(),
+            due_at: Some("1699999999".to_string()),
+        };
+        let created = service.create(&username, past, 1_700_000_000).await;
+        assert!(matches!(created, Err(ReminderError::Past)));
+
+        service.fire_due(1_700_233_199).await.unwrap();
+        assert!(events.try_recv().is_err());
+        assert!(service.fire_due(1_700_233_200).await.unwrap() >= 1);
+        match events.try_recv().unwrap() {
+            HubEvent::ReminderDue { id, text, .. } => {
+                assert_eq!(id, reminder.id);
+                assert_eq!(text, "Call Ana");
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        let reminders = service
+            .reminder_repo
+            .lock()
+            .await
+            .get_reminders(&username)
+            .unwrap();
+        assert_eq!(reminders[0].fired_at, Some(1_700_233_200));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[actix::test]
+    async fn test_texts_without_a_time_are_refused() {
+        let service = service("{}");
+        let request = ReminderRequest {
+            text: "remind me to call Ana".to_string(),
+            due_at: None,
+        };
+        let created = service.create("test_reminders_no_time", request, 0).await;
+        assert!(matches!(created, Err(ReminderError::NoTime)));
+    }
+}
@@ -0,0 +1,407 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::NaiveDate;
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+
+use crate::repos::{
+    attributes::{AttributeModel, AttributeRepo},
+    messages::MessageRepo,
+    replication::Replica,
+    RepoError,
+};
+
+// Longest wait for writes still on their way to the replica
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A difference between the primary stores and the replica
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub user: String,
+    /// Day whose messages differ, `None` when the attributes do
+    pub date: Option<NaiveDate>,
+    pub detail: String,
+}
+
+/// What comparing the primary stores with the replica found
+#[derive(Debug, Default, PartialEq)]
+pub struct ReconciliationReport {
+    pub users: usize,
+    /// Days compared, of the primary and the replica together
+    pub days: usize,
+    pub diverged: Vec<Divergence>,
+}
+
+/// Compares what the primary stores hold with their replica
+pub struct ReplicationService {
+    pub message_repo: Arc<Mutex<dyn MessageRepo>>,
+    pub attribute_repo: Arc<Mutex<dyn AttributeRepo>>,
+    pub replica: Replica,
+}
+
+// Hashes of the day's messages, with whether each one was forgotten
+async fn day_of(
+    repo: &Arc<Mutex<dyn MessageRepo>>,
+    user: &str,
+    date: NaiveDate,
+) -> Result<BTreeSet<(String, bool)>, RepoError> {
+    Ok(repo
+        .lock()
+        .await
+        .get_all_for_user_on_day(user.to_string(), date)
+        .await?
+        .into_iter()
+        .map(|chat| (chat.hash, chat.forgotten))
+        .collect())
+}
+
+async fn attributes_of(
+    repo: &Arc<Mutex<dyn AttributeRepo>>,
+    user: &str,
+) -> Result<HashMap<String, AttributeModel>, ()> {
+    Ok(repo
+        .lock()
+        .await
+        .get_all_attributes(user)
+        .await?
+        .into_iter()
+        .map(|attribute| (attribute.attribute.clone(), attribute))
+        .collect())
+}
+
+// Names of the attributes whose values differ between the two sides
+fn differing(
+    primary: &HashMap<String, AttributeModel>,
+    replica: &HashMap<String, AttributeModel>,
+) -> Vec<String> {
+    let value = |attributes: &HashMap<String, AttributeModel>, name: &String| {
+        attributes
+            .get(name)
+            .map(|attribute| attribute.value.clone())
+    };
+    let mut differing = primary
+        .keys()
+        .chain(replica.keys())
+        .filter(|name| value(primary, name) != value(replica, name))
+        .cloned()
+        .collect::<Vec<String>>();
+    differing.sort();
+    differing.dedup();
+    differing
+}
+
+impl ReplicationService {
+    /// Compares every user of either side, after giving the replica time to
+    /// apply the writes still on their way to it
+    pub async fn reconcile(&self) -> Result<ReconciliationReport, ()> {
+        if !self.replica.replicator.flush(FLUSH_TIMEOUT).await {
+            warn!(
+                "Reconciling with {} writes not yet replicated",
+                self.replica.replicator.pending()
+            );
+        }
+        let mut users = BTreeSet::new();
+        for repo in [&self.message_repo, &self.replica.message_repo] {
+            let listed = repo.lock().await.get_users().await.map_err(|e| {
+                error!("Error listing users to reconcile: {}", e);
+            })?;
+            users.extend(listed);
+        }
+        let mut report = ReconciliationReport::default();
+        for user in users {
+            let reconciled = self.reconcile_user(&user).await?;
+            report.users += 1;
+            report.days += reconciled.days;
+            report.diverged.extend(reconciled.diverged);
+        }
+        Ok(report)
+    }
+
+    /// Compares the user's messages day by day, and their attributes
+    pub async fn reconcile_user(&self, user: &str) -> Result<ReconciliationReport, ()> {
+        let mut days = BTreeSet::new();
+        for repo in [&self.message_repo, &self.replica.message_repo] {
+            days.extend(repo.lock().await.get_days(user.to_string()).await?);
+        }
+        let mut report = ReconciliationReport {
+            users: 1,
+            days: days.len(),
+            diverged: vec![],
+        };
+        for date in days {
+            let primary = day_of(&self.message_repo, user, date).await?;
+            let replica = day_of(&self.replica.message_repo, user, date).await?;
+            if primary != replica {
+                report.diverged.push(Divergence {
+                    user: user.to_string(),
+                    date: Some(date),
+                    detail: format!(
+                        "{} messages differ in the primary, {} in the replica",
+                        primary.difference(&replica).count(),
+                        replica.difference(&primary).count()
+                    ),
+                });
+            }
+        }
+
+        let primary = attributes_of(&self.attribute_repo, user).await?;
+        let replica = attributes_of(&self.replica.attribute_repo, user).await?;
+        let differing = differing(&primary, &replica);
+        if !differing.is_empty() {
+            report.diverged.push(Divergence {
+                user: user.to_string(),
+                date: None,
+                detail: format!("attributes differ: {}", differing.join(", ")),
+            });
+        }
+        Ok(report)
+    }
+
+    /// Copies what the primary stores hold over the replica wherever they
+    /// diverged, the primary being the source of truth. This is also how a
+    /// replica added to an existing install gets the history written before.
+    /// Returns how many divergences were repaired.
+    pub async fn repair(&self, diverged: &[Divergence]) -> Result<usize, ()> {
+        let mut repaired = 0;
+        for divergence in diverged {
+            let user = divergence.user.clone();
+            match divergence.date {
+                Some(date) => {
+                    let chats = self
+                        .message_repo
+                        .lock()
+                        .await
+                        .get_all_for_user_on_day(user.clone(), date)
+                        .await?;
+                    let mut replica = self.replica.message_repo.lock().await;
+                    replica.delete_day(user.clone(), date).await?;
+                    if !chats.is_empty() {
+                        replica.save_chats(date, user, chats).await?;
+                    }
+                }
+                None => {
+                    let primary = attributes_of(&self.attribute_repo, &user).await?;
+                    let replica = attributes_of(&self.replica.attribute_repo, &user).await?;
+                    let mut repo = self.replica.attribute_repo.lock().await;
+                    for name in differing(&primary, &replica) {
+                        match primary.get(&name) {
+                            Some(attribute) => {
+                                repo.save_attribute(
+                                    &user,
+                                    &name,
+                                    &attribute.value,
+                                    attribute.expires_at,
+                                    attribute.source,
+                                )
+                                .await?;
+                            }
+                            None => repo.delete_attribute(&user, &name).await?,
+                        }
+                    }
+                }
+            }
+            repaired += 1;
+        }
+        Ok(repaired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clients::embeddings::Similarity,
+        repos::{
+            attributes::FsAttributeRepo,
+            messages::{ChatModel, FsMessageRepo},
+            replication::{ReplicatedMessageRepo, Replicator},
+        },
+    };
+
+    // Keeps messages in memory under the day they were saved to
+    #[derive(Default)]
+    struct MemoryMessageRepo {
+        chats: Vec<(String, NaiveDate, ChatModel)>,
+    }
+
+    #[async_trait::async_trait]
+    impl MessageRepo for MemoryMessageRepo {
+        async fn save_chat(
+            &mut self,
+            date: NaiveDate,
+            user: String,
+            chat: ChatModel,
+        ) -> Result<ChatModel, RepoError> {
+            self.chats.push((user, date, chat.clone()));
+            Ok(chat)
+        }
+
+        async fn get_chat(&mut self, user: String, id: String) -> Result<ChatModel, RepoError> {
+            self.get_all_for_user(user)
+                .await?
+                .into_iter()
+                .find(|chat| chat.hash == id)
+                .ok_or(RepoError::NotFound)
+        }
+
+        async fn embeddings_search_for_user(
+            &self,
+            _: String,
+            _: Vec<f32>,
+            _: &str,
+            _: Similarity,
+        ) -> Result<Vec<(f32, ChatModel)>, RepoError> {
+            Ok(vec![])
+        }
+
+        async fn get_all_for_user(&self, user: String) -> Result<Vec<ChatModel>, RepoError> {
+            Ok(self
+                .chats
+                .iter()
+                .filter(|(owner, _, _)| *owner == user)
+                .map(|(_, _, chat)| chat.clone())
+                .collect())
+        }
+
+        async fn get_all_for_user_on_day(
+            &self,
+            user: String,
+            date: NaiveDate,
+        ) -> Result<Vec<ChatModel>, RepoError> {
+            Ok(self
+                .chats
+                .iter()
+                .filter(|(owner, day, _)| *owner == user && *day == date)
+                .map(|(_, _, chat)| chat.clone())
+                .collect())
+        }
+
+        async fn get_for_dates(
+            &self,
+            _: String,
+            _: Vec<NaiveDate>,
+        ) -> Result<Vec<ChatModel>, RepoError> {
+            Ok(vec![])
+        }
+
+        async fn get_users(&self) -> Result<Vec<String>, RepoError> {
+            Ok(self.chats.iter().map(|(user, _, _)| user.clone()).collect())
+        }
+
+        async fn get_collections(&self, _: String) -> Result<Vec<String>, RepoError> {
+            Ok(vec![])
+        }
+
+        async fn redact_chat(&mut self, _: String, id: String) -> Result<ChatModel, RepoError> {
+            let (_, _, chat) = self
+                .chats
+                .iter_mut()
+                .find(|(_, _, chat)| chat.hash == id)
+                .ok_or(RepoError::NotFound)?;
+            chat.content = "".to_string();
+            chat.forgotten = true;
+            Ok(chat.clone())
+        }
+
+        async fn delete_chat(&mut self, _: String, id: String) -> Result<(), RepoError> {
+            self.chats.retain(|(_, _, chat)| chat.hash != id);
+            Ok(())
+        }
+
+        async fn get_days(&self, user: String) -> Result<Vec<NaiveDate>, RepoError> {
+            let mut days = self
+                .chats
+                .iter()
+                .filter(|(owner, _, _)| *owner == user)
+                .map(|(_, day, _)| *day)
+                .collect::<Vec<NaiveDate>>();
+            days.sort();
+            days.dedup();
+            Ok(days)
+        }
+
+        async fn replace_embeddings(
+            &mut self,
+            _: String,
+            _: HashMap<String, Vec<f32>>,
+            _: &str,
+        ) -> Result<usize, RepoError> {
+            Ok(0)
+        }
+    }
+
+    #[actix::test]
+    async fn test_writes_reach_the_replica_and_divergence_is_reported() {
+        let username = format!("test_replication_{}", uuid::Uuid::new_v4());
+        let date = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        let primary = Arc::new(Mutex::new(FsMessageRepo::new()));
+        let attributes = Arc::new(Mutex::new(FsAttributeRepo::new()));
+        let replica = Replica {
+            message_repo: Arc::new(Mutex::new(MemoryMessageRepo::default())),
+            attribute_repo: attributes.clone(),
+            replicator: Arc::new(Replicator::start()),
+        };
+        let mut replicated = ReplicatedMessageRepo::new(primary.clone(), replica.clone());
+        let service = ReplicationService {
+            message_repo: primary.clone(),
+            attribute_repo: attributes,
+            replica: replica.clone(),
+        };
+
+        for hash in ["a", "b"] {
+            replicated
+                .save_chat(date, username.clone(), ChatModel::test(hash, hash))
+                .await
+                .unwrap();
+        }
+        replicated
+            .redact_chat(username.clone(), "b".to_string())
+            .await
+            .unwrap();
+        assert!(replica.replicator.flush(FLUSH_TIMEOUT).await);
+        let report = service.reconcile_user(&username).await.unwrap();
+        assert_eq!((report.days, report.diverged), (1, vec![]));
+        let replicated_chat = replica
+            .message_repo
+            .lock()
+            .await
+            .get_chat(username.clone(), "b".to_string())
+            .await
+            .unwrap();
+        assert!(replicated_chat.forgotten);
+
+        // a write that only reached the primary
+        primary
+            .lock()
+            .await
+            .delete_chat(username.clone(), "a".to_string())
+            .await
+            .unwrap();
+        let report = service.reconcile_user(&username).await.unwrap();
+        assert_eq!(
+            report.diverged,
+            vec![Divergence {
+                user: username.clone(),
+                date: Some(date),
+                detail: "0 messages differ in the primary, 1 in the replica".to_string(),
+            }]
+        );
+
+        // the primary is copied over the replica, as for a replica that is
+        // new to an install with history
+        primary
+            .lock()
+            .await
+            .save_chat(date, username.clone(), ChatModel::test("c", "c"))
+            .await
+            .unwrap();
+        let report = service.reconcile_user(&username).await.unwrap();
+        assert_eq!(service.repair(&report.diverged).await, Ok(1));
+        let report = service.reconcile_user(&username).await.unwrap();
+        assert_eq!(report.diverged, vec![]);
+    }
+}
@@ -0,0 +1,278 @@
+use std::sync::Arc;
+
+use chrono::{Duration, NaiveDate};
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::{
+    config::RetentionPolicy,
+    repos::{collection_key, messages::MessageRepo, retention::RetentionRepo},
+    services::summary::SummaryService,
+};
+
+/// What enforcing retention removed
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct RetentionReport {
+    /// Days whose summary was stored before their messages were removed
+    pub summarized: usize,
+    /// Days whose messages were removed
+    pub days: usize,
+    pub messages: usize,
+}
+
+impl RetentionReport {
+    fn add(&mut self, other: RetentionReport) {
+        self.summarized += other.summarized;
+        self.days += other.days;
+        self.messages += other.messages;
+    }
+}
+
+/// Removes old messages as each user's retention policy says, keeping the
+/// summaries of the days that are summarized as their condensed memory
+pub struct RetentionService {
+    pub retention_repo: Arc<Mutex<dyn RetentionRepo>>,
+    pub message_repo: Arc<Mutex<dyn MessageRepo>>,
+    pub summary_service: SummaryService,
+    /// Policy of users without one of their own
+    pub default_policy: RetentionPolicy,
+}
+
+impl RetentionService {
+    /// The policy the user's messages are kept by
+    pub async fn policy(&self, username: &str) -> Result<RetentionPolicy, ()> {
+        let policy = self.retention_repo.lock().await.get_policy(username)?;
+        Ok(policy.unwrap_or(self.default_policy))
+    }
+
+    /// Enforces the policy of every user with messages
+    pub async fn enforce_all(&self, today: NaiveDate) -> Result<RetentionReport, ()> {
+        let users = self
+            .message_repo
+            .lock()
+            .await
+            .get_users()
+            .await
+            .map_err(|e| error!("Error listing users: {}", e))?;
+        let mut report = RetentionReport::default();
+        let mut result = Ok(());
+        for user in users {
+            match self.enforce(&user, today).await {
+                Ok(enforced) => report.add(enforced),
+                Err(_) => result = Err(()),
+            }
+        }
+        result.map(|_| report)
+    }
+
+    /// Removes the messages of the user's days that fell out of their
+    /// policy, in their own memory and in their collections. A day that is
+    /// to be summarized keeps its messages when the summary could not be
+    /// stored, so the next run tries again.
+    pub async fn enforce(&self, username: &str, today: NaiveDate) -> Result<RetentionReport, ()> {
+        let policy = self.policy(username).await?;
+        let mut report = RetentionReport::default();
+        let cutoff = match policy.days() {
+            Some(days) => today - Duration::days(days as i64),
+            None => return Ok(report),
+        };
+        let collections = self
+            .message_repo
+            .lock()
+            .await
+            .get_collections(username.to_string())
+            .await
+            .map_err(|e| error!("Error listing the collections of {}: {}", username, e))?;
+        let keys = std::iter::once(username.to_string())
+            .chain(collections.iter().map(|name| collection_key(username, name)));
+        let mut result = Ok(());
+        for key in keys {
+            match self.enforce_key(&key, policy, cutoff, today).await {
+                Ok(enforced) => report.add(enforced),
+                Err(_) => result = Err(()),
+            }
+        }
+        result.map(|_| report)
+    }
+
+    // Enforces `policy` on the days before `cutoff` of the messages stored
+    // under `username`, which is a user or one of their collections
+    async fn enforce_key(
+        &self,
+        username: &str,
+        policy: RetentionPolicy,
+        cutoff: NaiveDate,
+        today: NaiveDate,
+    ) -> Result<RetentionReport, ()> {
+        let mut report = RetentionReport::default();
+        let days = self
+            .message_repo
+            .lock()
+            .await
+            .get_days(username.to_string())
+            .await
+            .map_err(|e| error!("Error listing the days of {}: {}", username, e))?;
+        let mut result = Ok(());
+        for day in days.into_iter().filter(|day| *day < cutoff) {
+            if let RetentionPolicy::Summarize { .. } = policy {
+                match self
+                    .summary_service
+                    .get_summary(username, day, today, None)
+                    .await
+                {
+                    Ok(summary) if summary.message_count > 0 => report.summarized += 1,
+                    Ok(_) => {}
+                    Err(_) => {
+                        error!(
+                            "Keeping {} of {}, it could not be summarized",
+                            day, username
+                        );
+                        result = Err(());
+                        continue;
+                    }
+                }
+            }
+            match self
+                .message_repo
+                .lock()
+                .await
+                .delete_day(username.to_string(), day)
+                .await
+            {
+                Ok(deleted) => {
+                    info!("Removed {} messages of {} from {}", deleted, day, username);
+                    report.days += 1;
+                    report.messages += deleted;
+                }
+                Err(e) => {
+                    error!("Error removing {} of {}: {}", day, username, e);
+                    result = Err(());
+                }
+            }
+        }
+        result.map(|_| report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clients::{chat::MockChatClient, embeddings::MockEmbeddingsClient},
+        repos::{
+            messages::{ChatModel, FsMessageRepo},
+            retention::FsRetentionRepo,
+            summaries::{FsSummaryRepo, SummaryRepo},
+        },
+    };
+
+    #[actix::test]
+    async fn test_old_days_are_summarized_then_removed() {
+        let username = format!("test_retention_{}", uuid::Uuid::new_v4());
+        let message_repo = Arc::new(Mutex::new(FsMessageRepo::new()));
+        let summary_repo = Arc::new(Mutex::new(FsSummaryRepo::new()));
+        let service = RetentionService {
+            retention_repo: Arc::new(Mutex::new(FsRetentionRepo::new())),
+            message_repo: message_repo.clone(),
+            summary_service: SummaryService {
+                message_repo: message_repo.clone(),
+                chat_client: Arc::new(Mutex::new(MockChatClient::new())),
+                summary_repo: summary_repo.clone(),
+                embedding_client: Arc::new(Mutex::new(MockEmbeddingsClient::new())),
+            },
+            default_policy: RetentionPolicy::Forever,
+        };
+        let today = NaiveDate::from_ymd_opt(2024, 3, 31).unwrap();
+        let old = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        let recent = NaiveDate::from_ymd_opt(2024, 3, 30).unwrap();
+        for (date, hash) in [(old, "old"), (recent, "recent")] {
+            message_repo
+                .lock()
+                .await
+                .save_chat(date, username.clone(), ChatModel::test(hash, hash))
+                .await
+                .unwrap();
+        }
+
+        // kept forever by default
+        assert_eq!(
+            service.enforce(&username, today).await,
+            Ok(RetentionReport::default())
+        );
+
+        service
+            .retention_repo
+            .lock()
+            .await
+            .save_policy(&username, RetentionPolicy::Summarize { days: 7 })
+            .unwrap();
+        let report = service.enforce(&username, today).await.unwrap();
+        assert_eq!(
+            report,
+            RetentionReport {
+                summarized: 1,
+                days: 1,
+                messages: 1,
+            }
+        );
+        let days = message_repo
+            .lock()
+            .await
+            .get_days(username.clone())
+            .await
+            .unwrap();
+        assert_eq!(days, vec![recent]);
+        let summary = summary_repo.lock().await.get_summary(&username, old);
+        assert_eq!(summary.unwrap().message_count, 1);
+
+        // nothing is left to remove
+        assert_eq!(
+            service.enforce(&username, today).await,
+            Ok(RetentionReport::default())
+        );
+    }
+
+    #[actix::test]
+    async fn test_collections_follow_their_owners_policy() {
+        let username = format!("test_retention_{}", uuid::Uuid::new_v4());
+        let collection = collection_key(&username, "work");
+        let message_repo = Arc::new(Mutex::new(FsMessageRepo::new()));
+        let service = RetentionService {
+            retention_repo: Arc::new(Mutex::new(FsRetentionRepo::new())),
+            message_repo: message_repo.clone(),
+            summary_service: SummaryService {
+                message_repo: message_repo.clone(),
+                chat_client: Arc::new(Mutex::new(MockChatClient::new())),
+                summary_repo: Arc::new(Mutex::new(FsSummaryRepo::new())),
+                embedding_client: Arc::new(Mutex::new(MockEmbeddingsClient::new())),
+            },
+            default_policy: RetentionPolicy::Delete { days: 7 },
+        };
+        let today = NaiveDate::from_ymd_opt(2024, 3, 31).unwrap();
+        let old = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        let recent = NaiveDate::from_ymd_opt(2024, 3, 30).unwrap();
+        for (key, date, hash) in [
+            (&username, old, "old"),
+            (&collection, old, "old_work"),
+            (&collection, recent, "recent_work"),
+        ] {
+            message_repo
+                .lock()
+                .await
+                .save_chat(date, key.clone(), ChatModel::test(hash, hash))
+                .await
+                .unwrap();
+        }
+
+        let report = service.enforce(&username, today).await.unwrap();
+        assert_eq!((report.days, report.messages), (2, 2));
+        let days = message_repo
+            .lock()
+            .await
+            .get_days(collection.clone())
+            .await
+            .unwrap();
+        assert_eq!(days, vec![recent]);
+    }
+}
@@ -0,0 +1,191 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::{
+    clients::embeddings::EmbeddingsClient,
+    hub::{EventHub, HubEvent},
+    repos::{
+        messages::{cosine_similarity, ChatModel, MessageRepo},
+        saved_searches::{SavedSearchModel, SavedSearchRepo},
+    },
+};
+
+#[derive(Deserialize)]
+pub struct SavedSearchRequest {
+    pub name: String,
+    pub query: String,
+    #[serde(default = "default_threshold")]
+    pub threshold: f32,
+}
+
+fn default_threshold() -> f32 {
+    0.8
+}
+
+pub struct SavedSearchService {
+    pub embedding_client: Arc<Mutex<dyn EmbeddingsClient>>,
+    pub message_repo: Arc<Mutex<dyn MessageRepo>>,
+    pub saved_search_repo: Arc<Mutex<dyn SavedSearchRepo>>,
+    pub event_hub: Arc<EventHub>,
+}
+
+// Hashes of messages saved since the search last ran that match it, and of
+// those that cannot be compared yet since their embedding is still on its way
+fn find_new_matches(search: &SavedSearchModel, chats: &[ChatModel]) -> (Vec<String>, Vec<String>) {
+    let mut matches = vec![];
+    let mut unembedded = vec![];
+    for chat in chats
+        .iter()
+        .filter(|chat| {
+            chat.timestamp > search.last_checked || search.unembedded.contains(&chat.hash)
+        })
+        .filter(|chat| !chat.forgotten && !chat.low_value)
+    {
+        match &chat.embedding {
+            Some(embedding) => {
+                if embedding.len() == search.embedding.len()
+                    && cosine_similarity(embedding, &search.embedding) >= search.threshold
+                {
+                    matches.push(chat.hash.clone());
+                }
+            }
+            None => unembedded.push(chat.hash.clone()),
+        }
+    }
+    (matches, unembedded)
+}
+
+impl SavedSearchService {
+    pub async fn save_search(
+        &self,
+        username: &str,
+        request: SavedSearchRequest,
+    ) -> Result<SavedSearchModel, ()> {
+        let embedding = self
+            .embedding_client
+            .lock()
+            .await
+            .get_embeddings(request.query.clone())
+            .await
+            .map_err(|_| {
+                error!("Failed to get embeddings");
+            })?;
+
+        let now = chrono::Utc::now().timestamp();
+        let search = SavedSearchModel {
+            name: request.name,
+            query: request.query,
+            embedding,
+            threshold: request.threshold,
+            last_checked: now,
+            unembedded: vec![],
+            created_at: now,
+        };
+        self.saved_search_repo
+            .lock()
+            .await
+            .save_search(username, search)
+    }
+
+    /// Re-runs every saved search against messages that arrived since its
+    /// last run, publishing an event for each search with new matches, and
+    /// returns how many searches matched
+    pub async fn check_saved_searches(&self, now: i64) -> Result<usize, ()> {
+        let users = self.message_repo.lock().await.get_users().await?;
+        let mut matched = 0;
+
+        for user in users {
+            let searches = self.saved_search_repo.lock().await.get_searches(&user)?;
+            if searches.is_empty() {
+                continue;
+            }
+            let chats = self
+                .message_repo
+                .lock()
+                .await
+                .get_all_for_user(user.clone())
+                .await?;
+
+            for search in searches {
+                let (hashes, unembedded) = find_new_matches(&search, &chats);
+                if !hashes.is_empty() {
+                    info!(
+                        "Saved search {} matched {} new messages for {}",
+                        search.name,
+                        hashes.len(),
+                        user
+                    );
+                    self.event_hub.publish(HubEvent::SearchMatched {
+                        username: user.clone(),
+                        search: search.name.clone(),
+                        hashes,
+                    });
+                    matched += 1;
+                }
+
+                let checked = SavedSearchModel {
+                    last_checked: now,
+                    unembedded,
+                    ..search
+                };
+                if self
+                    .saved_search_repo
+                    .lock()
+                    .await
+                    .save_search(&user, checked)
+                    .is_err()
+                {
+                    error!("Error updating saved search for {}", user);
+                }
+            }
+        }
+        Ok(matched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_new_similar_messages_match() {
+        let search = SavedSearchModel {
+            name: "apartment".to_string(),
+            query: "apartment viewing".to_string(),
+            embedding: vec![1.0, 0.0],
+            threshold: 0.8,
+            last_checked: 100,
+            unembedded: vec![],
+            created_at: 100,
+        };
+        let chat = |hash: &str, embedding: Option<Vec<f32>>, timestamp: i64| ChatModel {
+            embedding,
+            timestamp,
+            ..ChatModel::test(hash, "content")
+        };
+        let mut chats = vec![
+            chat("old", Some(vec![1.0, 0.0]), 50),
+            chat("new", Some(vec![0.9, 0.1]), 150),
+            chat("unrelated", Some(vec![0.0, 1.0]), 150),
+            chat("pending", None, 150),
+        ];
+
+        let (matches, unembedded) = find_new_matches(&search, &chats);
+        assert_eq!(matches, vec!["new".to_string()]);
+        assert_eq!(unembedded, vec!["pending".to_string()]);
+
+        // once its embedding arrived it is matched on the next run
+        let search = SavedSearchModel {
+            last_checked: 200,
+            unembedded,
+            ..search
+        };
+        chats[3].embedding = Some(vec![1.0, 0.0]);
+        let (matches, unembedded) = find_new_matches(&search, &chats);
+        assert_eq!(matches, vec!["pending".to_string()]);
+        assert!(unembedded.is_empty());
+    }
+}
@@ -0,0 +1,222 @@
+use std::sync::Arc;
+
+use serde_json::Value;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::{
+    config::MqttSubscription,
+    hub::{EventHub, HubEvent},
+    repos::attributes::{AttributeModel, AttributeRepo, AttributeSource},
+};
+
+// Longest value that is kept, longer payloads are rather documents than
+// readings
+const MAX_VALUE_CHARS: usize = 1024;
+
+/// The levels of `topic` matched by the wildcards of `filter`, in order,
+/// `None` when the topic does not match. `#` matches the rest of the topic
+/// as one level.
+pub fn topic_levels<'a>(filter: &str, topic: &'a str) -> Option<Vec<&'a str>> {
+    // wildcards never match the broker's own topics
+    if topic.starts_with('$') && !filter.starts_with('$') {
+        return None;
+    }
+    let mut levels = vec![];
+    let mut rest = Some(topic);
+    for part in filter.split('/') {
+        if part == "#" {
+            levels.push(rest.unwrap_or_default());
+            return Some(levels);
+        }
+        let (level, remaining) = match rest?.split_once('/') {
+            Some((level, remaining)) => (level, Some(remaining)),
+            None => (rest?, None),
+        };
+        match part {
+            "+" => levels.push(level),
+            _ if part == level => {}
+            _ => return None,
+        }
+        rest = remaining;
+    }
+    match rest {
+        None => Some(levels),
+        Some(_) => None,
+    }
+}
+
+// `template` with `{1}`, `{2}` and so on replaced by the matched levels
+fn fill(template: &str, levels: &[&str]) -> String {
+    let mut filled = template.to_string();
+    for (index, level) in levels.iter().enumerate() {
+        filled = filled.replace(&format!("{{{}}}", index + 1), level);
+    }
+    filled
+}
+
+/// The value a message holds in `format`, `None` for messages that hold no
+/// reading, such as OwnTracks waypoints
+pub fn read_payload(format: &str, payload: &[u8]) -> Option<String> {
+    let text = std::str::from_utf8(payload).ok()?.trim();
+    let value = match format {
+        // `lat,lon` of location messages
+        "owntracks" => {
+            let message: Value = serde_json::from_str(text).ok()?;
+            if message["_type"] != "location" {
+                return None;
+            }
+            format!("{},{}", message["lat"].as_f64()?, message["lon"].as_f64()?)
+        }
+        // plain values, or JSON holding a value or a `state`
+        _ => match serde_json::from_str::<Value>(text) {
+            Ok(Value::String(value)) => value,
+            Ok(Value::Object(object)) => match object.get("state")? {
+                Value::String(state) => state.clone(),
+                Value::Null => return None,
+                state => state.to_string(),
+            },
+            _ => text.to_string(),
+        },
+    };
+    match value.is_empty() || value.chars().count() > MAX_VALUE_CHARS {
+        true => None,
+        false => Some(value),
+    }
+}
+
+/// Keeps the readings devices report over MQTT as attributes of their users
+pub struct SensorService {
+    pub attribute_repo: Arc<Mutex<dyn AttributeRepo>>,
+    pub event_hub: Arc<EventHub>,
+    pub subscriptions: Vec<MqttSubscription>,
+}
+
+impl SensorService {
+    /// Saves the value of a message on `topic` for every subscription the
+    /// topic matches and returns the attributes that changed, with their
+    /// users. The attribute history records when each value arrived.
+    pub async fn receive(
+        &self,
+        topic: &str,
+        payload: &[u8],
+    ) -> Result<Vec<(String, AttributeModel)>, ()> {
+        let mut changed = vec![];
+        for subscription in &self.subscriptions {
+            let levels = match topic_levels(&subscription.topic, topic) {
+                Some(levels) => levels,
+                None => continue,
+            };
+            let value = match read_payload(&subscription.format, payload) {
+                Some(value) => value,
+                None => continue,
+            };
+            let username = fill(&subscription.username, &levels);
+            let attribute = fill(&subscription.attribute, &levels);
+            if !crate::username::is_valid(&username) || attribute.is_empty() {
+                warn!("Ignoring MQTT message on {} for user {}", topic, username);
+                continue;
+            }
+
+            let mut attribute_repo = self.attribute_repo.lock().await;
+            // devices repeat themselves, only changes are recorded
+            if let Ok(current) = attribute_repo.get_attribute(&username, &attribute).await {
+                if current.value == value {
+                    continue;
+                }
+            }
+            let saved = attribute_repo
+                .save_attribute(&username, &attribute, &value, None, AttributeSource::Sensor)
+                .await?;
+            drop(attribute_repo);
+            info!("Set {} of {} from {}", attribute, username, topic);
+            self.event_hub.publish(HubEvent::AttributeChanged {
+                username: username.clone(),
+                attribute,
+            });
+            changed.push((username, saved));
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repos::attributes::FsAttributeRepo;
+
+    #[test]
+    fn test_topics_match_their_filters() {
+        assert_eq!(topic_levels("a/b", "a/b"), Some(vec![]));
+        assert_eq!(topic_levels("a/+/c", "a/b/c"), Some(vec!["b"]));
+        assert_eq!(topic_levels("a/#", "a/b/c"), Some(vec!["b/c"]));
+        assert_eq!(topic_levels("a/#", "a"), Some(vec![""]));
+        assert_eq!(topic_levels("+/+", "a/b/c"), None);
+        assert_eq!(topic_levels("a/+/c", "a/b"), None);
+        assert_eq!(topic_levels("#", "$SYS/uptime"), None);
+
+        assert_eq!(read_payload("value", b" 21.5\n").as_deref(), Some("21.5"));
+        assert_eq!(read_payload("value", b"\"on\"").as_deref(), Some("on"));
+        assert_eq!(
+            read_payload("value", br#"{"state": 3, "unit": "W"}"#).as_deref(),
+            Some("3")
+        );
+        assert_eq!(read_payload("value", b""), None);
+        let waypoint = br#"{"_type": "waypoint", "lat": 1.0, "lon": 2.0}"#;
+        assert_eq!(read_payload("owntracks", waypoint), None);
+    }
+
+    #[actix::test]
+    async fn test_readings_become_attributes() {
+        let username = format!("test_sensors_{}", uuid::Uuid::new_v4().simple());
+        let service = SensorService {
+            attribute_repo: Arc::new(Mutex::new(FsAttributeRepo::new())),
+            event_hub: Arc::new(EventHub::new(16)),
+            subscriptions: vec![
+                MqttSubscription {
+                    topic: "owntracks/+/phone".to_string(),
+                    username: "{1}".to_string(),
+                    attribute: "location".to_string(),
+                    format: "owntracks".to_string(),
+                },
+                MqttSubscription {
+                    topic: "homeassistant/sensor/+/state".to_string(),
+                    username: username.clone(),
+                    attribute: "sensor.{1}".to_string(),
+                    format: "value".to_string(),
+                },
+            ],
+        };
+
+        let location = br#"{"_type": "location", "lat": 52.52, "lon": 13.405, "tst": 1700000000}"#;
+        let topic = format!("owntracks/{}/phone", username);
+        let changed = service.receive(&topic, location).await.unwrap();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].0, username);
+        assert_eq!(changed[0].1.value, "52.52,13.405");
+        assert_eq!(changed[0].1.source, AttributeSource::Sensor);
+        // the same reading again changes nothing
+        assert!(service.receive(&topic, location).await.unwrap().is_empty());
+
+        let changed = service
+            .receive("homeassistant/sensor/living_room/state", b"21.5")
+            .await
+            .unwrap();
+        assert_eq!(changed[0].1.attribute, "sensor.living_room");
+        let saved = service
+            .attribute_repo
+            .lock()
+            .await
+            .get_attribute(&username, "sensor.living_room")
+            .await
+            .unwrap();
+        assert_eq!(saved.value, "21.5");
+
+        // levels that are not plain usernames are not used as one
+        let changed = service
+            .receive("owntracks/../phone", location)
+            .await
+            .unwrap();
+        assert!(changed.is_empty());
+    }
+}
@@ -1,46 +1,513 @@
-use chrono::NaiveDate;
+use chrono::{Duration, NaiveDate, Weekday};
+use serde::Deserialize;
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use tracing::{error, info, warn};
 
-use crate::repos::messages::MessageRepo;
+use crate::{
+    clients::{
+        chat::{ChatClient, ChatError, Message},
+        embeddings::EmbeddingsClient,
+    },
+    repos::{
+        messages::{ChatModel, MessageRepo},
+        summaries::{SummaryModel, SummaryRepo},
+    },
+};
+
+/// A stretch of days that daily summaries are rolled up over
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Period {
+    /// ISO year and week number
+    Week(i32, u32),
+    /// Year and month
+    Month(i32, u32),
+}
+
+impl Period {
+    /// Parses an ISO week such as `2024-W09`
+    pub fn parse_week(value: &str) -> Option<Period> {
+        let (year, week) = value.split_once("-W")?;
+        let (year, week) = (year.parse().ok()?, week.parse().ok()?);
+        NaiveDate::from_isoywd_opt(year, week, Weekday::Mon)?;
+        Some(Period::Week(year, week))
+    }
+
+    /// Parses a month such as `2024-03`
+    pub fn parse_month(value: &str) -> Option<Period> {
+        let (year, month) = value.split_once('-')?;
+        let (year, month) = (year.parse().ok()?, month.parse().ok()?);
+        NaiveDate::from_ymd_opt(year, month, 1)?;
+        Some(Period::Month(year, month))
+    }
+
+    pub fn key(&self) -> String {
+        match self {
+            Period::Week(year, week) => format!("{}-W{:02}", year, week),
+            Period::Month(year, month) => format!("{}-{:02}", year, month),
+        }
+    }
+
+    pub fn days(&self) -> Vec<NaiveDate> {
+        let (first, count) = match *self {
+            Period::Week(year, week) => (NaiveDate::from_isoywd_opt(year, week, Weekday::Mon), 7),
+            Period::Month(year, month) => {
+                let first = NaiveDate::from_ymd_opt(year, month, 1);
+                let next = match month {
+                    12 => NaiveDate::from_ymd_opt(year + 1, 1, 1),
+                    month => NaiveDate::from_ymd_opt(year, month + 1, 1),
+                };
+                let count = match (first, next) {
+                    (Some(first), Some(next)) => (next - first).num_days(),
+                    _ => 0,
+                };
+                (first, count)
+            }
+        };
+        match first {
+            Some(first) => (0..count).map(|day| first + Duration::days(day)).collect(),
+            None => vec![],
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SummaryParams {
+    /// Model writing a summary that is not stored yet, from
+    /// `models.allowed_chat_models`
+    pub model: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SummaryRangeQuery {
+    /// First day to include, as `YYYY-MM-DD`
+    pub from_date: Option<String>,
+    /// Last day to include, as `YYYY-MM-DD`
+    pub to_date: Option<String>,
+}
+
+impl SummaryRangeQuery {
+    /// `None` when one of the dates is not a valid `YYYY-MM-DD`
+    pub fn dates(&self) -> Option<(NaiveDate, NaiveDate)> {
+        let parse = |date: &Option<String>, open: NaiveDate| match date {
+            Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d").ok(),
+            None => Some(open),
+        };
+        Some((
+            parse(&self.from_date, NaiveDate::MIN)?,
+            parse(&self.to_date, NaiveDate::MAX)?,
+        ))
+    }
+}
+
+// Raised whenever a prompt changes, so summaries written with an older one
+// can be told apart
+const DAY_PROMPT_VERSION: u32 = 1;
+const ROLLUP_PROMPT_VERSION: u32 = 1;
 
 pub struct SummaryService {
     pub message_repo: Arc<Mutex<dyn MessageRepo>>,
-    pub embedding_client: Arc<Mutex<dyn crate::clients::embeddings::EmbeddingsClient>>,
+    pub chat_client: Arc<Mutex<dyn ChatClient>>,
+    pub summary_repo: Arc<Mutex<dyn SummaryRepo>>,
+    pub embedding_client: Arc<Mutex<dyn EmbeddingsClient>>,
 }
 
 impl SummaryService {
-    pub async fn summarize_chats_for_user_for_date(
+    /// Returns the stored summary of `date`, writing one when there is none.
+    /// Summaries of days before `today` are stored, today's may still grow.
+    pub async fn get_summary(
         &self,
-        user: String,
-        date_str: String,
-    ) -> Result<Vec<String>, ()> {
-        let date = match NaiveDate::parse_from_str(&date_str, "%Y-%m-%d") {
-            Ok(date) => date,
-            Err(_) => {
-                return Err(());
+        user: &str,
+        date: NaiveDate,
+        today: NaiveDate,
+        model: Option<&str>,
+    ) -> Result<SummaryModel, ()> {
+        if let Ok(summary) = self.summary_repo.lock().await.get_summary(user, date) {
+            return Ok(summary);
+        }
+
+        let messages = self.messages_on(user, date).await?;
+        let summary = match messages.is_empty() {
+            true => SummaryModel {
+                date: date.format("%Y-%m-%d").to_string(),
+                summary: "No messages on this day.".to_string(),
+                message_count: 0,
+                created_at: chrono::Utc::now().timestamp(),
+                model: None,
+                prompt_version: None,
+                embedding: None,
+                embedding_model: None,
+            },
+            false => summarize_day(&self.chat_client, date, &messages, model)
+                .await
+                .map_err(|e| error!("Error summarizing {} for {}: {}", date, user, e))?,
+        };
+        if date >= today || messages.is_empty() {
+            return Ok(summary);
+        }
+        let summary = self.embed(summary).await;
+        self.summary_repo.lock().await.save_summary(user, summary)
+    }
+
+    /// Writes the missing summaries of `date` for every user that has
+    /// messages on it, returning how many were written
+    pub async fn summarize_users_for_date(&self, date: NaiveDate) -> Result<usize, ()> {
+        let users = self.message_repo.lock().await.get_users().await?;
+        let mut written = 0;
+
+        for user in users {
+            if self.embed_missing(&user).await.is_err() {
+                error!("Error embedding the summaries of {}", user);
+            }
+            if self
+                .summary_repo
+                .lock()
+                .await
+                .get_summary(&user, date)
+                .is_ok()
+            {
+                continue;
+            }
+            let messages = match self.messages_on(&user, date).await {
+                Ok(messages) => messages,
+                Err(_) => {
+                    error!("Error loading messages for {}", user);
+                    continue;
+                }
+            };
+            if messages.is_empty() {
+                continue;
+            }
+
+            let summary = match summarize_day(&self.chat_client, date, &messages, None).await {
+                Ok(summary) => summary,
+                Err(e) => {
+                    error!("Error summarizing {} for {}: {}", date, user, e);
+                    continue;
+                }
+            };
+            let summary = self.embed(summary).await;
+            match self.summary_repo.lock().await.save_summary(&user, summary) {
+                Ok(summary) => {
+                    info!("Summarized {} for {}", summary.date, user);
+                    written += 1;
+                }
+                Err(_) => error!("Error saving summary of {} for {}", date, user),
             }
+        }
+        Ok(written)
+    }
+
+    /// Rolls the stored daily summaries of `period` up into one. The rollup
+    /// is stored once the period is over and every day in it that has
+    /// messages has been summarized, until then it is written on each call.
+    pub async fn get_rollup(
+        &self,
+        user: &str,
+        period: Period,
+        today: NaiveDate,
+        model: Option<&str>,
+    ) -> Result<SummaryModel, ()> {
+        let key = period.key();
+        if let Ok(rollup) = self.summary_repo.lock().await.get_rollup(user, &key) {
+            return Ok(rollup);
+        }
+
+        let mut summaries = vec![];
+        let mut complete = true;
+        for day in period.days() {
+            match self.summary_repo.lock().await.get_summary(user, day) {
+                Ok(summary) => summaries.push(summary),
+                Err(_) => {
+                    complete =
+                        complete && day < today && self.messages_on(user, day).await?.is_empty()
+                }
+            }
+        }
+
+        let rollup = match summaries.is_empty() {
+            true => SummaryModel {
+                date: key,
+                summary: "No summaries for this period.".to_string(),
+                message_count: 0,
+                created_at: chrono::Utc::now().timestamp(),
+                model: None,
+                prompt_version: None,
+                embedding: None,
+                embedding_model: None,
+            },
+            false => roll_up(&self.chat_client, key, &summaries, model)
+                .await
+                .map_err(|e| error!("Error rolling up summaries for {}: {}", user, e))?,
         };
+        if !complete || summaries.is_empty() {
+            return Ok(rollup);
+        }
+        info!("Rolled up {} for {}", rollup.date, user);
+        let rollup = self.embed(rollup).await;
+        self.summary_repo.lock().await.save_summary(user, rollup)
+    }
 
-        let messages = self
-            .message_repo
+    /// The stored daily summaries from `from` to `to`, none are written
+    pub async fn list_summaries(
+        &self,
+        user: &str,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<SummaryModel>, ()> {
+        self.summary_repo
             .lock()
             .await
-            .get_all_for_user_on_day(user.clone(), date);
-
-        // convert to message type
+            .list_summaries(user, from, to)
+    }
 
-        match messages {
-            Ok(messages) => {
-                let mut summaries = vec![];
-                for message in messages {
-                    summaries.push(message.content.clone());
-                }
-                return Ok(summaries);
+    /// Embeds the stored summaries of `user` that have no embedding from the
+    /// current model, such as ones written before summaries were searched,
+    /// returning how many were embedded
+    pub async fn embed_missing(&self, user: &str) -> Result<usize, ()> {
+        let model = self.embedding_client.lock().await.model();
+        let missing = self
+            .summary_repo
+            .lock()
+            .await
+            .get_summaries(user)?
+            .into_iter()
+            .filter(|summary| summary.embedding_model.as_ref() != Some(&model))
+            .collect::<Vec<SummaryModel>>();
+        let mut embedded = 0;
+        for summary in missing {
+            let summary = self.embed(summary).await;
+            if summary.embedding.is_none() {
+                continue;
             }
+            self.summary_repo.lock().await.save_summary(user, summary)?;
+            embedded += 1;
+        }
+        if embedded > 0 {
+            info!("Embedded {} summaries of {}", embedded, user);
+        }
+        Ok(embedded)
+    }
+
+    // A summary that cannot be embedded is still kept, it is embedded again
+    // by `embed_missing`
+    async fn embed(&self, summary: SummaryModel) -> SummaryModel {
+        let embedding_client = self.embedding_client.lock().await;
+        match embedding_client
+            .get_embeddings(summary.summary.clone())
+            .await
+        {
+            Ok(embedding) => SummaryModel {
+                embedding: Some(embedding_client.similarity().prepare(embedding)),
+                embedding_model: Some(embedding_client.model()),
+                ..summary
+            },
             Err(_) => {
-                return Err(());
+                warn!("Error embedding summary of {}", summary.date);
+                summary
             }
         }
     }
+
+    async fn messages_on(&self, user: &str, date: NaiveDate) -> Result<Vec<ChatModel>, ()> {
+        Ok(self
+            .message_repo
+            .lock()
+            .await
+            .get_all_for_user_on_day(user.to_string(), date)
+            .await?
+            .into_iter()
+            .filter(|chat| !chat.forgotten && !chat.content.is_empty())
+            .collect())
+    }
+}
+
+async fn roll_up(
+    chat_client: &Mutex<dyn ChatClient>,
+    period: String,
+    summaries: &[SummaryModel],
+    model: Option<&str>,
+) -> Result<SummaryModel, ChatError> {
+    let system_prompt = "Below are summaries of consecutive days in the user's life. Combine them into one summary of the whole period: recurring themes first, then notable events and decisions. Keep names and facts exact and do not invent anything that is not in the summaries.";
+    let days = summaries
+        .iter()
+        .map(|summary| format!("{}: {}", summary.date, summary.summary))
+        .collect::<Vec<String>>()
+        .join("\n\n");
+    let context = vec![
+        Message {
+            role: "system".to_string(),
+            content: system_prompt.to_string(),
+        },
+        Message {
+            role: "user".to_string(),
+            content: days,
+        },
+    ];
+
+    let completion = chat_client
+        .lock()
+        .await
+        .complete_with(context, model)
+        .await?;
+    Ok(SummaryModel {
+        date: period,
+        summary: completion.content,
+        message_count: summaries.iter().map(|summary| summary.message_count).sum(),
+        created_at: chrono::Utc::now().timestamp(),
+        model: Some(completion.model),
+        prompt_version: Some(ROLLUP_PROMPT_VERSION),
+        embedding: None,
+        embedding_model: None,
+    })
+}
+
+async fn summarize_day(
+    chat_client: &Mutex<dyn ChatClient>,
+    date: NaiveDate,
+    messages: &[ChatModel],
+    model: Option<&str>,
+) -> Result<SummaryModel, ChatError> {
+    let system_prompt = "Summarize the user's day from the conversation below. Cover what they did, decided and worried about, keep names and facts exact, and do not invent anything that is not in the conversation.";
+    let transcript = messages
+        .iter()
+        .map(|chat| format!("{}: {}", chat.role, chat.content))
+        .collect::<Vec<String>>()
+        .join("\n");
+    let context = vec![
+        Message {
+            role: "system".to_string(),
+            content: system_prompt.to_string(),
+        },
+        Message {
+            role: "user".to_string(),
+            content: transcript,
+        },
+    ];
+
+    let completion = chat_client
+        .lock()
+        .await
+        .complete_with(context, model)
+        .await?;
+    Ok(SummaryModel {
+        date: date.format("%Y-%m-%d").to_string(),
+        summary: completion.content,
+        message_count: messages.len(),
+        created_at: chrono::Utc::now().timestamp(),
+        model: Some(completion.model),
+        prompt_version: Some(DAY_PROMPT_VERSION),
+        embedding: None,
+        embedding_model: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clients::{chat::MockChatClient, embeddings::MockEmbeddingsClient},
+        repos::{messages::FsMessageRepo, summaries::FsSummaryRepo},
+    };
+
+    #[tokio::test]
+    async fn test_stored_summaries_are_not_recomputed() {
+        let user = format!("test_summary_{}", uuid::Uuid::new_v4());
+        let service = SummaryService {
+            message_repo: Arc::new(Mutex::new(FsMessageRepo::new())),
+            chat_client: Arc::new(Mutex::new(MockChatClient::new())),
+            summary_repo: Arc::new(Mutex::new(FsSummaryRepo::new())),
+            embedding_client: Arc::new(Mutex::new(MockEmbeddingsClient::new())),
+        };
+        let today = NaiveDate::from_ymd_opt(2024, 3, 2).unwrap();
+        let yesterday = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+
+        let empty = service
+            .get_summary(&user, yesterday, today, None)
+            .await
+            .unwrap();
+        assert_eq!(empty.message_count, 0);
+        assert!(service
+            .summary_repo
+            .lock()
+            .await
+            .get_summary(&user, yesterday)
+            .is_err());
+
+        let stored = SummaryModel {
+            date: "2024-03-01".to_string(),
+            summary: "Went hiking".to_string(),
+            message_count: 3,
+            created_at: 0,
+            model: None,
+            prompt_version: None,
+            embedding: None,
+            embedding_model: None,
+        };
+        service
+            .summary_repo
+            .lock()
+            .await
+            .save_summary(&user, stored)
+            .unwrap();
+        let summary = service
+            .get_summary(&user, yesterday, today, None)
+            .await
+            .unwrap();
+        assert_eq!(summary.summary, "Went hiking");
+    }
+
+    #[tokio::test]
+    async fn test_summaries_without_embeddings_are_embedded() {
+        let user = format!("test_summary_embeddings_{}", uuid::Uuid::new_v4());
+        let service = SummaryService {
+            message_repo: Arc::new(Mutex::new(FsMessageRepo::new())),
+            chat_client: Arc::new(Mutex::new(MockChatClient::new())),
+            summary_repo: Arc::new(Mutex::new(FsSummaryRepo::new())),
+            embedding_client: Arc::new(Mutex::new(MockEmbeddingsClient::new())),
+        };
+        let stored = SummaryModel {
+            date: "2024-03-01".to_string(),
+            summary: "Went hiking".to_string(),
+            message_count: 3,
+            created_at: 0,
+            model: None,
+            prompt_version: None,
+            embedding: None,
+            embedding_model: None,
+        };
+        service
+            .summary_repo
+            .lock()
+            .await
+            .save_summary(&user, stored)
+            .unwrap();
+
+        assert_eq!(service.embed_missing(&user).await.unwrap(), 1);
+        assert_eq!(service.embed_missing(&user).await.unwrap(), 0);
+        let summaries = service
+            .summary_repo
+            .lock()
+            .await
+            .get_summaries(&user)
+            .unwrap();
+        assert_eq!(summaries[0].embedding_model.as_deref(), Some("mock"));
+        assert!(summaries[0].clone().without_embedding().embedding.is_none());
+    }
+
+    #[test]
+    fn test_periods_cover_their_days() {
+        let week = Period::parse_week("2024-W09").unwrap();
+        let days = week.days();
+        assert_eq!(days.len(), 7);
+        assert_eq!(days[0], NaiveDate::from_ymd_opt(2024, 2, 26).unwrap());
+        assert_eq!(week.key(), "2024-W09");
+
+        let february = Period::parse_month("2024-02").unwrap();
+        assert_eq!(february.days().len(), 29);
+        assert_eq!(Period::parse_month("2024-12").unwrap().days().len(), 31);
+
+        assert!(Period::parse_week("2024-W54").is_none());
+        assert!(Period::parse_month("2024-13").is_none());
+    }
 }
@@ -0,0 +1,516 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::NaiveDate;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
+
+use crate::{
+    clients::{
+        chat::{Message, Role},
+        telegram::{TelegramApi, TelegramMessage},
+    },
+    config::DedupMode,
+    hub::{EventHub, HubEvent},
+    services::{
+        chat::{ChatRequest, ChatService, CompleteError, CompleteRequest},
+        summary::SummaryService,
+    },
+};
+
+// Seconds a poll waits for updates before asking again
+const POLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+const HELP: &str = "Everything you write here is remembered.
+
+/ask <question> answers from your memories
+/summary [YYYY-MM-DD] sums up a day, today by default";
+
+const CHECK_IN_PROMPT: &str = "Below is a summary of what the user talked about yesterday. Pick one thing they mentioned that has likely moved on since, such as a plan, a worry or an appointment, and ask them how it went in one short, friendly sentence, such as \"Yesterday you mentioned the job interview, how did it go?\". Reply with only the question.";
+
+/// Remembers what is written in the chats it knows and answers questions
+/// about it
+pub struct TelegramBot {
+    pub api: Arc<dyn TelegramApi>,
+    /// Muninn user of every chat id the bot listens to
+    pub chats: HashMap<i64, String>,
+    pub chat_service: ChatService,
+    pub summary_service: SummaryService,
+    pub event_hub: Arc<EventHub>,
+    pub max_content_length: usize,
+    /// Hash of the check-in question each chat was asked and has not
+    /// answered yet
+    pub check_ins: Mutex<HashMap<i64, String>>,
+}
+
+/// Chat ids of the configured `chats`, leaving out the ones that are not
+/// numbers
+pub fn parse_chats(chats: &HashMap<String, String>) -> HashMap<i64, String> {
+    chats
+        .iter()
+        .filter_map(|(chat, username)| match chat.parse::<i64>() {
+            Ok(chat) => Some((chat, username.clone())),
+            Err(_) => {
+                error!("Invalid Telegram chat id {}", chat);
+                None
+            }
+        })
+        .collect()
+}
+
+impl TelegramBot {
+    /// What to answer `message` with, `None` when nothing is
+    pub async fn handle(&self, message: &TelegramMessage) -> Option<String> {
+        let text = message.text.as_deref()?.trim();
+        let username = match self.chats.get(&message.chat.id) {
+            Some(username) => username,
+            None => {
+                warn!("Ignoring Telegram chat {}", message.chat.id);
+                return None;
+            }
+        };
+        let (command, argument) = match text.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (text, ""),
+        };
+        // commands in groups are sent as /ask@muninn_bot
+        let command = command.split('@').next().unwrap_or_default();
+        match command {
+            "/ask" if !argument.is_empty() => Some(self.ask(username, message, argument).await),
+            "/summary" => Some(self.summary(username, argument).await),
+            _ if command.starts_with('/') => Some(HELP.to_string()),
+            _ => self.remember(username, message, text).await,
+        }
+    }
+
+    async fn remember(
+        &self,
+        username: &str,
+        message: &TelegramMessage,
+        text: &str,
+    ) -> Option<String> {
+        let mut chat = ChatRequest {
+            role: Role::User.to_string(),
+            content: text.to_string(),
+            // the same message is stored once, even when Telegram resends it
+            hash: format!("telegram:{}:{}", message.chat.id, message.message_id),
+            conversation_id: Some(format!("telegram:{}", message.chat.id)),
+            occurred_at: Some(message.date),
+            metadata: HashMap::from([("source".to_string(), "telegram".to_string())]),
+            tags: vec![],
+        };
+        if let Err(e) = chat.validate(self.max_content_length) {
+            return Some(format!("That was not remembered, it {}", e.message));
+        }
+        // the first message after a check-in answers it
+        let check_in = self.check_ins.lock().unwrap().remove(&message.chat.id);
+        if let Some(question) = check_in {
+            chat.metadata.insert("check_in".to_string(), question);
+            chat.tags.push("journal".to_string());
+        }
+        match self
+            .chat_service
+            .save_chat(username, chat, DedupMode::Allow)
+            .await
+        {
+            Ok(saved) => {
+                self.event_hub.publish(HubEvent::MessageSaved {
+                    username: username.to_string(),
+                    hash: saved.hash,
+                    role: saved.role,
+                });
+                None
+            }
+            Err(_) => Some("Sorry, that could not be remembered.".to_string()),
+        }
+    }
+
+    async fn ask(&self, username: &str, message: &TelegramMessage, question: &str) -> String {
+        let request = CompleteRequest {
+            content: question.to_string(),
+            conversation_id: Some(format!("telegram:{}", message.chat.id)),
+            limit: None,
+            model: None,
+        };
+        match self.chat_service.complete(username, request).await {
+            Ok((_, completion)) => completion.reply.content,
+            Err(CompleteError::QuotaExceeded) => {
+                "Today's token quota is spent, ask again tomorrow.".to_string()
+            }
+            Err(e) => {
+                error!("Error answering {} on Telegram: {:?}", username, e);
+                "Sorry, that could not be answered.".to_string()
+            }
+        }
+    }
+
+    async fn summary(&self, username: &str, date: &str) -> String {
+        let today = chrono::Utc::now().date_naive();
+        let date = match date {
+            "" => today,
+            date => match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+                Ok(date) => date,
+                Err(_) => return "Dates are written YYYY-MM-DD.".to_string(),
+            },
+        };
+        match self
+            .summary_service
+            .get_summary(username, date, today, None)
+            .await
+        {
+            Ok(summary) => summary.summary,
+            Err(_) => "Sorry, that day could not be summarized.".to_string(),
+        }
+    }
+
+    /// Sends the summary of `date` to every chat whose user talked on it,
+    /// returning how many were sent
+    pub async fn send_summaries(&self, date: NaiveDate) -> Result<usize, ()> {
+        let today = chrono::Utc::now().date_naive();
+        let mut sent = 0;
+        let mut result = Ok(());
+        for (chat, username) in &self.chats {
+            let summary = match self
+                .summary_service
+                .get_summary(username, date, today, None)
+                .await
+            {
+                Ok(summary) if summary.message_count > 0 => summary,
+                Ok(_) => continue,
+                Err(_) => {
+                    result = Err(());
+                    continue;
+                }
+            };
+            let text = format!("Your {}:\n\n{}", date.format("%A, %B %-d"), summary.summary);
+            match self.api.send_message(*chat, &text).await {
+                Ok(_) => sent += 1,
+                Err(_) => result = Err(()),
+            }
+        }
+        result.map(|_| sent)
+    }
+
+    /// Asks every chat whose user talked on `date` how something they
+    /// mentioned went, returning how many were asked. Each question is
+    /// remembered in the chat's conversation and the answer that follows is
+    /// tagged `journal`.
+    pub async fn send_check_ins(&self, date: NaiveDate) -> Result<usize, ()> {
+        let today = chrono::Utc::now().date_naive();
+        let mut sent = 0;
+        let mut result = Ok(());
+        for (chat, username) in &self.chats {
+            let question = match self.check_in(username, date, today).await {
+                Ok(Some(question)) => question,
+                Ok(None) => continue,
+                Err(_) => {
+                    result = Err(());
+                    continue;
+                }
+            };
+            if self.api.send_message(*chat, &question).await.is_err() {
+                result = Err(());
+                continue;
+            }
+            let asked = ChatRequest {
+                role: Role::Assistant.to_string(),
+                content: question,
+                hash: format!("telegram:check_in:{}:{}", chat, date),
+                conversation_id: Some(format!("telegram:{}", chat)),
+                occurred_at: Some(chrono::Utc::now().timestamp()),
+                metadata: HashMap::from([("source".to_string(), "telegram".to_string())]),
+                tags: vec!["journal".to_string()],
+            };
+            match self
+                .chat_service
+                .save_chat(username, asked, DedupMode::Allow)
+                .await
+            {
+                Ok(saved) => {
+                    self.check_ins
+                        .lock()
+                        .unwrap()
+                        .insert(*chat, saved.hash.clone());
+                    self.event_hub.publish(HubEvent::MessageSaved {
+                        username: username.to_string(),
+                        hash: saved.hash,
+                        role: saved.role,
+                    });
+                }
+                Err(_) => result = Err(()),
+            }
+            sent += 1;
+        }
+        result.map(|_| sent)
+    }
+
+    // A question following up on what `username` talked about on `date`,
+    // `None` when they did not talk
+    async fn check_in(
+        &self,
+        username: &str,
+        date: NaiveDate,
+        today: NaiveDate,
+    ) -> Result<Option<String>, ()> {
+        let summary = self
+            .summary_service
+            .get_summary(username, date, today, None)
+            .await?;
+        if summary.message_count == 0 {
+            return Ok(None);
+        }
+        let context = vec![
+            Message {
+                role: "system".to_string(),
+                content: CHECK_IN_PROMPT.to_string(),
+            },
+            Message {
+                role: "user".to_string(),
+                content: summary.summary,
+            },
+        ];
+        let reply = self
+            .chat_service
+            .chat_client
+            .lock()
+            .await
+            .complete(context)
+            .await
+            .map_err(|e| error!("Error writing a check-in for {}: {}", username, e))?;
+        match reply.content.trim() {
+            "" => Ok(None),
+            question => Ok(Some(question.to_string())),
+        }
+    }
+
+    /// Sends `text` to every chat of `username`, returning how many it
+    /// reached
+    pub async fn send_reminder(&self, username: &str, text: &str) -> usize {
+        let text = format!("Reminder: {}", text);
+        let mut sent = 0;
+        for (chat, user) in &self.chats {
+            if user != username {
+                continue;
+            }
+            if self.api.send_message(*chat, &text).await.is_ok() {
+                sent += 1;
+            }
+        }
+        sent
+    }
+}
+
+/// Long polls Telegram for messages and answers them one after another, and
+/// sends due reminders
+pub fn start_telegram_bot(bot: Arc<TelegramBot>) {
+    let mut events = bot.event_hub.subscribe_all();
+    let reminders = bot.clone();
+    tokio::spawn(async move {
+        let mut offset = 0;
+        loop {
+            let updates = match bot.api.get_updates(offset, POLL_TIMEOUT).await {
+                Ok(updates) => updates,
+                Err(_) => {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+            for update in updates {
+                offset = offset.max(update.update_id + 1);
+                let message = match update.message {
+                    Some(message) => message,
+                    None => continue,
+                };
+                if let Some(reply) = bot.handle(&message).await {
+                    let _ = bot.api.send_message(message.chat.id, &reply).await;
+                }
+            }
+        }
+    });
+    info!("Telegram bot is listening");
+
+    // reminders are sent to the chats of their user as they come due
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(HubEvent::ReminderDue { username, text, .. }) => {
+                    reminders.send_reminder(&username, &text).await;
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    error!("Telegram bot skipped {} events", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use tokio::sync::Mutex;
+
+    use super::*;
+    use crate::{
+        clients::{
+            chat::MockChatClient, embeddings::MockEmbeddingsClient, telegram::Chat,
+            telegram::Update,
+        },
+        repos::{
+            messages::{FsMessageRepo, HistoryQuery},
+            summaries::FsSummaryRepo,
+        },
+    };
+
+    #[derive(Default)]
+    struct SilentApi {
+        sent: std::sync::Mutex<Vec<(i64, String)>>,
+    }
+
+    #[async_trait]
+    impl TelegramApi for SilentApi {
+        async fn get_updates(&self, _offset: i64, _timeout: Duration) -> Result<Vec<Update>, ()> {
+            Ok(vec![])
+        }
+
+        async fn send_message(&self, chat_id: i64, text: &str) -> Result<(), ()> {
+            self.sent.lock().unwrap().push((chat_id, text.to_string()));
+            Ok(())
+        }
+    }
+
+    fn message(chat: i64, message_id: i64, text: &str) -> TelegramMessage {
+        TelegramMessage {
+            message_id,
+            chat: Chat { id: chat },
+            date: 1_700_000_000,
+            text: Some(text.to_string()),
+        }
+    }
+
+    fn bot(username: &str, api: Arc<SilentApi>) -> TelegramBot {
+        let message_repo = Arc::new(Mutex::new(FsMessageRepo::new()));
+        let chat_client = Arc::new(Mutex::new(MockChatClient::new()));
+        let embedding_client = Arc::new(Mutex::new(MockEmbeddingsClient::new()));
+        let summary_repo = Arc::new(Mutex::new(FsSummaryRepo::new()));
+        TelegramBot {
+            api,
+            chats: parse_chats(&HashMap::from([
+                ("42".to_string(), username.to_string()),
+                ("family".to_string(), "bob".to_string()),
+            ])),
+            chat_service: ChatService {
+                embedding_client: embedding_client.clone(),
+                chat_client: chat_client.clone(),
+                summary_repo: summary_repo.clone(),
+                ..ChatService::for_tests(message_repo.clone())
+            },
+            summary_service: SummaryService {
+                message_repo: message_repo.clone(),
+                chat_client,
+                summary_repo,
+                embedding_client,
+            },
+            event_hub: Arc::new(EventHub::new(8)),
+            max_content_length: 100,
+            check_ins: Default::default(),
+        }
+    }
+
+    #[actix::test]
+    async fn test_messages_are_remembered_and_questions_answered() {
+        let username = format!("test_telegram_{}", uuid::Uuid::new_v4());
+        let bot = bot(&username, Arc::new(SilentApi::default()));
+        let message_repo = bot.chat_service.message_repo.clone();
+        assert_eq!(bot.chats.len(), 1);
+
+        assert_eq!(
+            bot.handle(&message(42, 1, "I parked on level 3")).await,
+            None
+        );
+        // chats that are not configured are ignored
+        assert_eq!(bot.handle(&message(7, 2, "Hello")).await, None);
+        assert!(bot
+            .handle(&message(42, 3, &"a".repeat(101)))
+            .await
+            .unwrap()
+            .starts_with("That was not remembered"));
+
+        let reply = bot
+            .handle(&message(42, 4, "/ask@muninn_bot where did I park?"))
+            .await;
+        assert!(reply.unwrap().starts_with("Reply to"));
+        assert_eq!(
+            bot.handle(&message(42, 5, "/start")).await,
+            Some(HELP.to_string())
+        );
+
+        let history = message_repo
+            .lock()
+            .await
+            .get_history(
+                username.clone(),
+                &HistoryQuery {
+                    limit: 10,
+                    ..HistoryQuery::default()
+                },
+            )
+            .await
+            .unwrap();
+        let saved = history
+            .messages
+            .iter()
+            .find(|chat| chat.metadata.contains_key("source"))
+            .unwrap();
+        assert_eq!(saved.hash, "telegram:42:1");
+        assert_eq!(saved.metadata["source"], "telegram");
+        assert_eq!(saved.occurred_at, Some(1_700_000_000));
+        // the question and its answer are remembered too
+        assert_eq!(history.messages.len(), 3);
+    }
+
+    #[actix::test]
+    async fn test_check_ins_are_asked_and_answers_kept() {
+        let username = format!("test_telegram_{}", uuid::Uuid::new_v4());
+        let api = Arc::new(SilentApi::default());
+        let bot = bot(&username, api.clone());
+        // messages are kept under the day they arrive
+        let date = chrono::Utc::now().date_naive();
+        bot.handle(&message(42, 1, "Job interview tomorrow")).await;
+        assert_eq!(bot.send_check_ins(date).await, Ok(1));
+        let question = format!("telegram:check_in:42:{}", date);
+        assert_eq!(api.sent.lock().unwrap()[0].0, 42);
+        assert_eq!(bot.check_ins.lock().unwrap()[&42], question);
+
+        // the next message answers the check-in, the one after does not
+        bot.handle(&message(42, 2, "It went well")).await;
+        bot.handle(&message(42, 3, "Lunch was good")).await;
+        let history = bot
+            .chat_service
+            .message_repo
+            .lock()
+            .await
+            .get_history(
+                username.clone(),
+                &HistoryQuery {
+                    limit: 10,
+                    ..HistoryQuery::default()
+                },
+            )
+            .await
+            .unwrap();
+        let answers = history
+            .messages
+            .iter()
+            .filter(|chat| chat.metadata.get("check_in") == Some(&question))
+            .collect::<Vec<_>>();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].content, "It went well");
+        assert!(answers[0].tags.contains(&"journal".to_string()));
+        assert!(history.messages.iter().any(|chat| chat.hash == question));
+    }
+}
@@ -0,0 +1,121 @@
+use std::{collections::HashMap, sync::Arc};
+
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::{
+    clients::chat::{ChatClient, ChatError, Message},
+    hub::{EventHub, HubEvent},
+    repos::{
+        messages::{ChatModel, MessageRepo},
+        threads::{ThreadSummaryModel, ThreadSummaryRepo},
+    },
+};
+
+pub struct ThreadService {
+    pub message_repo: Arc<Mutex<dyn MessageRepo>>,
+    pub chat_client: Arc<Mutex<dyn ChatClient>>,
+    pub thread_repo: Arc<Mutex<dyn ThreadSummaryRepo>>,
+    pub event_hub: Arc<EventHub>,
+}
+
+// Groups messages by conversation, keeping only those that belong to one
+fn group_by_conversation(chats: Vec<ChatModel>) -> HashMap<String, Vec<ChatModel>> {
+    let mut threads: HashMap<String, Vec<ChatModel>> = HashMap::new();
+    for chat in chats {
+        if let Some(conversation_id) = chat.conversation_id.clone() {
+            threads.entry(conversation_id).or_default().push(chat);
+        }
+    }
+    threads
+}
+
+impl ThreadService {
+    /// Summarizes every conversation whose newest message is older than
+    /// `idle_secs` and has not been summarized since, returning how many
+    /// summaries were written
+    pub async fn summarize_idle_threads(&self, idle_secs: i64, now: i64) -> Result<usize, ()> {
+        let users = self.message_repo.lock().await.get_users().await?;
+        let mut written = 0;
+
+        for user in users {
+            let chats = match self
+                .message_repo
+                .lock()
+                .await
+                .get_all_for_user(user.clone())
+                .await
+            {
+                Ok(chats) => chats,
+                Err(_) => {
+                    error!("Error loading messages for {}", user);
+                    continue;
+                }
+            };
+            let existing = self
+                .thread_repo
+                .lock()
+                .await
+                .get_summaries(&user)
+                .unwrap_or_default();
+
+            for (conversation_id, turns) in group_by_conversation(chats) {
+                let last_message_timestamp =
+                    turns.iter().map(|chat| chat.timestamp).max().unwrap_or(0);
+                if now - last_message_timestamp < idle_secs {
+                    continue;
+                }
+                let already_summarized = existing.iter().any(|summary| {
+                    summary.conversation_id == conversation_id
+                        && summary.last_message_timestamp >= last_message_timestamp
+                });
+                if already_summarized {
+                    continue;
+                }
+
+                let summary = match summarize_turns(&self.chat_client, &turns).await {
+                    Ok(summary) => summary,
+                    Err(e) => {
+                        error!("Error summarizing thread {}: {}", conversation_id, e);
+                        continue;
+                    }
+                };
+                let summary = ThreadSummaryModel {
+                    conversation_id: conversation_id.clone(),
+                    summary,
+                    last_message_timestamp,
+                    created_at: now,
+                };
+                match self.thread_repo.lock().await.save_summary(&user, summary) {
+                    Ok(_) => {
+                        info!("Summarized thread {} for {}", conversation_id, user);
+                        self.event_hub.publish(HubEvent::SummaryReady {
+                            username: user.clone(),
+                            conversation_id: conversation_id.clone(),
+                        });
+                        written += 1;
+                    }
+                    Err(_) => error!("Error saving thread summary {}", conversation_id),
+                }
+            }
+        }
+        Ok(written)
+    }
+}
+
+async fn summarize_turns(
+    chat_client: &Mutex<dyn ChatClient>,
+    turns: &[ChatModel],
+) -> Result<String, ChatError> {
+    let system_prompt = "Summarize the following conversation so it can stand in for the full transcript in a future chat with a large language model. Keep decisions, facts and open questions; optimise for a language model rather than human readability.";
+    let mut context = vec![Message {
+        role: "system".to_string(),
+        content: system_prompt.to_string(),
+    }];
+    context.extend(turns.iter().map(|chat| Message {
+        role: chat.role.clone(),
+        content: chat.content.clone(),
+    }));
+
+    Ok(chat_client.lock().await.complete(context).await?.content)
+}
@@ -0,0 +1,254 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::{
+    clients::{
+        chat::{ChatClient, ChatError, Message},
+        embeddings::EmbeddingsClient,
+    },
+    repos::messages::{ChatModel, MessageRepo},
+};
+
+// Most topics a map is split into when the client does not ask for a number,
+// and the most it may ask for
+const MAX_DEFAULT_TOPICS: usize = 12;
+const MAX_TOPICS: usize = 50;
+
+// Rounds of k-means, the clusters have usually settled well before
+const ITERATIONS: usize = 25;
+
+// Messages closest to the centre of a topic that the model names it from
+const LABEL_EXAMPLES: usize = 8;
+
+// Hashes of the most central messages returned with each topic
+const TOPIC_EXAMPLES: usize = 5;
+
+#[derive(Deserialize)]
+pub struct TopicsQuery {
+    /// Number of topics, picked from the number of messages when unset
+    pub k: Option<usize>,
+}
+
+/// Messages that are about the same thing
+#[derive(Serialize, Debug)]
+pub struct Topic {
+    /// Name given by the chat model, none when it could not be asked
+    pub label: Option<String>,
+    pub message_count: usize,
+    /// Hashes of the messages closest to the centre of the topic, closest
+    /// first
+    pub examples: Vec<String>,
+}
+
+pub struct TopicService {
+    pub message_repo: Arc<Mutex<dyn MessageRepo>>,
+    pub embedding_client: Arc<Mutex<dyn EmbeddingsClient>>,
+    pub chat_client: Arc<Mutex<dyn ChatClient>>,
+}
+
+impl TopicService {
+    /// Clusters the messages of `username` embedded by the current model
+    /// into `k` topics, largest first
+    pub async fn get_topics(&self, username: &str, k: Option<usize>) -> Result<Vec<Topic>, ()> {
+        let model = self.embedding_client.lock().await.model();
+        let (chats, vectors): (Vec<ChatModel>, Vec<Vec<f32>>) = self
+            .message_repo
+            .lock()
+            .await
+            .get_all_for_user(username.to_string())
+            .await?
+            .into_iter()
+            .filter(|chat| !chat.forgotten && !chat.low_value)
+            .filter(|chat| chat.embedding_model.as_ref() == Some(&model))
+            .filter_map(|chat| {
+                let vector = unit(chat.embedding.as_ref()?);
+                Some((chat, vector))
+            })
+            .unzip();
+        if chats.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let k = k
+            .unwrap_or_else(|| default_topic_count(chats.len()))
+            .clamp(1, MAX_TOPICS)
+            .min(chats.len());
+        let mut topics = vec![];
+        for members in kmeans(&vectors, k) {
+            let examples = members
+                .iter()
+                .take(LABEL_EXAMPLES)
+                .map(|member| &chats[*member])
+                .collect::<Vec<&ChatModel>>();
+            let label = label_topic(&self.chat_client, &examples)
+                .await
+                .map_err(|e| warn!("Error labelling a topic of {}: {}", username, e))
+                .ok();
+            topics.push(Topic {
+                label,
+                message_count: members.len(),
+                examples: examples
+                    .iter()
+                    .take(TOPIC_EXAMPLES)
+                    .map(|chat| chat.hash.clone())
+                    .collect(),
+            });
+        }
+        topics.sort_by_key(|topic| std::cmp::Reverse(topic.message_count));
+        Ok(topics)
+    }
+}
+
+// Grows with the square root of the number of messages, so large histories
+// are not split into topics too small to mean anything
+fn default_topic_count(messages: usize) -> usize {
+    ((messages as f64 / 2.0).sqrt().round() as usize).clamp(1, MAX_DEFAULT_TOPICS)
+}
+
+fn unit(vector: &[f32]) -> Vec<f32> {
+    let magnitude = vector.iter().map(|a| a * a).sum::<f32>().sqrt();
+    match magnitude > 0.0 {
+        true => vector.iter().map(|a| a / magnitude).collect(),
+        false => vector.to_vec(),
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+/// Splits unit `vectors` into at most `k` clusters by cosine similarity,
+/// returning the indexes in each cluster closest to its centre first. The
+/// first centres are picked furthest apart, so the result does not change
+/// between calls.
+fn kmeans(vectors: &[Vec<f32>], k: usize) -> Vec<Vec<usize>> {
+    let mut centres = vec![vectors[0].clone()];
+    while centres.len() < k {
+        let furthest = vectors
+            .iter()
+            .map(|vector| {
+                centres
+                    .iter()
+                    .map(|centre| dot(vector, centre))
+                    .fold(f32::MIN, f32::max)
+            })
+            .enumerate()
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(index, _)| index)
+            .unwrap_or(0);
+        centres.push(vectors[furthest].clone());
+    }
+
+    let nearest = |vector: &[f32], centres: &[Vec<f32>]| {
+        centres
+            .iter()
+            .enumerate()
+            .map(|(index, centre)| (index, dot(vector, centre)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(index, _)| index)
+            .unwrap_or(0)
+    };
+    let mut assignments = vec![0; vectors.len()];
+    for _ in 0..ITERATIONS {
+        let assigned = vectors
+            .iter()
+            .map(|vector| nearest(vector, &centres))
+            .collect::<Vec<usize>>();
+        let settled = assigned == assignments;
+        assignments = assigned;
+        for (cluster, centre) in centres.iter_mut().enumerate() {
+            let mut sum = vec![0.0; centre.len()];
+            for (vector, _) in vectors
+                .iter()
+                .zip(&assignments)
+                .filter(|(_, assigned)| **assigned == cluster)
+            {
+                for (total, value) in sum.iter_mut().zip(vector) {
+                    *total += value;
+                }
+            }
+            // an empty cluster keeps its centre
+            if sum.iter().any(|value| *value != 0.0) {
+                *centre = unit(&sum);
+            }
+        }
+        if settled {
+            break;
+        }
+    }
+
+    let mut clusters = vec![vec![]; centres.len()];
+    for (index, cluster) in assignments.iter().enumerate() {
+        clusters[*cluster].push(index);
+    }
+    for (cluster, members) in clusters.iter_mut().enumerate() {
+        members.sort_by(|a, b| {
+            dot(&vectors[*b], &centres[cluster]).total_cmp(&dot(&vectors[*a], &centres[cluster]))
+        });
+    }
+    clusters.retain(|members| !members.is_empty());
+    clusters
+}
+
+async fn label_topic(
+    chat_client: &Mutex<dyn ChatClient>,
+    examples: &[&ChatModel],
+) -> Result<String, ChatError> {
+    let system_prompt = "The messages below are about the same topic. Name the topic in two to five words and answer with the name only.";
+    let messages = examples
+        .iter()
+        .map(|chat| format!("- {}", chat.content))
+        .collect::<Vec<String>>()
+        .join("\n");
+    let context = vec![
+        Message {
+            role: "system".to_string(),
+            content: system_prompt.to_string(),
+        },
+        Message {
+            role: "user".to_string(),
+            content: messages,
+        },
+    ];
+
+    let label = chat_client.lock().await.complete(context).await?.content;
+    Ok(label
+        .trim()
+        .trim_matches('"')
+        .trim_end_matches('.')
+        .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kmeans_separates_unrelated_messages() {
+        let vectors = [
+            vec![1.0, 0.1, 0.0],
+            vec![0.0, 0.1, 1.0],
+            vec![0.9, 0.0, 0.1],
+            vec![0.1, 0.0, 0.9],
+            vec![1.0, 0.0, 0.0],
+        ]
+        .iter()
+        .map(|vector| unit(vector))
+        .collect::<Vec<Vec<f32>>>();
+
+        let mut clusters = kmeans(&vectors, 2);
+        clusters.sort_by_key(|members| members.iter().min().copied());
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].len(), 3);
+        assert!(clusters[0].iter().all(|member| [0, 2, 4].contains(member)));
+        assert!(clusters[1].iter().all(|member| [1, 3].contains(member)));
+
+        assert_eq!(kmeans(&vectors, 1)[0].len(), 5);
+        assert_eq!(default_topic_count(1), 1);
+        assert_eq!(default_topic_count(200), 10);
+        assert_eq!(default_topic_count(100_000), MAX_DEFAULT_TOPICS);
+    }
+}
@@ -0,0 +1,75 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::error;
+
+use crate::{
+    clients::chat::Usage,
+    repos::usage::{UsageModel, UsageRepo},
+};
+
+/// Tokens a user may spend per day, unlimited unless DAILY_TOKEN_QUOTA is set
+pub fn daily_quota() -> Option<u64> {
+    std::env::var("DAILY_TOKEN_QUOTA")
+        .ok()
+        .and_then(|value| value.parse().ok())
+}
+
+#[derive(Serialize)]
+pub struct UsageResponse {
+    pub daily_quota: Option<u64>,
+    /// Tokens left today, `None` without a quota
+    pub remaining_today: Option<u64>,
+    pub days: Vec<UsageModel>,
+}
+
+pub struct UsageService {
+    pub(crate) usage_repo: Arc<Mutex<dyn UsageRepo>>,
+}
+
+impl UsageService {
+    /// Adds a completion's tokens to the user's total for today
+    pub async fn record(&self, username: &str, usage: &Usage) {
+        let today = chrono::Utc::now().date_naive();
+        if self
+            .usage_repo
+            .lock()
+            .await
+            .record_usage(username, today, usage)
+            .is_err()
+        {
+            error!("Error recording token usage for {}", username);
+        }
+    }
+
+    /// Whether the user has used up today's quota
+    pub async fn quota_exceeded(&self, username: &str) -> Result<bool, ()> {
+        let quota = match daily_quota() {
+            Some(quota) => quota,
+            None => return Ok(false),
+        };
+        let today = chrono::Utc::now().date_naive();
+        let usage = self.usage_repo.lock().await.get_usage(username, today)?;
+        Ok(usage.total_tokens >= quota)
+    }
+
+    pub async fn get_usage(&self, username: &str) -> Result<UsageResponse, ()> {
+        let days = self.usage_repo.lock().await.get_all_usage(username)?;
+        let today = chrono::Utc::now()
+            .date_naive()
+            .format("%Y-%m-%d")
+            .to_string();
+        let spent_today = days
+            .iter()
+            .find(|day| day.date == today)
+            .map(|day| day.total_tokens)
+            .unwrap_or(0);
+        let daily_quota = daily_quota();
+        Ok(UsageResponse {
+            daily_quota,
+            remaining_today: daily_quota.map(|quota| quota.saturating_sub(spent_today)),
+            days,
+        })
+    }
+}
@@ -1,15 +1,68 @@
-use std::{collections::HashMap, sync::Arc};
+use std::sync::Arc;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 use tracing::info;
 
-use crate::repos::attributes::AttributeRepo;
+use crate::repos::attributes::{AttributeEvent, AttributeModel, AttributeRepo, AttributeSource};
 
 #[derive(Deserialize)]
 pub struct AttributeRequest {
     pub attribute: String,
     pub value: String,
+    /// Optional unix timestamp after which the attribute expires
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+}
+
+/// Portable representation of every attribute held for a user
+#[derive(Deserialize, Serialize)]
+pub struct AttributeDocument {
+    pub attributes: Vec<AttributeModel>,
+}
+
+/// How an import treats attributes that already exist for the user
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictStrategy {
+    /// Imported values replace existing ones
+    #[default]
+    Overwrite,
+    /// Existing values are kept and the imported ones skipped
+    Keep,
+    /// Nothing is imported if any attribute already exists
+    Fail,
+}
+
+#[derive(Deserialize)]
+pub struct AttributeQuery {
+    /// Point in time to look the attribute up at, as unix seconds or as
+    /// `YYYY-MM-DD` meaning the end of that day
+    pub at: Option<String>,
+}
+
+/// Reads the `at` parameter of an attribute lookup
+pub fn parse_point_in_time(value: &str) -> Option<i64> {
+    if let Ok(seconds) = value.parse::<i64>() {
+        return Some(seconds);
+    }
+    chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(23, 59, 59))
+        .map(|date| date.and_utc().timestamp())
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    #[serde(default)]
+    pub strategy: ConflictStrategy,
+}
+
+#[derive(Serialize)]
+pub struct ImportResult {
+    pub imported: usize,
+    pub skipped: usize,
+    pub conflicts: Vec<String>,
 }
 
 pub struct UserAttributeService {
@@ -19,14 +72,21 @@ pub struct UserAttributeService {
 impl UserAttributeService {
     pub async fn save_attribute(
         &mut self,
-        username: &String,
-        attribute: &String,
-        value: &String,
+        username: &str,
+        attribute: &str,
+        value: &str,
+        expires_at: Option<i64>,
     ) -> Result<(), ()> {
         self.attribute_repo
             .lock()
             .await
-            .save_attribute(username, attribute, value)
+            .save_attribute(
+                username,
+                attribute,
+                value,
+                expires_at,
+                AttributeSource::Stated,
+            )
             .await
             .map_err(|_| ())?;
 
@@ -38,7 +98,7 @@ impl UserAttributeService {
         Ok(())
     }
 
-    pub async fn get_attribute(&self, username: &String, attribute: &String) -> Result<String, ()> {
+    pub async fn get_attribute(&self, username: &str, attribute: &str) -> Result<String, ()> {
         let attribute = self
             .attribute_repo
             .lock()
@@ -49,4 +109,121 @@ impl UserAttributeService {
 
         Ok(attribute.value)
     }
+
+    pub async fn get_attribute_at(
+        &self,
+        username: &str,
+        attribute: &str,
+        at: i64,
+    ) -> Result<String, ()> {
+        let attribute = self
+            .attribute_repo
+            .lock()
+            .await
+            .get_attribute_at(username, attribute, at)
+            .await?;
+
+        Ok(attribute.value)
+    }
+
+    pub async fn get_history(
+        &self,
+        username: &str,
+        attribute: &str,
+    ) -> Result<Vec<AttributeEvent>, ()> {
+        self.attribute_repo
+            .lock()
+            .await
+            .get_history(username, attribute)
+            .await
+    }
+
+    pub async fn delete_attribute(&mut self, username: &str, attribute: &str) -> Result<(), ()> {
+        self.attribute_repo
+            .lock()
+            .await
+            .delete_attribute(username, attribute)
+            .await?;
+
+        info!("Deleted attribute {} for user {}", attribute, username);
+        Ok(())
+    }
+
+    pub async fn export_attributes(&self, username: &str) -> Result<AttributeDocument, ()> {
+        let attributes = self
+            .attribute_repo
+            .lock()
+            .await
+            .get_all_attributes(username)
+            .await
+            .map_err(|_| ())?;
+
+        Ok(AttributeDocument { attributes })
+    }
+
+    /// Merges a previously exported document into the user's attributes,
+    /// returning `Err` with the conflicting names when `strategy` is `Fail`
+    pub async fn import_attributes(
+        &mut self,
+        username: &str,
+        document: AttributeDocument,
+        strategy: ConflictStrategy,
+    ) -> Result<ImportResult, ImportResult> {
+        let mut repo = self.attribute_repo.lock().await;
+        let existing = repo
+            .get_all_attributes(username)
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|attribute| attribute.attribute)
+            .collect::<Vec<String>>();
+
+        let conflicts = document
+            .attributes
+            .iter()
+            .filter(|attribute| existing.contains(&attribute.attribute))
+            .map(|attribute| attribute.attribute.clone())
+            .collect::<Vec<String>>();
+
+        if strategy == ConflictStrategy::Fail && !conflicts.is_empty() {
+            return Err(ImportResult {
+                imported: 0,
+                skipped: document.attributes.len(),
+                conflicts,
+            });
+        }
+
+        let mut imported = 0;
+        let mut skipped = 0;
+        for attribute in document.attributes {
+            if strategy == ConflictStrategy::Keep && conflicts.contains(&attribute.attribute) {
+                skipped += 1;
+                continue;
+            }
+            match repo
+                .save_attribute(
+                    username,
+                    &attribute.attribute,
+                    &attribute.value,
+                    attribute.expires_at,
+                    attribute.source,
+                )
+                .await
+            {
+                Ok(_) => imported += 1,
+                Err(_) => skipped += 1,
+            }
+        }
+
+        info!(
+            "Imported {} attributes for user {} ({} skipped)",
+            imported, username, skipped
+        );
+
+        Ok(ImportResult {
+            imported,
+            skipped,
+            conflicts,
+        })
+    }
 }
@@ -0,0 +1,445 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr},
+};
+
+use serde::Serialize;
+
+use crate::config::RetentionPolicy;
+
+use super::{
+    chat::ChatRequest,
+    feeds::FeedRequest,
+    ingest::{
+        bookmark::BookmarkRequest,
+        email::{self, EmailRequest},
+    },
+    reminders::{self, ReminderRequest},
+    webhooks::{WebhookRequest, WEBHOOK_EVENTS},
+};
+
+/// Roles a saved message may have
+pub const ROLES: [&str; 3] = ["user", "assistant", "system"];
+
+// Longest hash a client may choose for a message
+const MAX_HASH_LENGTH: usize = 128;
+
+// Longest name a feed may be given
+const MAX_FEED_ID_LENGTH: usize = 64;
+
+// Longest webhook or bookmark URL that is taken
+const MAX_URL_LENGTH: usize = 2048;
+
+// Most days a retention policy may keep messages for, a century
+const MAX_RETENTION_DAYS: u32 = 36_500;
+
+/// Why a request was refused before anything was done with it
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ValidationError {
+    /// Path of the offending field, such as `content` or `[2].role`
+    pub field: String,
+    pub message: String,
+}
+
+impl ValidationError {
+    fn new(field: &str, message: impl Into<String>) -> Self {
+        ValidationError {
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+
+    /// The same error for the request at `index` of a batch
+    pub fn at(self, index: usize) -> Self {
+        ValidationError {
+            field: format!("[{}].{}", index, self.field),
+            message: self.message,
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Checks that `username` can name a user, see `crate::username`
+pub fn username(username: &str) -> Result<(), ValidationError> {
+    match crate::username::problem(username) {
+        Some(problem) => Err(ValidationError::new("username", problem)),
+        None => Ok(()),
+    }
+}
+
+/// Checks that `content` is not blank and at most `max_length` characters
+pub fn content(field: &str, content: &str, max_length: usize) -> Result<(), ValidationError> {
+    if content.trim().is_empty() {
+        return Err(ValidationError::new(field, "must not be empty"));
+    }
+    let length = content.chars().count();
+    if length > max_length {
+        return Err(ValidationError::new(
+            field,
+            format!(
+                "is {} characters, at most {} are allowed",
+                length, max_length
+            ),
+        ));
+    }
+    Ok(())
+}
+
+pub fn role(role: &str) -> Result<(), ValidationError> {
+    match ROLES.contains(&role) {
+        true => Ok(()),
+        false => Err(ValidationError::new(
+            "role",
+            format!("must be one of {}", ROLES.join(", ")),
+        )),
+    }
+}
+
+/// Hashes end up in file names and URLs, so only a safe set of characters
+/// is taken
+pub fn hash(hash: &str) -> Result<(), ValidationError> {
+    if hash.is_empty() || hash.len() > MAX_HASH_LENGTH {
+        return Err(ValidationError::new(
+            "hash",
+            format!("must be 1 to {} characters", MAX_HASH_LENGTH),
+        ));
+    }
+    let safe = hash
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c));
+    if !safe || hash.starts_with('.') {
+        return Err(ValidationError::new(
+            "hash",
+            "may only hold letters, digits and - _ . :",
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that `url` is an http:// or https:// URL short enough to keep
+pub fn http_url(url: &str) -> Result<(), ValidationError> {
+    let address = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"));
+    match address {
+        Some(address) if !address.is_empty() && !address.starts_with('/') => {}
+        _ => {
+            return Err(ValidationError::new(
+                "url",
+                "must be an http:// or https:// URL",
+            ))
+        }
+    }
+    if url.len() > MAX_URL_LENGTH {
+        return Err(ValidationError::new(
+            "url",
+            format!("must be at most {} characters", MAX_URL_LENGTH),
+        ));
+    }
+    Ok(())
+}
+
+impl ChatRequest {
+    /// Checks a message before it is embedded and saved
+    pub fn validate(&self, max_content_length: usize) -> Result<(), ValidationError> {
+        role(&self.role)?;
+        content("content", &self.content, max_content_length)?;
+        hash(&self.hash)
+    }
+}
+
+impl EmailRequest {
+    /// Checks that an email says something besides what it quotes and that
+    /// its date can be read
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if email::clean_body(&self.body).is_empty() {
+            return Err(ValidationError::new(
+                "body",
+                "must hold text besides quotes and a signature",
+            ));
+        }
+        match &self.date {
+            Some(date) if email::parse_date(date).is_none() => Err(ValidationError::new(
+                "date",
+                "must be an RFC 2822 or RFC 3339 date",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl BookmarkRequest {
+    /// Checks that the URL is http(s)
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        http_url(&self.url)
+    }
+}
+
+impl FeedRequest {
+    /// Checks that the URL is http(s) and the id can be used as a tag
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        http_url(&self.url)?;
+        let id = match &self.id {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        let safe = id
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_.".contains(c));
+        if id.is_empty() || id.len() > MAX_FEED_ID_LENGTH || !safe || id.starts_with('.') {
+            return Err(ValidationError::new(
+                "id",
+                format!(
+                    "must be 1 to {} lowercase letters, digits and - _ .",
+                    MAX_FEED_ID_LENGTH
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl ReminderRequest {
+    /// Checks that there is something to remind of and that a given due
+    /// time can be read
+    pub fn validate(&self, max_content_length: usize) -> Result<(), ValidationError> {
+        content("text", &self.text, max_content_length)?;
+        match &self.due_at {
+            Some(due_at) if reminders::parse_due_at(due_at).is_none() => Err(ValidationError::new(
+                "due_at",
+                "must be an RFC 3339 date or seconds since the epoch",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl RetentionPolicy {
+    /// Checks that a policy removing messages keeps at least a day of them
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        match self.days() {
+            Some(days) if days == 0 || days > MAX_RETENTION_DAYS => Err(ValidationError::new(
+                "days",
+                format!("must be between 1 and {}", MAX_RETENTION_DAYS),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Webhooks are posted to by the server, so one may not point back at the
+// server itself or at a link-local address such as a cloud metadata service
+fn webhook_url(url: &str) -> Result<(), ValidationError> {
+    let url = reqwest::Url::parse(url).map_err(|e| ValidationError::new("url", e.to_string()))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(ValidationError::new(
+            "url",
+            "must be an http:// or https:// URL",
+        ));
+    }
+    let host = url.host_str().unwrap_or_default();
+    // IPv6 hosts keep their brackets
+    let address = host.trim_start_matches('[').trim_end_matches(']');
+    let is_v4_local = |ip: Ipv4Addr| ip.is_loopback() || ip.is_link_local() || ip.is_unspecified();
+    let local = match address.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => is_v4_local(ip),
+        Ok(IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_v4_local(ip),
+            None => ip.is_loopback() || ip.is_unicast_link_local() || ip.is_unspecified(),
+        },
+        Err(_) => host == "localhost" || host.ends_with(".localhost"),
+    };
+    match local {
+        true => Err(ValidationError::new(
+            "url",
+            "must not point at this server or a link-local address",
+        )),
+        false => Ok(()),
+    }
+}
+
+impl WebhookRequest {
+    /// Checks that the URL is http(s) on another machine and every event
+    /// type is known
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        http_url(&self.url)?;
+        webhook_url(&self.url)?;
+        for event in &self.events {
+            if !WEBHOOK_EVENTS.contains(&event.as_str()) {
+                return Err(ValidationError::new(
+                    "events",
+                    format!("must be some of {}", WEBHOOK_EVENTS.join(", ")),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn request(role: &str, content: &str, hash: &str) -> ChatRequest {
+        ChatRequest {
+            role: role.to_string(),
+            content: content.to_string(),
+            hash: hash.to_string(),
+            conversation_id: None,
+            occurred_at: None,
+            metadata: HashMap::new(),
+            tags: vec![],
+        }
+    }
+
+    #[test]
+    fn test_requests_are_validated_field_by_field() {
+        assert!(request("user", "hello", "a1b2-c3").validate(10).is_ok());
+        assert!(request("system", "hello", "msg:2024.01")
+            .validate(10)
+            .is_ok());
+
+        let error = request("robot", "hello", "a1").validate(10).unwrap_err();
+        assert_eq!(error.field, "role");
+        let error = request("user", "  \n", "a1").validate(10).unwrap_err();
+        assert_eq!(error.field, "content");
+        let error = request("user", "hello world", "a1")
+            .validate(10)
+            .unwrap_err();
+        assert_eq!(error.message, "is 11 characters, at most 10 are allowed");
+        // characters are counted, not bytes
+        assert!(request("user", "ééééé", "a1").validate(5).is_ok());
+
+        for bad in ["", "../etc", "a/b", "a b", &"x".repeat(129)] {
+            let error = request("user", "hello", bad).validate(10).unwrap_err();
+            assert_eq!(error.field, "hash");
+        }
+        let error = request("robot", "", "").validate(10).unwrap_err().at(3);
+        assert_eq!(error.field, "[3].role");
+    }
+
+    #[test]
+    fn test_webhooks_need_http_urls_and_known_events() {
+        let webhook = |url: &str, events: &[&str]| WebhookRequest {
+            url: url.to_string(),
+            events: events.iter().map(|event| event.to_string()).collect(),
+            secret: None,
+        };
+        assert!(webhook("https://bot.example/hook", &["message_saved"])
+            .validate()
+            .is_ok());
+        assert!(webhook("http://10.0.0.2:9000", &[]).validate().is_ok());
+        for url in [
+            "ftp://bot.example",
+            "https://",
+            "bot.example/hook",
+            "http://127.0.0.1:8080/hook",
+            "http://localhost/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/hook",
+            "http://[::ffff:127.0.0.1]/hook",
+            "http://[fe80::1]/hook",
+            "http://0.0.0.0/hook",
+        ] {
+            assert_eq!(webhook(url, &[]).validate().unwrap_err().field, "url");
+        }
+        let error = webhook("https://bot.example", &["message_deleted"])
+            .validate()
+            .unwrap_err();
+        assert_eq!(error.field, "events");
+    }
+
+    #[test]
+    fn test_bookmarks_need_http_urls() {
+        let bookmark = |url: &str| BookmarkRequest {
+            url: url.to_string(),
+            ..BookmarkRequest::default()
+        };
+        assert!(bookmark("https://blog.example/post").validate().is_ok());
+        let error = bookmark("javascript:alert(1)").validate().unwrap_err();
+        assert_eq!(error.field, "url");
+        let long = format!("https://blog.example/{}", "a".repeat(MAX_URL_LENGTH));
+        assert_eq!(bookmark(&long).validate().unwrap_err().field, "url");
+    }
+
+    #[test]
+    fn test_feeds_need_http_urls_and_plain_ids() {
+        let feed = |url: &str, id: Option<&str>| FeedRequest {
+            url: url.to_string(),
+            id: id.map(str::to_string),
+            notify: false,
+            full_text: false,
+        };
+        assert!(feed("https://blog.example/feed.xml", None)
+            .validate()
+            .is_ok());
+        assert!(feed("https://blog.example/feed.xml", Some("blog-2.notes"))
+            .validate()
+            .is_ok());
+        let error = feed("ftp://blog.example/feed.xml", None)
+            .validate()
+            .unwrap_err();
+        assert_eq!(error.field, "url");
+        for id in ["", "Blog", "../blog", "a b", &"a".repeat(65)] {
+            let error = feed("https://blog.example/", Some(id))
+                .validate()
+                .unwrap_err();
+            assert_eq!(error.field, "id");
+        }
+    }
+
+    #[test]
+    fn test_reminders_need_text_and_readable_times() {
+        let reminder = |text: &str, due_at: Option<&str>| ReminderRequest {
+            text: text.to_string(),
+            due_at: due_at.map(str::to_string),
+        };
+        assert!(reminder("remind me Friday at 5", None)
+            .validate(100)
+            .is_ok());
+        assert!(reminder("Call Ana", Some("2023-11-17T17:00:00+02:00"))
+            .validate(100)
+            .is_ok());
+        assert_eq!(reminder(" ", None).validate(100).unwrap_err().field, "text");
+        let error = reminder("Call Ana", Some("Friday"))
+            .validate(100)
+            .unwrap_err();
+        assert_eq!(error.field, "due_at");
+    }
+
+    #[test]
+    fn test_retention_keeps_at_least_a_day() {
+        assert!(RetentionPolicy::Forever.validate().is_ok());
+        assert!(RetentionPolicy::Summarize { days: 30 }.validate().is_ok());
+        let error = RetentionPolicy::Delete { days: 0 }.validate().unwrap_err();
+        assert_eq!(error.field, "days");
+        assert!(RetentionPolicy::Delete { days: 40_000 }.validate().is_err());
+    }
+
+    #[test]
+    fn test_emails_need_text_and_readable_dates() {
+        let email = |body: &str, date: Option<&str>| EmailRequest {
+            body: body.to_string(),
+            date: date.map(str::to_string),
+            ..EmailRequest::default()
+        };
+        assert!(
+            email("See you at ten", Some("Tue, 14 Nov 2023 22:13:20 +0000"))
+                .validate()
+                .is_ok()
+        );
+        let error = email("> quoted\n-- \nAna", None).validate().unwrap_err();
+        assert_eq!(error.field, "body");
+        let error = email("Hi", Some("soon")).validate().unwrap_err();
+        assert_eq!(error.field, "date");
+    }
+}
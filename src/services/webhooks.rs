@@ -0,0 +1,391 @@
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::sync::{broadcast::error::RecvError, Mutex};
+use tracing::{error, info, warn};
+
+use crate::{
+    clients::retry::{self, RetryPolicy},
+    hub::{EventHub, HubEvent},
+    repos::webhooks::{DeliveryModel, WebhookModel, WebhookRepo},
+    services::validation::ValidationError,
+};
+
+/// Event types a webhook can ask for
+pub const WEBHOOK_EVENTS: [&str; 5] = [
+    "message_saved",
+    "summary_ready",
+    "attribute_changed",
+    "feed_item",
+    "reminder_due",
+];
+
+// Longest a receiver may take to answer one attempt
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+pub struct WebhookRequest {
+    pub url: String,
+    /// Every event type when empty
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Generated when the client does not choose one
+    pub secret: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum RegisterError {
+    /// The request was refused, such as for a URL on this server
+    Invalid(ValidationError),
+    Failed,
+}
+
+impl From<()> for RegisterError {
+    fn from(_: ()) -> Self {
+        RegisterError::Failed
+    }
+}
+
+/// The webhook event type of `event`, `None` for events webhooks are not
+/// told about
+pub fn event_type(event: &HubEvent) -> Option<&'static str> {
+    match event {
+        HubEvent::MessageSaved { .. } => Some("message_saved"),
+        HubEvent::SummaryReady { .. } => Some("summary_ready"),
+        HubEvent::AttributeChanged { .. } => Some("attribute_changed"),
+        HubEvent::FeedItem { .. } => Some("feed_item"),
+        HubEvent::ReminderDue { .. } => Some("reminder_due"),
+        _ => None,
+    }
+}
+
+/// HMAC-SHA256 of `body` keyed with `secret`, in hex. Receivers compare it
+/// with the `X-Muninn-Signature` header to know the payload came from here.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    const BLOCK: usize = 64;
+    let mut key = secret.as_bytes().to_vec();
+    if key.len() > BLOCK {
+        key = Sha256::digest(&key).to_vec();
+    }
+    key.resize(BLOCK, 0);
+    let pad = |byte: u8| key.iter().map(|k| k ^ byte).collect::<Vec<u8>>();
+
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(body)
+        .finalize();
+    let outer = Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize();
+    format!("{:x}", outer)
+}
+
+fn new_secret() -> String {
+    let bytes: [u8; 24] = rand::thread_rng().gen();
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub struct WebhookService {
+    pub webhook_repo: Arc<Mutex<dyn WebhookRepo>>,
+    pub client: reqwest::Client,
+    pub retry: RetryPolicy,
+}
+
+impl WebhookService {
+    pub fn new(webhook_repo: Arc<Mutex<dyn WebhookRepo>>) -> Self {
+        WebhookService {
+            webhook_repo,
+            client: reqwest::Client::new(),
+            retry: RetryPolicy::for_backend("webhook"),
+        }
+    }
+
+    /// Checks and stores a request, the returned webhook is the only one
+    /// that shows the secret
+    pub async fn register(
+        &self,
+        username: &str,
+        request: WebhookRequest,
+    ) -> Result<WebhookModel, RegisterError> {
+        request.validate().map_err(RegisterError::Invalid)?;
+        let webhook = WebhookModel {
+            id: uuid::Uuid::new_v4().to_string(),
+            url: request.url,
+            secret: request.secret.unwrap_or_else(new_secret),
+            events: request.events,
+            created_at: chrono::Utc::now().timestamp(),
+            deliveries: vec![],
+        };
+        Ok(self
+            .webhook_repo
+            .lock()
+            .await
+            .save_webhook(username, webhook)?)
+    }
+
+    /// Posts `event` to every webhook of its user that wants it and records
+    /// how each delivery went
+    pub async fn deliver(&self, event: &HubEvent) {
+        let event_type = match event_type(event) {
+            Some(event_type) => event_type,
+            None => return,
+        };
+        let username = event.username();
+        let webhooks = match self.webhook_repo.lock().await.get_webhooks(username) {
+            Ok(webhooks) => webhooks,
+            Err(_) => {
+                error!("Error getting webhooks of {}", username);
+                return;
+            }
+        };
+        for webhook in webhooks.iter().filter(|webhook| webhook.wants(event_type)) {
+            let delivery = self.post(webhook, event_type, event).await;
+            if !delivery.delivered {
+                warn!("Webhook {} of {} failed", webhook.id, username);
+            }
+            let recorded =
+                self.webhook_repo
+                    .lock()
+                    .await
+                    .record_delivery(username, &webhook.id, delivery);
+            if recorded.is_err() {
+                error!("Error recording delivery to webhook {}", webhook.id);
+            }
+        }
+    }
+
+    async fn post(
+        &self,
+        webhook: &WebhookModel,
+        event_type: &str,
+        event: &HubEvent,
+    ) -> DeliveryModel {
+        let timestamp = chrono::Utc::now().timestamp();
+        let body = json!({
+            "id": uuid::Uuid::new_v4().to_string(),
+            "type": event_type,
+            "timestamp": timestamp,
+            "event": event,
+        })
+        .to_string();
+        let signature = format!("sha256={}", sign(&webhook.secret, body.as_bytes()));
+        let attempts = AtomicU32::new(0);
+        let sent = retry::send(&self.retry, "webhook", || {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            self.client
+                .post(&webhook.url)
+                .timeout(DELIVERY_TIMEOUT)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header("X-Muninn-Event", event_type)
+                .header("X-Muninn-Signature", &signature)
+                .body(body.clone())
+        })
+        .await;
+        let (delivered, status, error) = match sent {
+            Ok(response) => (
+                response.status().is_success(),
+                Some(response.status().as_u16()),
+                None,
+            ),
+            Err(e) => (false, None, Some(e.to_string())),
+        };
+        DeliveryModel {
+            event: event_type.to_string(),
+            delivered,
+            attempts: attempts.into_inner(),
+            status,
+            error,
+            timestamp,
+        }
+    }
+}
+
+/// Follows the event hub and delivers every event webhooks are told about,
+/// each in its own task so a slow receiver does not hold up the others
+pub fn start_webhook_dispatcher(hub: Arc<EventHub>, service: Arc<WebhookService>) {
+    let mut events = hub.subscribe_all();
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    error!("Webhook dispatcher skipped {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            if event_type(&event).is_none() {
+                continue;
+            }
+            let service = service.clone();
+            tokio::spawn(async move { service.deliver(&event).await });
+        }
+    });
+    info!("Delivering events to webhooks");
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::repos::webhooks::FsWebhookRepo;
+
+    #[test]
+    fn test_signatures_are_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[actix::test]
+    async fn test_webhooks_on_this_server_are_refused() {
+        let username = format!("test_webhooks_{}", uuid::Uuid::new_v4());
+        let service = WebhookService::new(Arc::new(Mutex::new(FsWebhookRepo::new())));
+        let request = WebhookRequest {
+            url: "http://127.0.0.1:8080/api/v1/admin/users".to_string(),
+            events: vec![],
+            secret: None,
+        };
+        match service.register(&username, request).await {
+            Err(RegisterError::Invalid(e)) => assert_eq!(e.field, "url"),
+            other => panic!("expected the URL to be refused, got {:?}", other),
+        }
+        assert!(service
+            .webhook_repo
+            .lock()
+            .await
+            .get_webhooks(&username)
+            .unwrap()
+            .is_empty());
+    }
+
+    // Reads up to the end of the body that the headers announce
+    async fn read_request(socket: &mut tokio::net::TcpStream) -> String {
+        let mut request = vec![];
+        let mut buffer = [0; 4096];
+        loop {
+            let read = socket.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                let length = headers
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .and_then(|length| length.parse::<usize>().ok())
+                    .unwrap_or(0);
+                if body.len() >= length || read == 0 {
+                    return text;
+                }
+            }
+        }
+    }
+
+    // Answers one connection after another with the given statuses and
+    // hands back what was sent
+    async fn serve(statuses: Vec<u16>) -> (String, tokio::sync::mpsc::Receiver<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (requests, received) = tokio::sync::mpsc::channel(8);
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let _ = requests.send(read_request(&mut socket).await).await;
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (format!("http://{}/hook", address), received)
+    }
+
+    #[actix::test]
+    async fn test_events_are_signed_retried_and_recorded() {
+        let (url, mut received) = serve(vec![503, 200]).await;
+        let username = format!("test_webhooks_{}", uuid::Uuid::new_v4());
+        let service = WebhookService {
+            webhook_repo: Arc::new(Mutex::new(FsWebhookRepo::new())),
+            client: reqwest::Client::new(),
+            retry: RetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(1),
+            },
+        };
+        // registering refuses the receiver, which runs on this machine
+        let webhook = service
+            .webhook_repo
+            .lock()
+            .await
+            .save_webhook(
+                &username,
+                WebhookModel {
+                    id: "hook".to_string(),
+                    url,
+                    secret: "s3cret".to_string(),
+                    events: vec!["message_saved".to_string()],
+                    created_at: 0,
+                    deliveries: vec![],
+                },
+            )
+            .unwrap();
+
+        // not an event the webhook asked for
+        service
+            .deliver(&HubEvent::AttributeChanged {
+                username: username.clone(),
+                attribute: "name".to_string(),
+            })
+            .await;
+        service
+            .deliver(&HubEvent::MessageSaved {
+                username: username.clone(),
+                hash: "abc".to_string(),
+                role: "user".to_string(),
+            })
+            .await;
+
+        let webhooks = service
+            .webhook_repo
+            .lock()
+            .await
+            .get_webhooks(&username)
+            .unwrap();
+        assert_eq!(
+            webhooks[0].deliveries,
+            vec![DeliveryModel {
+                event: "message_saved".to_string(),
+                delivered: true,
+                attempts: 2,
+                status: Some(200),
+                error: None,
+                timestamp: webhooks[0].deliveries[0].timestamp,
+            }]
+        );
+
+        let request = received.recv().await.unwrap();
+        let (headers, body) = request.split_once("\r\n\r\n").unwrap();
+        assert!(headers.contains("x-muninn-event: message_saved"));
+        let signature = format!(
+            "x-muninn-signature: sha256={}",
+            sign(&webhook.secret, body.as_bytes())
+        );
+        assert!(headers.contains(&signature));
+        let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(payload["event"]["hash"], "abc");
+    }
+}
@@ -0,0 +1,48 @@
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use rustls::ServerConfig;
+
+/// TLS settings serving the PEM certificate chain at `cert` with the
+/// private key at `key`
+pub fn server_config(cert: &Path, key: &Path) -> Result<ServerConfig> {
+    let mut reader = BufReader::new(
+        File::open(cert).with_context(|| format!("Error opening {}", cert.display()))?,
+    );
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Error reading certificates from {}", cert.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates in {}", cert.display());
+    }
+
+    let mut reader = BufReader::new(
+        File::open(key).with_context(|| format!("Error opening {}", key.display()))?,
+    );
+    let key = rustls_pemfile::private_key(&mut reader)
+        .with_context(|| format!("Error reading private key from {}", key.display()))?
+        .with_context(|| format!("No private key in {}", key.display()))?;
+
+    ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid certificate or private key")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_files_without_pem_blocks_are_rejected() {
+        let path = std::env::temp_dir().join(format!("muninn_tls_{}.pem", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "not a certificate").unwrap();
+
+        let error = server_config(&path, &path).unwrap_err();
+        assert!(error.to_string().starts_with("No certificates"));
+        assert!(server_config(Path::new("/nonexistent/cert.pem"), &path).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{repos::COLLECTIONS_FOLDER, services::collections};
+
+/// Longest username that is taken
+pub const MAX_LENGTH: usize = 64;
+
+/// Why `username` cannot name a user, `None` when it can. Usernames name a
+/// folder below the storage root, so they are kept to ASCII letters, digits
+/// and `-_.@+`, which leaves no lookalike or differently normalized forms of
+/// a name, and may not start with a dot, which rules out `.`, `..` and the
+/// storage's own hidden folders.
+pub fn problem(username: &str) -> Option<&'static str> {
+    if username.is_empty() {
+        return Some("must not be empty");
+    }
+    if username.len() > MAX_LENGTH {
+        return Some("must be at most 64 characters");
+    }
+    if username.starts_with('.') {
+        return Some("must not start with a dot");
+    }
+    let allowed = |c: char| c.is_ascii_alphanumeric() || "-_.@+".contains(c);
+    if !username.chars().all(allowed) {
+        return Some("may only hold ASCII letters, digits and -_.@+");
+    }
+    None
+}
+
+pub fn is_valid(username: &str) -> bool {
+    problem(username).is_none()
+}
+
+/// A valid username close to `username`, with every character that is not
+/// allowed replaced by `_`, for folders named before usernames were checked
+pub fn replacement(username: &str) -> String {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || "-_.@+".contains(c);
+    let replaced = username
+        .trim_start_matches('.')
+        .chars()
+        .map(|c| if allowed(c) { c } else { '_' })
+        .take(MAX_LENGTH)
+        .collect::<String>();
+    match replaced.is_empty() {
+        true => "_".to_string(),
+        false => replaced,
+    }
+}
+
+/// Whether `key` is a username or the storage key of one of the user's
+/// collections, either of which stays inside the storage root
+pub fn is_valid_key(key: &str) -> bool {
+    let mut parts = key.splitn(3, '/');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(username), None, None) => is_valid(username),
+        (Some(username), Some(COLLECTIONS_FOLDER), Some(collection)) => {
+            is_valid(username) && collections::is_valid_name(collection)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usernames_stay_inside_the_storage_root() {
+        assert!(is_valid("alice"));
+        assert!(is_valid("alice.smith+work@example.com"));
+        assert!(is_valid("-100987"));
+        for username in ["", ".", "..", "../etc", "a/b", "a\\b", ".content", "b\0"] {
+            assert!(!is_valid(username), "{:?}", username);
+        }
+        // lookalikes and invisible characters
+        assert!(!is_valid("аlice"));
+        assert!(!is_valid("alice\u{200b}"));
+        assert!(!is_valid(&"a".repeat(65)));
+        assert_eq!(replacement("john smith"), "john_smith");
+        assert_eq!(replacement("josé"), "jos_");
+        assert!(is_valid(&replacement(&"é".repeat(65))));
+
+        assert!(is_valid_key("alice"));
+        assert!(is_valid_key("alice/collections/notes"));
+        assert!(!is_valid_key("alice/collections/../../etc"));
+        assert!(!is_valid_key("alice/2024-03-01"));
+        assert!(!is_valid_key("../collections/notes"));
+    }
+}